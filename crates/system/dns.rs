@@ -0,0 +1,89 @@
+use core::str;
+
+use error::{Error, Result, EINVAL, ENAMETOOLONG};
+use syscall::{sys_close, sys_open, sys_read, O_RDONLY};
+
+/// The longest host name DNS allows
+pub const HOST_MAX: usize = 253;
+
+/// Parse a dotted IPv4 address like `10.0.2.2`
+pub fn parse_addr(text: &str) -> Option<[u8; 4]> {
+    let mut addr = [0; 4];
+    let mut parts = text.split('.');
+    for byte in addr.iter_mut() {
+        match parts.next().and_then(|part| if part.is_empty() || part.len() > 3 { None } else { part.parse::<u8>().ok() }) {
+            Some(value) => *byte = value,
+            None => return None,
+        }
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(addr)
+}
+
+/// Parse the text of dns:/host, one dotted address per line, into `addrs`
+///
+/// Returns how many were filled, the rest of a longer list is left out. Anything that is not an address fails
+/// with EINVAL.
+pub fn parse_addrs(text: &[u8], addrs: &mut [[u8; 4]]) -> Result<usize> {
+    let text = try!(str::from_utf8(text).map_err(|_| Error::new(EINVAL)));
+    let mut count = 0;
+    for line in text.lines().map(|line| line.trim()).filter(|line| ! line.is_empty()) {
+        let addr = try!(parse_addr(line).ok_or(Error::new(EINVAL)));
+        if count < addrs.len() {
+            addrs[count] = addr;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Resolve `host` to its IPv4 addresses through the dns: scheme, filling `addrs`
+///
+/// A dotted address is given back as it is, without a lookup. Returns how many addresses were filled, a name
+/// that does not exist gives none.
+pub fn resolve(host: &str, addrs: &mut [[u8; 4]]) -> Result<usize> {
+    if let Some(addr) = parse_addr(host) {
+        if addrs.is_empty() {
+            return Ok(0);
+        }
+        addrs[0] = addr;
+        return Ok(1);
+    }
+
+    if host.is_empty() {
+        return Err(Error::new(EINVAL));
+    } else if host.len() > HOST_MAX {
+        return Err(Error::new(ENAMETOOLONG));
+    }
+
+    let mut path = [0; 5 + HOST_MAX];
+    for (p, b) in path.iter_mut().zip(b"dns:/".iter().chain(host.as_bytes().iter())) {
+        *p = *b;
+    }
+    let path = unsafe { str::from_utf8_unchecked(&path[.. 5 + host.len()]) };
+
+    let fd = try!(sys_open(path, O_RDONLY));
+    let mut text = [0; 4096];
+    let mut len = 0;
+    let mut result = Ok(());
+    while len < text.len() {
+        match sys_read(fd, &mut text[len ..]) {
+            Ok(0) => break,
+            Ok(count) => len += count,
+            Err(err) => {
+                result = Err(err);
+                break;
+            },
+        }
+    }
+    let _ = sys_close(fd);
+    try!(result);
+
+    // A list longer than the buffer is cut after its last whole line
+    if len == text.len() {
+        len = text.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+    }
+    parse_addrs(&text[.. len], addrs)
+}
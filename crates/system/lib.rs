@@ -6,6 +6,7 @@
 
 use core::{ptr, slice, str};
 
+pub mod dns;
 pub mod error;
 #[cfg(target_os="redox")]
 pub mod externs;
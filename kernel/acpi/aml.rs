@@ -485,3 +485,31 @@ pub fn parse(bytes: &[u8]) {
         }
     }
 }
+
+/// Find the sleep types of `\_S5`, the first two integers of its package, by looking for its name rather
+/// than interpreting the table
+///
+/// The package is usually a named constant, though some firmware computes it in a method, which is not
+/// found this way.
+pub fn find_s5(bytes: &[u8]) -> Option<(u8, u8)> {
+    let mut i = 0;
+    while i + 5 < bytes.len() {
+        if &bytes[i..i + 4] == b"_S5_" {
+            // NameOp before the name, with the root prefix between them if the name is absolute
+            let named = (i >= 1 && bytes[i - 1] == NAME_OP) ||
+                        (i >= 2 && bytes[i - 2] == NAME_OP && bytes[i - 1] == ROOT_PREFIX);
+            if named && bytes[i + 4] == PACKAGE_OP {
+                let mut j = i + 5;
+                parse_length(bytes, &mut j);
+                // The number of elements
+                j += 1;
+                let slp_typ_a = parse_int(bytes, &mut j);
+                let slp_typ_b = parse_int(bytes, &mut j);
+                return Some((slp_typ_a as u8, slp_typ_b as u8));
+            }
+        }
+        i += 1;
+    }
+
+    None
+}
@@ -1,5 +1,21 @@
 use super::SDTHeader;
-use core::ptr;
+
+use arch::timekeeping;
+
+use core::{cmp, ptr};
+use core::mem::size_of;
+
+use drivers::io::{Io, Pio};
+
+/// The address space of a generic address for I/O ports
+const GAS_SYSTEM_IO: u8 = 1;
+
+/// Bits of the PM1 control register
+const PM1_CNT_SCI_EN: u16 = 1;
+const PM1_CNT_SLP_TYP: u16 = 0x7 << 10;
+const PM1_CNT_SLP_EN: u16 = 1 << 13;
+/// Milliseconds the firmware is given to hand over to ACPI
+const ACPI_ENABLE_TIMEOUT: u64 = 1000;
 
 #[repr(packed)]
 #[derive(Clone, Copy, Debug, Default)]
@@ -80,12 +96,78 @@ pub struct FADT {
     pub x_gpe1_block: GenericAddressStructure,
 }
 
+impl GenericAddressStructure {
+    /// The port of the register, if it is in I/O space
+    pub fn io_port(&self) -> Option<u16> {
+        if self.address_space == GAS_SYSTEM_IO && self.address != 0 && self.address <= 0xFFFF {
+            Some(self.address as u16)
+        } else {
+            None
+        }
+    }
+}
+
 impl FADT {
+    /// Read the table, the fields past its length, which older revisions end before, are left 0
     pub fn new(header: &'static SDTHeader) -> Option<Self> {
         if header.valid("FACP") {
-            Some(unsafe { ptr::read((header as *const SDTHeader) as *const FADT) })
+            let mut fadt = FADT::default();
+            let len = cmp::min(header.length as usize, size_of::<FADT>());
+            unsafe { ptr::copy_nonoverlapping(header as *const SDTHeader as *const u8, &mut fadt as *mut FADT as *mut u8, len) };
+            Some(fadt)
         } else {
             None
         }
     }
+
+    /// The address of the DSDT, from the 64 bit field if the 32 bit one is 0
+    pub fn dsdt_address(&self) -> usize {
+        if self.dsdt != 0 {
+            self.dsdt as usize
+        } else {
+            self.x_dsdt as usize
+        }
+    }
+
+    /// The ports of the PM1a and PM1b control blocks, from the generic addresses if the 32 bit fields are 0
+    pub fn pm1_control_ports(&self) -> (Option<u16>, Option<u16>) {
+        let port = |block: u32, x_block: GenericAddressStructure| if block != 0 && block <= 0xFFFF {
+            Some(block as u16)
+        } else {
+            x_block.io_port()
+        };
+        (port(self.pm1a_control_block, self.x_pm1a_control_block),
+         port(self.pm1b_control_block, self.x_pm1b_control_block))
+    }
+
+    /// Enter S5 with the sleep types `slp_typ_a` and `slp_typ_b`, for the PM1a and PM1b control blocks
+    ///
+    /// ACPI is enabled through the SMI command port first if the firmware has not handed it over, as the
+    /// sleep registers are ignored until it is. Returns only if the machine did not power off.
+    pub fn poweroff(&self, slp_typ_a: u8, slp_typ_b: u8) {
+        let (pm1a, pm1b) = self.pm1_control_ports();
+        let pm1a = match pm1a {
+            Some(port) => port,
+            None => {
+                syslog_error!("ACPI: No PM1a control block");
+                return;
+            }
+        };
+
+        let mut control = Pio::<u16>::new(pm1a);
+        if ! control.readf(PM1_CNT_SCI_EN) && self.smi_command_port != 0 && self.acpi_enable != 0 {
+            Pio::<u8>::new(self.smi_command_port as u16).write(self.acpi_enable);
+            if ! timekeeping::spin_for(ACPI_ENABLE_TIMEOUT, || control.readf(PM1_CNT_SCI_EN)) {
+                syslog_warning!("ACPI: Firmware did not enable ACPI");
+            }
+        }
+
+        let value = control.read() & ! PM1_CNT_SLP_TYP;
+        control.write(value | ((slp_typ_a as u16) << 10 & PM1_CNT_SLP_TYP) | PM1_CNT_SLP_EN);
+        if let Some(pm1b) = pm1b {
+            let mut control = Pio::<u16>::new(pm1b);
+            let value = control.read() & ! PM1_CNT_SLP_TYP;
+            control.write(value | ((slp_typ_b as u16) << 10 & PM1_CNT_SLP_TYP) | PM1_CNT_SLP_EN);
+        }
+    }
 }
@@ -0,0 +1,35 @@
+use super::SDTHeader;
+
+use core::ptr;
+
+/// The table of the high precision event timer, of which only the address of the first block is used
+#[derive(Clone, Copy, Debug)]
+pub struct HPET {
+    pub header: &'static SDTHeader,
+    /// The memory mapped registers of the first timer block
+    pub base: usize,
+}
+
+impl HPET {
+    pub fn new(header: &'static SDTHeader) -> Option<Self> {
+        if header.valid("HPET") {
+            let data: &'static [u8] = header.data();
+            if data.len() < 16 {
+                return None;
+            }
+
+            // After the block id is a generic address, its space is 0 for memory
+            let address = unsafe { ptr::read(data.as_ptr().offset(8) as *const u64) };
+            if data[4] == 0 && address != 0 {
+                Some(HPET {
+                    header: header,
+                    base: address as usize,
+                })
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
+}
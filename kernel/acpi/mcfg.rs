@@ -0,0 +1,61 @@
+use super::SDTHeader;
+
+use collections::vec::Vec;
+
+use core::mem::size_of;
+use core::ptr;
+
+/// The ECAM region of a PCI segment, which maps the configuration space of buses `start_bus` to
+/// `end_bus`
+#[repr(packed)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct McfgAllocation {
+    pub base: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+    reserved: u32,
+}
+
+/// The table of the PCI Express memory mapped configuration space
+#[derive(Clone, Debug)]
+pub struct MCFG {
+    pub header: &'static SDTHeader,
+    pub allocations: Vec<McfgAllocation>,
+}
+
+impl MCFG {
+    pub fn new(header: &'static SDTHeader) -> Option<Self> {
+        if header.valid("MCFG") {
+            let data: &'static [u8] = header.data();
+
+            let mut mcfg = MCFG {
+                header: header,
+                allocations: Vec::new(),
+            };
+
+            // The allocations follow 8 reserved bytes
+            let mut i = 8;
+            while i + size_of::<McfgAllocation>() <= data.len() {
+                mcfg.allocations.push(unsafe {
+                    ptr::read(data.as_ptr().offset(i as isize) as *const McfgAllocation)
+                });
+                i += size_of::<McfgAllocation>();
+            }
+
+            Some(mcfg)
+        } else {
+            None
+        }
+    }
+
+    /// The address of the 4 KB configuration space of a function on segment 0, if an allocation maps its bus
+    pub fn address(&self, bus: u8, slot: u8, func: u8) -> Option<usize> {
+        self.allocations.iter().find(|allocation| {
+            allocation.segment == 0 && bus >= allocation.start_bus && bus <= allocation.end_bus
+        }).map(|allocation| {
+            allocation.base as usize +
+            (((bus - allocation.start_bus) as usize) << 20 | (slot as usize & 0x1F) << 15 | (func as usize & 0x7) << 12)
+        })
+    }
+}
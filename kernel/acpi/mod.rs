@@ -2,12 +2,16 @@ use alloc::boxed::Box;
 
 use core::str;
 
+use common::event::{POWER_OFF, POWER_REBOOT};
+use drivers::{ps2, rtc};
 use fs::{KScheme, Resource};
 use system::error::{Error, Result, ENOENT};
 use system::syscall::O_CREAT;
 pub use self::dsdt::DSDT;
 pub use self::fadt::FADT;
+pub use self::hpet::HPET;
 pub use self::madt::MADT;
+pub use self::mcfg::MCFG;
 pub use self::rsdt::RSDT;
 pub use self::sdt::SDTHeader;
 pub use self::ssdt::SSDT;
@@ -15,51 +19,91 @@ pub use self::ssdt::SSDT;
 pub mod aml;
 pub mod dsdt;
 pub mod fadt;
+pub mod hpet;
 pub mod madt;
+pub mod mcfg;
 pub mod rsdt;
 pub mod sdt;
 pub mod ssdt;
 
+/// The sleep types QEMU and Bochs give `\_S5`, for when the tables do not name it
+const S5_FALLBACK: (u8, u8) = (0, 0);
+
+/// The tables, found once at boot
+static mut ACPI: Option<Acpi> = None;
+
+/// Find the tables, before the drivers that read them are started
+pub fn init() {
+    unsafe { ACPI = Acpi::new() };
+}
+
+/// The tables found by `init`, if there were any
+pub fn tables() -> Option<&'static Acpi> {
+    unsafe { ACPI.as_ref() }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct Acpi {
     rsdt: RSDT,
-    fadt: Option<FADT>,
+    pub fadt: Option<FADT>,
     dsdt: Option<DSDT>,
     ssdt: Option<SSDT>,
-    madt: Option<MADT>,
+    pub madt: Option<MADT>,
+    pub mcfg: Option<MCFG>,
+    pub hpet: Option<HPET>,
+    /// The sleep types of `\_S5`, if it was found
+    pub s5: Option<(u8, u8)>,
 }
 
 impl Acpi {
-    pub fn new() -> Option<Box<Self>> {
+    pub fn new() -> Option<Self> {
         match RSDT::new() {
             Ok(rsdt) => {
-                // debugln!("{:#?}", rsdt);
+                syslog_info!(" + ACPI: {} with {} tables",
+                             unsafe { str::from_utf8_unchecked(&rsdt.header.signature) }, rsdt.addrs.len());
 
-                let mut acpi = box Acpi {
+                let mut acpi = Acpi {
                     rsdt: rsdt,
                     fadt: None,
                     dsdt: None,
                     ssdt: None,
                     madt: None,
+                    mcfg: None,
+                    hpet: None,
+                    s5: None,
                 };
 
                 for addr in acpi.rsdt.addrs.iter() {
                     let header = unsafe { &*(*addr as *const SDTHeader) };
                     if let Some(fadt) = FADT::new(header) {
                         //Can't do it debugln!("{:#?}", fadt);
-                        if let Some(dsdt) = DSDT::new(unsafe { &*(fadt.dsdt as *const SDTHeader) }) {
+                        if let Some(dsdt) = DSDT::new(unsafe { &*(fadt.dsdt_address() as *const SDTHeader) }) {
                             syslog_debug!("DSDT:");
                             aml::parse(dsdt.data);
+                            acpi.s5 = aml::find_s5(dsdt.data);
                             acpi.dsdt = Some(dsdt);
                         }
+                        rtc::set_century_register(fadt.century);
                         acpi.fadt = Some(fadt);
                     } else if let Some(ssdt) = SSDT::new(header) {
                         syslog_debug!("SSDT:");
                         aml::parse(ssdt.data);
+                        if acpi.s5.is_none() {
+                            acpi.s5 = aml::find_s5(ssdt.data);
+                        }
                         acpi.ssdt = Some(ssdt);
                     } else if let Some(madt) = MADT::new(header) {
                         syslog_debug!("{:#?}", madt);
                         acpi.madt = Some(madt);
+                    } else if let Some(mcfg) = MCFG::new(header) {
+                        for allocation in mcfg.allocations.iter() {
+                            syslog_info!("   + ECAM: {:X} Segment: {} Buses: {}-{}",
+                                         allocation.base, allocation.segment, allocation.start_bus, allocation.end_bus);
+                        }
+                        acpi.mcfg = Some(mcfg);
+                    } else if let Some(hpet) = HPET::new(header) {
+                        syslog_debug!("HPET: {:X}", hpet.base);
+                        acpi.hpet = Some(hpet);
                     } else {
                         syslog_debug!("{}: Unknown Table", unsafe { str::from_utf8_unchecked(&header.signature) });
                     }
@@ -75,23 +119,49 @@ impl Acpi {
     }
 }
 
-impl KScheme for Acpi {
+    /// Enter S5, with the sleep types of `\_S5`, or `S5_FALLBACK` if it was not found
+    ///
+    /// Returns only if the machine did not power off.
+    pub fn poweroff(&self) {
+        match self.fadt {
+            Some(fadt) => {
+                let (slp_typ_a, slp_typ_b) = self.s5.unwrap_or(S5_FALLBACK);
+                fadt.poweroff(slp_typ_a, slp_typ_b);
+            },
+            None => debugln!("Unable to power off: No FADT"),
+        }
+    }
+}
+
+/// The `acpi:` scheme
+pub struct AcpiScheme;
+
+impl KScheme for AcpiScheme {
     fn scheme(&self) -> &'static str {
         "acpi"
     }
 
+    /// Creating `acpi:off` powers off and `acpi:reboot` restarts, after the system is shut down
     fn open(&mut self, url: &str, flags: usize) -> Result<Box<Resource>> {
-        if url.splitn(2, ":").nth(1).unwrap_or("") == "off" && flags & O_CREAT == O_CREAT {
-            match self.fadt {
-                Some(fadt) => {
-                    debugln!("Powering Off");
-                    unsafe {
-                        asm!("out dx, ax" : : "{edx}"(fadt.pm1a_control_block), "{ax}"(0 | 1 << 13) : : "intel", "volatile")
-                    };
-                }
-                None => {
-                    debugln!("Unable to power off: No FADT");
-                }
+        if flags & O_CREAT == O_CREAT {
+            match url.splitn(2, ":").nth(1).unwrap_or("") {
+                "off" => match tables() {
+                    Some(acpi) if acpi.fadt.is_some() => {
+                        ::env().shutdown(POWER_OFF);
+                        debugln!("Powering Off");
+                        acpi.poweroff();
+                        debugln!("Power off failed");
+                    },
+                    _ => {
+                        debugln!("Unable to power off: No FADT");
+                    },
+                },
+                "reboot" => {
+                    ::env().shutdown(POWER_REBOOT);
+                    debugln!("Restarting");
+                    ps2::reset();
+                },
+                _ => (),
             }
         }
 
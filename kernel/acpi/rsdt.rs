@@ -1,7 +1,11 @@
+use collections::vec::Vec;
+
+use core::{ptr, usize};
 use core::mem::size_of;
 
 use super::SDTHeader;
 
+/// The RSDP, the fields from `length` on are only there from revision 2
 #[repr(packed)]
 #[derive(Clone, Copy, Debug, Default)]
 struct RSDP {
@@ -10,61 +14,90 @@ struct RSDP {
     oemid: [u8; 6],
     revision: u8,
     addr: u32,
+    length: u32,
+    xsdt_addr: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
 }
 
 const SIGNATURE: &'static [u8] = b"RSD PTR ";
+/// The bytes the revision 1 checksum covers
+const RSDP_V1_LENGTH: usize = 20;
+
+/// The sum of `len` bytes from `ptr`, which is 0 for a valid table
+fn checksum(ptr: *const u8, len: usize) -> u8 {
+    (0..len as isize).fold(0, |sum: u8, i| sum.wrapping_add(unsafe { ptr::read(ptr.offset(i)) }))
+}
 
 impl RSDP {
+    /// Search the first KB of the EBDA, then the BIOS area, on 16 byte boundaries
     pub fn new() -> Result<Self, &'static str> {
-        // Search top of bios region
-        let mut search_ptr = 0xE0000;
-        while search_ptr < 0xFFFFF {
-            let rsdp = search_ptr as *const RSDP;
-            if unsafe { (*rsdp).valid() } {
-                return Ok(unsafe { *rsdp });
+        let mut areas = Vec::new();
+        // The BIOS data area holds the segment of the EBDA
+        let ebda = (unsafe { ptr::read(0x40E as *const u16) } as usize) << 4;
+        if ebda >= 0x80000 && ebda < 0xA0000 {
+            areas.push((ebda, ebda + 1024));
+        }
+        areas.push((0xE0000, 0x100000));
+
+        for &(start, end) in areas.iter() {
+            let mut search_ptr = start;
+            while search_ptr + RSDP_V1_LENGTH <= end {
+                let rsdp = search_ptr as *const RSDP;
+                if unsafe { (*rsdp).valid() } {
+                    return Ok(unsafe { ptr::read(rsdp) });
+                }
+                search_ptr += 16;
             }
-            search_ptr += 16;
         }
 
         Err("Did not find RSDP")
     }
 
-    // TODO: Checksum validation
+    /// Check the signature and the checksum, and from revision 2 the extended checksum of the whole RSDP
     pub fn valid(&self) -> bool {
         if self.signature == SIGNATURE {
             let ptr = (self as *const Self) as *const u8;
-            let sum = (0..size_of::<Self>() as isize)
-                          .fold(0, |sum, i| sum + unsafe { *ptr.offset(i) });
-
-            sum == 0
+            checksum(ptr, RSDP_V1_LENGTH) == 0 &&
+            (self.revision < 2 || (self.length as usize >= size_of::<Self>() && checksum(ptr, self.length as usize) == 0))
         } else {
             false
         }
     }
 }
 
-#[repr(packed)]
+/// The root table, read from the XSDT if the RSDP has one and the RSDT otherwise
 #[derive(Clone, Debug, Default)]
 pub struct RSDT {
     pub header: SDTHeader,
-    pub addrs: &'static [u32],
+    /// The addresses of the other tables, those out of reach of a 32 bit kernel are left out
+    pub addrs: Vec<usize>,
 }
 
 impl RSDT {
     pub fn new() -> Result<Self, &'static str> {
-        match RSDP::new() {
-            Ok(rsdp) => {
-                let header = rsdp.addr as *const SDTHeader;
-                if unsafe { (*header).valid("RSDT") } {
-                    Ok(RSDT {
-                        header: unsafe { *header },
-                        addrs: unsafe { (*header).data() },
-                    })
-                } else {
-                    Err("Did not find RSDT")
-                }
+        let rsdp = try!(RSDP::new());
+
+        if rsdp.revision >= 2 && rsdp.xsdt_addr != 0 && rsdp.xsdt_addr <= usize::MAX as u64 {
+            let header = rsdp.xsdt_addr as usize as *const SDTHeader;
+            if unsafe { (*header).valid("XSDT") } {
+                let addrs: &'static [u64] = unsafe { (*header).data() };
+                return Ok(RSDT {
+                    header: unsafe { *header },
+                    addrs: addrs.iter().filter(|&&addr| addr <= usize::MAX as u64).map(|&addr| addr as usize).collect(),
+                });
             }
-            Err(e) => Err(e),
+        }
+
+        let header = rsdp.addr as usize as *const SDTHeader;
+        if unsafe { (*header).valid("RSDT") } {
+            let addrs: &'static [u32] = unsafe { (*header).data() };
+            Ok(RSDT {
+                header: unsafe { *header },
+                addrs: addrs.iter().map(|&addr| addr as usize).collect(),
+            })
+        } else {
+            Err("Did not find RSDT")
         }
     }
 }
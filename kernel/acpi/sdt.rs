@@ -20,8 +20,9 @@ impl SDTHeader {
     pub fn valid(&self, signature: &str) -> bool {
         if self.signature == signature.as_bytes() {
             let ptr = (self as *const Self) as *const u8;
+            // The bytes sum to 0 modulo 256, so the sum overflows on the way
             let sum: u8 = (0..self.length as isize)
-                .fold(0, |sum, i| sum + unsafe { ptr::read(ptr.offset(i)) });
+                .fold(0, |sum: u8, i| sum.wrapping_add(unsafe { ptr::read(ptr.offset(i)) }));
 
             sum == 0
         } else {
@@ -1,6 +1,8 @@
 use arch::memory::*;
 use arch::paging::Page;
 
+use core::cmp;
+
 #[allocator]
 #[no_mangle]
 pub extern "C" fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
@@ -13,6 +15,9 @@ pub extern "C" fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
                 Page::new(virtual_address).map_kernel_write(physical_address);
             }
 
+            ALLOC_STATS.heap_allocations += 1;
+            ALLOC_STATS.heap_bytes += size;
+
             (address + LOGICAL_OFFSET) as *mut u8
         } else {
             address as *mut u8
@@ -32,6 +37,9 @@ pub extern "C" fn __rust_deallocate(ptr: *mut u8, old_size: usize, _align: usize
             let virtual_address = physical_address + LOGICAL_OFFSET;
             Page::new(virtual_address).unmap();
         }
+
+        ALLOC_STATS.heap_frees += 1;
+        ALLOC_STATS.heap_bytes -= cmp::min(ALLOC_STATS.heap_bytes, old_size);
     }
 }
 
@@ -56,6 +64,8 @@ pub extern "C" fn __rust_reallocate(ptr: *mut u8, old_size: usize, size: usize,
                 }
             }
 
+            ALLOC_STATS.heap_bytes = ALLOC_STATS.heap_bytes - cmp::min(ALLOC_STATS.heap_bytes, old_size) + size;
+
             (address + LOGICAL_OFFSET) as *mut u8
         } else {
             address as *mut u8
@@ -0,0 +1,141 @@
+use drivers::io::{Io, Mmio};
+
+/// The local APIC, at the address the MADT gives
+const LAPIC_ID: usize = 0x20;
+const LAPIC_EOI: usize = 0xB0;
+const LAPIC_SVR: usize = 0xF0;
+const LAPIC_SVR_ENABLE: u32 = 1 << 8;
+/// The local interrupt the PICs are wired to in virtual wire mode
+const LAPIC_LVT_LINT0: usize = 0x350;
+const LAPIC_LVT_MASKED: u32 = 1 << 16;
+
+/// The vector the local APIC raises when an interrupt goes away before it is delivered, which is not
+/// acknowledged
+pub const SPURIOUS_VECTOR: u8 = 0x4F;
+
+/// The IOAPIC registers, written through a select and a window register
+const IOAPIC_REGSEL: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+
+/// Redirection entry bits of the low half, fixed delivery to a physical destination
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+/// How an interrupt is signalled on its input
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Polarity {
+    High,
+    Low,
+}
+
+fn reg(base: usize, offset: usize) -> &'static mut Mmio<u32> {
+    unsafe { &mut *((base + offset) as *mut Mmio<u32>) }
+}
+
+/// The local APIC of the processor
+pub struct LocalApic {
+    base: usize,
+}
+
+impl LocalApic {
+    pub fn new(base: usize) -> Self {
+        LocalApic {
+            base: base,
+        }
+    }
+
+    /// Turn on the local APIC, so it delivers what the IOAPICs send, and cut the PICs off from it
+    pub fn enable(&mut self) {
+        reg(self.base, LAPIC_SVR).write(LAPIC_SVR_ENABLE | SPURIOUS_VECTOR as u32);
+        reg(self.base, LAPIC_LVT_LINT0).writef(LAPIC_LVT_MASKED, true);
+    }
+
+    /// The APIC ID, which the IOAPICs address this processor by
+    pub fn id(&self) -> u8 {
+        (reg(self.base, LAPIC_ID).read() >> 24) as u8
+    }
+
+    /// Acknowledge the interrupt in service, which for a level triggered one also lets its IOAPIC
+    /// raise it again
+    pub fn eoi(&mut self) {
+        reg(self.base, LAPIC_EOI).write(0);
+    }
+}
+
+/// An IOAPIC, which takes the inputs from `gsi_base` on
+pub struct IoApic {
+    pub id: u8,
+    pub base: usize,
+    pub gsi_base: u32,
+    pub inputs: u32,
+}
+
+impl IoApic {
+    pub fn new(id: u8, base: usize, gsi_base: u32) -> Self {
+        let mut ioapic = IoApic {
+            id: id,
+            base: base,
+            gsi_base: gsi_base,
+            inputs: 0,
+        };
+        // The highest redirection entry is in bits 16 to 23 of the version
+        ioapic.inputs = ((ioapic.read(IOAPIC_VERSION) >> 16) & 0xFF) + 1;
+        ioapic
+    }
+
+    fn read(&mut self, index: u32) -> u32 {
+        reg(self.base, IOAPIC_REGSEL).write(index);
+        reg(self.base, IOAPIC_WINDOW).read()
+    }
+
+    fn write(&mut self, index: u32, value: u32) {
+        reg(self.base, IOAPIC_REGSEL).write(index);
+        reg(self.base, IOAPIC_WINDOW).write(value);
+    }
+
+    /// Whether `gsi` is one of the inputs
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.inputs
+    }
+
+    /// Send `gsi` to `vector` of the local APIC `destination`
+    ///
+    /// The entry is masked while the halves are written, so it is never raised half programmed.
+    pub fn route(&mut self, gsi: u32, vector: u8, destination: u8, trigger: Trigger, polarity: Polarity, masked: bool) {
+        let index = IOAPIC_REDIRECTION + (gsi - self.gsi_base) * 2;
+
+        let mut low = vector as u32;
+        if trigger == Trigger::Level {
+            low |= REDIRECTION_LEVEL;
+        }
+        if polarity == Polarity::Low {
+            low |= REDIRECTION_ACTIVE_LOW;
+        }
+
+        self.write(index, REDIRECTION_MASKED);
+        self.write(index + 1, (destination as u32) << 24);
+        if masked {
+            self.write(index, low | REDIRECTION_MASKED);
+        } else {
+            self.write(index, low);
+        }
+    }
+
+    /// Mask every input
+    pub fn mask_all(&mut self) {
+        for input in 0..self.inputs {
+            let index = IOAPIC_REDIRECTION + input * 2;
+            let low = self.read(index);
+            self.write(index, low | REDIRECTION_MASKED);
+        }
+    }
+}
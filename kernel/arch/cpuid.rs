@@ -0,0 +1,6 @@
+/// The registers cpuid gives for `leaf` and `sub`, as eax, ebx, ecx and edx
+pub fn cpuid(leaf: u32, sub: u32) -> (u32, u32, u32, u32) {
+    let (a, b, c, d): (u32, u32, u32, u32);
+    unsafe { asm!("cpuid" : "={eax}"(a), "={ebx}"(b), "={ecx}"(c), "={edx}"(d) : "{eax}"(leaf), "{ecx}"(sub) : : "intel", "volatile") };
+    (a, b, c, d)
+}
@@ -0,0 +1,139 @@
+use core::{cmp, intrinsics, mem, ptr, slice};
+use core::ops::{Index, IndexMut};
+
+use system::error::{Error, Result, EINVAL, ENOMEM};
+
+use super::memory;
+
+/// The boundary that ISA DMA and the buffers of some controllers may not cross
+pub const BOUNDARY_64K: usize = 0x10000;
+
+/// Memory for a device to read and write, contiguous in physical memory and freed on drop
+///
+/// The device is given the physical address and the kernel uses the pointer. They are the same while
+/// allocations are identity mapped, which drivers should not count on.
+pub struct Dma<T> {
+    ptr: *mut T,
+    physical: usize,
+    length: usize,
+}
+
+/// `size` bytes of T, at a physical address aligned to `align`
+///
+/// The size must hold whole T, and the alignment be a power of two at least that of T, which debug
+/// builds assert.
+pub fn alloc_contiguous<T>(size: usize, align: usize) -> Result<Dma<T>> {
+    alloc(size, align, None)
+}
+
+/// As `alloc_contiguous`, within one aligned block of `boundary` bytes, such as `BOUNDARY_64K`
+pub fn alloc_bounded<T>(size: usize, align: usize, boundary: usize) -> Result<Dma<T>> {
+    alloc(size, align, Some(boundary))
+}
+
+fn alloc<T>(size: usize, align: usize, boundary: Option<usize>) -> Result<Dma<T>> {
+    debug_assert!(align.is_power_of_two(), "DMA: alignment {} is not a power of two", align);
+    debug_assert!(align >= mem::align_of::<T>(),
+                  "DMA: alignment {} is less than the {} of the type", align, mem::align_of::<T>());
+    debug_assert!(size >= mem::size_of::<T>() && size % cmp::max(1, mem::size_of::<T>()) == 0,
+                  "DMA: {} bytes do not hold whole elements of {} bytes", size, mem::size_of::<T>());
+
+    let mut align = align;
+    if let Some(boundary) = boundary {
+        debug_assert!(boundary.is_power_of_two() && size <= boundary,
+                      "DMA: {} bytes do not fit within a boundary of {:X}", size, boundary);
+        if ! boundary.is_power_of_two() || size > boundary {
+            return Err(Error::new(EINVAL));
+        }
+        // Aligned to the power of two it fits in, the allocation is within every larger boundary
+        align = cmp::max(align, size.next_power_of_two());
+    }
+
+    let address = unsafe { memory::alloc_aligned(size, align) };
+    if address == 0 {
+        return Err(Error::new(ENOMEM));
+    }
+    debug_assert!(address % align == 0, "DMA: {:X} is not aligned to {}", address, align);
+
+    Ok(Dma {
+        ptr: address as *mut T,
+        physical: address,
+        length: size / cmp::max(1, mem::size_of::<T>()),
+    })
+}
+
+impl<T> Dma<T> {
+    /// Get the length in T elements
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// The physical address, for the device
+    pub fn physical(&self) -> usize {
+        self.physical
+    }
+
+    /// The physical address of element `i`, for descriptors pointing into the buffer
+    pub fn physical_of(&self, i: usize) -> usize {
+        self.check_index(i);
+        self.physical + i * mem::size_of::<T>()
+    }
+
+    #[inline(always)]
+    fn check_index(&self, i: usize) {
+        if i >= self.length {
+            panic!("Dma: {} >= {}", i, self.length);
+        }
+    }
+
+    /// Read element `i` after what the device wrote before it
+    pub fn load(&self, i: usize) -> T {
+        self.check_index(i);
+        unsafe {
+            intrinsics::atomic_singlethreadfence();
+            ptr::read(self.ptr.offset(i as isize))
+        }
+    }
+
+    /// Borrow as a slice
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.ptr, self.length) }
+    }
+
+    /// Borrow as a mutable slice
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.length) }
+    }
+
+    /// Borrow as a raw pointer
+    pub unsafe fn as_ptr(&self) -> *const T {
+        self.ptr as *const T
+    }
+
+    /// Borrow as a mutable raw pointer
+    pub unsafe fn as_mut_ptr(&mut self) -> *mut T {
+        self.ptr
+    }
+}
+
+impl<T> Drop for Dma<T> {
+    fn drop(&mut self) {
+        unsafe { memory::unalloc(self.ptr as usize) };
+    }
+}
+
+impl<T> Index<usize> for Dma<T> {
+    type Output = T;
+
+    fn index(&self, i: usize) -> &T {
+        self.check_index(i);
+        unsafe { &*self.ptr.offset(i as isize) }
+    }
+}
+
+impl<T> IndexMut<usize> for Dma<T> {
+    fn index_mut(&mut self, i: usize) -> &mut T {
+        self.check_index(i);
+        unsafe { &mut *self.ptr.offset(i as isize) }
+    }
+}
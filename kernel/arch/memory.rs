@@ -14,6 +14,36 @@ pub const CLUSTER_SIZE: usize = 4096; // Of 4 K chunks
 
 pub const LOGICAL_OFFSET: usize = 0x80000000;
 
+/// Counters kept by the cluster allocator, so reading them does not scan every cluster
+#[derive(Copy, Clone)]
+pub struct AllocStats {
+    /// Clusters that were free after `cluster_init`
+    pub total: usize,
+    /// Clusters allocated now, and the most that ever were
+    pub used: usize,
+    pub peak: usize,
+    /// Calls to `alloc_aligned` that succeeded and failed, and allocations given back to `unalloc`
+    pub allocations: u64,
+    pub failures: u64,
+    pub frees: u64,
+    /// Allocations and frees by the kernel heap, and the bytes it asked for that are still allocated
+    pub heap_allocations: u64,
+    pub heap_frees: u64,
+    pub heap_bytes: usize,
+}
+
+pub static mut ALLOC_STATS: AllocStats = AllocStats {
+    total: 0,
+    used: 0,
+    peak: 0,
+    allocations: 0,
+    failures: 0,
+    frees: 0,
+    heap_allocations: 0,
+    heap_frees: 0,
+    heap_bytes: 0,
+};
+
 pub unsafe fn copy_pages(dst: *mut u8, src: *const u8, size: usize) {
     let read_cluster = address_to_cluster(src as usize);
     let write_cluster = address_to_cluster(dst as usize);
@@ -262,6 +292,8 @@ pub unsafe fn cluster_init() {
             }
         }
     }
+
+    ALLOC_STATS.total = (0..CLUSTER_COUNT).filter(|&i| cluster(i) == 0).count();
 }
 
 /// Allocate memory
@@ -309,8 +341,14 @@ pub unsafe fn alloc_aligned(size: usize, align: usize) -> usize {
                 page.flush();
             }
 
+            ALLOC_STATS.used += count;
+            ALLOC_STATS.peak = cmp::max(ALLOC_STATS.peak, ALLOC_STATS.used);
+            ALLOC_STATS.allocations += 1;
+
             return address;
         }
+
+        ALLOC_STATS.failures += 1;
     }
 
     0
@@ -339,13 +377,20 @@ pub unsafe fn alloc_size(ptr: usize) -> usize {
 
 pub unsafe fn unalloc(ptr: usize) {
     if ptr > 0 {
+        let mut count = 0;
         for i in address_to_cluster(ptr)..CLUSTER_COUNT {
             if cluster(i) == ptr {
                 set_cluster(i, 0);
+                count += 1;
             } else {
                 break;
             }
         }
+
+        if count > 0 {
+            ALLOC_STATS.used -= cmp::min(ALLOC_STATS.used, count);
+            ALLOC_STATS.frees += 1;
+        }
     }
 }
 
@@ -392,22 +437,20 @@ pub unsafe fn realloc_inplace(ptr: usize, size: usize) -> usize {
     }
 }
 
+/// The allocator counters as they are now
+pub fn alloc_stats() -> AllocStats {
+    unsafe { ALLOC_STATS }
+}
+
+pub fn memory_total() -> usize {
+    alloc_stats().total * CLUSTER_SIZE
+}
+
 pub fn memory_used() -> usize {
-    (0..CLUSTER_COUNT).fold(0usize, |ret, i| unsafe {
-        if cluster(i) != 0 && cluster(i) != 0xFFFFFFFF {
-            ret + CLUSTER_SIZE
-        } else {
-            ret
-        }
-    })
+    alloc_stats().used * CLUSTER_SIZE
 }
 
 pub fn memory_free() -> usize {
-    (0..CLUSTER_COUNT).fold(0usize, |ret, i| unsafe {
-        if cluster(i) == 0 {
-            ret + CLUSTER_SIZE
-        } else {
-            ret
-        }
-    })
+    let stats = alloc_stats();
+    (stats.total - cmp::min(stats.total, stats.used)) * CLUSTER_SIZE
 }
@@ -1,8 +1,13 @@
+pub mod apic;
 pub mod context;
+pub mod cpuid;
+pub mod dma;
 pub mod elf;
 pub mod gdt;
 pub mod idt;
 pub mod memory;
+pub mod mptable;
 pub mod paging;
 pub mod regs;
+pub mod timekeeping;
 pub mod tss;
@@ -0,0 +1,139 @@
+use collections::vec::Vec;
+
+use core::ptr;
+use core::mem::size_of;
+
+/// The MP floating pointer structure of the MultiProcessor Specification
+#[repr(packed)]
+#[derive(Clone, Copy, Debug, Default)]
+struct FloatingPointer {
+    signature: [u8; 4],
+    config: u32,
+    length: u8,
+    revision: u8,
+    checksum: u8,
+    features: [u8; 5],
+}
+
+/// The header of the configuration table the floating pointer points to, the entries follow it
+#[repr(packed)]
+#[derive(Clone, Copy, Debug, Default)]
+struct ConfigHeader {
+    signature: [u8; 4],
+    length: u16,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 8],
+    product_id: [u8; 12],
+    oem_table: u32,
+    oem_table_size: u16,
+    entries: u16,
+    local_apic: u32,
+    extended_length: u16,
+    extended_checksum: u8,
+    reserved: u8,
+}
+
+const ENTRY_PROCESSOR: u8 = 0;
+const ENTRY_BUS: u8 = 1;
+const ENTRY_INTERRUPT: u8 = 3;
+/// Processor entries are 20 bytes, the others 8
+const PROCESSOR_LENGTH: usize = 20;
+const ENTRY_LENGTH: usize = 8;
+/// A vectored interrupt, as opposed to NMI, SMI or ExtINT
+const INTERRUPT_INT: u8 = 0;
+
+/// Where a PCI interrupt pin is wired to, from an I/O interrupt assignment entry of a PCI bus
+#[derive(Clone, Copy, Debug)]
+pub struct PciInterrupt {
+    pub bus: u8,
+    pub slot: u8,
+    /// 0 for INTA# to 3 for INTD#
+    pub pin: u8,
+    pub ioapic: u8,
+    pub input: u8,
+    /// The polarity and trigger flags, laid out as in the MADT
+    pub flags: u16,
+}
+
+fn checksum(ptr: *const u8, len: usize) -> u8 {
+    (0..len as isize).fold(0, |sum: u8, i| sum.wrapping_add(unsafe { ptr::read(ptr.offset(i)) }))
+}
+
+/// Search the first KB of the EBDA, the last KB of base memory and the BIOS area for the floating
+/// pointer
+fn floating_pointer() -> Option<FloatingPointer> {
+    let mut areas = Vec::new();
+    let ebda = (unsafe { ptr::read(0x40E as *const u16) } as usize) << 4;
+    if ebda >= 0x80000 && ebda < 0xA0000 {
+        areas.push((ebda, ebda + 1024));
+    }
+    areas.push((0x9FC00, 0xA0000));
+    areas.push((0xF0000, 0x100000));
+
+    for &(start, end) in areas.iter() {
+        let mut search_ptr = start;
+        while search_ptr + 16 <= end {
+            let pointer = unsafe { ptr::read(search_ptr as *const FloatingPointer) };
+            if &pointer.signature == b"_MP_" && checksum(search_ptr as *const u8, 16) == 0 {
+                return Some(pointer);
+            }
+            search_ptr += 16;
+        }
+    }
+
+    None
+}
+
+/// The PCI interrupt assignments of the MP table, which route PCI devices to IOAPIC inputs without AML
+/// to evaluate
+///
+/// There are none if there is no table, or it gives only a default configuration.
+pub fn pci_interrupts() -> Vec<PciInterrupt> {
+    let mut interrupts = Vec::new();
+
+    let pointer = match floating_pointer() {
+        Some(pointer) if pointer.config != 0 => pointer,
+        _ => return interrupts,
+    };
+
+    let base = pointer.config as usize;
+    let header = unsafe { ptr::read(base as *const ConfigHeader) };
+    if &header.signature != b"PCMP" || checksum(base as *const u8, header.length as usize) != 0 {
+        return interrupts;
+    }
+
+    let mut pci_buses = Vec::new();
+    let end = base + header.length as usize;
+    let mut entry = base + size_of::<ConfigHeader>();
+    for _ in 0..header.entries {
+        let entry_type = unsafe { ptr::read(entry as *const u8) };
+        let length = if entry_type == ENTRY_PROCESSOR { PROCESSOR_LENGTH } else { ENTRY_LENGTH };
+        if entry + length > end {
+            break;
+        }
+
+        let bytes = unsafe { ptr::read(entry as *const [u8; ENTRY_LENGTH]) };
+        match entry_type {
+            ENTRY_BUS => if &bytes[2..5] == b"PCI" {
+                pci_buses.push(bytes[1]);
+            },
+            ENTRY_INTERRUPT => if bytes[1] == INTERRUPT_INT && pci_buses.contains(&bytes[4]) {
+                // The bus IRQ of a PCI bus is the device in bits 2 to 6 and the pin in bits 0 and 1
+                interrupts.push(PciInterrupt {
+                    bus: bytes[4],
+                    slot: (bytes[5] >> 2) & 0x1F,
+                    pin: bytes[5] & 0x3,
+                    ioapic: bytes[6],
+                    input: bytes[7],
+                    flags: bytes[2] as u16 | (bytes[3] as u16) << 8,
+                });
+            },
+            _ => (),
+        }
+
+        entry += length;
+    }
+
+    interrupts
+}
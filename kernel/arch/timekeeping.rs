@@ -0,0 +1,221 @@
+use collections::string::String;
+
+use common::random::rdtsc;
+use common::time::{Duration, NANOS_PER_MILLI, NANOS_PER_SEC};
+
+use drivers::io::{Io, Mmio, Pio};
+
+use super::context::context_switch;
+use super::cpuid::cpuid;
+
+/// The input clock of the PIT
+const PIT_HZ: u64 = 1193182;
+/// The count the PIT runs down from to calibrate the TSC, about 55 ms
+const PIT_CALIBRATION_COUNT: u64 = 0xFFFF;
+/// Port reads the PIT is waited for before it is taken to be missing, several times the calibration
+const PIT_CALIBRATION_SPINS: usize = 10000000;
+
+const HPET_CAPABILITIES: usize = 0;
+const HPET_CONFIG: usize = 0x10;
+const HPET_COUNTER: usize = 0xF0;
+const HPET_COUNT_SIZE_64: u64 = 1 << 13;
+const HPET_ENABLE: u64 = 1;
+/// The longest counter period the specification allows, in femtoseconds
+const HPET_MAX_PERIOD: u64 = 100000000;
+
+/// What the monotonic clock is read from
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ClockSource {
+    /// The timestamp counter, used only if it runs at the same rate in every power state
+    Tsc,
+    /// The main counter of the HPET
+    Hpet,
+    /// The PIT interrupts, counted by the handler, which are as fine as the clock gets without the others
+    Pit,
+}
+
+struct Clock {
+    source: ClockSource,
+    /// Counts per second of the source
+    hz: u64,
+    /// The HPET registers
+    hpet: usize,
+    /// The count of the source when it took over, and the monotonic clock then
+    base_count: u64,
+    base_nanos: u64,
+}
+
+static mut CLOCK: Clock = Clock {
+    source: ClockSource::Pit,
+    hz: 0,
+    hpet: 0,
+    base_count: 0,
+    base_nanos: 0,
+};
+
+/// The nanoseconds counted by the PIT interrupt
+fn pit_nanos() -> u64 {
+    let clock = unsafe { *::env().clock_monotonic.get() };
+    clock.secs as u64 * NANOS_PER_SEC as u64 + clock.nanos as u64
+}
+
+/// `counts` of a source running at `hz` in nanoseconds, without overflowing for any count a source reaches
+fn nanos(counts: u64, hz: u64) -> u64 {
+    counts / hz * NANOS_PER_SEC as u64 + counts % hz * NANOS_PER_SEC as u64 / hz
+}
+
+/// Whether there is a TSC, and it keeps its rate through frequency changes and sleep states
+fn invariant_tsc() -> bool {
+    let (_, _, _, features) = cpuid(1, 0);
+    let (max_extended, _, _, _) = cpuid(0x80000000, 0);
+    features & 1 << 4 == 1 << 4 && max_extended >= 0x80000007 && cpuid(0x80000007, 0).3 & 1 << 8 == 1 << 8
+}
+
+/// The rate of the TSC, measured over a countdown of channel 2 of the PIT
+///
+/// Channel 2 drives the speaker, which is kept off while it counts.
+fn calibrate_tsc() -> Option<u64> {
+    let mut gate = Pio::<u8>::new(0x61);
+    let old = gate.read();
+    gate.write((old & ! 0x02) | 0x01);
+
+    // Mode 0 raises the output when the count runs out
+    Pio::<u8>::new(0x43).write(0xB0);
+    Pio::<u8>::new(0x42).write(PIT_CALIBRATION_COUNT as u8);
+    Pio::<u8>::new(0x42).write((PIT_CALIBRATION_COUNT >> 8) as u8);
+
+    let start = rdtsc();
+    let mut spins = 0;
+    while gate.read() & 0x20 == 0 && spins < PIT_CALIBRATION_SPINS {
+        spins += 1;
+    }
+    let end = rdtsc();
+    gate.write(old);
+
+    if spins >= PIT_CALIBRATION_SPINS || end <= start {
+        None
+    } else {
+        Some((end - start) * PIT_HZ / PIT_CALIBRATION_COUNT)
+    }
+}
+
+fn hpet_reg(base: usize, offset: usize) -> &'static mut Mmio<u64> {
+    unsafe { &mut *((base + offset) as *mut Mmio<u64>) }
+}
+
+/// Start the main counter of the HPET at `base`, returning its rate, if it is 64 bits wide
+///
+/// A 32 bit counter wraps within minutes, which would have to be watched for.
+fn start_hpet(base: usize) -> Option<u64> {
+    let capabilities = hpet_reg(base, HPET_CAPABILITIES).read();
+    let period = capabilities >> 32;
+    if period == 0 || period > HPET_MAX_PERIOD || capabilities & HPET_COUNT_SIZE_64 != HPET_COUNT_SIZE_64 {
+        return None;
+    }
+
+    hpet_reg(base, HPET_CONFIG).writef(HPET_ENABLE, true);
+    Some(1000000000000000 / period)
+}
+
+/// Choose the clock source, the TSC if it is invariant, then the HPET at `hpet`, then the PIT
+///
+/// The monotonic clock carries on from what the PIT counted before.
+pub fn init(hpet: Option<usize>) {
+    let clock = unsafe { &mut CLOCK };
+
+    if invariant_tsc() {
+        if let Some(hz) = calibrate_tsc() {
+            clock.source = ClockSource::Tsc;
+            clock.hz = hz;
+            clock.base_count = rdtsc();
+            clock.base_nanos = pit_nanos();
+            syslog_info!(" + Clock: invariant TSC at {}.{:>03} MHz", hz / 1000000, hz / 1000 % 1000);
+            return;
+        }
+        syslog_warning!(" + Clock: TSC calibration failed, the PIT did not count down");
+    }
+
+    if let Some(base) = hpet {
+        if let Some(hz) = start_hpet(base) {
+            clock.source = ClockSource::Hpet;
+            clock.hz = hz;
+            clock.hpet = base;
+            clock.base_count = hpet_reg(base, HPET_COUNTER).read();
+            clock.base_nanos = pit_nanos();
+            syslog_info!(" + Clock: HPET at {:X}, {}.{:>03} MHz", base, hz / 1000000, hz / 1000 % 1000);
+            return;
+        }
+    }
+
+    syslog_info!(" + Clock: PIT interrupts, no invariant TSC or 64 bit HPET");
+}
+
+/// The nanoseconds since boot, as fine as the clock source gives
+pub fn monotonic_ns() -> u64 {
+    let clock = unsafe { &CLOCK };
+    match clock.source {
+        ClockSource::Tsc => clock.base_nanos + nanos(rdtsc().wrapping_sub(clock.base_count), clock.hz),
+        ClockSource::Hpet => {
+            let count = hpet_reg(clock.hpet, HPET_COUNTER).read();
+            clock.base_nanos + nanos(count.wrapping_sub(clock.base_count), clock.hz)
+        },
+        ClockSource::Pit => pit_nanos(),
+    }
+}
+
+/// The clock source
+pub fn source() -> ClockSource {
+    unsafe { CLOCK.source }
+}
+
+/// Check `done` until it is true or `ms` milliseconds pass, returning whether it became true
+///
+/// This spins, for the waits on devices that are made before interrupts are enabled. The PIT count does
+/// not move then, so with only the PIT each check is taken as a microsecond instead.
+pub fn spin_for<F: FnMut() -> bool>(ms: u64, mut done: F) -> bool {
+    if source() == ClockSource::Pit {
+        for _ in 0..ms * 1000 {
+            if done() {
+                return true;
+            }
+        }
+    } else {
+        let end = monotonic_ns() + ms * 1000000;
+        while monotonic_ns() < end {
+            if done() {
+                return true;
+            }
+        }
+    }
+    done()
+}
+
+/// Sleep the current context for `ms` milliseconds, `reason` says what it waits for
+///
+/// Outside of a context, before the first is made, it returns at once.
+pub fn sleep(ms: u64, reason: &str) {
+    unsafe {
+        {
+            let contexts = &mut *::env().contexts.get();
+            if let Ok(mut current) = contexts.current_mut() {
+                current.wake = Some(Duration::monotonic() + Duration::new((ms / 1000) as i64, (ms % 1000) as i32 * NANOS_PER_MILLI));
+                current.block(reason);
+            } else {
+                return;
+            }
+        }
+
+        context_switch();
+    }
+}
+
+/// The clock source and its rate, for time:/clock
+pub fn clock_status() -> String {
+    let clock = unsafe { &CLOCK };
+    let mut string = format!("source: {:?}\n", clock.source);
+    if clock.source != ClockSource::Pit {
+        string.push_str(&format!("hz: {}\n", clock.hz));
+    }
+    string.push_str(&format!("monotonic: {}\n", monotonic_ns()));
+    string
+}
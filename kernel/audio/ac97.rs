@@ -1,210 +1,676 @@
+use alloc::arc::Arc;
 use alloc::boxed::Box;
 
-use arch::context::context_switch;
+use collections::string::String;
+use collections::vec::Vec;
+use collections::vec_deque::VecDeque;
+
+use arch::context::{context_switch, Context};
 use arch::memory;
+use arch::timekeeping;
 
-use common::time::{self, Duration};
+use common::to_num::ToNum;
 
-use core::{cmp, mem};
+use core::cell::UnsafeCell;
+use core::{cmp, mem, str};
 
 use drivers::pci::config::PciConfig;
 use drivers::io::{Io, Mmio, Pio, PhysAddr};
 
-use fs::{KScheme, Resource};
+use env::log::InterruptGuard;
+
+use fs::Resource;
 
 use syscall;
 
+use super::{AudioDevice, Converter, Format, PlaybackRing};
+
+const MASTER_VOLUME: u16 = 0x02;
+const PCM_VOLUME: u16 = 0x18;
+/// The mute bit of a volume register
+const VOLUME_MUTE: u16 = 1 << 15;
+/// The attenuation of PCM out that is 0 dB, lower values are gain
+const PCM_0DB: u16 = 0x08;
+const PCM_MAX: u16 = 0x1F;
+const RECORD_SELECT: u16 = 0x1A;
+const RECORD_GAIN: u16 = 0x1C;
+/// The record select sources, the same for both channels
+const RECORD_MIC: u16 = 0;
+const RECORD_LINE: u16 = 4;
+const RECORD_GAIN_MAX: u16 = 0xF;
+
+const EXT_AUDIO_ID: u16 = 0x28;
+const EXT_AUDIO_CTRL: u16 = 0x2A;
+const FRONT_DAC_RATE: u16 = 0x2C;
+/// Variable rate audio, in the extended audio id and control registers
+const EXT_VRA: u16 = 1;
+
+/// The PCM out engine, in bus master space
+const PO_BDBAR: u16 = 0x10;
+const PO_CIV: u16 = 0x14;
+const PO_LVI: u16 = 0x15;
+const PO_SR: u16 = 0x16;
+/// Samples left in the current buffer
+const PO_PICB: u16 = 0x18;
+const PO_CR: u16 = 0x1B;
+
+/// Buffers in the ring the PCM out engine plays, and the bytes in each, about 340 ms at 48 kHz
+const PLAYBACK_BUFFERS: usize = 32;
+const PLAYBACK_SIZE: usize = 2048;
+
+/// The PCM in engine, in bus master space
+const PI_BDBAR: u16 = 0x00;
+const PI_CIV: u16 = 0x04;
+const PI_LVI: u16 = 0x05;
+const PI_SR: u16 = 0x06;
+const PI_CR: u16 = 0x0B;
+/// The engine stopped, because it reached the last valid buffer
+const SR_DCH: u16 = 1;
+const CR_RPBM: u8 = 1;
+const CR_RR: u8 = 1 << 1;
+
+/// Buffers in the ring the PCM in engine fills, and the bytes in each
+const CAPTURE_BUFFERS: usize = 32;
+const CAPTURE_SIZE: usize = 4096;
+/// Bytes recorded and not read yet, the oldest are dropped past this
+const CAPTURE_MAX: usize = 65536;
+
+/// Global status, in bus master space
+const GLOB_STA: u16 = 0x30;
+const GLOB_STA_CODEC_READY: u32 = 1 << 8;
+
+/// Wait until the primary codec takes register accesses, for at most about 100 ms of port reads
+fn codec_ready(bus_master: usize) -> bool {
+    let glob_sta = Pio::<u32>::new(bus_master as u16 + GLOB_STA);
+    for _ in 0..100000 {
+        if glob_sta.read() & GLOB_STA_CODEC_READY == GLOB_STA_CODEC_READY {
+            return true;
+        }
+    }
+    false
+}
+
+/// The mixer registers of the codec, as percentages of full volume
+#[derive(Copy, Clone)]
+struct Mixer {
+    audio: usize,
+    bus_master: usize,
+    /// The highest attenuation of the master volume, 0x3F on codecs with 6 bits and 0x1F on those with 5
+    master_max: u16,
+}
+
+impl Mixer {
+    /// Find out how many bits the master volume has
+    ///
+    /// A codec with 5 bits reads back 1xxxxx written to the attenuation as 011111.
+    unsafe fn new(audio: usize, bus_master: usize) -> Mixer {
+        let mut mixer = Mixer {
+            audio: audio,
+            bus_master: bus_master,
+            master_max: 0x1F,
+        };
+
+        if codec_ready(bus_master) {
+            let mut master = Pio::<u16>::new(audio as u16 + MASTER_VOLUME);
+            let value = master.read();
+            master.write(VOLUME_MUTE | 0x2020);
+            if master.read() & 0x3F == 0x20 {
+                mixer.master_max = 0x3F;
+            }
+            master.write(value);
+        } else {
+            syslog_warning!("AC97: Codec not ready");
+        }
+
+        mixer
+    }
+
+    /// The percentage and mute of the volume register at `reg`, from its left channel
+    fn get(&self, reg: u16, full: u16, max: u16) -> (usize, bool) {
+        if ! codec_ready(self.bus_master) {
+            return (0, true);
+        }
+
+        let value = Pio::<u16>::new(self.audio as u16 + reg).read();
+        let attenuation = cmp::min(cmp::max((value >> 8) & max, full), max);
+        (((max - attenuation) as usize * 100 + (max - full) as usize / 2) / (max - full) as usize,
+         value & VOLUME_MUTE == VOLUME_MUTE)
+    }
+
+    /// Set both channels of the volume register at `reg`, on the DAC side only, so playback goes on as it is
+    fn set(&self, reg: u16, full: u16, max: u16, percent: usize, mute: bool) -> bool {
+        if ! codec_ready(self.bus_master) {
+            return false;
+        }
+
+        let attenuation = max - ((cmp::min(percent, 100) * (max - full) as usize + 50) / 100) as u16;
+        let value = if mute { VOLUME_MUTE } else { 0 } | attenuation << 8 | attenuation;
+        Pio::<u16>::new(self.audio as u16 + reg).write(value);
+        true
+    }
+
+    fn master(&self) -> (usize, bool) {
+        self.get(MASTER_VOLUME, 0, self.master_max)
+    }
+
+    fn pcm(&self) -> (usize, bool) {
+        self.get(PCM_VOLUME, PCM_0DB, PCM_MAX)
+    }
+
+    /// The record gain, as a percentage of the most the codec has
+    fn record(&self) -> usize {
+        if ! codec_ready(self.bus_master) {
+            return 0;
+        }
+
+        let value = Pio::<u16>::new(self.audio as u16 + RECORD_GAIN).read();
+        ((value >> 8) & RECORD_GAIN_MAX) as usize * 100 / RECORD_GAIN_MAX as usize
+    }
+
+    fn set_record(&self, percent: usize) -> bool {
+        if ! codec_ready(self.bus_master) {
+            return false;
+        }
+
+        let gain = ((cmp::min(percent, 100) * RECORD_GAIN_MAX as usize + 50) / 100) as u16;
+        Pio::<u16>::new(self.audio as u16 + RECORD_GAIN).write(gain << 8 | gain);
+        true
+    }
+
+    /// Whether the microphone is recorded, instead of line in
+    fn mic(&self) -> bool {
+        codec_ready(self.bus_master) && Pio::<u16>::new(self.audio as u16 + RECORD_SELECT).read() & 0b111 == RECORD_MIC
+    }
+
+    fn set_mic(&self, mic: bool) -> bool {
+        if ! codec_ready(self.bus_master) {
+            return false;
+        }
+
+        let source = if mic { RECORD_MIC } else { RECORD_LINE };
+        Pio::<u16>::new(self.audio as u16 + RECORD_SELECT).write(source << 8 | source);
+        true
+    }
+
+    fn status(&self) -> String {
+        let (master, mute) = self.master();
+        let (pcm, _) = self.pcm();
+        format!("master: {}\npcm: {}\nmute: {}\nrecord: {}\ninput: {}\n",
+                master, pcm, if mute { "on" } else { "off" }, self.record(), if self.mic() { "mic" } else { "line" })
+    }
+}
+
 #[repr(packed)]
 struct Bd {
     ptr: PhysAddr<Mmio<u32>>,
     samples: Mmio<u32>,
 }
 
-struct Ac97Resource {
+/// The PCM out engine, which plays a ring of buffers round and round while a stream is open
+struct Playback {
     audio: usize,
     bus_master: usize,
     bdl: *mut Bd,
+    /// Allocated when the first stream is opened
+    ring: Option<PlaybackRing>,
+    /// The rate the codec plays at
+    rate: u32,
+    running: bool,
 }
 
-impl Resource for Ac97Resource {
-    fn dup(&self) -> syscall::Result<Box<Resource>> {
-        Ok(box Ac97Resource {
-            audio: self.audio,
-            bus_master: self.bus_master,
-            bdl: self.bdl
-        })
+impl Playback {
+    /// Where the engine is in the ring, from the current buffer and the samples left in it
+    fn position(&self) -> usize {
+        let bus_master = self.bus_master as u16;
+        let civ = Pio::<u8>::new(bus_master + PO_CIV).read() as usize % PLAYBACK_BUFFERS;
+        let picb = Pio::<u16>::new(bus_master + PO_PICB).read() as usize;
+        // Nothing is left before the engine loads a buffer
+        if picb == 0 {
+            civ * PLAYBACK_SIZE
+        } else {
+            civ * PLAYBACK_SIZE + PLAYBACK_SIZE.saturating_sub(picb * 2)
+        }
     }
 
-    fn path(&self, buf: &mut [u8]) -> syscall::Result <usize> {
-        let path = b"audio:";
+    /// Silence what was played, and keep the last valid buffer just behind the engine so it goes round
+    fn update(&mut self) {
+        if ! self.running {
+            return;
+        }
 
-        for (b, p) in buf.iter_mut().zip(path.iter()) {
-            *b = *p;
+        let position = self.position();
+        if let Some(ref mut ring) = self.ring {
+            ring.update(position);
         }
 
-        Ok(cmp::min(buf.len(), path.len()))
+        let bus_master = self.bus_master as u16;
+        let civ = Pio::<u8>::new(bus_master + PO_CIV).read() as usize;
+        Pio::<u8>::new(bus_master + PO_LVI).write(((civ + PLAYBACK_BUFFERS - 1) % PLAYBACK_BUFFERS) as u8);
+        let mut po_sr = Pio::<u16>::new(bus_master + PO_SR);
+        if po_sr.read() & SR_DCH == SR_DCH {
+            // The status bits are cleared by writing them
+            po_sr.write(0x1C);
+            Pio::<u8>::new(bus_master + PO_CR).write(CR_RPBM);
+        }
     }
 
-    fn write(&mut self, buf: &[u8]) -> syscall::Result<usize> {
-        unsafe {
-            let audio = self.audio as u16;
+    /// Reset the engine and start it on a silent ring, at `rate` if the codec has variable rates
+    unsafe fn start(&mut self, rate: u32, vra: bool) -> syscall::Result<()> {
+        if self.ring.is_none() {
+            self.ring = Some(try!(PlaybackRing::new(PLAYBACK_BUFFERS * PLAYBACK_SIZE, 8, PLAYBACK_SIZE)));
+        }
+        self.stop();
 
-            let mut master_volume = Pio::<u16>::new(audio + 2);
-            let mut pcm_volume = Pio::<u16>::new(audio + 0x18);
+        let bus_master = self.bus_master as u16;
+        let mut po_cr = Pio::<u8>::new(bus_master + PO_CR);
+        po_cr.write(CR_RR);
+        while po_cr.read() & CR_RR == CR_RR {
+            context_switch();
+        }
 
-            debugln!("MASTER {:X} PCM {:X}", master_volume.read(), pcm_volume.read());
+        // The rate only changes while the engine is stopped
+        if vra {
+            let mut ext_ctrl = Pio::<u16>::new(self.audio as u16 + EXT_AUDIO_CTRL);
+            let value = ext_ctrl.read();
+            ext_ctrl.write(value | EXT_VRA);
+            Pio::<u16>::new(self.audio as u16 + FRONT_DAC_RATE).write(rate as u16);
+        }
+        self.rate = rate;
 
-            master_volume.write(0);
-            pcm_volume.write(0x808);
+        if let Some(ref mut ring) = self.ring {
+            ring.reset();
+            for i in 0..PLAYBACK_BUFFERS {
+                (*self.bdl.offset(i as isize)).ptr.write((ring.data.address() + i * PLAYBACK_SIZE) as u32);
+                (*self.bdl.offset(i as isize)).samples.write((PLAYBACK_SIZE / 2) as u32);
+            }
+        }
+        let mut po_bdbar = PhysAddr::new(Pio::<u32>::new(bus_master + PO_BDBAR));
+        po_bdbar.write(self.bdl as u32);
+        Pio::<u8>::new(bus_master + PO_LVI).write((PLAYBACK_BUFFERS - 1) as u8);
+        po_cr.write(CR_RPBM);
+        self.running = true;
 
-            debugln!("MASTER {:X} PCM {:X}", master_volume.read(), pcm_volume.read());
+        Ok(())
+    }
 
-            let bus_master = self.bus_master as u16;
+    /// Stop the engine, dropping what was not played
+    fn stop(&mut self) {
+        Pio::<u8>::new(self.bus_master as u16 + PO_CR).write(0);
+        self.running = false;
+        if let Some(ref mut ring) = self.ring {
+            ring.clear();
+        }
+    }
 
-            let po_civ = Pio::<u8>::new(bus_master + 0x14);
-            let mut po_lvi = Pio::<u8>::new(bus_master + 0x15);
-            let mut po_cr = Pio::<u8>::new(bus_master + 0x1B);
+    fn write(&mut self, buf: &[u8]) -> usize {
+        self.update();
+        self.ring.as_mut().map_or(0, |ring| ring.write(buf))
+    }
 
-            loop {
-                if po_cr.read() & 1 == 0 {
-                    break;
-                }
-                context_switch();
-            }
+    fn space(&mut self) -> usize {
+        self.update();
+        self.ring.as_ref().map_or(0, |ring| ring.space())
+    }
 
-            po_cr.write(0);
+    fn queued(&mut self) -> usize {
+        self.update();
+        self.ring.as_ref().map_or(0, |ring| ring.queued())
+    }
+}
 
-            for i in 0..32 {
-                (*self.bdl.offset(i)).ptr.write(0);
-                (*self.bdl.offset(i)).samples.write(0);
-            }
+/// The mixer, read as one line for each control and written as `name=value` settings like `master=80 mute=off`
+struct Ac97MixerResource {
+    mixer: Mixer,
+    seek: usize,
+}
+
+impl Resource for Ac97MixerResource {
+    fn dup(&self) -> syscall::Result<Box<Resource>> {
+        Ok(box Ac97MixerResource {
+            mixer: self.mixer,
+            seek: self.seek,
+        })
+    }
 
-            let mut wait = false;
-            let mut position = 0;
+    fn path(&self, buf: &mut [u8]) -> syscall::Result <usize> {
+        let path = b"audio:/mixer";
 
-            let mut lvi = po_lvi.read();
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
 
-            let start_lvi;
-            if lvi == 0 {
-                start_lvi = 31;
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> syscall::Result<usize> {
+        let status = self.mixer.status();
+        let data = status.as_bytes();
+
+        let mut i = 0;
+        while i < buf.len() && self.seek < data.len() {
+            buf[i] = data[self.seek];
+            i += 1;
+            self.seek += 1;
+        }
+
+        Ok(i)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> syscall::Result<usize> {
+        let (mut master, mut mute) = self.mixer.master();
+        let (mut pcm, _) = self.mixer.pcm();
+        let mut record = self.mixer.record();
+        let mut mic = self.mixer.mic();
+        for setting in str::from_utf8(buf).unwrap_or("").split_whitespace() {
+            let mut parts = setting.splitn(2, '=');
+            let (name, value) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+            let percent = if value.chars().all(|c| c.is_digit(10)) && ! value.is_empty() {
+                Some(cmp::min(value.to_num(), 100))
             } else {
-                start_lvi = lvi - 1;
+                None
+            };
+            match (name, percent, value) {
+                ("master", Some(percent), _) => master = percent,
+                ("pcm", Some(percent), _) => pcm = percent,
+                ("mute", _, "on") => mute = true,
+                ("mute", _, "off") => mute = false,
+                ("record", Some(percent), _) => record = percent,
+                ("input", _, "mic") => mic = true,
+                ("input", _, "line") => mic = false,
+                _ => return Err(syscall::Error::new(syscall::EINVAL)),
             }
+        }
+
+        // Mute is kept on the master volume, PCM out stays unmuted
+        if ! self.mixer.set(MASTER_VOLUME, 0, self.mixer.master_max, master, mute)
+            || ! self.mixer.set(PCM_VOLUME, PCM_0DB, PCM_MAX, pcm, false)
+            || ! self.mixer.set_record(record) || ! self.mixer.set_mic(mic) {
+            return Err(syscall::Error::new(syscall::EIO));
+        }
+        self.seek = 0;
+
+        Ok(buf.len())
+    }
+}
+
+/// What the PCM in engine has recorded, shared by the recording thread and the resources reading it
+struct Capture {
+    bus_master: usize,
+    bdl: *mut Bd,
+    /// The buffers of the ring, one after the other
+    buffers: *mut u8,
+    /// Added to by the recording thread and taken from by readers, with interrupts disabled
+    data: VecDeque<u8>,
+    /// Bytes dropped because they were not read in time
+    dropped: usize,
+    /// The open record resources, the engine stops once there are none
+    readers: usize,
+    running: bool,
+}
 
-            lvi += 1;
-            if lvi >= 32 {
-                lvi = 0;
+impl Capture {
+    /// Start the PCM in engine and the thread that takes what it records, unless they are running
+    unsafe fn start(arc: &Arc<UnsafeCell<Capture>>) {
+        let capture = &mut *arc.get();
+        {
+            let _guard = InterruptGuard::new();
+            if capture.running {
+                return;
             }
+            capture.running = true;
+        }
+
+        let bus_master = capture.bus_master as u16;
+        let mut pi_cr = Pio::<u8>::new(bus_master + PI_CR);
+        pi_cr.write(CR_RR);
+        while pi_cr.read() & CR_RR == CR_RR {
+            context_switch();
+        }
+
+        for i in 0..CAPTURE_BUFFERS {
+            (*capture.bdl.offset(i as isize)).ptr.write(capture.buffers as u32 + (i * CAPTURE_SIZE) as u32);
+            (*capture.bdl.offset(i as isize)).samples.write((CAPTURE_SIZE / 2) as u32);
+        }
+        let mut pi_bdbar = PhysAddr::new(Pio::<u32>::new(bus_master + PI_BDBAR));
+        pi_bdbar.write(capture.bdl as u32);
+        // The engine fills the ring round and round, the last valid buffer is kept just behind it
+        Pio::<u8>::new(bus_master + PI_LVI).write((CAPTURE_BUFFERS - 1) as u8);
+        pi_cr.write(CR_RPBM);
+
+        let arc = arc.clone();
+        Context::spawn("kac97_record".into(), box move || {
+            let capture = &mut *arc.get();
+            let pi_civ = Pio::<u8>::new(bus_master + PI_CIV);
+            let mut pi_lvi = Pio::<u8>::new(bus_master + PI_LVI);
+            let mut pi_sr = Pio::<u16>::new(bus_master + PI_SR);
+
+            let mut next = 0;
             loop {
-                while wait {
-                    if po_civ.read() != lvi as u8 {
+                // A reader opened once this is seen finds the engine stopped, and starts it again
+                {
+                    let _guard = InterruptGuard::new();
+                    if capture.readers == 0 {
+                        Pio::<u8>::new(bus_master + PI_CR).write(0);
+                        capture.data.clear();
+                        capture.running = false;
                         break;
                     }
+                }
 
-                    {
-                        let contexts = &mut *::env().contexts.get();
-                        if let Ok(mut current) = contexts.current_mut() {
-                            current.wake = Some(Duration::monotonic() + Duration::new(0, 10 * time::NANOS_PER_MILLI));
-                            current.block("AC97 sleep 1");
+                // The buffers before the current one are full
+                let civ = pi_civ.read() as usize % CAPTURE_BUFFERS;
+                {
+                    let _guard = InterruptGuard::new();
+                    while next != civ {
+                        let buffer = capture.buffers.offset((next * CAPTURE_SIZE) as isize);
+                        for i in 0..CAPTURE_SIZE {
+                            capture.data.push_back(*buffer.offset(i as isize));
                         }
+                        next = (next + 1) % CAPTURE_BUFFERS;
                     }
+                    pi_lvi.write(((civ + CAPTURE_BUFFERS - 1) % CAPTURE_BUFFERS) as u8);
 
-                    context_switch();
-                }
-
-                debugln!("AC97 {} / {}: {} / {}",
-                       po_civ.read(),
-                       lvi as usize,
-                       position,
-                       buf.len());
-
-                let bytes = cmp::min(65534 * 2, (buf.len() - position + 1));
-                let samples = bytes / 2;
-
-                let mut phys_buf = buf.as_ptr() as usize;
-                {
-                    let contexts = &mut *::env().contexts.get();
-                    if let Ok(current) = contexts.current() {
-                        if let Ok(phys) = current.translate(buf.as_ptr().offset(position as isize) as usize, bytes) {
-                            debugln!("logical {:#X} -> physical {:#X}", &(buf.as_ptr() as usize), &phys);
-                            phys_buf = phys;
+                    // A reader that is too slow loses the oldest of what was recorded
+                    if capture.data.len() > CAPTURE_MAX {
+                        let extra = capture.data.len() - CAPTURE_MAX;
+                        for _ in 0..extra {
+                            capture.data.pop_front();
                         }
+                        capture.dropped += extra;
+                        syslog_debug!("AC97: Recording overran, {} bytes dropped", capture.dropped);
                     }
                 }
 
-                (*self.bdl.offset(lvi as isize)).ptr.write(phys_buf as u32);
-                (*self.bdl.offset(lvi as isize)).samples.write((samples & 0xFFFF) as u32);
-
-                position += bytes;
-
-                if position >= buf.len() {
-                    break;
+                // The engine stops if it catches up with the last valid buffer, the ring goes on where it is
+                if pi_sr.read() & SR_DCH == SR_DCH {
+                    // The status bits are cleared by writing them
+                    pi_sr.write(0x1C);
+                    Pio::<u8>::new(bus_master + PI_CR).write(CR_RPBM);
                 }
 
-                lvi += 1;
+                timekeeping::sleep(10, "AC97 record sleep");
+            }
+        });
+    }
+}
 
-                if lvi >= 32 {
-                    lvi = 0;
-                }
+/// The format the codec records in
+const CAPTURE_FORMAT: Format = Format {
+    rate: 48000,
+    channels: 2,
+    bits: 16,
+};
+
+/// Recording, read in the format it was opened with, which waits until something was recorded
+struct Ac97RecordResource {
+    capture: Arc<UnsafeCell<Capture>>,
+    /// From what the codec records to the format read
+    converter: Converter,
+    /// What was converted and not read yet
+    data: VecDeque<u8>,
+}
 
-                if lvi == start_lvi {
-                    po_lvi.write(start_lvi);
-                    po_cr.write(1);
-                    wait = true;
-                }
-            }
+impl Ac97RecordResource {
+    fn new(capture: Arc<UnsafeCell<Capture>>, format: Format) -> Ac97RecordResource {
+        {
+            let _guard = InterruptGuard::new();
+            unsafe { (*capture.get()).readers += 1 };
+        }
+        Ac97RecordResource {
+            capture: capture,
+            converter: Converter::new(CAPTURE_FORMAT, format),
+            data: VecDeque::new(),
+        }
+    }
+}
 
-            po_lvi.write(lvi);
-            po_cr.write(1);
+impl Resource for Ac97RecordResource {
+    fn dup(&self) -> syscall::Result<Box<Resource>> {
+        Ok(box Ac97RecordResource::new(self.capture.clone(), self.converter.to))
+    }
 
-            loop {
-                if po_civ.read() == lvi {
-                    po_cr.write(0);
-                    break;
-                }
+    fn path(&self, buf: &mut [u8]) -> syscall::Result <usize> {
+        let format = self.converter.to;
+        let path_string = format!("audio:/record/{}/{}/{}", format.rate, format.channels, format.bits);
+        let path = path_string.as_bytes();
 
-                {
-                    let contexts = &mut *::env().contexts.get();
-                    if let Ok(mut current) = contexts.current_mut() {
-                        current.wake = Some(Duration::monotonic() + Duration::new(0, 10 * time::NANOS_PER_MILLI));
-                        current.block("AC97 sleep 2");
-                    }
-                }
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
 
-                context_switch();
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> syscall::Result<usize> {
+        let capture = unsafe { &mut *self.capture.get() };
+        let frame_size = self.converter.to.frame_size();
+        while self.data.len() < frame_size {
+            // Whole stereo frames, so the channels stay in step
+            let count = capture.data.len() / 4 * 4;
+            if count > 0 {
+                let recorded: Vec<u8> = {
+                    let _guard = InterruptGuard::new();
+                    (0..count).map(|_| capture.data.pop_front().unwrap_or(0)).collect()
+                };
+                self.data.extend(self.converter.convert(&recorded));
+                continue;
             }
 
-            debug!("AC97 Finished {} / {}\n", po_civ.read(), lvi);
+            timekeeping::sleep(10, "AC97 record read");
         }
 
-        Ok(buf.len())
+        let count = cmp::min(buf.len(), self.data.len()) / frame_size * frame_size;
+        for i in 0..count {
+            buf[i] = self.data.pop_front().unwrap_or(0);
+        }
+
+        Ok(count)
+    }
+}
+
+impl Drop for Ac97RecordResource {
+    fn drop(&mut self) {
+        let _guard = InterruptGuard::new();
+        unsafe { (*self.capture.get()).readers -= 1 };
     }
 }
 
 pub struct Ac97 {
-    audio: usize,
-    bus_master: usize,
     irq: u8,
-    bdl: *mut Bd,
+    mixer: Mixer,
+    playback: Arc<UnsafeCell<Playback>>,
+    capture: Arc<UnsafeCell<Capture>>,
 }
 
-impl KScheme for Ac97 {
-    fn scheme(&self) -> &str {
-        "audio"
+impl AudioDevice for Ac97 {
+    fn name(&self) -> &str {
+        "AC97"
     }
 
-    fn open(&mut self, _: &str, _: usize) -> syscall::Result<Box<Resource>> {
-        Ok(box Ac97Resource {
-            audio: self.audio,
-            bus_master: self.bus_master,
-            bdl: self.bdl
-        })
-    }
-
-    fn on_irq(&mut self, irq: u8) {
+    fn on_irq(&mut self, irq: u8) -> bool {
         if irq == self.irq {
             // d("AC97 IRQ\n");
         }
+        irq == self.irq
+    }
+
+    /// The format is played at its own rate if the codec has variable rates, otherwise at 48 kHz
+    fn open(&mut self, format: &Format) -> syscall::Result<u32> {
+        let playback = unsafe { &mut *self.playback.get() };
+        let vra = codec_ready(playback.bus_master) && Pio::<u16>::new(playback.audio as u16 + EXT_AUDIO_ID).read() & EXT_VRA == EXT_VRA;
+        let rate = if vra && format.rate >= 8000 && format.rate <= 48000 {
+            format.rate
+        } else {
+            48000
+        };
+
+        try!(unsafe { playback.start(rate, vra) });
+        Ok(rate)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> usize {
+        unsafe { &mut *self.playback.get() }.write(buf)
+    }
+
+    fn space(&mut self) -> usize {
+        unsafe { &mut *self.playback.get() }.space()
+    }
+
+    fn queued(&mut self) -> usize {
+        unsafe { &mut *self.playback.get() }.queued()
+    }
+
+    fn underruns(&self) -> usize {
+        unsafe { & *self.playback.get() }.ring.as_ref().map_or(0, |ring| ring.underruns)
+    }
+
+    fn volume(&self) -> (usize, bool) {
+        self.mixer.master()
+    }
+
+    fn set_volume(&mut self, percent: usize, mute: bool) -> syscall::Result<()> {
+        if self.mixer.set(MASTER_VOLUME, 0, self.mixer.master_max, percent, mute) {
+            Ok(())
+        } else {
+            Err(syscall::Error::new(syscall::EIO))
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(ref mut ring) = unsafe { &mut *self.playback.get() }.ring {
+            ring.clear();
+        }
+    }
+
+    fn stop(&mut self) {
+        unsafe { &mut *self.playback.get() }.stop();
+    }
+
+    /// `mixer` has the volume and record controls, `record` reads what is recorded as 48 kHz 16 bit stereo,
+    /// and `record/rate/channels/bits` in other formats
+    fn control(&mut self, name: &str) -> syscall::Result<Box<Resource>> {
+        let mut parts = name.splitn(2, '/');
+        match (parts.next().unwrap_or(""), parts.next()) {
+            ("mixer", None) => Ok(box Ac97MixerResource {
+                mixer: self.mixer,
+                seek: 0,
+            }),
+            ("record", format) => {
+                let format = match format {
+                    Some(format) => try!(Format::parse(format).ok_or(syscall::Error::new(syscall::EINVAL))),
+                    None => CAPTURE_FORMAT,
+                };
+                let resource = Ac97RecordResource::new(self.capture.clone(), format);
+                unsafe { Capture::start(&self.capture) };
+                Ok(box resource)
+            },
+            _ => Err(syscall::Error::new(syscall::ENOENT)),
+        }
+    }
+
+    fn status(&self) -> String {
+        let playback = unsafe { & *self.playback.get() };
+        format!("rate: {}\n", playback.rate) + &self.mixer.status()
     }
 }
 
@@ -212,17 +678,48 @@ impl Ac97 {
     pub unsafe fn new(mut pci: PciConfig) -> Box<Ac97> {
         pci.flag(4, 4, true); // Bus mastering
 
+        let audio = pci.read(0x10) as usize & 0xFFFFFFF0;
+        let bus_master = pci.read(0x14) as usize & 0xFFFFFFF0;
+        let irq = pci.irq();
         let module = box Ac97 {
-            audio: pci.read(0x10) as usize & 0xFFFFFFF0,
-            bus_master: pci.read(0x14) as usize & 0xFFFFFFF0,
-            irq: pci.read(0x3C) as u8 & 0xF,
-            bdl: memory::alloc(32 * mem::size_of::<Bd>()) as *mut Bd,
+            irq: irq,
+            mixer: Mixer::new(audio, bus_master),
+            playback: Arc::new(UnsafeCell::new(Playback {
+                audio: audio,
+                bus_master: bus_master,
+                bdl: memory::alloc(PLAYBACK_BUFFERS * mem::size_of::<Bd>()) as *mut Bd,
+                ring: None,
+                rate: 48000,
+                running: false,
+            })),
+            capture: Arc::new(UnsafeCell::new(Capture {
+                bus_master: bus_master,
+                bdl: memory::alloc(CAPTURE_BUFFERS * mem::size_of::<Bd>()) as *mut Bd,
+                buffers: memory::alloc(CAPTURE_BUFFERS * CAPTURE_SIZE) as *mut u8,
+                data: VecDeque::new(),
+                dropped: 0,
+                readers: 0,
+                running: false,
+            })),
         };
 
-        syslog_info!(" + AC97 on: {:X}, {:X}, IRQ: {:X}", module.audio, module.bus_master, module.irq);
+        syslog_info!(" + AC97 on: {:X}, {:X}, IRQ: {:X}", audio, bus_master, irq);
+
+        // Start at full volume, which was set on every write before there was a mixer
+        module.mixer.set(MASTER_VOLUME, 0, module.mixer.master_max, 100, false);
+        module.mixer.set(PCM_VOLUME, PCM_0DB, PCM_MAX, 100, false);
+        module.mixer.set_record(0);
+        module.mixer.set_mic(false);
 
-        let mut po_bdbar = PhysAddr::new(Pio::<u32>::new(module.bus_master as u16 + 0x10));
-        po_bdbar.write(module.bdl as u32);
+        // What was played is silenced as the engine goes round, so nothing is played twice
+        let playback = module.playback.clone();
+        Context::spawn("kac97_play".into(), box move || {
+            loop {
+                (*playback.get()).update();
+
+                timekeeping::sleep(10, "AC97 play sleep");
+            }
+        });
 
         module
     }
@@ -1,475 +1,887 @@
+use alloc::arc::Arc;
 use alloc::boxed::Box;
 
-use arch::memory::Memory;
+use arch::context::{context_switch, Context};
+use arch::memory::{Memory, LOGICAL_OFFSET};
+use arch::timekeeping;
 
-use core::ptr;
+use collections::string::{String, ToString};
+use collections::vec::Vec;
+use collections::vec_deque::VecDeque;
 
-use drivers::pci::config::PciConfig;
-
-use common::time;
+use common::time::{self, Duration};
 
+use core::cell::UnsafeCell;
 use core::cmp;
 
-use fs::{KScheme, Resource};
+use drivers::io::{Io, Mmio};
+use drivers::pci::config::PciConfig;
 
 use syscall;
-use syscall::TimeSpec;
 
-#[repr(packed)]
-struct Stream {
-    interrupt: u8,
-    reserved: u8,
-    control: u8,
-    status: u8,
-    // RO Position of link in buffer
-    lpib: u32,
-    // RW length of buffer
-    cbl: u32,
-    // RW last valid buffer description
-    lvi: u16,
-    reserved_2: u16,
-    // RO maximum number of fetchable bytes
-    fifos: u16,
-    // format
-    format: u16,
-    reserved_3: u32,
-    // pointer to buffer descriptor list
-    bdlpl: u32,
-    bdlpu: u32,
+use super::{AudioDevice, Format, PlaybackRing};
+use super::scheme::jack_changed;
+
+const GCAP: usize = 0x00;
+const GCTL: usize = 0x08;
+const STATESTS: usize = 0x0E;
+const INTCTL: usize = 0x20;
+const CORBLBASE: usize = 0x40;
+const CORBUBASE: usize = 0x44;
+const CORBWP: usize = 0x48;
+const CORBRP: usize = 0x4A;
+const CORBCTL: usize = 0x4C;
+const CORBSIZE: usize = 0x4E;
+const RIRBLBASE: usize = 0x50;
+const RIRBUBASE: usize = 0x54;
+const RIRBWP: usize = 0x58;
+const RINTCNT: usize = 0x5A;
+const RIRBCTL: usize = 0x5C;
+const RIRBSTS: usize = 0x5D;
+const RIRBSIZE: usize = 0x5E;
+
+const GCTL_CRST: u32 = 1;
+/// Accept unsolicited responses, which codecs send on their own
+const GCTL_UNSOL: u32 = 1 << 8;
+const INTCTL_GIE: u32 = 1 << 31;
+const INTCTL_CIE: u32 = 1 << 30;
+/// Interrupt when a response comes in, and when one is lost for the RIRB being full
+const RIRBCTL_RINTCTL: u8 = 1;
+const RIRBCTL_OIC: u8 = 1 << 2;
+const RIRBSTS_RINTFL: u8 = 1;
+const RIRBSTS_RIRBOIS: u8 = 1 << 2;
+/// The response was sent on its own by the codec, in the extended response
+const RESPONSE_UNSOLICITED: u32 = 1 << 4;
+/// Run the DMA engine of the CORB or RIRB
+const DMA_RUN: u8 = 1 << 1;
+/// Resets the CORB read pointer, or the RIRB write pointer
+const POINTER_RESET: u16 = 1 << 15;
+/// Entries in the CORB and RIRB, the largest size every controller has
+const RING_ENTRIES: usize = 256;
+const RING_SIZE_256: u8 = 0b10;
+
+/// The registers of stream descriptor n are at this offset plus 0x20 times n, input streams come first
+const SD_BASE: usize = 0x80;
+const SD_CTL: usize = 0x00;
+const SD_LPIB: usize = 0x04;
+const SD_CBL: usize = 0x08;
+const SD_LVI: usize = 0x0C;
+const SD_FMT: usize = 0x12;
+const SD_BDPL: usize = 0x18;
+const SD_BDPU: usize = 0x1C;
+const SD_CTL_SRST: u32 = 1;
+const SD_CTL_RUN: u32 = 1 << 1;
+/// The tag the output stream has on the link, which its converter listens for
+const STREAM_TAG: u32 = 1;
+
+/// 48 kHz, 16 bits, 2 channels, in the stream and converter format
+const FORMAT_48K_16_STEREO: u16 = 0x0011;
+
+const VERB_GET_PARAMETER: u32 = 0xF00;
+const VERB_GET_CONNECTION_LIST: u32 = 0xF02;
+const VERB_SET_CONNECTION_SELECT: u32 = 0x701;
+const VERB_SET_POWER_STATE: u32 = 0x705;
+const VERB_SET_STREAM_CHANNEL: u32 = 0x706;
+const VERB_SET_PIN_CONTROL: u32 = 0x707;
+const VERB_SET_EAPD: u32 = 0x70C;
+const VERB_SET_UNSOLICITED: u32 = 0x708;
+const VERB_GET_PIN_SENSE: u32 = 0xF09;
+const VERB_GET_CONFIG_DEFAULT: u32 = 0xF1C;
+/// The verbs with 16 bit payloads, which have 4 bit ids
+const VERB_SET_FORMAT: u32 = 0x2;
+const VERB_SET_AMP: u32 = 0x3;
+
+const PARAM_NODE_COUNT: u32 = 0x04;
+const PARAM_FUNCTION_GROUP: u32 = 0x05;
+const PARAM_WIDGET_CAPS: u32 = 0x09;
+const PARAM_PIN_CAPS: u32 = 0x0C;
+const PARAM_IN_AMP_CAPS: u32 = 0x0D;
+const PARAM_CONNECTION_LENGTH: u32 = 0x0E;
+const PARAM_OUT_AMP_CAPS: u32 = 0x12;
+
+const FUNCTION_GROUP_AUDIO: u32 = 1;
+
+const WIDGET_OUTPUT: u8 = 0;
+const WIDGET_MIXER: u8 = 2;
+const WIDGET_SELECTOR: u8 = 3;
+const WIDGET_PIN: u8 = 4;
+
+const CAPS_IN_AMP: u32 = 1 << 1;
+const CAPS_OUT_AMP: u32 = 1 << 2;
+/// The widget has its own amplifier capabilities instead of those of the function group
+const CAPS_AMP_OVERRIDE: u32 = 1 << 3;
+
+const PIN_CAPS_PRESENCE: u32 = 1 << 2;
+const PIN_CAPS_OUTPUT: u32 = 1 << 4;
+const PIN_CAPS_EAPD: u32 = 1 << 16;
+const PIN_OUT_ENABLE: u32 = 1 << 6;
+const PIN_HP_ENABLE: u32 = 1 << 7;
+const PIN_SENSE_PRESENCE: u32 = 1 << 31;
+/// Set in the misc field of the default configuration if presence detect does not work on the jack
+const CONFIG_NO_PRESENCE: u32 = 1 << 8;
+/// Enable unsolicited responses of a widget, with the tag they are sent with in the low bits
+const UNSOLICITED_ENABLE: u32 = 1 << 7;
+/// The tag of unsolicited responses from the headphone pin, in the top 6 bits of the response
+const JACK_TAG: u32 = 1;
+
+const AMP_OUTPUT: u32 = 1 << 15;
+const AMP_INPUT: u32 = 1 << 14;
+const AMP_LEFT: u32 = 1 << 13;
+const AMP_RIGHT: u32 = 1 << 12;
+const AMP_MUTE: u32 = 1 << 7;
+
+/// The default devices of output pins, in the order a path to one is looked for
+const DEVICE_LINE_OUT: u32 = 0x0;
+const DEVICE_SPEAKER: u32 = 0x1;
+const DEVICE_HP_OUT: u32 = 0x2;
+/// A pin whose jack is not connected to anything
+const PORT_NONE: u32 = 0b01;
+
+/// Buffers in the cyclic list of the output stream, and the bytes in each
+const BUFFERS: usize = 4;
+const BUFFER_SIZE: usize = 16384;
+/// Bytes kept clear ahead of the position of the controller, which it may have fetched already
+const MARGIN: usize = 2048;
+
+/// The physical address of `address`, which may be logical, for the controller to read or write
+fn physical(address: usize) -> u32 {
+    if address >= LOGICAL_OFFSET {
+        (address - LOGICAL_OFFSET) as u32
+    } else {
+        address as u32
+    }
+}
+
+/// Check `done` every millisecond for up to `ms` milliseconds, returning whether it became true
+fn wait_for<F: FnMut() -> bool>(ms: i32, mut done: F) -> bool {
+    for _ in 0..ms {
+        if done() {
+            return true;
+        }
+        timekeeping::sleep(1, "Intel HDA wait");
+    }
+    done()
 }
 
 #[repr(packed)]
-struct BD {
-    addr: u32,
-    addru: u32,
-    len: u32,
-    ioc: u32,
+struct Bd {
+    addr: Mmio<u32>,
+    addru: Mmio<u32>,
+    len: Mmio<u32>,
+    ioc: Mmio<u32>,
+}
+
+/// A widget of an audio function group, with what finding a path through it needs
+struct Widget {
+    node: u8,
+    kind: u8,
+    caps: u32,
+    pin_caps: u32,
+    /// The default configuration of a pin
+    config: u32,
+    connections: Vec<u8>,
+}
+
+/// The output stream, a ring of buffers the controller plays round and round
+pub struct HdaOutput {
+    /// The stream descriptor registers
+    sd: usize,
+    _bdl: Memory<Bd>,
+    ring: PlaybackRing,
+}
+
+impl HdaOutput {
+    /// Silence what the controller played, from where it is in the ring
+    fn update(&mut self) {
+        let position = unsafe { (*((self.sd + SD_LPIB) as *const Mmio<u32>)).read() as usize };
+        self.ring.update(position);
+    }
+}
+
+/// The amplifier the volume is set on
+#[derive(Copy, Clone)]
+struct Amp {
+    codec: u8,
+    node: u8,
+    /// The gain of 0 dB, which is full volume
+    offset: u32,
+}
+
+/// An output pin that is turned off while headphones are plugged in
+#[derive(Copy, Clone)]
+struct Speaker {
+    node: u8,
+    /// The pin control it has while it plays
+    control: u32,
+    eapd: bool,
+}
+
+/// A headphone jack with presence detect, and the speakers it mutes
+struct Jack {
+    codec: u8,
+    node: u8,
+    speakers: Vec<Speaker>,
+    /// Headphones are plugged in, unknown until the pin is first sensed
+    plugged: Option<bool>,
 }
 
-struct IntelHdaResource {
-    base: usize,
+pub struct IntelHda {
+    pub pci: PciConfig,
+    pub base: usize,
+    /// The bytes of registers the BAR maps
+    pub size: usize,
+    pub memory_mapped: bool,
+    pub irq: u8,
+    corb: Option<Memory<Mmio<u32>>>,
+    /// Two dwords in each entry, the response and the codec it came from
+    rirb: Option<Memory<Mmio<u32>>>,
+    /// The last RIRB entry that was read
+    rirb_read: usize,
+    output: Option<Arc<UnsafeCell<HdaOutput>>>,
+    /// The amplifiers the volume is set on, one for each output path
+    amps: Vec<Amp>,
+    volume: usize,
+    mute: bool,
+    /// A command is waiting for its response, others wait for it
+    commanding: bool,
+    /// Unsolicited responses read from the RIRB and not handled yet
+    unsolicited: VecDeque<u32>,
+    /// A response interrupt came in, so unsolicited responses may be waiting
+    responses_pending: bool,
+    jack: Option<Jack>,
 }
 
-impl Resource for IntelHdaResource {
-    fn dup(&self) -> syscall::Result<Box<Resource>> {
-        Ok(box IntelHdaResource { base: self.base })
+impl AudioDevice for IntelHda {
+    fn name(&self) -> &str {
+        "Intel HDA"
     }
 
-    fn path(&self, buf: &mut [u8]) -> syscall::Result <usize> {
-        let path = b"audio:";
+    /// Responses are read by the bring up thread, which handles jack changes once it sees this
+    fn on_irq(&mut self, irq: u8) -> bool {
+        if irq == self.irq {
+            let rirbsts = self.reg8(RIRBSTS);
+            let status = rirbsts.read();
+            if status & (RIRBSTS_RINTFL | RIRBSTS_RIRBOIS) != 0 {
+                rirbsts.write(status & (RIRBSTS_RINTFL | RIRBSTS_RIRBOIS));
+                self.responses_pending = true;
+            }
+        }
+        irq == self.irq
+    }
 
-        for (b, p) in buf.iter_mut().zip(path.iter()) {
-            *b = *p;
+    /// The converter runs at 48 kHz, other rates are resampled
+    fn open(&mut self, _format: &Format) -> syscall::Result<u32> {
+        match self.output {
+            Some(ref output) => {
+                unsafe { &mut *output.get() }.ring.clear();
+                Ok(48000)
+            },
+            None => Err(syscall::Error::new(syscall::ENODEV)),
         }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> usize {
+        self.output.as_ref().map_or(0, |output| {
+            let output = unsafe { &mut *output.get() };
+            output.update();
+            output.ring.write(buf)
+        })
+    }
 
-        Ok(cmp::min(buf.len(), path.len()))
+    fn space(&mut self) -> usize {
+        self.output.as_ref().map_or(0, |output| {
+            let output = unsafe { &mut *output.get() };
+            output.update();
+            output.ring.space()
+        })
     }
 
-    fn write(&mut self, buf: &[u8]) -> syscall::Result<usize> {
-        unsafe {
-            debug!("Write HDA");
+    fn queued(&mut self) -> usize {
+        self.output.as_ref().map_or(0, |output| {
+            let output = unsafe { &mut *output.get() };
+            output.update();
+            output.ring.queued()
+        })
+    }
 
-            let gcap = (self.base) as *mut u16;
+    fn underruns(&self) -> usize {
+        self.output.as_ref().map_or(0, |output| unsafe { & *output.get() }.ring.underruns)
+    }
 
-            let iss = (ptr::read(gcap) as usize >> 12) & 0b1111;
+    fn volume(&self) -> (usize, bool) {
+        (self.volume, self.mute)
+    }
 
-            let stream = &mut *((self.base + 0x80 + iss * 0x20) as *mut Stream);
+    /// The gain of the amplifier goes from its lowest to 0 dB
+    fn set_volume(&mut self, percent: usize, mute: bool) -> syscall::Result<()> {
+        if self.output.is_none() || self.amps.is_empty() {
+            return Err(syscall::Error::new(syscall::ENODEV));
+        }
 
-            stream.interrupt = 1;
-            loop {
-                if stream.interrupt & 1 == 1 {
-                    break;
-                }
+        let percent = cmp::min(percent, 100);
+        for amp in self.amps.clone().iter() {
+            let gain = (percent as u32 * amp.offset + 50) / 100;
+            try!(self.command(amp.codec, amp.node, VERB_SET_AMP << 16 | AMP_OUTPUT | AMP_LEFT | AMP_RIGHT
+                                                   | if mute { AMP_MUTE } else { 0 } | gain));
+        }
+        self.volume = percent;
+        self.mute = mute;
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        if let Some(ref output) = self.output {
+            unsafe { &mut *output.get() }.ring.clear();
+        }
+    }
+
+    /// The stream keeps running, playing silence
+    fn stop(&mut self) {
+        self.flush();
+    }
+
+    fn jack(&self) -> Option<bool> {
+        self.jack.as_ref().and_then(|jack| jack.plugged)
+    }
+
+    fn status(&self) -> String {
+        "rate: 48000\n".to_string()
+    }
+}
+
+impl IntelHda {
+    pub unsafe fn new(mut pci: PciConfig) -> Box<IntelHda> {
+        let bar = pci.bar(0);
+        let mut module = box IntelHda {
+            pci: pci,
+            base: bar.base,
+            size: bar.size,
+            memory_mapped: bar.is_memory(),
+            irq: pci.irq(),
+            corb: None,
+            rirb: None,
+            rirb_read: 0,
+            output: None,
+            amps: Vec::new(),
+            volume: 100,
+            mute: false,
+            commanding: false,
+            unsolicited: VecDeque::new(),
+            responses_pending: false,
+            jack: None,
+        };
+        module.init();
+        module
+    }
+
+    pub unsafe fn init(&mut self) {
+        syslog_info!(" + Intel HDA on: {:X}, IRQ {:X}", self.base, self.irq);
+
+        self.pci.flag(4, 4, true); // Bus mastering
+
+        // The resets and codec commands need to sleep, so they are done once the scheduler runs
+        let this = self as *mut IntelHda;
+        Context::spawn("khda".into(), box move || {
+            match (*this).start() {
+                Ok(output) => {
+                    (*this).output = Some(output.clone());
+                    syslog_info!("Intel HDA: Ready");
+                    // The ring is silenced as it is played, so nothing is played twice
+                    let mut tick = 0;
+                    loop {
+                        (*output.get()).update();
+                        // Jack changes are looked for now and then too, in case the interrupt is not routed
+                        if (*this).responses_pending || tick % 50 == 0 {
+                            (*this).responses_pending = false;
+                            (*this).handle_unsolicited();
+                        }
+                        tick += 1;
+                        timekeeping::sleep(10, "Intel HDA poll");
+                    }
+                },
+                Err(err) => syslog_warning!("Intel HDA: Failed to start: {}", err),
             }
+        });
+    }
 
-            stream.interrupt = 0;
-            loop {
-                if stream.interrupt & 1 == 0 {
-                    break;
-                }
+    fn reg8(&self, offset: usize) -> &'static mut Mmio<u8> {
+        unsafe { &mut *((self.base + offset) as *mut Mmio<u8>) }
+    }
+
+    fn reg16(&self, offset: usize) -> &'static mut Mmio<u16> {
+        unsafe { &mut *((self.base + offset) as *mut Mmio<u16>) }
+    }
+
+    fn reg32(&self, offset: usize) -> &'static mut Mmio<u32> {
+        unsafe { &mut *((self.base + offset) as *mut Mmio<u32>) }
+    }
+
+    /// Reset the controller and codecs, set up the command rings, and find an output path on a codec
+    unsafe fn start(&mut self) -> syscall::Result<Arc<UnsafeCell<HdaOutput>>> {
+        let gctl = self.reg32(GCTL);
+        gctl.writef(GCTL_CRST, false);
+        if ! wait_for(100, || ! gctl.readf(GCTL_CRST)) {
+            return Err(syscall::Error::new(syscall::ETIMEDOUT));
+        }
+        gctl.writef(GCTL_CRST, true);
+        if ! wait_for(100, || gctl.readf(GCTL_CRST)) {
+            return Err(syscall::Error::new(syscall::ETIMEDOUT));
+        }
+        // Codecs have 521 us after the reset to ask for an address
+        timekeeping::sleep(1, "Intel HDA codec reset");
+        let codecs = self.reg16(STATESTS).read();
+
+        // Responses to commands are polled, the interrupt is for unsolicited ones
+        try!(self.start_corb());
+        try!(self.start_rirb());
+        gctl.writef(GCTL_UNSOL, true);
+        self.reg32(INTCTL).write(INTCTL_GIE | INTCTL_CIE);
+
+        for codec in 0..15 {
+            if codecs & 1 << codec == 0 {
+                continue;
+            }
+            match self.codec(codec) {
+                Ok(Some(output)) => return Ok(output),
+                Ok(None) => syslog_info!("Intel HDA: No output path on codec {}", codec),
+                Err(err) => syslog_warning!("Intel HDA: Codec {} failed: {}", codec, err),
+            }
+        }
+
+        Err(syscall::Error::new(syscall::ENODEV))
+    }
+
+    unsafe fn start_corb(&mut self) -> syscall::Result<()> {
+        let corbctl = self.reg8(CORBCTL);
+        corbctl.write(0);
+        if ! wait_for(10, || corbctl.read() & DMA_RUN == 0) {
+            return Err(syscall::Error::new(syscall::ETIMEDOUT));
+        }
+
+        let corb = try!(Memory::<Mmio<u32>>::new_aligned(RING_ENTRIES, 128));
+        self.reg32(CORBLBASE).write(physical(corb.address()));
+        self.reg32(CORBUBASE).write(0);
+        let size = self.reg8(CORBSIZE).read();
+        self.reg8(CORBSIZE).write((size & ! 0b11) | RING_SIZE_256);
+
+        // The read pointer reads back as reset before it is let go
+        let corbrp = self.reg16(CORBRP);
+        corbrp.write(POINTER_RESET);
+        if ! wait_for(10, || corbrp.read() & POINTER_RESET == POINTER_RESET) {
+            return Err(syscall::Error::new(syscall::ETIMEDOUT));
+        }
+        corbrp.write(0);
+        if ! wait_for(10, || corbrp.read() & POINTER_RESET == 0) {
+            return Err(syscall::Error::new(syscall::ETIMEDOUT));
+        }
+        self.reg16(CORBWP).write(0);
+
+        corbctl.write(DMA_RUN);
+        self.corb = Some(corb);
+        Ok(())
+    }
+
+    unsafe fn start_rirb(&mut self) -> syscall::Result<()> {
+        let rirbctl = self.reg8(RIRBCTL);
+        rirbctl.write(0);
+        if ! wait_for(10, || rirbctl.read() & DMA_RUN == 0) {
+            return Err(syscall::Error::new(syscall::ETIMEDOUT));
+        }
+
+        let rirb = try!(Memory::<Mmio<u32>>::new_aligned(RING_ENTRIES * 2, 128));
+        self.reg32(RIRBLBASE).write(physical(rirb.address()));
+        self.reg32(RIRBUBASE).write(0);
+        let size = self.reg8(RIRBSIZE).read();
+        self.reg8(RIRBSIZE).write((size & ! 0b11) | RING_SIZE_256);
+        self.reg16(RIRBWP).write(POINTER_RESET);
+        self.reg16(RINTCNT).write(1);
+        self.rirb_read = 0;
+
+        rirbctl.write(DMA_RUN | RIRBCTL_RINTCTL | RIRBCTL_OIC);
+        self.rirb = Some(rirb);
+        Ok(())
+    }
+
+    /// Send `verb` to `node` of `codec` and wait for its response
+    ///
+    /// One command is sent at a time, since responses come back in order without saying what they answer.
+    fn command(&mut self, codec: u8, node: u8, verb: u32) -> syscall::Result<u32> {
+        while self.commanding {
+            unsafe { context_switch() };
+        }
+        self.commanding = true;
+        let result = self.exchange(codec, node, verb);
+        self.commanding = false;
+        result
+    }
+
+    fn exchange(&mut self, codec: u8, node: u8, verb: u32) -> syscall::Result<u32> {
+        let corbwp = self.reg16(CORBWP);
+
+        let i = (corbwp.read() as usize + 1) % RING_ENTRIES;
+        match self.corb {
+            Some(ref mut corb) => corb[i].write((codec as u32 & 0xF) << 28 | (node as u32) << 20 | verb & 0xFFFFF),
+            None => return Err(syscall::Error::new(syscall::ENODEV)),
+        }
+        corbwp.write(i as u16);
+
+        let end = Duration::monotonic() + Duration::new(0, 100 * time::NANOS_PER_MILLI);
+        loop {
+            if let Some(response) = try!(self.read_responses()) {
+                return Ok(response);
+            }
+
+            if Duration::monotonic() > end {
+                return Err(syscall::Error::new(syscall::ETIMEDOUT));
             }
+            unsafe { context_switch() };
+        }
+    }
 
-            stream.control = 1 << 4 as u8;
+    /// Read the RIRB up to the first solicited response, keeping the unsolicited ones before it
+    fn read_responses(&mut self) -> syscall::Result<Option<u32>> {
+        let rirbwp = self.reg16(RIRBWP);
+        while self.rirb_read != rirbwp.read() as usize % RING_ENTRIES {
+            self.rirb_read = (self.rirb_read + 1) % RING_ENTRIES;
+            let (response, extended) = match self.rirb {
+                Some(ref rirb) => (rirb[self.rirb_read * 2].read(), rirb[self.rirb_read * 2 + 1].read()),
+                None => return Err(syscall::Error::new(syscall::ENODEV)),
+            };
+            if extended & RESPONSE_UNSOLICITED == RESPONSE_UNSOLICITED {
+                self.unsolicited.push_back(response);
+            } else {
+                return Ok(Some(response));
+            }
+        }
 
-            stream.format = 0b0000000000010001;
+        Ok(None)
+    }
 
-            let mut bd_addr = try!(Memory::<u8>::new(buf.len()));
-            let bd_size = bd_addr.len();
+    /// Handle the unsolicited responses that came in, which are jack changes
+    fn handle_unsolicited(&mut self) {
+        if ! self.commanding {
+            self.commanding = true;
+            if let Ok(Some(response)) = self.read_responses() {
+                syslog_debug!("Intel HDA: Response {:X} without a command", response);
+            }
+            self.commanding = false;
+        }
+
+        let mut sense = false;
+        while let Some(response) = self.unsolicited.pop_front() {
+            if response >> 26 == JACK_TAG {
+                sense = true;
+            } else {
+                syslog_debug!("Intel HDA: Unsolicited response {:X}", response);
+            }
+        }
+        if sense {
+            self.sense_jack();
+        }
+    }
 
-            ::memset(bd_addr.as_mut_ptr(), 0, bd_size);
-            ::memcpy(bd_addr.as_mut_ptr(), buf.as_ptr(), buf.len());
+    /// Check if headphones are plugged in, and turn the speakers off while they are
+    fn sense_jack(&mut self) {
+        let (codec, node, speakers, plugged) = match self.jack {
+            Some(ref jack) => (jack.codec, jack.node, jack.speakers.clone(), jack.plugged),
+            None => return,
+        };
 
-            let mut bdl = try!(Memory::<BD>::new(2));
-            bdl.write(0, BD {
-                addr: bd_addr.address() as u32,
-                addru: 0,
-                len: bd_size as u32,
-                ioc: 1,
-            });
-            bdl.write(1, BD {
-                addr: bd_addr.address() as u32,
-                addru: 0,
-                len: bd_size as u32,
-                ioc: 1,
-            });
+        let present = match self.command(codec, node, VERB_GET_PIN_SENSE << 8) {
+            Ok(sense) => sense & PIN_SENSE_PRESENCE == PIN_SENSE_PRESENCE,
+            Err(err) => {
+                syslog_warning!("Intel HDA: Failed to sense the headphone jack: {}", err);
+                return;
+            }
+        };
+        if plugged == Some(present) {
+            return;
+        }
 
-            stream.bdlpl = bdl.address() as u32;
+        for speaker in speakers.iter() {
+            let _ = self.command(codec, speaker.node, VERB_SET_PIN_CONTROL << 8 | if present { 0 } else { speaker.control });
+            if speaker.eapd {
+                let _ = self.command(codec, speaker.node, VERB_SET_EAPD << 8 | if present { 0 } else { 1 << 1 });
+            }
+        }
+        if let Some(ref mut jack) = self.jack {
+            jack.plugged = Some(present);
+        }
 
-            stream.cbl = (bd_size * 2) as u32;
+        syslog_info!("Intel HDA: Headphones {}", if present { "plugged in" } else { "unplugged" });
+        // The first sense is the state at boot, not a change
+        if plugged.is_some() {
+            jack_changed(&*self, present);
+        }
+    }
 
-            stream.lvi = 1;
+    fn parameter(&mut self, codec: u8, node: u8, parameter: u32) -> syscall::Result<u32> {
+        self.command(codec, node, VERB_GET_PARAMETER << 8 | parameter)
+    }
 
-            stream.interrupt = 1 << 2 | 1 << 1;
+    /// The first node under `node` and how many there are
+    fn nodes(&mut self, codec: u8, node: u8) -> syscall::Result<(u8, u8)> {
+        let count = try!(self.parameter(codec, node, PARAM_NODE_COUNT));
+        Ok(((count >> 16) as u8, count as u8))
+    }
 
-            loop {
-                if stream.status & 4 == 4 {
-                    break;
+    /// The nodes `node` takes its input from, with ranges given by their ends made into every node in them
+    fn connections(&mut self, codec: u8, node: u8) -> syscall::Result<Vec<u8>> {
+        let length = try!(self.parameter(codec, node, PARAM_CONNECTION_LENGTH));
+        let long = length & 1 << 7 == 1 << 7;
+        let count = (length & 0x7F) as usize;
+        let (per_response, bits) = if long { (2, 16) } else { (4, 8) };
+
+        let mut connections: Vec<u8> = Vec::new();
+        let mut i = 0;
+        while i < count {
+            let response = try!(self.command(codec, node, VERB_GET_CONNECTION_LIST << 8 | i as u32));
+            for j in 0..cmp::min(per_response, count - i) {
+                let entry = (response >> (j * bits)) & ((1 << bits) - 1);
+                let range = entry & 1 << (bits - 1) != 0;
+                let id = (entry & ((1 << (bits - 1)) - 1)) as u8;
+                match connections.last().map(|&last| last) {
+                    Some(last) if range && id > last => connections.extend(last + 1 .. id + 1),
+                    _ => connections.push(id),
                 }
+            }
+            i += per_response;
+        }
 
-                let req = TimeSpec {
-                    tv_sec: 0,
-                    tv_nsec: 10 * time::NANOS_PER_MILLI
-                };
-                let mut rem = TimeSpec {
-                    tv_sec: 0,
-                    tv_nsec: 0,
+        Ok(connections)
+    }
+
+    /// Enumerate the widgets of the audio function groups of `codec`, and set up a path to an output pin
+    unsafe fn codec(&mut self, codec: u8) -> syscall::Result<Option<Arc<UnsafeCell<HdaOutput>>>> {
+        let (first_group, groups) = try!(self.nodes(codec, 0));
+        for group in first_group..first_group.saturating_add(groups) {
+            let kind = try!(self.parameter(codec, group, PARAM_FUNCTION_GROUP));
+            if kind & 0xFF != FUNCTION_GROUP_AUDIO {
+                continue;
+            }
+            try!(self.command(codec, group, VERB_SET_POWER_STATE << 8));
+
+            let mut widgets = Vec::new();
+            let (first_widget, count) = try!(self.nodes(codec, group));
+            for node in first_widget..first_widget.saturating_add(count) {
+                let caps = try!(self.parameter(codec, node, PARAM_WIDGET_CAPS));
+                let kind = ((caps >> 20) & 0xF) as u8;
+                let (pin_caps, config) = if kind == WIDGET_PIN {
+                    (try!(self.parameter(codec, node, PARAM_PIN_CAPS)), try!(self.command(codec, node, VERB_GET_CONFIG_DEFAULT << 8)))
+                } else {
+                    (0, 0)
                 };
-                try!(syscall::time::nanosleep(&req, Some(&mut rem)));
+                widgets.push(Widget {
+                    node: node,
+                    kind: kind,
+                    caps: caps,
+                    pin_caps: pin_caps,
+                    config: config,
+                    connections: try!(self.connections(codec, node)),
+                });
             }
 
-            stream.interrupt = 0;
-            // stream.control = 0;
-            // stream.status = 0;
-            // stream.cbl = 0;
-            // stream.lvi = 0;
-            // stream.bdlpl = 0;
-            // memory::unalloc(bd_addr);
-            // memory::unalloc(bdl as usize);
-            //
+            // A path to the first pin of each kind, which all play the stream
+            let mut pins = Vec::new();
+            let mut dac = None;
+            for &device in [DEVICE_LINE_OUT, DEVICE_SPEAKER, DEVICE_HP_OUT].iter() {
+                for pin in widgets.iter().filter(|widget| {
+                    widget.kind == WIDGET_PIN && widget.pin_caps & PIN_CAPS_OUTPUT == PIN_CAPS_OUTPUT
+                        && widget.config >> 30 != PORT_NONE && (widget.config >> 20) & 0xF == device
+                }) {
+                    let mut path = Vec::new();
+                    if find_path(&widgets, pin.node, 10, &mut path) {
+                        syslog_info!("Intel HDA: Codec {} output path {:?}", codec, path);
+                        try!(self.configure(codec, group, &widgets, &path));
+                        if dac.is_none() {
+                            dac = Some(path[path.len() - 1].0);
+                        }
+                        pins.push(pin);
+                        break;
+                    }
+                }
+            }
 
-            Ok(buf.len())
+            if let Some(dac) = dac {
+                self.setup_jack(codec, &pins);
+                return self.start_output(codec, dac).map(Some);
+            }
         }
+
+        Ok(None)
     }
-}
 
-pub struct IntelHda {
-    pub pci: PciConfig,
-    pub base: usize,
-    pub memory_mapped: bool,
-    pub irq: u8,
-}
+    /// Turn on jack sensing on the headphone pin, if it has presence detect and there are speakers to mute
+    fn setup_jack(&mut self, codec: u8, pins: &[&Widget]) {
+        let headphone = match pins.iter().find(|pin| {
+            (pin.config >> 20) & 0xF == DEVICE_HP_OUT && pin.pin_caps & PIN_CAPS_PRESENCE == PIN_CAPS_PRESENCE
+                && pin.config & CONFIG_NO_PRESENCE == 0
+        }) {
+            Some(pin) => pin.node,
+            None => return,
+        };
+        let speakers: Vec<Speaker> = pins.iter().filter(|pin| (pin.config >> 20) & 0xF == DEVICE_SPEAKER).map(|pin| Speaker {
+            node: pin.node,
+            control: PIN_OUT_ENABLE,
+            eapd: pin.pin_caps & PIN_CAPS_EAPD == PIN_CAPS_EAPD,
+        }).collect();
+        if speakers.is_empty() {
+            return;
+        }
 
-impl KScheme for IntelHda {
-    fn scheme(&self) -> &str {
-        "hda"
+        if let Err(err) = self.command(codec, headphone, VERB_SET_UNSOLICITED << 8 | UNSOLICITED_ENABLE | JACK_TAG) {
+            syslog_warning!("Intel HDA: Failed to enable jack sensing: {}", err);
+            return;
+        }
+        self.jack = Some(Jack {
+            codec: codec,
+            node: headphone,
+            speakers: speakers,
+            plugged: None,
+        });
+        self.sense_jack();
     }
 
-    fn open(&mut self, _: &str, _: usize) -> syscall::Result<Box<Resource>> {
-        Ok(box IntelHdaResource { base: self.base })
+    /// The capabilities of the output or input amplifier of `widget`, from the function group unless it has its own
+    fn amp_caps(&mut self, codec: u8, group: u8, widget: &Widget, output: bool) -> syscall::Result<u32> {
+        let node = if widget.caps & CAPS_AMP_OVERRIDE == CAPS_AMP_OVERRIDE { widget.node } else { group };
+        self.parameter(codec, node, if output { PARAM_OUT_AMP_CAPS } else { PARAM_IN_AMP_CAPS })
     }
 
-    fn on_irq(&mut self, irq: u8) {
-        if irq == self.irq {
-            // d("HDA IRQ\n");
+    /// Power up the widgets of `path`, select the connection each takes and unmute their amplifiers at 0 dB
+    fn configure(&mut self, codec: u8, group: u8, widgets: &[Widget], path: &[(u8, usize)]) -> syscall::Result<()> {
+        let mut first_amp = true;
+        for &(node, index) in path.iter() {
+            let widget = match widgets.iter().find(|widget| widget.node == node) {
+                Some(widget) => widget,
+                None => continue,
+            };
+
+            try!(self.command(codec, node, VERB_SET_POWER_STATE << 8));
+            if widget.connections.len() > 1 && widget.kind != WIDGET_MIXER {
+                try!(self.command(codec, node, VERB_SET_CONNECTION_SELECT << 8 | index as u32));
+            }
+            if widget.caps & CAPS_OUT_AMP == CAPS_OUT_AMP {
+                let offset = try!(self.amp_caps(codec, group, widget, true)) & 0x7F;
+                try!(self.command(codec, node, VERB_SET_AMP << 16 | AMP_OUTPUT | AMP_LEFT | AMP_RIGHT | offset));
+                // The volume is set on the converter if it has an amplifier, otherwise on the one nearest the pin
+                if first_amp || widget.kind == WIDGET_OUTPUT {
+                    if ! first_amp {
+                        self.amps.pop();
+                    }
+                    first_amp = false;
+                    self.amps.push(Amp {
+                        codec: codec,
+                        node: node,
+                        offset: offset,
+                    });
+                }
+            }
+            // A mixer adds its inputs, only the one on the path is unmuted
+            if widget.kind == WIDGET_MIXER && widget.caps & CAPS_IN_AMP == CAPS_IN_AMP {
+                let offset = try!(self.amp_caps(codec, group, widget, false)) & 0x7F;
+                try!(self.command(codec, node, VERB_SET_AMP << 16 | AMP_INPUT | AMP_LEFT | AMP_RIGHT | (index as u32) << 8 | offset));
+            }
+
+            match widget.kind {
+                WIDGET_PIN => {
+                    let headphone = (widget.config >> 20) & 0xF == DEVICE_HP_OUT;
+                    try!(self.command(codec, node, VERB_SET_PIN_CONTROL << 8 | PIN_OUT_ENABLE | if headphone { PIN_HP_ENABLE } else { 0 }));
+                    if widget.pin_caps & PIN_CAPS_EAPD == PIN_CAPS_EAPD {
+                        try!(self.command(codec, node, VERB_SET_EAPD << 8 | 1 << 1));
+                    }
+                },
+                WIDGET_OUTPUT => {
+                    try!(self.command(codec, node, VERB_SET_STREAM_CHANNEL << 8 | STREAM_TAG << 4));
+                    try!(self.command(codec, node, VERB_SET_FORMAT << 16 | FORMAT_48K_16_STEREO as u32));
+                },
+                _ => (),
+            }
         }
+
+        Ok(())
     }
-}
 
-impl IntelHda {
-    pub unsafe fn new(mut pci: PciConfig) -> Box<IntelHda> {
-        let base = pci.read(0x10) as usize;
-        let mut module = box IntelHda {
-            pci: pci,
-            base: base & 0xFFFFFFF0,
-            memory_mapped: base & 1 == 0,
-            irq: pci.read(0x3C) as u8 & 0xF,
-        };
-        module.init();
-        module
+    /// Start the first output stream descriptor, playing a ring of silence until something is written
+    unsafe fn start_output(&mut self, codec: u8, dac: u8) -> syscall::Result<Arc<UnsafeCell<HdaOutput>>> {
+        let gcap = self.reg16(GCAP).read() as usize;
+        let (inputs, outputs) = ((gcap >> 8) & 0xF, (gcap >> 12) & 0xF);
+        if outputs == 0 {
+            return Err(syscall::Error::new(syscall::ENODEV));
+        }
+        let sd = self.base + SD_BASE + inputs * 0x20;
+        if sd + 0x20 > self.base + self.size {
+            return Err(syscall::Error::new(syscall::ENODEV));
+        }
+
+        let ctl = &mut *((sd + SD_CTL) as *mut Mmio<u32>);
+        ctl.writef(SD_CTL_RUN, false);
+        ctl.writef(SD_CTL_SRST, true);
+        if ! wait_for(10, || ctl.readf(SD_CTL_SRST)) {
+            return Err(syscall::Error::new(syscall::ETIMEDOUT));
+        }
+        ctl.writef(SD_CTL_SRST, false);
+        if ! wait_for(10, || ! ctl.readf(SD_CTL_SRST)) {
+            return Err(syscall::Error::new(syscall::ETIMEDOUT));
+        }
+
+        let ring = try!(PlaybackRing::new(BUFFERS * BUFFER_SIZE, 128, MARGIN));
+        let mut bdl = try!(Memory::<Bd>::new_aligned(BUFFERS, 128));
+        for i in 0..BUFFERS {
+            bdl[i].addr.write(physical(ring.data.address() + i * BUFFER_SIZE));
+            bdl[i].addru.write(0);
+            bdl[i].len.write(BUFFER_SIZE as u32);
+            bdl[i].ioc.write(0);
+        }
+
+        (*((sd + SD_BDPL) as *mut Mmio<u32>)).write(physical(bdl.address()));
+        (*((sd + SD_BDPU) as *mut Mmio<u32>)).write(0);
+        (*((sd + SD_CBL) as *mut Mmio<u32>)).write(ring.data.len() as u32);
+        (*((sd + SD_LVI) as *mut Mmio<u16>)).write(BUFFERS as u16 - 1);
+        (*((sd + SD_FMT) as *mut Mmio<u16>)).write(FORMAT_48K_16_STEREO);
+        let value = ctl.read();
+        ctl.write((value & ! (0xF << 20)) | STREAM_TAG << 20);
+        ctl.writef(SD_CTL_RUN, true);
+
+        syslog_info!("Intel HDA: Codec {} converter {} on output stream {}", codec, dac, inputs);
+
+        Ok(Arc::new(UnsafeCell::new(HdaOutput {
+            sd: sd,
+            _bdl: bdl,
+            ring: ring,
+        })))
     }
+}
 
-    pub unsafe fn init(&mut self) {
-        syslog_info!(" + Intel HDA on: {:X}, IRQ {:X}", self.base, self.irq);
+/// Find a path from `node` to an output converter, through mixers and selectors, as each node with the
+/// connection it takes
+fn find_path(widgets: &[Widget], node: u8, depth: usize, path: &mut Vec<(u8, usize)>) -> bool {
+    let widget = match widgets.iter().find(|widget| widget.node == node) {
+        Some(widget) => widget,
+        None => return false,
+    };
+
+    if widget.kind == WIDGET_OUTPUT {
+        path.push((node, 0));
+        return true;
+    }
+    if depth == 0 || path.iter().any(|&(other, _)| other == node) {
+        return false;
+    }
 
-        return;
-        // let pci = &mut self.pci;
-        //
-        // pci.flag(4, 4, true); // Bus mastering
-        //
-        // let gcap = (self.base) as *mut u16;
-        // let gctl = (self.base + 0x8) as *mut u32;
-        // let statests = (self.base + 0xE) as *mut u16;
-        //
-        // let corb = (self.base + 0x40) as *mut u32;
-        // let corbwp = (self.base + 0x48) as *mut u16;
-        // let corbrp = (self.base + 0x4A) as *mut u16;
-        // let corbctl = (self.base + 0x4C) as *mut u8;
-        // let corbsize = (self.base + 0x4E) as *mut u8;
-        //
-        // let rirb = (self.base + 0x50) as *mut u32;
-        // let rirbwp = (self.base + 0x58) as *mut u16;
-        // let rintcnt = (self.base + 0x5A) as *mut u16;
-        // let rirbctl = (self.base + 0x5C) as *mut u8;
-        // let rirbsize = (self.base + 0x5E) as *mut u8;
-        //
-        // let iss = (ptr::read(gcap) as usize >> 12) & 0b1111;
-        //
-        // let oss = (ptr::read(gcap) as usize >> 8) & 0b1111;
-        //
-        // let bss = (ptr::read(gcap) as usize >> 3) & 0b11111;
-        //
-        // ptr::write(gctl, 0);
-        // loop {
-        // if ptr::read(gctl) & 1 == 0 {
-        // break;
-        // }
-        // }
-        //
-        // ptr::write(gctl, 1);
-        // loop {
-        // if ptr::read(gctl) & 1 == 1 {
-        // break;
-        // }
-        // }
-        //
-        // let disable = scheduler::start_ints();
-        // Duration::new(0, 10 * time::NANOS_PER_MILLI).sleep();
-        // scheduler::end_ints(disable);
-        //
-        // let corb_ptr = memory::alloc(256 * 4) as *mut u32;
-        // {
-        // ptr::write(corbctl, 0);
-        // loop {
-        // if ptr::read(corbctl) & 1 << 1 == 0 {
-        // break;
-        // }
-        // }
-        //
-        // ptr::write(corb, corb_ptr as u32);
-        // ptr::write(corbsize, 0b10);
-        // ptr::write(corbrp, 1 << 15);
-        // loop {
-        // if ptr::read(corbrp) == 1 << 15 {
-        // break;
-        // }
-        // }
-        // ptr::write(corbrp, 0);
-        // loop {
-        // if ptr::read(corbrp) == 0 {
-        // break;
-        // }
-        // }
-        // ptr::write(corbwp, 0);
-        //
-        // ptr::write(corbctl, 1 << 1);
-        // loop {
-        // if ptr::read(corbctl) & 1 << 1 == 1 << 1 {
-        // break;
-        // }
-        // }
-        // }
-        //
-        // let rirb_ptr = memory::alloc(256 * 8) as *mut u64;
-        // {
-        // ptr::write(rirbctl, 0);
-        // loop {
-        // if ptr::read(rirbctl) & 1 << 1 == 0 {
-        // break;
-        // }
-        // }
-        //
-        // ptr::write(rirb, rirb_ptr as u32);
-        // ptr::write(rirbsize, 0b10);
-        // ptr::write(rirbwp, 1 << 15);
-        // ptr::write(rintcnt, 0xFF);
-        //
-        // ptr::write(rirbctl, 1 << 1);
-        // loop {
-        // if ptr::read(rirbctl) & 1 << 1 == 1 << 1 {
-        // break;
-        // }
-        // }
-        // }
-        //
-        // let cmd = |command: u32| -> u64 {
-        // let corb_i = (ptr::read(corbwp) + 1) & 0xFF;
-        // let rirb_i = (ptr::read(rirbwp) + 1) & 0xFF;
-        //
-        //
-        // d("CORB ");
-        // dd(corb_i as usize);
-        // d(" RIRB ");
-        // dd(rirb_i as usize);
-        // dl();
-        //
-        //
-        // ptr::write(corb_ptr.offset(corb_i as isize), command);
-        // ptr::write(corbwp, corb_i);
-        //
-        // loop {
-        // if ptr::read(rirbwp) == rirb_i {
-        // break;
-        // }
-        // }
-        //
-        // ptr::read(rirb_ptr.offset(rirb_i as isize))
-        // };
-        //
-        // let mut output_stream_id = 1;
-        //
-        // let root_nodes_packed = cmd(0xF0004);
-        // let root_nodes_start = (root_nodes_packed >> 16) as u32;
-        // let root_nodes_length = (root_nodes_packed & 0xFFFF) as u32;
-        //
-        // debug!("Root Sub-Nodes ");
-        // debug::dd(root_nodes_start as usize);
-        // debug!(" ");
-        // debug::dd(root_nodes_length as usize);
-        // debug::dl();
-        //
-        // for fg_node in root_nodes_start..root_nodes_start + root_nodes_length {
-        // debug!("  Function Group ");
-        // debug::dd(fg_node as usize);
-        // debug::dl();
-        //
-        // let fg_type = cmd(fg_node << 20 | 0xF0005);
-        //
-        // debug!("    Type ");
-        // debug::dh(fg_type as usize);
-        // debug::dl();
-        //
-        // let fg_nodes_packed = cmd(fg_node << 20 | 0xF0004);
-        // let fg_nodes_start = (fg_nodes_packed >> 16) as u32;
-        // let fg_nodes_length = (fg_nodes_packed & 0xFFFF) as u32;
-        //
-        // debug!("    Sub-Nodes ");
-        // debug::dd(fg_nodes_start as usize);
-        // debug!(" ");
-        // debug::dd(fg_nodes_length as usize);
-        // debug::dl();
-        //
-        // for w_node in fg_nodes_start..fg_nodes_start + fg_nodes_length {
-        // debug!("      Widget ");
-        // debug::dh(w_node as usize);
-        // debug::dl();
-        //
-        // let w_caps = cmd(w_node << 20 | 0xF0009);
-        //
-        // debug!("        Capabilities ");
-        // debug::dh(w_caps as usize);
-        // debug::dl();
-        //
-        // match w_caps >> 20 {
-        // 0 => {
-        // debug!("        Type: Output\n");
-        //
-        // debug!("        Sample Rate and Bits ");
-        // debug::dh(cmd(w_node << 20 | 0xF000A) as usize);
-        // debug::dl();
-        //
-        // debug!("        Sample Format ");
-        // debug::dh(cmd(w_node << 20 | 0xF000B) as usize);
-        // debug::dl();
-        //
-        // debug!("        Output Stream (Before) ");
-        // debug::dh(cmd(w_node << 20 | 0xF0600) as usize);
-        // debug::dl();
-        //
-        // cmd(w_node << 20 | 0x70600 | (output_stream_id as u32) << 4);
-        //
-        // debug!("        Output Stream (After) ");
-        // debug::dh(cmd(w_node << 20 | 0xF0600) as usize);
-        // debug::dl();
-        //
-        // debug!("        Format (Before) ");
-        // debug::dh(cmd(w_node << 20 | 0xA0000) as usize);
-        // debug::dl();
-        //
-        // cmd(w_node << 20 | 0x20000 | 0b0000000000010001);
-        //
-        // debug!("        Format (After) ");
-        // debug::dh(cmd(w_node << 20 | 0xA0000) as usize);
-        // debug::dl();
-        //
-        //
-        // debug!("        Amplifier Gain/Mute (Before) ");
-        // debug::dh(cmd(w_node << 20 | 0xB0000 | 1 << 15 | 1 << 13) as usize);
-        // debug!(" ");
-        // debug::dh(cmd(w_node << 20 | 0xB0000 | 1 << 15) as usize);
-        // debug::dl();
-        //
-        // cmd(w_node << 20 | 0x30000 | 1 << 15 | 1 << 13 | 1 << 12 | 0b111111);
-        //
-        // debug!("        Amplifier Gain/Mute (After) ");
-        // debug::dh(cmd(w_node << 20 | 0xB0000 | 1 << 15 | 1 << 13) as usize);
-        // debug!(" ");
-        // debug::dh(cmd(w_node << 20 | 0xB0000 | 1 << 15) as usize);
-        // debug::dl();
-        //
-        //
-        // output_stream_id += 1;
-        // }
-        // 1 => {
-        // debug!("        Type: Input\n");
-        //
-        // debug!("        Input Stream ");
-        // debug::dh(cmd(w_node << 20 | 0xF0600) as usize);
-        // debug::dl();
-        // }
-        // 2 => debug!("        Type: Mixer\n"),
-        // 3 => debug!("        Type: Selector\n"),
-        // 4 => {
-        // debug!("        Type: Pin\n");
-        //
-        // debug!("        Pin Capabilities ");
-        // debug::dh(cmd(w_node << 20 | 0xF000C) as usize);
-        // debug::dl();
-        //
-        // debug!("        Pin Control (Before) ");
-        // debug::dh(cmd(w_node << 20 | 0xF0700) as usize);
-        // debug::dl();
-        //
-        // cmd(w_node << 20 | 0x70700 | 0b11100101);
-        //
-        // debug!("        Pin Control (After) ");
-        // debug::dh(cmd(w_node << 20 | 0xF0700) as usize);
-        // debug::dl();
-        //
-        //
-        // debug!("        Pin EAPD/BTL (Before) ");
-        // debug::dh(cmd(w_node << 20 | 0xF0C00) as usize);
-        // debug::dl();
-        //
-        // cmd(w_node << 20 | 0x70C00 | 1 << 1);
-        //
-        // debug!("        Pin EAPD/BPL (After) ");
-        // debug::dh(cmd(w_node << 20 | 0xF0C00) as usize);
-        // debug::dl();
-        //
-        // debug!("        Amplifier Gain/Mute (Before) ");
-        // debug::dh(cmd(w_node << 20 | 0xB0000 | 1 << 15 | 1 << 13) as usize);
-        // debug!(" ");
-        // debug::dh(cmd(w_node << 20 | 0xB0000 | 1 << 15) as usize);
-        // debug::dl();
-        //
-        // cmd(w_node << 20 | 0x30000 | 1 << 15 | 1 << 13 | 1 << 12 | 0b111111);
-        //
-        // debug!("        Amplifier Gain/Mute (After) ");
-        // debug::dh(cmd(w_node << 20 | 0xB0000 | 1 << 15 | 1 << 13) as usize);
-        // debug!(" ");
-        // debug::dh(cmd(w_node << 20 | 0xB0000 | 1 << 15) as usize);
-        // debug::dl();
-        //
-        // }
-        // 5 => debug!("        Type: Power\n"),
-        // 6 => debug!("        Type: Volume\n"),
-        // 7 => debug!("        Type: Beep Generator\n"),
-        // _ => {
-        // debug!("        Type: Unknown\n");
-        // }
-        // }
-        // }
-        // }
-        //
+    for (i, &next) in widget.connections.iter().enumerate() {
+        let passes = widgets.iter().any(|other| other.node == next && match other.kind {
+            WIDGET_OUTPUT | WIDGET_MIXER | WIDGET_SELECTOR => true,
+            _ => false,
+        });
+        if passes {
+            path.push((node, i));
+            if find_path(widgets, next, depth - 1, path) {
+                return true;
+            }
+            path.pop();
+        }
     }
+
+    false
 }
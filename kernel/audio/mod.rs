@@ -1,2 +1,342 @@
 pub mod ac97;
 pub mod intelhda;
+pub mod pcspkr;
+pub mod scheme;
+pub mod wav;
+
+use alloc::boxed::Box;
+
+use arch::memory::Memory;
+
+use collections::string::{String, ToString};
+use collections::vec::Vec;
+
+use common::to_num::ToNum;
+
+use core::cmp;
+
+use fs::Resource;
+
+use system::error::{Error, Result, ENOENT};
+
+/// The bytes in a 16 bit stereo frame, the format every device plays
+pub const FRAME_SIZE: usize = 4;
+
+/// The format of the samples written to a playback stream
+#[derive(Copy, Clone)]
+pub struct Format {
+    pub rate: u32,
+    pub channels: u8,
+    pub bits: u8,
+}
+
+impl Format {
+    /// The format given in a path like `44100/1/8`, the channels and bits may be left out
+    pub fn parse(path: &str) -> Option<Format> {
+        let mut format = Format {
+            rate: 48000,
+            channels: 2,
+            bits: 16,
+        };
+        for (i, part) in path.split('/').enumerate() {
+            if part.is_empty() || ! part.chars().all(|c| c.is_digit(10)) {
+                return None;
+            }
+            match i {
+                0 => format.rate = part.to_num() as u32,
+                1 => format.channels = part.to_num() as u8,
+                2 => format.bits = part.to_num() as u8,
+                _ => return None,
+            }
+        }
+
+        if format.valid() {
+            Some(format)
+        } else {
+            None
+        }
+    }
+
+    /// Whether streams can be played in this format
+    pub fn valid(&self) -> bool {
+        self.rate >= 4000 && self.rate <= 192000 && (self.channels == 1 || self.channels == 2)
+            && (self.bits == 8 || self.bits == 16)
+    }
+
+    /// The bytes in one frame
+    pub fn frame_size(&self) -> usize {
+        self.channels as usize * self.bits as usize / 8
+    }
+
+    /// The stereo frames in `data`, 8 bit samples are unsigned and 16 bit ones little endian
+    pub fn frames(&self, data: &[u8]) -> Vec<(i16, i16)> {
+        let size = self.frame_size();
+        data.chunks(size).filter(|frame| frame.len() == size).map(|frame| {
+            let sample = |i: usize| if self.bits == 8 {
+                ((frame[i] as i16) - 128) << 8
+            } else {
+                (frame[i * 2] as u16 | (frame[i * 2 + 1] as u16) << 8) as i16
+            };
+            if self.channels == 2 {
+                (sample(0), sample(1))
+            } else {
+                (sample(0), sample(0))
+            }
+        }).collect()
+    }
+
+    /// `frames` in this format, stereo is averaged for mono and 8 bit samples are scaled down
+    pub fn encode(&self, frames: &[(i16, i16)]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(frames.len() * self.frame_size());
+        for &(left, right) in frames.iter() {
+            let samples = if self.channels == 2 {
+                [left, right]
+            } else {
+                [((left as i32 + right as i32) / 2) as i16, 0]
+            };
+            for &sample in samples.iter().take(self.channels as usize) {
+                if self.bits == 8 {
+                    data.push(((sample >> 8) + 128) as u8);
+                } else {
+                    data.push(sample as u8);
+                    data.push((sample >> 8) as u8);
+                }
+            }
+        }
+        data
+    }
+}
+
+/// A linear interpolation from one rate to another, which carries on from one write to the next
+#[derive(Copy, Clone, Default)]
+pub struct Resampler {
+    /// How far past the last frame of the previous write the next output frame is, in 1/65536ths of a frame
+    position: u64,
+    last: (i16, i16),
+}
+
+impl Resampler {
+    /// `frames` at `from`, at `to`
+    pub fn resample(&mut self, frames: &[(i16, i16)], from: u32, to: u32) -> Vec<(i16, i16)> {
+        if from == to {
+            return frames.to_vec();
+        }
+
+        let mut data = Vec::new();
+        if let Some(&end) = frames.last() {
+            // The last frame of the previous write comes first, so the first output frames interpolate from it
+            let last = self.last;
+            let at = |i: usize| if i == 0 { last } else { frames[i - 1] };
+            let step = ((from as u64) << 16) / to as u64;
+            let mut position = self.position;
+            while (position >> 16) < frames.len() as u64 {
+                let i = (position >> 16) as usize;
+                let fraction = (position & 0xFFFF) as i32;
+                let (a, b) = (at(i), at(i + 1));
+                data.push(((a.0 as i32 + ((b.0 as i32 - a.0 as i32) * fraction >> 16)) as i16,
+                           (a.1 as i32 + ((b.1 as i32 - a.1 as i32) * fraction >> 16)) as i16));
+                position += step;
+            }
+            self.position = position - ((frames.len() as u64) << 16);
+            self.last = end;
+        }
+
+        data
+    }
+}
+
+/// The conversion of samples from the format of a stream to the format of a device, or back for recording
+///
+/// Samples go through 16 bit stereo: they are decoded, resampled, then encoded, and each step is left out
+/// when the formats agree on it.
+pub struct Converter {
+    pub from: Format,
+    pub to: Format,
+    resampler: Resampler,
+}
+
+impl Converter {
+    pub fn new(from: Format, to: Format) -> Converter {
+        Converter {
+            from: from,
+            to: to,
+            resampler: Resampler::default(),
+        }
+    }
+
+    /// The steps taken, like `mono->stereo u8->s16 44100->48000`, or `none`
+    pub fn steps(&self) -> String {
+        let mut steps = String::new();
+        if self.from.channels != self.to.channels {
+            steps.push_str(if self.from.channels == 1 { " mono->stereo" } else { " stereo->mono" });
+        }
+        if self.from.bits != self.to.bits {
+            steps.push_str(if self.from.bits == 8 { " u8->s16" } else { " s16->u8" });
+        }
+        if self.from.rate != self.to.rate {
+            steps.push_str(&format!(" {}->{}", self.from.rate, self.to.rate));
+        }
+
+        if steps.is_empty() {
+            "none".to_string()
+        } else {
+            steps.trim_left().to_string()
+        }
+    }
+
+    /// The whole frames of `data` as 16 bit stereo at the rate converted to, which is how they are mixed
+    pub fn frames(&mut self, data: &[u8]) -> Vec<(i16, i16)> {
+        let frames = self.from.frames(data);
+        self.resampler.resample(&frames, self.from.rate, self.to.rate)
+    }
+
+    /// The whole frames of `data` in the format converted to
+    pub fn convert(&mut self, data: &[u8]) -> Vec<u8> {
+        let frames = self.frames(data);
+        self.to.encode(&frames)
+    }
+}
+
+/// 16 bit stereo samples in a ring the device plays round and round
+///
+/// What was played is silenced, so the device plays silence once it runs out of samples instead of
+/// playing the ring again.
+pub struct PlaybackRing {
+    pub data: Memory<u8>,
+    /// Where the next write goes
+    write: usize,
+    /// The position of the device when it was last updated
+    played: usize,
+    /// Bytes kept clear ahead of the device position, which it may have fetched already
+    margin: usize,
+    /// Everything written was played
+    idle: bool,
+    /// Times the device ran out of samples
+    pub underruns: usize,
+}
+
+impl PlaybackRing {
+    /// A silent ring of `size` bytes, aligned for the DMA engine of the device
+    pub fn new(size: usize, align: usize, margin: usize) -> Result<PlaybackRing> {
+        let mut data = try!(Memory::<u8>::new_aligned(size, align));
+        for i in 0..size {
+            data[i] = 0;
+        }
+
+        Ok(PlaybackRing {
+            data: data,
+            write: margin,
+            played: 0,
+            margin: margin,
+            idle: true,
+            underruns: 0,
+        })
+    }
+
+    /// The bytes ahead of the device, silence included if nothing was written since it went idle
+    fn ahead(&self) -> usize {
+        (self.write + self.data.len() - self.played) % self.data.len()
+    }
+
+    /// The bytes written and not played yet
+    pub fn queued(&self) -> usize {
+        if self.idle { 0 } else { self.ahead() }
+    }
+
+    /// The bytes that can be written without catching up with the device
+    pub fn space(&self) -> usize {
+        (self.data.len() - self.ahead()).saturating_sub(self.margin) / FRAME_SIZE * FRAME_SIZE
+    }
+
+    /// Silence what the device played since the last update, now that it is at `position`
+    ///
+    /// If it went past the end of what was written, the next write goes just ahead of it.
+    pub fn update(&mut self, position: usize) {
+        let len = self.data.len();
+        let position = position % len;
+        let played = (position + len - self.played) % len;
+        let ahead = self.ahead();
+        for i in 0..played {
+            self.data[(self.played + i) % len] = 0;
+        }
+        self.played = position;
+
+        if played >= ahead && ! self.idle {
+            self.idle = true;
+            self.underruns += 1;
+        }
+        if self.idle {
+            self.write = (position + self.margin) % len;
+        }
+    }
+
+    /// Copy the whole frames of `buf` that fit after what was written before
+    pub fn write(&mut self, buf: &[u8]) -> usize {
+        let count = cmp::min(self.space(), buf.len() / FRAME_SIZE * FRAME_SIZE);
+        let len = self.data.len();
+        for i in 0..count {
+            self.data[(self.write + i) % len] = buf[i];
+        }
+        self.write = (self.write + count) % len;
+        if count > 0 {
+            self.idle = false;
+        }
+
+        count
+    }
+
+    /// Silence what was written and not played
+    pub fn clear(&mut self) {
+        for i in 0..self.data.len() {
+            self.data[i] = 0;
+        }
+        self.idle = true;
+        self.write = (self.played + self.margin) % self.data.len();
+    }
+
+    /// Go back to the start of the ring, for a device whose DMA engine was reset
+    pub fn reset(&mut self) {
+        self.played = 0;
+        self.clear();
+    }
+}
+
+/// A sound card driver, wrapped by `AudioScheme` to provide the audio: scheme
+///
+/// The device plays one stream, which the scheme mixes the streams opened on it into.
+pub trait AudioDevice {
+    /// The driver name
+    fn name(&self) -> &str;
+    /// Acknowledge an interrupt, returning whether it was for this device
+    fn on_irq(&mut self, irq: u8) -> bool;
+    /// Start a stream of `format`, returning the rate the 16 bit stereo samples given to `write` have to be at
+    fn open(&mut self, format: &Format) -> Result<u32>;
+    /// Queue as many whole frames of `buf` as fit without waiting, returning how many bytes were taken
+    fn write(&mut self, buf: &[u8]) -> usize;
+    /// The bytes `write` takes without waiting
+    fn space(&mut self) -> usize;
+    /// The bytes written and not played yet, which is the latency of the next write
+    fn queued(&mut self) -> usize;
+    /// Times the device ran out of samples to play
+    fn underruns(&self) -> usize;
+    /// The playback volume in percent and whether it is muted
+    fn volume(&self) -> (usize, bool);
+    /// Change the playback volume
+    fn set_volume(&mut self, percent: usize, mute: bool) -> Result<()>;
+    /// Drop what is queued, the stream stays open
+    fn flush(&mut self);
+    /// End the stream, what is queued is dropped
+    fn stop(&mut self);
+    /// Open a control file of the driver, like a mixer
+    fn control(&mut self, _name: &str) -> Result<Box<Resource>> {
+        Err(Error::new(ENOENT))
+    }
+    /// Whether headphones are plugged in, if the device can tell
+    fn jack(&self) -> Option<bool> {
+        None
+    }
+    /// Lines the driver adds to the status of the device
+    fn status(&self) -> String {
+        String::new()
+    }
+}
@@ -0,0 +1,224 @@
+use alloc::boxed::Box;
+
+use collections::vec_deque::VecDeque;
+
+use common::time::{self, Duration};
+use common::to_num::ToNum;
+
+use core::str;
+
+use drivers::io::{Io, Pio};
+
+use env::log::InterruptGuard;
+
+use fs::{KScheme, Resource};
+
+use system::error::{Error, Result, EINVAL, ENOSPC};
+
+/// The frequency the PIT counts at
+const PIT_FREQUENCY: u32 = 1193182;
+/// The lowest and highest tone, the divisor of the PIT is 16 bit
+const MIN_FREQUENCY: u32 = 19;
+const MAX_FREQUENCY: u32 = 20000;
+/// The longest one tone may last
+const MAX_DURATION: u32 = 10000;
+/// Tones waiting to be played at once
+const MAX_TONES: usize = 64;
+
+/// A tone, a frequency of 0 is a rest
+#[derive(Copy, Clone)]
+struct Tone {
+    frequency: u32,
+    ms: u32,
+}
+
+/// The tones waiting to be played after the current one
+static mut BEEP_QUEUE: Option<VecDeque<Tone>> = None;
+
+/// When the current tone ends, the speaker is silenced by the timer interrupt at that time
+static mut BEEP_END: Option<Duration> = None;
+
+/// Program channel 2 of the PIT to `frequency` and open the speaker gate
+unsafe fn tone_on(frequency: u32) {
+    let divisor = PIT_FREQUENCY / frequency;
+    Pio::<u8>::new(0x43).write(0xB6);
+    Pio::<u8>::new(0x42).write(divisor as u8);
+    Pio::<u8>::new(0x42).write((divisor >> 8) as u8);
+
+    let mut gate = Pio::<u8>::new(0x61);
+    let value = gate.read();
+    gate.write(value | 3);
+}
+
+/// Close the speaker gate
+unsafe fn tone_off() {
+    let mut gate = Pio::<u8>::new(0x61);
+    let value = gate.read();
+    gate.write(value & !3);
+}
+
+/// Start `tone`, it ends at the first timer interrupt after its duration
+unsafe fn start(tone: Tone) {
+    if tone.frequency == 0 {
+        tone_off();
+    } else {
+        tone_on(tone.frequency);
+    }
+    BEEP_END = Some(Duration::monotonic() + Duration::new((tone.ms / 1000) as i64,
+                                                          (tone.ms % 1000) as i32 * time::NANOS_PER_MILLI));
+}
+
+/// Queue `tone`, starting it if the speaker is silent
+///
+/// The timer interrupt moves the queue on, so it is held off meanwhile.
+unsafe fn queue(tone: Tone) -> Result<()> {
+    let _guard = InterruptGuard::new();
+    if BEEP_END.is_none() {
+        start(tone);
+        return Ok(());
+    }
+
+    if BEEP_QUEUE.is_none() {
+        BEEP_QUEUE = Some(VecDeque::new());
+    }
+    match BEEP_QUEUE {
+        Some(ref mut tones) if tones.len() < MAX_TONES => {
+            tones.push_back(tone);
+            Ok(())
+        },
+        _ => Err(Error::new(ENOSPC)),
+    }
+}
+
+/// Called on every timer interrupt, ends the current tone once it has lasted long enough
+///
+/// Tones are timed here instead of by the process that asked for them, so one it queued stops when
+/// it should even if it exits or is killed while the speaker is on.
+pub fn on_tick() {
+    unsafe {
+        if let Some(end) = BEEP_END {
+            if Duration::monotonic() >= end {
+                let next = match BEEP_QUEUE {
+                    Some(ref mut tones) => tones.pop_front(),
+                    None => None,
+                };
+                match next {
+                    Some(tone) => start(tone),
+                    None => {
+                        tone_off();
+                        BEEP_END = None;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Beep at `frequency` for `ms` milliseconds, after the tones already queued
+pub fn beep(frequency: u32, ms: u32) {
+    if frequency >= MIN_FREQUENCY && frequency <= MAX_FREQUENCY {
+        let _ = unsafe { queue(Tone {
+            frequency: frequency,
+            ms: ms,
+        }) };
+    }
+}
+
+/// Beep while interrupts are off and the timer is not counting, like on a panic
+///
+/// Each write to the POST port takes about a microsecond, which times the tone.
+pub fn beep_blocking(frequency: u32, ms: u32) {
+    unsafe {
+        BEEP_END = None;
+        BEEP_QUEUE = None;
+
+        tone_on(frequency);
+        let mut post = Pio::<u8>::new(0x80);
+        for _ in 0..ms * 1000 {
+            post.write(0);
+        }
+        tone_off();
+    }
+}
+
+/// The tones in `string`, pairs like `440,200` separated by spaces or lines
+fn parse(string: &str) -> Option<VecDeque<Tone>> {
+    let mut tones = VecDeque::new();
+    for pair in string.split_whitespace() {
+        let mut parts = pair.split(',');
+        let frequency = parts.next().unwrap_or("");
+        let ms = parts.next().unwrap_or("");
+        if parts.next().is_some() || frequency.is_empty() || ms.is_empty()
+            || ! frequency.chars().chain(ms.chars()).all(|c| c.is_digit(10)) {
+            return None;
+        }
+
+        let tone = Tone {
+            frequency: frequency.to_num() as u32,
+            ms: ms.to_num() as u32,
+        };
+        if (tone.frequency != 0 && (tone.frequency < MIN_FREQUENCY || tone.frequency > MAX_FREQUENCY))
+            || tone.ms > MAX_DURATION {
+            return None;
+        }
+        tones.push_back(tone);
+    }
+    Some(tones)
+}
+
+/// The PC speaker, driven by channel 2 of the PIT
+///
+/// Writing pairs of a frequency in Hz and a duration in milliseconds, like `440,200 0,100 880,200`, queues
+/// tones, a frequency of 0 is a rest. The write returns once they are queued.
+pub struct BeepScheme;
+
+impl KScheme for BeepScheme {
+    fn scheme(&self) -> &str {
+        "beep"
+    }
+
+    fn open(&mut self, _url: &str, _flags: usize) -> Result<Box<Resource>> {
+        Ok(box BeepResource)
+    }
+}
+
+/// A handle to the PC speaker
+pub struct BeepResource;
+
+impl Resource for BeepResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box BeepResource)
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = b"beep:";
+
+        let mut i = 0;
+        while i < buf.len() && i < path.len() {
+            buf[i] = path[i];
+            i += 1;
+        }
+
+        Ok(i)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let string = try!(str::from_utf8(buf).map_err(|_| Error::new(EINVAL)));
+        let tones = try!(parse(string).ok_or(Error::new(EINVAL)));
+
+        // The timer interrupt takes tones off the queue, it is held off until all of them are queued
+        let _guard = InterruptGuard::new();
+        let queued = unsafe {
+            BEEP_QUEUE.as_ref().map_or(0, |tones| tones.len()) + BEEP_END.map_or(0, |_| 1)
+        };
+        if queued + tones.len() > MAX_TONES + 1 {
+            return Err(Error::new(ENOSPC));
+        }
+
+        for &tone in tones.iter() {
+            try!(unsafe { queue(tone) });
+        }
+
+        Ok(buf.len())
+    }
+}
@@ -0,0 +1,1026 @@
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+
+use arch::context::Context;
+use arch::timekeeping;
+
+use collections::string::{String, ToString};
+use collections::vec::Vec;
+use collections::vec_deque::VecDeque;
+
+use common::event::{AudioEvent, KeyEvent, AUDIO_JACK, AUDIO_REFILL, AUDIO_UNDERRUN, AUDIO_VOLUME, K_MUTE, K_VOLDOWN, K_VOLUP};
+use common::time::{self, Duration};
+use common::to_num::ToNum;
+
+use core::cell::UnsafeCell;
+use core::ops::DerefMut;
+use core::{cmp, i16, str};
+
+use env::log::InterruptGuard;
+
+use fs::{Check, KScheme, Resource, VecResource};
+
+use system::error::{Error, Result, EAGAIN, EBUSY, EINVAL, ENOENT};
+use system::syscall::{MODE_FILE, O_NONBLOCK};
+
+use syscall::execute::read_file;
+
+use super::{wav, AudioDevice, Converter, Format, FRAME_SIZE};
+
+/// Every audio scheme created, the index of a device is its place in the list
+pub static mut AUDIO_DEVICES: Option<Vec<*mut AudioScheme>> = None;
+
+/// The device that plays streams opened without an index
+pub static mut AUDIO_DEFAULT: usize = 0;
+
+/// Whether the volume and mute keys change the volume of the default device instead of going to the focus
+static mut MEDIA_KEYS: bool = true;
+/// Percent the volume keys change the volume by
+static mut MEDIA_KEY_STEP: usize = 5;
+
+/// The format the device is opened with while streams are mixed into it
+const MIX_FORMAT: Format = Format {
+    rate: 48000,
+    channels: 2,
+    bits: 16,
+};
+
+// A write is heard after what its stream buffered and what the mixer queued on the device, so the latency
+// is at most STREAM_FRAMES plus MIX_AHEAD, about 255 ms at 48 kHz, and less when streams are kept short.
+
+/// Frames a stream buffers at the rate of the device, about 170 ms at 48 kHz, writes wait once it is full
+const STREAM_FRAMES: usize = 8192;
+/// Bytes the mixer keeps queued on the device, about 85 ms at 48 kHz
+const MIX_AHEAD: usize = 16384;
+/// A stream that buffered less than this many frames gets an `AUDIO_REFILL` event
+const REFILL_FRAMES: usize = STREAM_FRAMES / 4;
+/// Milliseconds between the mixer topping up the device, well inside what it keeps queued
+const MIX_INTERVAL: u64 = 10;
+/// Streams open on a device at once
+const MAX_STREAMS: usize = 16;
+
+/// The longest waiting for a stream to be played takes
+const DRAIN_TIMEOUT: i64 = 2;
+
+/// Milliseconds of the tone the playback check mixes in, longer than it waits so the stream never runs dry
+const CHECK_TONE: usize = 300;
+/// Milliseconds the playback check waits for the device position to pass the tone
+const CHECK_TIMEOUT: i32 = 200;
+
+/// The upper bounds in milliseconds of the buckets refill latencies are counted in, the last bucket has the rest
+const REFILL_BUCKETS: [i64; 5] = [5, 10, 20, 50, 100];
+
+fn devices() -> &'static [*mut AudioScheme] {
+    match unsafe { AUDIO_DEVICES.as_ref() } {
+        Some(devices) => devices,
+        None => &[],
+    }
+}
+
+/// Tell userspace that headphones were plugged into or pulled out of `device`
+pub fn jack_changed(device: &AudioDevice, plugged: bool) {
+    let address = device as *const AudioDevice as *const u8;
+    for &audio in devices().iter() {
+        let audio = unsafe { & *audio };
+        if &*audio.device as *const AudioDevice as *const u8 == address {
+            audio.event(AUDIO_JACK, plugged as i64);
+        }
+    }
+}
+
+/// Change the volume of the default device if `key_event` is a press of a volume or mute key
+///
+/// Returns whether the event was taken, releases of those keys included, so keyboard drivers can pass it
+/// on otherwise.
+pub fn media_key(key_event: &KeyEvent) -> bool {
+    if ! unsafe { MEDIA_KEYS } || (key_event.scancode != K_VOLUP && key_event.scancode != K_VOLDOWN && key_event.scancode != K_MUTE) {
+        return false;
+    }
+    let audio = match devices().get(unsafe { AUDIO_DEFAULT }) {
+        Some(&audio) => unsafe { &mut *audio },
+        None => return false,
+    };
+
+    if key_event.pressed {
+        // The device keeps its volume while muted, so unmuting goes back to it
+        let (volume, mute) = audio.device.volume();
+        let step = unsafe { MEDIA_KEY_STEP };
+        let (volume, mute) = match key_event.scancode {
+            K_VOLUP => (cmp::min(volume + step, 100), false),
+            K_VOLDOWN => (volume.saturating_sub(step), false),
+            _ => (volume, ! mute),
+        };
+        if audio.device.set_volume(volume, mute).is_ok() {
+            audio.event(AUDIO_VOLUME, if mute { -1 } else { volume as i64 });
+        }
+    }
+
+    true
+}
+
+/// Counters of a stream, cleared by writing `reset` to `audio:/N/stats`
+///
+/// Frames are counted at the rate of the device.
+#[derive(Copy, Clone, Default)]
+struct StreamStats {
+    /// Frames the client wrote
+    written: u64,
+    /// Frames the mixer took from the stream
+    mixed: u64,
+    /// Frames the device played, going by its position
+    played: u64,
+    /// Times the stream buffer was empty when the mixer needed more, the client was late
+    client_underruns: usize,
+    /// Times the device ran dry while the stream had enough buffered, the mixer was late
+    mixer_underruns: usize,
+    /// How long the client took to write after a refill event, counted in the `REFILL_BUCKETS`
+    refill: [usize; 6],
+}
+
+/// A playback stream, buffered until the mixer adds it to what the device plays
+struct MixStream {
+    id: usize,
+    /// From the format the stream was opened with to 16 bit stereo at the rate of the device
+    converter: Converter,
+    data: VecDeque<(i16, i16)>,
+    /// Percent of full volume the stream is mixed at
+    volume: usize,
+    stats: StreamStats,
+    /// Where the device is once it played each mix of the stream, and the frames that mix had of it
+    marks: VecDeque<(u64, u64)>,
+    /// When the last refill event was sent, if the client did not write since
+    refill_sent: Option<Duration>,
+    /// The last mix came up short, so an underrun is only counted once until it catches up
+    starved: bool,
+    /// The stream buffered more than `REFILL_FRAMES` since the last refill event
+    refill: bool,
+    /// Every resource of the stream was closed, it is removed once what it buffered was mixed
+    closed: bool,
+}
+
+/// The audio scheme, shared by all sound card drivers
+///
+/// Every device registers one, and whichever the environment finds first opens for all of them, so
+/// `audio:/N/...` reaches device N and `audio:/...` the default device.
+pub struct AudioScheme {
+    pub device: Box<AudioDevice>,
+    /// The place of the device in `AUDIO_DEVICES`
+    index: usize,
+    /// The streams, added to by writers and removed from by the mixer with interrupts disabled
+    streams: Vec<Arc<UnsafeCell<MixStream>>>,
+    /// The device is open and the mixing thread runs
+    mixing: bool,
+    /// A mix is being done, by the mixing thread or a writer topping up the device
+    in_mix: bool,
+    /// The rate the device was opened at
+    rate: u32,
+    next_id: usize,
+    /// Frames written to the device since it was created, the position mixes are marked with
+    mixed: u64,
+    /// The underruns of the device as of the last mix
+    device_underruns: usize,
+    /// Times the device ran dry while a stream had enough buffered
+    mixer_underruns: usize,
+    /// Times the device ran dry because every stream was empty
+    client_underruns: usize,
+}
+
+impl AudioScheme {
+    pub fn new(device: Box<AudioDevice>) -> Box<Self> {
+        let mut ret = box AudioScheme {
+            device: device,
+            index: 0,
+            streams: Vec::new(),
+            mixing: false,
+            in_mix: false,
+            rate: MIX_FORMAT.rate,
+            next_id: 0,
+            mixed: 0,
+            device_underruns: 0,
+            mixer_underruns: 0,
+            client_underruns: 0,
+        };
+
+        // Schemes are never dropped, so the pointer stays valid
+        unsafe {
+            if AUDIO_DEVICES.is_none() {
+                AUDIO_DEVICES = Some(Vec::new());
+            }
+            ret.index = AUDIO_DEVICES.as_ref().unwrap().len();
+            AUDIO_DEVICES.as_mut().unwrap().push(ret.deref_mut());
+        }
+
+        ret
+    }
+
+    /// Add a stream of `format`, opening the device and starting the mixer for the first one
+    fn add(&mut self, format: Format) -> Result<Arc<UnsafeCell<MixStream>>> {
+        if self.streams.len() >= MAX_STREAMS {
+            return Err(Error::new(EBUSY));
+        }
+
+        // The mixer ends once it finds no streams, so interrupts stay disabled from seeing that it runs until
+        // the stream is added. Otherwise the device is opened and the mixer started for the stream.
+        let mut guard = InterruptGuard::new();
+        let start = ! self.mixing;
+        if start {
+            self.mixing = true;
+            drop(guard);
+            self.rate = match self.device.open(&MIX_FORMAT) {
+                Ok(rate) => rate,
+                Err(err) => {
+                    self.mixing = false;
+                    return Err(err);
+                }
+            };
+            guard = InterruptGuard::new();
+        }
+
+        let stream = Arc::new(UnsafeCell::new(MixStream {
+            id: self.next_id,
+            converter: Converter::new(format, Format {
+                rate: self.rate,
+                channels: 2,
+                bits: 16,
+            }),
+            data: VecDeque::new(),
+            volume: 100,
+            stats: StreamStats::default(),
+            marks: VecDeque::new(),
+            refill_sent: None,
+            starved: false,
+            refill: false,
+            closed: false,
+        }));
+        self.next_id += 1;
+        self.streams.push(stream.clone());
+        drop(guard);
+
+        if start {
+            let audio = self as *mut AudioScheme;
+            Context::spawn("kaudio_mix".into(), box move || {
+                while unsafe { (*audio).mix() } {
+                    timekeeping::sleep(MIX_INTERVAL, "AudioScheme mix");
+                }
+            });
+        }
+
+        Ok(stream)
+    }
+
+    /// The streams as of now, to look at while writers add to the list and the mixer removes from it
+    fn snapshot(&self) -> Vec<Arc<UnsafeCell<MixStream>>> {
+        let _guard = InterruptGuard::new();
+        self.streams.clone()
+    }
+
+    /// Sum what the streams buffered into what the device has queued, up to `MIX_AHEAD`
+    ///
+    /// A stream that has too little adds silence for the rest, the others go on. Streams that run low get
+    /// an event to refill them. Returns false once the last stream is gone and the device played what it
+    /// had, after stopping the device.
+    ///
+    /// Only one mix is done at a time, one asked for while another is being done returns at once.
+    fn mix(&mut self) -> bool {
+        {
+            let _guard = InterruptGuard::new();
+            if self.in_mix {
+                return true;
+            }
+            self.in_mix = true;
+        }
+        let mixing = self.mix_streams();
+        self.in_mix = false;
+        mixing
+    }
+
+    fn mix_streams(&mut self) -> bool {
+        let streams = {
+            let _guard = InterruptGuard::new();
+            self.streams.retain(|stream| {
+                let stream = unsafe { & *stream.get() };
+                ! (stream.closed && stream.data.is_empty())
+            });
+            self.streams.clone()
+        };
+
+        let queued = self.device.queued();
+        let position = self.mixed.saturating_sub((queued / FRAME_SIZE) as u64);
+        for stream in streams.iter() {
+            let stream = unsafe { &mut *stream.get() };
+            let _guard = InterruptGuard::new();
+            while stream.marks.front().map_or(false, |&(end, _)| end <= position) {
+                if let Some((_, count)) = stream.marks.pop_front() {
+                    stream.stats.played += count;
+                }
+            }
+        }
+
+        // The device ran dry since the last mix, if a stream had enough then the mixer came too late
+        let underruns = self.device.underruns();
+        if underruns != self.device_underruns {
+            self.device_underruns = underruns;
+            let mut late = false;
+            for stream in streams.iter() {
+                let stream = unsafe { &mut *stream.get() };
+                if ! stream.starved && ! stream.data.is_empty() {
+                    stream.stats.mixer_underruns += 1;
+                    late = true;
+                }
+            }
+            if late {
+                self.mixer_underruns += 1;
+            } else {
+                self.client_underruns += 1;
+            }
+        }
+
+        if streams.is_empty() {
+            if queued > 0 {
+                return true;
+            }
+            // A stream added since the list was taken keeps the device going
+            let _guard = InterruptGuard::new();
+            if ! self.streams.is_empty() {
+                return true;
+            }
+            self.device.stop();
+            self.mixing = false;
+            return false;
+        }
+
+        let frames = cmp::min(MIX_AHEAD.saturating_sub(queued), self.device.space()) / FRAME_SIZE;
+        if frames == 0 {
+            return true;
+        }
+
+        let mut mix = vec![(0i32, 0i32); frames];
+        for stream in streams.iter() {
+            let stream = unsafe { &mut *stream.get() };
+            let count = cmp::min(frames, stream.data.len());
+            if count < frames && ! stream.closed {
+                if ! stream.starved {
+                    stream.stats.client_underruns += 1;
+                    stream.starved = true;
+                    self.event(AUDIO_UNDERRUN, stream.id as i64);
+                }
+            } else {
+                stream.starved = false;
+            }
+
+            let volume = stream.volume as i32;
+            {
+                let _guard = InterruptGuard::new();
+                for frame in mix.iter_mut().take(count) {
+                    if let Some((left, right)) = stream.data.pop_front() {
+                        frame.0 += left as i32 * volume / 100;
+                        frame.1 += right as i32 * volume / 100;
+                    }
+                }
+
+                if count > 0 {
+                    stream.stats.mixed += count as u64;
+                    stream.marks.push_back((self.mixed + count as u64, count as u64));
+                }
+            }
+
+            if stream.refill && stream.data.len() < REFILL_FRAMES && ! stream.closed {
+                stream.refill = false;
+                stream.refill_sent = Some(Duration::monotonic());
+                self.event(AUDIO_REFILL, stream.id as i64);
+            }
+        }
+
+        let clamp = |sample: i32| cmp::max(cmp::min(sample, i16::MAX as i32), i16::MIN as i32) as i16;
+        let mut data = Vec::with_capacity(frames * FRAME_SIZE);
+        for &(left, right) in mix.iter() {
+            let (left, right) = (clamp(left), clamp(right));
+            data.push(left as u8);
+            data.push((left >> 8) as u8);
+            data.push(right as u8);
+            data.push((right >> 8) as u8);
+        }
+        self.mixed += (self.device.write(&data) / FRAME_SIZE) as u64;
+
+        true
+    }
+
+    fn event(&self, kind: i64, value: i64) {
+        let event = AudioEvent {
+            kind: kind,
+            device: self.index as i64,
+            value: value,
+        };
+        ::env().events.send(event.to_event(), "AudioScheme::event");
+    }
+
+    fn status(&mut self, index: usize) -> String {
+        let (volume, mute) = self.device.volume();
+        let queued = self.device.queued();
+        let jack = match self.device.jack() {
+            Some(true) => "headphones",
+            Some(false) => "speakers",
+            None => "unknown",
+        };
+        format!("device: {}\nname: {}\ndefault: {}\nstreams: {}\nqueued: {}\nspace: {}\nunderruns: {}\nvolume: {}\nmute: {}\noutput: {}\n",
+                index,
+                self.device.name(),
+                if index == unsafe { AUDIO_DEFAULT } { "yes" } else { "no" },
+                self.streams.len(),
+                queued,
+                self.device.space(),
+                self.device.underruns(),
+                volume,
+                if mute { "on" } else { "off" },
+                jack) + &self.device.status()
+    }
+
+    /// A line for each stream, with its id, format and conversion, what it buffered and its volume
+    fn streams(&self) -> String {
+        let mut list = String::new();
+        for stream in self.snapshot().iter() {
+            let stream = unsafe { & *stream.get() };
+            let format = stream.converter.from;
+            list.push_str(&format!("{}: {}/{}/{} conversion={} buffered={} volume={} underruns={}{}\n",
+                                   stream.id,
+                                   format.rate,
+                                   format.channels,
+                                   format.bits,
+                                   stream.converter.steps().replace(' ', ","),
+                                   stream.data.len(),
+                                   stream.volume,
+                                   stream.stats.client_underruns,
+                                   if stream.closed { " closed" } else { "" }));
+        }
+        list
+    }
+
+    /// The counters of the device and a line for each stream, with the latency it has now
+    fn stats(&mut self) -> String {
+        let queued = self.device.queued() / FRAME_SIZE;
+        let mut list = format!("mixed: {}\nplayed: {}\ndevice_underruns: {}\nmixer_underruns: {}\nclient_underruns: {}\n",
+                               self.mixed,
+                               self.mixed.saturating_sub(queued as u64),
+                               self.device.underruns(),
+                               self.mixer_underruns,
+                               self.client_underruns);
+        for stream in self.snapshot().iter() {
+            let stream = unsafe { & *stream.get() };
+            let mut refill = String::new();
+            for (i, count) in stream.stats.refill.iter().enumerate() {
+                match REFILL_BUCKETS.get(i) {
+                    Some(bound) => refill.push_str(&format!("<{}ms:{},", bound, count)),
+                    None => refill.push_str(&format!(">={}ms:{}", REFILL_BUCKETS[REFILL_BUCKETS.len() - 1], count)),
+                }
+            }
+            list.push_str(&format!("{}: written={} mixed={} played={} client_underruns={} mixer_underruns={} latency_ms={} refill={}\n",
+                                   stream.id,
+                                   stream.stats.written,
+                                   stream.stats.mixed,
+                                   stream.stats.played,
+                                   stream.stats.client_underruns,
+                                   stream.stats.mixer_underruns,
+                                   (stream.data.len() + queued) as u64 * 1000 / stream.converter.to.rate as u64,
+                                   refill));
+        }
+        list
+    }
+
+    /// Play the WAV file at `path` through the mixer, returning once it was read
+    fn play(&mut self, path: &str) -> Result<()> {
+        let path = {
+            let contexts = unsafe { & *::env().contexts.get() };
+            try!(contexts.current()).canonicalize(path)
+        };
+        let file = try!(read_file(&path));
+        let (format, data) = {
+            let (format, data) = try!(wav::parse(&file));
+            (format, data.to_vec())
+        };
+
+        let stream = try!(self.add(format));
+        let handle = StreamHandle {
+            audio: self,
+            index: self.index,
+            stream: stream,
+        };
+        Context::spawn("kaudio_play".into(), box move || {
+            let chunk = STREAM_FRAMES / 4 * format.frame_size();
+            for data in data.chunks(chunk) {
+                let _ = handle.write(data, false);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Clear the counters of the device and its streams
+    fn reset_stats(&mut self) {
+        self.mixer_underruns = 0;
+        self.client_underruns = 0;
+        for stream in self.snapshot().iter() {
+            let stream = unsafe { &mut *stream.get() };
+            let _guard = InterruptGuard::new();
+            stream.stats = StreamStats::default();
+            // What is still queued on the device was mixed before the reset
+            stream.marks.clear();
+        }
+    }
+}
+
+impl KScheme for AudioScheme {
+    fn scheme(&self) -> &str {
+        "audio"
+    }
+
+    /// Mix a tone at no volume into what the device plays, and check that its position moves past it
+    ///
+    /// The tone is a stream of its own, so streams already playing are not disturbed.
+    fn diagnose(&mut self) -> Vec<Check> {
+        let stream = match self.add(MIX_FORMAT) {
+            Ok(stream) => stream,
+            Err(err) => return vec![Check::new("playback", false, format!("opening the device failed: {}", err))],
+        };
+
+        {
+            let stream = unsafe { &mut *stream.get() };
+            stream.volume = 0;
+            // A square wave at 1 kHz
+            let rate = self.rate as usize;
+            let _guard = InterruptGuard::new();
+            for i in 0..rate * CHECK_TONE / 1000 {
+                let sample = if i * 2000 / rate % 2 == 0 { 8192 } else { -8192 };
+                stream.data.push_back((sample, sample));
+            }
+        }
+
+        let end = Duration::monotonic() + Duration::new(0, CHECK_TIMEOUT * time::NANOS_PER_MILLI);
+        while unsafe { & *stream.get() }.stats.played == 0 && Duration::monotonic() < end {
+            timekeeping::sleep(MIX_INTERVAL, "AudioScheme::diagnose");
+        }
+
+        let stream = unsafe { &mut *stream.get() };
+        let played = stream.stats.played;
+        {
+            let _guard = InterruptGuard::new();
+            stream.data.clear();
+            stream.closed = true;
+        }
+
+        vec![if played > 0 {
+            Check::new("playback", true, format!("{}: the device played {} frames of a muted tone", self.device.name(), played))
+        } else {
+            Check::new("playback", false, format!("{}: the device position did not move within {} ms", self.device.name(), CHECK_TIMEOUT))
+        }]
+    }
+
+    /// Drop what the streams have left and stop the device, the mixer ends on its next pass
+    fn power_off(&mut self) {
+        for stream in self.snapshot().iter() {
+            let stream = unsafe { &mut *stream.get() };
+            let _guard = InterruptGuard::new();
+            stream.data.clear();
+            stream.closed = true;
+        }
+        self.device.flush();
+        self.device.stop();
+    }
+
+    /// - `audio:/status` lists the devices
+    /// - `audio:/default` has the index of the default device, writing an index changes it
+    /// - `audio:/keys` has the media key settings, written as settings like `intercept=on step=5`
+    /// - `audio:/N` plays 48 kHz 16 bit stereo on device N, `audio:/N/rate/channels/bits` other formats
+    /// - `audio:/N/status` has the state of the device
+    /// - `audio:/N/volume` has the playback volume, written as settings like `volume=80 mute=off`
+    /// - `audio:/N/streams` lists the open streams, writing `id volume=50` changes the volume of one
+    /// - `audio:/N/play` plays the WAV file whose path is written to it
+    /// - `audio:/N/stats` has playback counters of the device and its streams, writing `reset` clears them
+    ///
+    /// The index may be left out for the default device, anything else opens a control file of its driver.
+    fn open(&mut self, url: &str, flags: usize) -> Result<Box<Resource>> {
+        let reference = url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/');
+        match reference {
+            "status" => {
+                let mut list = String::new();
+                for (i, &audio) in devices().iter().enumerate() {
+                    list.push_str(&format!("{}: {}{}\n",
+                                           i,
+                                           unsafe { (*audio).device.name() },
+                                           if i == unsafe { AUDIO_DEFAULT } { " default" } else { "" }));
+                }
+                return Ok(box VecResource::new("audio:/status".to_string(), list.into_bytes(), MODE_FILE));
+            },
+            _ => if let Some(file) = AudioFile::from_name(reference) {
+                if file.global() {
+                    return Ok(box AudioFileResource {
+                        audio: 0 as *mut AudioScheme,
+                        index: 0,
+                        file: file,
+                        seek: 0,
+                    });
+                }
+            },
+        }
+
+        // A rate is never below the number of devices, so a small first part is an index
+        let mut parts = reference.splitn(2, '/');
+        let first = parts.next().unwrap_or("");
+        let (index, rest) = if ! first.is_empty() && first.chars().all(|c| c.is_digit(10)) && first.to_num() < devices().len() {
+            (first.to_num(), parts.next().unwrap_or(""))
+        } else {
+            (unsafe { AUDIO_DEFAULT }, reference)
+        };
+        let audio = match devices().get(index) {
+            Some(&audio) => audio,
+            None => return Err(Error::new(ENOENT)),
+        };
+
+        match rest {
+            "status" => {
+                let status = unsafe { (*audio).status(index) };
+                Ok(box VecResource::new(format!("audio:/{}/status", index), status.into_bytes(), MODE_FILE))
+            },
+            _ => match AudioFile::from_name(rest) {
+                Some(file) if ! file.global() => Ok(box AudioFileResource {
+                    audio: audio,
+                    index: index,
+                    file: file,
+                    seek: 0,
+                }),
+                _ => match Format::parse(if rest.is_empty() { "48000" } else { rest }) {
+                    Some(format) => {
+                        let stream = try!(unsafe { (*audio).add(format) });
+                        Ok(box AudioResource {
+                            handle: Arc::new(StreamHandle {
+                                audio: audio,
+                                index: index,
+                                stream: stream,
+                            }),
+                            nonblock: flags & O_NONBLOCK == O_NONBLOCK,
+                        })
+                    },
+                    None => unsafe { (*audio).device.control(rest) },
+                },
+            },
+        }
+    }
+
+    fn on_irq(&mut self, irq: u8) -> bool {
+        self.device.on_irq(irq)
+    }
+}
+
+/// A stream, shared by a resource and its duplicates and closed when the last of them is
+struct StreamHandle {
+    audio: *mut AudioScheme,
+    index: usize,
+    stream: Arc<UnsafeCell<MixStream>>,
+}
+
+impl StreamHandle {
+    /// Buffer `buf`, converted to stereo at the rate of the device
+    ///
+    /// Unless `nonblock` is set this waits until all of it fits, otherwise it takes the whole frames that
+    /// fit and fails with EAGAIN if none do. Only whole frames are taken and counted, a trailing partial
+    /// frame is left to the next write.
+    fn write(&self, buf: &[u8], nonblock: bool) -> Result<usize> {
+        let stream = unsafe { &mut *self.stream.get() };
+        let frame_size = stream.converter.from.frame_size();
+        let mut count = buf.len() / frame_size;
+        if nonblock {
+            // Top up the device first, so the space is as of where it is now
+            unsafe { (*self.audio).mix() };
+            count = cmp::min(count, self.space());
+            if count == 0 && buf.len() >= frame_size {
+                return Err(Error::new(EAGAIN));
+            }
+        }
+        let taken = count * frame_size;
+        let frames = stream.converter.frames(&buf[.. taken]);
+
+        // The mixer takes from the stream and updates its counters while this adds to them
+        let mut guard = InterruptGuard::new();
+        if let Some(sent) = stream.refill_sent.take() {
+            let elapsed = Duration::monotonic() - sent;
+            let ms = elapsed.secs * 1000 + (elapsed.nanos / time::NANOS_PER_MILLI) as i64;
+            let bucket = REFILL_BUCKETS.iter().position(|&bound| ms < bound).unwrap_or(REFILL_BUCKETS.len());
+            stream.stats.refill[bucket] += 1;
+        }
+        stream.stats.written += frames.len() as u64;
+
+        let mut i = 0;
+        while i < frames.len() {
+            let count = cmp::min(STREAM_FRAMES.saturating_sub(stream.data.len()), frames.len() - i);
+            if count == 0 {
+                drop(guard);
+                timekeeping::sleep(MIX_INTERVAL, "StreamHandle::write");
+                guard = InterruptGuard::new();
+                continue;
+            }
+            stream.data.extend(frames[i .. i + count].iter().cloned());
+            i += count;
+        }
+        if stream.data.len() >= REFILL_FRAMES {
+            stream.refill = true;
+        }
+        drop(guard);
+
+        Ok(taken)
+    }
+
+    /// The frames of the stream format that fit without waiting
+    ///
+    /// One is kept back, since resampling may make one more frame than the rates give.
+    fn space(&self) -> usize {
+        let stream = unsafe { & *self.stream.get() };
+        let free = STREAM_FRAMES.saturating_sub(stream.data.len()).saturating_sub(1);
+        (free as u64 * stream.converter.from.rate as u64 / stream.converter.to.rate as u64) as usize
+    }
+
+    /// The state of the stream, which the device buffer position is part of
+    fn status(&self) -> String {
+        unsafe { (*self.audio).mix() };
+        let stream = unsafe { & *self.stream.get() };
+        let device = unsafe { &mut (*self.audio).device };
+        let space = self.space();
+        let queued = device.queued() / FRAME_SIZE;
+        let latency = (stream.data.len() + queued) as u64 * 1000 / stream.converter.to.rate as u64;
+        let format = stream.converter.from;
+        format!("stream: {}\nformat: {}/{}/{}\nconversion: {}\nspace: {}\nframes: {}\nbuffered: {}\nlatency_ms: {}\nunderruns: {}\nmixer_underruns: {}\n",
+                stream.id,
+                format.rate,
+                format.channels,
+                format.bits,
+                stream.converter.steps(),
+                space * format.frame_size(),
+                space,
+                stream.data.len(),
+                latency,
+                stream.stats.client_underruns,
+                stream.stats.mixer_underruns)
+    }
+
+    /// Wait until what was buffered was mixed and the device played it
+    fn drain(&self) {
+        let stream = unsafe { & *self.stream.get() };
+        let device = unsafe { &mut (*self.audio).device };
+        let end = Duration::monotonic() + Duration::new(DRAIN_TIMEOUT, 0);
+        while (! stream.data.is_empty() || device.queued() > 0) && Duration::monotonic() < end {
+            timekeeping::sleep(MIX_INTERVAL, "StreamHandle::drain");
+        }
+    }
+}
+
+impl Drop for StreamHandle {
+    /// What the stream buffered is still played, so closing does not cut it off
+    fn drop(&mut self) {
+        unsafe { (*self.stream.get()).closed = true };
+    }
+}
+
+/// A playback stream
+///
+/// Reading it gives its status, with the bytes and frames that can be written without waiting as `space`
+/// and `frames`.
+pub struct AudioResource {
+    handle: Arc<StreamHandle>,
+    /// Writes take what fits instead of waiting for the rest
+    nonblock: bool,
+}
+
+impl Resource for AudioResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box AudioResource {
+            handle: self.handle.clone(),
+            nonblock: self.nonblock,
+        })
+    }
+
+    /// The path gives the device and format, like `audio:/0/48000/2/16`
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let format = unsafe { & *self.handle.stream.get() }.converter.from;
+        let path_string = format!("audio:/{}/{}/{}/{}", self.handle.index, format.rate, format.channels, format.bits);
+        let path = path_string.as_bytes();
+
+        let mut i = 0;
+        while i < buf.len() && i < path.len() {
+            buf[i] = path[i];
+            i += 1;
+        }
+
+        Ok(i)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let string = self.handle.status();
+        let data = string.as_bytes();
+
+        let mut i = 0;
+        while i < buf.len() && i < data.len() {
+            buf[i] = data[i];
+            i += 1;
+        }
+
+        Ok(i)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.handle.write(buf, self.nonblock)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.handle.drain();
+        Ok(())
+    }
+}
+
+/// An audio control file
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AudioFile {
+    /// The index of the device streams without an index play on, writing an index changes it
+    Default,
+    /// Whether the media keys change the volume of the default device and by how much
+    Keys,
+    /// The playback volume of a device, changed by settings like `volume=80 mute=off`
+    Volume,
+    /// The streams open on a device, changed by an id followed by settings like `3 volume=50`
+    Streams,
+    /// Playback counters of a device and its streams, cleared by `reset`
+    Stats,
+    /// Plays the WAV file at the path written to it
+    Play,
+}
+
+impl AudioFile {
+    /// The control file called `name`
+    pub fn from_name(name: &str) -> Option<AudioFile> {
+        match name {
+            "default" => Some(AudioFile::Default),
+            "keys" => Some(AudioFile::Keys),
+            "volume" => Some(AudioFile::Volume),
+            "streams" => Some(AudioFile::Streams),
+            "stats" => Some(AudioFile::Stats),
+            "play" => Some(AudioFile::Play),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            AudioFile::Default => "default",
+            AudioFile::Keys => "keys",
+            AudioFile::Volume => "volume",
+            AudioFile::Streams => "streams",
+            AudioFile::Stats => "stats",
+            AudioFile::Play => "play",
+        }
+    }
+
+    /// Whether the file is at the root of audio: instead of under each device
+    pub fn global(&self) -> bool {
+        *self == AudioFile::Default || *self == AudioFile::Keys
+    }
+}
+
+/// A resource for one of the audio control files
+pub struct AudioFileResource {
+    audio: *mut AudioScheme,
+    index: usize,
+    file: AudioFile,
+    seek: usize,
+}
+
+impl AudioFileResource {
+    fn contents(&self) -> String {
+        match self.file {
+            AudioFile::Default => format!("{}\n", unsafe { AUDIO_DEFAULT }),
+            AudioFile::Keys => format!("intercept: {}\nstep: {}\n", if unsafe { MEDIA_KEYS } { "on" } else { "off" }, unsafe { MEDIA_KEY_STEP }),
+            AudioFile::Volume => {
+                let (volume, mute) = unsafe { (*self.audio).device.volume() };
+                format!("volume: {}\nmute: {}\n", volume, if mute { "on" } else { "off" })
+            },
+            AudioFile::Streams => unsafe { (*self.audio).streams() },
+            AudioFile::Stats => unsafe { (*self.audio).stats() },
+            AudioFile::Play => String::new(),
+        }
+    }
+}
+
+/// A percentage given as a setting value
+fn percent(value: &str) -> Option<usize> {
+    if ! value.is_empty() && value.chars().all(|c| c.is_digit(10)) {
+        Some(cmp::min(value.to_num(), 100))
+    } else {
+        None
+    }
+}
+
+impl Resource for AudioFileResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box AudioFileResource {
+            audio: self.audio,
+            index: self.index,
+            file: self.file,
+            seek: self.seek,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path_string = if self.file.global() {
+            format!("audio:/{}", self.file.name())
+        } else {
+            format!("audio:/{}/{}", self.index, self.file.name())
+        };
+        let path = path_string.as_bytes();
+
+        let mut i = 0;
+        while i < buf.len() && i < path.len() {
+            buf[i] = path[i];
+            i += 1;
+        }
+
+        Ok(i)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let string = self.contents();
+        let data = string.as_bytes();
+
+        let mut i = 0;
+        while i < buf.len() && self.seek < data.len() {
+            buf[i] = data[self.seek];
+            i += 1;
+            self.seek += 1;
+        }
+
+        Ok(i)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let string = str::from_utf8(buf).unwrap_or("").trim();
+
+        match self.file {
+            AudioFile::Default => {
+                if string.is_empty() || ! string.chars().all(|c| c.is_digit(10)) || string.to_num() >= devices().len() {
+                    return Err(Error::new(EINVAL));
+                }
+                unsafe { AUDIO_DEFAULT = string.to_num() };
+            },
+            AudioFile::Keys => {
+                let (mut intercept, mut step) = unsafe { (MEDIA_KEYS, MEDIA_KEY_STEP) };
+                for setting in string.split_whitespace() {
+                    let mut parts = setting.splitn(2, '=');
+                    match (parts.next().unwrap_or(""), parts.next().unwrap_or("")) {
+                        ("intercept", "on") => intercept = true,
+                        ("intercept", "off") => intercept = false,
+                        ("step", value) if percent(value).map_or(false, |step| step > 0) => step = percent(value).unwrap(),
+                        _ => return Err(Error::new(EINVAL)),
+                    }
+                }
+                unsafe {
+                    MEDIA_KEYS = intercept;
+                    MEDIA_KEY_STEP = step;
+                }
+            },
+            AudioFile::Volume => {
+                let device = unsafe { &mut (*self.audio).device };
+                let (mut volume, mut mute) = device.volume();
+                for setting in string.split_whitespace() {
+                    let mut parts = setting.splitn(2, '=');
+                    match (parts.next().unwrap_or(""), parts.next().unwrap_or("")) {
+                        ("volume", value) if percent(value).is_some() => volume = percent(value).unwrap(),
+                        ("mute", "on") => mute = true,
+                        ("mute", "off") => mute = false,
+                        _ => return Err(Error::new(EINVAL)),
+                    }
+                }
+                try!(device.set_volume(volume, mute));
+            },
+            AudioFile::Streams => {
+                let mut settings = string.split_whitespace();
+                let id = settings.next().unwrap_or("");
+                let streams = unsafe { (*self.audio).snapshot() };
+                let stream = streams.iter().find(|stream| {
+                    ! id.is_empty() && id.chars().all(|c| c.is_digit(10)) && unsafe { & *stream.get() }.id == id.to_num()
+                });
+                let stream = match stream {
+                    Some(stream) => unsafe { &mut *stream.get() },
+                    None => return Err(Error::new(ENOENT)),
+                };
+                for setting in settings {
+                    let mut parts = setting.splitn(2, '=');
+                    match (parts.next().unwrap_or(""), parts.next().unwrap_or("")) {
+                        ("volume", value) if percent(value).is_some() => stream.volume = percent(value).unwrap(),
+                        _ => return Err(Error::new(EINVAL)),
+                    }
+                }
+            },
+            AudioFile::Stats if string == "reset" => unsafe { (*self.audio).reset_stats() },
+            AudioFile::Play if ! string.is_empty() => try!(unsafe { (*self.audio).play(string) }),
+            _ => return Err(Error::new(EINVAL)),
+        }
+        self.seek = 0;
+
+        Ok(buf.len())
+    }
+}
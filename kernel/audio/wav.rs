@@ -0,0 +1,91 @@
+use system::error::{Error, Result, EINVAL};
+
+use super::Format;
+
+/// The format tag of integer PCM samples
+const WAVE_FORMAT_PCM: u16 = 1;
+/// The format tag of a format chunk that gives the real tag in its extension
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+fn word(data: &[u8], i: usize) -> u16 {
+    data[i] as u16 | (data[i + 1] as u16) << 8
+}
+
+fn dword(data: &[u8], i: usize) -> u32 {
+    word(data, i) as u32 | (word(data, i + 2) as u32) << 16
+}
+
+/// The format of the fmt chunk `data`, which has to be 8 or 16 bit PCM
+fn fmt_chunk(data: &[u8]) -> Result<Format> {
+    if data.len() < 16 {
+        return Err(Error::new(EINVAL));
+    }
+
+    let mut tag = word(data, 0);
+    let channels = word(data, 2);
+    let rate = dword(data, 4);
+    let block_align = word(data, 12);
+    let bits = word(data, 14);
+
+    // The sub format starts with the format tag it stands for
+    if tag == WAVE_FORMAT_EXTENSIBLE {
+        if data.len() < 26 {
+            return Err(Error::new(EINVAL));
+        }
+        tag = word(data, 24);
+    }
+
+    if tag != WAVE_FORMAT_PCM || channels > 2 || bits > 16 {
+        return Err(Error::new(EINVAL));
+    }
+
+    let format = Format {
+        rate: rate,
+        channels: channels as u8,
+        bits: bits as u8,
+    };
+    if ! format.valid() || block_align as usize != format.frame_size() {
+        return Err(Error::new(EINVAL));
+    }
+
+    Ok(format)
+}
+
+/// The format and samples of the RIFF WAVE file `file`
+///
+/// Chunks other than fmt and data are skipped. A chunk that runs past the end of the file fails, so a
+/// truncated file or an absurd size is never read beyond the buffer.
+pub fn parse(file: &[u8]) -> Result<(Format, &[u8])> {
+    if file.len() < 12 || &file[0..4] != b"RIFF" || &file[8..12] != b"WAVE" {
+        return Err(Error::new(EINVAL));
+    }
+
+    let mut format = None;
+    let mut i = 12;
+    while file.len() - i >= 8 {
+        let id = &file[i .. i + 4];
+        let size = dword(file, i + 4) as usize;
+        let start = i + 8;
+        if size > file.len() - start {
+            return Err(Error::new(EINVAL));
+        }
+        let chunk = &file[start .. start + size];
+
+        if id == b"fmt " {
+            format = Some(try!(fmt_chunk(chunk)));
+        } else if id == b"data" {
+            return match format {
+                Some(format) => Ok((format, &chunk[.. size / format.frame_size() * format.frame_size()])),
+                None => Err(Error::new(EINVAL)),
+            };
+        }
+
+        // Chunks are padded to an even size, the padding may be missing after the last one
+        i = start + size + (size & 1);
+        if i > file.len() {
+            break;
+        }
+    }
+
+    Err(Error::new(EINVAL))
+}
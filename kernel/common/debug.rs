@@ -1,45 +1,26 @@
 use core::fmt;
-use drivers::io::{Io, Pio};
 
-pub struct SerialConsole {
-    status: Pio<u8>,
-    data: Pio<u8>
-}
-
-impl SerialConsole {
-    pub fn new() -> SerialConsole {
-        SerialConsole {
-            status: Pio::new(0x3F8 + 5),
-            data: Pio::new(0x3F8)
-        }
-    }
+use drivers::serial;
 
-    pub fn write(&mut self, bytes: &[u8]) {
-        for byte in bytes.iter() {
-            while !self.status.readf(0x20) {}
-            self.data.write(*byte);
+/// Kernel output, which goes to the console and to the serial ports that mirror debug output
+pub struct ConsoleWriter;
 
-            if *byte == 8 {
-                while !self.status.readf(0x20) {}
-                self.data.write(0x20);
+impl fmt::Write for ConsoleWriter {
+    fn write_str(&mut self, s: &str) -> Result<(), fmt::Error> {
+        unsafe { &mut *::env().console.get() }.write(s.as_bytes());
+        serial::debug_write(s.as_bytes());
 
-                while !self.status.readf(0x20) {}
-                self.data.write(8);
-            }
-        }
+        Ok(())
     }
 }
 
-impl fmt::Write for SerialConsole {
-    fn write_str(&mut self, s: &str) -> Result<(), fmt::Error> {
-        //self.write(s.as_bytes());
-
-        {
-            let console = unsafe { &mut *::env().console.get() };
-            console.write(s.as_bytes());
-        }
+/// Debug output, which goes to the console and the kernel log
+pub struct DebugWriter;
 
-        Ok(())
+impl fmt::Write for DebugWriter {
+    fn write_str(&mut self, s: &str) -> Result<(), fmt::Error> {
+        unsafe { &mut *::env().log.get() }.debug(s);
+        fmt::Write::write_str(&mut ConsoleWriter, s)
     }
 }
 
@@ -48,7 +29,7 @@ impl fmt::Write for SerialConsole {
 macro_rules! debug {
     ($($arg:tt)*) => ({
         use $crate::core::fmt::Write;
-        let _ = write!($crate::common::debug::SerialConsole::new(), $($arg)*);
+        let _ = write!($crate::common::debug::DebugWriter, $($arg)*);
     });
 }
 
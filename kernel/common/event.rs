@@ -5,6 +5,59 @@ pub const EVENT_NONE: i64 = 0;
 pub const EVENT_MOUSE: i64 = 1;
 pub const EVENT_KEY: i64 = 2;
 pub const EVENT_QUIT: i64 = 3;
+pub const EVENT_HOTPLUG: i64 = 4;
+pub const EVENT_LINK: i64 = 5;
+pub const EVENT_SCROLL: i64 = 6;
+pub const EVENT_AUDIO: i64 = 7;
+pub const EVENT_POWER: i64 = 8;
+pub const EVENT_IO: i64 = 9;
+pub const EVENT_RAISE: i64 = 10;
+pub const EVENT_OBSCURED: i64 = 11;
+pub const EVENT_RESIZE: i64 = 12;
+pub const EVENT_REDRAW: i64 = 13;
+pub const EVENT_FOCUS: i64 = 14;
+pub const EVENT_LAYOUT: i64 = 15;
+
+pub const HOTPLUG_DISK: i64 = 1;
+pub const HOTPLUG_USB: i64 = 2;
+/// A scheme was unregistered, the index is its id in the registry
+pub const HOTPLUG_SCHEME: i64 = 3;
+
+/// What a stream buffered fell below the refill threshold
+pub const AUDIO_REFILL: i64 = 1;
+/// A stream ran out of samples and silence was played for it
+pub const AUDIO_UNDERRUN: i64 = 2;
+/// Headphones were plugged in or pulled out
+pub const AUDIO_JACK: i64 = 3;
+/// The output volume was changed by a media key, the value is the new percent or -1 while muted
+pub const AUDIO_VOLUME: i64 = 4;
+
+/// The system is about to power off
+pub const POWER_OFF: i64 = 1;
+/// The system is about to restart
+pub const POWER_REBOOT: i64 = 2;
+
+/// Set in `c` of a key, mouse or scroll event that was injected through `input:` instead of coming from a device
+pub const EVENT_SYNTHETIC: i64 = 1 << 32;
+
+/// The first byte of each event schemes hand out, no raw event starts with it as no code is that large
+pub const EVENT_MAGIC: u8 = 0xEE;
+/// The version of the events written, readers skip the events of newer versions
+pub const EVENT_VERSION: u8 = 1;
+/// The magic byte, the version and the length of the event after it as a little endian `u16`
+pub const EVENT_HEADER_LEN: usize = 4;
+/// The bytes of an encoded event of this version, the header and the 32 of an `Event`
+///
+/// A read of `display:` or `event:` returns a multiple of it, as many events as fit and are queued, so
+/// a reader can take a buffer of several at a time and decode them without another read.
+pub const EVENT_FRAME_LEN: usize = EVENT_HEADER_LEN + 32;
+
+/// A read would not wait
+pub const IO_READ: i64 = 1;
+/// A write would not wait
+pub const IO_WRITE: i64 = 2;
+/// The file was closed, its registration is gone
+pub const IO_CLOSED: i64 = 4;
 
 /// An optional event
 #[derive(Copy, Clone, Debug)]
@@ -15,6 +68,30 @@ pub enum EventOption {
     Key(KeyEvent),
     /// A quit request event
     Quit(QuitEvent),
+    /// A device attach or detach event
+    Hotplug(HotplugEvent),
+    /// A network link change event
+    Link(LinkEvent),
+    /// A mouse wheel event
+    Scroll(ScrollEvent),
+    /// An audio stream needs more samples, headphones were plugged in or pulled out, or the volume changed
+    Audio(AudioEvent),
+    /// The system is about to power off or restart
+    Power(PowerEvent),
+    /// A file registered with an `event:` queue became readable or writable
+    Io(IoEvent),
+    /// A window asks to be raised above or lowered below the others
+    Raise(RaiseRequest),
+    /// The stacking of windows changed how much of a window is seen
+    Obscured(ObscuredEvent),
+    /// The display was switched to another mode
+    Resize(ResizeEvent),
+    /// What was drawn is gone and has to be drawn again
+    Redraw(RedrawEvent),
+    /// The display manager gained or lost the keyboard and mouse
+    Focus(FocusEvent),
+    /// A program asks for a keyboard layout while it has the focus
+    Layout(LayoutRequest),
     /// An unknown event
     Unknown(Event),
     /// No event
@@ -48,11 +125,166 @@ impl Event {
         match self.code {
             EVENT_NONE => EventOption::None,
             EVENT_MOUSE => EventOption::Mouse(MouseEvent::from_event(self)),
-            EVENT_KEY => EventOption::Key(KeyEvent::from_event(self)),
+            EVENT_KEY => match KeyEvent::from_event(self) {
+                Some(key_event) => EventOption::Key(key_event),
+                None => EventOption::Unknown(self),
+            },
             EVENT_QUIT => EventOption::Quit(QuitEvent::from_event(self)),
+            EVENT_HOTPLUG => EventOption::Hotplug(HotplugEvent::from_event(self)),
+            EVENT_LINK => EventOption::Link(LinkEvent::from_event(self)),
+            EVENT_SCROLL => EventOption::Scroll(ScrollEvent::from_event(self)),
+            EVENT_AUDIO => EventOption::Audio(AudioEvent::from_event(self)),
+            EVENT_POWER => EventOption::Power(PowerEvent::from_event(self)),
+            EVENT_IO => EventOption::Io(IoEvent::from_event(self)),
+            EVENT_RAISE => EventOption::Raise(RaiseRequest::from_event(self)),
+            EVENT_OBSCURED => EventOption::Obscured(ObscuredEvent::from_event(self)),
+            EVENT_RESIZE => EventOption::Resize(ResizeEvent::from_event(self)),
+            EVENT_REDRAW => EventOption::Redraw(RedrawEvent::from_event(self)),
+            EVENT_FOCUS => EventOption::Focus(FocusEvent::from_event(self)),
+            EVENT_LAYOUT => EventOption::Layout(LayoutRequest::from_event(self)),
             _ => EventOption::Unknown(self),
         }
     }
+
+    /// Whether the event was injected through `input:`, only key, mouse and scroll events are
+    pub fn is_synthetic(&self) -> bool {
+        match self.code {
+            EVENT_KEY | EVENT_MOUSE | EVENT_SCROLL => self.c & EVENT_SYNTHETIC == EVENT_SYNTHETIC,
+            _ => false,
+        }
+    }
+
+    /// Mark the event as injected
+    pub fn synthetic(mut self) -> Event {
+        self.c |= EVENT_SYNTHETIC;
+        self
+    }
+
+    /// Encode with a header, as schemes hand events out
+    pub fn to_bytes(&self) -> [u8; EVENT_FRAME_LEN] {
+        let mut bytes = [0; EVENT_FRAME_LEN];
+        bytes[0] = EVENT_MAGIC;
+        bytes[1] = EVENT_VERSION;
+        bytes[2] = mem::size_of::<Event>() as u8;
+        bytes[3] = (mem::size_of::<Event>() >> 8) as u8;
+        bytes[EVENT_HEADER_LEN..].copy_from_slice(self);
+        bytes
+    }
+
+    /// Decode an event of this version encoded by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Option<Event> {
+        match EventDecoder::framed().decode(bytes) {
+            (Decoded::Event(event), _) => Some(event),
+            _ => None,
+        }
+    }
+}
+
+/// How a stream of events is encoded
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EventFormat {
+    /// Each event after a header, see `Event::to_bytes`
+    Framed,
+    /// Bare `Event` structs, as they were read from schemes before the header
+    Raw,
+}
+
+/// What was at the start of the bytes given to `EventDecoder::decode`
+#[derive(Copy, Clone, Debug)]
+pub enum Decoded {
+    Event(Event),
+    /// An event of a newer version, which can only be skipped
+    Skipped,
+    /// Fewer bytes than the event needs
+    Incomplete,
+    /// Not an event, so the stream is out of step and no event after it can be trusted
+    Corrupt,
+}
+
+/// Decodes a stream of events, framed or, while there are programs writing them, raw
+///
+/// The format is sniffed from the first event and held to after it, so a stream that loses its framing
+/// is found corrupt rather than read as raw events.
+pub struct EventDecoder {
+    format: Option<EventFormat>,
+}
+
+impl EventDecoder {
+    /// Sniff the format from the first event
+    pub fn new() -> EventDecoder {
+        EventDecoder {
+            format: None,
+        }
+    }
+
+    /// Accept framed events only
+    pub fn framed() -> EventDecoder {
+        EventDecoder {
+            format: Some(EventFormat::Framed),
+        }
+    }
+
+    /// The format of the stream, `None` until the first event
+    pub fn format(&self) -> Option<EventFormat> {
+        self.format
+    }
+
+    /// A raw event has a code below 256, so only its first byte is set, and that is never `EVENT_MAGIC`
+    fn sniff(bytes: &[u8]) -> Option<EventFormat> {
+        if bytes.is_empty() {
+            None
+        } else if bytes[0] == EVENT_MAGIC {
+            Some(EventFormat::Framed)
+        } else if bytes.len() < 8 {
+            None
+        } else if bytes[1..8].iter().all(|&byte| byte == 0) {
+            Some(EventFormat::Raw)
+        } else {
+            Some(EventFormat::Framed)
+        }
+    }
+
+    /// Decode the event at the start of `bytes`, with the bytes it took
+    pub fn decode(&mut self, bytes: &[u8]) -> (Decoded, usize) {
+        let format = match self.format.or_else(|| EventDecoder::sniff(bytes)) {
+            Some(format) => format,
+            None => return (Decoded::Incomplete, 0),
+        };
+
+        let size = mem::size_of::<Event>();
+        let (decoded, len) = match format {
+            EventFormat::Raw => if bytes.len() < size {
+                (Decoded::Incomplete, 0)
+            } else {
+                let mut event = Event::new();
+                event.copy_from_slice(&bytes[..size]);
+                (Decoded::Event(event), size)
+            },
+            EventFormat::Framed => if bytes.len() < EVENT_HEADER_LEN {
+                (Decoded::Incomplete, 0)
+            } else if bytes[0] != EVENT_MAGIC {
+                (Decoded::Corrupt, 0)
+            } else {
+                let body = bytes[2] as usize | (bytes[3] as usize) << 8;
+                if bytes[1] < EVENT_VERSION || (bytes[1] == EVENT_VERSION && body != size) {
+                    (Decoded::Corrupt, 0)
+                } else if bytes.len() < EVENT_HEADER_LEN + body {
+                    (Decoded::Incomplete, 0)
+                } else if bytes[1] > EVENT_VERSION {
+                    (Decoded::Skipped, EVENT_HEADER_LEN + body)
+                } else {
+                    let mut event = Event::new();
+                    event.copy_from_slice(&bytes[EVENT_HEADER_LEN..EVENT_HEADER_LEN + size]);
+                    (Decoded::Event(event), EVENT_HEADER_LEN + size)
+                }
+            },
+        };
+
+        if len > 0 {
+            self.format = Some(format);
+        }
+        (decoded, len)
+    }
 }
 
 impl Deref for Event {
@@ -85,6 +317,8 @@ pub struct MouseEvent {
     pub middle_button: bool,
     /// Was the right button pressed?
     pub right_button: bool,
+    /// The pointing device, 0 for the PS/2 mouse
+    pub device: u8,
 }
 
 impl MouseEvent {
@@ -95,7 +329,7 @@ impl MouseEvent {
             a: self.x as i64,
             b: self.y as i64,
             c: self.left_button as i64 | (self.middle_button as i64) << 1 |
-               (self.right_button as i64) << 2,
+               (self.right_button as i64) << 2 | (self.device as i64) << 8,
         }
     }
 
@@ -107,6 +341,39 @@ impl MouseEvent {
             left_button: event.c & 1 == 1,
             middle_button: event.c & 2 == 2,
             right_button: event.c & 4 == 4,
+            device: (event.c >> 8) as u8,
+        }
+    }
+}
+
+/// A mouse wheel was turned
+#[derive(Copy, Clone, Debug)]
+pub struct ScrollEvent {
+    /// The horizontal steps, positive to the right
+    pub x: i32,
+    /// The vertical steps, positive away from the user
+    pub y: i32,
+    /// The pointing device
+    pub device: u8,
+}
+
+impl ScrollEvent {
+    /// Convert to an `Event`
+    pub fn to_event(&self) -> Event {
+        Event {
+            code: EVENT_SCROLL,
+            a: self.x as i64,
+            b: self.y as i64,
+            c: self.device as i64,
+        }
+    }
+
+    /// Convert an `Event` to a `ScrollEvent`
+    pub fn from_event(event: Event) -> ScrollEvent {
+        ScrollEvent {
+            x: event.a as i32,
+            y: event.b as i32,
+            device: event.c as u8,
         }
     }
 }
@@ -232,12 +499,20 @@ pub const K_DEL: u8 = 0x53;
 pub const K_F11: u8 = 0x57;
 /// F12 key
 pub const K_F12: u8 = 0x58;
+/// Mute key, sent by PS/2 keyboards as 0xE0 0x20
+pub const K_MUTE: u8 = 0x71;
+/// Volume down key, sent by PS/2 keyboards as 0xE0 0x2E
+pub const K_VOLDOWN: u8 = 0x72;
+/// Volume up key, sent by PS/2 keyboards as 0xE0 0x30
+pub const K_VOLUP: u8 = 0x73;
 
 /// A key event (such as a pressed key)
 #[derive(Copy, Clone, Debug)]
 pub struct KeyEvent {
-    /// The charecter of the key
-    pub character: char,
+    /// The character of the key, `None` for keys like shift that have none
+    ///
+    /// In an `Event` a key without a character is sent as 0, so NUL is not a character of a key.
+    pub character: Option<char>,
     /// The scancode of the key
     pub scancode: u8,
     /// Was it pressed?
@@ -249,19 +524,30 @@ impl KeyEvent {
     pub fn to_event(&self) -> Event {
         Event {
             code: EVENT_KEY,
-            a: self.character as i64,
+            a: self.character.map_or(0, |character| character as i64),
             b: self.scancode as i64,
             c: self.pressed as i64,
         }
     }
 
-    /// Convert from an `Event`
-    pub fn from_event(event: Event) -> KeyEvent {
-        KeyEvent {
-            character: char::from_u32(event.a as u32).unwrap_or('\0'),
+    /// Convert from an `Event`, `None` if it does not hold a character, like a surrogate or a value past U+10FFFF
+    pub fn from_event(event: Event) -> Option<KeyEvent> {
+        let character = if event.a == 0 {
+            None
+        } else if event.a > 0 && event.a <= 0x10FFFF {
+            match char::from_u32(event.a as u32) {
+                Some(character) => Some(character),
+                None => return None,
+            }
+        } else {
+            return None;
+        };
+
+        Some(KeyEvent {
+            character: character,
             scancode: event.b as u8,
-            pressed: event.c > 0,
-        }
+            pressed: event.c & ! EVENT_SYNTHETIC > 0,
+        })
     }
 }
 
@@ -282,3 +568,319 @@ impl QuitEvent {
         QuitEvent
     }
 }
+
+/// A device was attached or detached
+#[derive(Copy, Clone, Debug)]
+pub struct HotplugEvent {
+    /// The kind of device, such as `HOTPLUG_DISK`
+    pub kind: i64,
+    /// The index of the device in its scheme
+    pub index: i64,
+    /// Was it attached?
+    pub added: bool,
+}
+
+impl HotplugEvent {
+    /// Convert to an `Event`
+    pub fn to_event(&self) -> Event {
+        Event {
+            code: EVENT_HOTPLUG,
+            a: self.kind,
+            b: self.index,
+            c: self.added as i64,
+        }
+    }
+
+    /// Convert from an `Event`
+    pub fn from_event(event: Event) -> HotplugEvent {
+        HotplugEvent {
+            kind: event.a,
+            index: event.b,
+            added: event.c > 0,
+        }
+    }
+}
+
+/// A network link went up or down
+#[derive(Copy, Clone, Debug)]
+pub struct LinkEvent {
+    /// Is the link up?
+    pub up: bool,
+    /// The speed in Mbit/s, 0 if unknown
+    pub speed: i64,
+    /// Is the link full duplex?
+    pub full_duplex: bool,
+}
+
+impl LinkEvent {
+    /// Convert to an `Event`
+    pub fn to_event(&self) -> Event {
+        Event {
+            code: EVENT_LINK,
+            a: self.up as i64,
+            b: self.speed,
+            c: self.full_duplex as i64,
+        }
+    }
+
+    /// Convert from an `Event`
+    pub fn from_event(event: Event) -> LinkEvent {
+        LinkEvent {
+            up: event.a > 0,
+            speed: event.b,
+            full_duplex: event.c > 0,
+        }
+    }
+}
+
+/// An audio stream needs more samples, or the output of a device changed
+#[derive(Copy, Clone, Debug)]
+pub struct AudioEvent {
+    /// What happened, such as `AUDIO_REFILL`
+    pub kind: i64,
+    /// The index of the device in the audio scheme
+    pub device: i64,
+    /// The id of the stream, or for `AUDIO_JACK` 1 if headphones were plugged in and 0 if they were pulled out
+    pub value: i64,
+}
+
+impl AudioEvent {
+    /// Convert to an `Event`
+    pub fn to_event(&self) -> Event {
+        Event {
+            code: EVENT_AUDIO,
+            a: self.kind,
+            b: self.device,
+            c: self.value,
+        }
+    }
+
+    /// Convert from an `Event`
+    pub fn from_event(event: Event) -> AudioEvent {
+        AudioEvent {
+            kind: event.a,
+            device: event.b,
+            value: event.c,
+        }
+    }
+}
+
+/// The system is about to power off or restart, programs should save what they have in the grace period
+#[derive(Copy, Clone, Debug)]
+pub struct PowerEvent {
+    /// What will happen, `POWER_OFF` or `POWER_REBOOT`
+    pub kind: i64,
+    /// The milliseconds before devices are shut down
+    pub grace: i64,
+}
+
+impl PowerEvent {
+    /// Convert to an `Event`
+    pub fn to_event(&self) -> Event {
+        Event {
+            code: EVENT_POWER,
+            a: self.kind,
+            b: self.grace,
+            c: 0,
+        }
+    }
+
+    /// Convert from an `Event`
+    pub fn from_event(event: Event) -> PowerEvent {
+        PowerEvent {
+            kind: event.a,
+            grace: event.b,
+        }
+    }
+}
+
+/// A file registered with an `event:` queue became ready
+#[derive(Copy, Clone, Debug)]
+pub struct IoEvent {
+    /// The id it was registered with
+    pub id: i64,
+    /// What it became, `IO_READ` and `IO_WRITE`, or `IO_CLOSED`
+    pub flags: i64,
+}
+
+impl IoEvent {
+    /// Convert to an `Event`
+    pub fn to_event(&self) -> Event {
+        Event {
+            code: EVENT_IO,
+            a: self.id,
+            b: self.flags,
+            c: 0,
+        }
+    }
+
+    /// Convert from an `Event`
+    pub fn from_event(event: Event) -> IoEvent {
+        IoEvent {
+            id: event.a,
+            flags: event.b,
+        }
+    }
+}
+
+/// Sent by a window to the window manager, for itself only, to be put on top of or under the others
+#[derive(Copy, Clone, Debug)]
+pub struct RaiseRequest {
+    /// Raise it, or lower it if false
+    pub raise: bool,
+}
+
+impl RaiseRequest {
+    /// Convert to an `Event`
+    pub fn to_event(&self) -> Event {
+        Event {
+            code: EVENT_RAISE,
+            a: self.raise as i64,
+            b: 0,
+            c: 0,
+        }
+    }
+
+    /// Convert from an `Event`
+    pub fn from_event(event: Event) -> RaiseRequest {
+        RaiseRequest {
+            raise: event.a > 0,
+        }
+    }
+}
+
+/// Sent by the window manager to a window when the stacking covers or uncovers it
+///
+/// Each change is sent, one with `obscured` false once all of the window can be seen again. A window
+/// that is fully obscured can stop drawing until then.
+#[derive(Copy, Clone, Debug)]
+pub struct ObscuredEvent {
+    /// Is any of it covered?
+    pub obscured: bool,
+    /// Is all of it covered?
+    pub fully: bool,
+}
+
+impl ObscuredEvent {
+    /// Convert to an `Event`
+    pub fn to_event(&self) -> Event {
+        Event {
+            code: EVENT_OBSCURED,
+            a: self.obscured as i64,
+            b: self.fully as i64,
+            c: 0,
+        }
+    }
+
+    /// Convert from an `Event`, a fully obscured window is obscured
+    pub fn from_event(event: Event) -> ObscuredEvent {
+        ObscuredEvent {
+            obscured: event.a > 0 || event.b > 0,
+            fully: event.b > 0,
+        }
+    }
+}
+
+/// Sent to the display manager when the display is switched to another mode, for it to pass on to
+/// its windows once they are moved onto the screen
+#[derive(Copy, Clone, Debug)]
+pub struct ResizeEvent {
+    pub width: usize,
+    pub height: usize,
+}
+
+impl ResizeEvent {
+    /// Convert to an `Event`
+    pub fn to_event(&self) -> Event {
+        Event {
+            code: EVENT_RESIZE,
+            a: self.width as i64,
+            b: self.height as i64,
+            c: 0,
+        }
+    }
+
+    /// Convert from an `Event`
+    pub fn from_event(event: Event) -> ResizeEvent {
+        ResizeEvent {
+            width: event.a as usize,
+            height: event.b as usize,
+        }
+    }
+}
+
+/// Sent when the screen was cleared, as after a mode switch, so the whole of it has to be drawn again
+#[derive(Copy, Clone, Debug)]
+pub struct RedrawEvent;
+
+impl RedrawEvent {
+    /// Convert to an `Event`
+    pub fn to_event(&self) -> Event {
+        Event {
+            code: EVENT_REDRAW,
+            a: 0,
+            b: 0,
+            c: 0,
+        }
+    }
+
+    /// Convert from an `Event`
+    pub fn from_event(_: Event) -> RedrawEvent {
+        RedrawEvent
+    }
+}
+
+/// Sent to the display manager when the keyboard and mouse go to it or away from it, as through Alt+Tab
+///
+/// The keys and buttons held in it are released before it loses the focus.
+#[derive(Copy, Clone, Debug)]
+pub struct FocusEvent {
+    pub focused: bool,
+}
+
+impl FocusEvent {
+    /// Convert to an `Event`
+    pub fn to_event(&self) -> Event {
+        Event {
+            code: EVENT_FOCUS,
+            a: self.focused as i64,
+            b: 0,
+            c: 0,
+        }
+    }
+
+    /// Convert from an `Event`
+    pub fn from_event(event: Event) -> FocusEvent {
+        FocusEvent {
+            focused: event.a > 0,
+        }
+    }
+}
+
+/// Written to `keyboard:control` to type with a layout while the focus is where it is now
+///
+/// A display manager sends one each time it focuses a window with a layout of its own.
+#[derive(Copy, Clone, Debug)]
+pub struct LayoutRequest {
+    /// The number of the layout in `layouts::LAYOUTS`, or `None` for the default
+    pub layout: Option<usize>,
+}
+
+impl LayoutRequest {
+    /// Convert to an `Event`
+    pub fn to_event(&self) -> Event {
+        Event {
+            code: EVENT_LAYOUT,
+            a: self.layout.map_or(0, |layout| layout as i64 + 1),
+            b: 0,
+            c: 0,
+        }
+    }
+
+    /// Convert from an `Event`
+    pub fn from_event(event: Event) -> LayoutRequest {
+        LayoutRequest {
+            layout: if event.a > 0 { Some(event.a as usize - 1) } else { None },
+        }
+    }
+}
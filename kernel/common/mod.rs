@@ -5,7 +5,7 @@ pub mod debug;
 pub mod event;
 /// Slice-related traits
 pub mod slice;
-/// A module for pseudorandom generator
+/// Random numbers from an entropy pool
 pub mod random;
 /// A module for time
 pub mod time;
@@ -1,19 +1,239 @@
-static mut seed: u64 = 19940046431; //259261034506304368955239; //1706322144714608529217229883707268827757977089;
+use arch::cpuid::cpuid;
 
-/// Generate pseudo random number
-pub fn rand() -> usize {
-    unsafe {
-        seed ^= seed << 12;
-        seed ^= seed << 25;
-        seed ^= seed << 27;
-        seed = seed * 82724793451 + 12345;
-        seed as usize % ::core::usize::MAX
+use collections::string::String;
+
+use env::log::InterruptGuard;
+
+/// The entropy pool and the generator it keys
+///
+/// Output comes from ChaCha20 blocks. After every request the key is replaced by part of the next
+/// block, so the output already given cannot be worked out from the state. Each request also mixes in
+/// the timestamp counter, what RDSEED or RDRAND give, and the interrupt timings gathered since the last
+/// one, so the output is not predictable even before any entropy was gathered.
+struct Pool {
+    key: [u32; 8],
+    counter: u64,
+    /// Interrupt timings since the last request, and the next word to mix one into
+    samples: [u32; 16],
+    sample_i: usize,
+    /// A rough count of the bits of entropy gathered, at most the bits of the key
+    entropy: usize,
+    /// Samples and words mixed in from each source
+    interrupts: u64,
+    rdseed_words: u64,
+    rdrand_words: u64,
+    /// Whether cpuid was checked, and what it has
+    probed: bool,
+    rdrand: bool,
+    rdseed: bool,
+    /// The timestamp counter at the last interrupt
+    last_tsc: u64,
+}
+
+static mut POOL: Pool = Pool {
+    key: [0; 8],
+    counter: 0,
+    samples: [0; 16],
+    sample_i: 0,
+    entropy: 0,
+    interrupts: 0,
+    rdseed_words: 0,
+    rdrand_words: 0,
+    probed: false,
+    rdrand: false,
+    rdseed: false,
+    last_tsc: 0,
+};
+
+/// The bits of entropy the pool is counted as full at
+const MAX_ENTROPY: usize = 256;
+
+/// The timestamp counter
+pub fn rdtsc() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe { asm!("rdtsc" : "={eax}"(low), "={edx}"(high) : : : "intel", "volatile") };
+    (high as u64) << 32 | low as u64
+}
+
+/// A word from RDRAND, which may fail for a moment while it reseeds, so it is tried a few times
+fn rdrand() -> Option<u32> {
+    for _ in 0..10 {
+        let (value, ok): (u32, u8);
+        unsafe { asm!("rdrand $0 ; setc $1" : "=r"(value), "=r"(ok) : : "cc" : "intel", "volatile") };
+        if ok == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// A word from RDSEED, which fails when the entropy source cannot keep up
+fn rdseed() -> Option<u32> {
+    for _ in 0..10 {
+        let (value, ok): (u32, u8);
+        unsafe { asm!("rdseed $0 ; setc $1" : "=r"(value), "=r"(ok) : : "cc" : "intel", "volatile") };
+        if ok == 1 {
+            return Some(value);
+        }
     }
+    None
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
 }
 
-/// Set the seed
-pub fn srand(s: usize) {
-    unsafe {
-        seed = s as u64;
+/// The ChaCha20 block of `key` at `counter`, with a nonce of zero
+fn chacha20(key: &[u32; 8], counter: u64) -> [u32; 16] {
+    let mut input = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574, 0, 0, 0, 0, 0, 0, 0, 0,
+                     counter as u32, (counter >> 32) as u32, 0, 0];
+    for i in 0..8 {
+        input[4 + i] = key[i];
+    }
+
+    let mut state = input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for i in 0..16 {
+        state[i] = state[i].wrapping_add(input[i]);
+    }
+    state
+}
+
+impl Pool {
+    fn probe(&mut self) {
+        if ! self.probed {
+            let (max, _, _, _) = cpuid(0, 0);
+            let (_, _, ecx, _) = cpuid(1, 0);
+            self.rdrand = ecx & 1 << 30 == 1 << 30;
+            if max >= 7 {
+                let (_, ebx, _, _) = cpuid(7, 0);
+                self.rdseed = ebx & 1 << 18 == 1 << 18;
+            }
+            self.probed = true;
+        }
+    }
+
+    fn credit(&mut self, bits: usize) {
+        self.entropy = if self.entropy + bits > MAX_ENTROPY { MAX_ENTROPY } else { self.entropy + bits };
+    }
+
+    /// Mix the timestamp counter, the hardware generators and the interrupt timings into the key
+    fn reseed(&mut self) {
+        self.probe();
+
+        let tsc = rdtsc();
+        let mut words = [0u32; 16];
+        words[0] = tsc as u32;
+        words[1] = (tsc >> 32) as u32;
+        for i in 2..10 {
+            if self.rdseed {
+                if let Some(word) = rdseed() {
+                    words[i] = word;
+                    self.rdseed_words += 1;
+                    self.credit(32);
+                    continue;
+                }
+            }
+            if self.rdrand {
+                if let Some(word) = rdrand() {
+                    words[i] = word;
+                    self.rdrand_words += 1;
+                    // RDRAND is a generator seeded by the hardware source, so it is not counted in full
+                    self.credit(8);
+                }
+            }
+        }
+
+        for i in 0..16 {
+            self.key[i % 8] ^= words[i] ^ self.samples[i];
+            self.samples[i] = 0;
+        }
+
+        let block = chacha20(&self.key, self.counter);
+        self.counter = self.counter.wrapping_add(1);
+        for i in 0..8 {
+            self.key[i] = block[i] ^ block[i + 8];
+        }
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        self.reseed();
+
+        for chunk in buf.chunks_mut(64) {
+            let block = chacha20(&self.key, self.counter);
+            self.counter = self.counter.wrapping_add(1);
+            for (i, byte) in chunk.iter_mut().enumerate() {
+                *byte = (block[i / 4] >> (i % 4 * 8)) as u8;
+            }
+        }
+
+        // Fast key erasure, the key that made this output is gone
+        let block = chacha20(&self.key, self.counter);
+        self.counter = self.counter.wrapping_add(1);
+        for i in 0..8 {
+            self.key[i] = block[i];
+        }
+    }
+}
+
+/// Mix the time of an interrupt into the pool, each one from a device counts as a bit of entropy
+///
+/// The timer interrupt is mixed in too, but counted as none, since it comes at a fixed rate.
+pub fn add_interrupt(interrupt: usize, device: bool) {
+    let pool = unsafe { &mut POOL };
+    let tsc = rdtsc();
+    let delta = tsc.wrapping_sub(pool.last_tsc);
+    pool.last_tsc = tsc;
+
+    let i = pool.sample_i;
+    pool.samples[i] = pool.samples[i].rotate_left(7) ^ delta as u32 ^ (interrupt as u32) << 24;
+    pool.sample_i = (i + 1) % 16;
+    pool.interrupts += 1;
+    if device {
+        pool.credit(1);
+    }
+}
+
+/// Fill `buf` with random bytes, which never waits for entropy
+pub fn fill(buf: &mut [u8]) {
+    let _guard = InterruptGuard::new();
+    unsafe { POOL.fill(buf) };
+}
+
+/// The entropy estimate and where it came from, as `random:/entropy` gives it
+pub fn status() -> String {
+    let _guard = InterruptGuard::new();
+    let pool = unsafe { &mut POOL };
+    pool.probe();
+    format!("entropy: {}/{} bits\ninterrupts: {}\nrdseed: {}, {} words\nrdrand: {}, {} words\n",
+            pool.entropy, MAX_ENTROPY, pool.interrupts,
+            if pool.rdseed { "yes" } else { "no" }, pool.rdseed_words,
+            if pool.rdrand { "yes" } else { "no" }, pool.rdrand_words)
+}
+
+/// Generate random number
+pub fn rand() -> usize {
+    let mut bytes = [0; 8];
+    fill(&mut bytes);
+    let mut value = 0;
+    for &byte in bytes.iter() {
+        value = value << 8 | byte as u64;
     }
+    value as usize
 }
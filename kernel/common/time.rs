@@ -1,4 +1,5 @@
 use core::cmp::Ordering;
+use core::fmt;
 use core::ops::{Add, Sub};
 
 pub const NANOS_PER_MICRO: i32 = 1000;
@@ -33,9 +34,10 @@ impl Duration {
         }
     }
 
-    /// Get the current duration
+    /// Get the current duration, from the finest clock source there is
     pub fn monotonic() -> Self {
-        unsafe { *::env().clock_monotonic.get() }
+        let nanos = ::arch::timekeeping::monotonic_ns();
+        Duration::new((nanos / NANOS_PER_SEC as u64) as i64, (nanos % NANOS_PER_SEC as u64) as i32)
     }
 
     /// Get the realtime
@@ -83,3 +85,72 @@ impl PartialOrd for Duration {
         }
     }
 }
+
+/// The wall clock time in seconds since the Unix epoch, as file times are given
+pub fn timestamp() -> u32 {
+    Duration::realtime().secs as u32
+}
+
+/// A date and time of day in UTC, as the RTC keeps it
+#[derive(Copy, Clone, PartialEq)]
+pub struct DateTime {
+    pub year: i64,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// The date and time `secs` seconds after the Unix epoch
+    pub fn from_unix(secs: i64) -> DateTime {
+        let days = if secs >= 0 { secs / 86400 } else { (secs - 86399) / 86400 };
+        let time = secs - days * 86400;
+
+        // Years start in March here, so the leap day is the last day of the year
+        let z = days + 719468;
+        let era = if z >= 0 { z } else { z - 146096 } / 146097;
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+
+        DateTime {
+            year: yoe + era * 400 + if month <= 2 { 1 } else { 0 },
+            month: month as u8,
+            day: (doy - (153 * mp + 2) / 5 + 1) as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    /// The seconds since the Unix epoch
+    pub fn to_unix(&self) -> i64 {
+        let year = if self.month <= 2 { self.year - 1 } else { self.year };
+        let era = if year >= 0 { year } else { year - 399 } / 400;
+        let yoe = year - era * 400;
+        let mp = (self.month as i64 + 9) % 12;
+        let doy = (153 * mp + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+
+        days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64
+    }
+
+    /// Whether the fields are in range, the day for the month included
+    pub fn valid(&self) -> bool {
+        self.month >= 1 && self.month <= 12 && self.day >= 1 && self.hour < 24 && self.minute < 60
+            && self.second < 60 && DateTime::from_unix(self.to_unix()) == *self
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>04}-{:>02}-{:>02} {:>02}:{:>02}:{:>02}", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
@@ -1,32 +1,78 @@
-use arch::memory::{self, Memory};
+use arch::dma::{self, Dma};
+use arch::timekeeping;
 
 use collections::String;
+use collections::string::ToString;
+use collections::vec::Vec;
 
+use core::{cmp, result};
 use core::mem::size_of;
 use core::u32;
 
 use drivers::io::{Io, Mmio};
 
-use system::error::{Error, Result, EIO};
+use disk::{self, Chunk, Limits, PartError};
 
-use super::fis::{FIS_TYPE_REG_H2D, FisRegH2D};
+use system::error::{Error, Result, EIO, ENODATA, ENODEV, ETIMEDOUT};
+
+use super::fis::{FIS_TYPE_REG_H2D, FisRegD2H, FisRegH2D};
+use super::pm::PM_CONTROL_PORT;
 
 const ATA_CMD_READ_DMA_EXT: u8 = 0x25;
 const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_CMD_READ_LOG_EXT: u8 = 0x2F;
+const ATA_CMD_READ_FPDMA_QUEUED: u8 = 0x60;
+const ATA_CMD_WRITE_FPDMA_QUEUED: u8 = 0x61;
+/// NCQ command error log page
+const ATA_LOG_NCQ_ERROR: u8 = 0x10;
 const ATA_CMD_IDENTIFY: u8 = 0xEC;
+const ATA_CMD_FLUSH_CACHE_EXT: u8 = 0xEA;
+const ATA_CMD_READ_PM: u8 = 0xE4;
+const ATA_CMD_WRITE_PM: u8 = 0xE8;
+const ATA_CTL_SRST: u8 = 0x04;
 const ATA_DEV_BUSY: u8 = 0x80;
 const ATA_DEV_DRQ: u8 = 0x08;
+/// Bad block, uncorrectable data, ID not found and address mark not found error bits
+const ATA_ERR_MEDIA: u8 = 0x80 | 0x40 | 0x10 | 0x01;
 
 const HBA_PORT_CMD_CR: u32 = 1 << 15;
 const HBA_PORT_CMD_FR: u32 = 1 << 14;
+const HBA_PORT_CMD_PMA: u32 = 1 << 17;
 const HBA_PORT_CMD_FRE: u32 = 1 << 4;
+const HBA_PORT_CMD_CLO: u32 = 1 << 3;
 const HBA_PORT_CMD_ST: u32 = 1;
 const HBA_PORT_IS_TFES: u32 = 1 << 30;
+pub const HBA_PORT_IS_PRCS: u32 = 1 << 22;
+pub const HBA_PORT_IS_PCS: u32 = 1 << 6;
+pub const HBA_PORT_IE_PRCE: u32 = 1 << 22;
+pub const HBA_PORT_IE_PCE: u32 = 1 << 6;
+pub const HBA_GHC_IE: u32 = 1 << 1;
+pub const HBA_CAP_SNCQ: u32 = 1 << 30;
+pub const HBA_CAP_SCLO: u32 = 1 << 24;
+pub const HBA_CAP_SPM: u32 = 1 << 17;
+/// The reset and clear busy on R_OK bits of the command header, which send a control FIS
+const HBA_CMD_RESET: u8 = 1;
+const HBA_CMD_CLEAR_BUSY: u8 = 1 << 2;
 const HBA_SSTS_PRESENT: u32 = 0x3;
-const HBA_SIG_ATA: u32 = 0x00000101;
+pub const HBA_SIG_ATA: u32 = 0x00000101;
 const HBA_SIG_ATAPI: u32 = 0xEB140101;
-const HBA_SIG_PM: u32 = 0x96690101;
+pub const HBA_SIG_PM: u32 = 0x96690101;
 const HBA_SIG_SEMB: u32 = 0xC33C0101;
+/// Milliseconds the command list engine is given to start or stop
+const HBA_ENGINE_TIMEOUT: u64 = 500;
+/// Milliseconds a device is given to become ready for a command, or to answer IDENTIFY, as it may be
+/// spinning up
+const HBA_READY_TIMEOUT: u64 = 10000;
+/// Milliseconds a command is given to complete, after which the device is taken to have stopped answering
+const HBA_COMMAND_TIMEOUT: u64 = 30000;
+
+/// What one command can transfer: DMA EXT and FPDMA counts are 16 bits with 0 for 65536 sectors, a PRDT
+/// entry counts up to 4 MB and PRDTL up to 65535 entries
+pub const HBA_LIMITS: Limits = Limits {
+    max_sectors: 65536,
+    max_regions: 65535,
+    max_region_len: 4 * 1024 * 1024,
+};
 
 #[derive(Debug)]
 pub enum HbaPortType {
@@ -75,79 +121,75 @@ impl HbaPort {
         }
     }
 
-    pub fn init(&mut self) {
+    /// Point the port at `memory` and start it, the memory it used before can be freed after
+    pub fn init(&mut self, memory: &mut HbaPortMemory) {
         self.stop();
 
-        // debugln!("Port Command List");
-        let clb = unsafe { memory::alloc_aligned(size_of::<HbaCmdHeader>(), 1024) };
-        self.clb.write(clb as u64);
-
-        // debugln!("Port FIS");
-        let fb = unsafe { memory::alloc_aligned(256, 256) };
-        self.fb.write(fb as u64);
+        self.clb.write(memory.clb.physical() as u64);
+        self.fb.write(memory.fb.physical() as u64);
 
-        for i in 0..32 {
-            // debugln!("Port Command Table {}", i);
-            let cmdheader = unsafe { &mut *(clb as *mut HbaCmdHeader).offset(i) };
-            let ctba = unsafe { memory::alloc_aligned(size_of::<HbaCmdTable>(), 256) };
-            cmdheader.ctba.write(ctba as u64);
-            cmdheader.prdtl.write(0);
+        for (i, ctba) in memory.ctbas.iter().enumerate() {
+            memory.clb[i].ctba.write(ctba.physical() as u64);
+            memory.clb[i].prdtl.write(0);
         }
 
         self.start();
     }
 
-    pub unsafe fn identify(&mut self, port: usize) -> Option<u64> {
+    /// Switch the port to address the devices behind a port multiplier by the PMP field of each FIS, or
+    /// back to the one device attached
+    pub fn set_pm(&mut self, pm: bool) {
+        self.stop();
+        self.cmd.writef(HBA_PORT_CMD_PMA, pm);
+        self.start();
+    }
+
+    /// Identify the device on `pmp`, returning its size in bytes, serial number and NCQ queue depth (0 if
+    /// unsupported), `name` is what it is logged as
+    pub unsafe fn identify(&mut self, name: &str, pmp: u8) -> Option<(u64, String, usize)> {
         self.is.write(u32::MAX);
 
-        let mut destination = Memory::<u16>::new(256).unwrap();
+        let destination = match dma::alloc_contiguous::<u16>(512, 2) {
+            Ok(destination) => destination,
+            Err(_) => return None,
+        };
 
         if let Some(slot) = self.slot() {
             // debugln!("Slot {}", slot);
 
-            let clb = self.clb.read() as usize;
-            let cmdheader = &mut *(clb as *mut HbaCmdHeader).offset(slot as isize);
-
-            cmdheader.cfl.write(((size_of::<FisRegH2D>() / size_of::<u32>()) as u8));
-
-            cmdheader.prdtl.write(1);
-
-            let ctba = cmdheader.ctba.read() as usize;
-            ::memset(ctba as *mut u8, 0, size_of::<HbaCmdTable>());
-            let cmdtbl = &mut *(ctba as *mut HbaCmdTable);
-
-            let prdt_entry = &mut cmdtbl.prdt_entry[0];
-            prdt_entry.dba.write(destination.as_mut_ptr() as u64);
-            prdt_entry.dbc.write(512 | 1);
-
-            let cmdfis = &mut *(cmdtbl.cfis.as_ptr() as *mut FisRegH2D);
-
-            cmdfis.fis_type.write(FIS_TYPE_REG_H2D);
-            cmdfis.pm.write(1 << 7);
-            cmdfis.command.write(ATA_CMD_IDENTIFY);
-            cmdfis.device.write(0);
-            cmdfis.countl.write(1);
-            cmdfis.counth.write(0);
+            {
+                let cmdfis = self.prepare(slot, pmp, 0, &[(destination.physical(), 512)], false);
+                cmdfis.command.write(ATA_CMD_IDENTIFY);
+                cmdfis.device.write(0);
+                cmdfis.countl.write(1);
+                cmdfis.counth.write(0);
+            }
 
-            // debugln!("Busy Wait");
-            while self.tfd.readf((ATA_DEV_BUSY | ATA_DEV_DRQ) as u32) {}
+            if self.wait_ready().is_err() {
+                syslog_warning!("   + {}: Device did not become ready", name);
+                return None;
+            }
 
             self.ci.writef(1 << slot, true);
 
             // debugln!("Completion Wait");
-            while self.ci.readf(1 << slot) {
-                if self.is.readf(HBA_PORT_IS_TFES) {
-                    return None;
-                }
-            }
+            let completed = timekeeping::spin_for(HBA_READY_TIMEOUT, || {
+                ! self.ci.readf(1 << slot) || self.is.readf(HBA_PORT_IS_TFES)
+            });
 
             if self.is.readf(HBA_PORT_IS_TFES) {
+                // Cleared, so the port takes commands for any other device behind it
+                self.error();
+                return None;
+            }
+            if ! completed {
+                syslog_warning!("   + {}: IDENTIFY timed out", name);
                 return None;
             }
 
             let mut serial = String::new();
             for word in 10..20 {
-                let d = destination.read(word);
+                let d = destination.load(word);
                 let a = ((d >> 8) as u8) as char;
                 if a != '\0' {
                     serial.push(a);
@@ -160,7 +202,7 @@ impl HbaPort {
 
             let mut firmware = String::new();
             for word in 23..27 {
-                let d = destination.read(word);
+                let d = destination.load(word);
                 let a = ((d >> 8) as u8) as char;
                 if a != '\0' {
                     firmware.push(a);
@@ -173,7 +215,7 @@ impl HbaPort {
 
             let mut model = String::new();
             for word in 27..47 {
-                let d = destination.read(word);
+                let d = destination.load(word);
                 let a = ((d >> 8) as u8) as char;
                 if a != '\0' {
                     model.push(a);
@@ -184,22 +226,28 @@ impl HbaPort {
                 }
             }
 
-            let mut sectors = (destination.read(100) as u64) |
-                              ((destination.read(101) as u64) << 16) |
-                              ((destination.read(102) as u64) << 32) |
-                              ((destination.read(103) as u64) << 48);
+            let mut sectors = (destination.load(100) as u64) |
+                              ((destination.load(101) as u64) << 16) |
+                              ((destination.load(102) as u64) << 32) |
+                              ((destination.load(103) as u64) << 48);
 
             let lba_bits = if sectors == 0 {
-                sectors = (destination.read(60) as u64) | ((destination.read(61) as u64) << 16);
+                sectors = (destination.load(60) as u64) | ((destination.load(61) as u64) << 16);
                 28
             } else {
                 48
             };
 
-            syslog_info!("   + Port {}: Serial: {} Firmware: {} Model: {} {}-bit LBA Size: {} MB",
-                        port, serial.trim(), firmware.trim(), model.trim(), lba_bits, sectors / 2048);
+            let queue_depth = if destination.load(76) & 1 << 8 == 1 << 8 {
+                (destination.load(75) & 0x1F) as usize + 1
+            } else {
+                0
+            };
 
-            Some(sectors * 512)
+            syslog_info!("   + {}: Serial: {} Firmware: {} Model: {} {}-bit LBA Size: {} MB NCQ: {}",
+                        name, serial.trim(), firmware.trim(), model.trim(), lba_bits, sectors / 2048, queue_depth);
+
+            Some((sectors * 512, serial.trim().to_string(), queue_depth))
         } else {
             debugln!("No Command Slots");
             None
@@ -209,7 +257,9 @@ impl HbaPort {
     pub fn start(&mut self) {
         // debugln!("Starting port");
 
-        while self.cmd.readf(HBA_PORT_CMD_CR) {}
+        if ! timekeeping::spin_for(HBA_ENGINE_TIMEOUT, || ! self.cmd.readf(HBA_PORT_CMD_CR)) {
+            syslog_warning!("AHCI: Command list engine did not stop before starting");
+        }
 
         self.cmd.writef(HBA_PORT_CMD_FRE, true);
         self.cmd.writef(HBA_PORT_CMD_ST, true);
@@ -220,11 +270,156 @@ impl HbaPort {
 
         self.cmd.writef(HBA_PORT_CMD_ST, false);
 
-        while self.cmd.readf(HBA_PORT_CMD_FR | HBA_PORT_CMD_CR) {}
+        if ! timekeeping::spin_for(HBA_ENGINE_TIMEOUT, || ! self.cmd.readf(HBA_PORT_CMD_FR | HBA_PORT_CMD_CR)) {
+            syslog_warning!("AHCI: Command list engine did not stop");
+        }
 
         self.cmd.writef(HBA_PORT_CMD_FRE, false);
     }
 
+    /// Recover from a task file error, returning ENODATA for media errors and EIO otherwise
+    pub fn error(&mut self) -> Error {
+        let error = (self.tfd.read() >> 8) as u8;
+
+        self.stop();
+        self.serr.write(u32::MAX);
+        self.is.write(u32::MAX);
+        self.start();
+
+        if error & ATA_ERR_MEDIA != 0 {
+            Error::new(ENODATA)
+        } else {
+            Error::new(EIO)
+        }
+    }
+
+    /// Bring the port back after a command to the device on `pmp` failed or timed out, resetting that
+    /// device alone
+    ///
+    /// Stopping the port aborts every command on it, so the caller makes sure no other device behind a
+    /// port multiplier has one issued.
+    ///
+    /// A device left busy would hold up the port, so its busy and DRQ bits are overridden if `clo`, when
+    /// the HBA supports command list override. Returns whether the device answered the reset.
+    pub fn recover(&mut self, pmp: u8, clo: bool) -> bool {
+        self.stop();
+        self.serr.write(u32::MAX);
+        self.is.write(u32::MAX);
+
+        if clo && self.tfd.read() & (ATA_DEV_BUSY | ATA_DEV_DRQ) as u32 != 0 {
+            self.cmd.writef(HBA_PORT_CMD_CLO, true);
+            if ! timekeeping::spin_for(HBA_ENGINE_TIMEOUT, || ! self.cmd.readf(HBA_PORT_CMD_CLO)) {
+                syslog_warning!("AHCI: Command list override did not complete");
+            }
+        }
+
+        self.start();
+
+        self.soft_reset(pmp).is_ok()
+    }
+
+    /// Issue a control FIS to `pmp`, raising SRST if `reset`
+    fn control(&mut self, slot: u32, pmp: u8, reset: bool) -> Result<()> {
+        {
+            let cmdfis = self.prepare(slot, pmp, 0, &[], false);
+            cmdfis.pm.write(pmp);
+            cmdfis.device.write(0);
+            if reset {
+                cmdfis.control.write(ATA_CTL_SRST);
+            }
+        }
+        // Nothing answers the FIS raising SRST, so the HBA clears the slot once it is sent
+        self.header(slot).pm.writef(HBA_CMD_RESET | HBA_CMD_CLEAR_BUSY, reset);
+
+        self.ci.writef(1 << slot, true);
+
+        if timekeeping::spin_for(HBA_ENGINE_TIMEOUT, || ! self.ci.readf(1 << slot)) {
+            Ok(())
+        } else {
+            Err(Error::new(ETIMEDOUT))
+        }
+    }
+
+    /// Reset the device on `pmp` with a software reset, returning the signature it answers with
+    ///
+    /// Unlike a COMRESET this goes to the one device, not to every device behind a port multiplier.
+    pub fn soft_reset(&mut self, pmp: u8) -> Result<u32> {
+        self.is.write(u32::MAX);
+
+        let slot = try!(self.slot().ok_or(Error::new(EIO)));
+        try!(self.control(slot, pmp, true));
+        // SRST is held for at least 5 microseconds
+        timekeeping::spin_for(1, || false);
+        try!(self.control(slot, pmp, false));
+
+        if ! timekeeping::spin_for(HBA_READY_TIMEOUT, || self.tfd.read() & ATA_DEV_BUSY as u32 == 0) {
+            return Err(Error::new(ETIMEDOUT));
+        }
+
+        let fis = self.received();
+        Ok((fis.countl.read() as u32) |
+           ((fis.lba0.read() as u32) << 8) |
+           ((fis.lba1.read() as u32) << 16) |
+           ((fis.lba2.read() as u32) << 24))
+    }
+
+    /// Issue READ or WRITE PORT MULTIPLIER to the control port of the multiplier, for `register` of the
+    /// multiplier or of its fan-out `port`, returning the value it answers with
+    fn pm_command(&mut self, command: u8, port: u8, register: u16, value: u32) -> Result<u32> {
+        self.is.write(u32::MAX);
+
+        let slot = try!(self.slot().ok_or(Error::new(EIO)));
+        {
+            let cmdfis = self.prepare(slot, PM_CONTROL_PORT, 0, &[], false);
+            cmdfis.command.write(command);
+            cmdfis.featurel.write(register as u8);
+            cmdfis.featureh.write((register >> 8) as u8);
+            cmdfis.device.write(port);
+            cmdfis.countl.write(value as u8);
+            cmdfis.lba0.write((value >> 8) as u8);
+            cmdfis.lba1.write((value >> 16) as u8);
+            cmdfis.lba2.write((value >> 24) as u8);
+        }
+
+        try!(self.wait_ready());
+
+        self.ci.writef(1 << slot, true);
+
+        try!(self.wait_complete(slot));
+
+        let fis = self.received();
+        Ok((fis.countl.read() as u32) |
+           ((fis.lba0.read() as u32) << 8) |
+           ((fis.lba1.read() as u32) << 16) |
+           ((fis.lba2.read() as u32) << 24))
+    }
+
+    /// Read a register of the port multiplier, or of its fan-out `port`
+    pub fn pm_read(&mut self, port: u8, register: u16) -> Result<u32> {
+        self.pm_command(ATA_CMD_READ_PM, port, register, 0)
+    }
+
+    /// Write a register of the port multiplier, or of its fan-out `port`
+    pub fn pm_write(&mut self, port: u8, register: u16, value: u32) -> Result<()> {
+        self.pm_command(ATA_CMD_WRITE_PM, port, register, value).map(|_| ())
+    }
+
+    /// The last register FIS received from a device
+    fn received(&self) -> &'static FisRegD2H {
+        unsafe { & *((self.fb.read() as usize + 0x40) as *const FisRegD2H) }
+    }
+
+    /// The LBA reported by the device in the last register FIS, this is the failing block after an error
+    pub fn error_lba(&self) -> u64 {
+        let fis = self.received();
+        (fis.lba0.read() as u64) |
+        ((fis.lba1.read() as u64) << 8) |
+        ((fis.lba2.read() as u64) << 16) |
+        ((fis.lba3.read() as u64) << 24) |
+        ((fis.lba4.read() as u64) << 32) |
+        ((fis.lba5.read() as u64) << 40)
+    }
+
     pub fn slot(&self) -> Option<u32> {
         let slots = self.sact.read() | self.ci.read();
         for i in 0..32 {
@@ -235,75 +430,121 @@ impl HbaPort {
         None
     }
 
-    pub fn ata_dma_small(&mut self, block: u64, sectors: usize, mut buf: usize, write: bool) -> Result<usize> {
-        if buf >= 0x80000000 {
-            buf -= 0x80000000;
-        }
+    fn header(&self, slot: u32) -> &'static mut HbaCmdHeader {
+        let clb = self.clb.read() as usize;
+        unsafe { &mut *(clb as *mut HbaCmdHeader).offset(slot as isize) }
+    }
 
-        // TODO: PRDTL for files larger than 4MB
-        let entries = 1;
+    /// Fill in the command header, PRDT and FIS for a transfer with the device on `pmp`, to or from the
+    /// physical `regions`, the caller sets the command and count
+    ///
+    /// `pmp` selects the port behind a port multiplier, and is 0 for a device attached directly.
+    fn prepare(&mut self, slot: u32, pmp: u8, block: u64, regions: &[(usize, usize)], write: bool) -> &'static mut FisRegH2D {
+        let cmdheader = self.header(slot);
 
-        if buf > 0 && sectors > 0 {
-            self.is.write(u32::MAX);
+        cmdheader.cfl.write(((size_of::<FisRegH2D>() / size_of::<u32>()) as u8));
+        cmdheader.cfl.writef(1 << 6, write);
+        cmdheader.pm.write(pmp << 4);
 
-            if let Some(slot) = self.slot() {
-                // debugln!("Slot {}", slot);
-
-                let clb = self.clb.read() as usize;
-                let cmdheader = unsafe { &mut *(clb as *mut HbaCmdHeader).offset(slot as isize) };
+        cmdheader.prdtl.write(regions.len() as u16);
 
-                cmdheader.cfl.write(((size_of::<FisRegH2D>() / size_of::<u32>()) as u8));
-                cmdheader.cfl.writef(1 << 6, write);
+        let ctba = cmdheader.ctba.read() as usize;
+        // Only the FIS and the entries in use need clearing, not the whole table
+        unsafe { ::memset(ctba as *mut u8, 0, 0x80 + regions.len() * size_of::<HbaPrdtEntry>()) };
+        let cmdtbl = unsafe { &mut *(ctba as *mut HbaCmdTable) };
 
-                cmdheader.prdtl.write(entries);
+        for (prdt_entry, &(address, len)) in cmdtbl.prdt_entry.iter_mut().zip(regions.iter()) {
+            prdt_entry.dba.write(address as u64);
+            // The byte count is one less than the length, which is always even
+            prdt_entry.dbc.write((len - 1) as u32 | 1);
+        }
 
-                let ctba = cmdheader.ctba.read() as usize;
-                unsafe { ::memset(ctba as *mut u8, 0, size_of::<HbaCmdTable>()) };
-                let cmdtbl = unsafe { &mut *(ctba as *mut HbaCmdTable) };
+        let cmdfis = unsafe { &mut *(cmdtbl.cfis.as_ptr() as *mut FisRegH2D) };
 
-                let prdt_entry = &mut cmdtbl.prdt_entry[0];
-                prdt_entry.dba.write(buf as u64);
-                prdt_entry.dbc.write(((sectors * 512) as u32) | 1);
+        cmdfis.fis_type.write(FIS_TYPE_REG_H2D);
+        cmdfis.pm.write(1 << 7 | pmp);
 
-                let cmdfis = unsafe { &mut *(cmdtbl.cfis.as_ptr() as *mut FisRegH2D) };
+        cmdfis.lba0.write(block as u8);
+        cmdfis.lba1.write((block >> 8) as u8);
+        cmdfis.lba2.write((block >> 16) as u8);
 
-                cmdfis.fis_type.write(FIS_TYPE_REG_H2D);
-                cmdfis.pm.write(1 << 7);
-                if write {
-                    cmdfis.command.write(ATA_CMD_WRITE_DMA_EXT);
-                } else {
-                    cmdfis.command.write(ATA_CMD_READ_DMA_EXT);
-                }
+        cmdfis.device.write(1 << 6);
 
-                cmdfis.lba0.write(block as u8);
-                cmdfis.lba1.write((block >> 8) as u8);
-                cmdfis.lba2.write((block >> 16) as u8);
+        cmdfis.lba3.write((block >> 24) as u8);
+        cmdfis.lba4.write((block >> 32) as u8);
+        cmdfis.lba5.write((block >> 40) as u8);
 
-                cmdfis.device.write(1 << 6);
+        cmdfis
+    }
 
-                cmdfis.lba3.write((block >> 24) as u8);
-                cmdfis.lba4.write((block >> 32) as u8);
-                cmdfis.lba5.write((block >> 40) as u8);
+    /// Wait for the device to accept a new command, for up to `HBA_READY_TIMEOUT`
+    fn wait_ready(&self) -> Result<()> {
+        // debugln!("Busy Wait");
+        let mut present = true;
+        let ready = timekeeping::spin_for(HBA_READY_TIMEOUT, || {
+            present = self.ssts.readf(HBA_SSTS_PRESENT);
+            ! present || ! self.tfd.readf((ATA_DEV_BUSY | ATA_DEV_DRQ) as u32)
+        });
+
+        if ! present {
+            Err(Error::new(ENODEV))
+        } else if ! ready {
+            Err(Error::new(ETIMEDOUT))
+        } else {
+            Ok(())
+        }
+    }
 
-                cmdfis.countl.write(sectors as u8);
-                cmdfis.counth.write((sectors >> 8) as u8);
+    /// Wait for the command in `slot` to complete, for up to `HBA_COMMAND_TIMEOUT`
+    ///
+    /// The command is left issued if it times out, for `recover` to clear.
+    fn wait_complete(&mut self, slot: u32) -> Result<()> {
+        // debugln!("Completion Wait");
+        let mut present = true;
+        let completed = timekeeping::spin_for(HBA_COMMAND_TIMEOUT, || {
+            present = self.ssts.readf(HBA_SSTS_PRESENT);
+            ! present || ! self.ci.readf(1 << slot) || self.is.readf(HBA_PORT_IS_TFES)
+        });
+
+        if self.is.readf(HBA_PORT_IS_TFES) {
+            Err(self.error())
+        } else if ! present {
+            Err(Error::new(ENODEV))
+        } else if ! completed {
+            Err(Error::new(ETIMEDOUT))
+        } else {
+            Ok(())
+        }
+    }
 
-                // debugln!("Busy Wait");
-                while self.tfd.readf((ATA_DEV_BUSY | ATA_DEV_DRQ) as u32) {}
+    /// Transfer one chunk with a READ/WRITE DMA EXT command to the device on `pmp`, its regions must be
+    /// physical
+    pub fn ata_dma_chunk(&mut self, pmp: u8, chunk: &Chunk, write: bool) -> Result<usize> {
+        let sectors = chunk.sectors;
+        if sectors > 0 {
+            self.is.write(u32::MAX);
 
-                self.ci.writef(1 << slot, true);
+            if let Some(slot) = self.slot() {
+                // debugln!("Slot {}", slot);
 
-                // debugln!("Completion Wait");
-                while self.ci.readf(1 << slot) {
-                    if self.is.readf(HBA_PORT_IS_TFES) {
-                        return Err(Error::new(EIO));
+                {
+                    let cmdfis = self.prepare(slot, pmp, chunk.block, &chunk.regions, write);
+                    if write {
+                        cmdfis.command.write(ATA_CMD_WRITE_DMA_EXT);
+                    } else {
+                        cmdfis.command.write(ATA_CMD_READ_DMA_EXT);
                     }
-                }
 
-                if self.is.readf(HBA_PORT_IS_TFES) {
-                    return Err(Error::new(EIO));
+                    cmdfis.countl.write(sectors as u8);
+                    cmdfis.counth.write((sectors >> 8) as u8);
                 }
 
+                try!(self.wait_ready());
+
+                self.ci.writef(1 << slot, true);
+
+                try!(self.wait_complete(slot));
+
                 Ok(sectors * 512)
             } else {
                 debugln!("No Command Slots");
@@ -315,34 +556,222 @@ impl HbaPort {
         }
     }
 
-    pub fn ata_dma(&mut self, block: u64, sectors: usize, buf: usize, write: bool) -> Result<usize> {
-        // debugln!("AHCI {:X} DMA BLOCK: {:X} SECTORS: {} BUF: {:X} WRITE: {}", (self as *mut HbaPort) as usize, block, sectors, buf, write);
+    /// Write the volatile cache of the device on `pmp` to the medium
+    pub fn flush(&mut self, pmp: u8) -> Result<()> {
+        self.is.write(u32::MAX);
 
-        if sectors > 0 {
-            let contexts = unsafe { & *::env().contexts.get() };
-            let current = try!(contexts.current());
-            let physical_address = try!(current.translate(buf, sectors * 512));
+        let slot = try!(self.slot().ok_or(Error::new(EIO)));
+        {
+            // Nothing is transferred, so the command has no PRDT entries
+            let cmdfis = self.prepare(slot, pmp, 0, &[], false);
+            cmdfis.command.write(ATA_CMD_FLUSH_CACHE_EXT);
+        }
+
+        try!(self.wait_ready());
+
+        self.ci.writef(1 << slot, true);
+
+        self.wait_complete(slot)
+    }
+
+    /// Issue a READ/WRITE FPDMA QUEUED command for a chunk on a tag, its regions must be physical
+    fn fpdma_issue(&mut self, pmp: u8, tag: u32, chunk: &Chunk, write: bool) -> Result<()> {
+        let sectors = chunk.sectors;
+        {
+            let cmdfis = self.prepare(tag, pmp, chunk.block, &chunk.regions, write);
+            if write {
+                cmdfis.command.write(ATA_CMD_WRITE_FPDMA_QUEUED);
+            } else {
+                cmdfis.command.write(ATA_CMD_READ_FPDMA_QUEUED);
+            }
+
+            // Queued commands carry the sector count in the feature register and the tag in the count register
+            cmdfis.featurel.write(sectors as u8);
+            cmdfis.featureh.write((sectors >> 8) as u8);
+            cmdfis.countl.write((tag << 3) as u8);
+            cmdfis.counth.write(0);
+        }
+
+        try!(self.wait_ready());
+
+        self.sact.writef(1 << tag, true);
+        self.ci.writef(1 << tag, true);
+
+        Ok(())
+    }
+
+    /// Read the NCQ error log after a queued command failed, returning the failing tag.
+    /// This also clears the error condition on the device so the remaining commands can be reissued
+    fn fpdma_error_tag(&mut self, pmp: u8) -> Option<u32> {
+        let log = match dma::alloc_contiguous::<u8>(512, 2) {
+            Ok(log) => log,
+            Err(_) => return None,
+        };
+
+        self.is.write(u32::MAX);
+
+        if let Some(slot) = self.slot() {
+            {
+                let cmdfis = self.prepare(slot, pmp, ATA_LOG_NCQ_ERROR as u64, &[(log.physical(), 512)], false);
+                cmdfis.command.write(ATA_CMD_READ_LOG_EXT);
+                cmdfis.device.write(0);
+                cmdfis.countl.write(1);
+                cmdfis.counth.write(0);
+            }
+
+            if self.wait_ready().is_err() {
+                return None;
+            }
+
+            self.ci.writef(1 << slot, true);
+
+            if self.wait_complete(slot).is_err() {
+                return None;
+            }
+
+            let status = log.load(0);
+            // The NQ bit means the error was for a non-queued command
+            if status & 1 << 7 == 0 {
+                return Some((status & 0x1F) as u32);
+            }
+        }
 
-            let mut sector: usize = 0;
-            while sectors - sector >= 255 {
-                if let Err(err) = self.ata_dma_small(block + sector as u64, 255, physical_address + sector * 512, write) {
-                    return Err(err);
+        None
+    }
+
+    /// Transfer using native command queueing with the device on `pmp`, the chunks are queued on up to
+    /// `depth` tags and may complete out of order
+    ///
+    /// The transfer times out if no command completes within `HBA_COMMAND_TIMEOUT`.
+    fn ata_fpdma(&mut self, pmp: u8, chunks: &[Chunk], write: bool, depth: usize) -> result::Result<usize, PartError> {
+        let depth = cmp::min(depth, 32);
+
+        // Each tag remembers which chunk it carries
+        let mut tags: [Option<usize>; 32] = [None; 32];
+        let mut next: usize = 0;
+        let mut done: usize = 0;
+        let mut failed: Option<PartError> = None;
+
+        self.is.write(u32::MAX);
+
+        while done < chunks.len() {
+            // Fill free tags
+            for tag in 0..depth {
+                if next >= chunks.len() {
+                    break;
+                }
+                if tags[tag].is_none() {
+                    if let Err(err) = self.fpdma_issue(pmp, tag as u32, &chunks[next], write) {
+                        return Err(PartError::new(chunks, next, err));
+                    }
+                    tags[tag] = Some(next);
+                    next += 1;
                 }
+            }
+
+            let issued = tags.iter().enumerate().fold(0, |mask, (tag, part)| if part.is_some() { mask | 1 << tag } else { mask });
+            let progressed = timekeeping::spin_for(HBA_COMMAND_TIMEOUT, || {
+                self.is.readf(HBA_PORT_IS_TFES) || ! self.ssts.readf(HBA_SSTS_PRESENT) || self.sact.read() & issued != issued
+            });
 
-                sector += 255;
+            if self.is.readf(HBA_PORT_IS_TFES) {
+                let err = self.error();
+                let failed_tag = self.fpdma_error_tag(pmp);
+
+                // All outstanding commands were aborted, reissue the ones that did not fail
+                for tag in 0..depth {
+                    if let Some(part) = tags[tag] {
+                        if failed_tag == Some(tag as u32) || failed_tag.is_none() {
+                            tags[tag] = None;
+                            done += 1;
+                            // The first failure in the order of the request is the one reported
+                            if failed.as_ref().map_or(true, |failed| part < failed.part) {
+                                failed = Some(PartError::new(chunks, part, Error::new(err.errno)));
+                            }
+                        } else if let Err(err) = self.fpdma_issue(pmp, tag as u32, &chunks[part], write) {
+                            return Err(PartError::new(chunks, part, err));
+                        }
+                    }
+                }
+
+                continue;
             }
-            if sector < sectors {
-                if let Err(err) = self.ata_dma_small(block + sector as u64, sectors - sector, physical_address + sector * 512, write) {
-                    return Err(err);
+
+            if ! self.ssts.readf(HBA_SSTS_PRESENT) {
+                return Err(PartError::new(chunks, tags.iter().filter_map(|&part| part).min().unwrap_or(0), Error::new(ENODEV)));
+            }
+
+            if ! progressed {
+                return Err(PartError::new(chunks, tags.iter().filter_map(|&part| part).min().unwrap_or(0), Error::new(ETIMEDOUT)));
+            }
+
+            // Completed commands are cleared from PxSACT by the set device bits FIS
+            let active = self.sact.read();
+            for tag in 0..depth {
+                if tags[tag].is_some() && active & 1 << tag == 0 {
+                    tags[tag] = None;
+                    done += 1;
                 }
             }
+        }
 
-            Ok(sectors * 512)
-        } else {
-            debugln!("Invalid request");
-            Err(Error::new(EIO))
+        match failed {
+            Some(part) => Err(part),
+            None => Ok(chunks.iter().fold(0, |count, chunk| count + chunk.sectors * 512)),
         }
     }
+
+    /// Transfer `sectors` from `block` of the device on `pmp` to or from the `segments` of memory of the
+    /// current context, using native command queueing if `queue_depth` is not zero
+    ///
+    /// The request is split into chunks within `HBA_LIMITS`, issued in order unless they are queued.
+    pub fn ata_dma(&mut self, pmp: u8, block: u64, segments: &[(usize, usize)], write: bool, queue_depth: usize)
+                   -> result::Result<usize, PartError> {
+        // debugln!("AHCI {:X} DMA BLOCK: {:X} SEGMENTS: {} WRITE: {}", (self as *mut HbaPort) as usize, block, segments.len(), write);
+
+        let mut regions = Vec::new();
+        {
+            let contexts = unsafe { & *::env().contexts.get() };
+            let translated = contexts.current().and_then(|current| {
+                for &(buf, len) in segments.iter().filter(|&&(_, len)| len > 0) {
+                    let mut physical_address = try!(current.translate(buf, len));
+                    if physical_address >= 0x80000000 {
+                        physical_address -= 0x80000000;
+                    }
+                    regions.push((physical_address, len));
+                }
+                Ok(())
+            });
+            if let Err(err) = translated {
+                return Err(PartError { part: 0, parts: 1, offset: 0, error: err });
+            }
+        }
+
+        let chunks = match disk::chunks(block, &regions, HBA_LIMITS) {
+            Ok(ref chunks) if chunks.is_empty() => Err(Error::new(EIO)),
+            result => result,
+        };
+        let chunks = match chunks {
+            Ok(chunks) => chunks,
+            Err(err) => {
+                debugln!("Invalid request");
+                return Err(PartError { part: 0, parts: 1, offset: 0, error: err });
+            }
+        };
+
+        if queue_depth > 0 {
+            return self.ata_fpdma(pmp, &chunks, write, queue_depth);
+        }
+
+        let mut count = 0;
+        for (part, chunk) in chunks.iter().enumerate() {
+            match self.ata_dma_chunk(pmp, chunk, write) {
+                Ok(size) => count += size,
+                Err(err) => return Err(PartError::new(&chunks, part, err)),
+            }
+        }
+        Ok(count)
+    }
 }
 
 #[repr(packed)]
@@ -389,7 +818,7 @@ struct HbaCmdTable {
 struct HbaCmdHeader {
     // DW0
     cfl: Mmio<u8>, /* Command FIS length in DWORDS, 2 ~ 16, atapi: 4, write - host to device: 2, prefetchable: 1 */
-    pm: Mmio<u8>, // Reset - 0x80, bist: 0x40, clear busy on ok: 0x20, port multiplier
+    pm: Mmio<u8>, // Reset: 0x01, bist: 0x02, clear busy on ok: 0x04, port multiplier port: 0xF0
 
     prdtl: Mmio<u16>, // Physical region descriptor table length in entries
 
@@ -402,3 +831,28 @@ struct HbaCmdHeader {
     // DW4 - 7
     rsv1: [Mmio<u32>; 4], // Reserved
 }
+
+/// The command list, received FIS area and command tables a port transfers with
+///
+/// The port uses them until it is pointed at others by `HbaPort::init`, so they are kept until then.
+pub struct HbaPortMemory {
+    clb: Dma<HbaCmdHeader>,
+    fb: Dma<u8>,
+    ctbas: Vec<Dma<HbaCmdTable>>,
+}
+
+impl HbaPortMemory {
+    /// A header and a table for each of the 32 command slots, aligned as the HBA requires
+    pub fn new() -> Result<HbaPortMemory> {
+        let mut ctbas = Vec::new();
+        for _ in 0..32 {
+            ctbas.push(try!(dma::alloc_contiguous(size_of::<HbaCmdTable>(), 256)));
+        }
+
+        Ok(HbaPortMemory {
+            clb: try!(dma::alloc_contiguous(32 * size_of::<HbaCmdHeader>(), 1024)),
+            fb: try!(dma::alloc_contiguous(256, 256)),
+            ctbas: ctbas,
+        })
+    }
+}
@@ -1,92 +1,473 @@
+use alloc::arc::Arc;
 use alloc::boxed::Box;
 
+use arch::context::{context_switch, Context};
+
 use collections::string::String;
 use collections::vec::Vec;
 
-use disk::Disk;
+use common::event::{HotplugEvent, HOTPLUG_DISK};
+use common::time::Duration;
+
+use core::cell::UnsafeCell;
+use core::{cmp, u32};
+use core::mem::size_of;
+
+use disk::{media_error, part_result, Disk, DiskStats, DISK_RETRIES};
+use disk::cache::{BlockCache, CACHE_SECTORS};
 
 use drivers::io::Io;
 use drivers::pci::config::PciConfig;
 
-use system::error::Result;
+use env::log::InterruptGuard;
+
+use fs::KScheme;
+
+use sync::WaitCondition;
+
+use system::error::{Error, Result, EIO, ENODEV, ETIMEDOUT};
 
-use self::hba::{HbaMem, HbaPort, HbaPortType};
+use self::hba::{HbaMem, HbaPort, HbaPortMemory, HbaPortType, HBA_CAP_SCLO, HBA_CAP_SNCQ, HBA_CAP_SPM, HBA_GHC_IE, HBA_PORT_IE_PCE,
+                HBA_PORT_IE_PRCE, HBA_PORT_IS_PCS, HBA_PORT_IS_PRCS};
 
 pub mod fis;
 pub mod hba;
+pub mod pm;
 
-pub struct Ahci;
+/// Held by a disk of a port while it has commands on it, from issuing them through any recovery
+///
+/// The devices behind a port multiplier are switched between by command, which allows commands to only one
+/// of them at a time, and stopping the port to recover one would abort those of the others.
+struct PortLock {
+    busy: UnsafeCell<bool>,
+}
+
+impl PortLock {
+    fn new() -> PortLock {
+        PortLock {
+            busy: UnsafeCell::new(false),
+        }
+    }
+
+    /// Wait for the other disks of the port to finish, then take it
+    fn lock(&self) -> PortGuard {
+        loop {
+            {
+                let _guard = InterruptGuard::new();
+                let busy = unsafe { &mut *self.busy.get() };
+                if ! *busy {
+                    *busy = true;
+                    return PortGuard {
+                        lock: self,
+                    };
+                }
+            }
+            unsafe { context_switch() };
+        }
+    }
+}
+
+struct PortGuard<'a> {
+    lock: &'a PortLock,
+}
+
+impl<'a> Drop for PortGuard<'a> {
+    fn drop(&mut self) {
+        unsafe { *self.lock.busy.get() = false };
+    }
+}
+
+/// A disk attached to an AHCI port, directly or behind a port multiplier
+struct AhciPort {
+    index: usize,
+    disk: Arc<UnsafeCell<Box<Disk>>>,
+    raw: *mut AhciDisk,
+}
+
+/// An AHCI controller, watching its ports for hotplug
+pub struct Ahci {
+    base: usize,
+    irq: u8,
+    /// The ports whose registers fit in the ABAR
+    mapped: usize,
+    ports: Vec<AhciPort>,
+    /// What each port was last initialized with
+    memory: Vec<Option<HbaPortMemory>>,
+    /// Shared by the disks of each port
+    locks: Vec<Arc<PortLock>>,
+    /// The ports whose link changed, set by the IRQ and taken by the hotplug context
+    changed: u32,
+    changed_condition: WaitCondition,
+}
 
 impl Ahci {
-    pub fn disks(mut pci: PciConfig) -> Vec<Box<Disk>> {
-        let base = unsafe { (pci.read(0x24) & 0xFFFFFFF0) as usize };
-        let irq = unsafe { (pci.read(0x3C) & 0xF) as u8 };
-
-        syslog_info!(" + AHCI on: {:X} IRQ: {:X}", base as usize, irq);
-
-        let pi = unsafe { &mut *(base as *mut HbaMem) }.pi.read();
-        let ret: Vec<Box<Disk>> = (0..32)
-                                      .filter(|&i| pi & 1 << i as i32 == 1 << i as i32)
-                                      .filter_map(|i| {
-                                          let mut disk = box AhciDisk::new(base, i, irq);
-                                          let port_type = disk.port.probe();
-                                          match port_type {
-                                              HbaPortType::SATA => {
-                                                  disk.port.init();
-                                                  if let Some(size) = unsafe { disk.port.identify(i) } {
-                                                      disk.size = size;
-                                                      Some(disk as Box<Disk>)
-                                                  } else {
-                                                      None
-                                                  }
-                                              }
-                                              _ => None,
-                                          }
-                                      })
-                                      .collect();
-
-        ret
+    pub fn new(mut pci: PciConfig) -> Box<Ahci> {
+        let abar = pci.bar(5);
+        let base = abar.base;
+        let irq = unsafe { pci.irq() };
+        // The port registers follow the generic host control
+        let mapped = cmp::min(32, abar.size.saturating_sub(0x100) / size_of::<HbaPort>());
+
+        syslog_info!(" + AHCI on: {:X} IRQ: {:X} Ports: {}", base as usize, irq, mapped);
+
+        let mut module = box Ahci {
+            base: base,
+            irq: irq,
+            mapped: mapped,
+            ports: Vec::new(),
+            memory: (0..mapped).map(|_| None).collect(),
+            locks: (0..mapped).map(|_| Arc::new(PortLock::new())).collect(),
+            changed: 0,
+            changed_condition: WaitCondition::new(),
+        };
+
+        let hba = unsafe { &mut *(base as *mut HbaMem) };
+        let pi = hba.pi.read();
+        for i in 0..mapped {
+            if pi & 1 << i == 1 << i {
+                hba.ports[i].is.write(u32::MAX);
+                hba.ports[i].serr.write(u32::MAX);
+                hba.ports[i].ie.write(HBA_PORT_IE_PRCE | HBA_PORT_IE_PCE);
+                module.attach(i, false);
+            }
+        }
+        hba.ghc.writef(HBA_GHC_IE, true);
+
+        // Probing a port allocates its command list and waits on the device, which cannot be done in the IRQ
+        let this: *mut Ahci = &mut *module;
+        Context::spawn("kahci".into(), box move || {
+            unsafe { (*this).hotplug() };
+        });
+
+        module
+    }
+
+    /// Replace the disks of each port the IRQ marked as changed with what is on it now
+    ///
+    /// The port is held throughout, so the requests of its old disks finish before they are removed.
+    fn hotplug(&mut self) {
+        loop {
+            let changed;
+            {
+                let _guard = InterruptGuard::new();
+                if self.changed == 0 {
+                    self.changed_condition.wait("Ahci::hotplug");
+                }
+                changed = self.changed;
+                self.changed = 0;
+            }
+
+            for i in 0..self.mapped {
+                if changed & 1 << i == 1 << i {
+                    let lock = self.locks[i].clone();
+                    let _port = lock.lock();
+                    self.detach(i);
+                    self.attach(i, true);
+                }
+            }
+        }
+    }
+
+    /// Probe a port and add its disk if one is present, or a disk for each device behind a port multiplier
+    ///
+    /// The devices behind a multiplier are switched between by command, one at a time.
+    fn attach(&mut self, i: usize, hotplug: bool) {
+        let hba = unsafe { &mut *(self.base as *mut HbaMem) };
+        let kind = hba.ports[i].probe();
+        match kind {
+            HbaPortType::SATA | HbaPortType::PM => match HbaPortMemory::new() {
+                Ok(mut memory) => {
+                    hba.ports[i].init(&mut memory);
+                    self.memory[i] = Some(memory);
+                },
+                Err(err) => {
+                    syslog_warning!("   + Port {}: No memory for its command list: {}", i, err);
+                    return;
+                },
+            },
+            _ => return,
+        }
+
+        let spm = hba.cap.readf(HBA_CAP_SPM);
+        let clo = hba.cap.readf(HBA_CAP_SCLO);
+        if spm && pm::probe(&mut hba.ports[i], clo) {
+            match pm::info(&mut hba.ports[i]) {
+                Ok((vendor, device, count)) => {
+                    syslog_info!("   + Port {}: Port multiplier {:04X}:{:04X} with {} ports", i, vendor, device, count);
+                    for pmp in 0..count {
+                        if pm::reset_link(&mut hba.ports[i], pmp) {
+                            self.attach_disk(i, Some(pmp), hotplug);
+                        }
+                    }
+                },
+                Err(err) => syslog_warning!("   + Port {}: Port multiplier did not answer: {}", i, err),
+            }
+        } else if let HbaPortType::PM = kind {
+            if spm {
+                syslog_warning!("   + Port {}: Port multiplier signature, but its control port did not answer", i);
+            } else {
+                syslog_warning!("   + Port {}: Port multiplier, which the controller does not support", i);
+            }
+        } else {
+            self.attach_disk(i, None, hotplug);
+        }
+    }
+
+    /// Identify the device on port `i`, behind a multiplier on `pmp` if given, and add it as a disk
+    fn attach_disk(&mut self, i: usize, pmp: Option<u8>, hotplug: bool) {
+        let hba = unsafe { & *(self.base as *const HbaMem) };
+        let mut disk = box AhciDisk::new(self.base, i, pmp, self.irq, hba.cap.readf(HBA_CAP_SCLO), self.locks[i].clone());
+        let label = disk.label();
+        if let Some((size, serial, queue_depth)) = unsafe { disk.port.identify(&label, pmp.unwrap_or(0)) } {
+            disk.size = size;
+            disk.serial = serial;
+            if hba.cap.readf(HBA_CAP_SNCQ) {
+                disk.queue_depth = queue_depth;
+            }
+
+            let raw: *mut AhciDisk = &mut *disk;
+            let arc = Arc::new(UnsafeCell::new(disk as Box<Disk>));
+
+            let disks = unsafe { &mut *::env().disks.get() };
+            disks.push(Some(arc.clone()));
+
+            if hotplug {
+                syslog_info!("AHCI {}: attached as disk:/{}", label, disks.len() - 1);
+                ::env().events.send(HotplugEvent {
+                    kind: HOTPLUG_DISK,
+                    index: (disks.len() - 1) as i64,
+                    added: true,
+                }.to_event(), "Ahci::attach");
+            }
+
+            self.ports.push(AhciPort {
+                index: i,
+                disk: arc,
+                raw: raw,
+            });
+        }
+    }
+
+    /// Remove the disks on a port, all of those behind a multiplier on it, requests already issued against
+    /// them fail with ENODEV
+    fn detach(&mut self, i: usize) {
+        while let Some(position) = self.ports.iter().position(|port| port.index == i) {
+            let port = self.ports.remove(position);
+            unsafe { (*port.raw).removed = true; }
+
+            let disks = unsafe { &mut *::env().disks.get() };
+            let index = disks.iter().position(|disk| disk.as_ref().map_or(false, |disk| disk.get() == port.disk.get()));
+            if let Some(index) = index {
+                // The slot is kept, so the disks after it keep their numbers
+                disks[index] = None;
+
+                syslog_info!("AHCI {}: detached disk:/{}", unsafe { (*port.raw).label() }, index);
+                ::env().events.send(HotplugEvent {
+                    kind: HOTPLUG_DISK,
+                    index: index as i64,
+                    added: false,
+                }.to_event(), "Ahci::detach");
+            }
+        }
+    }
+}
+
+impl KScheme for Ahci {
+    /// Mask the interrupts of the controller and detach every disk, so requests on them fail with ENODEV
+    fn shutdown(&mut self) {
+        let hba = unsafe { &mut *(self.base as *mut HbaMem) };
+        hba.ghc.writef(HBA_GHC_IE, false);
+
+        let mut ports: Vec<usize> = self.ports.iter().map(|port| port.index).collect();
+        // The disks behind a multiplier share the port
+        ports.dedup();
+        for i in ports {
+            hba.ports[i].ie.write(0);
+            self.detach(i);
+        }
+    }
+
+    fn on_irq(&mut self, irq: u8) -> bool {
+        if irq == self.irq {
+            let hba = unsafe { &mut *(self.base as *mut HbaMem) };
+            let is = hba.is.read();
+            for i in 0..self.mapped {
+                if is & 1 << i == 1 << i {
+                    let port_is = hba.ports[i].is.read();
+                    if port_is & (HBA_PORT_IS_PRCS | HBA_PORT_IS_PCS) != 0 {
+                        // Clearing SERR.DIAG.N and SERR.DIAG.X acknowledges the change
+                        hba.ports[i].serr.write(u32::MAX);
+                        hba.ports[i].is.write(port_is & (HBA_PORT_IS_PRCS | HBA_PORT_IS_PCS));
+                        self.changed |= 1 << i;
+                    }
+                }
+            }
+            hba.is.write(is);
+
+            if self.changed != 0 {
+                self.changed_condition.notify("Ahci::on_irq");
+            }
+
+            is != 0
+        } else {
+            false
+        }
     }
 }
 
 pub struct AhciDisk {
     port: &'static mut HbaPort,
     port_index: usize,
+    lock: Arc<PortLock>,
+    /// The port of the multiplier the disk is behind, if it is behind one
+    pmp: Option<u8>,
+    /// Whether the controller can override a busy device with CLO, to recover without resetting the port
+    clo: bool,
     irq: u8,
     size: u64,
+    serial: String,
+    /// Number of NCQ tags to use, 0 if the device or controller lacks NCQ
+    queue_depth: usize,
+    stats: DiskStats,
+    cache: BlockCache,
+    removed: bool,
 }
 
 impl AhciDisk {
-    fn new(base: usize, port_index: usize, irq: u8) -> Self {
+    fn new(base: usize, port_index: usize, pmp: Option<u8>, irq: u8, clo: bool, lock: Arc<PortLock>) -> Self {
         AhciDisk {
             port: &mut unsafe { &mut *(base as *mut HbaMem) }.ports[port_index],
             port_index: port_index,
+            lock: lock,
+            pmp: pmp,
+            clo: clo,
             irq: irq,
-            size: 0
+            size: 0,
+            serial: String::new(),
+            queue_depth: 0,
+            stats: DiskStats::default(),
+            cache: BlockCache::new(CACHE_SECTORS),
+            removed: false,
+        }
+    }
+
+    /// The port, and the port of the multiplier if the disk is behind one, as `Port 1.2`
+    fn label(&self) -> String {
+        match self.pmp {
+            Some(pmp) => format!("Port {}.{}", self.port_index, pmp),
+            None => format!("Port {}", self.port_index),
+        }
+    }
+
+    /// Reset the device after a command failed other than on the medium, so the port takes commands again
+    ///
+    /// Only this device is reset, and as the port is held no other has commands to abort. A device whose
+    /// link went down is reported as gone.
+    fn recover<T>(&mut self, result: Result<T>) -> Result<T> {
+        match result {
+            Err(ref err) if err.errno == EIO || err.errno == ETIMEDOUT => {
+                let pmp = self.pmp.unwrap_or(0);
+                if ! self.port.recover(pmp, self.clo) {
+                    syslog_warning!("AHCI {}: did not answer a reset", self.label());
+                }
+                if self.pmp.map_or(false, |pmp| ! pm::link_up(self.port, pmp)) {
+                    return Err(Error::new(ENODEV));
+                }
+            },
+            _ => (),
         }
+        result
+    }
+
+    /// Transfer from `block` to or from each of the `segments`, given as address and length
+    fn request(&mut self, block: u64, segments: &[(usize, usize)], write: bool) -> Result<usize> {
+        // The disk may have been removed while waiting for the port
+        let lock = self.lock.clone();
+        let _guard = lock.lock();
+        if self.removed {
+            return Err(Error::new(ENODEV));
+        }
+
+        let start = Duration::monotonic();
+
+        let name = self.name();
+        let pmp = self.pmp.unwrap_or(0);
+        let mut result = part_result(&name, self.port.ata_dma(pmp, block, segments, write, self.queue_depth));
+        let mut retries = 0;
+        // Retries are not queued, so the register FIS reports the failing block
+        while media_error(&result) && retries < DISK_RETRIES {
+            retries += 1;
+            result = part_result(&name, self.port.ata_dma(pmp, block, segments, write, 0));
+        }
+        let result = self.recover(result);
+
+        self.stats.retries += retries as u64;
+        if media_error(&result) {
+            let lba = self.port.error_lba();
+            syslog_error!("AHCI {}: media error at {} after {} retries", self.label(), lba, retries);
+            self.stats.bad_sector(lba);
+        }
+        self.stats.record(write, start, &result);
+
+        result
     }
 }
 
 impl Disk for AhciDisk {
     fn name(&self) -> String {
-        format!("AHCI Port {}", self.port_index)
+        format!("AHCI {}", self.label())
     }
 
-    fn on_irq(&mut self, irq: u8) {
+    fn on_irq(&mut self, irq: u8) -> bool {
         if irq == self.irq {
             //debugln!("AHCI IRQ");
         }
+        irq == self.irq
+    }
+
+    fn serial(&self) -> String {
+        self.serial.clone()
     }
 
     fn size(&self) -> u64 {
         self.size
     }
 
+    fn stats(&mut self) -> &mut DiskStats {
+        &mut self.stats
+    }
+
+    fn cache(&mut self) -> &mut BlockCache {
+        &mut self.cache
+    }
+
     fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
-        self.port.ata_dma(block, buffer.len() / 512, buffer.as_ptr() as usize, false)
+        self.request(block, &[(buffer.as_ptr() as usize, buffer.len() / 512 * 512)], false)
     }
 
     fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
-        self.port.ata_dma(block, buffer.len() / 512, buffer.as_ptr() as usize, true)
+        self.request(block, &[(buffer.as_ptr() as usize, buffer.len() / 512 * 512)], true)
+    }
+
+    fn read_segments(&mut self, block: u64, segments: &mut [&mut [u8]]) -> Result<usize> {
+        let segments: Vec<(usize, usize)> = segments.iter().map(|segment| (segment.as_ptr() as usize, segment.len())).collect();
+        self.request(block, &segments, false)
+    }
+
+    fn write_segments(&mut self, block: u64, segments: &[&[u8]]) -> Result<usize> {
+        let segments: Vec<(usize, usize)> = segments.iter().map(|segment| (segment.as_ptr() as usize, segment.len())).collect();
+        self.request(block, &segments, true)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let lock = self.lock.clone();
+        let _guard = lock.lock();
+        if self.removed {
+            return Err(Error::new(ENODEV));
+        }
+
+        let pmp = self.pmp.unwrap_or(0);
+        let result = self.port.flush(pmp);
+        self.recover(result)
     }
 }
@@ -0,0 +1,72 @@
+use arch::timekeeping;
+
+use core::u32;
+
+use system::error::Result;
+
+use super::hba::{HbaPort, HBA_SIG_ATA, HBA_SIG_PM};
+
+/// The port of the multiplier itself, which takes READ and WRITE PORT MULTIPLIER
+pub const PM_CONTROL_PORT: u8 = 15;
+
+/// General registers of the multiplier, read from its control port
+const PM_GSCR_PRODUCT: u16 = 0;
+const PM_GSCR_PORT_INFO: u16 = 2;
+/// Registers of each fan-out port, laid out as the SCRs of an HBA port
+const PM_PSCR_SSTATUS: u16 = 0;
+const PM_PSCR_SERROR: u16 = 1;
+const PM_PSCR_SCONTROL: u16 = 2;
+/// Power management disabled, with DET asking for a COMRESET or for none
+const PM_SCONTROL_RESET: u32 = 0x301;
+const PM_SCONTROL_IDLE: u32 = 0x300;
+const PM_SSTATUS_PRESENT: u32 = 0x3;
+/// Milliseconds a fan-out link is given to come up after it is reset
+const PM_LINK_TIMEOUT: u64 = 1000;
+
+/// Whether a port multiplier is on `port`, asked of its control port with PMA set, which is left set if
+/// there is one
+///
+/// Without PMA a multiplier passes its fan-out port 0 through, so the signature of the port does not
+/// always show it. A device that took the reset for itself is recovered, with CLO if `clo`.
+pub fn probe(port: &mut HbaPort, clo: bool) -> bool {
+    port.set_pm(true);
+    if port.soft_reset(PM_CONTROL_PORT).ok() == Some(HBA_SIG_PM) {
+        true
+    } else {
+        port.set_pm(false);
+        port.recover(0, clo);
+        false
+    }
+}
+
+/// The vendor and device ID of the multiplier, and the number of its fan-out ports
+pub fn info(port: &mut HbaPort) -> Result<(u16, u16, u8)> {
+    let product = try!(port.pm_read(PM_CONTROL_PORT, PM_GSCR_PRODUCT));
+    let ports = try!(port.pm_read(PM_CONTROL_PORT, PM_GSCR_PORT_INFO));
+    Ok((product as u16, (product >> 16) as u16, (ports & 0xF) as u8))
+}
+
+/// Whether the link of fan-out port `pmp` is up
+pub fn link_up(port: &mut HbaPort, pmp: u8) -> bool {
+    port.pm_read(pmp, PM_PSCR_SSTATUS).map_or(false, |status| status & PM_SSTATUS_PRESENT == PM_SSTATUS_PRESENT)
+}
+
+/// Reset the link of fan-out port `pmp` and then its device, returning whether an ATA disk answered
+///
+/// The links of the other fan-out ports are left alone.
+pub fn reset_link(port: &mut HbaPort, pmp: u8) -> bool {
+    if port.pm_write(pmp, PM_PSCR_SCONTROL, PM_SCONTROL_RESET).is_err() {
+        return false;
+    }
+    // COMRESET is held for at least 1 ms
+    timekeeping::spin_for(1, || false);
+    if port.pm_write(pmp, PM_PSCR_SCONTROL, PM_SCONTROL_IDLE).is_err() {
+        return false;
+    }
+
+    let up = timekeeping::spin_for(PM_LINK_TIMEOUT, || link_up(port, pmp));
+    // The reset leaves the PhyRdy change and exchanged bits set
+    let _ = port.pm_write(pmp, PM_PSCR_SERROR, u32::MAX);
+
+    up && port.soft_reset(pmp).ok() == Some(HBA_SIG_ATA)
+}
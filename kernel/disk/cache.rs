@@ -0,0 +1,89 @@
+use collections::{BTreeMap, String, Vec};
+use collections::vec_deque::VecDeque;
+
+/// Sectors kept by the cache of each disk, 512 KiB
+pub const CACHE_SECTORS: usize = 1024;
+
+/// Recently read and written sectors of a disk, the oldest are dropped first
+///
+/// Writes go through to the disk at once, so no sector is ever dirty.
+pub struct BlockCache {
+    sectors: BTreeMap<u64, Vec<u8>>,
+    /// The cached blocks in the order they were added
+    order: VecDeque<u64>,
+    capacity: usize,
+    /// Reads answered from the cache
+    pub hits: u64,
+    /// Reads that went to the disk
+    pub misses: u64,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> BlockCache {
+        BlockCache {
+            sectors: BTreeMap::new(),
+            order: VecDeque::new(),
+            capacity: capacity,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Fill `buffer` from `block` if every sector of it is cached, counting a hit or a miss
+    pub fn get(&mut self, block: u64, buffer: &mut [u8]) -> bool {
+        let cached = buffer.len() % 512 == 0 && (0..buffer.len() as u64 / 512).all(|i| self.sectors.contains_key(&(block + i)));
+        if cached {
+            for (i, sector) in buffer.chunks_mut(512).enumerate() {
+                for (b, s) in sector.iter_mut().zip(self.sectors[&(block + i as u64)].iter()) {
+                    *b = *s;
+                }
+            }
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+        cached
+    }
+
+    /// Keep the whole sectors of `data`, as read from or written to `block`
+    pub fn insert(&mut self, block: u64, data: &[u8]) {
+        for (i, sector) in data.chunks(512).filter(|sector| sector.len() == 512).enumerate() {
+            let block = block + i as u64;
+            if self.sectors.insert(block, sector.to_vec()).is_none() {
+                self.order.push_back(block);
+            }
+        }
+
+        while self.order.len() > self.capacity {
+            if let Some(block) = self.order.pop_front() {
+                self.sectors.remove(&block);
+            }
+        }
+    }
+
+    /// Drop the sectors from `block` on, for `len` bytes
+    pub fn invalidate(&mut self, block: u64, len: usize) {
+        let end = block + ((len + 511) / 512) as u64;
+        let sectors = &mut self.sectors;
+        self.order.retain(|&cached| if cached >= block && cached < end {
+            sectors.remove(&cached);
+            false
+        } else {
+            true
+        });
+    }
+
+    /// Reset the counters, the cached sectors are kept
+    pub fn reset(&mut self) {
+        self.hits = 0;
+        self.misses = 0;
+    }
+
+    /// Format the counters as text, in the same form as `DiskStats`
+    pub fn to_string(&self) -> String {
+        format!("cache_hits: {}\ncache_misses: {}\ncache_sectors: {}\n",
+                self.hits,
+                self.misses,
+                self.order.len())
+    }
+}
@@ -0,0 +1,125 @@
+use collections::string::String;
+use collections::vec::Vec;
+
+use core::{cmp, str};
+
+use system::error::{Error, Result, EINVAL};
+
+use super::Disk;
+
+/// The most partition entries read from a table, the usual 128
+const MAX_ENTRIES: usize = 128;
+
+/// A partition listed in a GUID partition table
+#[derive(Clone, Debug, PartialEq)]
+pub struct Partition {
+    /// The unique partition GUID, as stored on disk
+    pub guid: [u8; 16],
+    /// The partition name
+    pub label: String,
+}
+
+impl Partition {
+    /// Check if `guid` is the text form of this partition's GUID, in either case
+    pub fn has_guid(&self, guid: &str) -> bool {
+        parse_guid(guid).map_or(false, |guid| guid == self.guid)
+    }
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    (0..4).fold(0, |value, i| value | (data[offset + i] as u32) << (i * 8))
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u32_at(data, offset) as u64 | (u32_at(data, offset + 4) as u64) << 32
+}
+
+/// Parse the text form of a GUID, where the first three groups are stored little endian
+pub fn parse_guid(text: &str) -> Option<[u8; 16]> {
+    let groups: Vec<&str> = text.split('-').collect();
+    if groups.len() != 5 || groups.iter().zip([8, 4, 4, 4, 12].iter()).any(|(group, &len)| group.len() != len) {
+        return None;
+    }
+
+    let mut bytes = [0; 16];
+    let mut i = 0;
+    for (g, group) in groups.iter().enumerate() {
+        let mut group_bytes = Vec::new();
+        for pair in group.as_bytes().chunks(2) {
+            match str::from_utf8(pair).ok().and_then(|digits| u8::from_str_radix(digits, 16).ok()) {
+                Some(byte) => group_bytes.push(byte),
+                None => return None,
+            }
+        }
+        if g < 3 {
+            group_bytes.reverse();
+        }
+        for byte in group_bytes {
+            bytes[i] = byte;
+            i += 1;
+        }
+    }
+    Some(bytes)
+}
+
+/// The text form of a GUID in lower case
+pub fn format_guid(guid: &[u8; 16]) -> String {
+    format!("{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+            guid[3], guid[2], guid[1], guid[0], guid[5], guid[4], guid[7], guid[6],
+            guid[8], guid[9], guid[10], guid[11], guid[12], guid[13], guid[14], guid[15])
+}
+
+/// Where the entries of a table are, from its `header` sector: the first block, the count and the size of each
+pub fn entries_at(header: &[u8]) -> Result<(u64, usize, usize)> {
+    if header.len() < 92 || &header[.. 8] != b"EFI PART" {
+        return Err(Error::new(EINVAL));
+    }
+
+    let size = u32_at(header, 84) as usize;
+    if size < 128 || size % 8 != 0 {
+        return Err(Error::new(EINVAL));
+    }
+    Ok((u64_at(header, 72), cmp::min(u32_at(header, 80) as usize, MAX_ENTRIES), size))
+}
+
+/// Parse the partition `entries` of `size` bytes each, leaving out those that are unused
+pub fn parse_entries(entries: &[u8], size: usize) -> Vec<Partition> {
+    let mut partitions = Vec::new();
+    for entry in entries.chunks(size).filter(|entry| entry.len() == size) {
+        // An unused entry has no partition type
+        if entry[.. 16].iter().all(|&b| b == 0) {
+            continue;
+        }
+
+        let mut guid = [0; 16];
+        for (b, e) in guid.iter_mut().zip(entry[16 .. 32].iter()) {
+            *b = *e;
+        }
+
+        let name: Vec<u16> = entry[56 .. 128].chunks(2)
+                                             .map(|pair| pair[0] as u16 | (pair[1] as u16) << 8)
+                                             .take_while(|&c| c != 0)
+                                             .collect();
+        partitions.push(Partition {
+            guid: guid,
+            label: String::from_utf16_lossy(&name),
+        });
+    }
+    partitions
+}
+
+/// Read the partitions of a disk from its primary table
+///
+/// The checksums are not verified, a disk without a table fails with EINVAL.
+pub fn partitions(disk: &mut Disk) -> Result<Vec<Partition>> {
+    let mut header = vec![0; 512];
+    if try!(disk.read(1, &mut header)) < header.len() {
+        return Err(Error::new(EINVAL));
+    }
+    let (block, count, size) = try!(entries_at(&header));
+
+    let mut entries = vec![0; (count * size + 511) / 512 * 512];
+    let read = try!(disk.read(block, &mut entries));
+    entries.truncate(cmp::min(read, count * size));
+    Ok(parse_entries(&entries, size))
+}
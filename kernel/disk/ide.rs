@@ -1,18 +1,21 @@
 use alloc::boxed::Box;
 
-use collections::string::String;
+use collections::string::{String, ToString};
 use collections::vec::Vec;
 
 use core::ptr;
 
 use arch::memory::Memory;
 
-use disk::Disk;
+use common::time::Duration;
+
+use disk::{media_error, Disk, DiskStats, DISK_RETRIES};
+use disk::cache::{BlockCache, CACHE_SECTORS};
 
 use drivers::pci::config::PciConfig;
 use drivers::io::{Io, Pio, ReadOnly, WriteOnly};
 
-use system::error::{Error, Result, EIO};
+use system::error::{Error, Result, EIO, ENODATA};
 
 /// An disk extent
 #[derive(Copy, Clone)]
@@ -211,6 +214,10 @@ pub struct IdeDisk {
     irq: u8,
     master: bool,
     size: u64,
+    serial: String,
+    stats: DiskStats,
+    cache: BlockCache,
+    error_lba: u64,
 }
 
 impl IdeDisk {
@@ -232,10 +239,15 @@ impl IdeDisk {
             irq: irq,
             master: master,
             size: 0,
+            serial: String::new(),
+            stats: DiskStats::default(),
+            cache: BlockCache::new(CACHE_SECTORS),
+            error_lba: 0,
         };
 
-        if let Some(size) = unsafe { ret.identify() } {
+        if let Some((size, serial)) = unsafe { ret.identify() } {
             ret.size = size;
+            ret.serial = serial;
             Some(ret)
         } else {
             None
@@ -291,7 +303,7 @@ impl IdeDisk {
     }
 
     /// Identify
-    pub unsafe fn identify(&mut self) -> Option<u64> {
+    pub unsafe fn identify(&mut self) -> Option<(u64, String)> {
         let name = if self.master { "Master" } else { "Slave" };
 
         if self.alt_sts.read() == 0xFF {
@@ -372,7 +384,7 @@ impl IdeDisk {
         syslog_info!("     + {}: Serial: {} Firmware: {} Model: {} {}-bit LBA Size: {} MB",
                     name, serial.trim(), firmware.trim(), model.trim(), lba_bits, sectors / 2048);
 
-        Some(sectors * 512)
+        Some((sectors * 512, serial.trim().to_string()))
     }
 
     unsafe fn ata_pio_small(&mut self, block: u64, sectors: u16, mut buf: usize, write: bool) -> Result<usize> {
@@ -390,7 +402,12 @@ impl IdeDisk {
             for sector in 0..sectors as usize {
                 let err = self.ide_poll(true);
                 if err > 0 {
-                    debugln!("IDE Error: {:X}={:X}", err, self.error.read());
+                    let error = self.error.read();
+                    debugln!("IDE Error: {:X}={:X}", err, error);
+                    if err == 2 && error & (ATA_ER_BBK | ATA_ER_UNC | ATA_ER_IDNF | ATA_ER_AMNF) != 0 {
+                        self.error_lba = block + sector as u64;
+                        return Err(Error::new(ENODATA));
+                    }
                     return Err(Error::new(EIO));
                 }
 
@@ -447,6 +464,27 @@ impl IdeDisk {
         }
     }
 
+    fn request(&mut self, block: u64, sectors: usize, buf: usize, write: bool) -> Result<usize> {
+        let start = Duration::monotonic();
+
+        let mut result = self.ata_pio(block, sectors, buf, write);
+        let mut retries = 0;
+        while media_error(&result) && retries < DISK_RETRIES {
+            retries += 1;
+            result = self.ata_pio(block, sectors, buf, write);
+        }
+
+        self.stats.retries += retries as u64;
+        if media_error(&result) {
+            syslog_error!("{}: media error at {} after {} retries", self.name(), self.error_lba, retries);
+            let lba = self.error_lba;
+            self.stats.bad_sector(lba);
+        }
+        self.stats.record(write, start, &result);
+
+        result
+    }
+
     unsafe fn ata_dma_small(&mut self, block: u64, sectors: u16, mut buf: usize, write: bool) -> Result<usize> {
         if buf >= 0x80000000 {
             buf -= 0x80000000;
@@ -580,21 +618,44 @@ impl Disk for IdeDisk {
         })
     }
 
-    fn on_irq(&mut self, irq: u8) {
+    fn on_irq(&mut self, irq: u8) -> bool {
         if irq == self.irq {
             //debugln!("IDE IRQ");
         }
+        irq == self.irq
+    }
+
+    fn serial(&self) -> String {
+        self.serial.clone()
     }
 
     fn size(&self) -> u64 {
         self.size
     }
 
+    fn stats(&mut self) -> &mut DiskStats {
+        &mut self.stats
+    }
+
+    fn cache(&mut self) -> &mut BlockCache {
+        &mut self.cache
+    }
+
     fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
-        self.ata_pio(block, buffer.len() / 512, buffer.as_ptr() as usize, false)
+        self.request(block, buffer.len() / 512, buffer.as_ptr() as usize, false)
     }
 
     fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
-        self.ata_pio(block, buffer.len() / 512, buffer.as_ptr() as usize, true)
+        self.request(block, buffer.len() / 512, buffer.as_ptr() as usize, true)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.ata(ATA_CMD_CACHE_FLUSH, 0, 0);
+        unsafe { self.ide_poll(false) };
+        if self.alt_sts.readf(ATA_SR_ERR) {
+            Err(Error::new(EIO))
+        } else {
+            Ok(())
+        }
     }
 }
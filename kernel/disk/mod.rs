@@ -1,14 +1,237 @@
 use collections::string::String;
+use collections::vec::Vec;
 
-use system::error::Result;
+use common::time::Duration;
+
+use core::{cmp, result};
+
+use system::error::{Error, Result, EINVAL, ENODATA};
+
+use self::cache::BlockCache;
 
 pub mod ahci;
+pub mod cache;
+pub mod gpt;
 pub mod ide;
 
+/// Number of times a request that failed with a media error is reissued
+pub const DISK_RETRIES: usize = 3;
+
+/// Check if a request failed because of a media error, drivers report these as ENODATA
+pub fn media_error(result: &Result<usize>) -> bool {
+    match *result {
+        Err(ref err) => err.errno == ENODATA,
+        Ok(_) => false,
+    }
+}
+
+/// What one command of a controller can transfer
+#[derive(Copy, Clone)]
+pub struct Limits {
+    pub max_sectors: usize,
+    /// Physical regions in one command, the entries of its scatter-gather table
+    pub max_regions: usize,
+    /// Bytes in one region, a multiple of the sector size
+    pub max_region_len: usize,
+}
+
+/// One command of a split request, `sectors` from `block` to or from the physical `regions`
+pub struct Chunk {
+    pub block: u64,
+    pub sectors: usize,
+    /// The address and length of each region, in the order they are filled
+    pub regions: Vec<(usize, usize)>,
+}
+
+/// Split a request at `block` over the physical `regions` into commands within `limits`
+///
+/// Every region must be a whole number of sectors, so no sector needs two commands.
+pub fn chunks(block: u64, regions: &[(usize, usize)], limits: Limits) -> Result<Vec<Chunk>> {
+    let mut chunks: Vec<Chunk> = Vec::new();
+    let mut block = block;
+    for &(address, len) in regions.iter() {
+        if len % 512 != 0 {
+            return Err(Error::new(EINVAL));
+        }
+
+        let mut offset = 0;
+        while offset < len {
+            let full = match chunks.last() {
+                Some(chunk) => chunk.sectors >= limits.max_sectors || chunk.regions.len() >= limits.max_regions,
+                None => true,
+            };
+            if full {
+                chunks.push(Chunk {
+                    block: block,
+                    sectors: 0,
+                    regions: Vec::new(),
+                });
+            }
+
+            let chunk = chunks.last_mut().unwrap();
+            let size = cmp::min(cmp::min(len - offset, limits.max_region_len),
+                                (limits.max_sectors - chunk.sectors) * 512);
+            chunk.regions.push((address + offset, size));
+            chunk.sectors += size / 512;
+            block += (size / 512) as u64;
+            offset += size;
+        }
+    }
+    Ok(chunks)
+}
+
+/// The part of a split request that failed
+pub struct PartError {
+    /// The index of the chunk, and how many there were
+    pub part: usize,
+    pub parts: usize,
+    /// Bytes into the request the chunk starts at
+    pub offset: usize,
+    pub error: Error,
+}
+
+impl PartError {
+    pub fn new(chunks: &[Chunk], part: usize, error: Error) -> PartError {
+        PartError {
+            part: part,
+            parts: chunks.len(),
+            offset: chunks[.. part].iter().fold(0, |offset, chunk| offset + chunk.sectors * 512),
+            error: error,
+        }
+    }
+}
+
+/// The result of a split request, with the part that failed logged for `name`
+pub fn part_result(name: &str, result: result::Result<usize, PartError>) -> Result<usize> {
+    result.map_err(|part| {
+        syslog_error!("{}: part {} of {} failed at byte {}: {}", name, part.part + 1, part.parts, part.offset, part.error);
+        part.error
+    })
+}
+
+/// Per-disk I/O counters
+#[derive(Clone, Default)]
+pub struct DiskStats {
+    /// Completed read requests
+    pub reads: u64,
+    /// Completed write requests
+    pub writes: u64,
+    /// Bytes read
+    pub read_bytes: u64,
+    /// Bytes written
+    pub write_bytes: u64,
+    /// Failed read requests
+    pub read_errors: u64,
+    /// Failed write requests
+    pub write_errors: u64,
+    /// Cumulative read latency in nanoseconds
+    pub read_nanos: u64,
+    /// Cumulative write latency in nanoseconds
+    pub write_nanos: u64,
+    /// Requests reissued after a media error
+    pub retries: u64,
+    /// Blocks that kept failing after all retries
+    pub bad_sectors: Vec<u64>,
+}
+
+impl DiskStats {
+    /// Record the outcome of a request that started at `start`
+    pub fn record(&mut self, write: bool, start: Duration, result: &Result<usize>) {
+        let elapsed = Duration::monotonic() - start;
+        let nanos = elapsed.secs as u64 * 1000000000 + elapsed.nanos as u64;
+
+        if write {
+            self.write_nanos += nanos;
+            match *result {
+                Ok(count) => {
+                    self.writes += 1;
+                    self.write_bytes += count as u64;
+                },
+                Err(_) => self.write_errors += 1,
+            }
+        } else {
+            self.read_nanos += nanos;
+            match *result {
+                Ok(count) => {
+                    self.reads += 1;
+                    self.read_bytes += count as u64;
+                },
+                Err(_) => self.read_errors += 1,
+            }
+        }
+    }
+
+    /// Remember a block that could not be accessed
+    pub fn bad_sector(&mut self, block: u64) {
+        if ! self.bad_sectors.contains(&block) {
+            self.bad_sectors.push(block);
+        }
+    }
+
+    /// Reset all counters, the bad sector list is kept
+    pub fn reset(&mut self) {
+        let bad_sectors = self.bad_sectors.clone();
+        *self = DiskStats::default();
+        self.bad_sectors = bad_sectors;
+    }
+
+    /// Format the counters as text
+    pub fn to_string(&self) -> String {
+        format!("reads: {}\nwrites: {}\nread_bytes: {}\nwrite_bytes: {}\nread_errors: {}\nwrite_errors: {}\nread_latency_ns: {}\nwrite_latency_ns: {}\nretries: {}\nbad_sectors: {}\n",
+                self.reads,
+                self.writes,
+                self.read_bytes,
+                self.write_bytes,
+                self.read_errors,
+                self.write_errors,
+                self.read_nanos,
+                self.write_nanos,
+                self.retries,
+                self.bad_sectors.len())
+    }
+}
+
 pub trait Disk {
     fn name(&self) -> String;
-    fn on_irq(&mut self, irq: u8);
+    fn on_irq(&mut self, irq: u8) -> bool;
+    /// The serial number reported by IDENTIFY, may be empty
+    fn serial(&self) -> String;
     fn size(&self) -> u64;
+    fn stats(&mut self) -> &mut DiskStats;
+    /// The sectors kept for reads through the disk scheme
+    fn cache(&mut self) -> &mut BlockCache;
     fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize>;
     fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize>;
+
+    /// Read from `block` into each of `segments` in turn, stopping at a short read
+    ///
+    /// Drivers with scatter-gather DMA fill every segment from the same commands.
+    fn read_segments(&mut self, block: u64, segments: &mut [&mut [u8]]) -> Result<usize> {
+        let mut count = 0;
+        for segment in segments.iter_mut() {
+            let len = segment.len();
+            let read = try!(self.read(block + (count / 512) as u64, segment));
+            count += read;
+            if read < len {
+                break;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Write each of `segments` in turn from `block`, stopping at a short write
+    fn write_segments(&mut self, block: u64, segments: &[&[u8]]) -> Result<usize> {
+        let mut count = 0;
+        for segment in segments.iter() {
+            let written = try!(self.write(block + (count / 512) as u64, segment));
+            count += written;
+            if written < segment.len() {
+                break;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Write what the disk has in its volatile cache to the medium
+    fn flush(&mut self) -> Result<()>;
 }
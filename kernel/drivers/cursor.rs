@@ -0,0 +1,25 @@
+use core::cmp;
+
+use graphics::display::VBEMODEINFO;
+
+/// The cursor position, shared by every pointing device that reports movement
+static mut CURSOR_X: i32 = 0;
+static mut CURSOR_Y: i32 = 0;
+
+/// Move the cursor by `dx`, `dy`, keeping it on the screen, and return where it is
+pub fn cursor_move(dx: i32, dy: i32) -> (i32, i32) {
+    unsafe {
+        if let Some(mode_info) = VBEMODEINFO {
+            CURSOR_X = cmp::max(0, cmp::min(mode_info.xresolution as i32, CURSOR_X + dx));
+            CURSOR_Y = cmp::max(0, cmp::min(mode_info.yresolution as i32, CURSOR_Y + dy));
+        }
+        (CURSOR_X, CURSOR_Y)
+    }
+}
+
+/// Put the cursor at `x`, `y`, keeping it on the screen, and return where it is
+pub fn cursor_set(x: i32, y: i32) -> (i32, i32) {
+    unsafe {
+        cursor_move(x - CURSOR_X, y - CURSOR_Y)
+    }
+}
@@ -0,0 +1,123 @@
+use alloc::boxed::Box;
+
+use common::to_num::ToNum;
+
+use core::{cmp, str};
+
+use drivers::io::{Io, Pio};
+
+use fs::{KScheme, Resource};
+
+use system::error::{Error, Result, EINVAL, ENOENT, EPERM};
+
+/// Set once the debug console answered
+static mut PRESENT: bool = false;
+
+/// A port given at build time in hex, like `DEBUGCON_PORT=402`
+fn build_port(port: Option<&'static str>, default: u16) -> u16 {
+    port.map_or(default, |port| port.to_num_radix(16) as u16)
+}
+
+/// The port of the debug console, the `iobase` of QEMU's isa-debugcon
+fn port() -> u16 {
+    build_port(option_env!("DEBUGCON_PORT"), 0xE9)
+}
+
+/// The port of QEMU's isa-debug-exit, which ends the emulator when written
+fn exit_port() -> u16 {
+    build_port(option_env!("DEBUG_EXIT_PORT"), 0xF4)
+}
+
+/// Look for the debug console of Bochs or QEMU, which reads back as 0xE9
+pub fn init() {
+    unsafe { PRESENT = Pio::<u8>::new(port()).read() == 0xE9 };
+}
+
+/// Check if the debug console was found
+pub fn present() -> bool {
+    unsafe { PRESENT }
+}
+
+/// Write to the debug console, if there is one
+pub fn write(bytes: &[u8]) {
+    if present() {
+        let mut port = Pio::<u8>::new(port());
+        for &byte in bytes.iter() {
+            port.write(byte);
+        }
+    }
+}
+
+/// End QEMU, which exits with the status `code * 2 + 1`
+///
+/// Only an emulator that has the debug console is written to, on hardware the port could be anything.
+/// Returns if there is no exit device.
+pub fn exit(code: u8) {
+    if present() {
+        Pio::<u8>::new(exit_port()).write(code);
+    }
+}
+
+/// What is written to `debugcon:` goes to the debug console, and the exit code written to
+/// `debugcon:exit` ends the emulator
+pub struct DebugconResource {
+    exit: bool,
+}
+
+impl Resource for DebugconResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box DebugconResource {
+            exit: self.exit,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path: &[u8] = if self.exit { b"debugcon:exit" } else { b"debugcon:" };
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.exit {
+            let code = str::from_utf8(buf).unwrap_or("").trim();
+            if code.is_empty() || ! code.chars().all(|c| c.is_digit(10)) || code.to_num() > 255 {
+                return Err(Error::new(EINVAL));
+            }
+            syslog_info!("Exiting the emulator with code {}", code);
+            exit(code.to_num() as u8);
+        } else {
+            write(buf);
+        }
+        Ok(buf.len())
+    }
+}
+
+/// The debug console scheme, registered when the emulator has one
+pub struct DebugconScheme;
+
+impl KScheme for DebugconScheme {
+    fn scheme(&self) -> &str {
+        "debugcon"
+    }
+
+    /// Only a process with I/O privileges may end the emulator
+    fn open(&mut self, url: &str, _flags: usize) -> Result<Box<Resource>> {
+        let exit = match url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/') {
+            "" => false,
+            "exit" => true,
+            _ => return Err(Error::new(ENOENT)),
+        };
+
+        if exit && try!(unsafe { & *::env().contexts.get() }.current()).iopl != 3 {
+            return Err(Error::new(EPERM));
+        }
+
+        Ok(box DebugconResource {
+            exit: exit,
+        })
+    }
+}
@@ -3,12 +3,56 @@
 /// *   English
 /// *   French
 /// *   German
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Layout {
     English,
     French,
     German,
 }
 
+/// Every layout, in the order of the numbers `LayoutRequest` gives them
+pub const LAYOUTS: [Layout; 3] = [Layout::English, Layout::French, Layout::German];
+
+impl Layout {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Layout::English => "english",
+            Layout::French => "french",
+            Layout::German => "german",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Layout> {
+        LAYOUTS.iter().find(|layout| layout.name() == name).map(|&layout| layout)
+    }
+}
+
+/// Whether `character` is a dead key of `layout`, which changes the next letter instead of being typed
+pub fn is_dead_key(layout: &Layout, character: char) -> bool {
+    match *layout {
+        Layout::English => false,
+        Layout::French => character == '^' || character == '¨',
+        Layout::German => character == '^' || character == '`',
+    }
+}
+
+/// What the dead key `dead` followed by `character` types, `None` if they do not combine
+///
+/// A space or the dead key again types the dead key itself.
+pub fn compose(dead: char, character: char) -> Option<char> {
+    if character == ' ' || character == dead {
+        return Some(dead);
+    }
+
+    let (from, to) = match dead {
+        '^' => ("aeiouAEIOU", "âêîôûÂÊÎÔÛ"),
+        '¨' => ("aeiouyAEIOU", "äëïöüÿÄËÏÖÜ"),
+        '`' => ("aeiouAEIOU", "àèìòùÀÈÌÒÙ"),
+        _ => return None,
+    };
+    from.chars().position(|c| c == character).and_then(|i| to.chars().nth(i))
+}
+
 /// Function to get the scancode from the current layout
 ///
 /// # Example
@@ -39,8 +83,8 @@ fn get_special_keys_from_layout(layout: &Layout, scancode: u8) -> [char; 3] {
 }
 
 
-/// Function to return the character associated with the scancode, and the layout
-pub fn char_for_scancode(scancode: u8, shift: bool, altgr: bool, layout: &Layout) -> char {
+/// Function to return the character associated with the scancode, and the layout, `None` if there is none
+pub fn char_for_scancode(scancode: u8, shift: bool, altgr: bool, layout: &Layout) -> Option<char> {
     let character;
 
     let characters = if scancode < 58 {
@@ -56,8 +100,12 @@ pub fn char_for_scancode(scancode: u8, shift: bool, altgr: bool, layout: &Layout
     } else {
         character = characters[0];
     }
-    
-    character
+
+    if character == '\0' {
+        None
+    } else {
+        Some(character)
+    }
 }
 
 // SCANCODES
@@ -1,3 +1,7 @@
+/// Cursor
+pub mod cursor;
+/// Bochs and QEMU debug console
+pub mod debugcon;
 /// IO primitives
 pub mod io;
 /// PCI
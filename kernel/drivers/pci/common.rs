@@ -98,7 +98,12 @@ pub mod programming_interface {
     pub const XHCI: u8 = 0x30;
 }
 
+pub mod capability {
+    pub const POWER_MANAGEMENT: u8 = 0x01;
+}
+
 pub mod vendorid {
+    pub const AMD: u16 = 0x1022;
     pub const INTEL: u16 = 0x8086;
     pub const REALTEK: u16 = 0x10EC;
     pub const REDHAT: u16 = 0x1AF4;
@@ -106,7 +111,11 @@ pub mod vendorid {
 }
 
 pub mod deviceid {
+    // AMD
+    pub const PCNET32: u16 = 0x2000;        // 79c970 PCnet32 LANCE
+
     // Realtek
+    pub const RTL8029: u16 = 0x8029;        // RTL-8029(AS), NE2000 compatible
     pub const RTL8139: u16 = 0x8139;        // RTL-8100/8101L/8139 PCI Fast Ethernet Adapter
 
     // Intel
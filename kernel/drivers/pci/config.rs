@@ -1,5 +1,85 @@
 use drivers::io::{Io, Pio};
 
+use env::irq;
+
+use super::common::capability::POWER_MANAGEMENT;
+use super::common::class::BRIDGE_DEVICE;
+use super::common::config::{PCI_CFG_BAR_1, PCI_CFG_CAPABILITIES_PTR, PCI_CFG_COMMAND, PCI_CFG_HEADER_TYPE,
+                            PCI_CFG_INTERRUPT_LINE};
+
+/// Power management capabilities, in the high half of the capability header: PME# from D3hot
+const PMC_PME_D3HOT: u32 = 1 << 30;
+/// Power management control and status
+const PMCSR_POWER_STATE: u32 = 0b11;
+const PMCSR_PME_EN: u32 = 1 << 8;
+const PMCSR_PME_STATUS: u32 = 1 << 15;
+
+/// The command register bits that make the device decode its I/O and memory BARs
+const COMMAND_DECODE: u32 = 0b11;
+
+/// The bus numbers of a PCI to PCI or CardBus bridge, the secondary bus in the second byte
+const BRIDGE_BUS_NUMBERS: u8 = 0x18;
+/// The header types of PCI to PCI and CardBus bridges
+const HEADER_BRIDGE: u32 = 1;
+const HEADER_CARDBUS: u32 = 2;
+
+/// The bus and slot of the bridge whose secondary bus is `bus`, among the functions already found
+///
+/// The buses are scanned in order, so a bridge is always found before the buses behind it.
+unsafe fn bridge_above(bus: u8) -> Option<(u8, u8)> {
+    if bus == 0 {
+        return None;
+    }
+
+    for function in (& *::env().pci.get()).iter() {
+        if function.class_id == BRIDGE_DEVICE {
+            let mut bridge = PciConfig::new(function.bus, function.slot, function.func);
+            let header_type = (bridge.read(PCI_CFG_HEADER_TYPE) >> 16) & 0x7F;
+            if (header_type == HEADER_BRIDGE || header_type == HEADER_CARDBUS) &&
+               (bridge.read(BRIDGE_BUS_NUMBERS) >> 8) as u8 == bus {
+                return Some((function.bus, function.slot));
+            }
+        }
+    }
+
+    None
+}
+
+/// What a base address register maps
+#[derive(Copy, Clone, PartialEq)]
+pub enum PciBarKind {
+    /// Nothing, or the upper half of the 64-bit BAR before it
+    None,
+    Port,
+    Memory,
+    /// Memory that may be anywhere in 64-bit space, taking this BAR and the next
+    Memory64,
+}
+
+/// A decoded base address register
+#[derive(Copy, Clone)]
+pub struct PciBar {
+    pub kind: PciBarKind,
+    pub base: usize,
+    /// The bytes, or ports, decoded from `base`
+    pub size: usize,
+}
+
+impl PciBar {
+    pub fn none() -> PciBar {
+        PciBar {
+            kind: PciBarKind::None,
+            base: 0,
+            size: 0,
+        }
+    }
+
+    /// Memory, 32 or 64-bit
+    pub fn is_memory(&self) -> bool {
+        self.kind == PciBarKind::Memory || self.kind == PciBarKind::Memory64
+    }
+}
+
 /// A PCI configuration
 #[derive(Copy, Clone)]
 pub struct PciConfig {
@@ -8,6 +88,7 @@ pub struct PciConfig {
     func: u8,
     addr: Pio<u32>,
     data: Pio<u32>,
+    bars: [PciBar; 6],
 }
 
 impl PciConfig {
@@ -19,9 +100,78 @@ impl PciConfig {
             func: func,
             addr: Pio::<u32>::new(0xCF8),
             data: Pio::<u32>::new(0xCFC),
+            bars: [PciBar::none(); 6],
         }
     }
 
+    /// The BARs, as `probe_bars` found them
+    pub fn bars(&self) -> [PciBar; 6] {
+        self.bars
+    }
+
+    /// BAR `i`, as `probe_bars` found it
+    pub fn bar(&self, i: usize) -> PciBar {
+        self.bars.get(i).map_or(PciBar::none(), |bar| *bar)
+    }
+
+    /// Decode the BARs, sizing each by writing ones to it
+    ///
+    /// The device stops decoding while they are written, so this is done once, at enumeration.
+    pub unsafe fn probe_bars(&mut self) {
+        // The status in the high half is cleared by writing ones, so it is written as zero
+        let command = self.read(PCI_CFG_COMMAND) & 0xFFFF;
+        self.write(PCI_CFG_COMMAND, command & ! COMMAND_DECODE);
+
+        // A bridge has two BARs before its bus numbers, a CardBus bridge none
+        let count = match (self.read(PCI_CFG_HEADER_TYPE) >> 16) & 0x7F {
+            0 => 6,
+            1 => 2,
+            _ => 0,
+        };
+
+        let mut i = 0;
+        while i < count {
+            let offset = PCI_CFG_BAR_1 + i as u8 * 4;
+            let bar = self.read(offset);
+            self.write(offset, 0xFFFFFFFF);
+            let mask = self.read(offset);
+            self.write(offset, bar);
+
+            if bar & 1 == 1 {
+                self.bars[i] = PciBar {
+                    kind: PciBarKind::Port,
+                    base: (bar & 0xFFFFFFFC) as usize,
+                    size: ((! (mask & 0xFFFFFFFC) & 0xFFFF) + 1) as usize,
+                };
+            } else if bar & 0b110 == 0b100 && i + 1 < count {
+                let high = self.read(offset + 4);
+                self.write(offset + 4, 0xFFFFFFFF);
+                let mask_high = self.read(offset + 4);
+                self.write(offset + 4, high);
+
+                let base = (high as u64) << 32 | (bar & 0xFFFFFFF0) as u64;
+                let mask = (mask_high as u64) << 32 | (mask & 0xFFFFFFF0) as u64;
+                if mask != 0 {
+                    self.bars[i] = PciBar {
+                        kind: PciBarKind::Memory64,
+                        base: base as usize,
+                        size: (! mask).wrapping_add(1) as usize,
+                    };
+                }
+                i += 1;
+            } else if mask & 0xFFFFFFF0 != 0 {
+                self.bars[i] = PciBar {
+                    kind: PciBarKind::Memory,
+                    base: (bar & 0xFFFFFFF0) as usize,
+                    size: (! (mask & 0xFFFFFFF0)).wrapping_add(1) as usize,
+                };
+            }
+            i += 1;
+        }
+
+        self.write(PCI_CFG_COMMAND, command);
+    }
+
     fn address(&self, offset: u8) -> u32 {
         return 1 << 31 | (self.bus as u32) << 16 | (self.slot as u32) << 11 |
                (self.func as u32) << 8 | (offset as u32 & 0xFC);
@@ -51,5 +201,82 @@ impl PciConfig {
         self.write(offset, value);
     }
 
+    /// The IRQ line the driver of the function is given its interrupts on, routed from its interrupt pin
+    ///
+    /// A function without an interrupt pin is given what its interrupt line register holds.
+    pub unsafe fn irq(&mut self) -> u8 {
+        let interrupt = self.read(PCI_CFG_INTERRUPT_LINE);
+        let firmware_irq = interrupt as u8;
+        let pin = (interrupt >> 8) as u8;
+        if pin == 0 || pin > 4 {
+            return firmware_irq & 0xF;
+        }
+
+        // A bridge pointing back at a bus below it would loop
+        let mut path = vec![(self.bus, self.slot)];
+        while path.len() < 256 {
+            match bridge_above(path[path.len() - 1].0) {
+                Some(bridge) => path.push(bridge),
+                None => break,
+            }
+        }
+
+        irq::pci_line(&path, pin - 1, firmware_irq)
+    }
+
+    /// Find a capability by its ID, returning its offset
+    pub unsafe fn capability(&mut self, id: u8) -> Option<u8> {
+        // The status register, in the high half, says if there is a capability list
+        if self.read(PCI_CFG_COMMAND) & 1 << 20 == 0 {
+            return None;
+        }
+
+        let mut offset = (self.read(PCI_CFG_CAPABILITIES_PTR) & 0xFC) as u8;
+        // A malformed list could loop, there is only room for 48 capabilities
+        for _ in 0..48 {
+            if offset == 0 {
+                break;
+            }
+
+            let header = self.read(offset);
+            if header as u8 == id {
+                return Some(offset);
+            }
+            offset = ((header >> 8) & 0xFC) as u8;
+        }
+
+        None
+    }
+
+    /// Check if the device can assert PME# from D3hot
+    pub unsafe fn pme_supported(&mut self) -> bool {
+        match self.capability(POWER_MANAGEMENT) {
+            Some(pm) => self.read(pm) & PMC_PME_D3HOT == PMC_PME_D3HOT,
+            None => false,
+        }
+    }
+
+    /// Enable or disable PME#, clearing any pending PME
+    pub unsafe fn set_pme(&mut self, enable: bool) {
+        if let Some(pm) = self.capability(POWER_MANAGEMENT) {
+            let mut pmcsr = self.read(pm + 4) | PMCSR_PME_STATUS;
+            if enable {
+                pmcsr |= PMCSR_PME_EN;
+            } else {
+                pmcsr &= ! PMCSR_PME_EN;
+            }
+            self.write(pm + 4, pmcsr);
+        }
+    }
+
+    /// Change the power state, from 0 for D0 to 3 for D3hot
+    pub unsafe fn set_power_state(&mut self, state: u8) {
+        if let Some(pm) = self.capability(POWER_MANAGEMENT) {
+            // Leave the PME status as it is
+            let pmcsr = self.read(pm + 4) & ! (PMCSR_PME_STATUS | PMCSR_POWER_STATE);
+            self.write(pm + 4, pmcsr | (state as u32 & PMCSR_POWER_STATE));
+        }
+    }
+
     // TODO: Write functions to get data structures
 }
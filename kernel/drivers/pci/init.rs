@@ -6,7 +6,7 @@ use disk::ide::Ide;
 
 use env::Environment;
 
-use super::config::PciConfig;
+use super::config::{PciBar, PciConfig};
 use super::common::class::*;
 use super::common::subclass::*;
 use super::common::programming_interface::*;
@@ -16,15 +16,43 @@ use super::common::deviceid::*;
 
 use audio::ac97::Ac97;
 use audio::intelhda::IntelHda;
+use audio::scheme::AudioScheme;
 
+use network::ne2000::Ne2000;
+use network::pcnet32::Pcnet32;
 use network::rtl8139::Rtl8139;
 use network::intel8254x::Intel8254x;
+use network::scheme::NetworkScheme;
 
 use usb::uhci::Uhci;
 use usb::ohci::Ohci;
 use usb::ehci::Ehci;
 use usb::xhci::Xhci;
 
+/// What became of a PCI function when it was enumerated
+pub enum PciOutcome {
+    /// The driver took it
+    Bound(&'static str),
+    /// The driver matched but could not use it, and why
+    Failed(&'static str, &'static str),
+    /// No driver matched its class or ID
+    Unmatched,
+}
+
+/// A PCI function found at boot
+pub struct PciFunction {
+    pub bus: u8,
+    pub slot: u8,
+    pub func: u8,
+    pub class_id: u8,
+    pub subclass_id: u8,
+    pub interface_id: u8,
+    pub vendor_code: u16,
+    pub device_code: u16,
+    pub bars: [PciBar; 6],
+    pub outcome: PciOutcome,
+}
+
 /// PCI device
 pub unsafe fn pci_device(env: &mut Environment,
                          pci: PciConfig,
@@ -32,25 +60,76 @@ pub unsafe fn pci_device(env: &mut Environment,
                          subclass_id: u8,
                          interface_id: u8,
                          vendor_code: u16,
-                         device_code: u16) {
+                         device_code: u16) -> PciOutcome {
     match (class_id, subclass_id, interface_id) {
-        (MASS_STORAGE, IDE, _) => for disk in Ide::disks(pci) {
-            (&mut *env.disks.get()).push(Arc::new(UnsafeCell::new(disk)));
+        (MASS_STORAGE, IDE, _) => {
+            let disks = Ide::disks(pci);
+            if disks.is_empty() {
+                return PciOutcome::Failed("ide", "no disks");
+            }
+            for disk in disks {
+                (&mut *env.disks.get()).push(Some(Arc::new(UnsafeCell::new(disk))));
+            }
+            PciOutcome::Bound("ide")
         },
-        (MASS_STORAGE, SATA, AHCI) => for disk in Ahci::disks(pci) {
-            (&mut *env.disks.get()).push(Arc::new(UnsafeCell::new(disk)));
+        (MASS_STORAGE, SATA, AHCI) => {
+            if ! pci.bar(5).is_memory() {
+                return PciOutcome::Failed("ahci", "no memory BAR");
+            }
+            env.schemes.register(Ahci::new(pci));
+            PciOutcome::Bound("ahci")
+        },
+        (SERIAL_BUS, USB, UHCI) => {
+            env.schemes.register(Uhci::new(pci));
+            PciOutcome::Bound("uhci")
+        },
+        (SERIAL_BUS, USB, OHCI) => {
+            env.schemes.register(Ohci::new(pci));
+            PciOutcome::Bound("ohci")
+        },
+        (SERIAL_BUS, USB, EHCI) => {
+            env.schemes.register(Ehci::new(pci));
+            PciOutcome::Bound("ehci")
+        },
+        (SERIAL_BUS, USB, XHCI) => {
+            if ! pci.bar(0).is_memory() {
+                return PciOutcome::Failed("xhci", "no memory BAR");
+            }
+            env.schemes.register(Xhci::new(pci));
+            PciOutcome::Bound("xhci")
         },
-        (SERIAL_BUS, USB, UHCI) => (&mut *env.schemes.get()).push(Uhci::new(pci)),
-        (SERIAL_BUS, USB, OHCI) => (&mut *env.schemes.get()).push(Ohci::new(pci)),
-        (SERIAL_BUS, USB, EHCI) => (&mut *env.schemes.get()).push(Ehci::new(pci)),
-        (SERIAL_BUS, USB, XHCI) => (&mut *env.schemes.get()).push(Xhci::new(pci)),
         _ => match (vendor_code, device_code) {
-            (REALTEK, RTL8139) => (&mut *env.schemes.get()).push(Rtl8139::new(pci)),
-            (INTEL, GBE_82540EM) => (&mut *env.schemes.get()).push(Intel8254x::new(pci)),
-            (INTEL, AC97_82801AA) => (&mut *env.schemes.get()).push(Ac97::new(pci)),
-            (INTEL, AC97_ICH4) => (&mut *env.schemes.get()).push(Ac97::new(pci)),
-            (INTEL, INTELHDA_ICH6) => (&mut *env.schemes.get()).push(IntelHda::new(pci)),
-            _ => syslog_info!(" ? CLASS {:02X}.{:02X}.{:02X} ID {:04X}:{:04X}", class_id, subclass_id, interface_id, vendor_code, device_code),
+            (AMD, PCNET32) => {
+                env.schemes.register(NetworkScheme::new(Pcnet32::new(pci)));
+                PciOutcome::Bound("pcnet32")
+            },
+            (REALTEK, RTL8029) => {
+                env.schemes.register(NetworkScheme::new(Ne2000::new(pci)));
+                PciOutcome::Bound("ne2000")
+            },
+            (REALTEK, RTL8139) => {
+                env.schemes.register(NetworkScheme::new(Rtl8139::new(pci)));
+                PciOutcome::Bound("rtl8139")
+            },
+            (INTEL, GBE_82540EM) => {
+                env.schemes.register(NetworkScheme::new(Intel8254x::new(pci)));
+                PciOutcome::Bound("intel8254x")
+            },
+            (INTEL, AC97_82801AA) | (INTEL, AC97_ICH4) => {
+                env.schemes.register(AudioScheme::new(Ac97::new(pci)));
+                PciOutcome::Bound("ac97")
+            },
+            (INTEL, INTELHDA_ICH6) => {
+                if ! pci.bar(0).is_memory() {
+                    return PciOutcome::Failed("intelhda", "no memory BAR");
+                }
+                env.schemes.register(AudioScheme::new(IntelHda::new(pci)));
+                PciOutcome::Bound("intelhda")
+            },
+            _ => {
+                syslog_info!(" ? CLASS {:02X}.{:02X}.{:02X} ID {:04X}:{:04X}", class_id, subclass_id, interface_id, vendor_code, device_code);
+                PciOutcome::Unmatched
+            },
         }
     }
 }
@@ -66,39 +145,30 @@ pub unsafe fn pci_init(env: &mut Environment) {
                 if (id & 0xFFFF) != 0xFFFF {
                     let class_id = pci.read(8);
 
-                    /*
-                    debug!(" * PCI {}, {}, {}: ID {:X} CL {:X}",
-                           bus,
-                           slot,
-                           func,
-                           id,
-                           class_id);
-
-                    for i in 0..6 {
-                        let bar = pci.read(i * 4 + 0x10);
-                        if bar > 0 {
-                            debug!(" BAR{}: {:X}", i, bar);
-
-                            pci.write(i * 4 + 0x10, 0xFFFFFFFF);
-                            let size = (0xFFFFFFFF - (pci.read(i * 4 + 0x10) & 0xFFFFFFF0)) + 1;
-                            pci.write(i * 4 + 0x10, bar);
-
-                            if size > 0 {
-                                debug!(" {}", size);
-                            }
-                        }
-                    }
-
-                    debugln!("");
-                    */
-
-                    pci_device(env,
-                               pci,
-                               ((class_id >> 24) & 0xFF) as u8,
-                               ((class_id >> 16) & 0xFF) as u8,
-                               ((class_id >> 8) & 0xFF) as u8,
-                               (id & 0xFFFF) as u16,
-                               ((id >> 16) & 0xFFFF) as u16);
+                    pci.probe_bars();
+
+                    let mut function = PciFunction {
+                        bus: bus as u8,
+                        slot: slot as u8,
+                        func: func as u8,
+                        class_id: ((class_id >> 24) & 0xFF) as u8,
+                        subclass_id: ((class_id >> 16) & 0xFF) as u8,
+                        interface_id: ((class_id >> 8) & 0xFF) as u8,
+                        vendor_code: (id & 0xFFFF) as u16,
+                        device_code: ((id >> 16) & 0xFFFF) as u16,
+                        bars: pci.bars(),
+                        outcome: PciOutcome::Unmatched,
+                    };
+
+                    function.outcome = pci_device(env,
+                                                  pci,
+                                                  function.class_id,
+                                                  function.subclass_id,
+                                                  function.interface_id,
+                                                  function.vendor_code,
+                                                  function.device_code);
+
+                    (&mut *env.pci.get()).push(function);
                 }
             }
         }
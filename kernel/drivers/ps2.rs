@@ -1,30 +1,131 @@
 use alloc::boxed::Box;
 
+use arch::context::Context;
+use arch::timekeeping;
+
+use audio::scheme::media_key;
+
 use collections::String;
 
-use core::cmp;
+use common::event::{KeyEvent, MouseEvent, ScrollEvent, K_MUTE, K_VOLDOWN, K_VOLUP};
+use common::time::{self, Duration};
 
-use common::event::{KeyEvent, MouseEvent};
+use core::cmp;
 
+use drivers::cursor::cursor_move;
 use drivers::io::{Io, Pio, ReadOnly, WriteOnly};
 
-use graphics::display::VBEMODEINFO;
+use env::focus;
+use env::log::InterruptGuard;
 
 use fs::KScheme;
 
 use drivers::kb_layouts::layouts;
 
+use schemes::keyboard::{self, KeyRepeat, LED_CAPS, LED_NUM, LED_SCROLL};
+
+/// The controller, for the LEDs to be set from outside its interrupt
+static mut PS2: *mut Ps2 = 0 as *mut Ps2;
+
+/// Status reads to wait for the keyboard to answer a command before giving up
+const ACK_SPINS: usize = 100000;
+/// Status reads to wait for the self test after a reset, which takes much longer
+const RESET_SPINS: usize = 2000000;
+/// Status reads to wait for the controller to take or give a byte
+const CONTROLLER_SPINS: usize = 100000;
+/// Times a command is sent again when the keyboard asks for it
+const RESENDS: usize = 3;
+
+/// The controller configuration: the interrupts of the ports, the system flag and translation to set 1
+const CONFIG_IRQ_KEYBOARD: u8 = 1;
+const CONFIG_IRQ_MOUSE: u8 = 1 << 1;
+const CONFIG_SYSTEM: u8 = 1 << 2;
+const CONFIG_TRANSLATE: u8 = 1 << 6;
+
+/// Scancode set 2 to set 1, as the controller translates, for controllers that cannot
+static SET2_TO_SET1: [u8; 128] = [
+    0xFF, 0x43, 0x41, 0x3F, 0x3D, 0x3B, 0x3C, 0x58, 0x64, 0x44, 0x42, 0x40, 0x3E, 0x0F, 0x29, 0x59,
+    0x65, 0x38, 0x2A, 0x70, 0x1D, 0x10, 0x02, 0x5A, 0x66, 0x71, 0x2C, 0x1F, 0x1E, 0x11, 0x03, 0x5B,
+    0x67, 0x2E, 0x2D, 0x20, 0x12, 0x05, 0x04, 0x5C, 0x68, 0x39, 0x2F, 0x21, 0x14, 0x13, 0x06, 0x5D,
+    0x69, 0x31, 0x30, 0x23, 0x22, 0x15, 0x07, 0x5E, 0x6A, 0x72, 0x32, 0x24, 0x16, 0x08, 0x09, 0x5F,
+    0x6B, 0x33, 0x25, 0x17, 0x18, 0x0B, 0x0A, 0x60, 0x6C, 0x34, 0x35, 0x26, 0x27, 0x19, 0x0C, 0x61,
+    0x6D, 0x73, 0x28, 0x74, 0x1A, 0x0D, 0x62, 0x6E, 0x3A, 0x36, 0x1C, 0x1B, 0x75, 0x2B, 0x63, 0x76,
+    0x55, 0x56, 0x77, 0x78, 0x79, 0x7A, 0x0E, 0x7B, 0x7C, 0x4F, 0x7D, 0x4B, 0x47, 0x7E, 0x7F, 0x6F,
+    0x52, 0x53, 0x50, 0x4C, 0x4D, 0x48, 0x01, 0x45, 0x57, 0x4E, 0x51, 0x4A, 0x37, 0x49, 0x46, 0x54,
+];
+
 pub struct Ps2Keyboard<'a> {
     bus: &'a mut Ps2
 }
 
 impl<'a> Ps2Keyboard<'a> {
-    //TODO: Use result
-    fn cmd(&mut self, command: u8) -> u8 {
-        self.bus.wait_write();
-        self.bus.data.write(command);
-        self.bus.wait_read();
-        self.bus.data.read()
+    /// Wait for a byte from the keyboard, for at most `spins` reads of the status
+    ///
+    /// This waits by reading the status, the clock does not move while it runs in the interrupt.
+    fn receive(&mut self, spins: usize) -> Option<u8> {
+        for _ in 0..spins {
+            let status = self.bus.sts.read();
+            if status & 0x21 == 0x01 {
+                return Some(self.bus.data.read());
+            } else if status & 0x21 == 0x21 {
+                // A byte of the mouse is lost, its packets get back in step by the gap after
+                self.bus.data.read();
+            }
+        }
+        None
+    }
+
+    /// Send a byte until the keyboard acknowledges it, returning false if it never does
+    fn send(&mut self, byte: u8) -> bool {
+        for _ in 0..RESENDS {
+            self.bus.wait_write();
+            self.bus.data.write(byte);
+
+            match self.receive(ACK_SPINS) {
+                Some(0xFA) => return true,
+                Some(0xFE) => continue,
+                _ => return false,
+            }
+        }
+        false
+    }
+
+    /// Reset the keyboard, returning whether it passed its self test
+    fn reset(&mut self) -> bool {
+        self.send(0xFF) && self.receive(RESET_SPINS) == Some(0xAA)
+    }
+
+    /// The ID bytes, none for an old AT keyboard and usually 0xAB 0x83 for others
+    fn identify(&mut self) -> Option<u16> {
+        if ! self.send(0xF2) {
+            return None;
+        }
+        let mut id = 0;
+        for _ in 0..2 {
+            match self.receive(ACK_SPINS) {
+                Some(byte) => id = id << 8 | byte as u16,
+                None => break,
+            }
+        }
+        Some(id)
+    }
+
+    /// The scancode set in use, read while the controller does not translate
+    fn scancode_set(&mut self) -> Option<u8> {
+        if self.send(0xF0) && self.send(0) {
+            self.receive(ACK_SPINS)
+        } else {
+            None
+        }
+    }
+
+    fn set_scancode_set(&mut self, set: u8) -> bool {
+        self.send(0xF0) && self.send(set)
+    }
+
+    /// Light the LEDs, `LED_SCROLL`, `LED_NUM` and `LED_CAPS`
+    fn set_leds(&mut self, leds: u8) -> bool {
+        self.send(0xED) && self.send(leds & 7)
     }
 }
 
@@ -39,8 +140,43 @@ impl<'a> Ps2Mouse<'a> {
         self.bus.wait_read();
         self.bus.data.read()
     }
+
+    fn set_sample_rate(&mut self, rate: u8) {
+        self.cmd(0xF3);
+        self.cmd(rate);
+    }
+
+    /// The device ID, 0 for a plain mouse, 3 with a wheel and 4 with a wheel and five buttons
+    fn id(&mut self) -> u8 {
+        self.cmd(0xF2);
+        self.bus.wait_read();
+        self.bus.data.read()
+    }
+
+    /// Switch to the IntelliMouse protocol with the magic sample rate sequences, returning the ID the
+    /// mouse has after them
+    ///
+    /// Mice without a wheel ignore the sequences and keep ID 0. The rate is put back to 100 after.
+    fn intellimouse(&mut self) -> u8 {
+        for &rate in [200, 100, 80].iter() {
+            self.set_sample_rate(rate);
+        }
+        let mut id = self.id();
+        if id == 3 {
+            for &rate in [200, 200, 80].iter() {
+                self.set_sample_rate(rate);
+            }
+            id = self.id();
+        }
+        self.set_sample_rate(100);
+        id
+    }
 }
 
+/// Bytes of a packet that come further apart than this start a new packet, so the stream gets back in
+/// step after a byte is lost
+const MOUSE_PACKET_GAP: i32 = 50 * time::NANOS_PER_MILLI;
+
 /// PS2
 pub struct Ps2 {
     /// The data register
@@ -57,21 +193,35 @@ pub struct Ps2 {
     caps_lock: bool,
     /// Caps lock toggle
     caps_lock_toggle: bool,
+    /// Num lock and scroll lock
+    num_lock: bool,
+    scroll_lock: bool,
+    /// The lock keys held down, so their repeats do not toggle them
+    locks_held: u8,
+    /// The LEDs last lit
+    leds: u8,
     /// Left control
     lctrl: bool,
     /// AltGr?
     altgr: bool,
+    /// The controller configuration last written
+    config: u8,
+    /// The keyboard sends set 2, which is turned to set 1 here as the controller does not translate it
+    decode_set2: bool,
+    /// The 0xE0 prefix of an extended key came
+    extended: bool,
+    /// The 0xF0 prefix of a set 2 release came
+    release: bool,
+    /// The keys repeat in software as those of other keyboards do, the repeats the keyboard sends are dropped
+    repeat: KeyRepeat,
     /// The mouse packet
     mouse_packet: [u8; 4],
     /// Mouse packet index
     mouse_i: usize,
-    /// Mouse point x
-    mouse_x: i32,
-    /// Mouse point y
-    mouse_y: i32,
-    /// Layout for keyboard
-    /// Default: English
-    layout: layouts::Layout,
+    /// The device ID of the mouse, which has 4 byte packets with a wheel delta if it is 3 or 4
+    mouse_id: u8,
+    /// When the last byte from the mouse came
+    mouse_time: Duration,
 }
 
 impl Ps2 {
@@ -85,26 +235,61 @@ impl Ps2 {
             rshift: false,
             caps_lock: false,
             caps_lock_toggle: false,
+            num_lock: false,
+            scroll_lock: false,
+            locks_held: 0,
+            leds: 0,
             lctrl: false,
             altgr: false,
+            config: CONFIG_SYSTEM | CONFIG_TRANSLATE,
+            decode_set2: false,
+            extended: false,
+            release: false,
+            repeat: KeyRepeat::new(),
             mouse_packet: [0; 4],
             mouse_i: 0,
-            mouse_x: 0,
-            mouse_y: 0,
-            layout: layouts::Layout::English,
+            mouse_id: 0,
+            mouse_time: Duration::new(0, 0),
         };
 
         module.init();
 
+        unsafe { PS2 = &mut *module };
+
+        Context::spawn("kps2_repeat".into(), box move || {
+            loop {
+                {
+                    let _guard = InterruptGuard::new();
+                    if let Some(key_event) = unsafe { (*PS2).repeat.due() } {
+                        send_key(key_event);
+                    }
+                }
+
+                timekeeping::sleep(10, "PS/2 repeat sleep");
+            }
+        });
+
         module
     }
 
-    fn wait_read(&self) {
-        while ! self.sts.readf(1) {}
+    /// Wait for a byte to read, returning false if none comes, with no device on the port
+    fn wait_read(&self) -> bool {
+        for _ in 0..CONTROLLER_SPINS {
+            if self.sts.readf(1) {
+                return true;
+            }
+        }
+        false
     }
 
-    fn wait_write(&self) {
-        while self.sts.readf(2) {}
+    /// Wait for the controller to take a byte, returning false if it does not
+    fn wait_write(&self) -> bool {
+        for _ in 0..CONTROLLER_SPINS {
+            if ! self.sts.readf(2) {
+                return true;
+            }
+        }
+        false
     }
 
     fn cmd(&mut self, command: u8) {
@@ -144,7 +329,8 @@ impl Ps2 {
         syslog_info!(" + PS/2");
 
         // No interrupts, system flag set, clocks enabled, translation enabled
-        self.write(0x60, 0b01000100);
+        let config = self.config;
+        self.write(0x60, config);
 
         while self.sts.readf(1) {
             syslog_info!("   - Extra {}: {:X}", line!(), self.data.read());
@@ -158,29 +344,8 @@ impl Ps2 {
             syslog_info!("     - Extra {}: {:X}", line!(), self.data.read());
         }
 
-        {
-            // Reset
-            self.keyboard().cmd(0xFF);
-            self.wait_read();
-            self.data.read();
-
-            while self.sts.readf(1) {
-                syslog_info!("     - Extra {}: {:X}", line!(), self.data.read());
-            }
-
-            // Set defaults
-            self.keyboard().cmd(0xF6);
-
-            while self.sts.readf(1) {
-                syslog_info!("     - Extra {}: {:X}", line!(), self.data.read());
-            }
-
-            // Enable Streaming
-            self.keyboard().cmd(0xF4);
-
-            while self.sts.readf(1) {
-                syslog_info!("     - Extra {}: {:X}", line!(), self.data.read());
-            }
+        if ! self.configure_keyboard(true) {
+            syslog_warning!("     - No keyboard answered");
         }
 
         // Enable Second Port
@@ -193,7 +358,7 @@ impl Ps2 {
 
         {
             // Reset
-            self.keyboard().cmd(0xFF);
+            self.mouse().cmd(0xFF);
             self.wait_read();
             self.data.read();
 
@@ -208,6 +373,20 @@ impl Ps2 {
                 syslog_info!("     - Extra {}: {:X}", line!(), self.data.read());
             }
 
+            self.mouse_id = match self.mouse().intellimouse() {
+                id @ 3 ... 4 => id,
+                _ => 0,
+            };
+            match self.mouse_id {
+                3 => syslog_info!("     + Wheel"),
+                4 => syslog_info!("     + Wheel and 5 buttons"),
+                _ => (),
+            }
+
+            while self.sts.readf(1) {
+                syslog_info!("     - Extra {}: {:X}", line!(), self.data.read());
+            }
+
             // Enable Streaming
             self.mouse().cmd(0xF4);
 
@@ -216,18 +395,185 @@ impl Ps2 {
             }
         }
 
-        // Key and mouse interrupts, system flag set, clocks enabled, translation enabled
-        self.write(0x60, 0b01000111);
+        // Key and mouse interrupts, system flag set, clocks enabled
+        self.config |= CONFIG_IRQ_KEYBOARD | CONFIG_IRQ_MOUSE;
+        let config = self.config;
+        self.write(0x60, config);
 
         while self.sts.readf(1) {
             syslog_info!("     - Extra {}: {:X}", line!(), self.data.read());
         }
     }
 
+    /// Turn the translation of the controller on or off, returning whether it is on after
+    fn set_translation(&mut self, translate: bool) -> bool {
+        let config = if translate {
+            self.config | CONFIG_TRANSLATE
+        } else {
+            self.config & ! CONFIG_TRANSLATE
+        };
+        self.write(0x60, config);
+        self.config = self.read(0x20);
+        self.config & CONFIG_TRANSLATE == CONFIG_TRANSLATE
+    }
+
+    /// Set up the keyboard for set 1, which the decoding expects, resetting it first if `reset` is set
+    ///
+    /// Set 2 translated by the controller is tried first, since every keyboard has it. A controller that
+    /// does not translate gets its set 2 decoded here, and a keyboard that stays on set 1 is read as it is.
+    /// This is done again when a keyboard is plugged back in. Returns whether the keyboard answered.
+    fn configure_keyboard(&mut self, reset: bool) -> bool {
+        if reset && ! self.keyboard().reset() {
+            return false;
+        }
+        // No scancodes until it is set up
+        if ! self.keyboard().send(0xF5) {
+            return false;
+        }
+
+        // The ID and the set are read back untranslated
+        self.set_translation(false);
+        let id = self.keyboard().identify();
+        self.keyboard().set_scancode_set(2);
+        let set = match self.keyboard().scancode_set() {
+            Some(set @ 1 ... 3) => set,
+            // A keyboard that cannot say is an old one, which only has set 2
+            _ => 2,
+        };
+        if set == 3 && self.keyboard().set_scancode_set(1) {
+            syslog_warning!("     - Keyboard was on set 3, set to set 1");
+        }
+
+        let (set, translated) = if set == 2 {
+            (2, self.set_translation(true))
+        } else {
+            (1, false)
+        };
+        self.decode_set2 = set == 2 && ! translated;
+        self.extended = false;
+        self.release = false;
+
+        syslog_info!("     + ID {}, scancode set {}, {}",
+                     id.map_or("none".into(), |id| format!("{:04X}", id)),
+                     set,
+                     if translated { "translated by the controller" } else if set == 2 { "translated by the driver" } else { "untranslated" });
+
+        // Light what is on again, the keyboard lost it if it was reset
+        let leds = keyboard::leds(self.locks());
+        if self.keyboard().set_leds(leds) {
+            self.leds = leds;
+        }
+
+        self.keyboard().send(0xF4)
+    }
+
+    /// Turn a byte from the keyboard into a set 1 scancode, with whether it had the 0xE0 prefix, once
+    /// the key is complete
+    fn scancode(&mut self, byte: u8) -> Option<(bool, u8)> {
+        if byte == 0xE0 {
+            self.extended = true;
+            return None;
+        }
+
+        let code = if self.decode_set2 {
+            match byte {
+                0xF0 => {
+                    self.release = true;
+                    return None;
+                },
+                0x83 => 0x41,
+                0x00 ... 0x7F => SET2_TO_SET1[byte as usize],
+                _ => byte,
+            }
+        } else {
+            byte
+        };
+        let release = if self.release { 0x80 } else { 0 };
+
+        let extended = self.extended;
+        self.extended = false;
+        self.release = false;
+        Some((extended, code | release))
+    }
+
+    /// A keyboard sends 0xAA after its self test, when it is plugged in
+    ///
+    /// Translated, that is also the release of left shift, so it is only taken as a keyboard while
+    /// left shift is up.
+    fn reconnected(&self, byte: u8) -> bool {
+        byte == 0xAA && ! self.extended && ! self.release && (self.decode_set2 || ! self.lshift)
+    }
+
+    /// The locks that are on
+    fn locks(&self) -> u8 {
+        let mut locks = 0;
+        if self.caps_lock {
+            locks |= LED_CAPS;
+        }
+        if self.num_lock {
+            locks |= LED_NUM;
+        }
+        if self.scroll_lock {
+            locks |= LED_SCROLL;
+        }
+        locks
+    }
+
+    /// Light the LEDs of the locks, or those set through `keyboard:leds`, if they changed
+    fn leds_changed(&mut self) {
+        let leds = keyboard::leds(self.locks());
+        if leds != self.leds {
+            if self.keyboard().set_leds(leds) {
+                self.leds = leds;
+            } else {
+                syslog_warning!("PS/2: Keyboard did not take LEDs {:X}", leds);
+            }
+        }
+    }
+
+    /// Toggle a lock on its press, not on the repeats while it is held
+    fn toggle_lock(&mut self, lock: u8, pressed: bool) -> bool {
+        if pressed {
+            let toggle = self.locks_held & lock == 0;
+            self.locks_held |= lock;
+            toggle
+        } else {
+            self.locks_held &= ! lock;
+            false
+        }
+    }
+
     /// Keyboard interrupt
-    pub fn keyboard_interrupt(&mut self, mut scancode: u8) -> Option<KeyEvent> {
+    pub fn keyboard_interrupt(&mut self, byte: u8) -> Option<KeyEvent> {
+        if self.reconnected(byte) {
+            syslog_info!("PS/2: Keyboard plugged in");
+            self.repeat.clear();
+            self.configure_keyboard(false);
+            return None;
+        }
+
+        let (extended, mut scancode) = match self.scancode(byte) {
+            Some(scancode) => scancode,
+            None => return None,
+        };
+
         if scancode == 0 {
             return None;
+        } else if extended {
+            if scancode == 0x38 {
+                self.altgr = true;
+            } else if scancode == 0xB8 {
+                self.altgr = false;
+            } else {
+                // The media keys share their second byte with letters, so they get codes of their own
+                let release = scancode & 0x80;
+                scancode = match scancode & 0x7F {
+                    0x20 => K_MUTE | release,
+                    0x2E => K_VOLDOWN | release,
+                    0x30 => K_VOLUP | release,
+                    _ => scancode,
+                };
+            }
         } else if scancode == 0x2A {
             self.lshift = true;
         } else if scancode == 0xAA {
@@ -247,19 +593,18 @@ impl Ps2 {
             if self.caps_lock && !self.caps_lock_toggle {
                 self.caps_lock = false;
             }
+        } else if scancode & 0x7F == 0x45 {
+            if self.toggle_lock(LED_NUM, scancode < 0x80) {
+                self.num_lock = ! self.num_lock;
+            }
+        } else if scancode & 0x7F == 0x46 {
+            if self.toggle_lock(LED_SCROLL, scancode < 0x80) {
+                self.scroll_lock = ! self.scroll_lock;
+            }
         } else if scancode == 0x1D {
             self.lctrl = true;
         } else if scancode == 0x9D {
             self.lctrl = false;
-        } else if scancode == 0xE0 {
-            let scancode_byte_2 = self.data.read();
-            if scancode_byte_2 == 0x38 {
-                self.altgr = true;
-            } else if scancode_byte_2 == 0xB8 {
-                self.altgr = false;
-            } else {
-                scancode = scancode_byte_2;
-            }
         }
 
         if self.lctrl {
@@ -267,9 +612,9 @@ impl Ps2 {
                 let console = unsafe { &mut *::env().console.get() };
 
                 console.write(b"^C\n");
-                console.commands.send(String::new(), "Serial Control C");
+                console.active_mut().commands.send(String::new(), "Serial Control C");
 
-                if let Some(ref mut inner) = console.inner {
+                if let Some(ref mut inner) = console.active_mut().inner {
                     inner.redraw = true;
                 }
                 console.write(b"");
@@ -296,7 +641,7 @@ impl Ps2 {
                     }
                 }
 
-                if let Some(ref mut inner) = console.inner {
+                if let Some(ref mut inner) = console.active_mut().inner {
                     inner.redraw = true;
                 }
                 console.write(b"");
@@ -305,107 +650,162 @@ impl Ps2 {
             }
         }
 
-        let shift = self.caps_lock != (self.lshift || self.rshift);
+        if scancode & 0x7F == 0x3A || scancode & 0x7F == 0x45 || scancode & 0x7F == 0x46 {
+            self.leds_changed();
+        }
 
+        let shift = self.caps_lock != (self.lshift || self.rshift);
 
-        Some(KeyEvent {
-            character: layouts::char_for_scancode(scancode & 0x7F, shift, self.altgr, &self.layout),
+        let key_event = KeyEvent {
+            character: layouts::char_for_scancode(scancode & 0x7F, shift, self.altgr, &focus::layout()),
             scancode: scancode & 0x7F,
             pressed: scancode < 0x80,
-        })
+        };
+        if self.repeat.key(&key_event) {
+            Some(key_event)
+        } else {
+            None
+        }
+    }
+
+    /// The bytes in a packet of the mouse
+    fn mouse_packet_size(&self) -> usize {
+        if self.mouse_id == 3 || self.mouse_id == 4 { 4 } else { 3 }
     }
 
-    /// Mouse interrupt
-    pub fn mouse_interrupt(&mut self, byte: u8) -> Option<MouseEvent> {
+    /// Mouse interrupt, returning the movement and the wheel steps, if any, once a packet is complete
+    pub fn mouse_interrupt(&mut self, byte: u8) -> Option<(MouseEvent, Option<ScrollEvent>)> {
+        let now = Duration::monotonic();
+        if self.mouse_i > 0 && now - self.mouse_time > Duration::new(0, MOUSE_PACKET_GAP) {
+            self.mouse_i = 0;
+        }
+        self.mouse_time = now;
+
         if self.mouse_i == 0 {
+            // The first byte always has bit 3 set, bytes are dropped until one does
             if byte & 0x8 == 0x8 {
                 self.mouse_packet[0] = byte;
                 self.mouse_i += 1;
             }
-        } else if self.mouse_i == 1 {
-            self.mouse_packet[1] = byte;
+            return None;
+        }
 
-            self.mouse_i += 1;
-        } else {
-            self.mouse_packet[2] = byte;
+        self.mouse_packet[self.mouse_i] = byte;
+        self.mouse_i += 1;
+        if self.mouse_i < self.mouse_packet_size() {
+            return None;
+        }
+        self.mouse_i = 0;
 
-            let left_button = (self.mouse_packet[0] & 1) == 1;
-            let right_button = (self.mouse_packet[0] & 2) == 2;
-            let middle_button = (self.mouse_packet[0] & 4) == 4;
+        let left_button = (self.mouse_packet[0] & 1) == 1;
+        let right_button = (self.mouse_packet[0] & 2) == 2;
+        let middle_button = (self.mouse_packet[0] & 4) == 4;
 
-            let x;
-            if (self.mouse_packet[0] & 0x40) != 0x40 && self.mouse_packet[1] != 0 {
-                x = (self.mouse_packet[1] as isize -
-                     (((self.mouse_packet[0] as isize) << 4) & 0x100)) as i32;
-            } else {
-                x = 0;
-            }
+        let x;
+        if (self.mouse_packet[0] & 0x40) != 0x40 && self.mouse_packet[1] != 0 {
+            x = (self.mouse_packet[1] as isize -
+                 (((self.mouse_packet[0] as isize) << 4) & 0x100)) as i32;
+        } else {
+            x = 0;
+        }
 
-            let y;
-            if (self.mouse_packet[0] & 0x80) != 0x80 && self.mouse_packet[2] != 0 {
-                y = ((((self.mouse_packet[0] as isize) << 3) & 0x100) -
-                     self.mouse_packet[2] as isize) as i32;
-            } else {
-                y = 0;
-            }
+        let y;
+        if (self.mouse_packet[0] & 0x80) != 0x80 && self.mouse_packet[2] != 0 {
+            y = ((((self.mouse_packet[0] as isize) << 3) & 0x100) -
+                 self.mouse_packet[2] as isize) as i32;
+        } else {
+            y = 0;
+        }
 
-            if let Some(mode_info) = unsafe { VBEMODEINFO } {
-                self.mouse_x = cmp::max(0, cmp::min(mode_info.xresolution as i32, self.mouse_x + x));
-                self.mouse_y = cmp::max(0, cmp::min(mode_info.yresolution as i32, self.mouse_y + y));
+        let mut z = 0;
+        if self.mouse_id == 4 {
+            // The low 4 bits are the delta, the others have buttons 4 and 5 and two that are always clear
+            if self.mouse_packet[3] & 0xC0 != 0 {
+                return None;
             }
+            z = ((self.mouse_packet[3] << 4) as i8 >> 4) as i32;
+        } else if self.mouse_id == 3 {
+            // The delta is a whole byte, but only ever as large as the 4 bit one of 5 button mice
+            z = cmp::max(-8, cmp::min(7, self.mouse_packet[3] as i8 as i32));
+        }
 
-            self.mouse_i = 0;
+        let (mouse_x, mouse_y) = cursor_move(x, y);
 
-            return Some(MouseEvent {
-                x: self.mouse_x,
-                y: self.mouse_y,
-                left_button: left_button,
-                right_button: right_button,
-                middle_button: middle_button,
-            });
-        }
+        let mouse_event = MouseEvent {
+            x: mouse_x,
+            y: mouse_y,
+            left_button: left_button,
+            right_button: right_button,
+            middle_button: middle_button,
+            device: 0,
+        };
 
-        return None;
-    }
+        // The mouse reports turning the wheel away from the user as negative
+        let scroll_event = if z != 0 {
+            Some(ScrollEvent {
+                x: 0,
+                y: -z,
+                device: 0,
+            })
+        } else {
+            None
+        };
 
-    /// Function to change the layout of the keyboard
-    pub fn change_layout(&mut self, layout: usize) {
-        self.layout = match layout {
-            0 => layouts::Layout::English,
-            1 => layouts::Layout::French,
-            2 => layouts::Layout::German,
-            _ => layouts::Layout::English,
-        }
+        Some((mouse_event, scroll_event))
     }
 }
 
 impl KScheme for Ps2 {
-    fn on_irq(&mut self, irq: u8) {
+    fn on_irq(&mut self, irq: u8) -> bool {
+        let mut claimed = false;
         if irq == 0xC || irq == 0x1 {
             loop {
                 let status = self.sts.read();
                 if status & 0x21 == 0x21 {
+                    claimed = true;
                     let data = self.data.read();
-                    if let Some(mouse_event) = self.mouse_interrupt(data) {
-                        if unsafe { & *::env().console.get() }.draw {
-                            //Ignore mouse event
-                        } else {
-                            ::env().events.send(mouse_event.to_event(), "Ps2::on_irq mouse");
+                    if let Some((mouse_event, scroll_event)) = self.mouse_interrupt(data) {
+                        focus::send(mouse_event.to_event());
+                        if let Some(scroll_event) = scroll_event {
+                            focus::send(scroll_event.to_event());
                         }
                     }
                 } else if status & 0x21 == 0x01 {
+                    claimed = true;
                     let data = self.data.read();
                     if let Some(key_event) = self.keyboard_interrupt(data) {
-                        if unsafe { & *::env().console.get() }.draw {
-                            unsafe { &mut *::env().console.get() }.event(key_event.to_event());
-                        } else {
-                            ::env().events.send(key_event.to_event(), "Ps2::on_irq key");
-                        }
+                        send_key(key_event);
                     }
                 } else {
                     break;
                 }
             }
         }
+        claimed
+    }
+}
+
+/// Pass a key event to the focus, media keys are handled by the mixer whatever has focus
+fn send_key(key_event: KeyEvent) {
+    if ! media_key(&key_event) {
+        focus::send(key_event.to_event());
     }
 }
+
+/// Light the PS/2 keyboard LEDs again, after those set through `keyboard:leds` changed
+pub fn update_leds() {
+    let _guard = InterruptGuard::new();
+    unsafe {
+        if ! PS2.is_null() {
+            (*PS2).leds_changed();
+        }
+    }
+}
+
+/// Restart the machine by pulsing the reset line of the CPU through the controller
+pub fn reset() {
+    let sts = ReadOnly::new(Pio::<u8>::new(0x64));
+    let mut cmd = WriteOnly::new(Pio::<u8>::new(0x64));
+    while sts.readf(2) {}
+    cmd.write(0xFE);
+}
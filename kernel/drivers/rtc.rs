@@ -1,11 +1,37 @@
-use common::time::Duration;
+use common::time::{DateTime, Duration};
 
 use drivers::io::{Io, Pio};
 
-fn cvt_bcd(value: usize) -> usize {
+/// The CMOS register with the century, given by the FADT, 0 if there is none
+static mut CENTURY_REGISTER: u8 = 0;
+
+/// Use the century register `register` the FADT gives, firmware without one only keeps two digit years
+pub fn set_century_register(register: u8) {
+    unsafe { CENTURY_REGISTER = register };
+}
+
+/// Status register A, with the update in progress flag
+const REG_A: u8 = 0xA;
+const REG_A_UIP: u8 = 1 << 7;
+/// Status register B, with the register modes
+const REG_B: u8 = 0xB;
+/// Hours are 0 to 23, otherwise 1 to 12 with bit 7 set after noon
+const REG_B_24H: u8 = 1 << 1;
+/// Registers are binary, otherwise BCD
+const REG_B_BINARY: u8 = 1 << 2;
+/// Updates are stopped while the clock is set
+const REG_B_SET: u8 = 1 << 7;
+
+const HOUR_PM: u8 = 1 << 7;
+
+fn cvt_bcd(value: u8) -> u8 {
     (value & 0xF) + ((value / 16) * 10)
 }
 
+fn to_bcd(value: u8) -> u8 {
+    (value / 10) * 16 + value % 10
+}
+
 /// RTC
 pub struct Rtc {
     addr: Pio<u8>,
@@ -27,79 +53,115 @@ impl Rtc {
         return self.data.read();
     }
 
-    /// Wait
-    unsafe fn wait(&mut self) {
-        while self.read(0xA) & 0x80 != 0x80 {}
-        while self.read(0xA) & 0x80 == 0x80 {}
+    unsafe fn write(&mut self, reg: u8, value: u8) {
+        self.addr.write(reg);
+        self.data.write(value);
     }
 
-    /// Get time
-    pub fn time(&mut self) -> Duration {
-        let mut second;
-        let mut minute;
-        let mut hour;
-        let mut day;
-        let mut month;
-        let mut year;
-        let register_b;
-        unsafe {
-            self.wait();
-            second = self.read(0) as usize;
-            minute = self.read(2) as usize;
-            hour = self.read(4) as usize;
-            day = self.read(7) as usize;
-            month = self.read(8) as usize;
-            year = self.read(9) as usize;
-            register_b = self.read(0xB);
-        }
+    /// Second, minute, hour, day, month, year and century, as the registers have them
+    unsafe fn registers(&mut self) -> [u8; 7] {
+        let century = if CENTURY_REGISTER != 0 { self.read(CENTURY_REGISTER) } else { 0 };
+        [self.read(0), self.read(2), self.read(4), self.read(7), self.read(8), self.read(9), century]
+    }
 
-        if register_b & 4 != 4 {
-            second = cvt_bcd(second);
-            minute = cvt_bcd(minute);
-            hour = cvt_bcd(hour & 0x7F) | (hour & 0x80);
-            day = cvt_bcd(day);
-            month = cvt_bcd(month);
-            year = cvt_bcd(year);
+    /// The registers, read between updates
+    ///
+    /// Reading them while the clock updates could mix the time before and after, so they are read until
+    /// two reads outside an update agree. An update takes about 2 ms.
+    unsafe fn read_registers(&mut self) -> [u8; 7] {
+        loop {
+            while self.read(REG_A) & REG_A_UIP == REG_A_UIP {}
+            let first = self.registers();
+            while self.read(REG_A) & REG_A_UIP == REG_A_UIP {}
+            let second = self.registers();
+            if first == second {
+                return second;
+            }
         }
+    }
+
+    /// The date and time the clock has, in whatever mode the firmware left it
+    pub fn date(&mut self) -> DateTime {
+        let (mut registers, register_b) = unsafe { (self.read_registers(), self.read(REG_B)) };
 
-        if register_b & 2 != 2 || hour & 0x80 == 0x80 {
-            hour = ((hour & 0x7F) + 12) % 24;
+        let pm = registers[2] & HOUR_PM == HOUR_PM;
+        registers[2] &= !HOUR_PM;
+        if register_b & REG_B_BINARY != REG_B_BINARY {
+            for register in registers.iter_mut() {
+                *register = cvt_bcd(*register);
+            }
         }
 
-        // TODO: Century Register
-        year += 2000;
+        let mut hour = registers[2];
+        if register_b & REG_B_24H != REG_B_24H {
+            // 12 AM is midnight and 12 PM is noon
+            hour = hour % 12 + if pm { 12 } else { 0 };
+        }
 
-        // Unix time from clock
-        let mut secs: i64 = (year as i64 - 1970) * 31536000;
+        let year = if registers[6] != 0 {
+            registers[6] as i64 * 100 + registers[5] as i64
+        } else if registers[5] < 70 {
+            2000 + registers[5] as i64
+        } else {
+            1900 + registers[5] as i64
+        };
 
-        let mut leap_days = (year as i64 - 1972) / 4 + 1;
-        if year % 4 == 0 {
-            if month <= 2 {
-                leap_days -= 1;
-            }
+        DateTime {
+            year: year,
+            month: registers[4],
+            day: registers[3],
+            hour: hour,
+            minute: registers[1],
+            second: registers[0],
         }
-        secs += leap_days * 86400;
-
-        match month {
-            2 => secs += 2678400,
-            3 => secs += 5097600,
-            4 => secs += 7776000,
-            5 => secs += 10368000,
-            6 => secs += 13046400,
-            7 => secs += 15638400,
-            8 => secs += 18316800,
-            9 => secs += 20995200,
-            10 => secs += 23587200,
-            11 => secs += 26265600,
-            12 => secs += 28857600,
-            _ => (),
+    }
+
+    /// Get time
+    pub fn time(&mut self) -> Duration {
+        Duration::new(self.date().to_unix(), 0)
+    }
+
+    /// Set the clock to `date`, keeping the mode the firmware uses
+    ///
+    /// Without a century register the year has to be 1970 to 2069, which is what two digits are read as.
+    pub fn set_date(&mut self, date: &DateTime) -> bool {
+        let century_register = unsafe { CENTURY_REGISTER };
+        if ! date.valid() || date.year < 0 || date.year > 9999
+            || (century_register == 0 && (date.year < 1970 || date.year >= 2070)) {
+            return false;
         }
 
-        secs += (day as i64 - 1) * 86400;
-        secs += hour as i64 * 3600;
-        secs += minute as i64 * 60;
-        secs += second as i64;
+        unsafe {
+            let register_b = self.read(REG_B);
+
+            let mut hour = date.hour;
+            let mut pm = false;
+            if register_b & REG_B_24H != REG_B_24H {
+                pm = hour >= 12;
+                hour = if hour % 12 == 0 { 12 } else { hour % 12 };
+            }
+
+            let mut registers = [date.second, date.minute, hour, date.day, date.month, (date.year % 100) as u8,
+                                 (date.year / 100) as u8];
+            if register_b & REG_B_BINARY != REG_B_BINARY {
+                for register in registers.iter_mut() {
+                    *register = to_bcd(*register);
+                }
+            }
+            if pm {
+                registers[2] |= HOUR_PM;
+            }
+
+            self.write(REG_B, register_b | REG_B_SET);
+            for (&reg, &value) in [0, 2, 4, 7, 8, 9].iter().zip(registers.iter()) {
+                self.write(reg, value);
+            }
+            if century_register != 0 {
+                self.write(century_register, registers[6]);
+            }
+            self.write(REG_B, register_b & !REG_B_SET);
+        }
 
-        Duration::new(secs, 0)
+        true
     }
 }
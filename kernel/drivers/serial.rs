@@ -1,12 +1,23 @@
 use alloc::boxed::Box;
 
-use collections::string::String;
+use collections::string::{String, ToString};
+use collections::vec::Vec;
+use collections::vec_deque::VecDeque;
 
 use common::event;
+use common::to_num::ToNum;
+
+use core::{cmp, str};
 
 use drivers::io::{Io, Pio};
 
-use fs::KScheme;
+use env::log::InterruptGuard;
+
+use fs::{KScheme, Resource, ResourceSeek};
+
+use sync::WaitCondition;
+
+use system::error::{Error, Result, EINVAL, ENOENT};
 
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(packed)]
@@ -20,139 +31,737 @@ pub unsafe fn bda_init() {
     SERIALINFO = Some(*(0x400 as *const SerialInfo));
 }
 
-/// Serial
-pub struct Serial {
-    pub data: Pio<u8>,
-    pub status: Pio<u8>,
+/// The ports of the serial scheme, the first one is the console if there is no display
+static mut SERIAL_PORTS: Option<Vec<*mut SerialPort>> = None;
+
+/// The clock of the baud rate generator divided by 16, the rate with a divisor of 1
+const BAUD_BASE: u32 = 115200;
+/// Bytes received and not read yet, the oldest ones are dropped past it
+const RX_SIZE: usize = 4096;
+/// Bytes waiting to be sent, writers wait past it
+const TX_SIZE: usize = 4096;
+
+/// Interrupt enable bits
+const IER_RDA: u8 = 1;
+const IER_THRE: u8 = 1 << 1;
+const IER_RLS: u8 = 1 << 2;
+
+/// Interrupt identification, the low bit is clear while one is pending
+const IIR_NONE: u8 = 1;
+/// Both bits are set when the FIFOs are enabled and work, which tells a 16550A from older UARTs
+const IIR_FIFO: u8 = 0xC0;
+
+/// Enable the FIFOs and clear them, interrupting when 14 bytes were received
+const FCR_ENABLE: u8 = 0xC7;
+
+/// The divisor latch replaces the data and interrupt enable registers while set
+const LCR_DLAB: u8 = 1 << 7;
+
+/// DTR, RTS and OUT2, which connects the interrupt to the PIC
+const MCR_INIT: u8 = 0x0B;
+
+/// Line status bits
+const LSR_DR: u8 = 1;
+const LSR_OE: u8 = 1 << 1;
+const LSR_PE: u8 = 1 << 2;
+const LSR_FE: u8 = 1 << 3;
+const LSR_THRE: u8 = 1 << 5;
+
+/// Parity of a serial line
+#[derive(Copy, Clone, PartialEq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,
+    Space,
+}
+
+impl Parity {
+    fn name(&self) -> &'static str {
+        match *self {
+            Parity::None => "none",
+            Parity::Odd => "odd",
+            Parity::Even => "even",
+            Parity::Mark => "mark",
+            Parity::Space => "space",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Parity> {
+        match name {
+            "none" => Some(Parity::None),
+            "odd" => Some(Parity::Odd),
+            "even" => Some(Parity::Even),
+            "mark" => Some(Parity::Mark),
+            "space" => Some(Parity::Space),
+            _ => None,
+        }
+    }
+
+    /// The parity bits of the line control register
+    fn bits(&self) -> u8 {
+        match *self {
+            Parity::None => 0,
+            Parity::Odd => 0x08,
+            Parity::Even => 0x18,
+            Parity::Mark => 0x28,
+            Parity::Space => 0x38,
+        }
+    }
+}
+
+/// The settings of a serial line
+#[derive(Copy, Clone)]
+pub struct LineConfig {
+    pub baud: u32,
+    /// Bits in a character, 5 to 8
+    pub data_bits: u8,
+    pub parity: Parity,
+    /// 1 or 2
+    pub stop_bits: u8,
+}
+
+impl LineConfig {
+    /// 115200 baud, 8N1
+    pub fn new() -> LineConfig {
+        LineConfig {
+            baud: BAUD_BASE,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1,
+        }
+    }
+
+    /// Whether the UART can run the line like this, the baud rate has to divide the clock
+    pub fn valid(&self) -> bool {
+        self.baud > 0 && self.baud <= BAUD_BASE && BAUD_BASE % self.baud == 0
+            && self.data_bits >= 5 && self.data_bits <= 8 && (self.stop_bits == 1 || self.stop_bits == 2)
+    }
+
+    /// The line control register for these settings
+    fn lcr(&self) -> u8 {
+        (self.data_bits - 5) | if self.stop_bits == 2 { 1 << 2 } else { 0 } | self.parity.bits()
+    }
+}
+
+/// The registers of a 16550 compatible UART
+pub struct Uart {
+    /// Received and transmitted bytes, or the low byte of the divisor
+    data: Pio<u8>,
+    /// Interrupt enable, or the high byte of the divisor
+    int_en: Pio<u8>,
+    /// Interrupt identification when read, FIFO control when written
+    int_id: Pio<u8>,
+    line_ctrl: Pio<u8>,
+    modem_ctrl: Pio<u8>,
+    line_sts: Pio<u8>,
+    modem_sts: Pio<u8>,
+}
+
+impl Uart {
+    pub fn new(base: u16) -> Uart {
+        Uart {
+            data: Pio::new(base),
+            int_en: Pio::new(base + 1),
+            int_id: Pio::new(base + 2),
+            line_ctrl: Pio::new(base + 3),
+            modem_ctrl: Pio::new(base + 4),
+            line_sts: Pio::new(base + 5),
+            modem_sts: Pio::new(base + 6),
+        }
+    }
+
+    /// Program the divisor and line settings of `config`
+    pub fn configure(&mut self, config: &LineConfig) {
+        let divisor = (BAUD_BASE / config.baud) as u16;
+        let int_en = self.int_en.read();
+        self.int_en.write(0);
+        self.line_ctrl.write(LCR_DLAB);
+        self.data.write(divisor as u8);
+        self.int_en.write((divisor >> 8) as u8);
+        self.line_ctrl.write(config.lcr());
+        self.int_en.write(int_en);
+    }
+
+    /// Send `byte` once the transmitter can take it, for output that cannot wait for an interrupt
+    pub fn send_polled(&mut self, byte: u8) {
+        while ! self.line_sts.readf(LSR_THRE) {}
+        self.data.write(byte);
+    }
+
+    /// Wait for a byte and return it
+    pub fn receive_polled(&mut self) -> u8 {
+        while ! self.line_sts.readf(LSR_DR) {}
+        self.data.read()
+    }
+}
+
+/// A 16550 serial port
+///
+/// Received bytes go into a ring that `serial:/N` reads, or to the console while that is not open on
+/// the console port. Written bytes are queued and sent from the transmitter empty interrupt, a FIFO
+/// at a time.
+pub struct SerialPort {
+    pub base: u16,
     pub irq: u8,
-    pub escape: bool,
-    pub cursor_control: bool,
-}
-
-impl Serial {
-    /// Create new
-    pub fn new(port: u16, irq: u8) -> Box<Self> {
-        Pio::<u8>::new(port + 1).write(0x00);
-        Pio::<u8>::new(port + 3).write(0x80);
-        Pio::<u8>::new(port + 0).write(0x03);
-        Pio::<u8>::new(port + 1).write(0x00);
-        Pio::<u8>::new(port + 3).write(0x03);
-        Pio::<u8>::new(port + 2).write(0xC7);
-        Pio::<u8>::new(port + 4).write(0x0B);
-        Pio::<u8>::new(port + 1).write(0x01);
-
-        box Serial {
-            data: Pio::<u8>::new(port),
-            status: Pio::<u8>::new(port + 5),
+    pub config: LineConfig,
+    uart: Uart,
+    /// The bytes the transmitter takes at once, 16 with a working FIFO
+    fifo: usize,
+    /// Takes the input of the console when nothing reads it
+    console: bool,
+    /// Mirror debug output to the port
+    pub debug: bool,
+    rx: VecDeque<u8>,
+    tx: VecDeque<u8>,
+    rx_condition: WaitCondition,
+    tx_condition: WaitCondition,
+    /// Resources reading the port
+    readers: usize,
+    /// Bytes received and dropped, because the ring or the FIFO was full
+    pub overruns: usize,
+    /// Characters received with a parity or framing error
+    pub errors: usize,
+    escape: bool,
+    cursor_control: bool,
+}
+
+impl SerialPort {
+    /// Reset the UART at `base` to 115200 8N1 with the FIFOs enabled, interrupting on `irq` when
+    /// bytes are received
+    pub fn new(base: u16, irq: u8, console: bool) -> Box<SerialPort> {
+        let mut uart = Uart::new(base);
+        uart.int_en.write(0);
+        let config = LineConfig::new();
+        uart.configure(&config);
+        uart.int_id.write(FCR_ENABLE);
+        let fifo = if uart.int_id.read() & IIR_FIFO == IIR_FIFO { 16 } else { 1 };
+        uart.modem_ctrl.write(MCR_INIT);
+        uart.int_en.write(IER_RDA | IER_RLS);
+
+        box SerialPort {
+            base: base,
             irq: irq,
+            config: config,
+            uart: uart,
+            fifo: fifo,
+            console: console,
+            debug: false,
+            rx: VecDeque::new(),
+            tx: VecDeque::new(),
+            rx_condition: WaitCondition::new(),
+            tx_condition: WaitCondition::new(),
+            readers: 0,
+            overruns: 0,
+            errors: 0,
             escape: false,
             cursor_control: false,
         }
     }
 
-    pub fn writeb(&mut self, byte: u8){
-        while !self.status.readf(0x20) {}
-        self.data.write(byte);
+    /// Change the line settings, what is queued is sent first
+    pub fn configure(&mut self, config: LineConfig) {
+        let _guard = InterruptGuard::new();
+        self.flush_polled();
+        self.config = config;
+        self.uart.configure(&config);
     }
 
-    pub fn write(&mut self, bytes: &[u8]) {
+    /// Send what is queued, then `bytes`, waiting on the transmitter
+    ///
+    /// This is how the console and debug output are sent, they may come from an interrupt handler or a
+    /// panic, where there is no transmitter interrupt to wait for.
+    pub fn write_polled(&mut self, bytes: &[u8]) {
+        let _guard = InterruptGuard::new();
+        self.flush_polled();
         for &byte in bytes.iter() {
-            self.writeb(byte);
+            self.uart.send_polled(byte);
         }
     }
 
+    /// Wait for a byte, before interrupts are enabled
     pub fn readb(&mut self) -> u8 {
-        while self.status.read() & 1 == 0 {}
-        self.data.read()
+        self.uart.receive_polled()
     }
-}
 
-impl KScheme for Serial {
-    fn on_irq(&mut self, irq: u8) {
-        if irq == self.irq {
-            let mut c = self.readb() as char;
-            let mut sc = 0;
+    fn flush_polled(&mut self) {
+        while let Some(byte) = self.tx.pop_front() {
+            self.uart.send_polled(byte);
+        }
+        self.transmit();
+    }
 
-            let console = unsafe { &mut *::env().console.get() };
+    /// Fill the transmitter from the queue if it is empty, the empty interrupt is on while bytes are queued
+    fn transmit(&mut self) {
+        if self.uart.line_sts.readf(LSR_THRE) {
+            for _ in 0..self.fifo {
+                match self.tx.pop_front() {
+                    Some(byte) => self.uart.data.write(byte),
+                    None => break,
+                }
+            }
+        }
 
-            if self.escape {
-                self.escape = false;
+        let int_en = if self.tx.is_empty() { IER_RDA | IER_RLS } else { IER_RDA | IER_RLS | IER_THRE };
+        self.uart.int_en.write(int_en);
+        self.tx_condition.notify("SerialPort::transmit");
+    }
 
-                if c == '[' {
-                    self.cursor_control = true;
-                }
+    fn receive(&mut self, byte: u8) {
+        if self.console && self.readers == 0 {
+            self.console_input(byte);
+        } else {
+            if self.rx.len() >= RX_SIZE {
+                self.rx.pop_front();
+                self.overruns += 1;
+            }
+            self.rx.push_back(byte);
+        }
+    }
+
+    /// Handle an interrupt, returning whether it was raised by this port
+    fn on_irq(&mut self) -> bool {
+        let mut claimed = false;
+        // A port that keeps an interrupt pending is not handled forever
+        for _ in 0..16 {
+            let int_id = self.uart.int_id.read();
+            if int_id & IIR_NONE == IIR_NONE {
+                break;
+            }
+            claimed = true;
 
-                c = '\0';
-            } else if self.cursor_control {
-                self.cursor_control = false;
-
-                if c == 'A' {
-                    sc = event::K_UP;
-                } else if c == 'B' {
-                    sc = event::K_DOWN;
-                } else if c == 'C' {
-                    sc = event::K_RIGHT;
-                } else if c == 'D' {
-                    sc = event::K_LEFT;
+            loop {
+                let line_sts = self.uart.line_sts.read();
+                if line_sts & LSR_OE == LSR_OE {
+                    self.overruns += 1;
+                }
+                if line_sts & (LSR_PE | LSR_FE) != 0 {
+                    self.errors += 1;
+                }
+                if line_sts & LSR_DR != LSR_DR {
+                    break;
                 }
+                let byte = self.uart.data.read();
+                self.receive(byte);
+            }
+
+            // Modem status changes are not used, reading the register acknowledges them
+            self.uart.modem_sts.read();
+            self.transmit();
+        }
+
+        if claimed {
+            self.rx_condition.notify("SerialPort::on_irq");
+        }
+        claimed
+    }
+
+    /// Pass a received byte to the console as a key, escape sequences of the arrow keys included
+    fn console_input(&mut self, byte: u8) {
+        let mut c = byte as char;
+        let mut sc = 0;
+
+        let console = unsafe { &mut *::env().console.get() };
+
+        if self.escape {
+            self.escape = false;
+
+            if c == '[' {
+                self.cursor_control = true;
+            }
 
-                c = '\0';
-            } else if c == '\x03' {
-                console.write(b"^C\n");
-                console.commands.send(String::new(), "Serial Control C");
+            c = '\0';
+        } else if self.cursor_control {
+            self.cursor_control = false;
 
-                if let Some(ref mut inner) = console.inner {
-                    inner.redraw = true;
+            if c == 'A' {
+                sc = event::K_UP;
+            } else if c == 'B' {
+                sc = event::K_DOWN;
+            } else if c == 'C' {
+                sc = event::K_RIGHT;
+            } else if c == 'D' {
+                sc = event::K_LEFT;
+            }
+
+            c = '\0';
+        } else if c == '\x03' {
+            console.write(b"^C\n");
+            console.active_mut().commands.send(String::new(), "Serial Control C");
+
+            if let Some(ref mut inner) = console.active_mut().inner {
+                inner.redraw = true;
+            }
+            console.write(b"");
+
+            c = '\0';
+            sc = 0;
+        } else if c == '\x04' {
+            console.write(b"^D\n");
+
+            {
+                let contexts = unsafe { &mut *::env().contexts.get() };
+                debugln!("Magic CTRL-D {}", ::common::time::Duration::monotonic().secs);
+                for context in contexts.iter() {
+                    debugln!("  PID {}: {}", context.pid, context.name);
+
+                    if context.blocked > 0 {
+                        debugln!("    BLOCKED {}", context.blocked);
+                    }
+
+                    if let Some(current_syscall) = context.current_syscall {
+                        debugln!("    SYS {:X}: {} {} {:X} {:X} {:X}", current_syscall.0, current_syscall.1, ::syscall::name(current_syscall.1), current_syscall.2, current_syscall.3, current_syscall.4);
+                    }
                 }
-                console.write(b"");
+            }
 
-                c = '\0';
-                sc = 0;
-            } else if c == '\x04' {
-                console.write(b"^D\n");
+            if let Some(ref mut inner) = console.active_mut().inner {
+                inner.redraw = true;
+            }
+            console.write(b"");
 
-                {
-                    let contexts = unsafe { &mut *::env().contexts.get() };
-                    debugln!("Magic CTRL-D {}", ::common::time::Duration::monotonic().secs);
-                    for context in contexts.iter() {
-                        debugln!("  PID {}: {}", context.pid, context.name);
+            c = '\0';
+            sc = 0;
+        } else if c == '\x1B' {
+            self.escape = true;
+            c = '\0';
+        } else if c == '\r' {
+            c = '\n';
+        } else if c == '\x7F' {
+            c = '\0';
+            sc = event::K_BKSP;
+        }
 
-                        if context.blocked > 0 {
-                            debugln!("    BLOCKED {}", context.blocked);
-                        }
+        if c != '\0' || sc != 0 {
+            let key_event = event::KeyEvent {
+                character: if c == '\0' { None } else { Some(c) },
+                scancode: sc,
+                pressed: true,
+            };
+
+            console.event(key_event.to_event());
+        }
+    }
+
+    /// The settings and counters of the port, as read from `serial:/N/config`
+    fn status(&self) -> String {
+        format!("port: {:X}\nirq: {}\nfifo: {}\nbaud: {}\ndata: {}\nparity: {}\nstop: {}\ndebug: {}\nqueued: {}\nbuffered: {}\noverruns: {}\nerrors: {}\n",
+                self.base, self.irq, self.fifo, self.config.baud, self.config.data_bits, self.config.parity.name(),
+                self.config.stop_bits, if self.debug { "on" } else { "off" }, self.tx.len(), self.rx.len(),
+                self.overruns, self.errors)
+    }
+}
+
+fn ports() -> &'static [*mut SerialPort] {
+    match unsafe { SERIAL_PORTS.as_ref() } {
+        Some(ports) => &ports[..],
+        None => &[],
+    }
+}
+
+/// Output the console, on the console port if the scheme has one, on COM1 directly until then
+pub fn console_write(bytes: &[u8]) {
+    let mut data = Vec::with_capacity(bytes.len());
+    for &byte in bytes.iter() {
+        data.push(byte);
+        // A backspace also clears the character it moves over
+        if byte == 8 {
+            data.push(b' ');
+            data.push(8);
+        }
+    }
+
+    match ports().iter().find(|&&port| unsafe { (*port).console }) {
+        Some(&port) => unsafe { (*port).write_polled(&data) },
+        None => {
+            let mut uart = Uart::new(0x3F8);
+            for &byte in data.iter() {
+                uart.send_polled(byte);
+            }
+        }
+    }
+}
+
+/// Mirror debug output to the ports that have `debug=on`
+///
+/// The console port is left out while there is no display, since the console is on it already.
+pub fn debug_write(bytes: &[u8]) {
+    let display = unsafe { & *::env().console.get() }.display.is_some();
+    for &port in ports().iter() {
+        let port = unsafe { &mut *port };
+        if port.debug && (display || ! port.console) {
+            port.write_polled(bytes);
+        }
+    }
+}
+
+/// The legacy serial ports, COM1 to COM4 as the BIOS found them
+///
+/// - `serial:` lists the ports
+/// - `serial:/N` reads what was received and sends what is written
+/// - `serial:/N/config` has the settings and counters of a port, changed by settings like
+///   `baud=9600 data=7 parity=even stop=2 debug=on`
+pub struct SerialScheme;
+
+impl SerialScheme {
+    pub fn new() -> Box<SerialScheme> {
+        let mut bases = match unsafe { SERIALINFO } {
+            Some(info) => info.ports,
+            None => [0; 4],
+        };
+        // COM1 is the console, even if the BIOS did not report it
+        if bases[0] == 0 {
+            bases[0] = 0x3F8;
+        }
+
+        let mut ports = Vec::new();
+        for (i, &base) in bases.iter().enumerate() {
+            if base != 0 {
+                // COM1 and COM3 share IRQ 4, COM2 and COM4 share IRQ 3
+                let irq = if i % 2 == 0 { 4 } else { 3 };
+                ports.push(Box::into_raw(SerialPort::new(base, irq, i == 0)));
+            }
+        }
+        unsafe { SERIAL_PORTS = Some(ports) };
+
+        box SerialScheme
+    }
+
+    /// The console port, used before interrupts are enabled
+    pub fn console(&mut self) -> &mut SerialPort {
+        unsafe { &mut *ports()[0] }
+    }
+}
+
+impl KScheme for SerialScheme {
+    fn scheme(&self) -> &str {
+        "serial"
+    }
+
+    fn on_irq(&mut self, irq: u8) -> bool {
+        let mut claimed = false;
+        for &port in ports().iter() {
+            let port = unsafe { &mut *port };
+            if port.irq == irq && port.on_irq() {
+                claimed = true;
+            }
+        }
+        claimed
+    }
+
+    fn open(&mut self, url: &str, _flags: usize) -> Result<Box<Resource>> {
+        let path = url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/');
+        if path.is_empty() {
+            let mut list = String::new();
+            for (i, &port) in ports().iter().enumerate() {
+                let port = unsafe { & *port };
+                list.push_str(&format!("{} {:X} irq={} baud={}\n", i, port.base, port.irq, port.config.baud));
+            }
+            return Ok(box SerialConfigResource {
+                port: None,
+                data: list.into_bytes(),
+                seek: 0,
+            });
+        }
+
+        let mut parts = path.splitn(2, '/');
+        let index = parts.next().unwrap_or("");
+        if index.is_empty() || ! index.chars().all(|c| c.is_digit(10)) || index.to_num() >= ports().len() {
+            return Err(Error::new(ENOENT));
+        }
+        let port = ports()[index.to_num()];
+
+        match parts.next() {
+            None => {
+                unsafe { (*port).readers += 1 };
+                Ok(box SerialResource {
+                    port: port,
+                    index: index.to_num(),
+                })
+            },
+            Some("config") => Ok(box SerialConfigResource {
+                port: Some(port),
+                data: unsafe { (*port).status() }.into_bytes(),
+                seek: 0,
+            }),
+            Some(_) => Err(Error::new(ENOENT)),
+        }
+    }
+}
+
+/// A serial port opened for reading and writing
+pub struct SerialResource {
+    port: *mut SerialPort,
+    index: usize,
+}
+
+impl Resource for SerialResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        unsafe { (*self.port).readers += 1 };
+        Ok(box SerialResource {
+            port: self.port,
+            index: self.index,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = format!("serial:/{}", self.index);
+
+        for (b, p) in buf.iter_mut().zip(path.bytes()) {
+            *b = p;
+        }
 
-                        if let Some(current_syscall) = context.current_syscall {
-                            debugln!("    SYS {:X}: {} {} {:X} {:X} {:X}", current_syscall.0, current_syscall.1, ::syscall::name(current_syscall.1), current_syscall.2, current_syscall.3, current_syscall.4);
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    /// Wait until something was received, then take as much of it as fits
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let port = unsafe { &mut *self.port };
+        loop {
+            {
+                let _guard = InterruptGuard::new();
+                if ! port.rx.is_empty() || buf.is_empty() {
+                    let mut i = 0;
+                    while i < buf.len() {
+                        match port.rx.pop_front() {
+                            Some(byte) => buf[i] = byte,
+                            None => break,
                         }
+                        i += 1;
                     }
+                    return Ok(i);
                 }
+                port.rx_condition.wait("SerialResource::read");
+            }
+        }
+    }
 
-                if let Some(ref mut inner) = console.inner {
-                    inner.redraw = true;
+    /// Queue `buf`, waiting while the queue is full
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let port = unsafe { &mut *self.port };
+        let mut i = 0;
+        loop {
+            {
+                let _guard = InterruptGuard::new();
+                while i < buf.len() && port.tx.len() < TX_SIZE {
+                    port.tx.push_back(buf[i]);
+                    i += 1;
+                }
+                port.transmit();
+                if i >= buf.len() {
+                    return Ok(i);
                 }
-                console.write(b"");
+                port.tx_condition.wait("SerialResource::write");
+            }
+        }
+    }
 
-                c = '\0';
-                sc = 0;
-            } else if c == '\x1B' {
-                self.escape = true;
-                c = '\0';
-            } else if c == '\r' {
-                c = '\n';
-            } else if c == '\x7F' {
-                c = '\0';
-                sc = event::K_BKSP;
+    /// Wait until what was queued was sent
+    fn sync(&mut self) -> Result<()> {
+        let port = unsafe { &mut *self.port };
+        loop {
+            {
+                let _guard = InterruptGuard::new();
+                if port.tx.is_empty() {
+                    return Ok(());
+                }
+                port.tx_condition.wait("SerialResource::sync");
             }
+        }
+    }
+}
+
+impl Drop for SerialResource {
+    fn drop(&mut self) {
+        unsafe { (*self.port).readers -= 1 };
+    }
+}
+
+/// The list of ports, or the settings of one
+pub struct SerialConfigResource {
+    port: Option<*mut SerialPort>,
+    data: Vec<u8>,
+    seek: usize,
+}
+
+impl Resource for SerialConfigResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box SerialConfigResource {
+            port: self.port,
+            data: self.data.clone(),
+            seek: self.seek,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = match self.port {
+            Some(port) => format!("serial:/{}/config", ports().iter().position(|&p| p == port).unwrap_or(0)),
+            None => "serial:".to_string(),
+        };
+
+        for (b, p) in buf.iter_mut().zip(path.bytes()) {
+            *b = p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut i = 0;
+        while i < buf.len() && self.seek < self.data.len() {
+            buf[i] = self.data[self.seek];
+            i += 1;
+            self.seek += 1;
+        }
+        Ok(i)
+    }
 
-            if c != '\0' || sc != 0 {
-                let key_event = event::KeyEvent {
-                    character: c,
-                    scancode: sc,
-                    pressed: true,
-                };
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let port = match self.port {
+            Some(port) => unsafe { &mut *port },
+            None => return Err(Error::new(EINVAL)),
+        };
 
-                console.event(key_event.to_event());
+        let string = str::from_utf8(buf).unwrap_or("").trim();
+        let mut config = port.config;
+        let mut debug = port.debug;
+        for setting in string.split_whitespace() {
+            let mut parts = setting.splitn(2, '=');
+            let (name, value) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+            let number = if ! value.is_empty() && value.chars().all(|c| c.is_digit(10)) {
+                Some(value.to_num())
+            } else {
+                None
+            };
+            match (name, number) {
+                ("baud", Some(baud)) => config.baud = baud as u32,
+                ("data", Some(bits)) => config.data_bits = bits as u8,
+                ("stop", Some(bits)) => config.stop_bits = bits as u8,
+                ("parity", None) if Parity::parse(value).is_some() => config.parity = Parity::parse(value).unwrap(),
+                ("debug", None) if value == "on" => debug = true,
+                ("debug", None) if value == "off" => debug = false,
+                _ => return Err(Error::new(EINVAL)),
             }
         }
+        if ! config.valid() {
+            return Err(Error::new(EINVAL));
+        }
+
+        port.configure(config);
+        port.debug = debug;
+        self.data = port.status().into_bytes();
+        self.seek = 0;
+
+        Ok(buf.len())
+    }
+
+    fn seek(&mut self, pos: ResourceSeek) -> Result<usize> {
+        self.seek = match pos {
+            ResourceSeek::Start(offset) => cmp::min(self.data.len(), offset),
+            ResourceSeek::Current(offset) => cmp::max(0, cmp::min(self.data.len() as isize, self.seek as isize + offset)) as usize,
+            ResourceSeek::End(offset) => cmp::max(0, cmp::min(self.data.len() as isize, self.data.len() as isize + offset)) as usize,
+        };
+        Ok(self.seek)
     }
 }
@@ -2,106 +2,350 @@ extern crate ransid;
 
 use alloc::boxed::Box;
 
-use collections::String;
+use collections::{String, Vec};
 
-use common::debug::SerialConsole;
-use common::event::{self, Event, EventOption};
+use drivers::serial;
+use common::event::{self, Event, EventOption, KeyEvent};
 
-use core::mem;
+use core::{cmp, mem};
 
 use graphics::color::Color;
 use graphics::display::Display;
 
 use sync::WaitQueue;
 
+use super::focus::Focus;
+use super::log::InterruptGuard;
+use super::scrollback::Scrollback;
+
+/// The virtual consoles, switched with Ctrl+Alt and F1 to F4
+pub const CONSOLES: usize = 4;
+
+/// The modifier keys held, tracked from their key events
+const MOD_LEFT_SHIFT: u8 = 1;
+const MOD_RIGHT_SHIFT: u8 = 2;
+const MOD_CTRL: u8 = 4;
+const MOD_ALT: u8 = 8;
+
+/// The colors of lines shown from the scroll-back buffer, which keeps no colors
+const SCROLLBACK_BG: Color = Color::new(0, 0, 0);
+const SCROLLBACK_FG: Color = Color::new(0xE0, 0xE0, 0xE0);
+
+/// The bar Alt+Tab shows across the top of the screen, with the candidate highlighted
+const SWITCHER_HEIGHT: usize = 24;
+const SWITCHER_BG: Color = Color::new(0x20, 0x20, 0x20);
+const SWITCHER_CANDIDATE: Color = Color::new(0x40, 0x60, 0xA0);
+const SWITCHER_FG: Color = Color::new(0xFF, 0xFF, 0xFF);
+
+/// A virtual console, with its own contents, cursor and input
+pub struct Terminal {
+    pub inner: Option<ransid::Console>,
+    pub command: String,
+    pub commands: WaitQueue<String>,
+    /// What was written, to be looked back at with shift and page up
+    pub scrollback: Scrollback,
+    /// The lines the view is scrolled back, 0 while it shows the live console
+    pub scroll: usize,
+}
+
+impl Terminal {
+    fn new(display: &Option<Box<Display>>) -> Terminal {
+        let inner = display.as_ref().map(|display| ransid::Console::new(display.width/8, display.height/16));
+        let width = inner.as_ref().map_or(80, |inner| inner.w);
+        Terminal {
+            inner: inner,
+            command: String::new(),
+            commands: WaitQueue::new(),
+            scrollback: Scrollback::new(width),
+            scroll: 0,
+        }
+    }
+
+    /// Size the terminal to `display`, which clears it, the scroll-back buffer keeps what it showed
+    fn resize(&mut self, display: &Display) {
+        self.inner = Some(ransid::Console::new(display.width/8, display.height/16));
+        self.scroll = 0;
+    }
+
+    /// Mark every row changed, so the next draw repaints all of it
+    fn invalidate(&mut self) {
+        if let Some(ref mut inner) = self.inner {
+            for changed in inner.changed.iter_mut() {
+                *changed = true;
+            }
+            inner.redraw = true;
+        }
+    }
+
+    fn send_command(&mut self, reason: &str) {
+        let mut command = String::new();
+        mem::swap(&mut self.command, &mut command);
+        self.commands.send(command, reason);
+    }
+
+    /// Add a pressed key to the command being typed, returning the byte to echo
+    fn input(&mut self, key_event: &KeyEvent) -> Option<u8> {
+        let raw_mode = if let Some(ref inner) = self.inner {
+            inner.raw_mode
+        } else {
+            false
+        };
+
+        if raw_mode {
+            match key_event.scancode {
+                event::K_BKSP => self.command.push_str("\x7F"),
+                event::K_UP => self.command.push_str("\x1B[A"),
+                event::K_DOWN => self.command.push_str("\x1B[B"),
+                event::K_RIGHT => self.command.push_str("\x1B[C"),
+                event::K_LEFT => self.command.push_str("\x1B[D"),
+                event::K_HOME => self.command.push_str("\x1B[H"),
+                event::K_END => self.command.push_str("\x1B[F"),
+                event::K_DEL => self.command.push_str("\x1B[3~"),
+                event::K_PGUP => self.command.push_str("\x1B[5~"),
+                event::K_PGDN => self.command.push_str("\x1B[6~"),
+                _ => if let Some(c) = key_event.character {
+                    self.command.push(c);
+                },
+            }
+
+            if ! self.command.is_empty() {
+                self.send_command("Console::event command (raw)");
+            }
+            None
+        } else {
+            match key_event.scancode {
+                event::K_BKSP => if ! self.command.is_empty() {
+                    if let Some(ref mut inner) = self.inner {
+                        inner.redraw = true;
+                    }
+
+                    self.command.pop();
+                    Some(8)
+                } else {
+                    None
+                },
+                _ => if let Some(c) = key_event.character {
+                    if let Some(ref mut inner) = self.inner {
+                        inner.redraw = true;
+                    }
+
+                    self.command.push(c);
+
+                    if c == '\n' {
+                        self.send_command("Console::event command (not raw)");
+                    }
+                    Some(c as u8)
+                } else {
+                    None
+                },
+            }
+        }
+    }
+}
+
+/// The text console, drawn to the display while no window manager has it
+///
+/// Only the active terminal is drawn and gets the keyboard, the others keep what is written to them until
+/// they are switched to.
 pub struct Console {
     pub display: Option<Box<Display>>,
-    pub inner: Option<ransid::Console>,
     pub draw: bool,
-    pub command: String,
-    pub commands: WaitQueue<String>
+    /// The files a display manager has `display:manager` open by, the display is its while there are any
+    pub managers: usize,
+    pub terminals: Vec<Terminal>,
+    pub active: usize,
+    modifiers: u8,
 }
 
 impl Console {
     pub fn new() -> Console {
         let display_option = Display::root();
-        let inner_option = if let Some(ref display) = display_option {
-            Some(ransid::Console::new(display.width/8, display.height/16))
-        } else {
-            None
-        };
+        let mut terminals = Vec::new();
+        for _ in 0..CONSOLES {
+            terminals.push(Terminal::new(&display_option));
+        }
         Console {
             display: display_option,
-            inner: inner_option,
             draw: false,
-            command: String::new(),
-            commands: WaitQueue::new()
+            managers: 0,
+            terminals: terminals,
+            active: 0,
+            modifiers: 0,
+        }
+    }
+
+    /// The terminal that is shown
+    pub fn active_mut(&mut self) -> &mut Terminal {
+        &mut self.terminals[self.active]
+    }
+
+    /// Show terminal `i`, repainting all of it
+    pub fn switch(&mut self, i: usize) {
+        if i != self.active && i < self.terminals.len() {
+            self.active = i;
+            self.active_mut().invalidate();
+            self.draw();
+        }
+    }
+
+    /// Switch the display to `width` by `height`, sizing every terminal to it, returning whether it did
+    ///
+    /// The active terminal is drawn again if the console has the display, so whatever owned it, the
+    /// screen can be read in the new mode.
+    pub fn set_mode(&mut self, width: usize, height: usize) -> bool {
+        let _guard = InterruptGuard::new();
+
+        match self.display {
+            Some(ref mut display) => if ! display.set_mode(width, height) {
+                return false;
+            },
+            None => return false,
+        }
+
+        if let Some(ref display) = self.display {
+            for terminal in self.terminals.iter_mut() {
+                terminal.resize(display);
+            }
+        }
+        if self.draw {
+            self.active_mut().invalidate();
+            self.draw();
+        }
+        true
+    }
+
+    /// Give the display to `focus`, a terminal or the display manager if there is one
+    pub fn focus(&mut self, focus: Focus) {
+        match focus {
+            Focus::Terminal(i) => if i < self.terminals.len() {
+                self.draw = true;
+                self.active = i;
+            },
+            Focus::Display => if self.managers > 0 {
+                self.draw = false;
+            },
         }
     }
 
+    /// Repaint all of the active terminal, if the console has the display
+    pub fn redraw(&mut self) {
+        if self.draw {
+            self.active_mut().invalidate();
+            self.draw();
+        }
+    }
+
+    /// Show the targets of Alt+Tab across the top of the screen, the one at `candidate` highlighted
+    ///
+    /// It is drawn over whatever has the display, which repaints when the switcher closes.
+    pub fn show_switcher(&mut self, targets: &[Focus], candidate: usize) {
+        if let Some(ref mut display) = self.display {
+            display.hide_cursor();
+            display.rect(0, 0, display.width, SWITCHER_HEIGHT, SWITCHER_BG);
+
+            let mut x = 8;
+            for (i, target) in targets.iter().enumerate() {
+                let name = match *target {
+                    Focus::Terminal(terminal) => format!("Console {}", terminal + 1),
+                    Focus::Display => String::from("Display manager"),
+                };
+                let width = (name.len() + 2) * 8;
+                if i == candidate {
+                    display.rect(x, 2, width, SWITCHER_HEIGHT - 4, SWITCHER_CANDIDATE);
+                }
+                for (j, c) in name.chars().enumerate() {
+                    display.char(x + 8 + j * 8, 4, c, SWITCHER_FG);
+                }
+                x += width + 8;
+            }
+
+            display.flip_rows(0, cmp::min(SWITCHER_HEIGHT, display.height));
+        }
+    }
+
+    /// Keep track of the modifier keys, returning whether `key_event` was one
+    fn modifier_key(&mut self, key_event: &KeyEvent) -> bool {
+        let modifier = match key_event.scancode {
+            event::K_LEFT_SHIFT => MOD_LEFT_SHIFT,
+            event::K_RIGHT_SHIFT => MOD_RIGHT_SHIFT,
+            event::K_CTRL => MOD_CTRL,
+            event::K_ALT => MOD_ALT,
+            _ => return false,
+        };
+        if key_event.pressed {
+            self.modifiers |= modifier;
+        } else {
+            self.modifiers &= ! modifier;
+        }
+        true
+    }
+
+    /// Switch terminals with Ctrl+Alt and a function key, returning whether the key was taken
+    fn switch_key(&mut self, key_event: &KeyEvent) -> bool {
+        if ! key_event.pressed || self.modifiers & (MOD_CTRL | MOD_ALT) != MOD_CTRL | MOD_ALT {
+            return false;
+        }
+
+        let i = match key_event.scancode {
+            event::K_F1 => 0,
+            event::K_F2 => 1,
+            event::K_F3 => 2,
+            event::K_F4 => 3,
+            _ => return false,
+        };
+        self.switch(i);
+        true
+    }
+
+    /// Move the view through the scroll-back buffer with shift and page up or down, returning whether
+    /// the key was taken
+    ///
+    /// Any other key that is pressed brings the view back to the live console, and is passed on.
+    fn scroll_key(&mut self, key_event: &KeyEvent) -> bool {
+        let shift = self.modifiers & (MOD_LEFT_SHIFT | MOD_RIGHT_SHIFT) != 0;
+        let (h, scroll, len) = {
+            let terminal = self.active_mut();
+            match terminal.inner {
+                Some(ref inner) if key_event.pressed => (inner.h, terminal.scroll, terminal.scrollback.len()),
+                _ => return false,
+            }
+        };
+
+        let scroll = if shift && key_event.scancode == event::K_PGUP {
+            cmp::min(scroll + h / 2, len.saturating_sub(h))
+        } else if shift && key_event.scancode == event::K_PGDN {
+            scroll.saturating_sub(h / 2)
+        } else {
+            if scroll > 0 {
+                self.set_scroll(0);
+            }
+            return false;
+        };
+
+        self.set_scroll(scroll);
+        true
+    }
+
+    /// Show the view `scroll` lines back, redrawing the whole console
+    fn set_scroll(&mut self, scroll: usize) {
+        {
+            let terminal = self.active_mut();
+            terminal.scroll = scroll;
+            terminal.invalidate();
+        }
+        self.draw();
+    }
+
     pub fn event(&mut self, event: Event) {
         match event.to_option() {
             EventOption::Key(key_event) => {
-                if key_event.pressed {
-                    let raw_mode = if let Some(ref inner) = self.inner {
-                        inner.raw_mode
-                    } else {
-                        false
-                    };
-
-                    if raw_mode {
-                        match key_event.scancode {
-                            event::K_BKSP => self.command.push_str("\x7F"),
-                            event::K_UP => self.command.push_str("\x1B[A"),
-                            event::K_DOWN => self.command.push_str("\x1B[B"),
-                            event::K_RIGHT => self.command.push_str("\x1B[C"),
-                            event::K_LEFT => self.command.push_str("\x1B[D"),
-                            event::K_HOME => self.command.push_str("\x1B[H"),
-                            event::K_END => self.command.push_str("\x1B[F"),
-                            event::K_DEL => self.command.push_str("\x1B[3~"),
-                            event::K_PGUP => self.command.push_str("\x1B[5~"),
-                            event::K_PGDN => self.command.push_str("\x1B[6~"),
-                            _ => match key_event.character {
-                                '\0' => {},
-                                c => {
-                                    self.command.push(c);
-                                }
-                            },
-                        }
+                if self.modifier_key(&key_event) || self.switch_key(&key_event) || self.scroll_key(&key_event) {
+                    return;
+                }
 
-                        if ! self.command.is_empty() {
-                            let mut command = String::new();
-                            mem::swap(&mut self.command, &mut command);
-                            self.commands.send(command, "Console::event command (raw)");
-                        }
-                    } else {
-                        match key_event.scancode {
-                            event::K_BKSP => if ! self.command.is_empty() {
-                                if let Some(ref mut inner) = self.inner {
-                                    inner.redraw = true;
-                                }
-
-                                self.write(&[8]);
-                                self.command.pop();
-                            },
-                            _ => match key_event.character {
-                                '\0' => (),
-                                c => {
-                                    if let Some(ref mut inner) = self.inner {
-                                        inner.redraw = true;
-                                    }
-
-                                    self.write(&[c as u8]);
-                                    self.command.push(c);
-
-                                    if c == '\n' {
-                                        let mut command = String::new();
-                                        mem::swap(&mut self.command, &mut command);
-                                        self.commands.send(command, "Console::event command (not raw)");
-                                    }
-                                }
-                            },
-                        }
+                if key_event.pressed {
+                    if let Some(echo) = self.active_mut().input(&key_event) {
+                        self.write(&[echo]);
                     }
                 }
             }
@@ -109,59 +353,102 @@ impl Console {
         }
     }
 
+    /// Write to the terminal that is shown
     pub fn write(&mut self, bytes: &[u8]) {
-        if self.draw && self.inner.is_some() {
-            if let Some(ref mut inner) = self.inner {
-                inner.write(bytes);
-                if inner.redraw {
-                    inner.redraw = false;
-                    if let Some(ref mut display) = self.display {
-                        let mut min = inner.h;
-                        let mut max = 0;
-
-                        for y in 0..inner.h {
-                            if inner.changed[y] {
-                                inner.changed[y] = false;
-
-                                if y < min {
-                                    min = y;
-                                }
-                                if y > max {
-                                    max = y;
-                                }
-
-                                for x in 0..inner.w {
-                                    let block = inner.display[y * inner.w + x];
-                                    let (bg, fg) = if inner.cursor && inner.y == y && inner.x == x {
-                                        (block.fg.data, block.bg.data)
-                                    }else{
-                                        (block.bg.data, block.fg.data)
-                                    };
-                                    display.rect(x * 8, y * 16, 8, 16, Color {
-                                        data: bg
-                                    });
-                                    if block.c != ' ' {
-                                        display.char(x * 8, y * 16, block.c, Color {
-                                            data: fg
-                                        });
-                                    }
-                                    if block.underlined {
-                                        display.rect(x * 8, y * 16 + 14, 8, 1, Color {
-                                            data: fg
-                                        });
-                                    }
-                                }
+        let active = self.active;
+        self.write_to(active, bytes);
+    }
+
+    /// Write to terminal `i`, which is drawn only if it is shown
+    pub fn write_to(&mut self, i: usize, bytes: &[u8]) {
+        // A switch from the keyboard interrupt must not draw a terminal while it is written
+        let _guard = InterruptGuard::new();
+
+        if self.draw && self.terminals[i].inner.is_some() {
+            let scroll = {
+                let terminal = &mut self.terminals[i];
+                terminal.scrollback.write(bytes);
+                if let Some(ref mut inner) = terminal.inner {
+                    inner.write(bytes);
+                }
+                terminal.scroll
+            };
+            // While scrolled back the changes wait for the view to come back
+            if i == self.active && scroll == 0 {
+                self.draw();
+            }
+        } else {
+            serial::console_write(bytes);
+        }
+    }
+
+    /// Draw the rows of the active terminal that changed, or its scroll-back buffer while scrolled back
+    fn draw(&mut self) {
+        let terminal = &mut self.terminals[self.active];
+        if let Some(ref mut inner) = terminal.inner {
+            if ! inner.redraw {
+                return;
+            }
+            if let Some(ref mut display) = self.display {
+                if terminal.scroll > 0 {
+                    let first = terminal.scrollback.len().saturating_sub(inner.h + terminal.scroll);
+                    for y in 0..inner.h {
+                        let line = terminal.scrollback.line(first + y);
+                        display.rect(0, y * 16, inner.w * 8, 16, SCROLLBACK_BG);
+                        for (x, &c) in line.iter().enumerate().take(inner.w) {
+                            if c != ' ' {
+                                display.char(x * 8, y * 16, c, SCROLLBACK_FG);
                             }
                         }
+                    }
+                    display.flip_rows(0, inner.h * 16);
+                    return;
+                }
 
-                        if min <= max {
-                            display.flip_rows(min * 16, (max + 1 - min) * 16);
+                inner.redraw = false;
+
+                let mut min = inner.h;
+                let mut max = 0;
+
+                for y in 0..inner.h {
+                    if inner.changed[y] {
+                        inner.changed[y] = false;
+
+                        if y < min {
+                            min = y;
+                        }
+                        if y > max {
+                            max = y;
+                        }
+
+                        for x in 0..inner.w {
+                            let block = inner.display[y * inner.w + x];
+                            let (bg, fg) = if inner.cursor && inner.y == y && inner.x == x {
+                                (block.fg.data, block.bg.data)
+                            }else{
+                                (block.bg.data, block.fg.data)
+                            };
+                            display.rect(x * 8, y * 16, 8, 16, Color {
+                                data: bg
+                            });
+                            if block.c != ' ' {
+                                display.char(x * 8, y * 16, block.c, Color {
+                                    data: fg
+                                });
+                            }
+                            if block.underlined {
+                                display.rect(x * 8, y * 16 + 14, 8, 1, Color {
+                                    data: fg
+                                });
+                            }
                         }
                     }
                 }
+
+                if min <= max {
+                    display.flip_rows(min * 16, (max + 1 - min) * 16);
+                }
             }
-        } else {
-            SerialConsole::new().write(bytes);
         }
     }
 }
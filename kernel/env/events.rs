@@ -0,0 +1,190 @@
+use collections::{Vec, VecDeque};
+
+use common::event::{Event, EVENT_AUDIO, EVENT_MOUSE, EVENT_SCROLL};
+
+use core::cell::UnsafeCell;
+
+use sync::WaitQueue;
+
+use super::log::InterruptGuard;
+
+/// The events kept for the display manager before the policy in `EventQueue::send` makes room
+pub const EVENT_QUEUE_SIZE: usize = 1024;
+/// The room past `EVENT_QUEUE_SIZE` left for keys and buttons once nothing else can go
+pub const EVENT_QUEUE_RESERVE: usize = 256;
+
+/// What the queue did to stay within its size
+#[derive(Copy, Clone)]
+pub struct EventStats {
+    /// Mouse moves and scrolls merged into one queued before them
+    pub coalesced: u64,
+    pub dropped_moves: u64,
+    pub dropped_scrolls: u64,
+    pub dropped_audio: u64,
+    /// Keys, button transitions and the rest that could not be queued even in the reserve
+    pub lost: u64,
+    /// The most events that were queued at once
+    pub peak: usize,
+}
+
+struct Queued {
+    event: Event,
+    /// Never dropped to make room
+    critical: bool,
+}
+
+/// The pointing device of a mouse or scroll event
+fn device(event: &Event) -> i64 {
+    if event.code == EVENT_MOUSE {
+        (event.c >> 8) & 0xFF
+    } else {
+        event.c & 0xFF
+    }
+}
+
+/// Merge a mouse move or scroll into the last queued one of its device, if that can be dropped
+fn coalesce(queue: &mut VecDeque<Queued>, event: &Event) -> bool {
+    let last = queue.iter().rposition(|queued| queued.event.code == event.code && device(&queued.event) == device(event));
+    match last {
+        Some(i) if ! queue[i].critical => {
+            let queued = &mut queue[i].event;
+            if event.code == EVENT_SCROLL {
+                queued.a += event.a;
+                queued.b += event.b;
+            } else {
+                // Mouse positions are absolute, the newest one is all that matters
+                *queued = *event;
+            }
+            true
+        },
+        _ => false,
+    }
+}
+
+/// The bounded queue of events read through `display:`
+///
+/// Once it is full, a mouse move or scroll is merged into the last one of its device, then the oldest
+/// mouse move, scroll or audio event is dropped. Keys, button transitions, hotplug and power events are
+/// never dropped for room, mouse data that comes while only they are queued is dropped instead.
+pub struct EventQueue {
+    queue: WaitQueue<Queued>,
+    stats: UnsafeCell<EventStats>,
+    /// The buttons of the last event of each pointing device, to tell moves from transitions
+    buttons: UnsafeCell<Vec<(i64, i64)>>,
+}
+
+impl EventQueue {
+    pub fn new() -> EventQueue {
+        EventQueue {
+            queue: WaitQueue::new(),
+            stats: UnsafeCell::new(EventStats {
+                coalesced: 0,
+                dropped_moves: 0,
+                dropped_scrolls: 0,
+                dropped_audio: 0,
+                lost: 0,
+                peak: 0,
+            }),
+            buttons: UnsafeCell::new(Vec::new()),
+        }
+    }
+
+    pub fn stats(&self) -> EventStats {
+        unsafe { *self.stats.get() }
+    }
+
+    pub fn len(&self) -> usize {
+        let _guard = InterruptGuard::new();
+        unsafe { self.queue.inner() }.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether `event` must not be dropped, mouse events are if their buttons changed
+    fn critical(&self, event: &Event) -> bool {
+        match event.code {
+            EVENT_MOUSE => {
+                let device = device(event);
+                let buttons = event.c & 0b111;
+                let last = unsafe { &mut *self.buttons.get() };
+                match last.iter().position(|&(last_device, _)| last_device == device) {
+                    Some(i) => {
+                        let changed = last[i].1 != buttons;
+                        last[i].1 = buttons;
+                        changed
+                    },
+                    None => {
+                        last.push((device, buttons));
+                        buttons != 0
+                    },
+                }
+            },
+            EVENT_SCROLL | EVENT_AUDIO => false,
+            _ => true,
+        }
+    }
+
+    fn dropped(&self, event: &Event) {
+        let stats = unsafe { &mut *self.stats.get() };
+        match event.code {
+            EVENT_MOUSE => stats.dropped_moves += 1,
+            EVENT_SCROLL => stats.dropped_scrolls += 1,
+            _ => stats.dropped_audio += 1,
+        }
+    }
+
+    pub fn send(&self, event: Event, reason: &str) {
+        {
+            let _guard = InterruptGuard::new();
+            let queue = unsafe { self.queue.inner() };
+            let stats = unsafe { &mut *self.stats.get() };
+            let critical = self.critical(&event);
+
+            if queue.len() >= EVENT_QUEUE_SIZE {
+                if ! critical && (event.code == EVENT_MOUSE || event.code == EVENT_SCROLL) && coalesce(queue, &event) {
+                    stats.coalesced += 1;
+                    return;
+                }
+
+                if let Some(i) = queue.iter().position(|queued| ! queued.critical) {
+                    if let Some(queued) = queue.remove(i) {
+                        self.dropped(&queued.event);
+                    }
+                } else if ! critical {
+                    self.dropped(&event);
+                    return;
+                } else if queue.len() >= EVENT_QUEUE_SIZE + EVENT_QUEUE_RESERVE {
+                    stats.lost += 1;
+                    return;
+                }
+            }
+
+            queue.push_back(Queued {
+                event: event,
+                critical: critical,
+            });
+            if queue.len() > stats.peak {
+                stats.peak = queue.len();
+            }
+        }
+        self.queue.condition.notify(reason);
+    }
+
+    /// The oldest event, if there is one
+    pub fn try_receive(&self) -> Option<Event> {
+        let _guard = InterruptGuard::new();
+        unsafe { self.queue.inner() }.pop_front().map(|queued| queued.event)
+    }
+
+    /// The oldest event, waiting for one
+    pub fn receive(&self, reason: &str) -> Event {
+        loop {
+            if let Some(event) = self.try_receive() {
+                return event;
+            }
+            self.queue.condition.wait(reason);
+        }
+    }
+}
@@ -0,0 +1,460 @@
+use collections::Vec;
+
+use common::event::{Event, EventOption, FocusEvent, KeyEvent, MouseEvent, RedrawEvent, EVENT_KEY, K_ALT, K_CTRL, K_ESC,
+                    K_LEFT_SHIFT, K_RIGHT_SHIFT, K_TAB};
+
+use core::cmp;
+
+use drivers::kb_layouts::layouts::{self, Layout};
+
+use super::log::InterruptGuard;
+
+/// The modifiers that are pressed again for a new focus while they are held
+const MODIFIERS: [u8; 4] = [K_LEFT_SHIFT, K_RIGHT_SHIFT, K_CTRL, K_ALT];
+
+/// Where keyboard and mouse input goes
+#[derive(Copy, Clone, PartialEq)]
+pub enum Focus {
+    /// A terminal of the console, which takes only keys
+    Terminal(usize),
+    /// The display manager, reading `display:`
+    Display,
+}
+
+/// The keyboard state of one focus, kept while it does not have the focus
+struct InputState {
+    focus: Focus,
+    /// The layout asked for, instead of the default
+    layout: Option<Layout>,
+    /// The dead key pressed, waiting for the key it changes
+    dead: Option<KeyEvent>,
+}
+
+/// Input routing, which releases every key and button that went down in a focus in that same focus
+///
+/// Otherwise a program that loses the focus while a key is held, as Alt is for Alt+Tab, never sees it
+/// released. Each focus has its own layout and dead key, so a letter typed after switching away from a
+/// dead key is the plain letter, and the dead key applies again on coming back.
+pub struct Router {
+    focus: Focus,
+    /// The keys down in the focus, in the order they were pressed
+    keys: Vec<KeyEvent>,
+    /// The last mouse event, while it had a button down
+    buttons: Option<MouseEvent>,
+    /// The layout of every focus that has not asked for another
+    default_layout: Layout,
+    states: Vec<InputState>,
+}
+
+impl Router {
+    pub fn new(focus: Focus) -> Router {
+        Router {
+            focus: focus,
+            keys: Vec::new(),
+            buttons: None,
+            default_layout: Layout::English,
+            states: Vec::new(),
+        }
+    }
+
+    /// Where the last event went
+    pub fn focus(&self) -> Focus {
+        self.focus
+    }
+
+    pub fn default_layout(&self) -> Layout {
+        self.default_layout
+    }
+
+    pub fn set_default_layout(&mut self, layout: Layout) {
+        self.default_layout = layout;
+    }
+
+    /// The layout the keys of `focus` are typed with
+    pub fn layout(&self, focus: Focus) -> Layout {
+        self.states.iter().find(|state| state.focus == focus).and_then(|state| state.layout).unwrap_or(self.default_layout)
+    }
+
+    /// Type with `layout` in `focus`, or the default if it is `None`, dropping a dead key typed with the last
+    pub fn set_layout(&mut self, focus: Focus, layout: Option<Layout>) {
+        let state = self.state(focus);
+        state.layout = layout;
+        state.dead = None;
+    }
+
+    /// Forget the layout and dead key of a focus that is gone
+    pub fn forget(&mut self, focus: Focus) {
+        self.states.retain(|state| state.focus != focus);
+    }
+
+    fn state(&mut self, focus: Focus) -> &mut InputState {
+        if let Some(i) = self.states.iter().position(|state| state.focus == focus) {
+            return &mut self.states[i];
+        }
+        self.states.push(InputState {
+            focus: focus,
+            layout: None,
+            dead: None,
+        });
+        self.states.last_mut().unwrap()
+    }
+
+    /// Hold back a dead key of the layout of `focus` until the next key, which it is combined with
+    ///
+    /// A key it does not combine with follows the dead key typed on its own.
+    fn compose(&mut self, focus: Focus, key_event: KeyEvent, deliveries: &mut Vec<(Focus, KeyEvent)>) -> KeyEvent {
+        let character = match key_event.character {
+            Some(character) if key_event.pressed => character,
+            _ => return key_event,
+        };
+        let layout = self.layout(focus);
+        let state = self.state(focus);
+
+        match state.dead.take() {
+            Some(dead) => {
+                let dead_character = dead.character.unwrap_or(' ');
+                match layouts::compose(dead_character, character) {
+                    Some(composed) => KeyEvent {
+                        character: Some(composed),
+                        .. key_event
+                    },
+                    None => {
+                        deliveries.push((focus, dead));
+                        key_event
+                    },
+                }
+            },
+            None => if layouts::is_dead_key(&layout, character) {
+                state.dead = Some(key_event);
+                KeyEvent {
+                    character: None,
+                    .. key_event
+                }
+            } else {
+                key_event
+            },
+        }
+    }
+
+    /// The events to deliver for `event` now that `focus` has the focus, each with where it goes
+    ///
+    /// A release of a key pressed in another focus was already delivered there, and is dropped.
+    pub fn route(&mut self, focus: Focus, event: Event) -> Vec<(Focus, Event)> {
+        let mut deliveries = self.refocus(focus);
+        let mut event = event;
+
+        match event.to_option() {
+            EventOption::Key(key_event) => {
+                let held = self.keys.iter().position(|key| key.scancode == key_event.scancode);
+                if key_event.pressed {
+                    if held.is_none() {
+                        self.keys.push(key_event);
+                    }
+                } else if let Some(i) = held {
+                    self.keys.remove(i);
+                } else {
+                    return deliveries;
+                }
+
+                // A dead key typed on its own goes first, both marked as the key was
+                let synthetic = event.is_synthetic();
+                let mark = |event: Event| if synthetic { event.synthetic() } else { event };
+                let mut dead = Vec::new();
+                let key_event = self.compose(focus, key_event, &mut dead);
+                for (target, key) in dead {
+                    deliveries.push((target, mark(key.to_event())));
+                }
+                event = mark(key_event.to_event());
+            },
+            EventOption::Mouse(mouse_event) => {
+                self.buttons = if mouse_event.left_button || mouse_event.middle_button || mouse_event.right_button {
+                    Some(mouse_event)
+                } else {
+                    None
+                };
+            },
+            _ => (),
+        }
+
+        deliveries.push((focus, event));
+        deliveries
+    }
+
+    /// Move to `focus`, releasing what is held in the old one and pressing the held modifiers in the new
+    fn refocus(&mut self, focus: Focus) -> Vec<(Focus, Event)> {
+        let mut deliveries = Vec::new();
+        if focus == self.focus {
+            return deliveries;
+        }
+        let old = self.focus;
+        self.focus = focus;
+
+        let mut keys = Vec::new();
+        for key in self.keys.drain(..) {
+            deliveries.push((old, KeyEvent {
+                pressed: false,
+                .. key
+            }.to_event()));
+            if MODIFIERS.contains(&key.scancode) {
+                keys.push(key);
+            }
+        }
+
+        if let Some(mouse) = self.buttons.take() {
+            deliveries.push((old, MouseEvent {
+                left_button: false,
+                middle_button: false,
+                right_button: false,
+                .. mouse
+            }.to_event()));
+        }
+
+        for key in keys.iter() {
+            deliveries.push((focus, key.to_event()));
+        }
+        self.keys = keys;
+
+        deliveries
+    }
+}
+
+/// What the switcher did with a key
+#[derive(Copy, Clone, PartialEq)]
+pub enum Switch {
+    /// The key is not for the switcher, and is routed on
+    Pass,
+    /// The switcher took Tab, and shows the candidate at this index of its list
+    Candidate(usize),
+    /// The switcher took the release of a key it handled
+    Taken,
+    /// The switcher took Escape, and closed without a change
+    Cancel,
+    /// Alt was released with the switcher open, the focus goes to the target, and the release is routed on
+    Commit(Focus),
+}
+
+/// Alt+Tab, which cycles through the focus targets, the most recently used first
+///
+/// Tab with Alt held opens the switcher on the target used before the focused one, each Tab after moves
+/// on and Shift+Tab back. Releasing Alt moves the focus to the candidate.
+pub struct Switcher {
+    /// The targets, the focused one first
+    targets: Vec<Focus>,
+    /// The index of the candidate while the switcher is open
+    candidate: Option<usize>,
+    alt: bool,
+    shift: u8,
+}
+
+impl Switcher {
+    pub fn new(targets: Vec<Focus>) -> Switcher {
+        Switcher {
+            targets: targets,
+            candidate: None,
+            alt: false,
+            shift: 0,
+        }
+    }
+
+    /// The targets, the focused one first
+    pub fn targets(&self) -> &[Focus] {
+        &self.targets
+    }
+
+    /// The index of the candidate in the targets, while the switcher is open
+    pub fn candidate(&self) -> Option<usize> {
+        self.candidate
+    }
+
+    /// Move `focus` to the front of the list, adding it if it was not in it
+    ///
+    /// Called with the focus of every event, so a change from anywhere, as Ctrl+Alt and a function key,
+    /// is kept in the order.
+    pub fn focused(&mut self, focus: Focus) {
+        if self.targets.first() == Some(&focus) {
+            return;
+        }
+        if let Some(i) = self.targets.iter().position(|&target| target == focus) {
+            self.targets.remove(i);
+            if let Some(candidate) = self.candidate {
+                if candidate < i {
+                    self.candidate = Some(candidate + 1);
+                }
+            }
+        }
+        self.targets.insert(0, focus);
+    }
+
+    /// Take out a target that is gone, keeping the candidate if it was another
+    pub fn remove(&mut self, focus: Focus) {
+        if let Some(i) = self.targets.iter().position(|&target| target == focus) {
+            self.targets.remove(i);
+            if let Some(candidate) = self.candidate {
+                self.candidate = if self.targets.len() < 2 {
+                    None
+                } else if candidate > i || candidate == self.targets.len() {
+                    Some(candidate - 1)
+                } else {
+                    Some(candidate)
+                };
+            }
+        }
+    }
+
+    pub fn key(&mut self, key_event: &KeyEvent) -> Switch {
+        match key_event.scancode {
+            K_ALT => {
+                self.alt = key_event.pressed;
+                if ! key_event.pressed {
+                    if let Some(candidate) = self.candidate.take() {
+                        return Switch::Commit(self.targets[candidate]);
+                    }
+                }
+            },
+            K_LEFT_SHIFT | K_RIGHT_SHIFT => {
+                let bit = if key_event.scancode == K_LEFT_SHIFT { 1 } else { 2 };
+                if key_event.pressed {
+                    self.shift |= bit;
+                } else {
+                    self.shift &= ! bit;
+                }
+            },
+            K_TAB if self.alt && self.targets.len() > 1 => {
+                if key_event.pressed {
+                    let len = self.targets.len();
+                    let candidate = match self.candidate {
+                        Some(candidate) if self.shift != 0 => (candidate + len - 1) % len,
+                        Some(candidate) => (candidate + 1) % len,
+                        None if self.shift != 0 => len - 1,
+                        None => 1,
+                    };
+                    self.candidate = Some(candidate);
+                    return Switch::Candidate(candidate);
+                } else if self.candidate.is_some() {
+                    return Switch::Taken;
+                }
+            },
+            K_ESC if self.candidate.is_some() => {
+                if key_event.pressed {
+                    self.candidate = None;
+                    return Switch::Cancel;
+                }
+                return Switch::Taken;
+            },
+            _ => (),
+        }
+        Switch::Pass
+    }
+}
+
+/// Pass input from a driver to the console or the display manager, whichever has the focus
+///
+/// Alt+Tab is taken by the switcher first. The display manager is told when it gains or loses the
+/// focus, after the keys held in it are released.
+pub fn send(event: Event) {
+    // The PS/2 events come from interrupts, the USB ones from the driver contexts
+    let _guard = InterruptGuard::new();
+
+    let console = unsafe { &mut *::env().console.get() };
+    let switcher = unsafe { &mut *::env().switcher.get() };
+    let router = unsafe { &mut *::env().focus.get() };
+
+    if let EventOption::Key(key_event) = event.to_option() {
+        match switcher.key(&key_event) {
+            Switch::Pass => (),
+            Switch::Taken => return,
+            Switch::Candidate(candidate) => {
+                console.show_switcher(switcher.targets(), candidate);
+                return;
+            },
+            Switch::Cancel => {
+                repaint(console, router.focus());
+                return;
+            },
+            Switch::Commit(target) => {
+                console.focus(target);
+                repaint(console, target);
+            },
+        }
+    }
+
+    let focus = current(console);
+    switcher.focused(focus);
+
+    let old = router.focus();
+    if focus != old && focus == Focus::Display {
+        ::env().events.send(FocusEvent {
+            focused: true,
+        }.to_event(), "focus::send");
+    }
+
+    for (target, event) in router.route(focus, event) {
+        match target {
+            Focus::Terminal(_) => if event.code == EVENT_KEY {
+                console.event(event);
+            },
+            Focus::Display => {
+                // The kernel draws the cursor, so it follows the mouse however busy the display manager is
+                if let EventOption::Mouse(mouse) = event.to_option() {
+                    if let Some(ref mut display) = console.display {
+                        display.show_cursor(cmp::max(0, mouse.x) as usize, cmp::max(0, mouse.y) as usize);
+                    }
+                }
+                ::env().events.send(event, "focus::send");
+            },
+        }
+    }
+
+    if focus != old && old == Focus::Display {
+        ::env().events.send(FocusEvent {
+            focused: false,
+        }.to_event(), "focus::send");
+    }
+}
+
+/// Where input goes now, the terminal shown or the display manager
+fn current(console: &::env::console::Console) -> Focus {
+    if console.draw {
+        Focus::Terminal(console.active)
+    } else {
+        Focus::Display
+    }
+}
+
+/// The layout the drivers type the keys with, that of the focus
+pub fn layout() -> Layout {
+    let _guard = InterruptGuard::new();
+    let console = unsafe { & *::env().console.get() };
+    unsafe { & *::env().focus.get() }.layout(current(console))
+}
+
+/// Type with `layout` where the focus is, or the default if it is `None`
+pub fn set_layout(layout: Option<Layout>) {
+    let _guard = InterruptGuard::new();
+    let console = unsafe { & *::env().console.get() };
+    unsafe { &mut *::env().focus.get() }.set_layout(current(console), layout);
+}
+
+/// Draw `focus` again over the switcher, the console itself or the display manager when told to
+fn repaint(console: &mut ::env::console::Console, focus: Focus) {
+    match focus {
+        Focus::Terminal(_) => console.redraw(),
+        Focus::Display => ::env().events.send(RedrawEvent.to_event(), "focus::repaint"),
+    }
+}
+
+/// The display manager closed the display, which the console takes back
+pub fn manager_closed() {
+    let _guard = InterruptGuard::new();
+    let console = unsafe { &mut *::env().console.get() };
+    let switcher = unsafe { &mut *::env().switcher.get() };
+
+    switcher.remove(Focus::Display);
+    unsafe { &mut *::env().focus.get() }.forget(Focus::Display);
+    let active = console.active;
+    console.focus(Focus::Terminal(active));
+    console.redraw();
+    if let Some(candidate) = switcher.candidate() {
+        console.show_switcher(switcher.targets(), candidate);
+    }
+}
@@ -0,0 +1,347 @@
+use acpi::madt::IntSourceOverride;
+
+use arch::apic::{IoApic, LocalApic, Polarity, Trigger};
+use arch::mptable::{self, PciInterrupt};
+
+use collections::vec::Vec;
+
+use common::time::Duration;
+
+use drivers::io::{Io, Pio};
+
+/// The lines interrupts are counted and handled on, line `n` is raised on vector `IRQ_VECTOR + n`
+///
+/// Lines 0 to 15 are the ISA IRQs, the only ones the PICs have. With the IOAPIC the lines from 16 are
+/// given to the other GSIs PCI devices are wired to.
+pub const IRQ_LINES: usize = 32;
+/// The lines of the two PICs
+pub const ISA_LINES: usize = 16;
+/// The vector of line 0
+pub const IRQ_VECTOR: u8 = 0x20;
+
+/// What happened on one interrupt line
+#[derive(Copy, Clone, Default)]
+pub struct IrqLine {
+    /// Interrupts raised on the line, spurious ones included
+    pub count: u64,
+    /// Interrupts the PIC raised with nothing in service, on lines 7 and 15
+    pub spurious: u64,
+    /// Interrupts no handler claimed
+    pub unclaimed: u64,
+}
+
+/// Interrupt counts of every line, since boot or since they were last reset
+///
+/// How many each scheme claimed is kept with the scheme in the registry.
+pub struct IrqStats {
+    pub lines: [IrqLine; IRQ_LINES],
+    /// The monotonic time of the last reset
+    pub since: Duration,
+}
+
+impl IrqStats {
+    pub fn new() -> IrqStats {
+        IrqStats {
+            lines: [IrqLine::default(); IRQ_LINES],
+            since: Duration::new(0, 0),
+        }
+    }
+
+    /// Zero the counts of every line and of every handler
+    pub fn reset(&mut self) {
+        self.lines = [IrqLine::default(); IRQ_LINES];
+        self.since = Duration::monotonic();
+        for entry in ::env().schemes.snapshot().iter() {
+            entry.reset_irqs();
+        }
+    }
+}
+
+/// Whether an interrupt on `irq` is spurious, so the PIC has no interrupt in service for it
+///
+/// Only the lowest priority line of each PIC, 7 and 15, gets spurious interrupts. With the IOAPIC the
+/// PICs are masked and cut off from the local APIC, so these lines are ISA IRQs like the rest.
+pub fn spurious(irq: u8) -> bool {
+    if mode() == IrqMode::Apic {
+        return false;
+    }
+
+    let (command, bit) = match irq {
+        7 => (0x20, 1 << 7),
+        15 => (0xA0, 1 << 7),
+        _ => return false,
+    };
+
+    // OCW3, read the in service register on the next read of the command port
+    let mut pic = Pio::<u8>::new(command);
+    pic.write(0x0B);
+    pic.read() & bit == 0
+}
+
+/// What delivers the interrupts of the devices
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum IrqMode {
+    /// The two 8259 PICs, PCI devices interrupt on the ISA IRQ the firmware gave them
+    Pic,
+    /// The IOAPICs of the MADT, through the local APIC
+    Apic,
+}
+
+/// Where a line is raised from with the IOAPIC
+#[derive(Copy, Clone, Debug)]
+pub struct Route {
+    pub gsi: u32,
+    pub trigger: Trigger,
+    pub polarity: Polarity,
+    /// A level triggered ISA line is masked until a device is routed to it, as nothing would handle it
+    pub masked: bool,
+}
+
+struct Router {
+    lapic: LocalApic,
+    ioapics: Vec<IoApic>,
+    /// The APIC ID of the processor every interrupt is sent to
+    destination: u8,
+    overrides: Vec<IntSourceOverride>,
+    pci: Vec<PciInterrupt>,
+    routes: [Option<Route>; IRQ_LINES],
+}
+
+/// Set by `init` if the IOAPIC is used
+static mut ROUTER: Option<Router> = None;
+
+fn router() -> Option<&'static mut Router> {
+    unsafe { ROUTER.as_mut() }
+}
+
+/// The polarity and trigger of MPS INTI flags, as the MADT and the MP table have them, `None` where
+/// they conform to the bus
+fn inti_flags(flags: u16) -> (Option<Polarity>, Option<Trigger>) {
+    let polarity = match flags & 0b11 {
+        0b01 => Some(Polarity::High),
+        0b11 => Some(Polarity::Low),
+        _ => None,
+    };
+    let trigger = match (flags >> 2) & 0b11 {
+        0b01 => Some(Trigger::Edge),
+        0b11 => Some(Trigger::Level),
+        _ => None,
+    };
+    (polarity, trigger)
+}
+
+impl Router {
+    /// The GSI of an ISA IRQ, with the flags of its interrupt source override if it has one
+    fn isa(&self, irq: u8) -> (u32, u16) {
+        match self.overrides.iter().find(|iso| iso.bus_source == 0 && iso.irq_source == irq) {
+            Some(iso) => (iso.gsi, iso.flags),
+            None => (irq as u32, 0),
+        }
+    }
+
+    /// The line `gsi` is routed to
+    fn line_of(&self, gsi: u32) -> Option<usize> {
+        self.routes.iter().position(|route| route.map_or(false, |route| route.gsi == gsi))
+    }
+
+    /// Program the redirection entry of the GSI of `route` for `line`, false if no IOAPIC has the GSI
+    fn program(&mut self, line: usize, route: Route) -> bool {
+        let destination = self.destination;
+        match self.ioapics.iter_mut().find(|ioapic| ioapic.handles(route.gsi)) {
+            Some(ioapic) => {
+                ioapic.route(route.gsi, IRQ_VECTOR + line as u8, destination, route.trigger, route.polarity,
+                             route.masked);
+                self.routes[line] = Some(route);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Route `gsi` for a PCI device and unmask it, on the line it has if another device is on it
+    ///
+    /// A new GSI takes the line of its number if it is free, so lines and GSIs mostly match.
+    fn claim(&mut self, gsi: u32, polarity: Polarity, trigger: Trigger) -> Option<usize> {
+        let line = match self.line_of(gsi) {
+            Some(line) => {
+                if let Some(route) = self.routes[line] {
+                    if route.trigger != trigger || route.polarity != polarity {
+                        syslog_debug!("IRQ: GSI {} on line {} changes from {:?} {:?} to {:?} {:?}",
+                                      gsi, line, route.trigger, route.polarity, trigger, polarity);
+                    }
+                }
+                line
+            },
+            None => if gsi as usize >= ISA_LINES && (gsi as usize) < IRQ_LINES && self.routes[gsi as usize].is_none() {
+                gsi as usize
+            } else {
+                match (ISA_LINES..IRQ_LINES).find(|&line| self.routes[line].is_none()) {
+                    Some(line) => line,
+                    None => return None,
+                }
+            },
+        };
+
+        if self.program(line, Route {
+            gsi: gsi,
+            trigger: trigger,
+            polarity: polarity,
+            masked: false,
+        }) {
+            Some(line)
+        } else {
+            None
+        }
+    }
+}
+
+/// Choose what delivers interrupts, before the drivers ask for their lines
+///
+/// The IOAPIC is used if the MADT has one, unless the kernel is built with `IRQ_MODE` set to `pic`.
+/// Then the PICs are masked and the ISA IRQs routed to their lines through the interrupt source
+/// overrides, edge triggered and active high where none says otherwise.
+pub fn init() {
+    let madt = match ::acpi::tables().and_then(|acpi| acpi.madt.as_ref()) {
+        Some(madt) if ! madt.io_apics.is_empty() => madt,
+        _ => {
+            syslog_info!(" + IRQ: PIC, there is no IOAPIC");
+            return;
+        }
+    };
+
+    if option_env!("IRQ_MODE") == Some("pic") {
+        syslog_info!(" + IRQ: PIC, as IRQ_MODE is pic");
+        return;
+    }
+
+    Pio::<u8>::new(0x21).write(0xFF);
+    Pio::<u8>::new(0xA1).write(0xFF);
+
+    let mut lapic = LocalApic::new(madt.local_apic_address as usize);
+    lapic.enable();
+
+    let mut router = Router {
+        destination: lapic.id(),
+        lapic: lapic,
+        ioapics: madt.io_apics.iter().map(|ioapic| {
+            IoApic::new(ioapic.id, ioapic.address as usize, ioapic.gsi_base)
+        }).collect(),
+        overrides: madt.int_source_overrides.clone(),
+        pci: mptable::pci_interrupts(),
+        routes: [None; IRQ_LINES],
+    };
+
+    syslog_info!(" + IRQ: IOAPIC, to local APIC {}, {} PCI routes in the MP table",
+                 router.destination, router.pci.len());
+    for ioapic in router.ioapics.iter_mut() {
+        syslog_info!("   + IOAPIC {}: {:X} GSIs: {}-{}",
+                     ioapic.id, ioapic.base, ioapic.gsi_base, ioapic.gsi_base + ioapic.inputs - 1);
+        ioapic.mask_all();
+    }
+
+    // Line 2 is the cascade of the PICs, which is never raised
+    for irq in (0..ISA_LINES as u8).filter(|&irq| irq != 2) {
+        let (gsi, flags) = router.isa(irq);
+        if let Some(line) = router.line_of(gsi) {
+            syslog_debug!("IRQ: ISA IRQ {} is on GSI {}, which line {} has", irq, gsi, line);
+            continue;
+        }
+
+        let (polarity, trigger) = inti_flags(flags);
+        let trigger = trigger.unwrap_or(Trigger::Edge);
+        if ! router.program(irq as usize, Route {
+            gsi: gsi,
+            trigger: trigger,
+            polarity: polarity.unwrap_or(Polarity::High),
+            masked: trigger == Trigger::Level,
+        }) {
+            syslog_warning!("IRQ: no IOAPIC has GSI {} of ISA IRQ {}", gsi, irq);
+        }
+    }
+
+    unsafe { ROUTER = Some(router) };
+}
+
+/// What delivers interrupts
+pub fn mode() -> IrqMode {
+    if router().is_some() {
+        IrqMode::Apic
+    } else {
+        IrqMode::Pic
+    }
+}
+
+/// The lines interrupts can come in on, the ISA ones only with the PICs
+pub fn lines() -> usize {
+    match mode() {
+        IrqMode::Pic => ISA_LINES,
+        IrqMode::Apic => IRQ_LINES,
+    }
+}
+
+/// Where `line` is raised from, with the IOAPIC
+pub fn route(line: usize) -> Option<Route> {
+    router().and_then(|router| router.routes.get(line).and_then(|route| *route))
+}
+
+/// The line of INTx `pin`, 0 for INTA#, of a PCI function, that its driver handles interrupts on
+///
+/// `path` is the bus and slot of the function, then of each bridge above it up to the root bus.
+/// With the PICs this is the ISA IRQ the firmware put in the interrupt line register. With the IOAPIC
+/// the MP table is looked in for the pin, and if it has no entry for the bus, for the pin it is
+/// swizzled to on the bus above. Without an entry the firmware's ISA IRQ is taken through the
+/// overrides. What is not overridden is level triggered and active low, as PCI interrupts are.
+pub fn pci_line(path: &[(u8, u8)], pin: u8, firmware_irq: u8) -> u8 {
+    let router = match router() {
+        Some(router) => router,
+        None => return firmware_irq & 0xF,
+    };
+    let (bus, slot) = path[0];
+
+    let mut routed = None;
+    let mut swizzled = pin;
+    for &(parent_bus, parent_slot) in path.iter() {
+        let interrupt = router.pci.iter().find(|interrupt| {
+            interrupt.bus == parent_bus && interrupt.slot == parent_slot && interrupt.pin == swizzled
+        }).cloned();
+        if let Some(interrupt) = interrupt {
+            routed = router.ioapics.iter().find(|ioapic| ioapic.id == interrupt.ioapic).map(|ioapic| {
+                (ioapic.gsi_base + interrupt.input as u32, interrupt.flags)
+            });
+            break;
+        }
+        swizzled = (swizzled + parent_slot) % 4;
+    }
+    if routed.is_none() && (firmware_irq as usize) < ISA_LINES {
+        routed = Some(router.isa(firmware_irq));
+    }
+
+    match routed {
+        Some((gsi, flags)) => {
+            let (polarity, trigger) = inti_flags(flags);
+            match router.claim(gsi, polarity.unwrap_or(Polarity::Low), trigger.unwrap_or(Trigger::Level)) {
+                Some(line) => return line as u8,
+                None => syslog_warning!("IRQ: no line for GSI {} of PCI {}:{} INT{}", gsi, bus, slot, (b'A' + pin) as char),
+            }
+        },
+        None => syslog_warning!("IRQ: no route for PCI {}:{} INT{}", bus, slot, (b'A' + pin) as char),
+    }
+
+    firmware_irq & 0xF
+}
+
+/// Acknowledge the interrupt of `vector`, one of the lines
+///
+/// A level triggered interrupt is raised again from here if the device still asserts it, so this is
+/// sent after the handlers had it stop.
+pub fn eoi(vector: u8) {
+    match router() {
+        Some(router) => router.lapic.eoi(),
+        None => {
+            if vector >= IRQ_VECTOR + 8 {
+                Pio::<u8>::new(0xA0).write(0x20);
+            }
+            Pio::<u8>::new(0x20).write(0x20);
+        },
+    }
+}
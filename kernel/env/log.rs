@@ -1,9 +1,59 @@
 use core::fmt;
 
+use common::time::Duration;
+
+use drivers::debugcon;
+
+use sync::WaitCondition;
+
+/// Interrupts are off while it lives, and put back the way they were when it is dropped
+///
+/// An interrupt handler logging in the middle of an entry would otherwise split it, or move the ends of the
+/// ring under it.
+pub struct InterruptGuard {
+    flags: usize,
+}
+
+impl InterruptGuard {
+    #[cfg(target_arch = "x86")]
+    pub fn new() -> InterruptGuard {
+        let flags: usize;
+        unsafe { asm!("pushfd ; pop $0 ; cli" : "=r"(flags) : : "memory" : "intel", "volatile") };
+        InterruptGuard {
+            flags: flags,
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn new() -> InterruptGuard {
+        let flags: usize;
+        unsafe { asm!("pushfq ; pop $0 ; cli" : "=r"(flags) : : "memory" : "intel", "volatile") };
+        InterruptGuard {
+            flags: flags,
+        }
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        // The interrupt flag
+        if self.flags & 1 << 9 == 1 << 9 {
+            unsafe { asm!("sti" : : : "memory" : "intel", "volatile") };
+        }
+    }
+}
+
+/// The kernel log, a ring of lines that drops the oldest ones once it is full
 pub struct Log {
     pub data: [u8; 65536],
     pub start: usize,
-    pub end: usize
+    pub end: usize,
+    /// The bytes dropped from the start since boot, positions count from the first byte ever logged
+    pub dropped: usize,
+    /// The last line written does not end yet
+    partial: bool,
+    /// Readers waiting for more to be logged
+    pub condition: WaitCondition,
 }
 
 impl Log {
@@ -11,12 +61,16 @@ impl Log {
         Log {
             data: [0; 65536],
             start: 0,
-            end: 0
+            end: 0,
+            dropped: 0,
+            partial: false,
+            condition: WaitCondition::new(),
         }
     }
 
     fn move_start(&mut self) {
         self.start += 1;
+        self.dropped += 1;
         while self.start >= self.data.len() {
             self.start -= self.data.len();
         }
@@ -38,8 +92,30 @@ impl Log {
         }
     }
 
+    /// The bytes in the ring
+    pub fn len(&self) -> usize {
+        (self.end + self.data.len() - self.start) % self.data.len()
+    }
+
+    /// The position after the last byte logged
+    pub fn position(&self) -> usize {
+        self.dropped + self.len()
+    }
+
+    /// Copy what was logged from `pos` on, returning the bytes copied and the position after them
+    ///
+    /// If what was at `pos` was dropped already, the copy starts at the oldest line kept.
+    pub fn read_from(&self, pos: usize, buf: &mut [u8]) -> (usize, usize) {
+        let pos = if pos < self.dropped { self.dropped } else { pos };
+        let count = self.read_at(pos - self.dropped, buf);
+        (count, pos + count)
+    }
+
     pub fn read_at(&self, pos: usize, buf: &mut [u8]) -> usize {
         let mut count = 0;
+        if pos >= self.len() {
+            return count;
+        }
         let mut i = self.start + pos;
         while i >= self.data.len() {
             i -= self.data.len()
@@ -58,15 +134,54 @@ impl Log {
         count
     }
 
+    /// Add bytes to the ring, which are mirrored to the debug console of an emulator
     pub fn write(&mut self, buf: &[u8]) -> usize {
+        debugcon::write(buf);
+
         let mut count = 0;
         for byte in buf.iter() {
             self.data[self.end] = *byte;
             self.move_end();
             count += 1;
         }
+        if let Some(&last) = buf.last() {
+            self.partial = last != b'\n';
+        }
         count
     }
+
+    /// Add a line with the time and `prefix`, after ending a partial line left by debug output
+    pub fn entry(&mut self, prefix: &str, message: fmt::Arguments) {
+        {
+            let _guard = InterruptGuard::new();
+            if self.partial {
+                self.write(b"\n");
+            }
+            let time = Duration::monotonic();
+            let _ = fmt::write(self, format_args!("[{}.{:>03}] {}{}\n", time.secs, time.nanos/1000000, prefix, message));
+        }
+        self.condition.notify("Log::entry");
+    }
+
+    /// Add debug output, which may be part of a line, each line it starts gets the time and a `DEBUG` prefix
+    pub fn debug(&mut self, text: &str) {
+        {
+            let _guard = InterruptGuard::new();
+            for (i, line) in text.split('\n').enumerate() {
+                if i > 0 {
+                    self.write(b"\n");
+                }
+                if ! line.is_empty() {
+                    if ! self.partial {
+                        let time = Duration::monotonic();
+                        let _ = fmt::write(self, format_args!("[{}.{:>03}] DEBUG ", time.secs, time.nanos/1000000));
+                    }
+                    self.write(line.as_bytes());
+                }
+            }
+        }
+        self.condition.notify("Log::debug");
+    }
 }
 
 impl fmt::Write for Log {
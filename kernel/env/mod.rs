@@ -7,25 +7,44 @@ use collections::string::ToString;
 use core::cell::UnsafeCell;
 
 use arch::context::{Context, ContextManager};
-use common::event::Event;
+use arch::timekeeping;
+use common::event::PowerEvent;
 use common::time::Duration;
 use disk::Disk;
-use network::Nic;
-use fs::{KScheme, Resource, Scheme, VecResource};
-use sync::WaitQueue;
+use drivers::pci::PciFunction;
+use fs::{Resource, Scheme, SchemeRegistry, ShutdownStage, VecResource};
 
 use system::error::{Error, Result, ENOENT, EEXIST};
 use system::syscall::{MODE_DIR, O_CREAT};
 
-use self::console::Console;
+use self::console::{Console, CONSOLES};
+use self::events::EventQueue;
+use self::focus::{Focus, Router, Switcher};
+use self::irq::{IrqStats, IRQ_LINES};
 use self::log::Log;
+use self::power::SHUTDOWN_GRACE;
 
 /// The Kernel Console
 pub mod console;
 
+/// The event queue
+pub mod events;
+
+/// Input focus
+pub mod focus;
+
+/// Interrupt statistics
+pub mod irq;
+
 /// The Kernel Log
 pub mod log;
 
+/// Power off
+pub mod power;
+
+/// Console scroll-back
+pub mod scrollback;
+
 /// The kernel environment
 pub struct Environment {
     /// Contexts
@@ -38,21 +57,27 @@ pub struct Environment {
 
     /// Default console
     pub console: UnsafeCell<Console>,
-    /// Disks
-    pub disks: UnsafeCell<Vec<Arc<UnsafeCell<Box<Disk>>>>>,
-    /// Network interfaces
-    pub nics: UnsafeCell<Vec<Box<Nic>>>,
+    /// Disks, numbered by their place. A disk that is removed leaves None, so the others keep their numbers
+    pub disks: UnsafeCell<Vec<Option<Arc<UnsafeCell<Box<Disk>>>>>>,
+    /// PCI functions, and the driver each was given to
+    pub pci: UnsafeCell<Vec<PciFunction>>,
     /// Pending events
-    pub events: WaitQueue<Event>,
+    pub events: EventQueue,
+    /// Where keyboard and mouse input goes
+    pub focus: UnsafeCell<Router>,
+    /// Alt+Tab
+    pub switcher: UnsafeCell<Switcher>,
     /// Futexes
     pub futexes: UnsafeCell<VecDeque<(*mut i32, *mut Context)>>,
     /// Kernel logs
     pub log: UnsafeCell<Log>,
     /// Schemes
-    pub schemes: UnsafeCell<Vec<Box<KScheme>>>,
+    pub schemes: SchemeRegistry,
 
     /// Interrupt stats
     pub interrupts: UnsafeCell<[u64; 256]>,
+    /// Interrupt stats of each IRQ line
+    pub irqs: UnsafeCell<IrqStats>,
 }
 
 impl Environment {
@@ -65,19 +90,53 @@ impl Environment {
 
             console: UnsafeCell::new(Console::new()),
             disks: UnsafeCell::new(Vec::new()),
-            nics: UnsafeCell::new(Vec::new()),
-            events: WaitQueue::new(),
+            pci: UnsafeCell::new(Vec::new()),
+            events: EventQueue::new(),
+            focus: UnsafeCell::new(Router::new(Focus::Display)),
+            switcher: UnsafeCell::new(Switcher::new((0..CONSOLES).map(Focus::Terminal).collect())),
             futexes: UnsafeCell::new(VecDeque::new()),
             log: UnsafeCell::new(Log::new()),
-            schemes: UnsafeCell::new(Vec::new()),
+            schemes: SchemeRegistry::new(),
 
             interrupts: UnsafeCell::new([0; 256]),
+            irqs: UnsafeCell::new(IrqStats::new()),
         }
     }
 
+    /// Pass an interrupt on to every scheme, counting the ones that claim it
     pub fn on_irq(&self, irq: u8) {
-        for mut scheme in unsafe { &mut *self.schemes.get() }.iter_mut() {
-            scheme.on_irq(irq);
+        let mut claimed = false;
+        for entry in self.schemes.snapshot().iter() {
+            if entry.scheme().on_irq(irq) {
+                claimed = true;
+                entry.claimed(irq);
+            }
+        }
+
+        if ! claimed && (irq as usize) < IRQ_LINES {
+            unsafe { &mut *self.irqs.get() }.lines[irq as usize].unclaimed += 1;
+        }
+    }
+
+    /// Shut down for power off or restart, `kind` is `POWER_OFF` or `POWER_REBOOT`
+    ///
+    /// Programs are told first and given `SHUTDOWN_GRACE` to save, then every scheme stops its device,
+    /// the disks last so what the rest wrote reaches the medium.
+    pub fn shutdown(&self, kind: i64) {
+        syslog_info!("Shutting down, programs have {} ms to save", SHUTDOWN_GRACE);
+        self.events.send(PowerEvent {
+            kind: kind,
+            grace: SHUTDOWN_GRACE as i64,
+        }.to_event(), "Environment::shutdown");
+        timekeeping::sleep(SHUTDOWN_GRACE as u64, "Environment::shutdown");
+
+        for &stage in [ShutdownStage::Device, ShutdownStage::Storage].iter() {
+            for entry in self.schemes.snapshot().iter() {
+                if entry.scheme().shutdown_stage() == stage {
+                    let name = if entry.name.is_empty() { format!("#{}", entry.id) } else { entry.name.clone() };
+                    power::power_off(&name, entry.clone());
+                }
+            }
         }
     }
 
@@ -90,28 +149,23 @@ impl Environment {
             if url_path.is_empty() {
                 let mut list = String::new();
 
-                for scheme in unsafe { &mut *self.schemes.get() }.iter() {
-                    let scheme_str = scheme.scheme();
-                    if !scheme_str.is_empty() {
-                        if !list.is_empty() {
-                            list = list + "\n" + scheme_str;
-                        } else {
-                            list = scheme_str.to_string();
-                        }
+                for name in self.schemes.names() {
+                    if !list.is_empty() {
+                        list = list + "\n" + &name;
+                    } else {
+                        list = name;
                     }
                 }
 
                 Ok(box VecResource::new(":".to_string(), list.into_bytes(), MODE_DIR))
             } else if flags & O_CREAT == O_CREAT {
-                for scheme in unsafe { &mut *self.schemes.get() }.iter_mut() {
-                    if scheme.scheme() == url_path {
-                        return Err(Error::new(EEXIST));
-                    }
+                if self.schemes.contains(url_path) {
+                    return Err(Error::new(EEXIST));
                 }
 
                 match Scheme::new(url_path) {
                     Ok((scheme, server)) => {
-                        unsafe { &mut *self.schemes.get() }.push(scheme);
+                        self.schemes.register(scheme);
                         Ok(server)
                     },
                     Err(err) => Err(err)
@@ -120,22 +174,18 @@ impl Environment {
                 Err(Error::new(ENOENT))
             }
         } else {
-            for mut scheme in unsafe { &mut *self.schemes.get() }.iter_mut() {
-                if scheme.scheme() == url_scheme {
-                    return scheme.open(url, flags);
-                }
+            match self.schemes.lookup(url_scheme) {
+                Some(entry) => entry.open(url, flags),
+                None => Err(Error::new(ENOENT)),
             }
-            Err(Error::new(ENOENT))
         }
     }
 
     /// Makes a directory
     pub fn mkdir(&self, url: &str, flags: usize) -> Result<()> {
         if let Some(url_scheme) = url.splitn(2, ":").next() {
-            for mut scheme in unsafe { &mut *self.schemes.get() }.iter_mut() {
-                if scheme.scheme() == url_scheme {
-                    return scheme.mkdir(url, flags);
-                }
+            if let Some(entry) = self.schemes.lookup(url_scheme) {
+                return entry.scheme().mkdir(url, flags);
             }
         }
         Err(Error::new(ENOENT))
@@ -144,10 +194,8 @@ impl Environment {
     /// Remove a directory
     pub fn rmdir(&self, url: &str) -> Result<()> {
         if let Some(url_scheme) = url.splitn(2, ":").next() {
-            for mut scheme in unsafe { &mut *self.schemes.get() }.iter_mut() {
-                if scheme.scheme() == url_scheme {
-                    return scheme.rmdir(url);
-                }
+            if let Some(entry) = self.schemes.lookup(url_scheme) {
+                return entry.scheme().rmdir(url);
             }
         }
         Err(Error::new(ENOENT))
@@ -156,10 +204,8 @@ impl Environment {
     /// Unlink a resource
     pub fn unlink(&self, url: &str) -> Result<()> {
         if let Some(url_scheme) = url.splitn(2, ":").next() {
-            for mut scheme in unsafe { &mut *self.schemes.get() }.iter_mut() {
-                if scheme.scheme() == url_scheme {
-                    return scheme.unlink(url);
-                }
+            if let Some(entry) = self.schemes.lookup(url_scheme) {
+                return entry.scheme().unlink(url);
             }
         }
         Err(Error::new(ENOENT))
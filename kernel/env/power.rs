@@ -0,0 +1,38 @@
+use alloc::arc::Arc;
+
+use arch::context::Context;
+use arch::timekeeping;
+
+use core::cell::UnsafeCell;
+
+use fs::SchemeEntry;
+
+/// Milliseconds programs are given to save after they are told the system is going down
+pub const SHUTDOWN_GRACE: i32 = 3000;
+
+/// Milliseconds a scheme is given to stop its device before it is given up on
+pub const POWER_OFF_TIMEOUT: i32 = 2000;
+
+/// Run the power off hook of the scheme of `entry` in a context of its own, returning whether it finished
+/// in time
+///
+/// A driver stuck on its device is left where it is, so the schemes after it still get to stop theirs.
+pub fn power_off(name: &str, entry: Arc<SchemeEntry>) -> bool {
+    let done = Arc::new(UnsafeCell::new(false));
+    let hook_done = done.clone();
+    Context::spawn(format!("kpower_off {}", name).into(), box move || {
+        entry.scheme().power_off();
+        unsafe { *hook_done.get() = true };
+    });
+
+    let mut waited = 0;
+    while ! unsafe { *done.get() } {
+        if waited >= POWER_OFF_TIMEOUT {
+            syslog_warning!("Shutdown: {} did not stop in {} ms", name, POWER_OFF_TIMEOUT);
+            return false;
+        }
+        timekeeping::sleep(10, "power::power_off");
+        waited += 10;
+    }
+    true
+}
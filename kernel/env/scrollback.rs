@@ -0,0 +1,139 @@
+use collections::{Vec, VecDeque};
+
+use core::{mem, str};
+
+/// The lines kept, those on screen included
+pub const SCROLLBACK_LINES: usize = 500;
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Normal,
+    /// After an ESC
+    Escape,
+    /// In a control sequence, which ends with a byte from `@` to `~`
+    Csi,
+    /// In an operating system command, which ends with BEL or ESC \
+    Osc,
+}
+
+/// The text written to the console, as lines as wide as it, so what scrolled off can be shown again
+///
+/// Escape sequences are skipped, so the lines have no colors, and the output of a program that moves the
+/// cursor around is kept in the order it was written.
+pub struct Scrollback {
+    /// The lines that were ended, oldest first
+    lines: VecDeque<Vec<char>>,
+    /// The line being written, and where the next character goes in it
+    current: Vec<char>,
+    column: usize,
+    width: usize,
+    state: State,
+    /// The start of a character split between writes
+    utf8: Vec<u8>,
+}
+
+impl Scrollback {
+    pub fn new(width: usize) -> Scrollback {
+        Scrollback {
+            lines: VecDeque::new(),
+            current: Vec::new(),
+            column: 0,
+            width: width,
+            state: State::Normal,
+            utf8: Vec::new(),
+        }
+    }
+
+    /// The lines, the one being written included
+    pub fn len(&self) -> usize {
+        self.lines.len() + 1
+    }
+
+    /// Line `i`, counting from the oldest
+    pub fn line(&self, i: usize) -> &[char] {
+        match self.lines.get(i) {
+            Some(line) => &line[..],
+            None => &self.current[..],
+        }
+    }
+
+    fn newline(&mut self) {
+        let mut line = Vec::new();
+        mem::swap(&mut line, &mut self.current);
+        self.lines.push_back(line);
+        while self.lines.len() >= SCROLLBACK_LINES {
+            self.lines.pop_front();
+        }
+        self.column = 0;
+    }
+
+    fn put(&mut self, c: char) {
+        if self.column >= self.width {
+            self.newline();
+        }
+        if self.column < self.current.len() {
+            self.current[self.column] = c;
+        } else {
+            self.current.push(c);
+        }
+        self.column += 1;
+    }
+
+    /// A byte outside of an escape sequence
+    fn text(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.newline(),
+            b'\r' => self.column = 0,
+            0x08 => if self.column > 0 {
+                self.column -= 1;
+            },
+            b'\t' => {
+                self.put(' ');
+                while self.column % 8 != 0 && self.column < self.width {
+                    self.put(' ');
+                }
+            },
+            0 ... 0x1F | 0x7F => (),
+            0x20 ... 0x7E => self.put(byte as char),
+            _ => {
+                self.utf8.push(byte);
+                let c = str::from_utf8(&self.utf8).ok().and_then(|string| string.chars().next());
+                if let Some(c) = c {
+                    self.utf8.clear();
+                    self.put(c);
+                } else if self.utf8.len() >= 4 {
+                    self.utf8.clear();
+                }
+            }
+        }
+    }
+
+    fn byte(&mut self, byte: u8) {
+        let state = self.state;
+        self.state = match state {
+            State::Normal => if byte == 0x1B {
+                State::Escape
+            } else {
+                self.text(byte);
+                State::Normal
+            },
+            State::Escape => match byte {
+                b'[' => State::Csi,
+                b']' => State::Osc,
+                _ => State::Normal,
+            },
+            State::Csi => if byte >= 0x40 && byte <= 0x7E { State::Normal } else { State::Csi },
+            State::Osc => match byte {
+                0x07 => State::Normal,
+                0x1B => State::Escape,
+                _ => State::Osc,
+            },
+        };
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes.iter() {
+            self.byte(byte);
+        }
+    }
+}
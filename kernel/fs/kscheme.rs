@@ -2,18 +2,80 @@ use super::Resource;
 
 use alloc::boxed::Box;
 
+use collections::string::{String, ToString};
+use collections::vec::Vec;
+
 use system::error::{Error, Result, EPERM};
 
+/// When a scheme is told the system is going down, disks go last so what the others did is written out
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ShutdownStage {
+    Device,
+    Storage,
+}
+
+/// The result of one self check a driver ran
+pub struct Check {
+    pub name: String,
+    pub passed: bool,
+    /// What was seen, or what went wrong
+    pub detail: String,
+}
+
+impl Check {
+    pub fn new(name: &str, passed: bool, detail: String) -> Check {
+        Check {
+            name: name.to_string(),
+            passed: passed,
+            detail: detail,
+        }
+    }
+
+    /// Format the result as a line of text
+    pub fn to_string(&self) -> String {
+        format!("{} {}: {}\n", if self.passed { "PASS" } else { "FAIL" }, self.name, self.detail)
+    }
+}
+
 #[allow(unused_variables)]
 pub trait KScheme {
-    fn on_irq(&mut self, irq: u8) {
-
+    /// Handle an interrupt on line `irq`, returning whether the device of the scheme raised it
+    ///
+    /// Every scheme is called for each interrupt, since lines may be shared.
+    fn on_irq(&mut self, irq: u8) -> bool {
+        false
     }
 
     fn scheme(&self) -> &str {
         ""
     }
 
+    /// Called when the scheme is unregistered, the driver cancels what it has in flight, masks the
+    /// interrupts of the device and frees its DMA memory
+    fn shutdown(&mut self) {
+
+    }
+
+    /// The stage of power off the scheme is told in
+    fn shutdown_stage(&self) -> ShutdownStage {
+        ShutdownStage::Device
+    }
+
+    /// Called before the system powers off or restarts, after programs were given time to save, the
+    /// driver stops the device but the scheme stays registered
+    ///
+    /// It runs in a context of its own, and is given up on if it takes too long.
+    fn power_off(&mut self) {
+
+    }
+
+    /// Exercise the device with quick self checks, a scheme without a device has none
+    ///
+    /// The device keeps serving programs afterwards, so a check puts back whatever it changed.
+    fn diagnose(&mut self) -> Vec<Check> {
+        Vec::new()
+    }
+
     fn open(&mut self, path: &str, flags: usize) -> Result<Box<Resource>> {
         Err(Error::new(EPERM))
     }
@@ -1,4 +1,5 @@
-pub use self::kscheme::KScheme;
+pub use self::kscheme::{Check, KScheme, ShutdownStage};
+pub use self::registry::{RegisteredResource, SchemeEntry, SchemeList, SchemeRegistry};
 pub use self::resource::{Resource, ResourceSeek};
 pub use self::scheme::Scheme;
 pub use self::slice_resource::{SliceResource, SliceMutResource};
@@ -7,6 +8,8 @@ pub use self::supervisor_resource::SupervisorResource;
 
 /// Kernel schemes
 pub mod kscheme;
+/// Registry of schemes by name
+pub mod registry;
 /// Internal resource representation
 pub mod resource;
 /// Userspace scheme
@@ -0,0 +1,347 @@
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+
+use collections::string::{String, ToString};
+use collections::vec::Vec;
+
+use common::event::{HotplugEvent, HOTPLUG_SCHEME, IO_CLOSED};
+
+use core::cell::UnsafeCell;
+
+use env::irq::IRQ_LINES;
+use env::log::InterruptGuard;
+
+use system::error::{Error, Result, ENODEV};
+use system::syscall::Stat;
+
+use super::{KScheme, Resource, ResourceSeek};
+
+/// Whether a scheme is still registered, and how many resources were opened on it and not closed
+///
+/// Resources are opened and closed from any context, and dropped in interrupt handlers, so the count is
+/// changed with interrupts disabled.
+struct SchemeState {
+    registered: bool,
+    resources: usize,
+}
+
+fn acquire(state: &UnsafeCell<SchemeState>) {
+    let _guard = InterruptGuard::new();
+    unsafe { (*state.get()).resources += 1 };
+}
+
+fn release(state: &UnsafeCell<SchemeState>) {
+    let _guard = InterruptGuard::new();
+    unsafe { (*state.get()).resources -= 1 };
+}
+
+/// A registered scheme and the name URLs reach it by
+///
+/// Entries are shared by the list of the registry and every snapshot taken of it, so only what is set
+/// when registering is plain, the rest is behind cells.
+pub struct SchemeEntry {
+    /// Given in the order schemes are registered, and never given again
+    pub id: usize,
+    pub name: String,
+    scheme: UnsafeCell<Box<KScheme>>,
+    /// The interrupts the scheme claimed on each line
+    irqs: UnsafeCell<[u64; IRQ_LINES]>,
+    state: Arc<UnsafeCell<SchemeState>>,
+}
+
+impl SchemeEntry {
+    /// The scheme, drivers guard their own state against the interrupts they handle as before
+    pub fn scheme(&self) -> &mut KScheme {
+        unsafe { &mut **self.scheme.get() }
+    }
+
+    /// Open `url` on the scheme, the resource fails with ENODEV once the scheme is unregistered
+    pub fn open(&self, url: &str, flags: usize) -> Result<Box<Resource>> {
+        // Opening may block, so the scheme is counted as in use until it returns
+        let state = self.state.clone();
+        acquire(&state);
+        let result = self.scheme().open(url, flags);
+        release(&state);
+
+        let resource = try!(result);
+        Ok(box RegisteredResource::new(state, resource))
+    }
+
+    /// The resources open on the scheme
+    pub fn resources(&self) -> usize {
+        unsafe { (*self.state.get()).resources }
+    }
+
+    /// The interrupts the scheme claimed on each line
+    pub fn irqs(&self) -> [u64; IRQ_LINES] {
+        let _guard = InterruptGuard::new();
+        unsafe { *self.irqs.get() }
+    }
+
+    /// Count an interrupt the scheme claimed, called with interrupts disabled
+    pub fn claimed(&self, irq: u8) {
+        if (irq as usize) < IRQ_LINES {
+            unsafe { (*self.irqs.get())[irq as usize] += 1 };
+        }
+    }
+
+    pub fn reset_irqs(&self) {
+        let _guard = InterruptGuard::new();
+        unsafe { *self.irqs.get() = [0; IRQ_LINES] };
+    }
+}
+
+/// A snapshot of the registered schemes, in the order they were registered
+///
+/// It stays as it was taken, and keeps its entries alive, however long it is held.
+pub type SchemeList = Arc<Vec<Arc<SchemeEntry>>>;
+
+struct RegistryInner {
+    entries: SchemeList,
+    /// Unregistered schemes that resources are still open on, kept until they are closed
+    removed: Vec<Arc<SchemeEntry>>,
+    next_id: usize,
+    /// Set while the list is changed
+    changing: bool,
+}
+
+/// The schemes of the environment, by name
+///
+/// Schemes without a name only handle interrupts, any number of them may be registered. Named ones are
+/// kept in the order they were registered, which is also the order interrupts are passed on in.
+///
+/// Readers take a snapshot, which they may hold across a blocking open or iterate while a scheme is
+/// registered. A change is made on a copy of the list that replaces it with interrupts disabled, so an
+/// interrupt handler or a preempting context sees the list either before or after. Starting a change
+/// inside another, from a scheme dropped or shut down during one, is caught by a debug assertion.
+pub struct SchemeRegistry {
+    inner: UnsafeCell<RegistryInner>,
+}
+
+impl SchemeRegistry {
+    pub fn new() -> SchemeRegistry {
+        SchemeRegistry {
+            inner: UnsafeCell::new(RegistryInner {
+                entries: Arc::new(Vec::new()),
+                removed: Vec::new(),
+                next_id: 0,
+                changing: false,
+            }),
+        }
+    }
+
+    /// Change a copy of the list, and the rest of the registry, with interrupts disabled
+    fn change<T, F: FnOnce(&mut Vec<Arc<SchemeEntry>>, &mut Vec<Arc<SchemeEntry>>, &mut usize) -> T>(&self, f: F) -> T {
+        let _guard = InterruptGuard::new();
+        let inner = unsafe { &mut *self.inner.get() };
+        debug_assert!(! inner.changing, "SchemeRegistry: changed during a change");
+        inner.changing = true;
+
+        let mut entries: Vec<Arc<SchemeEntry>> = inner.entries.iter().cloned().collect();
+        let ret = f(&mut entries, &mut inner.removed, &mut inner.next_id);
+        inner.entries = Arc::new(entries);
+
+        inner.changing = false;
+        ret
+    }
+
+    /// The registered schemes as they are now
+    pub fn snapshot(&self) -> SchemeList {
+        let _guard = InterruptGuard::new();
+        unsafe { (*self.inner.get()).entries.clone() }
+    }
+
+    /// Add `scheme`
+    ///
+    /// If its name is taken, it gets the first free one with a number after it, so a second `network`
+    /// becomes `network2`.
+    pub fn register(&self, scheme: Box<KScheme>) {
+        let base = scheme.scheme().to_string();
+        let name = self.change(move |entries, _, next_id| {
+            let mut name = base.clone();
+            if ! base.is_empty() {
+                let mut version = 2;
+                while entries.iter().any(|entry| entry.name == name) {
+                    name = format!("{}{}", base, version);
+                    version += 1;
+                }
+            }
+
+            entries.push(Arc::new(SchemeEntry {
+                id: *next_id,
+                name: name.clone(),
+                scheme: UnsafeCell::new(scheme),
+                irqs: UnsafeCell::new([0; IRQ_LINES]),
+                state: Arc::new(UnsafeCell::new(SchemeState {
+                    registered: true,
+                    resources: 0,
+                })),
+            }));
+            *next_id += 1;
+
+            (base, name)
+        });
+
+        if name.0 != name.1 {
+            syslog_info!("Scheme {} registered as {}", name.0, name.1);
+        }
+        self.sweep();
+    }
+
+    /// Whether a scheme is registered as `name`
+    pub fn contains(&self, name: &str) -> bool {
+        self.lookup(name).is_some()
+    }
+
+    /// The scheme registered as `name`
+    pub fn lookup(&self, name: &str) -> Option<Arc<SchemeEntry>> {
+        if name.is_empty() {
+            return None;
+        }
+        self.snapshot().iter().find(|entry| entry.name == name).cloned()
+    }
+
+    /// Remove the scheme registered as `name`, after its driver shut the device down
+    ///
+    /// Resources still open on it fail with ENODEV from then on, and the scheme is dropped once the last
+    /// of them is closed. A hotplug event with the id of the scheme tells userspace it is gone.
+    pub fn unregister(&self, name: &str) -> bool {
+        ! name.is_empty() && self.remove(|entry| entry.name == name)
+    }
+
+    fn remove<P: Fn(&SchemeEntry) -> bool>(&self, matches: P) -> bool {
+        let entry = match self.change(|entries, _, _| {
+            entries.iter().position(|entry| matches(&**entry)).map(|i| entries.remove(i))
+        }) {
+            Some(entry) => entry,
+            None => return false,
+        };
+
+        // The driver may block while it shuts down, so it is not called during the change
+        unsafe { (*entry.state.get()).registered = false };
+        entry.scheme().shutdown();
+
+        ::env().events.send(HotplugEvent {
+            kind: HOTPLUG_SCHEME,
+            index: entry.id as i64,
+            added: false,
+        }.to_event(), "SchemeRegistry::unregister");
+
+        if entry.resources() > 0 {
+            syslog_info!("Scheme {} unregistered, {} resources still open", entry.name, entry.resources());
+            self.change(|_, removed, _| removed.push(entry));
+        }
+        self.sweep();
+        true
+    }
+
+    /// Drop the unregistered schemes that have no resources left, after the change that takes them out
+    fn sweep(&self) {
+        let _closed: Vec<Arc<SchemeEntry>> = self.change(|_, removed, _| {
+            let mut closed = Vec::new();
+            let mut i = 0;
+            while i < removed.len() {
+                if removed[i].resources() > 0 {
+                    i += 1;
+                } else {
+                    closed.push(removed.remove(i));
+                }
+            }
+            closed
+        });
+    }
+
+    /// The unregistered schemes waiting for their resources to be closed
+    pub fn removed(&self) -> Vec<Arc<SchemeEntry>> {
+        let _guard = InterruptGuard::new();
+        unsafe { (*self.inner.get()).removed.clone() }
+    }
+
+    /// The names schemes are registered as, in the order they were registered
+    pub fn names(&self) -> Vec<String> {
+        self.snapshot().iter().filter(|entry| ! entry.name.is_empty()).map(|entry| entry.name.clone()).collect()
+    }
+}
+
+/// A resource opened through the registry, which stops reaching its scheme once that is unregistered
+///
+/// The scheme is kept until the resource is dropped, so dropping it is still safe after the device is gone.
+pub struct RegisteredResource {
+    state: Arc<UnsafeCell<SchemeState>>,
+    inner: Box<Resource>,
+}
+
+impl RegisteredResource {
+    fn new(state: Arc<UnsafeCell<SchemeState>>, inner: Box<Resource>) -> RegisteredResource {
+        acquire(&state);
+        RegisteredResource {
+            state: state,
+            inner: inner,
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        if unsafe { (*self.state.get()).registered } {
+            Ok(())
+        } else {
+            Err(Error::new(ENODEV))
+        }
+    }
+}
+
+impl Resource for RegisteredResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        try!(self.check());
+        let inner = try!(self.inner.dup());
+        Ok(box RegisteredResource::new(self.state.clone(), inner))
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        try!(self.check());
+        self.inner.path(buf)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        try!(self.check());
+        self.inner.read(buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        try!(self.check());
+        self.inner.write(buf)
+    }
+
+    fn seek(&mut self, pos: ResourceSeek) -> Result<usize> {
+        try!(self.check());
+        self.inner.seek(pos)
+    }
+
+    fn stat(&self, stat: &mut Stat) -> Result<()> {
+        try!(self.check());
+        self.inner.stat(stat)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        try!(self.check());
+        self.inner.sync()
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        try!(self.check());
+        self.inner.truncate(len)
+    }
+
+    fn ready(&mut self) -> i64 {
+        if unsafe { (*self.state.get()).registered } {
+            self.inner.ready()
+        } else {
+            IO_CLOSED
+        }
+    }
+}
+
+impl Drop for RegisteredResource {
+    fn drop(&mut self) {
+        release(&self.state);
+    }
+}
@@ -1,5 +1,7 @@
 use alloc::boxed::Box;
 
+use common::event::{IO_READ, IO_WRITE};
+
 use system::error::{Error, Result, EPERM, ESPIPE};
 use system::syscall::Stat;
 
@@ -64,4 +66,10 @@ pub trait Resource {
     fn truncate(&mut self, len: usize) -> Result<()> {
         Err(Error::new(EPERM))
     }
+
+    /// Whether a read or a write would go ahead without waiting, as the flags of an `IoEvent`
+    /// Must not wait itself. Resources that never wait are always ready.
+    fn ready(&mut self) -> i64 {
+        IO_READ | IO_WRITE
+    }
 }
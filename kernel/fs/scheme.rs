@@ -98,7 +98,7 @@ impl SchemeInner {
 
 impl Drop for SchemeInner {
     fn drop(&mut self) {
-        unsafe { &mut *::env().schemes.get() }.retain(|scheme| scheme.scheme() != self.name);
+        ::env().schemes.unregister(&self.name);
     }
 }
 
@@ -369,8 +369,8 @@ impl Scheme {
 }
 
 impl KScheme for Scheme {
-    fn on_irq(&mut self, _irq: u8) {
-
+    fn on_irq(&mut self, _irq: u8) -> bool {
+        false
     }
 
     fn scheme(&self) -> &str {
@@ -4,6 +4,8 @@ use alloc::boxed::Box;
 
 use collections::{String, Vec};
 
+use common::time;
+
 use core::cmp::{max, min};
 
 use system::error::Result;
@@ -15,6 +17,9 @@ pub struct VecResource {
     data: Vec<u8>,
     mode: u16,
     seek: usize,
+    /// When it was created and last changed, in seconds since the Unix epoch
+    ctime: u32,
+    mtime: u32,
 }
 
 impl VecResource {
@@ -24,6 +29,8 @@ impl VecResource {
             data: data,
             mode: mode,
             seek: 0,
+            ctime: time::timestamp(),
+            mtime: time::timestamp(),
         }
     }
 
@@ -39,6 +46,8 @@ impl Resource for VecResource {
             data: self.data.clone(),
             mode: self.mode,
             seek: self.seek,
+            ctime: self.ctime,
+            mtime: self.mtime,
         })
     }
 
@@ -79,6 +88,7 @@ impl Resource for VecResource {
             self.seek += 1;
             i += 1;
         }
+        self.mtime = time::timestamp();
         return Ok(i);
     }
 
@@ -99,6 +109,9 @@ impl Resource for VecResource {
     fn stat(&self, stat: &mut Stat) -> Result<()> {
         stat.st_size = self.data.len() as u32;
         stat.st_mode = self.mode;
+        stat.st_atime = self.mtime;
+        stat.st_mtime = self.mtime;
+        stat.st_ctime = self.ctime;
         Ok(())
     }
 
@@ -112,6 +125,7 @@ impl Resource for VecResource {
         }
         self.data.truncate(len);
         self.seek = min(self.seek, self.data.len());
+        self.mtime = time::timestamp();
         Ok(())
     }
 }
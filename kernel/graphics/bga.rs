@@ -0,0 +1,106 @@
+use collections::Vec;
+
+use drivers::io::{Io, Pio};
+
+const DISPI_INDEX: u16 = 0x1CE;
+const DISPI_DATA: u16 = 0x1CF;
+
+const DISPI_ID: u16 = 0;
+const DISPI_XRES: u16 = 1;
+const DISPI_YRES: u16 = 2;
+const DISPI_BPP: u16 = 3;
+const DISPI_ENABLE: u16 = 4;
+const DISPI_VIRT_WIDTH: u16 = 6;
+const DISPI_VIRT_HEIGHT: u16 = 7;
+const DISPI_X_OFFSET: u16 = 8;
+const DISPI_Y_OFFSET: u16 = 9;
+const DISPI_VIDEO_MEMORY_64K: u16 = 0xA;
+
+/// The versions of the interface that report their largest mode, through `DISPI_GETCAPS`
+const DISPI_ID_CAPS: u16 = 0xB0C3;
+const DISPI_ID_MAX: u16 = 0xB0C5;
+
+const DISPI_ENABLED: u16 = 0x01;
+const DISPI_GETCAPS: u16 = 0x02;
+const DISPI_LFB_ENABLED: u16 = 0x40;
+
+/// The video memory of an adapter that does not report it, the default of Bochs and QEMU
+const DEFAULT_VIDEO_MEMORY: usize = 16 * 1024 * 1024;
+
+/// The modes offered, of those that fit the adapter
+const MODES: [(usize, usize); 16] = [
+    (640, 480), (800, 600), (1024, 768), (1152, 864),
+    (1280, 720), (1280, 800), (1280, 1024), (1366, 768),
+    (1440, 900), (1600, 900), (1600, 1200), (1680, 1050),
+    (1920, 1080), (1920, 1200), (2560, 1440), (2560, 1600),
+];
+
+fn read(index: u16) -> u16 {
+    Pio::<u16>::new(DISPI_INDEX).write(index);
+    Pio::<u16>::new(DISPI_DATA).read()
+}
+
+fn write(index: u16, value: u16) {
+    Pio::<u16>::new(DISPI_INDEX).write(index);
+    Pio::<u16>::new(DISPI_DATA).write(value);
+}
+
+/// Whether the display is the Bochs graphics adapter, as in Bochs, QEMU and VirtualBox
+pub fn present() -> bool {
+    let id = read(DISPI_ID);
+    id >= DISPI_ID_CAPS && id <= DISPI_ID_MAX
+}
+
+/// The modes at 32 bits per pixel the adapter can show, smallest first
+pub fn modes() -> Vec<(usize, usize)> {
+    let mut modes = Vec::new();
+    if ! present() {
+        return modes;
+    }
+
+    let enable = read(DISPI_ENABLE);
+    write(DISPI_ENABLE, enable | DISPI_GETCAPS);
+    let (max_width, max_height) = (read(DISPI_XRES) as usize, read(DISPI_YRES) as usize);
+    write(DISPI_ENABLE, enable);
+
+    let memory = match read(DISPI_VIDEO_MEMORY_64K) as usize {
+        0 => DEFAULT_VIDEO_MEMORY,
+        blocks => blocks * 65536,
+    };
+
+    for &(width, height) in MODES.iter() {
+        if width <= max_width && height <= max_height && width * height * 4 <= memory {
+            modes.push((width, height));
+        }
+    }
+    modes
+}
+
+/// Program a mode of `width` by `height` at 32 bits per pixel, returning whether the adapter took it
+///
+/// The linear framebuffer stays where it was. If the adapter does not read back the mode, the one it
+/// had is programmed again.
+pub fn set_mode(width: usize, height: usize) -> bool {
+    let old = (read(DISPI_XRES), read(DISPI_YRES), read(DISPI_BPP));
+
+    let program = |width: u16, height: u16, bpp: u16| {
+        write(DISPI_ENABLE, 0);
+        write(DISPI_XRES, width);
+        write(DISPI_YRES, height);
+        write(DISPI_BPP, bpp);
+        write(DISPI_VIRT_WIDTH, width);
+        write(DISPI_VIRT_HEIGHT, height);
+        write(DISPI_X_OFFSET, 0);
+        write(DISPI_Y_OFFSET, 0);
+        write(DISPI_ENABLE, DISPI_ENABLED | DISPI_LFB_ENABLED);
+    };
+
+    program(width as u16, height as u16, 32);
+    if read(DISPI_XRES) as usize == width && read(DISPI_YRES) as usize == height && read(DISPI_BPP) == 32
+        && read(DISPI_VIRT_WIDTH) as usize == width {
+        true
+    } else {
+        program(old.0, old.1, old.2);
+        false
+    }
+}
@@ -0,0 +1,53 @@
+use collections::Vec;
+
+pub const CURSOR_WIDTH: usize = 12;
+pub const CURSOR_HEIGHT: usize = 19;
+
+/// The arrow, `X` is drawn black, `.` white, and what is under a space shows through
+const SPRITE: [&'static [u8; CURSOR_WIDTH]; CURSOR_HEIGHT] = [
+    b"X           ",
+    b"XX          ",
+    b"X.X         ",
+    b"X..X        ",
+    b"X...X       ",
+    b"X....X      ",
+    b"X.....X     ",
+    b"X......X    ",
+    b"X.......X   ",
+    b"X........X  ",
+    b"X.........X ",
+    b"X......XXXXX",
+    b"X...X..X    ",
+    b"X..XX..X    ",
+    b"X.X  X..X   ",
+    b"XX   X..X   ",
+    b"X     X..X  ",
+    b"      X..X  ",
+    b"       XX   ",
+];
+
+/// The sprite at column `x` and row `y` of it, over `pixel`
+pub fn composite(x: usize, y: usize, pixel: u32) -> u32 {
+    match SPRITE[y][x] {
+        b'X' => 0xFF000000,
+        b'.' => 0xFFFFFFFF,
+        _ => pixel,
+    }
+}
+
+/// The mouse cursor, drawn by the kernel straight to the screen over what the display manager writes
+pub struct Cursor {
+    /// The top left of the sprite, while it is drawn
+    pub shown: Option<(usize, usize)>,
+    /// The pixels the sprite covers, as they would be without it
+    pub under: Vec<u32>,
+}
+
+impl Cursor {
+    pub fn new() -> Cursor {
+        Cursor {
+            shown: None,
+            under: vec![0; CURSOR_WIDTH * CURSOR_HEIGHT],
+        }
+    }
+}
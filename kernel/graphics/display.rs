@@ -1,5 +1,7 @@
 use alloc::boxed::Box;
 
+use collections::Vec;
+
 use core::cmp;
 
 use arch::memory;
@@ -7,7 +9,9 @@ use arch::memory;
 use system::graphics::{fast_copy, fast_set};
 
 use super::FONT;
+use super::bga;
 use super::color::Color;
+use super::cursor::{composite, Cursor, CURSOR_HEIGHT, CURSOR_WIDTH};
 
 /// The info of the VBE mode
 #[derive(Copy, Clone, Default, Debug)]
@@ -65,6 +69,7 @@ pub struct Display {
     pub size: usize,
     pub width: usize,
     pub height: usize,
+    pub cursor: Cursor,
 }
 
 impl Display {
@@ -77,6 +82,7 @@ impl Display {
                 size: mode_info.xresolution as usize * mode_info.yresolution as usize,
                 width: mode_info.xresolution as usize,
                 height: mode_info.yresolution as usize,
+                cursor: Cursor::new(),
             };
 
             ret.set(Color::new(0, 0, 0));
@@ -87,6 +93,59 @@ impl Display {
         }
     }
 
+    /// The modes the display can be switched to, with the one it is in
+    pub fn modes(&self) -> Vec<(usize, usize)> {
+        let mut modes = bga::modes();
+        if ! modes.contains(&(self.width, self.height)) {
+            modes.push((self.width, self.height));
+            modes.sort();
+        }
+        modes
+    }
+
+    /// Switch to a mode of `width` by `height`, with an offscreen buffer for it, returning whether it did
+    ///
+    /// Nothing changes unless the adapter takes the mode, so the display is never left in a mode it is
+    /// not drawn for. The cursor is taken off and the screen cleared, to be drawn again by its owner.
+    pub fn set_mode(&mut self, width: usize, height: usize) -> bool {
+        if (width, height) == (self.width, self.height) {
+            return true;
+        }
+        if ! self.modes().contains(&(width, height)) {
+            return false;
+        }
+
+        let offscreen = unsafe { memory::alloc(width * height * 4) as *mut u32 };
+        if offscreen.is_null() {
+            return false;
+        }
+
+        self.hide_cursor();
+        if ! bga::set_mode(width, height) {
+            unsafe { memory::unalloc(offscreen as usize) };
+            return false;
+        }
+
+        unsafe {
+            if self.offscreen as usize > 0 {
+                memory::unalloc(self.offscreen as usize);
+            }
+            if let Some(ref mut mode_info) = VBEMODEINFO {
+                mode_info.xresolution = width as u16;
+                mode_info.yresolution = height as u16;
+                mode_info.bytesperscanline = (width * 4) as u16;
+            }
+        }
+        self.offscreen = offscreen;
+        self.size = width * height;
+        self.width = width;
+        self.height = height;
+
+        self.set(Color::new(0, 0, 0));
+        self.flip();
+        true
+    }
+
     /// Set the color
     pub fn set(&self, color: Color) {
         unsafe {
@@ -120,6 +179,81 @@ impl Display {
         }
     }
 
+    /// The part of the screen the cursor covers with its top left at `x`, `y`
+    fn cursor_rect(&self, x: usize, y: usize) -> (usize, usize, usize, usize) {
+        let x = cmp::min(x, self.width - 1);
+        let y = cmp::min(y, self.height - 1);
+        (x, y, cmp::min(CURSOR_WIDTH, self.width - x), cmp::min(CURSOR_HEIGHT, self.height - y))
+    }
+
+    /// Take the cursor off the screen, putting back what it covered
+    pub fn hide_cursor(&mut self) {
+        if let Some((x, y)) = self.cursor.shown.take() {
+            let (x, y, w, h) = self.cursor_rect(x, y);
+            for row in 0..h {
+                unsafe {
+                    fast_copy(self.onscreen.offset(((y + row) * self.width + x) as isize),
+                              self.cursor.under[row * CURSOR_WIDTH..].as_ptr(), w);
+                }
+            }
+        }
+    }
+
+    /// Move the cursor to `x`, `y`, which redraws only where it was and where it is
+    pub fn show_cursor(&mut self, x: usize, y: usize) {
+        self.hide_cursor();
+
+        let (x, y, w, h) = self.cursor_rect(x, y);
+        for row in 0..h {
+            for col in 0..w {
+                unsafe {
+                    let pixel = self.onscreen.offset(((y + row) * self.width + x + col) as isize);
+                    self.cursor.under[row * CURSOR_WIDTH + col] = *pixel;
+                    *pixel = composite(col, row, *pixel);
+                }
+            }
+        }
+        self.cursor.shown = Some((x, y));
+    }
+
+    /// Copy `pixels` to the screen from pixel `offset`, which must fit
+    ///
+    /// Pixels under the cursor are saved for when it moves and drawn with it over them, so writing a frame
+    /// neither erases the cursor nor lets it flicker.
+    pub fn write(&mut self, offset: usize, pixels: &[u32]) {
+        let end = offset + pixels.len();
+        let rect = self.cursor.shown.map(|(x, y)| self.cursor_rect(x, y));
+
+        let mut pos = offset;
+        while pos < end {
+            let (row, col) = (pos / self.width, pos % self.width);
+            let next = match rect {
+                Some((x, y, w, h)) if row >= y && row < y + h && col >= x && col < x + w => {
+                    let stop = cmp::min(end, row * self.width + x + w);
+                    for i in pos..stop {
+                        let (sprite_x, sprite_y) = (i % self.width - x, row - y);
+                        let pixel = pixels[i - offset];
+                        self.cursor.under[sprite_y * CURSOR_WIDTH + sprite_x] = pixel;
+                        unsafe { *self.onscreen.offset(i as isize) = composite(sprite_x, sprite_y, pixel); }
+                    }
+                    pos = stop;
+                    continue;
+                },
+                // Up to where the cursor starts on this row or the next it is on
+                Some((x, y, _, _)) if row < y => y * self.width + x,
+                Some((x, y, _, h)) if row < y + h && col < x => row * self.width + x,
+                Some((x, y, _, h)) if row + 1 < y + h => (row + 1) * self.width + x,
+                _ => end,
+            };
+
+            let stop = cmp::min(end, next);
+            unsafe {
+                fast_copy(self.onscreen.offset(pos as isize), pixels[pos - offset..].as_ptr(), stop - pos);
+            }
+            pos = stop;
+        }
+    }
+
     /// Draw a rectangle
     pub fn rect(&self, x: usize, y: usize, w: usize, h: usize, color: Color) {
         let data = color.data;
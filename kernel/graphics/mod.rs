@@ -1,7 +1,11 @@
 
 pub static FONT: &'static [u8] = include_bytes!("../../filesystem/ui/unifont.font");
 
+/// The Bochs graphics adapter, for switching modes
+pub mod bga;
 /// Color struct
 pub mod color;
+/// The mouse cursor
+pub mod cursor;
 /// Display struct
 pub mod display;
@@ -71,8 +71,8 @@ pub fn syslog_inner(level: LogLevel, message: fmt::Arguments) {
         LogLevel::Critical => ("CRIT  ", true),
     };
 
-    let _ = write!(unsafe { &mut *::env().log.get() }, "[{}.{:>03}] {}{}\n", time.secs, time.nanos/1000000, prefix, message);
+    unsafe { &mut *::env().log.get() }.entry(prefix, message);
     if display {
-        let _ = write!(::common::debug::SerialConsole::new(), "[{}.{:>03}] {}{}\n", time.secs, time.nanos/1000000, prefix, message);
+        let _ = write!(::common::debug::ConsoleWriter, "[{}.{:>03}] {}{}\n", time.secs, time.nanos/1000000, prefix, message);
     }
 }
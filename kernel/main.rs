@@ -21,7 +21,7 @@ extern crate system;
 
 extern crate goblin;
 
-use acpi::Acpi;
+use acpi::AcpiScheme;
 
 use alloc::boxed::Box;
 
@@ -31,38 +31,62 @@ use arch::idt::{IdtDescriptor, IdtEntry};
 use arch::memory;
 use arch::paging::Page;
 use arch::regs::Regs;
+use arch::timekeeping;
 use arch::tss::Tss;
 
+use audio::pcspkr::{self, BeepScheme};
+
 use collections::{String, Vec};
 use collections::string::ToString;
 
 use core::{mem, slice, usize};
 
+use common::random;
 use common::time::Duration;
 
 use drivers::pci;
 use drivers::io::{Io, Pio};
 use drivers::ps2::*;
 use drivers::rtc::*;
-use drivers::serial::{self, Serial};
+use drivers::debugcon::{self, DebugconScheme};
+use drivers::serial::{self, SerialScheme};
 
 use env::Environment;
+use env::irq;
 
 use graphics::display;
 
-use network::schemes::{ArpScheme, EthernetScheme, IcmpScheme, IpScheme, NetConfigScheme, TcpScheme, UdpScheme};
+use network::capture::CaptureScheme;
+use network::loopback::Loopback;
+use network::scheme::NetworkScheme;
+use network::schemes::{ArpScheme, DhcpScheme, DnsScheme, EthernetScheme, IcmpScheme, IpScheme, Ip6Scheme, MdnsScheme, NdpScheme, NetConfigScheme, NetstatScheme, TcpScheme, UdpScheme};
 
+use schemes::cpu::CpuScheme;
 use schemes::debug::DebugScheme;
 use schemes::disk::DiskScheme;
 use schemes::display::DisplayScheme;
+use schemes::diagnostics::DiagnosticsScheme;
+use schemes::drivers::DriversScheme;
 use schemes::env::EnvScheme;
+use schemes::event::EventScheme;
 use schemes::initfs::InitFsScheme;
+use schemes::input::InputScheme;
+use schemes::irq::IrqScheme;
+use schemes::keyboard::KeyboardScheme;
+use schemes::log::LogScheme;
+use schemes::memory::MemoryScheme;
 use schemes::pty::PtyScheme;
+use schemes::random::RandomScheme;
 use schemes::sys::SysScheme;
+use schemes::time::TimeScheme;
 
 use syscall::process::exit;
 use syscall::execute::execute;
 
+use usb::acm::UsbSerialScheme;
+use usb::audio::UsbAudioScheme;
+use usb::scheme::UsbScheme;
+
 pub use externs::*;
 
 /// Common std-like functionality.
@@ -290,6 +314,9 @@ unsafe fn init(gdt_ptr: *mut GdtDescriptor, idt_ptr: *mut IdtDescriptor, tss_ptr
     // Get the serial information
     serial::bda_init();
 
+    // Find the debug console before anything is logged
+    debugcon::init();
+
     // Get the VBE information before unmapping the first megabyte
     display::vbe_init();
 
@@ -354,7 +381,7 @@ unsafe fn init(gdt_ptr: *mut GdtDescriptor, idt_ptr: *mut IdtDescriptor, tss_ptr
         Some(ref mut env) => {
             (&mut *env.contexts.get()).push(Context::root());
 
-            let mut serial = Serial::new(0x3F8, 0x4);
+            let mut serial = SerialScheme::new();
 
             let mut term_columns;
             let mut term_lines;
@@ -367,11 +394,11 @@ unsafe fn init(gdt_ptr: *mut GdtDescriptor, idt_ptr: *mut IdtDescriptor, tss_ptr
                     term_columns = String::new();
                     term_lines = String::new();
                     //Magic for getting serial size
-                    serial.write("ANSI Terminal Size:\n\x1B[s\x1B[9999;9999f\x1B[6n\x1B[u".as_bytes());
+                    serial.console().write_polled("ANSI Terminal Size:\n\x1B[s\x1B[9999;9999f\x1B[6n\x1B[u".as_bytes());
                     let mut escaped = 0;
                     let mut param = 0;
                     loop {
-                        let c = serial.readb() as char;
+                        let c = serial.console().readb() as char;
                         match c {
                             '\0' => break,
                             '\x1B' if escaped == 0 => escaped = 1,
@@ -389,14 +416,14 @@ unsafe fn init(gdt_ptr: *mut GdtDescriptor, idt_ptr: *mut IdtDescriptor, tss_ptr
                             }
                         }
                     }
-                    serial.write(term_columns.as_bytes());
-                    serial.write(", ".as_bytes());
-                    serial.write(term_lines.as_bytes());
-                    serial.write("\n".as_bytes());
+                    serial.console().write_polled(term_columns.as_bytes());
+                    serial.console().write_polled(", ".as_bytes());
+                    serial.console().write_polled(term_lines.as_bytes());
+                    serial.console().write_polled("\n".as_bytes());
                 }
             }
 
-            (&mut *env.schemes.get()).push(serial);
+            env.schemes.register(serial);
 
             (&mut *env.console.get()).draw = true;
 
@@ -408,56 +435,101 @@ unsafe fn init(gdt_ptr: *mut GdtDescriptor, idt_ptr: *mut IdtDescriptor, tss_ptr
                     & __data_start as *const u8 as usize, & __data_end as *const u8 as usize,
                     & __bss_start as *const u8 as usize, & __bss_end as *const u8 as usize);
 
-            if let Some(acpi) = Acpi::new() {
-                (&mut *env.schemes.get()).push(acpi);
-            }
+            acpi::init();
+            env.schemes.register(box AcpiScheme);
+            let hpet = acpi::tables().and_then(|acpi| acpi.hpet).map(|hpet| hpet.base);
+            irq::init();
 
             *env.clock_realtime.get() = Rtc::new().time();
+            timekeeping::init(hpet);
 
-            (&mut *env.schemes.get()).push(Ps2::new());
+            env.schemes.register(Ps2::new());
+            env.schemes.register(box KeyboardScheme);
+            env.schemes.register(box InputScheme);
 
             pci::pci_init(env);
 
-            (&mut *env.schemes.get()).push(DebugScheme::new());
+            env.schemes.register(DebugScheme::new());
 
-            (&mut *env.schemes.get()).push(box DiskScheme);
+            if debugcon::present() {
+                env.schemes.register(box DebugconScheme);
+            }
 
-            (&mut *env.schemes.get()).push(box DisplayScheme);
+            env.schemes.register(box BeepScheme);
 
-            (&mut *env.schemes.get()).push(InitFsScheme::new());
+            env.schemes.register(DiskScheme::new());
 
-            (&mut *env.schemes.get()).push(box EnvScheme);
+            env.schemes.register(box DisplayScheme);
 
-            (&mut *env.schemes.get()).push(PtyScheme::new());
+            env.schemes.register(InitFsScheme::new());
 
-            (&mut *env.schemes.get()).push(SysScheme::new());
+            env.schemes.register(box LogScheme);
+            env.schemes.register(box IrqScheme);
+            env.schemes.register(box TimeScheme);
+            env.schemes.register(box RandomScheme);
+            env.schemes.register(CpuScheme::new());
+            env.schemes.register(box MemoryScheme);
+            env.schemes.register(box DriversScheme);
+            env.schemes.register(box DiagnosticsScheme);
 
-            /*
-            let mut nics = Vec::new();
-            nics.append(&mut env.nics.lock());
-            (&mut *env.schemes.get()).push(NetworkScheme::new(nics));
-            */
+            env.schemes.register(box EnvScheme);
+            env.schemes.register(box EventScheme);
+
+            env.schemes.register(PtyScheme::new());
+
+            env.schemes.register(SysScheme::new());
 
-            (&mut *env.schemes.get()).push(box NetConfigScheme);
-            (&mut *env.schemes.get()).push(box EthernetScheme);
-            //(&mut *env.schemes.get()).push(box ArpScheme);
-            //(&mut *env.schemes.get()).push(box IcmpScheme);
-            (&mut *env.schemes.get()).push(box IpScheme {
-                arp: Vec::new()
+            env.schemes.register(box UsbScheme);
+            env.schemes.register(box UsbSerialScheme);
+            env.schemes.register(box UsbAudioScheme);
+
+            // After the NICs, so it only serves network: when there is none
+            env.schemes.register(NetworkScheme::new(Loopback::new()));
+
+            env.schemes.register(box CaptureScheme);
+            env.schemes.register(box NetConfigScheme);
+            env.schemes.register(box EthernetScheme);
+            env.schemes.register(box ArpScheme);
+            env.schemes.register(box DhcpScheme);
+            env.schemes.register(box DnsScheme);
+            env.schemes.register(box IcmpScheme);
+            env.schemes.register(box IpScheme);
+            env.schemes.register(box Ip6Scheme);
+            env.schemes.register(box MdnsScheme);
+            env.schemes.register(box NdpScheme);
+            env.schemes.register(box NetstatScheme);
+            env.schemes.register(box TcpScheme {
+                accepted: Vec::new()
+            });
+            env.schemes.register(box UdpScheme {
+                ports: Vec::new()
             });
-            (&mut *env.schemes.get()).push(box TcpScheme);
-            (&mut *env.schemes.get()).push(box UdpScheme);
 
             Context::spawn("karp".into(),
                            box move || {
                                ArpScheme::reply_loop();
                            });
 
+            Context::spawn("kdhcp".into(),
+                           box move || {
+                               DhcpScheme::client_loop();
+                           });
+
             Context::spawn("kicmp".into(),
                            box move || {
                                IcmpScheme::reply_loop();
                            });
 
+            Context::spawn("kndp".into(),
+                           box move || {
+                               NdpScheme::reply_loop();
+                           });
+
+            Context::spawn("kmdns".into(),
+                           box move || {
+                               MdnsScheme::reply_loop();
+                           });
+
             (&mut *env.contexts.get()).enabled = true;
 
             Context::spawn("kinit".into(),
@@ -487,6 +559,10 @@ unsafe fn init(gdt_ptr: *mut GdtDescriptor, idt_ptr: *mut IdtDescriptor, tss_ptr
                     current.set_env_var("TTY", "debug:").unwrap();
                 }
 
+                if option_env!("KERNEL_TEST").is_some() {
+                    schemes::sys::test::boot();
+                }
+
                 syslog_info!("The kernel has finished booting. Running /bin/init");
                 if let Err(err) = execute(vec!["initfs:/bin/init".to_string()]) {
                     syslog_info!("kernel: init: failed to execute: {}", err);
@@ -643,8 +719,15 @@ pub extern "cdecl" fn kernel(interrupt: usize, mut regs: &mut Regs) {
         unsafe { (&mut *env().interrupts.get())[interrupt as usize] += 1 };
     }
 
+    let irq_line = interrupt >= 0x20 && interrupt < 0x20 + irq::IRQ_LINES;
+
+    if irq_line {
+        random::add_interrupt(interrupt, interrupt != 0x20);
+    }
+
     match interrupt {
         0x20 => {
+            unsafe { &mut *env().irqs.get() }.lines[0].count += 1;
             {
                 let mut clock_monotonic = unsafe { &mut *env().clock_monotonic.get() };
                 *clock_monotonic = *clock_monotonic + PIT_DURATION;
@@ -654,15 +737,29 @@ pub extern "cdecl" fn kernel(interrupt: usize, mut regs: &mut Regs) {
                 *clock_realtime = *clock_realtime + PIT_DURATION;
             }
 
+            pcspkr::on_tick();
+
             if let Ok(mut current) = unsafe { &mut *env().contexts.get() }.current_mut() {
                 current.time += 1;
             }
 
             unsafe { context_switch(); }
         }
-        i @ 0x21 ... 0x2F => {
-            env().on_irq(i as u8 - 0x20);
+        i @ 0x21 ... 0x3F => {
+            let irq = i as u8 - 0x20;
+            unsafe { &mut *env().irqs.get() }.lines[irq as usize].count += 1;
+            if irq::spurious(irq) {
+                unsafe { &mut *env().irqs.get() }.lines[irq as usize].spurious += 1;
+                // Nothing is in service on the PIC that raised it, but the master has the cascade in service
+                if irq == 15 {
+                    Pio::<u8>::new(0x20).write(0x20);
+                }
+                return;
+            }
+            env().on_irq(irq);
         },
+        // The spurious vector of the local APIC, which has nothing in service to acknowledge
+        0x4F => (),
         0x80 => syscall::handle(regs),
         0xFF => {
             unsafe {
@@ -694,11 +791,7 @@ pub extern "cdecl" fn kernel(interrupt: usize, mut regs: &mut Regs) {
         _ => exception!("Unknown Interrupt"),
     }
 
-    if interrupt >= 0x20 && interrupt < 0x30 {
-        if interrupt >= 0x28 {
-            Pio::<u8>::new(0xA0).write(0x20);
-        }
-
-        Pio::<u8>::new(0x20).write(0x20);
+    if irq_line {
+        irq::eoi(interrupt as u8);
     }
 }
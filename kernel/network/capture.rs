@@ -0,0 +1,288 @@
+use alloc::boxed::Box;
+
+use collections::string::{String, ToString};
+use collections::vec::Vec;
+
+use common::time::{self, Duration};
+use common::to_num::ToNum;
+
+use core::cmp;
+use core::ops::DerefMut;
+
+use fs::{KScheme, Resource};
+
+use network::common::{BROADCAST_MAC_ADDR, MAC_ADDR};
+use network::packet::{EthernetFrame, ETHERNET_HEADER_LEN};
+use network::scheme::{NetworkScheme, NETWORK_INTERFACES};
+
+use sync::WaitQueue;
+
+use system::error::{Error, Result, EAGAIN, EINVAL, ENODEV};
+use system::syscall::O_NONBLOCK;
+
+/// Bytes of records a capture may hold before frames are dropped
+const CAPTURE_RING_SIZE: usize = 1024 * 1024;
+/// Frames are captured whole, up to the largest frame a resource may read
+const CAPTURE_SNAPLEN: u32 = 65535;
+/// The Linux cooked capture link type, which records the direction of each frame
+const LINKTYPE_LINUX_SLL: u32 = 113;
+
+/// Linux cooked capture packet types
+const SLL_HOST: u16 = 0;
+const SLL_BROADCAST: u16 = 1;
+const SLL_MULTICAST: u16 = 2;
+const SLL_OTHERHOST: u16 = 3;
+const SLL_OUTGOING: u16 = 4;
+
+/// Every open capture, given a copy of each frame that passes its filter
+static mut CAPTURE_TAPS: Option<Vec<*mut CaptureResource>> = None;
+
+fn push_u16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.push(value as u8);
+    bytes.push((value >> 8) as u8);
+}
+
+fn push_u32(bytes: &mut Vec<u8>, value: u32) {
+    push_u16(bytes, value as u16);
+    push_u16(bytes, (value >> 16) as u16);
+}
+
+/// The pcap file header, little endian
+fn pcap_header() -> Vec<u8> {
+    let mut bytes = Vec::new();
+    push_u32(&mut bytes, 0xA1B2C3D4);
+    push_u16(&mut bytes, 2);
+    push_u16(&mut bytes, 4);
+    push_u32(&mut bytes, 0);
+    push_u32(&mut bytes, 0);
+    push_u32(&mut bytes, CAPTURE_SNAPLEN);
+    push_u32(&mut bytes, LINKTYPE_LINUX_SLL);
+    bytes
+}
+
+/// A pcap record for an ethernet frame, rewritten with a cooked header that holds the direction
+fn pcap_record(outgoing: bool, frame: EthernetFrame) -> Vec<u8> {
+    let dst = frame.dst();
+
+    let kind = if outgoing {
+        SLL_OUTGOING
+    } else if dst.equals(BROADCAST_MAC_ADDR) {
+        SLL_BROADCAST
+    } else if dst.is_multicast() {
+        SLL_MULTICAST
+    } else if dst.equals(unsafe { MAC_ADDR }) {
+        SLL_HOST
+    } else {
+        SLL_OTHERHOST
+    };
+
+    let payload = frame.payload();
+    // The cooked header is two bytes longer than the ethernet header
+    let len = ETHERNET_HEADER_LEN + 2 + payload.len();
+    let now = Duration::realtime();
+
+    let mut bytes = Vec::with_capacity(16 + len);
+    push_u32(&mut bytes, now.secs as u32);
+    push_u32(&mut bytes, (now.nanos / time::NANOS_PER_MICRO) as u32);
+    push_u32(&mut bytes, len as u32);
+    push_u32(&mut bytes, len as u32);
+
+    // The cooked header is big endian
+    bytes.extend_from_slice(&[(kind >> 8) as u8, kind as u8]);
+    bytes.extend_from_slice(&[0, 1]); // ARPHRD_ETHER
+    bytes.extend_from_slice(&[0, 6]);
+    bytes.extend_from_slice(&frame.src().bytes);
+    bytes.extend_from_slice(&[0, 0]);
+    bytes.extend_from_slice(&[(frame.ethertype() >> 8) as u8, frame.ethertype() as u8]);
+    bytes.extend_from_slice(payload);
+
+    bytes
+}
+
+/// Copy a frame to every capture that wants it, called by the network scheme for each frame in either direction
+///
+/// This never blocks, a capture that is full counts the frame as dropped
+pub fn capture(nic: *mut NetworkScheme, outgoing: bool, frame: &[u8]) {
+    let taps = match unsafe { CAPTURE_TAPS.as_ref() } {
+        Some(taps) if ! taps.is_empty() => taps,
+        _ => return,
+    };
+
+    let view = match EthernetFrame::new(frame) {
+        Some(view) => view,
+        None => return,
+    };
+    let ethertype = view.ethertype();
+
+    for &tap in taps.iter() {
+        let tap = unsafe { &mut *tap };
+        if tap.nic.map_or(false, |tap_nic| tap_nic != nic) ||
+           tap.ethertype.map_or(false, |tap_ethertype| tap_ethertype != ethertype) {
+            continue;
+        }
+
+        // The record header, and the cooked header is two bytes longer than the ethernet header
+        let record_len = 16 + frame.len() + 2;
+        if tap.queued + record_len > CAPTURE_RING_SIZE {
+            tap.dropped += 1;
+            continue;
+        }
+
+        tap.queued += record_len;
+        tap.captured += 1;
+        tap.records.send(pcap_record(outgoing, view), "capture");
+    }
+}
+
+/// The state of every open capture, for the netstat listing
+pub fn capture_status() -> String {
+    let mut string = String::new();
+    if let Some(ref taps) = unsafe { CAPTURE_TAPS.as_ref() } {
+        for (i, &tap) in taps.iter().enumerate() {
+            let tap = unsafe { &*tap };
+            string.push_str(&format!("capture {}: captured: {} dropped: {}\n", i, tap.captured, tap.dropped));
+        }
+    }
+    string
+}
+
+/// An open capture, reads return a pcap stream
+pub struct CaptureResource {
+    ptr: *mut CaptureResource,
+    /// Only frames on this interface, or every interface
+    nic: Option<*mut NetworkScheme>,
+    /// Only frames with this EtherType, or every frame
+    ethertype: Option<u16>,
+    filter: String,
+    records: WaitQueue<Vec<u8>>,
+    /// Bytes held in records
+    queued: usize,
+    /// The record being read, starting with the file header
+    current: Vec<u8>,
+    offset: usize,
+    captured: u64,
+    dropped: u64,
+    nonblock: bool,
+}
+
+impl CaptureResource {
+    fn new(nic: Option<*mut NetworkScheme>, ethertype: Option<u16>, filter: String, nonblock: bool) -> Box<Self> {
+        let mut ret = box CaptureResource {
+            ptr: 0 as *mut CaptureResource,
+            nic: nic,
+            ethertype: ethertype,
+            filter: filter,
+            records: WaitQueue::new(),
+            queued: 0,
+            current: pcap_header(),
+            offset: 0,
+            captured: 0,
+            dropped: 0,
+            nonblock: nonblock,
+        };
+
+        unsafe {
+            ret.ptr = ret.deref_mut();
+
+            if CAPTURE_TAPS.is_none() {
+                CAPTURE_TAPS = Some(Vec::new());
+            }
+            CAPTURE_TAPS.as_mut().unwrap().push(ret.ptr);
+        }
+
+        ret
+    }
+}
+
+impl Resource for CaptureResource {
+    /// A duplicate is a new capture with the same filter, starting at the file header
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(CaptureResource::new(self.nic, self.ethertype, self.filter.clone(), self.nonblock))
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path_string = format!("capture:{}", self.filter);
+        let path = path_string.as_bytes();
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.offset >= self.current.len() {
+            let record = if self.nonblock {
+                match unsafe { self.records.inner() }.pop_front() {
+                    Some(record) => record,
+                    None => return Err(Error::new(EAGAIN)),
+                }
+            } else {
+                self.records.receive("CaptureResource::read")
+            };
+
+            self.queued -= cmp::min(self.queued, record.len());
+            self.current = record;
+            self.offset = 0;
+        }
+
+        let mut i = 0;
+        while i < buf.len() && self.offset < self.current.len() {
+            buf[i] = self.current[self.offset];
+            i += 1;
+            self.offset += 1;
+        }
+
+        Ok(i)
+    }
+}
+
+impl Drop for CaptureResource {
+    fn drop(&mut self) {
+        if self.dropped > 0 {
+            debugln!("Capture: {} of {} frames dropped", self.dropped, self.captured + self.dropped);
+        }
+
+        let ptr = self.ptr;
+        if let Some(taps) = unsafe { CAPTURE_TAPS.as_mut() } {
+            taps.retain(|&tap| tap != ptr);
+        }
+    }
+}
+
+/// The capture scheme, capture:/interface=N/ethertype=X opens a capture
+///
+/// Both filters are optional. Interfaces are numbered as in netstat:, the EtherType is in hex.
+pub struct CaptureScheme;
+
+impl KScheme for CaptureScheme {
+    fn scheme(&self) -> &str {
+        "capture"
+    }
+
+    fn open(&mut self, url: &str, flags: usize) -> Result<Box<Resource>> {
+        let filter = url.splitn(2, ":").nth(1).unwrap_or("");
+
+        let mut nic = None;
+        let mut ethertype = None;
+        for part in filter.split('/').filter(|part| ! part.is_empty()) {
+            let mut pair = part.splitn(2, '=');
+            let key = pair.next().unwrap_or("");
+            let value = pair.next().unwrap_or("");
+            match key {
+                "interface" => {
+                    let index = value.to_num();
+                    match unsafe { NETWORK_INTERFACES.as_ref() }.and_then(|interfaces| interfaces.get(index)) {
+                        Some(&interface) => nic = Some(interface),
+                        None => return Err(Error::new(ENODEV)),
+                    }
+                },
+                "ethertype" => ethertype = Some(value.to_num_radix(16) as u16),
+                _ => return Err(Error::new(EINVAL)),
+            }
+        }
+
+        Ok(CaptureResource::new(nic, ethertype, filter.to_string(), flags & O_NONBLOCK == O_NONBLOCK))
+    }
+}
@@ -1,15 +1,89 @@
 use collections::string::String;
 use collections::vec::Vec;
 
+use common::random::rand;
 use common::to_num::ToNum;
 
 pub static mut DNS_ADDR: Ipv4Addr = Ipv4Addr { bytes: [10, 85, 85, 1] };
 pub static BROADCAST_IP_ADDR: Ipv4Addr = Ipv4Addr { bytes: [255, 255, 255, 255] };
+pub static NULL_IP_ADDR: Ipv4Addr = Ipv4Addr { bytes: [0, 0, 0, 0] };
+pub static LOOPBACK_IP_ADDR: Ipv4Addr = Ipv4Addr { bytes: [127, 0, 0, 1] };
 pub static mut IP_ADDR: Ipv4Addr = Ipv4Addr { bytes: [10, 85, 85, 2] };
 pub static mut IP_ROUTER_ADDR: Ipv4Addr = Ipv4Addr { bytes: [10, 85, 85, 1] };
 pub static mut IP_SUBNET: Ipv4Addr = Ipv4Addr { bytes: [255, 255, 255, 0] };
 pub static BROADCAST_MAC_ADDR: MacAddr = MacAddr { bytes: [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF] };
 pub static mut MAC_ADDR: MacAddr = MacAddr { bytes: [0x00, 0x00, 0x00, 0x00, 0x00, 0x00] };
+pub static NULL_IPV6_ADDR: Ipv6Addr = Ipv6Addr { bytes: [0; 16] };
+pub static LOOPBACK_IPV6_ADDR: Ipv6Addr = Ipv6Addr { bytes: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1] };
+pub static ALL_NODES_IPV6_ADDR: Ipv6Addr = Ipv6Addr { bytes: [0xFF, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1] };
+/// The link-local address, unspecified until duplicate address detection has passed
+pub static mut IPV6_ADDR: Ipv6Addr = Ipv6Addr { bytes: [0; 16] };
+/// Set by the NIC driver, address configuration waits while the link is down
+pub static mut LINK_UP: bool = false;
+
+/// The source address for packets to `peer`, loopback packets are sent from the address to itself
+pub fn local_addr(peer: Ipv4Addr) -> Ipv4Addr {
+    if peer.is_loopback() {
+        peer
+    } else {
+        unsafe { IP_ADDR }
+    }
+}
+
+/// Check if a packet to `addr` is for us
+pub fn is_local(addr: Ipv4Addr) -> bool {
+    addr.is_loopback() || addr.equals(unsafe { IP_ADDR })
+}
+
+/// The source address for IPv6 packets to `peer`
+pub fn local_addr6(peer: Ipv6Addr) -> Ipv6Addr {
+    if peer.is_loopback() {
+        peer
+    } else {
+        unsafe { IPV6_ADDR }
+    }
+}
+
+/// Check if an IPv6 packet to `addr` is for us, the groups we joined included
+pub fn is_local6(addr: Ipv6Addr) -> bool {
+    let ours = unsafe { IPV6_ADDR };
+    addr.is_loopback() || addr.equals(ALL_NODES_IPV6_ADDR) ||
+    (! ours.is_unspecified() && (addr.equals(ours) || addr.equals(ours.solicited_node())))
+}
+
+/// The big endian CRC-32 of an address, the top six bits index the multicast hash
+pub fn ether_crc(addr: &MacAddr) -> u32 {
+    let mut crc = 0xFFFFFFFF;
+    for &byte in addr.bytes.iter() {
+        let mut octet = byte;
+        for _ in 0..8 {
+            let carry = (crc >> 31) ^ (octet as u32 & 1);
+            crc <<= 1;
+            if carry == 1 {
+                crc ^= 0x04C11DB7;
+            }
+            octet >>= 1;
+        }
+    }
+    crc
+}
+
+/// The little endian CRC-32 of an address, as used by LANCE style multicast filters
+pub fn ether_crc_le(addr: &MacAddr) -> u32 {
+    let mut crc = 0xFFFFFFFF;
+    for &byte in addr.bytes.iter() {
+        let mut octet = byte;
+        for _ in 0..8 {
+            let carry = (crc ^ octet as u32) & 1;
+            crc >>= 1;
+            if carry == 1 {
+                crc ^= 0xEDB88320;
+            }
+            octet >>= 1;
+        }
+    }
+    crc
+}
 
 pub trait FromBytes {
     fn from_bytes(bytes: &[u8]) -> Option<Self> where Self: Sized;
@@ -79,6 +153,36 @@ impl MacAddr {
         true
     }
 
+    /// Check that the address can be used as a source address, it must not be all zeroes, broadcast or multicast
+    pub fn valid(&self) -> bool {
+        let mut zero = true;
+        for i in 0..6 {
+            if self.bytes[i] != 0 {
+                zero = false;
+            }
+        }
+        ! zero && self.bytes[0] & 1 == 0
+    }
+
+    /// Check if this is a group address other than broadcast
+    pub fn is_multicast(&self) -> bool {
+        self.bytes[0] & 1 == 1 && ! self.equals(BROADCAST_MAC_ADDR)
+    }
+
+    /// Generate a random locally administered address
+    pub fn random() -> Self {
+        let a = rand();
+        let b = rand();
+        MacAddr {
+            bytes: [0x02,
+                    (a >> 16) as u8,
+                    (a >> 8) as u8,
+                    (b >> 16) as u8,
+                    (b >> 8) as u8,
+                    b as u8],
+        }
+    }
+
     pub fn from_str(string: &str) -> Self {
         let mut addr = MacAddr { bytes: [0, 0, 0, 0, 0, 0] };
 
@@ -127,6 +231,21 @@ impl Ipv4Addr {
         true
     }
 
+    /// Check if this is in 127.0.0.0/8
+    pub fn is_loopback(&self) -> bool {
+        self.bytes[0] == 127
+    }
+
+    /// Check if this is in 224.0.0.0/4
+    pub fn is_multicast(&self) -> bool {
+        self.bytes[0] & 0xF0 == 0xE0
+    }
+
+    /// The ethernet group address a multicast address is sent to, 01:00:5E and its low 23 bits
+    pub fn multicast_mac(&self) -> MacAddr {
+        MacAddr { bytes: [0x01, 0x00, 0x5E, self.bytes[1] & 0x7F, self.bytes[2], self.bytes[3]] }
+    }
+
     pub fn from_str(string: &str) -> Self {
         let mut addr = Ipv4Addr { bytes: [0, 0, 0, 0] };
 
@@ -166,20 +285,168 @@ pub struct Ipv6Addr {
 }
 
 impl Ipv6Addr {
+    pub fn equals(&self, other: Self) -> bool {
+        self.bytes == other.bytes
+    }
+
+    /// Check if this is ::, the source of packets sent before an address is assigned
+    pub fn is_unspecified(&self) -> bool {
+        self.equals(NULL_IPV6_ADDR)
+    }
+
+    /// Check if this is ::1
+    pub fn is_loopback(&self) -> bool {
+        self.equals(LOOPBACK_IPV6_ADDR)
+    }
+
+    /// Check if this is in ff00::/8
+    pub fn is_multicast(&self) -> bool {
+        self.bytes[0] == 0xFF
+    }
+
+    /// Check if this is in fe80::/10
+    pub fn is_link_local(&self) -> bool {
+        self.bytes[0] == 0xFE && self.bytes[1] & 0xC0 == 0x80
+    }
+
+    /// The link-local address for a MAC address, with an interface identifier in modified EUI-64 format
+    pub fn link_local(mac: MacAddr) -> Self {
+        let mut addr = Ipv6Addr { bytes: [0; 16] };
+        addr.bytes[0] = 0xFE;
+        addr.bytes[1] = 0x80;
+        addr.bytes[8] = mac.bytes[0] ^ 0x02;
+        addr.bytes[9] = mac.bytes[1];
+        addr.bytes[10] = mac.bytes[2];
+        addr.bytes[11] = 0xFF;
+        addr.bytes[12] = 0xFE;
+        addr.bytes[13] = mac.bytes[3];
+        addr.bytes[14] = mac.bytes[4];
+        addr.bytes[15] = mac.bytes[5];
+        addr
+    }
+
+    /// The solicited-node multicast group of this address, ff02::1:ffXX:XXXX
+    pub fn solicited_node(&self) -> Self {
+        let mut addr = Ipv6Addr { bytes: [0xFF, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0xFF, 0, 0, 0] };
+        addr.bytes[13] = self.bytes[13];
+        addr.bytes[14] = self.bytes[14];
+        addr.bytes[15] = self.bytes[15];
+        addr
+    }
+
+    /// The ethernet group address a multicast address is sent to, 33:33 and its low 32 bits
+    pub fn multicast_mac(&self) -> MacAddr {
+        MacAddr { bytes: [0x33, 0x33, self.bytes[12], self.bytes[13], self.bytes[14], self.bytes[15]] }
+    }
+
+    fn segment(&self, i: usize) -> u16 {
+        (self.bytes[i * 2] as u16) << 8 | self.bytes[i * 2 + 1] as u16
+    }
+
+    /// Parse colon separated hex segments, an empty string has none
+    fn segments(string: &str) -> Option<Vec<u16>> {
+        let mut segments = Vec::new();
+        if ! string.is_empty() {
+            for segment in string.split(':') {
+                if segment.is_empty() || segment.len() > 4 || ! segment.chars().all(|c| c.is_digit(16)) {
+                    return None;
+                }
+                segments.push(segment.to_num_radix(16) as u16);
+            }
+        }
+        Some(segments)
+    }
+
+    /// Parse an address in the RFC 4291 text format, None if it is not one
+    ///
+    /// An embedded IPv4 address in the last 32 bits is not accepted.
+    pub fn from_str(string: &str) -> Option<Self> {
+        let mut halves = string.splitn(2, "::");
+        let head = match Ipv6Addr::segments(halves.next().unwrap_or("")) {
+            Some(segments) => segments,
+            None => return None,
+        };
+        let (tail, compressed) = match halves.next().map(Ipv6Addr::segments) {
+            Some(Some(segments)) => (segments, true),
+            Some(None) => return None,
+            None => (Vec::new(), false),
+        };
+
+        let count = head.len() + tail.len();
+        if count > 8 || (compressed && count == 8) || (! compressed && count != 8) {
+            return None;
+        }
+
+        let mut addr = Ipv6Addr { bytes: [0; 16] };
+        for (i, segment) in head.iter().enumerate() {
+            addr.bytes[i * 2] = (segment >> 8) as u8;
+            addr.bytes[i * 2 + 1] = *segment as u8;
+        }
+        for (i, segment) in tail.iter().enumerate() {
+            let j = 8 - tail.len() + i;
+            addr.bytes[j * 2] = (segment >> 8) as u8;
+            addr.bytes[j * 2 + 1] = *segment as u8;
+        }
+
+        Some(addr)
+    }
+
+    /// Format the address as RFC 5952 recommends, with the longest run of zero segments compressed
     pub fn to_string(&self) -> String {
-        let mut string = String::new();
+        let mut best_start = 8;
+        let mut best_len = 1;
+        let mut i = 0;
+        while i < 8 {
+            if self.segment(i) == 0 {
+                let start = i;
+                while i < 8 && self.segment(i) == 0 {
+                    i += 1;
+                }
+                if i - start > best_len {
+                    best_start = start;
+                    best_len = i - start;
+                }
+            } else {
+                i += 1;
+            }
+        }
 
-        for i in 0..16 {
-            if i > 0 && i % 2 == 0 {
-                string = string + ".";
+        let mut string = String::new();
+        let mut i = 0;
+        while i < 8 {
+            if i == best_start {
+                string.push_str("::");
+                i += best_len;
+                continue;
             }
-            string = string + &format!("{}", self.bytes[i]);
+            if i > 0 && i != best_start + best_len {
+                string.push(':');
+            }
+            string.push_str(&format!("{:x}", self.segment(i)));
+            i += 1;
         }
 
         string
     }
 }
 
+/// An address of either IP version, for schemes that accept both
+#[derive(Copy, Clone)]
+pub enum IpAddr {
+    V4(Ipv4Addr),
+    V6(Ipv6Addr),
+}
+
+impl IpAddr {
+    /// Format the address for a path, an IPv6 address is bracketed so a port can follow it
+    pub fn to_string(&self) -> String {
+        match *self {
+            IpAddr::V4(addr) => addr.to_string(),
+            IpAddr::V6(addr) => format!("[{}]", addr.to_string()),
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct Checksum {
     pub data: u16,
@@ -229,11 +496,16 @@ impl Checksum {
         sum
     }
 
-    pub fn compile(mut sum: usize) -> u16 {
+    pub fn compile(sum: usize) -> u16 {
+        0xFFFF - Checksum::fold(sum)
+    }
+
+    /// Fold a sum to 16 bits without complementing it, as a partial checksum left for the NIC
+    pub fn fold(mut sum: usize) -> u16 {
         while (sum >> 16) > 0 {
             sum = (sum & 0xFFFF) + (sum >> 16);
         }
 
-        0xFFFF - (sum as u16)
+        sum as u16
     }
 }
@@ -1,11 +1,10 @@
-use common::slice::GetSlice;
-
 use collections::slice;
 use collections::vec::Vec;
 
 use core::mem;
 
 use network::common::*;
+use network::packet::EthernetFrame;
 
 #[derive(Copy, Clone)]
 #[repr(packed)]
@@ -22,15 +21,14 @@ pub struct EthernetII {
 
 impl FromBytes for EthernetII {
     fn from_bytes(bytes: &[u8]) -> Option<Self> {
-        if bytes.len() >= mem::size_of::<EthernetIIHeader>() {
-            unsafe {
-                return Some(EthernetII {
-                    header: *(bytes.as_ptr() as *const EthernetIIHeader),
-                    data: bytes.get_slice(mem::size_of::<EthernetIIHeader>() ..).to_vec(),
-                });
-            }
-        }
-        None
+        EthernetFrame::new(bytes).map(|frame| EthernetII {
+            header: EthernetIIHeader {
+                dst: frame.dst(),
+                src: frame.src(),
+                ethertype: n16::new(frame.ethertype()),
+            },
+            data: frame.payload().to_vec(),
+        })
     }
 }
 
@@ -1,22 +1,25 @@
 use alloc::boxed::Box;
 
-use arch::memory;
+use arch::dma::{self, Dma};
 
-use collections::slice;
+use collections::string::{String, ToString};
 use collections::vec::Vec;
 use collections::vec_deque::VecDeque;
 
-use core::cell::UnsafeCell;
+use core::mem::size_of;
 use core::ptr;
 
 use drivers::pci::config::PciConfig;
 
-use network::common::*;
-use network::scheme::*;
+use common::event::LinkEvent;
 
-use fs::{KScheme, Resource};
+use network::{link_changed, NetworkDevice, NetworkStats, ETHERNET_MTU, OFFLOAD_RX_CHECKSUM, OFFLOAD_TX_CHECKSUM};
+use network::ipv4::finish_checksums;
+use network::common::*;
+use network::pool::FrameBuffer;
+use network::packet::{EthernetFrame, ETHERNET_HEADER_LEN, ETHERTYPE_IPV4, IPV4_HEADER_LEN, IP_PROTO_TCP, IP_PROTO_UDP};
 
-use system::error::Result;
+use system::error::{Error, Result, ENETDOWN};
 
 const CTRL: u32 = 0x00;
 const CTRL_LRST: u32 = 1 << 3;
@@ -28,14 +31,26 @@ const CTRL_PHY_RST: u32 = 1 << 31;
 
 const STATUS: u32 = 0x08;
 
+const EERD: u32 = 0x14;
+const EERD_START: u32 = 1;
+const EERD_DONE: u32 = 1 << 4;
+/// The words covered by the checksum, the last of them is set so they add up to `EEPROM_CHECKSUM`
+const EEPROM_WORDS: u32 = 0x40;
+const EEPROM_CHECKSUM: u16 = 0xBABA;
+const STATUS_FD: u32 = 1;
+const STATUS_LU: u32 = 1 << 1;
+const STATUS_SPEED_SHIFT: u32 = 6;
+
 const FCAL: u32 = 0x28;
 const FCAH: u32 = 0x2C;
 const FCT: u32 = 0x30;
 const FCTTV: u32 = 0x170;
 
 const ICR: u32 = 0xC0;
+const ICR_LSC: u32 = 1 << 2;
 
 const IMS: u32 = 0xD0;
+const IMC: u32 = 0xD8;
 const IMS_TXDW: u32 = 1;
 const IMS_TXQE: u32 = 1 << 1;
 const IMS_LSC: u32 = 1 << 2;
@@ -50,6 +65,8 @@ const RCTL_UPE: u32 = 1 << 3;
 const RCTL_MPE: u32 = 1 << 4;
 const RCTL_LPE: u32 = 1 << 5;
 const RCTL_LBM: u32 = 1 << 6 | 1 << 7;
+/// Frames are turned around in the MAC, before the PHY
+const RCTL_LBM_MAC: u32 = 1 << 6;
 const RCTL_BAM: u32 = 1 << 15;
 const RCTL_BSIZE1: u32 = 1 << 16;
 const RCTL_BSIZE2: u32 = 1 << 17;
@@ -62,8 +79,13 @@ const RDLEN: u32 = 0x2808;
 const RDH: u32 = 0x2810;
 const RDT: u32 = 0x2818;
 
+/// Multicast table array, a 4096 bit hash filter
+const MTA: u32 = 0x5200;
+const MTA_LENGTH: u32 = 128;
+
 const RAL0: u32 = 0x5400;
 const RAH0: u32 = 0x5404;
+const RAH_AV: u32 = 1 << 31;
 
 #[repr(packed)]
 struct Rd {
@@ -76,6 +98,30 @@ struct Rd {
 }
 const RD_DD: u8 = 1;
 const RD_EOP: u8 = 1 << 1;
+const RD_IXSM: u8 = 1 << 2;
+const RD_TCPCS: u8 = 1 << 5;
+const RD_IPCS: u8 = 1 << 6;
+const RD_ERR_CE: u8 = 1;
+const RD_ERR_TCPE: u8 = 1 << 5;
+const RD_ERR_IPE: u8 = 1 << 6;
+
+/// Receive checksum control
+const RXCSUM: u32 = 0x5000;
+const RXCSUM_IPOFL: u32 = 1 << 8;
+const RXCSUM_TUOFL: u32 = 1 << 9;
+
+/// Wake up control
+const WUC: u32 = 0x5800;
+const WUC_APME: u32 = 1;
+const WUC_PME_EN: u32 = 1 << 1;
+/// Wake up filter control
+const WUFC: u32 = 0x5808;
+const WUFC_MAG: u32 = 1 << 1;
+/// Wake up status, cleared by writing ones
+const WUS: u32 = 0x5810;
+
+/// Missed packets count, cleared on read
+const MPC: u32 = 0x4010;
 
 const TCTL: u32 = 0x400;
 const TCTL_EN: u32 = 1 << 1;
@@ -100,87 +146,275 @@ struct Td {
 const TD_CMD_EOP: u8 = 1;
 const TD_CMD_IFCS: u8 = 1 << 1;
 const TD_CMD_RS: u8 = 1 << 3;
+const TD_CMD_DEXT: u8 = 1 << 5;
 const TD_DD: u8 = 1;
+/// In an extended data descriptor, `cso` holds the high length bits and the descriptor type
+const TD_DTYP_DATA: u8 = 1 << 4;
+/// In an extended data descriptor, `css` holds the options to insert the IP and TCP/UDP checksums
+const TD_POPTS_IXSM: u8 = 1;
+const TD_POPTS_TXSM: u8 = 1 << 1;
+
+/// A TCP/IP context descriptor, saying where the checksums of the following data descriptors go
+#[repr(packed)]
+struct TxContext {
+    ipcss: u8,
+    ipcso: u8,
+    ipcse: u16,
+    tucss: u8,
+    tucso: u8,
+    tucse: u16,
+    command: u32,
+    status: u8,
+    hdrlen: u8,
+    mss: u16,
+}
+const TXC_TUCMD_TCP: u32 = 1 << 24;
+const TXC_TUCMD_IP: u32 = 1 << 25;
+const TXC_TUCMD_RS: u32 = 1 << 27;
+const TXC_TUCMD_DEXT: u32 = 1 << 29;
+
+/// Receive descriptors, the ring size in bytes must be a multiple of 128
+const RX_RING_LENGTH: usize = 32;
+/// Transmit descriptors, the ring size in bytes must be a multiple of 128
+const TX_RING_LENGTH: usize = 32;
+/// Buffer size for a standard MTU frame (RCTL.BSIZE = 2048)
+const BUFFER_SIZE: usize = 2048;
+/// Largest MTU, frames longer than a standard frame need RCTL.LPE
+const MAX_MTU: usize = 9000;
+/// Frames waiting for a free transmit descriptor, further frames are dropped
+const TX_QUEUE_MAX: usize = 64;
 
 pub struct Intel8254x {
     pub pci: PciConfig,
     pub base: usize,
     pub memory_mapped: bool,
     pub irq: u8,
-    pub resources: UnsafeCell<Vec<*mut NetworkResource>>,
-    pub inbound: VecDeque<Vec<u8>>,
-    pub outbound: VecDeque<Vec<u8>>,
+    pub mac: MacAddr,
+    pub inbound: VecDeque<FrameBuffer>,
+    pub outbound: VecDeque<FrameBuffer>,
+    receive_ring: Dma<Rd>,
+    transmit_ring: Dma<Td>,
+    /// The buffer of each receive descriptor
+    receive_buffers: Vec<Dma<u8>>,
+    /// Next receive descriptor to check for a frame
+    rx_next: usize,
+    /// Next transmit descriptor to fill
+    tx_tail: usize,
+    /// Oldest transmit descriptor not yet written back
+    tx_clean: usize,
+    /// The buffer of each transmit descriptor, kept apart since a context descriptor overwrites it
+    transmit_buffers: Vec<Dma<u8>>,
+    /// Size of every receive and transmit buffer, enough for a full MTU frame
+    buffer_size: usize,
+    /// The protocol the loaded checksum context is for, zero if there is none
+    tx_context: u8,
+    stats: NetworkStats,
+    /// EEPROM words 0x00 to 0x3F, up to the first that did not read
+    eeprom: Vec<u16>,
+    /// Why the EEPROM failed validation, if it did
+    eeprom_error: Option<String>,
+    /// Kept down for a failed EEPROM, until it is forced up through network:/eeprom
+    down: bool,
 }
 
-impl KScheme for Intel8254x {
-    fn scheme(&self) -> &str {
-        "network"
+impl NetworkDevice for Intel8254x {
+    fn name(&self) -> &str {
+        "Intel 8254x"
     }
 
-    fn open(&mut self, _: &str, _: usize) -> Result<Box<Resource>> {
-        Ok(NetworkResource::new(self))
+    fn on_irq(&mut self, irq: u8) -> bool {
+        if irq == self.irq {
+            let icr = unsafe { self.read(ICR) };
+
+            if icr & ICR_LSC == ICR_LSC {
+                link_changed(self.link());
+            }
+
+            unsafe { self.send_outbound(); }
+
+            icr != 0
+        } else {
+            false
+        }
     }
 
-    fn on_irq(&mut self, irq: u8) {
-        if irq == self.irq {
-            unsafe { self.read(ICR) };
+    fn mac(&self) -> MacAddr {
+        self.mac
+    }
 
-            self.sync();
+    fn set_mac(&mut self, mac: MacAddr) {
+        self.mac = mac;
+        unsafe {
+            self.write(RAL0, (mac.bytes[0] as u32) | (mac.bytes[1] as u32) << 8 |
+                             (mac.bytes[2] as u32) << 16 | (mac.bytes[3] as u32) << 24);
+            self.write(RAH0, (mac.bytes[4] as u32) | (mac.bytes[5] as u32) << 8 | RAH_AV);
         }
     }
-}
 
-impl NetworkScheme for Intel8254x {
-    fn add(&mut self, resource: *mut NetworkResource) {
-        unsafe { &mut *self.resources.get() }.push(resource);
+    /// The unicast and multicast promiscuous bits bypass the filters, which are left untouched
+    fn set_promiscuous(&mut self, promiscuous: bool) {
+        unsafe { self.flag(RCTL, RCTL_UPE | RCTL_MPE, promiscuous) };
     }
 
-    fn remove(&mut self, resource: *mut NetworkResource) {
-        let mut resources = unsafe { &mut *self.resources.get() };
+    fn offload(&self) -> u32 {
+        OFFLOAD_TX_CHECKSUM | OFFLOAD_RX_CHECKSUM
+    }
 
-        let mut i = 0;
-        while i < resources.len() {
-            let mut remove = false;
+    fn max_mtu(&self) -> usize {
+        MAX_MTU
+    }
 
-            match resources.get(i) {
-                Some(ptr) => if *ptr == resource {
-                    remove = true;
-                } else {
-                    i += 1;
-                },
-                None => break,
+    fn set_mtu(&mut self, mtu: usize) {
+        // A frame with its header, a VLAN tag and the CRC, in the smallest buffer size the hardware has
+        let frame = mtu + 22;
+        let size = [2048, 4096, 8192, 16384].iter().cloned().find(|&size| frame <= size).unwrap_or(16384);
+        unsafe {
+            if size != self.buffer_size {
+                self.resize_buffers(size);
             }
+            self.flag(RCTL, RCTL_LPE, mtu > ETHERNET_MTU);
+        }
+    }
 
-            if remove {
-                resources.remove(i);
-            }
+    fn set_multicast(&mut self, addrs: &[MacAddr]) {
+        let mut table = [0u32; MTA_LENGTH as usize];
+        for addr in addrs.iter() {
+            // With RCTL.MO at zero the hash is bits 47:36 of the address
+            let hash = (addr.bytes[4] as usize >> 4) | (addr.bytes[5] as usize) << 4;
+            table[hash >> 5] |= 1 << (hash & 31);
+        }
+
+        for (i, &entry) in table.iter().enumerate() {
+            unsafe { self.write(MTA + i as u32 * 4, entry) };
         }
     }
 
-    fn sync(&mut self) {
-        {
-            let resources = unsafe { &mut *self.resources.get() };
+    fn wake_on_lan(&self) -> bool {
+        let mut pci = self.pci;
+        unsafe { pci.pme_supported() }
+    }
+
+    fn set_wake_on_lan(&mut self, enabled: bool) {
+        unsafe {
+            self.write(WUS, 0xFFFFFFFF);
+            self.write(WUFC, if enabled { WUFC_MAG } else { 0 });
+            self.flag(WUC, WUC_APME | WUC_PME_EN, enabled);
+            self.pci.set_pme(enabled);
+        }
+    }
 
-            for resource in resources.iter() {
-                while let Some(bytes) = unsafe { &mut *(**resource).outbound.get() }.pop_front() {
-                    self.outbound.push_back(bytes);
-                }
+    /// Go to D3hot, the receiver is left on if it has to see a magic packet
+    fn shutdown(&mut self) {
+        unsafe {
+            self.write(IMC, 0xFFFFFFFF);
+            self.flag(TCTL, TCTL_EN, false);
+            if self.read(WUFC) & WUFC_MAG != WUFC_MAG {
+                self.flag(RCTL, RCTL_EN, false);
             }
+            self.pci.set_power_state(3);
         }
+    }
 
+    fn send(&mut self, frame: FrameBuffer) -> Result<usize> {
+        if self.down {
+            return Err(Error::new(ENETDOWN));
+        }
+
+        let len = frame.len();
+        self.outbound.push_back(frame);
         unsafe { self.send_outbound(); }
+        Ok(len)
+    }
+
+    fn receive(&mut self) -> Option<FrameBuffer> {
+        if self.inbound.is_empty() {
+            unsafe { self.receive_inbound(); }
+        }
+        self.inbound.pop_front()
+    }
 
-        unsafe { self.receive_inbound(); }
+    /// Read the link state from the status register
+    fn link(&mut self) -> LinkEvent {
+        let status = unsafe { self.read(STATUS) };
+        LinkEvent {
+            up: status & STATUS_LU == STATUS_LU,
+            speed: match (status >> STATUS_SPEED_SHIFT) & 0b11 {
+                0b00 => 10,
+                0b01 => 100,
+                _ => 1000,
+            },
+            full_duplex: status & STATUS_FD == STATUS_FD,
+        }
+    }
 
-        {
-            let resources = unsafe { &mut *self.resources.get() };
+    fn stats(&mut self) -> &mut NetworkStats {
+        &mut self.stats
+    }
 
-            while let Some(bytes) = self.inbound.pop_front() {
-                for resource in resources.iter() {
-                    unsafe { (**resource).inbound.send(bytes.clone(), "Intel8254x::sync") };
-                }
+    /// The receive address and the receiver and transmitter
+    fn check_registers(&mut self) -> Option<String> {
+        unsafe {
+            if self.read(STATUS) == 0xFFFFFFFF {
+                return Some("the device does not respond".to_string());
+            }
+
+            if self.down {
+                return Some(format!("kept down, the EEPROM {}", self.eeprom_error.as_ref().map_or("", |error| &error[..])));
+            }
+
+            let (ral, rah) = (self.read(RAL0), self.read(RAH0));
+            let mac = MacAddr {
+                bytes: [ral as u8, (ral >> 8) as u8, (ral >> 16) as u8, (ral >> 24) as u8, rah as u8, (rah >> 8) as u8],
+            };
+            if rah & RAH_AV != RAH_AV || ! mac.equals(self.mac) {
+                return Some(format!("RAL0 and RAH0 have {}, not {}", mac.to_string(), self.mac.to_string()));
+            }
+
+            let (rctl, tctl) = (self.read(RCTL), self.read(TCTL));
+            if rctl & RCTL_EN != RCTL_EN || tctl & TCTL_EN != TCTL_EN {
+                return Some(format!("receiver or transmitter off, RCTL {:X} TCTL {:X}", rctl, tctl));
+            }
+        }
+
+        None
+    }
+
+    fn set_loopback(&mut self, enabled: bool) -> bool {
+        unsafe { self.flag(RCTL, RCTL_LBM_MAC, enabled) };
+        true
+    }
+
+    fn eeprom(&mut self) -> Option<String> {
+        let mut string = match self.eeprom_error {
+            Some(ref error) => format!("checksum: failed, {}\n", error),
+            None => "checksum: ok\n".to_string(),
+        };
+        if self.down {
+            string.push_str("interface: down, write force to bring it up\n");
+        }
+
+        for (i, word) in self.eeprom.iter().enumerate() {
+            if i % 8 == 0 {
+                string.push_str(&format!("{:02X}:", i));
+            }
+            string.push_str(&format!(" {:04X}", word));
+            if i % 8 == 7 || i + 1 == self.eeprom.len() {
+                string.push('\n');
             }
         }
+        Some(string)
+    }
+
+    fn force_eeprom(&mut self) -> bool {
+        if ! self.down {
+            return false;
+        }
+
+        syslog_warning!("Intel 8254x: Forced up with {} from an EEPROM that {}",
+                        self.mac.to_string(), self.eeprom_error.as_ref().map_or("", |error| &error[..]));
+        self.down = false;
+        unsafe { self.enable(); }
+        true
     }
 }
 
@@ -192,10 +426,23 @@ impl Intel8254x {
             pci: pci,
             base: base & 0xFFFFFFF0,
             memory_mapped: base & 1 == 0,
-            irq: pci.read(0x3C) as u8 & 0xF,
-            resources: UnsafeCell::new(Vec::new()),
+            irq: pci.irq(),
+            mac: MacAddr { bytes: [0; 6] },
             inbound: VecDeque::new(),
             outbound: VecDeque::new(),
+            receive_ring: dma::alloc_contiguous(RX_RING_LENGTH * size_of::<Rd>(), 16).unwrap(),
+            transmit_ring: dma::alloc_contiguous(TX_RING_LENGTH * size_of::<Td>(), 16).unwrap(),
+            receive_buffers: Vec::new(),
+            rx_next: 0,
+            tx_tail: 0,
+            tx_clean: 0,
+            transmit_buffers: Vec::new(),
+            buffer_size: BUFFER_SIZE,
+            tx_context: 0,
+            stats: NetworkStats::default(),
+            eeprom: Vec::new(),
+            eeprom_error: None,
+            down: false,
         };
 
         module.init();
@@ -204,56 +451,279 @@ impl Intel8254x {
     }
 
     pub unsafe fn receive_inbound(&mut self) {
-        let receive_ring = self.read(RDBAL) as *mut Rd;
-        let length = self.read(RDLEN);
+        loop {
+            let rd = &mut *self.receive_ring.as_mut_ptr().offset(self.rx_next as isize);
+            if rd.status & RD_DD != RD_DD {
+                break;
+            }
+
+            if rd.status & RD_EOP == RD_EOP && rd.error & (RD_ERR_IPE | RD_ERR_TCPE) != 0 && rd.error & RD_ERR_CE == 0 {
+                self.stats.rx_dropped += 1;
+                self.stats.rx_checksum_errors += 1;
+            } else if rd.status & RD_EOP == RD_EOP && rd.error == 0 {
+                match FrameBuffer::from_raw_parts(self.receive_buffers[self.rx_next].as_ptr(), rd.length as usize) {
+                    // What the hardware did not verify is checked in software by the network scheme
+                    Some(mut frame) => {
+                        if Intel8254x::checksums_verified(rd.status, &frame) {
+                            frame.set_checksums_verified();
+                        }
+                        self.inbound.push_back(frame);
+                    },
+                    None => {
+                        self.stats.rx_dropped += 1;
+                        self.stats.rx_no_buffer += 1;
+                    },
+                }
+            } else {
+                debugln!("Intel 8254x: Dropped frame: status {:X} error {:X}", rd.status, rd.error);
+                self.stats.rx_dropped += 1;
+                if rd.status & RD_EOP != RD_EOP {
+                    // The buffers hold a full MTU frame, so a frame spanning descriptors is too long
+                    self.stats.rx_oversize += 1;
+                } else if rd.error & RD_ERR_CE == RD_ERR_CE {
+                    self.stats.rx_crc_errors += 1;
+                }
+            }
 
-        for tail in 0..length / 16 {
-            let rd = &mut *receive_ring.offset(tail as isize);
-            if rd.status & RD_DD == RD_DD {
-                self.inbound.push_back(Vec::from(slice::from_raw_parts(rd.buffer as *const u8, rd.length as usize)));
+            rd.status = 0;
+
+            // Hand the descriptor back to the card
+            self.write(RDT, self.rx_next as u32);
+            self.rx_next = (self.rx_next + 1) % RX_RING_LENGTH;
+        }
+
+        let missed = self.read(MPC) as u64;
+        self.stats.rx_overruns += missed;
+        self.stats.rx_dropped += missed;
+    }
 
-                rd.status = 0;
+    /// Reclaim transmit descriptors the card has written back
+    unsafe fn reclaim_transmit(&mut self) {
+        while self.tx_clean != self.tx_tail {
+            let td = &mut *self.transmit_ring.as_mut_ptr().offset(self.tx_clean as isize);
+            if td.status & TD_DD != TD_DD {
+                break;
             }
+
+            td.status = 0;
+            self.tx_clean = (self.tx_clean + 1) % TX_RING_LENGTH;
+        }
+    }
+
+    /// Check if the hardware verified every checksum a received frame has
+    fn checksums_verified(status: u8, frame: &[u8]) -> bool {
+        let frame = match EthernetFrame::new(frame) {
+            Some(frame) if frame.ethertype() == ETHERTYPE_IPV4 => frame,
+            _ => return true,
+        };
+        let packet = match frame.ipv4() {
+            Some(packet) => packet,
+            None => return false,
+        };
+        if status & RD_IXSM == RD_IXSM || status & RD_IPCS != RD_IPCS {
+            return false;
+        }
+        match packet.proto() {
+            IP_PROTO_TCP | IP_PROTO_UDP => status & RD_TCPCS == RD_TCPCS,
+            _ => true,
+        }
+    }
+
+    /// The protocol of a frame whose checksums the hardware can insert
+    ///
+    /// Only IPv4 without options carrying TCP or UDP is offloaded, the context offsets are fixed
+    fn offload_proto(frame: &[u8]) -> Option<u8> {
+        match EthernetFrame::new(frame).and_then(|frame| frame.ipv4()) {
+            Some(packet) if packet.header_len() == IPV4_HEADER_LEN && packet.checksums_pending() &&
+                            (packet.proto() == IP_PROTO_TCP || packet.proto() == IP_PROTO_UDP) => Some(packet.proto()),
+            _ => None,
         }
     }
 
+    /// Load a checksum context for TCP or UDP in the next descriptor
+    unsafe fn load_context(&mut self, proto: u8) {
+        let context = &mut *(self.transmit_ring.as_mut_ptr().offset(self.tx_tail as isize) as *mut TxContext);
+        let ip = ETHERNET_HEADER_LEN;
+        let transport = ETHERNET_HEADER_LEN + IPV4_HEADER_LEN;
+        context.ipcss = ip as u8;
+        context.ipcso = (ip + 10) as u8;
+        context.ipcse = (transport - 1) as u16;
+        context.tucss = transport as u8;
+        context.tucso = (transport + if proto == IP_PROTO_TCP { 16 } else { 6 }) as u8;
+        // To the end of the frame
+        context.tucse = 0;
+        context.command = TXC_TUCMD_DEXT | TXC_TUCMD_RS | TXC_TUCMD_IP | if proto == IP_PROTO_TCP { TXC_TUCMD_TCP } else { 0 };
+        context.status = 0;
+        context.hdrlen = 0;
+        context.mss = 0;
+
+        self.tx_context = proto;
+        self.tx_tail = (self.tx_tail + 1) % TX_RING_LENGTH;
+    }
+
     pub unsafe fn send_outbound(&mut self) {
-        while let Some(bytes) = self.outbound.pop_front() {
-            let transmit_ring = self.read(TDBAL) as *mut Td;
-            let length = self.read(TDLEN);
-
-            loop {
-                let head = self.read(TDH);
-                let mut tail = self.read(TDT);
-                let old_tail = tail;
-
-                tail += 1;
-                if tail >= length / 16 {
-                    tail = 0;
-                }
+        self.reclaim_transmit();
+
+        while let Some(mut bytes) = self.outbound.pop_front() {
+            if bytes.len() > self.buffer_size {
+                // TODO: More than one TD
+                debugln!("Intel 8254x: Frame too long for transmit: {}", bytes.len());
+                self.stats.tx_dropped += 1;
+                self.stats.tx_oversize += 1;
+                continue;
+            }
 
-                if tail != head {
-                    if bytes.len() < 16384 {
-                        let td = &mut *transmit_ring.offset(old_tail as isize);
-
-                        ::memcpy(td.buffer as *mut u8, bytes.as_ptr(), bytes.len());
-                        td.length = (bytes.len() & 0x3FFF) as u16;
-                        td.cso = 0;
-                        td.command = TD_CMD_EOP | TD_CMD_IFCS | TD_CMD_RS;
-                        td.status = 0;
-                        td.css = 0;
-                        td.special = 0;
-
-                        self.write(TDT, tail);
-                    } else {
-                        // TODO: More than one TD
-                        debugln!("Intel 8254x: Frame too long for transmit: {}", bytes.len());
+            let offload = Intel8254x::offload_proto(&bytes);
+            let needed = match offload {
+                Some(proto) if proto != self.tx_context => 2,
+                _ => 1,
+            };
+
+            let free = (self.tx_clean + TX_RING_LENGTH - self.tx_tail - 1) % TX_RING_LENGTH;
+            if free < needed {
+                // Ring is full, wait for a TXDW interrupt
+                self.outbound.push_front(bytes);
+                break;
+            }
+
+            match offload {
+                Some(proto) => if proto != self.tx_context {
+                    self.load_context(proto);
+                },
+                // Checksums left for us that the hardware cannot insert are done in software
+                None => if EthernetFrame::new(&bytes).and_then(|frame| frame.ipv4()).map_or(false, |packet| packet.checksums_pending()) {
+                    // A frame whose slot other handles share is copied first, so they keep it as it was
+                    match bytes.make_mut() {
+                        Some(bytes) => finish_checksums(&mut bytes[ETHERNET_HEADER_LEN..]),
+                        None => {
+                            self.stats.tx_dropped += 1;
+                            continue;
+                        }
                     }
+                },
+            }
 
-                    break;
-                }
+            let td = &mut *self.transmit_ring.as_mut_ptr().offset(self.tx_tail as isize);
+            {
+                let buffer = &mut self.transmit_buffers[self.tx_tail];
+                ::memcpy(buffer.as_mut_ptr(), bytes.as_ptr(), bytes.len());
+                td.buffer = buffer.physical() as u64;
+            }
+            td.length = (bytes.len() & 0x3FFF) as u16;
+            td.status = 0;
+            td.special = 0;
+            if offload.is_some() {
+                td.cso = TD_DTYP_DATA;
+                td.command = TD_CMD_EOP | TD_CMD_IFCS | TD_CMD_RS | TD_CMD_DEXT;
+                td.css = TD_POPTS_IXSM | TD_POPTS_TXSM;
+            } else {
+                td.cso = 0;
+                td.command = TD_CMD_EOP | TD_CMD_IFCS | TD_CMD_RS;
+                td.css = 0;
             }
+
+            self.tx_tail = (self.tx_tail + 1) % TX_RING_LENGTH;
+            self.write(TDT, self.tx_tail as u32);
         }
+
+        while self.outbound.len() > TX_QUEUE_MAX {
+            self.outbound.pop_front();
+            self.stats.tx_dropped += 1;
+        }
+    }
+
+    /// Program the receive buffer size, one of the sizes RCTL.BSIZE and RCTL.BSEX can express
+    unsafe fn set_buffer_size(&self, size: usize) {
+        self.flag(RCTL, RCTL_BSIZE1 | RCTL_BSIZE2 | RCTL_BSEX, false);
+        self.flag(RCTL, match size {
+            2048 => 0,
+            4096 => RCTL_BSEX | RCTL_BSIZE1 | RCTL_BSIZE2,
+            8192 => RCTL_BSEX | RCTL_BSIZE2,
+            _ => RCTL_BSEX | RCTL_BSIZE1,
+        }, true);
+    }
+
+    /// Replace every buffer, with the receiver stopped and the frames in flight sent
+    unsafe fn resize_buffers(&mut self, size: usize) {
+        self.flag(RCTL, RCTL_EN, false);
+        self.receive_inbound();
+
+        let mut timeout = 100000;
+        while self.tx_clean != self.tx_tail && timeout > 0 {
+            self.reclaim_transmit();
+            timeout -= 1;
+        }
+        if self.tx_clean != self.tx_tail {
+            debugln!("Intel 8254x: Transmit ring did not drain before resizing buffers");
+        }
+
+        for i in 0..RX_RING_LENGTH {
+            let buffer = dma::alloc_contiguous(size, 16).unwrap();
+            let rd = &mut *self.receive_ring.as_mut_ptr().offset(i as isize);
+            rd.buffer = buffer.physical() as u64;
+            rd.status = 0;
+            self.receive_buffers[i] = buffer;
+        }
+        for buffer in self.transmit_buffers.iter_mut() {
+            *buffer = dma::alloc_contiguous(size, 16).unwrap();
+        }
+        self.buffer_size = size;
+
+        self.write(RDH, 0);
+        self.write(RDT, RX_RING_LENGTH as u32 - 1);
+        self.rx_next = 0;
+
+        self.set_buffer_size(size);
+        self.flag(RCTL, RCTL_EN, ! self.down);
+    }
+
+    /// Read a word from the EEPROM, None if the read does not complete
+    pub unsafe fn eeprom_read(&self, word: u32) -> Option<u16> {
+        self.write(EERD, (word << 8) | EERD_START);
+        for _ in 0..100000 {
+            let eerd = self.read(EERD);
+            if eerd & EERD_DONE == EERD_DONE {
+                return Some((eerd >> 16) as u16);
+            }
+        }
+        None
+    }
+
+    /// Read the words under the checksum, returning why they are not valid, if they are not
+    unsafe fn eeprom_validate(&mut self) -> Option<String> {
+        self.eeprom.clear();
+        for word in 0..EEPROM_WORDS {
+            match self.eeprom_read(word) {
+                Some(value) => self.eeprom.push(value),
+                None => return Some(format!("did not answer a read of word {:02X}", word)),
+            }
+        }
+
+        let sum = self.eeprom.iter().fold(0u16, |sum, &word| sum.wrapping_add(word));
+        if sum != EEPROM_CHECKSUM {
+            Some(format!("sums to {:04X}, not {:04X}", sum, EEPROM_CHECKSUM))
+        } else {
+            None
+        }
+    }
+
+    /// The address in RAL0 and RAH0, if it is marked valid and could be one
+    unsafe fn receive_address(&self) -> Option<MacAddr> {
+        let (ral, rah) = (self.read(RAL0), self.read(RAH0));
+        let mac = MacAddr {
+            bytes: [ral as u8, (ral >> 8) as u8, (ral >> 16) as u8, (ral >> 24) as u8, rah as u8, (rah >> 8) as u8],
+        };
+        if rah & RAH_AV == RAH_AV && mac.valid() {
+            Some(mac)
+        } else {
+            None
+        }
+    }
+
+    /// Start the receiver and transmitter
+    unsafe fn enable(&mut self) {
+        self.flag(RCTL, RCTL_EN, true);
+        self.flag(TCTL, TCTL_EN, true);
     }
 
     pub unsafe fn read(&self, register: u32) -> u32 {
@@ -303,52 +773,71 @@ impl Intel8254x {
 
         // TODO: Clear statistical counters
 
-        let mac_low = self.read(RAL0);
-        let mac_high = self.read(RAH0);
-        MAC_ADDR = MacAddr {
-            bytes: [mac_low as u8,
-                    (mac_low >> 8) as u8,
-                    (mac_low >> 16) as u8,
-                    (mac_low >> 24) as u8,
-                    mac_high as u8,
-                    (mac_high >> 8) as u8],
-        };
+        // The EEPROM holds the address in words 0 to 2, the receive address registers are loaded from it at reset
+        self.eeprom_error = self.eeprom_validate();
+        let mut mac = MacAddr { bytes: [0; 6] };
+        if self.eeprom.len() >= 3 {
+            let (a, b, c) = (self.eeprom[0], self.eeprom[1], self.eeprom[2]);
+            mac.bytes = [a as u8, (a >> 8) as u8, b as u8, (b >> 8) as u8, c as u8, (c >> 8) as u8];
+        }
+
+        if let Some(error) = self.eeprom_error.clone() {
+            // Firmware may have programmed an address the EEPROM does not agree with
+            if let Some(programmed) = self.receive_address() {
+                syslog_warning!("   - EEPROM {}, using {} from RAL0 and RAH0 instead of {}",
+                                error, programmed.to_string(), mac.to_string());
+                mac = programmed;
+                self.eeprom_error = Some(format!("{}, the address is from RAL0 and RAH0", error));
+            } else {
+                syslog_error!("   - EEPROM {}, not bringing the interface up, write force to network:/eeprom to override", error);
+                self.down = true;
+            }
+        } else if ! mac.valid() {
+            if let Some(programmed) = self.receive_address() {
+                mac = programmed;
+            }
+        }
+
+        if ! mac.valid() {
+            mac = MacAddr::random();
+            syslog_warning!("   - Invalid MAC, using {}", mac.to_string());
+        }
+
+        self.set_mac(mac);
+        MAC_ADDR = self.mac;
         syslog_info!("   - MAC: {}", &MAC_ADDR.to_string());
 
-        //
-        // MTA => 0;
-        //
+        // No multicast groups until they are requested
+        self.set_multicast(&[]);
 
         // Receive Buffer
-        let receive_ring_length = 1024;
-        let receive_ring = memory::alloc(receive_ring_length * 16) as *mut Rd;
-        for i in 0..receive_ring_length {
-            let receive_buffer = memory::alloc(16384);
-            ptr::write(receive_ring.offset(i as isize),
+        for i in 0..RX_RING_LENGTH {
+            let receive_buffer = dma::alloc_contiguous(self.buffer_size, 16).unwrap();
+            ptr::write(self.receive_ring.as_mut_ptr().offset(i as isize),
                        Rd {
-                           buffer: receive_buffer as u64,
+                           buffer: receive_buffer.physical() as u64,
                            length: 0,
                            checksum: 0,
                            status: 0,
                            error: 0,
                            special: 0,
                        });
+            self.receive_buffers.push(receive_buffer);
         }
 
         self.write(RDBAH, 0);
-        self.write(RDBAL, receive_ring as u32);
-        self.write(RDLEN, (receive_ring_length * 16) as u32);
+        self.write(RDBAL, self.receive_ring.physical() as u32);
+        self.write(RDLEN, (RX_RING_LENGTH * size_of::<Rd>()) as u32);
         self.write(RDH, 0);
-        self.write(RDT, receive_ring_length as u32 - 1);
+        self.write(RDT, RX_RING_LENGTH as u32 - 1);
+        self.rx_next = 0;
 
         // Transmit Buffer
-        let transmit_ring_length = 64;
-        let transmit_ring = memory::alloc(transmit_ring_length * 16) as *mut Td;
-        for i in 0..transmit_ring_length {
-            let transmit_buffer = memory::alloc(16384);
-            ptr::write(transmit_ring.offset(i as isize),
+        for i in 0..TX_RING_LENGTH {
+            let transmit_buffer = dma::alloc_contiguous(self.buffer_size, 16).unwrap();
+            ptr::write(self.transmit_ring.as_mut_ptr().offset(i as isize),
                        Td {
-                           buffer: transmit_buffer as u64,
+                           buffer: transmit_buffer.physical() as u64,
                            length: 0,
                            cso: 0,
                            command: 0,
@@ -356,31 +845,42 @@ impl Intel8254x {
                            css: 0,
                            special: 0,
                        });
+            self.transmit_buffers.push(transmit_buffer);
         }
 
         self.write(TDBAH, 0);
-        self.write(TDBAL, transmit_ring as u32);
-        self.write(TDLEN, (transmit_ring_length * 16) as u32);
+        self.write(TDBAL, self.transmit_ring.physical() as u32);
+        self.write(TDLEN, (TX_RING_LENGTH * size_of::<Td>()) as u32);
         self.write(TDH, 0);
         self.write(TDT, 0);
+        self.tx_tail = 0;
+        self.tx_clean = 0;
+        self.tx_context = 0;
+
+        // Verify IPv4, TCP and UDP checksums on receive
+        self.flag(RXCSUM, RXCSUM_IPOFL | RXCSUM_TUOFL, true);
 
         self.write(IMS, IMS_RXT | IMS_RX | IMS_RXDMT | IMS_RXSEQ | IMS_LSC | IMS_TXQE | IMS_TXDW);
 
-        self.flag(RCTL, RCTL_EN, true);
-        self.flag(RCTL, RCTL_UPE, true);
-        // self.flag(RCTL, RCTL_MPE, true);
-        self.flag(RCTL, RCTL_LPE, true);
+        // Unicast to us and broadcast only, promiscuous mode is enabled through network:/promisc
+        self.flag(RCTL, RCTL_UPE | RCTL_MPE, false);
+        // Long packets are enabled with a larger MTU, see `set_mtu`
+        self.flag(RCTL, RCTL_LPE, false);
         self.flag(RCTL, RCTL_LBM, false);
         // RCTL.RDMTS = Minimum threshold size ???
         // RCTL.MO = Multicast offset
         self.flag(RCTL, RCTL_BAM, true);
-        self.flag(RCTL, RCTL_BSIZE1, true);
-        self.flag(RCTL, RCTL_BSIZE2, false);
-        self.flag(RCTL, RCTL_BSEX, true);
+        self.set_buffer_size(self.buffer_size);
         self.flag(RCTL, RCTL_SECRC, true);
 
-        self.flag(TCTL, TCTL_EN, true);
         self.flag(TCTL, TCTL_PSP, true);
+        if ! self.down {
+            self.enable();
+        }
+
+        let link = self.link();
+        syslog_info!("   - Link: {}", if link.up { "up" } else { "down" });
+        LINK_UP = link.up;
         // TCTL.CT = Collition threshold
         // TCTL.COLD = Collision distance
         // TIPG Packet Gap
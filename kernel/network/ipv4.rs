@@ -1,11 +1,10 @@
-use common::slice::GetSlice;
-
 use collections::slice;
 use collections::vec::Vec;
 
 use core::mem;
 
 use network::common::*;
+use network::packet::*;
 
 #[derive(Copy, Clone)]
 #[repr(packed)]
@@ -30,19 +29,13 @@ pub struct Ipv4 {
 
 impl FromBytes for Ipv4 {
     fn from_bytes(bytes: &[u8]) -> Option<Self> {
-        if bytes.len() >= mem::size_of::<Ipv4Header>() {
-            unsafe {
-                let header = *(bytes.as_ptr() as *const Ipv4Header);
-                let header_len = ((header.ver_hlen & 0xF) << 2) as usize;
-
-                return Some(Ipv4 {
-                    header: header,
-                    options: bytes.get_slice(mem::size_of::<Ipv4Header>() .. header_len).to_vec(),
-                    data: bytes.get_slice(header_len .. header.len.get() as usize).to_vec(),
-                });
+        Ipv4Packet::new(bytes).map(|packet| unsafe {
+            Ipv4 {
+                header: *(packet.header().as_ptr() as *const Ipv4Header),
+                options: packet.options().to_vec(),
+                data: packet.payload().to_vec(),
             }
-        }
-        None
+        })
     }
 }
 
@@ -58,3 +51,76 @@ impl ToBytes for Ipv4 {
         }
     }
 }
+
+/// The offset of the checksum in a TCP or UDP header
+fn transport_checksum_offset(proto: u8) -> Option<usize> {
+    match proto {
+        IP_PROTO_TCP => Some(16),
+        IP_PROTO_UDP => Some(6),
+        _ => None,
+    }
+}
+
+/// Sum the pseudo header of a TCP or UDP segment
+pub fn pseudo_header_sum(src: &Ipv4Addr, dst: &Ipv4Addr, proto: u8, len: usize) -> usize {
+    unsafe {
+        let proto = n16::new(proto as u16);
+        let len = n16::new(len as u16);
+        Checksum::sum((src as *const Ipv4Addr) as usize, mem::size_of::<Ipv4Addr>()) +
+        Checksum::sum((dst as *const Ipv4Addr) as usize, mem::size_of::<Ipv4Addr>()) +
+        Checksum::sum((&proto as *const n16) as usize, mem::size_of::<n16>()) +
+        Checksum::sum((&len as *const n16) as usize, mem::size_of::<n16>())
+    }
+}
+
+/// Check if the checksums of a packet were left for the NIC, which is marked by a zero header checksum
+pub fn checksums_pending(packet: &[u8]) -> bool {
+    Ipv4Packet::new(packet).map_or(false, |packet| packet.checksums_pending())
+}
+
+/// Calculate the checksums left for the NIC in software
+///
+/// The header checksum is zero, and a TCP or UDP checksum holds the folded pseudo header sum
+pub fn finish_checksums(packet: &mut [u8]) {
+    let (header_len, total_len, proto) = match Ipv4Packet::new(packet) {
+        Some(view) => (view.header_len(), view.total_len(), view.proto()),
+        None => return,
+    };
+
+    if let Some(offset) = transport_checksum_offset(proto) {
+        if header_len + offset + 2 <= total_len {
+            let checksum = match Checksum::compile(sum(&packet[header_len..total_len])) {
+                // Zero means no checksum in UDP
+                0 if proto == IP_PROTO_UDP => 0xFFFF,
+                checksum => checksum,
+            };
+            put_checksum(packet, header_len + offset, checksum);
+        }
+    }
+
+    let checksum = Checksum::compile(sum(&packet[..header_len]));
+    put_checksum(packet, 10, checksum);
+}
+
+/// Verify the header checksum and any TCP or UDP checksum of a received packet
+pub fn verify_checksums(packet: &[u8]) -> bool {
+    let packet = match Ipv4Packet::new(packet) {
+        Some(packet) => packet,
+        None => return false,
+    };
+
+    if ! packet.header_valid() {
+        return false;
+    }
+
+    // A fragment does not hold the whole segment
+    if packet.is_fragment() {
+        return true;
+    }
+
+    match packet.proto() {
+        IP_PROTO_TCP => TcpSegment::new(packet.payload()).map_or(true, |segment| segment.checksum_valid(&packet.src(), &packet.dst())),
+        IP_PROTO_UDP => UdpDatagram::new(packet.payload()).map_or(true, |datagram| datagram.checksum_valid(&packet.src(), &packet.dst())),
+        _ => true,
+    }
+}
@@ -1,13 +1,29 @@
-// TODO
+use core::mem;
 
 use network::common::*;
 
-#[derive(Copy, Clone)]
-pub struct Ipv6 {
-    pub version: n32, // also has traffic class and flow label, TODO
-    pub len: n16,
-    pub next_header: u8,
-    pub hop_limit: u8,
-    pub src: Ipv6Addr,
-    pub dst: Ipv6Addr,
+/// Hop limit of unicast packets we send
+pub const IPV6_HOP_LIMIT: u8 = 64;
+/// Hop limit of multicast packets we send, which stay on the link
+pub const IPV6_MULTICAST_HOP_LIMIT: u8 = 1;
+
+/// The hop limit for packets to `dst`
+pub fn hop_limit(dst: Ipv6Addr) -> u8 {
+    if dst.is_multicast() {
+        IPV6_MULTICAST_HOP_LIMIT
+    } else {
+        IPV6_HOP_LIMIT
+    }
+}
+
+/// Sum the pseudo header of an upper layer packet, which holds a 32 bit length unlike IPv4
+pub fn pseudo_header_sum(src: &Ipv6Addr, dst: &Ipv6Addr, next_header: u8, len: usize) -> usize {
+    unsafe {
+        let len = n32::new(len as u32);
+        let next_header = n32::new(next_header as u32);
+        Checksum::sum((src as *const Ipv6Addr) as usize, mem::size_of::<Ipv6Addr>()) +
+        Checksum::sum((dst as *const Ipv6Addr) as usize, mem::size_of::<Ipv6Addr>()) +
+        Checksum::sum((&len as *const n32) as usize, mem::size_of::<n32>()) +
+        Checksum::sum((&next_header as *const n32) as usize, mem::size_of::<n32>())
+    }
 }
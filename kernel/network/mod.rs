@@ -1,18 +1,253 @@
+pub mod capture;
 pub mod common;
 pub mod ethernet;
 pub mod intel8254x;
 pub mod ipv4;
 pub mod ipv6;
+pub mod loopback;
+pub mod ne2000;
+pub mod packet;
+pub mod pcnet32;
+pub mod pool;
+pub mod route;
 pub mod rtl8139;
 pub mod scheme;
 pub mod schemes;
 
 use collections::String;
 
+use common::event::LinkEvent;
+
 use system::error::Result;
 
-pub trait Nic {
-    fn name(&self) -> String;
-    fn read(&mut self, buffer: &mut [u8]) -> Result<usize>;
-    fn write(&mut self, buffer: &[u8]) -> Result<usize>;
+use self::common::{MacAddr, LINK_UP};
+use self::pool::FrameBuffer;
+
+/// The device calculates IPv4, TCP and UDP checksums on transmit, for packets marked by `ipv4::checksums_pending`
+pub const OFFLOAD_TX_CHECKSUM: u32 = 1;
+/// The device verifies checksums on receive, marking each frame it verified with `set_checksums_verified`
+///
+/// The network scheme checks the rest in software, so every IPv4 frame of the interface reaching the stack has
+/// good checksums.
+pub const OFFLOAD_RX_CHECKSUM: u32 = 1 << 1;
+
+/// Check if the interface providing network: has an offload, the protocol schemes build and check packets for it
+///
+/// Each interface has its own, what its device reports. Packets carry no mark of the interface they came
+/// through, the protocol schemes read and write them through network: only.
+pub fn offload(flag: u32) -> bool {
+    scheme::network_provider().map_or(false, |nic| nic.device.offload() & flag == flag)
+}
+
+/// The ethernet payload size every interface starts with
+pub const ETHERNET_MTU: usize = 1500;
+/// The smallest MTU an interface may be set to, the IPv4 minimum
+pub const MIN_MTU: usize = 68;
+
+/// The MTU of the interface providing network:, which the protocol schemes size packets for
+pub fn mtu() -> usize {
+    scheme::network_provider().map_or(ETHERNET_MTU, |nic| nic.mtu())
+}
+
+/// Per-NIC counters
+#[derive(Copy, Clone, Default)]
+pub struct NetworkStats {
+    /// Frames received
+    pub rx_frames: u64,
+    /// Bytes received
+    pub rx_bytes: u64,
+    /// Frames dropped on receive, for any reason
+    pub rx_dropped: u64,
+    /// Frames lost because the receive ring was full
+    pub rx_overruns: u64,
+    /// Frames with a bad frame check sequence
+    pub rx_crc_errors: u64,
+    /// Frames too long for a receive buffer
+    pub rx_oversize: u64,
+    /// Frames with a bad IPv4, TCP or UDP checksum, when the device verifies them
+    pub rx_checksum_errors: u64,
+    /// Frames lost because the frame pool had no free buffer
+    pub rx_no_buffer: u64,
+    /// Frames transmitted
+    pub tx_frames: u64,
+    /// Bytes transmitted
+    pub tx_bytes: u64,
+    /// Frames dropped on transmit, for any reason
+    pub tx_dropped: u64,
+    /// Frames too long to transmit
+    pub tx_oversize: u64,
+}
+
+impl NetworkStats {
+    /// Reset all counters
+    pub fn reset(&mut self) {
+        *self = NetworkStats::default();
+    }
+
+    /// Format the counters as text
+    pub fn to_string(&self) -> String {
+        format!("rx_frames: {}\nrx_bytes: {}\nrx_dropped: {}\nrx_overruns: {}\nrx_crc_errors: {}\nrx_oversize: {}\nrx_checksum_errors: {}\nrx_no_buffer: {}\ntx_frames: {}\ntx_bytes: {}\ntx_dropped: {}\ntx_oversize: {}\n",
+                self.rx_frames,
+                self.rx_bytes,
+                self.rx_dropped,
+                self.rx_overruns,
+                self.rx_crc_errors,
+                self.rx_oversize,
+                self.rx_checksum_errors,
+                self.rx_no_buffer,
+                self.tx_frames,
+                self.tx_bytes,
+                self.tx_dropped,
+                self.tx_oversize)
+    }
+}
+
+/// Counters kept by the protocol schemes
+#[derive(Copy, Clone)]
+pub struct ProtocolStats {
+    /// ARP requests sent to resolve an address
+    pub arp_requests_sent: u64,
+    /// ARP requests for our address that were answered
+    pub arp_requests_answered: u64,
+    /// ICMP echo requests answered
+    pub icmp_echo_answered: u64,
+    /// IPv6 neighbor solicitations sent to resolve an address or detect a duplicate
+    pub ndp_solicitations_sent: u64,
+    /// IPv6 neighbor solicitations for our address that were answered
+    pub ndp_solicitations_answered: u64,
+    /// UDP datagrams given to a resource
+    pub udp_delivered: u64,
+    /// UDP datagrams dropped for a bad checksum
+    pub udp_checksum_errors: u64,
+    /// TCP segments sent, including retransmissions
+    pub tcp_segments_sent: u64,
+    /// TCP segments resent after a timeout
+    pub tcp_retransmits: u64,
+    /// TCP segments dropped for a bad checksum
+    pub tcp_checksum_errors: u64,
+}
+
+pub static mut PROTOCOL_STATS: ProtocolStats = ProtocolStats {
+    arp_requests_sent: 0,
+    arp_requests_answered: 0,
+    icmp_echo_answered: 0,
+    ndp_solicitations_sent: 0,
+    ndp_solicitations_answered: 0,
+    udp_delivered: 0,
+    udp_checksum_errors: 0,
+    tcp_segments_sent: 0,
+    tcp_retransmits: 0,
+    tcp_checksum_errors: 0,
+};
+
+impl ProtocolStats {
+    /// Reset all counters
+    pub fn reset(&mut self) {
+        self.arp_requests_sent = 0;
+        self.arp_requests_answered = 0;
+        self.icmp_echo_answered = 0;
+        self.ndp_solicitations_sent = 0;
+        self.ndp_solicitations_answered = 0;
+        self.udp_delivered = 0;
+        self.udp_checksum_errors = 0;
+        self.tcp_segments_sent = 0;
+        self.tcp_retransmits = 0;
+        self.tcp_checksum_errors = 0;
+    }
+
+    /// Format the counters as text
+    pub fn to_string(&self) -> String {
+        format!("arp_requests_sent: {}\narp_requests_answered: {}\nicmp_echo_answered: {}\nndp_solicitations_sent: {}\nndp_solicitations_answered: {}\nudp_delivered: {}\nudp_checksum_errors: {}\ntcp_segments_sent: {}\ntcp_retransmits: {}\ntcp_checksum_errors: {}\n",
+                self.arp_requests_sent,
+                self.arp_requests_answered,
+                self.icmp_echo_answered,
+                self.ndp_solicitations_sent,
+                self.ndp_solicitations_answered,
+                self.udp_delivered,
+                self.udp_checksum_errors,
+                self.tcp_segments_sent,
+                self.tcp_retransmits,
+                self.tcp_checksum_errors)
+    }
+}
+
+/// A NIC driver, wrapped by `NetworkScheme` to provide the network: scheme
+pub trait NetworkDevice {
+    /// The driver name
+    fn name(&self) -> &str;
+    /// Acknowledge an interrupt, returning whether it was for this device
+    fn on_irq(&mut self, irq: u8) -> bool;
+    /// The hardware address
+    fn mac(&self) -> MacAddr;
+    /// Change the hardware address, programming the receive filter to match
+    fn set_mac(&mut self, mac: MacAddr);
+    /// Accept every frame, or go back to the receive filter as it was programmed
+    fn set_promiscuous(&mut self, promiscuous: bool);
+    /// Program the multicast filter to accept these group addresses, replacing the previous list
+    fn set_multicast(&mut self, addrs: &[MacAddr]);
+    /// The `OFFLOAD_*` capabilities
+    fn offload(&self) -> u32;
+    /// The largest MTU the device can send and receive
+    fn max_mtu(&self) -> usize;
+    /// Size the device buffers for an MTU, at most `max_mtu`
+    fn set_mtu(&mut self, mtu: usize);
+    /// Queue a frame for transmission, the buffer returns to the pool once it is sent
+    fn send(&mut self, frame: FrameBuffer) -> Result<usize>;
+    /// Take a received frame, in a buffer leased from the pool
+    fn receive(&mut self) -> Option<FrameBuffer>;
+    /// Check if the device can wake the system when it receives a magic packet
+    fn wake_on_lan(&self) -> bool;
+    /// Arm or disarm waking on a magic packet, only called if `wake_on_lan` is true
+    fn set_wake_on_lan(&mut self, enabled: bool);
+    /// Prepare for power off, the device keeps receiving if wake on LAN is armed
+    fn shutdown(&mut self);
+    /// The current link state
+    fn link(&mut self) -> LinkEvent;
+    /// Driver counters
+    fn stats(&mut self) -> &mut NetworkStats;
+    /// Read back what the driver programmed, returning what does not match, if anything
+    fn check_registers(&mut self) -> Option<String>;
+    /// Turn frames around inside the device instead of sending them, returning false if it cannot
+    fn set_loopback(&mut self, enabled: bool) -> bool;
+    /// The words of the EEPROM and whether they validated, None if the device has no EEPROM
+    fn eeprom(&mut self) -> Option<String> {
+        None
+    }
+    /// Bring up a device that was kept down because its EEPROM failed validation, returning false if it
+    /// was not
+    fn force_eeprom(&mut self) -> bool {
+        false
+    }
+}
+
+/// Record a link change reported by a NIC driver and notify userspace
+pub fn link_changed(link: LinkEvent) {
+    unsafe { LINK_UP = link.up; }
+
+    // Cached addresses may be stale on a new link, announce ourselves again once it is up
+    if link.up {
+        unsafe {
+            schemes::arp::ARP_ANNOUNCE = true;
+            schemes::ndp::NDP_CONFIGURE = true;
+        }
+    } else {
+        schemes::arp::arp_flush();
+        schemes::ndp::ndp_flush();
+    }
+
+    if link.up {
+        syslog_info!("Network link up: {} Mbit/s {} duplex", link.speed, if link.full_duplex { "full" } else { "half" });
+    } else {
+        syslog_warning!("Network link down");
+    }
+
+    ::env().events.send(link.to_event(), "network::link_changed");
+}
+
+/// Format a link status for a NIC scheme status entry
+pub fn link_status(link: LinkEvent) -> String {
+    format!("link: {}\nspeed: {}\nduplex: {}\n",
+            if link.up { "up" } else { "down" },
+            link.speed,
+            if link.full_duplex { "full" } else { "half" })
 }
@@ -0,0 +1,549 @@
+use alloc::boxed::Box;
+
+use collections::string::{String, ToString};
+use collections::vec_deque::VecDeque;
+
+use core::cmp;
+
+use drivers::pci::config::PciConfig;
+use drivers::io::{Io, Pio};
+
+use common::event::LinkEvent;
+
+use network::{NetworkDevice, NetworkStats, ETHERNET_MTU};
+use network::common::*;
+use network::pool::FrameBuffer;
+
+use system::error::Result;
+
+/// Command register, the same on every page
+const CR: u16 = 0x00;
+const CR_STP: u8 = 1;
+const CR_STA: u8 = 1 << 1;
+const CR_TXP: u8 = 1 << 2;
+const CR_RD_READ: u8 = 1 << 3;
+const CR_RD_WRITE: u8 = 1 << 4;
+const CR_RD_ABORT: u8 = 1 << 5;
+const CR_PAGE1: u8 = 1 << 6;
+
+// Page 0
+const PSTART: u16 = 0x01;
+const PSTOP: u16 = 0x02;
+const BNRY: u16 = 0x03;
+const TPSR: u16 = 0x04;
+const TSR: u16 = 0x04;
+const TBCR0: u16 = 0x05;
+const TBCR1: u16 = 0x06;
+const ISR: u16 = 0x07;
+const RSAR0: u16 = 0x08;
+const RSAR1: u16 = 0x09;
+const RBCR0: u16 = 0x0A;
+const RBCR1: u16 = 0x0B;
+const RCR: u16 = 0x0C;
+const TCR: u16 = 0x0D;
+/// Frame alignment error counter, cleared on read
+const CNTR0: u16 = 0x0D;
+const DCR: u16 = 0x0E;
+/// CRC error counter, cleared on read
+const CNTR1: u16 = 0x0E;
+const IMR: u16 = 0x0F;
+/// Missed packet counter, cleared on read
+const CNTR2: u16 = 0x0F;
+
+// Page 1
+const PAR0: u16 = 0x01;
+const CURR: u16 = 0x07;
+const MAR0: u16 = 0x08;
+
+/// Remote DMA data port, 16 bits wide in word mode
+const DATA: u16 = 0x10;
+/// Reading and writing this port resets the card
+const RESET: u16 = 0x1F;
+
+const ISR_PRX: u8 = 1;
+const ISR_PTX: u8 = 1 << 1;
+const ISR_RXE: u8 = 1 << 2;
+const ISR_TXE: u8 = 1 << 3;
+const ISR_OVW: u8 = 1 << 4;
+const ISR_CNT: u8 = 1 << 5;
+const ISR_RDC: u8 = 1 << 6;
+const ISR_RST: u8 = 1 << 7;
+
+const RCR_AB: u8 = 1 << 2;
+const RCR_AM: u8 = 1 << 3;
+const RCR_PRO: u8 = 1 << 4;
+const RCR_MON: u8 = 1 << 5;
+
+const TCR_LOOPBACK: u8 = 1 << 1;
+
+/// Word transfers, normal operation, FIFO threshold of 8 bytes
+const DCR_CONFIG: u8 = 0x49;
+
+const TSR_PTX: u8 = 1;
+
+const RSR_PRX: u8 = 1;
+
+/// The on-card memory is 16 KiB from 0x4000, in 256 byte pages
+const TX_START: u8 = 0x40;
+/// Six pages hold a full frame
+const RX_START: u8 = 0x46;
+const RX_STOP: u8 = 0x80;
+
+const MIN_FRAME: usize = 60;
+const MAX_FRAME: usize = 14 + ETHERNET_MTU;
+/// Frames waiting for the transmit buffer, further frames are dropped
+const TX_QUEUE_MAX: usize = 64;
+
+/// A NE2000 compatible card, the RTL8029 on PCI
+pub struct Ne2000 {
+    pci: PciConfig,
+    base: u16,
+    irq: u8,
+    mac: MacAddr,
+    inbound: VecDeque<FrameBuffer>,
+    outbound: VecDeque<FrameBuffer>,
+    /// The card has one transmit buffer, set while it is sending from it
+    tx_busy: bool,
+    /// The next ring page to read a frame from
+    rx_next: u8,
+    /// The multicast hash, kept so it can be restored after promiscuous mode
+    multicast_filter: [u8; 8],
+    promiscuous: bool,
+    stats: NetworkStats,
+}
+
+impl NetworkDevice for Ne2000 {
+    fn name(&self) -> &str {
+        "NE2000"
+    }
+
+    fn on_irq(&mut self, irq: u8) -> bool {
+        if irq == self.irq {
+            let isr = self.read(ISR);
+            self.write(ISR, isr & ! ISR_RDC);
+
+            if isr & ISR_OVW == ISR_OVW {
+                unsafe { self.recover_overflow(); }
+            }
+
+            if isr & ISR_CNT == ISR_CNT {
+                self.read_counters();
+            }
+
+            if isr & (ISR_PTX | ISR_TXE) != 0 {
+                if isr & ISR_TXE == ISR_TXE || self.read(TSR) & TSR_PTX != TSR_PTX {
+                    debugln!("NE2000: Transmit error: {:X}", self.read(TSR));
+                    self.stats.tx_dropped += 1;
+                }
+                self.tx_busy = false;
+                unsafe { self.send_outbound(); }
+            }
+
+            isr != 0
+        } else {
+            false
+        }
+    }
+
+    fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    fn set_mac(&mut self, mac: MacAddr) {
+        self.mac = mac;
+
+        self.page(1);
+        for i in 0..6 {
+            self.write(PAR0 + i as u16, mac.bytes[i]);
+        }
+        self.page(0);
+    }
+
+    /// Accept every frame and open the multicast hash, the saved hash is written back when disabled
+    fn set_promiscuous(&mut self, promiscuous: bool) {
+        self.promiscuous = promiscuous;
+        self.write(RCR, RCR_AB | RCR_AM | if promiscuous { RCR_PRO } else { 0 });
+        let filter = if promiscuous { [0xFF; 8] } else { self.multicast_filter };
+        self.write_mar(filter);
+    }
+
+    fn set_multicast(&mut self, addrs: &[MacAddr]) {
+        let mut filter = [0; 8];
+        for addr in addrs.iter() {
+            let bit = (ether_crc(addr) >> 26) as usize;
+            filter[bit >> 3] |= 1 << (bit & 7);
+        }
+        self.multicast_filter = filter;
+
+        if ! self.promiscuous {
+            self.write_mar(filter);
+        }
+    }
+
+    fn offload(&self) -> u32 {
+        0
+    }
+
+    fn max_mtu(&self) -> usize {
+        ETHERNET_MTU
+    }
+
+    /// The buffers always hold a full ethernet frame
+    fn set_mtu(&mut self, _mtu: usize) {}
+
+    fn wake_on_lan(&self) -> bool {
+        false
+    }
+
+    fn set_wake_on_lan(&mut self, _enabled: bool) {}
+
+    /// Stop the card, the packet buffer is left as it is
+    fn shutdown(&mut self) {
+        self.write(CR, CR_STP | CR_RD_ABORT);
+    }
+
+    fn send(&mut self, frame: FrameBuffer) -> Result<usize> {
+        let len = frame.len();
+        self.outbound.push_back(frame);
+        unsafe { self.send_outbound(); }
+        Ok(len)
+    }
+
+    fn receive(&mut self) -> Option<FrameBuffer> {
+        if self.inbound.is_empty() {
+            unsafe { self.receive_inbound(); }
+        }
+        self.inbound.pop_front()
+    }
+
+    /// The card has no link status, it is always up at 10 Mbit/s
+    fn link(&mut self) -> LinkEvent {
+        LinkEvent {
+            up: true,
+            speed: 10,
+            full_duplex: false,
+        }
+    }
+
+    fn stats(&mut self) -> &mut NetworkStats {
+        &mut self.stats
+    }
+
+    /// The physical address and the running state
+    fn check_registers(&mut self) -> Option<String> {
+        let cr = self.read(CR);
+        if cr == 0xFF {
+            return Some("the device does not respond".to_string());
+        }
+        if cr & (CR_STA | CR_STP) != CR_STA {
+            return Some(format!("not started, CR {:X}", cr));
+        }
+
+        let mut mac = MacAddr { bytes: [0; 6] };
+        self.page(1);
+        for i in 0..6 {
+            mac.bytes[i] = self.read(PAR0 + i as u16);
+        }
+        self.page(0);
+        if ! mac.equals(self.mac) {
+            return Some(format!("PAR0 to PAR5 have {}, not {}", mac.to_string(), self.mac.to_string()));
+        }
+
+        None
+    }
+
+    /// The 8390 keeps a looped back frame in its FIFO instead of the receive ring, where it cannot be seen
+    fn set_loopback(&mut self, _enabled: bool) -> bool {
+        false
+    }
+}
+
+impl Ne2000 {
+    pub fn new(mut pci: PciConfig) -> Box<Self> {
+        let base = unsafe { pci.read(0x10) as usize };
+        let irq = unsafe { pci.irq() };
+
+        let mut module = box Ne2000 {
+            pci: pci,
+            base: (base & 0xFFFFFFFC) as u16,
+            irq: irq,
+            mac: MacAddr { bytes: [0; 6] },
+            inbound: VecDeque::new(),
+            outbound: VecDeque::new(),
+            tx_busy: false,
+            rx_next: RX_START + 1,
+            multicast_filter: [0; 8],
+            promiscuous: false,
+            stats: NetworkStats::default(),
+        };
+
+        unsafe { module.init() };
+
+        module
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        Pio::<u8>::new(self.base + register).read()
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        Pio::<u8>::new(self.base + register).write(value);
+    }
+
+    /// Select a register page, leaving the card running state and remote DMA alone
+    fn page(&self, page: u8) {
+        let cr = self.read(CR) & ! (CR_PAGE1 | CR_RD_READ | CR_RD_WRITE | CR_TXP);
+        self.write(CR, cr | CR_RD_ABORT | if page == 1 { CR_PAGE1 } else { 0 });
+    }
+
+    fn write_mar(&self, filter: [u8; 8]) {
+        self.page(1);
+        for i in 0..8 {
+            self.write(MAR0 + i as u16, filter[i]);
+        }
+        self.page(0);
+    }
+
+    /// Set up a remote DMA of `len` bytes at `address` in card memory
+    fn remote_dma(&self, address: u16, len: usize, command: u8) {
+        self.write(ISR, ISR_RDC);
+        self.write(RBCR0, len as u8);
+        self.write(RBCR1, (len >> 8) as u8);
+        self.write(RSAR0, address as u8);
+        self.write(RSAR1, (address >> 8) as u8);
+        self.write(CR, CR_STA | command);
+    }
+
+    /// Wait for a remote DMA to complete
+    fn remote_dma_wait(&self) {
+        let mut timeout = 100000;
+        while self.read(ISR) & ISR_RDC != ISR_RDC && timeout > 0 {
+            timeout -= 1;
+        }
+        self.write(ISR, ISR_RDC);
+    }
+
+    /// Read card memory, the remote DMA wraps from the end of the receive ring to its start
+    fn read_memory(&self, address: u16, buffer: &mut [u8]) {
+        // An odd count would leave the last word unread and the DMA incomplete
+        let len = (buffer.len() + 1) & !1;
+        self.remote_dma(address, len, CR_RD_READ);
+
+        let mut data = Pio::<u16>::new(self.base + DATA);
+        let mut i = 0;
+        while i < len {
+            let word = data.read();
+            if i < buffer.len() {
+                buffer[i] = word as u8;
+            }
+            if i + 1 < buffer.len() {
+                buffer[i + 1] = (word >> 8) as u8;
+            }
+            i += 2;
+        }
+
+        self.remote_dma_wait();
+    }
+
+    fn write_memory(&self, address: u16, buffer: &[u8]) {
+        let len = (buffer.len() + 1) & !1;
+        self.remote_dma(address, len, CR_RD_WRITE);
+
+        let mut data = Pio::<u16>::new(self.base + DATA);
+        let mut i = 0;
+        while i < len {
+            let low = buffer[i] as u16;
+            let high = if i + 1 < buffer.len() { buffer[i + 1] as u16 } else { 0 };
+            data.write(low | high << 8);
+            i += 2;
+        }
+
+        self.remote_dma_wait();
+    }
+
+    /// Add the error counters to the statistics, reading them clears them
+    fn read_counters(&mut self) {
+        let alignment = self.read(CNTR0) as u64;
+        let crc = self.read(CNTR1) as u64;
+        let missed = self.read(CNTR2) as u64;
+
+        self.stats.rx_crc_errors += crc;
+        self.stats.rx_overruns += missed;
+        self.stats.rx_dropped += alignment + crc + missed;
+    }
+
+    unsafe fn init(&mut self) {
+        syslog_info!(" + NE2000 on: {:X}, IRQ: {:X}", self.base, self.irq);
+
+        self.pci.flag(4, 1, true); // I/O space
+
+        // Reading and then writing the reset port resets the card
+        let reset = self.read(RESET);
+        self.write(RESET, reset);
+        let mut timeout = 100000;
+        while self.read(ISR) & ISR_RST != ISR_RST && timeout > 0 {
+            timeout -= 1;
+        }
+        if timeout == 0 {
+            syslog_warning!("   - Reset did not complete");
+        }
+
+        self.write(CR, CR_STP | CR_RD_ABORT);
+        self.write(DCR, DCR_CONFIG);
+        self.write(RBCR0, 0);
+        self.write(RBCR1, 0);
+        // Keep frames out of the ring until it is set up
+        self.write(RCR, RCR_MON);
+        self.write(TCR, TCR_LOOPBACK);
+        self.write(ISR, 0xFF);
+
+        // In word mode each byte of the address PROM is doubled
+        let mut prom = [0; 32];
+        self.read_memory(0, &mut prom);
+        let mut mac = MacAddr { bytes: [0; 6] };
+        for i in 0..6 {
+            mac.bytes[i] = prom[i * 2];
+        }
+        if ! mac.valid() {
+            mac = MacAddr::random();
+            syslog_warning!("   - Invalid MAC, using {}", mac.to_string());
+        }
+
+        self.write(PSTART, RX_START);
+        self.write(PSTOP, RX_STOP);
+        self.write(BNRY, RX_START);
+        self.write(TPSR, TX_START);
+
+        self.set_mac(mac);
+        MAC_ADDR = self.mac;
+        syslog_info!("   - MAC: {}", &MAC_ADDR.to_string());
+
+        self.page(1);
+        self.write(CURR, RX_START + 1);
+        self.page(0);
+        self.rx_next = RX_START + 1;
+
+        // Multicast frames are only accepted once groups are added to the hash
+        self.set_multicast(&[]);
+
+        self.write(ISR, 0xFF);
+        self.write(IMR, ISR_PRX | ISR_PTX | ISR_RXE | ISR_TXE | ISR_OVW | ISR_CNT);
+
+        self.write(CR, CR_STA | CR_RD_ABORT);
+        self.write(TCR, 0);
+        self.write(RCR, RCR_AB | RCR_AM);
+
+        syslog_info!("   - Link: up");
+        LINK_UP = true;
+    }
+
+    /// Read every frame between the boundary and the current page of the receive ring
+    unsafe fn receive_inbound(&mut self) {
+        loop {
+            self.page(1);
+            let current = self.read(CURR);
+            self.page(0);
+
+            if self.rx_next == current {
+                break;
+            }
+
+            let mut header = [0; 4];
+            self.read_memory((self.rx_next as u16) << 8, &mut header);
+            let status = header[0];
+            let next = header[1];
+            // The count includes the header
+            let len = (header[2] as usize | (header[3] as usize) << 8).saturating_sub(4);
+
+            if next < RX_START || next >= RX_STOP || len > MAX_FRAME + 4 {
+                // The ring is corrupt, drop everything in it
+                debugln!("NE2000: Bad receive header: status {:X} next {:X} len {}", status, next, len);
+                self.stats.rx_dropped += 1;
+                self.rx_next = current;
+            } else {
+                if status & RSR_PRX == RSR_PRX && len >= 14 {
+                    match FrameBuffer::lease(len) {
+                        Some(mut frame) => {
+                            self.read_memory(((self.rx_next as u16) << 8) + 4, frame.get_mut().unwrap());
+                            self.inbound.push_back(frame);
+                        },
+                        None => {
+                            self.stats.rx_dropped += 1;
+                            self.stats.rx_no_buffer += 1;
+                        },
+                    }
+                } else {
+                    self.stats.rx_dropped += 1;
+                }
+                self.rx_next = next;
+            }
+
+            // The boundary trails the next page to read
+            let boundary = if self.rx_next == RX_START { RX_STOP - 1 } else { self.rx_next - 1 };
+            self.write(BNRY, boundary);
+        }
+
+        self.write(ISR, ISR_PRX | ISR_RXE);
+    }
+
+    /// Restart the card after the receive ring filled up, following the DP8390 overflow procedure
+    unsafe fn recover_overflow(&mut self) {
+        let transmitting = self.read(CR) & CR_TXP == CR_TXP;
+
+        self.write(CR, CR_STP | CR_RD_ABORT);
+        let mut timeout = 100000;
+        while self.read(ISR) & ISR_RST != ISR_RST && timeout > 0 {
+            timeout -= 1;
+        }
+
+        self.write(RBCR0, 0);
+        self.write(RBCR1, 0);
+        self.write(TCR, TCR_LOOPBACK);
+        self.write(CR, CR_STA | CR_RD_ABORT);
+
+        self.receive_inbound();
+
+        self.write(ISR, ISR_OVW);
+        self.write(TCR, 0);
+
+        self.stats.rx_overruns += 1;
+
+        // A transmit stopped by the reset must be issued again
+        if transmitting && self.read(ISR) & (ISR_PTX | ISR_TXE) == 0 {
+            self.write(CR, CR_STA | CR_TXP | CR_RD_ABORT);
+        }
+    }
+
+    unsafe fn send_outbound(&mut self) {
+        if self.tx_busy {
+            return;
+        }
+
+        while let Some(bytes) = self.outbound.pop_front() {
+            if bytes.len() > MAX_FRAME {
+                debugln!("NE2000: Frame too long for transmit: {}", bytes.len());
+                self.stats.tx_dropped += 1;
+                self.stats.tx_oversize += 1;
+                continue;
+            }
+
+            let len = cmp::max(bytes.len(), MIN_FRAME);
+            let mut frame = bytes;
+            frame.resize(len, 0);
+            self.write_memory((TX_START as u16) << 8, &frame);
+
+            self.write(TPSR, TX_START);
+            self.write(TBCR0, len as u8);
+            self.write(TBCR1, (len >> 8) as u8);
+            self.write(CR, CR_STA | CR_TXP | CR_RD_ABORT);
+
+            self.tx_busy = true;
+            break;
+        }
+
+        while self.outbound.len() > TX_QUEUE_MAX {
+            self.outbound.pop_front();
+            self.stats.tx_dropped += 1;
+        }
+    }
+}
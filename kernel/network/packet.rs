@@ -0,0 +1,616 @@
+//! Views over received packets and builders for sent ones
+//!
+//! A view is only created once the lengths it reads have been checked, so its accessors never read out of
+//! bounds. Fields are returned in host order, network order is handled here and nowhere else.
+
+use collections::vec::Vec;
+
+use core::mem;
+
+use network::common::{Checksum, Ipv4Addr, Ipv6Addr, MacAddr};
+use network::ipv4::pseudo_header_sum;
+use network::ipv6;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+pub const ETHERTYPE_IPV6: u16 = 0x86DD;
+
+pub const IP_PROTO_ICMP: u8 = 1;
+pub const IP_PROTO_TCP: u8 = 6;
+pub const IP_PROTO_UDP: u8 = 17;
+pub const IP_PROTO_ICMPV6: u8 = 58;
+
+pub const ETHERNET_HEADER_LEN: usize = 14;
+pub const IPV4_HEADER_LEN: usize = 20;
+pub const IPV6_HEADER_LEN: usize = 40;
+pub const UDP_HEADER_LEN: usize = 8;
+pub const TCP_HEADER_LEN: usize = 20;
+pub const ARP_PACKET_LEN: usize = 28;
+
+fn get_u16(bytes: &[u8], i: usize) -> u16 {
+    (bytes[i] as u16) << 8 | bytes[i + 1] as u16
+}
+
+fn get_u32(bytes: &[u8], i: usize) -> u32 {
+    (get_u16(bytes, i) as u32) << 16 | get_u16(bytes, i + 2) as u32
+}
+
+fn get_mac(bytes: &[u8], i: usize) -> MacAddr {
+    let mut addr = MacAddr { bytes: [0; 6] };
+    addr.bytes.copy_from_slice(&bytes[i..i + 6]);
+    addr
+}
+
+fn get_ip(bytes: &[u8], i: usize) -> Ipv4Addr {
+    let mut addr = Ipv4Addr { bytes: [0; 4] };
+    addr.bytes.copy_from_slice(&bytes[i..i + 4]);
+    addr
+}
+
+fn get_ip6(bytes: &[u8], i: usize) -> Ipv6Addr {
+    let mut addr = Ipv6Addr { bytes: [0; 16] };
+    addr.bytes.copy_from_slice(&bytes[i..i + 16]);
+    addr
+}
+
+fn push_u16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.push((value >> 8) as u8);
+    bytes.push(value as u8);
+}
+
+fn push_u32(bytes: &mut Vec<u8>, value: u32) {
+    push_u16(bytes, (value >> 16) as u16);
+    push_u16(bytes, value as u16);
+}
+
+/// The one's complement sum of some bytes, to be compiled into a checksum
+pub fn sum(bytes: &[u8]) -> usize {
+    unsafe { Checksum::sum(bytes.as_ptr() as usize, bytes.len()) }
+}
+
+/// Store a checksum as returned by `Checksum::compile`, which is already in network order in memory
+pub fn put_checksum(bytes: &mut [u8], i: usize, checksum: u16) {
+    let checksum: [u8; 2] = unsafe { mem::transmute(checksum) };
+    bytes[i..i + 2].copy_from_slice(&checksum);
+}
+
+/// An Ethernet II frame without its check sequence
+#[derive(Copy, Clone)]
+pub struct EthernetFrame<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> EthernetFrame<'a> {
+    pub fn new(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.len() >= ETHERNET_HEADER_LEN {
+            Some(EthernetFrame { bytes: bytes })
+        } else {
+            None
+        }
+    }
+
+    pub fn dst(&self) -> MacAddr {
+        get_mac(self.bytes, 0)
+    }
+
+    pub fn src(&self) -> MacAddr {
+        get_mac(self.bytes, 6)
+    }
+
+    pub fn ethertype(&self) -> u16 {
+        get_u16(self.bytes, 12)
+    }
+
+    /// The payload, with any padding of short frames
+    pub fn payload(&self) -> &'a [u8] {
+        &self.bytes[ETHERNET_HEADER_LEN..]
+    }
+
+    /// The IPv4 packet carried, if it is one and it is valid
+    pub fn ipv4(&self) -> Option<Ipv4Packet<'a>> {
+        if self.ethertype() == ETHERTYPE_IPV4 {
+            Ipv4Packet::new(self.payload())
+        } else {
+            None
+        }
+    }
+
+    /// The IPv6 packet carried, if it is one and it is valid
+    pub fn ipv6(&self) -> Option<Ipv6Packet<'a>> {
+        if self.ethertype() == ETHERTYPE_IPV6 {
+            Ipv6Packet::new(self.payload())
+        } else {
+            None
+        }
+    }
+}
+
+/// Build an Ethernet II frame
+pub fn ethernet_frame(dst: MacAddr, src: MacAddr, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(ETHERNET_HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&dst.bytes);
+    bytes.extend_from_slice(&src.bytes);
+    push_u16(&mut bytes, ethertype);
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+/// An IPv4 packet, with a header length and total length that fit the bytes it was made from
+#[derive(Copy, Clone)]
+pub struct Ipv4Packet<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Ipv4Packet<'a> {
+    /// A view of the packet at the start of `bytes`, anything after its total length is ignored
+    pub fn new(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.len() < IPV4_HEADER_LEN || bytes[0] >> 4 != 4 {
+            return None;
+        }
+
+        let header_len = ((bytes[0] & 0xF) as usize) << 2;
+        let total_len = get_u16(bytes, 2) as usize;
+        if header_len < IPV4_HEADER_LEN || header_len > total_len || total_len > bytes.len() {
+            return None;
+        }
+
+        Some(Ipv4Packet { bytes: &bytes[..total_len] })
+    }
+
+    pub fn header_len(&self) -> usize {
+        ((self.bytes[0] & 0xF) as usize) << 2
+    }
+
+    pub fn total_len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn services(&self) -> u8 {
+        self.bytes[1]
+    }
+
+    pub fn id(&self) -> u16 {
+        get_u16(self.bytes, 4)
+    }
+
+    /// The flags and the fragment offset
+    pub fn flags_fragment(&self) -> u16 {
+        get_u16(self.bytes, 6)
+    }
+
+    /// Check if this is part of a larger packet, the more fragments flag or the offset is set
+    pub fn is_fragment(&self) -> bool {
+        self.flags_fragment() & 0x3FFF != 0
+    }
+
+    pub fn ttl(&self) -> u8 {
+        self.bytes[8]
+    }
+
+    pub fn proto(&self) -> u8 {
+        self.bytes[9]
+    }
+
+    pub fn checksum(&self) -> u16 {
+        get_u16(self.bytes, 10)
+    }
+
+    pub fn src(&self) -> Ipv4Addr {
+        get_ip(self.bytes, 12)
+    }
+
+    pub fn dst(&self) -> Ipv4Addr {
+        get_ip(self.bytes, 16)
+    }
+
+    pub fn header(&self) -> &'a [u8] {
+        &self.bytes[..self.header_len()]
+    }
+
+    pub fn options(&self) -> &'a [u8] {
+        &self.bytes[IPV4_HEADER_LEN..self.header_len()]
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        &self.bytes[self.header_len()..]
+    }
+
+    pub fn header_valid(&self) -> bool {
+        Checksum::compile(sum(self.header())) == 0
+    }
+
+    /// Check if the checksums were left for the NIC, which is marked by a zero header checksum
+    pub fn checksums_pending(&self) -> bool {
+        self.checksum() == 0
+    }
+
+    /// The pseudo header sum of the TCP or UDP segment carried
+    pub fn pseudo_header_sum(&self) -> usize {
+        pseudo_header_sum(&self.src(), &self.dst(), self.proto(), self.payload().len())
+    }
+}
+
+/// Builds IPv4 packets without options
+pub struct Ipv4Builder {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub proto: u8,
+    pub id: u16,
+    pub ttl: u8,
+    /// Leave the header checksum at zero, for the NIC to calculate, see `checksums_pending`
+    pub offload: bool,
+}
+
+impl Ipv4Builder {
+    pub fn build(&self, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(IPV4_HEADER_LEN + payload.len());
+        bytes.push(0x40 | (IPV4_HEADER_LEN / 4) as u8);
+        bytes.push(0);
+        push_u16(&mut bytes, (IPV4_HEADER_LEN + payload.len()) as u16);
+        push_u16(&mut bytes, self.id);
+        push_u16(&mut bytes, 0);
+        bytes.push(self.ttl);
+        bytes.push(self.proto);
+        push_u16(&mut bytes, 0);
+        bytes.extend_from_slice(&self.src.bytes);
+        bytes.extend_from_slice(&self.dst.bytes);
+
+        if ! self.offload {
+            let checksum = Checksum::compile(sum(&bytes));
+            put_checksum(&mut bytes, 10, checksum);
+        }
+
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+}
+
+/// An IPv6 packet, with a payload length that fits the bytes it was made from
+///
+/// Extension headers are not parsed, a packet with one has it as its next header and is dropped by the protocols.
+#[derive(Copy, Clone)]
+pub struct Ipv6Packet<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Ipv6Packet<'a> {
+    /// A view of the packet at the start of `bytes`, anything after its payload length is ignored
+    pub fn new(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.len() < IPV6_HEADER_LEN || bytes[0] >> 4 != 6 {
+            return None;
+        }
+
+        let total_len = IPV6_HEADER_LEN + get_u16(bytes, 4) as usize;
+        if total_len > bytes.len() {
+            return None;
+        }
+
+        Some(Ipv6Packet { bytes: &bytes[..total_len] })
+    }
+
+    pub fn traffic_class(&self) -> u8 {
+        (get_u16(self.bytes, 0) >> 4) as u8
+    }
+
+    pub fn flow_label(&self) -> u32 {
+        get_u32(self.bytes, 0) & 0xFFFFF
+    }
+
+    pub fn next_header(&self) -> u8 {
+        self.bytes[6]
+    }
+
+    pub fn hop_limit(&self) -> u8 {
+        self.bytes[7]
+    }
+
+    pub fn src(&self) -> Ipv6Addr {
+        get_ip6(self.bytes, 8)
+    }
+
+    pub fn dst(&self) -> Ipv6Addr {
+        get_ip6(self.bytes, 24)
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        &self.bytes[IPV6_HEADER_LEN..]
+    }
+
+    /// The pseudo header sum of the upper layer packet carried
+    pub fn pseudo_header_sum(&self) -> usize {
+        ipv6::pseudo_header_sum(&self.src(), &self.dst(), self.next_header(), self.payload().len())
+    }
+}
+
+/// Builds IPv6 packets without extension headers
+pub struct Ipv6Builder {
+    pub src: Ipv6Addr,
+    pub dst: Ipv6Addr,
+    pub next_header: u8,
+    pub hop_limit: u8,
+}
+
+impl Ipv6Builder {
+    pub fn build(&self, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(IPV6_HEADER_LEN + payload.len());
+        push_u32(&mut bytes, 6 << 28);
+        push_u16(&mut bytes, payload.len() as u16);
+        bytes.push(self.next_header);
+        bytes.push(self.hop_limit);
+        bytes.extend_from_slice(&self.src.bytes);
+        bytes.extend_from_slice(&self.dst.bytes);
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+}
+
+/// A UDP datagram, with a length that fits the bytes it was made from
+#[derive(Copy, Clone)]
+pub struct UdpDatagram<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> UdpDatagram<'a> {
+    /// A view of the datagram at the start of `bytes`, anything after its length is ignored
+    pub fn new(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.len() < UDP_HEADER_LEN {
+            return None;
+        }
+
+        let len = get_u16(bytes, 4) as usize;
+        if len < UDP_HEADER_LEN || len > bytes.len() {
+            return None;
+        }
+
+        Some(UdpDatagram { bytes: &bytes[..len] })
+    }
+
+    pub fn src_port(&self) -> u16 {
+        get_u16(self.bytes, 0)
+    }
+
+    pub fn dst_port(&self) -> u16 {
+        get_u16(self.bytes, 2)
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Zero if the sender did not calculate one
+    pub fn checksum(&self) -> u16 {
+        get_u16(self.bytes, 6)
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        &self.bytes[UDP_HEADER_LEN..]
+    }
+
+    /// Verify the checksum against an IPv4 pseudo header, a datagram without one is accepted
+    pub fn checksum_valid(&self, src: &Ipv4Addr, dst: &Ipv4Addr) -> bool {
+        self.checksum() == 0 ||
+        Checksum::compile(pseudo_header_sum(src, dst, IP_PROTO_UDP, self.len()) + sum(self.bytes)) == 0
+    }
+
+    /// Verify the checksum against an IPv6 pseudo header, which is required over IPv6
+    pub fn checksum_valid_ipv6(&self, src: &Ipv6Addr, dst: &Ipv6Addr) -> bool {
+        self.checksum() != 0 &&
+        Checksum::compile(ipv6::pseudo_header_sum(src, dst, IP_PROTO_UDP, self.len()) + sum(self.bytes)) == 0
+    }
+}
+
+/// Build a UDP datagram with its checksum, starting from the pseudo header sum
+fn udp_datagram(src_port: u16, dst_port: u16, pseudo: usize, offload: bool, payload: &[u8]) -> Vec<u8> {
+    let len = UDP_HEADER_LEN + payload.len();
+    let mut bytes = Vec::with_capacity(len);
+    push_u16(&mut bytes, src_port);
+    push_u16(&mut bytes, dst_port);
+    push_u16(&mut bytes, len as u16);
+    push_u16(&mut bytes, 0);
+    bytes.extend_from_slice(payload);
+
+    let checksum = if offload {
+        Checksum::fold(pseudo)
+    } else {
+        match Checksum::compile(pseudo + sum(&bytes)) {
+            // Zero means no checksum
+            0 => 0xFFFF,
+            checksum => checksum,
+        }
+    };
+    put_checksum(&mut bytes, 6, checksum);
+
+    bytes
+}
+
+/// Builds UDP datagrams
+pub struct UdpBuilder {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub src_port: u16,
+    pub dst_port: u16,
+    /// Leave the checksum for the NIC, holding the pseudo header sum it starts from
+    pub offload: bool,
+}
+
+impl UdpBuilder {
+    pub fn build(&self, payload: &[u8]) -> Vec<u8> {
+        let pseudo = pseudo_header_sum(&self.src, &self.dst, IP_PROTO_UDP, UDP_HEADER_LEN + payload.len());
+        udp_datagram(self.src_port, self.dst_port, pseudo, self.offload, payload)
+    }
+}
+
+/// Builds UDP datagrams for IPv6, the checksum is always calculated as no NIC offloads it
+pub struct Udp6Builder {
+    pub src: Ipv6Addr,
+    pub dst: Ipv6Addr,
+    pub src_port: u16,
+    pub dst_port: u16,
+}
+
+impl Udp6Builder {
+    pub fn build(&self, payload: &[u8]) -> Vec<u8> {
+        let pseudo = ipv6::pseudo_header_sum(&self.src, &self.dst, IP_PROTO_UDP, UDP_HEADER_LEN + payload.len());
+        udp_datagram(self.src_port, self.dst_port, pseudo, false, payload)
+    }
+}
+
+/// A TCP segment, with a data offset that fits the bytes it was made from
+#[derive(Copy, Clone)]
+pub struct TcpSegment<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> TcpSegment<'a> {
+    /// A view of the segment in `bytes`, which must be the whole IPv4 payload
+    pub fn new(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.len() < TCP_HEADER_LEN {
+            return None;
+        }
+
+        let header_len = (bytes[12] >> 4) as usize * 4;
+        if header_len < TCP_HEADER_LEN || header_len > bytes.len() {
+            return None;
+        }
+
+        Some(TcpSegment { bytes: bytes })
+    }
+
+    pub fn src_port(&self) -> u16 {
+        get_u16(self.bytes, 0)
+    }
+
+    pub fn dst_port(&self) -> u16 {
+        get_u16(self.bytes, 2)
+    }
+
+    pub fn sequence(&self) -> u32 {
+        get_u32(self.bytes, 4)
+    }
+
+    pub fn ack_num(&self) -> u32 {
+        get_u32(self.bytes, 8)
+    }
+
+    pub fn header_len(&self) -> usize {
+        (self.bytes[12] >> 4) as usize * 4
+    }
+
+    /// The data offset and the flags, as in the header
+    pub fn offset_flags(&self) -> u16 {
+        get_u16(self.bytes, 12)
+    }
+
+    /// The flag bits, without the data offset
+    pub fn flags(&self) -> u16 {
+        self.offset_flags() & 0x0FFF
+    }
+
+    pub fn window_size(&self) -> u16 {
+        get_u16(self.bytes, 14)
+    }
+
+    pub fn checksum(&self) -> u16 {
+        get_u16(self.bytes, 16)
+    }
+
+    pub fn urgent_pointer(&self) -> u16 {
+        get_u16(self.bytes, 18)
+    }
+
+    pub fn options(&self) -> &'a [u8] {
+        &self.bytes[TCP_HEADER_LEN..self.header_len()]
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        &self.bytes[self.header_len()..]
+    }
+
+    /// Verify the checksum against an IPv4 pseudo header
+    pub fn checksum_valid(&self, src: &Ipv4Addr, dst: &Ipv4Addr) -> bool {
+        Checksum::compile(pseudo_header_sum(src, dst, IP_PROTO_TCP, self.bytes.len()) + sum(self.bytes)) == 0
+    }
+}
+
+/// Builds TCP segments
+pub struct TcpBuilder {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub sequence: u32,
+    pub ack_num: u32,
+    /// The flag bits, the data offset is set from the options
+    pub flags: u16,
+    pub window_size: u16,
+    /// Leave the checksum for the NIC, holding the pseudo header sum it starts from
+    pub offload: bool,
+}
+
+impl TcpBuilder {
+    /// Build a segment, the options must be padded to a multiple of four bytes
+    pub fn build(&self, options: &[u8], payload: &[u8]) -> Vec<u8> {
+        let header_len = TCP_HEADER_LEN + options.len();
+        let len = header_len + payload.len();
+        let mut bytes = Vec::with_capacity(len);
+        push_u16(&mut bytes, self.src_port);
+        push_u16(&mut bytes, self.dst_port);
+        push_u32(&mut bytes, self.sequence);
+        push_u32(&mut bytes, self.ack_num);
+        push_u16(&mut bytes, ((header_len / 4) as u16) << 12 | (self.flags & 0x0FFF));
+        push_u16(&mut bytes, self.window_size);
+        push_u16(&mut bytes, 0);
+        push_u16(&mut bytes, 0);
+        bytes.extend_from_slice(options);
+        bytes.extend_from_slice(payload);
+
+        let pseudo = pseudo_header_sum(&self.src, &self.dst, IP_PROTO_TCP, len);
+        let checksum = if self.offload {
+            Checksum::fold(pseudo)
+        } else {
+            Checksum::compile(pseudo + sum(&bytes))
+        };
+        put_checksum(&mut bytes, 16, checksum);
+
+        bytes
+    }
+}
+
+/// An ARP packet for IPv4 over ethernet
+#[derive(Copy, Clone)]
+pub struct ArpPacket<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ArpPacket<'a> {
+    /// A view of the packet, other hardware or protocol types are rejected
+    pub fn new(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.len() < ARP_PACKET_LEN || get_u16(bytes, 0) != 1 || get_u16(bytes, 2) != ETHERTYPE_IPV4 ||
+           bytes[4] != 6 || bytes[5] != 4 {
+            return None;
+        }
+
+        Some(ArpPacket { bytes: &bytes[..ARP_PACKET_LEN] })
+    }
+
+    /// 1 for a request, 2 for a reply
+    pub fn oper(&self) -> u16 {
+        get_u16(self.bytes, 6)
+    }
+
+    pub fn src_mac(&self) -> MacAddr {
+        get_mac(self.bytes, 8)
+    }
+
+    pub fn src_ip(&self) -> Ipv4Addr {
+        get_ip(self.bytes, 14)
+    }
+
+    pub fn dst_mac(&self) -> MacAddr {
+        get_mac(self.bytes, 18)
+    }
+
+    pub fn dst_ip(&self) -> Ipv4Addr {
+        get_ip(self.bytes, 24)
+    }
+}
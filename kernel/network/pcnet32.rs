@@ -0,0 +1,562 @@
+use alloc::boxed::Box;
+
+use arch::memory;
+
+use collections::string::{String, ToString};
+use collections::vec_deque::VecDeque;
+
+use core::ptr;
+
+use drivers::pci::config::PciConfig;
+use drivers::io::{Io, Pio};
+
+use common::event::LinkEvent;
+
+use network::{link_changed, NetworkDevice, NetworkStats, ETHERNET_MTU};
+use network::common::*;
+use network::pool::FrameBuffer;
+
+use system::error::Result;
+
+// Ports in 16 bit I/O mode, which the card is in after a reset
+/// The address PROM, the first six bytes are the MAC
+const APROM: u16 = 0x00;
+const RDP: u16 = 0x10;
+const RAP: u16 = 0x12;
+const RESET: u16 = 0x14;
+const BDP: u16 = 0x16;
+
+const CSR0_INIT: u16 = 1;
+const CSR0_STRT: u16 = 1 << 1;
+const CSR0_STOP: u16 = 1 << 2;
+const CSR0_TDMD: u16 = 1 << 3;
+const CSR0_IENA: u16 = 1 << 6;
+/// Any of the interrupt flags is set
+const CSR0_INTR: u16 = 1 << 7;
+const CSR0_IDON: u16 = 1 << 8;
+const CSR0_TINT: u16 = 1 << 9;
+const CSR0_RINT: u16 = 1 << 10;
+const CSR0_MERR: u16 = 1 << 11;
+const CSR0_MISS: u16 = 1 << 12;
+const CSR0_CERR: u16 = 1 << 13;
+const CSR0_BABL: u16 = 1 << 14;
+/// Bits that are cleared by writing one
+const CSR0_ACK: u16 = CSR0_IDON | CSR0_TINT | CSR0_RINT | CSR0_MERR | CSR0_MISS | CSR0_CERR | CSR0_BABL;
+
+/// Init block address, low and high halves
+const CSR1: u16 = 1;
+const CSR2: u16 = 2;
+/// Interrupt masks
+const CSR3: u16 = 3;
+const CSR3_IDONM: u16 = 1 << 8;
+const CSR4: u16 = 4;
+/// Pad short frames on transmit
+const CSR4_APAD_XMT: u16 = 1 << 11;
+/// Suspend, so the filters can be changed while the rings keep their state
+const CSR5: u16 = 5;
+const CSR5_SPND: u16 = 1;
+/// Logical address filter, four 16 bit words
+const CSR8: u16 = 8;
+/// Physical address, three 16 bit words
+const CSR12: u16 = 12;
+/// Mode
+const CSR15: u16 = 15;
+const CSR15_PROM: u16 = 1 << 15;
+/// Loopback, inside the chip when INTL is set too
+const CSR15_LOOP: u16 = 1 << 2;
+const CSR15_INTL: u16 = 1 << 6;
+
+/// Link status LED, which follows the link by default
+const BCR4: u16 = 4;
+const BCR4_LEDOUT: u16 = 1 << 15;
+/// Full duplex control
+const BCR9: u16 = 9;
+const BCR9_FDEN: u16 = 1;
+/// Software style
+const BCR20: u16 = 20;
+/// 32 bit descriptors and init block, PCnet-PCI II style
+const BCR20_SWSTYLE_32: u16 = 2;
+
+/// The init block for 32 bit software style
+#[repr(packed)]
+struct InitBlock {
+    mode: u16,
+    /// The log2 of the ring lengths, in the high nibbles
+    rlen: u8,
+    tlen: u8,
+    padr: [u8; 6],
+    reserved: u16,
+    ladrf: [u16; 4],
+    rdra: u32,
+    tdra: u32,
+}
+
+#[repr(packed)]
+struct Rd {
+    buffer: u32,
+    /// The negated buffer size, the high four bits must be set
+    length: u16,
+    status: u16,
+    /// Received byte count, including the frame check sequence
+    message_length: u32,
+    reserved: u32,
+}
+const RD_OWN: u16 = 1 << 15;
+const RD_ERR: u16 = 1 << 14;
+const RD_OFLO: u16 = 1 << 12;
+const RD_CRC: u16 = 1 << 11;
+const RD_STP: u16 = 1 << 9;
+const RD_ENP: u16 = 1 << 8;
+
+#[repr(packed)]
+struct Td {
+    buffer: u32,
+    /// The negated frame length, the high four bits must be set
+    length: u16,
+    status: u16,
+    misc: u32,
+    reserved: u32,
+}
+const TD_OWN: u16 = 1 << 15;
+const TD_ERR: u16 = 1 << 14;
+const TD_STP: u16 = 1 << 9;
+const TD_ENP: u16 = 1 << 8;
+
+/// Ring lengths must be powers of two
+const RX_RING_LOG2: usize = 5;
+const RX_RING_LENGTH: usize = 1 << RX_RING_LOG2;
+const TX_RING_LOG2: usize = 5;
+const TX_RING_LENGTH: usize = 1 << TX_RING_LOG2;
+const RX_BUFFER_SIZE: usize = 1544;
+const TX_BUFFER_SIZE: usize = 1544;
+const MAX_FRAME: usize = 14 + ETHERNET_MTU;
+/// Frames waiting for a free transmit descriptor, further frames are dropped
+const TX_QUEUE_MAX: usize = 64;
+
+/// An AMD PCnet32 card
+pub struct Pcnet32 {
+    pci: PciConfig,
+    base: u16,
+    irq: u8,
+    mac: MacAddr,
+    inbound: VecDeque<FrameBuffer>,
+    outbound: VecDeque<FrameBuffer>,
+    init_block: *mut InitBlock,
+    receive_ring: *mut Rd,
+    transmit_ring: *mut Td,
+    /// Next receive descriptor to check for a frame
+    rx_next: usize,
+    /// Next transmit descriptor to fill
+    tx_tail: usize,
+    /// Oldest transmit descriptor the card may still own
+    tx_clean: usize,
+    link_up: bool,
+    stats: NetworkStats,
+}
+
+impl NetworkDevice for Pcnet32 {
+    fn name(&self) -> &str {
+        "PCnet32"
+    }
+
+    fn on_irq(&mut self, irq: u8) -> bool {
+        if irq == self.irq {
+            let csr0 = self.read_csr(0);
+            self.write_csr(0, (csr0 & CSR0_ACK) | CSR0_IENA);
+
+            if csr0 & CSR0_MISS == CSR0_MISS {
+                self.stats.rx_overruns += 1;
+                self.stats.rx_dropped += 1;
+            }
+
+            if csr0 & CSR0_MERR == CSR0_MERR {
+                debugln!("PCnet32: Memory error: {:X}", csr0);
+            }
+
+            if csr0 & CSR0_TINT == CSR0_TINT {
+                unsafe { self.send_outbound(); }
+            }
+
+            // There is no link change interrupt, check the link on every interrupt instead
+            let link = self.link();
+            if link.up != self.link_up {
+                self.link_up = link.up;
+                link_changed(link);
+            }
+
+            csr0 & CSR0_INTR == CSR0_INTR
+        } else {
+            false
+        }
+    }
+
+    fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    fn set_mac(&mut self, mac: MacAddr) {
+        self.mac = mac;
+
+        self.suspend(|card| {
+            for i in 0..3 {
+                card.write_csr(CSR12 + i as u16, mac.bytes[i * 2] as u16 | (mac.bytes[i * 2 + 1] as u16) << 8);
+            }
+        });
+        unsafe { (*self.init_block).padr = mac.bytes; }
+    }
+
+    /// The mode register bypasses the filters, which are left untouched
+    fn set_promiscuous(&mut self, promiscuous: bool) {
+        self.suspend(|card| {
+            let mode = card.read_csr(CSR15);
+            card.write_csr(CSR15, if promiscuous { mode | CSR15_PROM } else { mode & ! CSR15_PROM });
+        });
+    }
+
+    fn set_multicast(&mut self, addrs: &[MacAddr]) {
+        let mut filter = [0; 4];
+        for addr in addrs.iter() {
+            let bit = (ether_crc_le(addr) >> 26) as usize;
+            filter[bit >> 4] |= 1 << (bit & 15);
+        }
+
+        self.suspend(|card| {
+            for i in 0..4 {
+                card.write_csr(CSR8 + i as u16, filter[i]);
+            }
+        });
+        unsafe { (*self.init_block).ladrf = filter; }
+    }
+
+    fn offload(&self) -> u32 {
+        0
+    }
+
+    fn max_mtu(&self) -> usize {
+        ETHERNET_MTU
+    }
+
+    /// The buffers always hold a full ethernet frame
+    fn set_mtu(&mut self, _mtu: usize) {}
+
+    fn wake_on_lan(&self) -> bool {
+        false
+    }
+
+    fn set_wake_on_lan(&mut self, _enabled: bool) {}
+
+    /// Stop the card, so it no longer touches the rings
+    fn shutdown(&mut self) {
+        self.write_csr(0, CSR0_STOP);
+    }
+
+    fn send(&mut self, frame: FrameBuffer) -> Result<usize> {
+        let len = frame.len();
+        self.outbound.push_back(frame);
+        unsafe { self.send_outbound(); }
+        Ok(len)
+    }
+
+    fn receive(&mut self) -> Option<FrameBuffer> {
+        if self.inbound.is_empty() {
+            unsafe { self.receive_inbound(); }
+        }
+        self.inbound.pop_front()
+    }
+
+    /// Read the link state from the link LED
+    fn link(&mut self) -> LinkEvent {
+        LinkEvent {
+            up: self.read_bcr(BCR4) & BCR4_LEDOUT == BCR4_LEDOUT,
+            speed: 100,
+            full_duplex: self.read_bcr(BCR9) & BCR9_FDEN == BCR9_FDEN,
+        }
+    }
+
+    fn stats(&mut self) -> &mut NetworkStats {
+        &mut self.stats
+    }
+
+    /// The physical address and the running state
+    fn check_registers(&mut self) -> Option<String> {
+        let csr0 = self.read_csr(0);
+        if csr0 == 0xFFFF {
+            return Some("the device does not respond".to_string());
+        }
+        if csr0 & (CSR0_STRT | CSR0_STOP) != CSR0_STRT {
+            return Some(format!("not started, CSR0 {:X}", csr0));
+        }
+
+        let mut mac = MacAddr { bytes: [0; 6] };
+        for i in 0..3 {
+            let word = self.read_csr(CSR12 + i as u16);
+            mac.bytes[i * 2] = word as u8;
+            mac.bytes[i * 2 + 1] = (word >> 8) as u8;
+        }
+        if ! mac.equals(self.mac) {
+            return Some(format!("CSR12 to CSR14 have {}, not {}", mac.to_string(), self.mac.to_string()));
+        }
+
+        None
+    }
+
+    fn set_loopback(&mut self, enabled: bool) -> bool {
+        self.suspend(|card| {
+            let mode = card.read_csr(CSR15);
+            card.write_csr(CSR15, if enabled { mode | CSR15_LOOP | CSR15_INTL } else { mode & ! (CSR15_LOOP | CSR15_INTL) });
+        });
+        true
+    }
+}
+
+impl Pcnet32 {
+    pub fn new(mut pci: PciConfig) -> Box<Self> {
+        let base = unsafe { pci.read(0x10) as usize };
+        let irq = unsafe { pci.irq() };
+
+        let mut module = box Pcnet32 {
+            pci: pci,
+            base: (base & 0xFFFFFFFC) as u16,
+            irq: irq,
+            mac: MacAddr { bytes: [0; 6] },
+            inbound: VecDeque::new(),
+            outbound: VecDeque::new(),
+            init_block: 0 as *mut InitBlock,
+            receive_ring: 0 as *mut Rd,
+            transmit_ring: 0 as *mut Td,
+            rx_next: 0,
+            tx_tail: 0,
+            tx_clean: 0,
+            link_up: false,
+            stats: NetworkStats::default(),
+        };
+
+        unsafe { module.init() };
+
+        module
+    }
+
+    fn read_csr(&self, register: u16) -> u16 {
+        Pio::<u16>::new(self.base + RAP).write(register);
+        Pio::<u16>::new(self.base + RDP).read()
+    }
+
+    fn write_csr(&self, register: u16, value: u16) {
+        Pio::<u16>::new(self.base + RAP).write(register);
+        Pio::<u16>::new(self.base + RDP).write(value);
+    }
+
+    fn read_bcr(&self, register: u16) -> u16 {
+        Pio::<u16>::new(self.base + RAP).write(register);
+        Pio::<u16>::new(self.base + BDP).read()
+    }
+
+    fn write_bcr(&self, register: u16, value: u16) {
+        Pio::<u16>::new(self.base + RAP).write(register);
+        Pio::<u16>::new(self.base + BDP).write(value);
+    }
+
+    /// Change registers that may only be written while the card is suspended, the rings keep their state
+    fn suspend<F: FnOnce(&Pcnet32)>(&self, change: F) {
+        let csr5 = self.read_csr(CSR5);
+        self.write_csr(CSR5, csr5 | CSR5_SPND);
+        let mut timeout = 100000;
+        while self.read_csr(CSR5) & CSR5_SPND != CSR5_SPND && timeout > 0 {
+            timeout -= 1;
+        }
+
+        change(self);
+
+        self.write_csr(CSR5, csr5 & ! CSR5_SPND);
+    }
+
+    unsafe fn init(&mut self) {
+        syslog_info!(" + PCnet32 on: {:X}, IRQ: {:X}", self.base, self.irq);
+
+        self.pci.flag(4, 4 | 1, true); // Bus mastering and I/O space
+
+        // Reading the reset port resets the card into 16 bit I/O mode
+        Pio::<u16>::new(self.base + RESET).read();
+        self.write_csr(0, CSR0_STOP);
+
+        self.write_bcr(BCR20, BCR20_SWSTYLE_32);
+
+        let mut mac = MacAddr { bytes: [0; 6] };
+        for i in 0..6 {
+            mac.bytes[i] = Pio::<u8>::new(self.base + APROM + i as u16).read();
+        }
+        if ! mac.valid() {
+            mac = MacAddr::random();
+            syslog_warning!("   - Invalid MAC, using {}", mac.to_string());
+        }
+        self.mac = mac;
+        MAC_ADDR = self.mac;
+        syslog_info!("   - MAC: {}", &MAC_ADDR.to_string());
+
+        // Receive Buffer
+        self.receive_ring = memory::alloc_aligned(RX_RING_LENGTH * 16, 16) as *mut Rd;
+        for i in 0..RX_RING_LENGTH {
+            ptr::write(self.receive_ring.offset(i as isize),
+                       Rd {
+                           buffer: memory::alloc(RX_BUFFER_SIZE) as u32,
+                           length: (0u16.wrapping_sub(RX_BUFFER_SIZE as u16)) | 0xF000,
+                           status: RD_OWN,
+                           message_length: 0,
+                           reserved: 0,
+                       });
+        }
+        self.rx_next = 0;
+
+        // Transmit Buffer
+        self.transmit_ring = memory::alloc_aligned(TX_RING_LENGTH * 16, 16) as *mut Td;
+        for i in 0..TX_RING_LENGTH {
+            ptr::write(self.transmit_ring.offset(i as isize),
+                       Td {
+                           buffer: memory::alloc(TX_BUFFER_SIZE) as u32,
+                           length: 0xF000,
+                           status: 0,
+                           misc: 0,
+                           reserved: 0,
+                       });
+        }
+        self.tx_tail = 0;
+        self.tx_clean = 0;
+
+        // Unicast to us and broadcast only, no multicast groups until they are requested
+        self.init_block = memory::alloc_aligned(32, 16) as *mut InitBlock;
+        ptr::write(self.init_block,
+                   InitBlock {
+                       mode: 0,
+                       rlen: (RX_RING_LOG2 << 4) as u8,
+                       tlen: (TX_RING_LOG2 << 4) as u8,
+                       padr: mac.bytes,
+                       reserved: 0,
+                       ladrf: [0; 4],
+                       rdra: self.receive_ring as u32,
+                       tdra: self.transmit_ring as u32,
+                   });
+
+        self.write_csr(CSR1, self.init_block as u32 as u16);
+        self.write_csr(CSR2, (self.init_block as u32 >> 16) as u16);
+
+        // No interrupt for the end of initialization, it is polled for
+        self.write_csr(CSR3, CSR3_IDONM);
+        let csr4 = self.read_csr(CSR4);
+        self.write_csr(CSR4, csr4 | CSR4_APAD_XMT);
+
+        self.write_csr(0, CSR0_INIT);
+        let mut timeout = 1000000;
+        while self.read_csr(0) & CSR0_IDON != CSR0_IDON && timeout > 0 {
+            timeout -= 1;
+        }
+        if timeout == 0 {
+            syslog_warning!("   - Initialization did not complete");
+        }
+
+        self.write_csr(0, CSR0_IDON | CSR0_STRT | CSR0_IENA);
+
+        let link = self.link();
+        syslog_info!("   - Link: {}", if link.up { "up" } else { "down" });
+        self.link_up = link.up;
+        LINK_UP = link.up;
+    }
+
+    unsafe fn receive_inbound(&mut self) {
+        loop {
+            let rd = &mut *self.receive_ring.offset(self.rx_next as isize);
+            if rd.status & RD_OWN == RD_OWN {
+                break;
+            }
+
+            if rd.status & RD_ERR == 0 && rd.status & (RD_STP | RD_ENP) == RD_STP | RD_ENP {
+                // The count includes the frame check sequence
+                let len = (rd.message_length & 0xFFF) as usize;
+                if len >= 18 && len <= MAX_FRAME + 4 {
+                    match FrameBuffer::from_raw_parts(rd.buffer as *const u8, len - 4) {
+                        Some(frame) => self.inbound.push_back(frame),
+                        None => {
+                            self.stats.rx_dropped += 1;
+                            self.stats.rx_no_buffer += 1;
+                        },
+                    }
+                } else {
+                    self.stats.rx_dropped += 1;
+                }
+            } else {
+                debugln!("PCnet32: Dropped frame: status {:X}", rd.status);
+                self.stats.rx_dropped += 1;
+                if rd.status & (RD_STP | RD_ENP) != RD_STP | RD_ENP {
+                    self.stats.rx_oversize += 1;
+                } else if rd.status & RD_OFLO == RD_OFLO {
+                    self.stats.rx_overruns += 1;
+                } else if rd.status & RD_CRC == RD_CRC {
+                    self.stats.rx_crc_errors += 1;
+                }
+            }
+
+            // Hand the descriptor back to the card
+            rd.message_length = 0;
+            rd.status = RD_OWN;
+            self.rx_next = (self.rx_next + 1) % RX_RING_LENGTH;
+        }
+    }
+
+    /// Reclaim transmit descriptors the card has finished with
+    unsafe fn reclaim_transmit(&mut self) {
+        while self.tx_clean != self.tx_tail {
+            let td = &mut *self.transmit_ring.offset(self.tx_clean as isize);
+            if td.status & TD_OWN == TD_OWN {
+                break;
+            }
+
+            if td.status & TD_ERR == TD_ERR {
+                debugln!("PCnet32: Transmit error: {:X}", td.misc);
+                self.stats.tx_dropped += 1;
+            }
+
+            td.status = 0;
+            self.tx_clean = (self.tx_clean + 1) % TX_RING_LENGTH;
+        }
+    }
+
+    unsafe fn send_outbound(&mut self) {
+        self.reclaim_transmit();
+
+        let mut sent = false;
+        while let Some(bytes) = self.outbound.pop_front() {
+            if bytes.len() > MAX_FRAME {
+                debugln!("PCnet32: Frame too long for transmit: {}", bytes.len());
+                self.stats.tx_dropped += 1;
+                self.stats.tx_oversize += 1;
+                continue;
+            }
+
+            let next_tail = (self.tx_tail + 1) % TX_RING_LENGTH;
+            if next_tail == self.tx_clean {
+                // Ring is full, wait for a TINT interrupt
+                self.outbound.push_front(bytes);
+                break;
+            }
+
+            let td = &mut *self.transmit_ring.offset(self.tx_tail as isize);
+
+            ::memcpy(td.buffer as *mut u8, bytes.as_ptr(), bytes.len());
+            td.length = (0u16.wrapping_sub(bytes.len() as u16)) | 0xF000;
+            td.misc = 0;
+            // Ownership is given last, the card may start as soon as it sees it
+            td.status = TD_OWN | TD_STP | TD_ENP;
+
+            self.tx_tail = next_tail;
+            sent = true;
+        }
+
+        if sent {
+            self.write_csr(0, CSR0_TDMD | CSR0_IENA);
+        }
+
+        while self.outbound.len() > TX_QUEUE_MAX {
+            self.outbound.pop_front();
+            self.stats.tx_dropped += 1;
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use collections::string::String;
+use collections::vec::Vec;
+
+use core::ops::Deref;
+use core::slice;
+
+use arch::memory;
+
+use env::log::InterruptGuard;
+
+/// Slots in the pool, a power of two so the free ring wraps with a mask
+const FRAME_POOL_SLOTS: usize = 256;
+/// Bytes in a slot, enough for a frame at the ethernet MTU
+pub const FRAME_SLOT_SIZE: usize = 2048;
+
+/// Fixed size buffers for frames, shared by every interface
+///
+/// The free slots are a ring of slot numbers, leased from the head and returned at the tail. Drivers lease
+/// and drop frames in their interrupt handlers, so the pool is only changed with interrupts disabled.
+struct FramePool {
+    /// The slots, allocated once
+    memory: usize,
+    /// Handles to each slot, a free slot has none
+    refs: [u16; FRAME_POOL_SLOTS],
+    free: [u16; FRAME_POOL_SLOTS],
+    free_head: usize,
+    free_count: usize,
+    /// Frames put in a slot
+    hits: u64,
+    /// Leases refused because every slot was in use
+    exhausted: u64,
+    /// Frames too long for a slot, which were allocated instead
+    oversize: u64,
+}
+
+static mut FRAME_POOL: Option<FramePool> = None;
+
+fn frame_pool() -> &'static mut FramePool {
+    unsafe {
+        if FRAME_POOL.is_none() {
+            let mut pool = FramePool {
+                memory: memory::alloc(FRAME_POOL_SLOTS * FRAME_SLOT_SIZE),
+                refs: [0; FRAME_POOL_SLOTS],
+                free: [0; FRAME_POOL_SLOTS],
+                free_head: 0,
+                free_count: FRAME_POOL_SLOTS,
+                hits: 0,
+                exhausted: 0,
+                oversize: 0,
+            };
+            for (i, slot) in pool.free.iter_mut().enumerate() {
+                *slot = i as u16;
+            }
+            FRAME_POOL = Some(pool);
+        }
+        FRAME_POOL.as_mut().unwrap()
+    }
+}
+
+impl FramePool {
+    fn lease(&mut self) -> Option<usize> {
+        if self.free_count == 0 || self.memory == 0 {
+            self.exhausted += 1;
+            return None;
+        }
+
+        let slot = self.free[self.free_head] as usize;
+        self.free_head = (self.free_head + 1) & (FRAME_POOL_SLOTS - 1);
+        self.free_count -= 1;
+        self.hits += 1;
+        self.refs[slot] = 1;
+        Some(slot)
+    }
+
+    fn release(&mut self, slot: usize) {
+        self.refs[slot] -= 1;
+        if self.refs[slot] == 0 {
+            self.free[(self.free_head + self.free_count) & (FRAME_POOL_SLOTS - 1)] = slot as u16;
+            self.free_count += 1;
+        }
+    }
+
+    fn slot(&self, slot: usize) -> *mut u8 {
+        (self.memory + slot * FRAME_SLOT_SIZE) as *mut u8
+    }
+}
+
+/// The state of the pool, for the netstat listing
+pub fn frame_pool_status() -> String {
+    let _guard = InterruptGuard::new();
+    let pool = frame_pool();
+    format!("frame pool: slots: {} free: {} hits: {} exhausted: {} oversize: {}\n",
+            FRAME_POOL_SLOTS,
+            pool.free_count,
+            pool.hits,
+            pool.exhausted,
+            pool.oversize)
+}
+
+enum Storage {
+    Slot(usize),
+    /// A frame too long for a slot, from an interface with a jumbo MTU
+    Heap(Vec<u8>),
+}
+
+/// A frame in a pool slot, the slot returns to the pool when the last handle is dropped
+///
+/// Clones share the slot, so a frame given to every resource of an interface is not copied.
+/// The contents can only be changed through the one handle to a slot, see `get_mut`.
+pub struct FrameBuffer {
+    storage: Storage,
+    len: usize,
+    /// The device verified the checksums of this received frame
+    checksums_verified: bool,
+}
+
+impl FrameBuffer {
+    /// Lease a buffer of `len` bytes, None if the pool is exhausted
+    ///
+    /// Interrupt handlers must not wait for a slot, the caller drops the frame and counts it instead.
+    pub fn lease(len: usize) -> Option<FrameBuffer> {
+        if len > FRAME_SLOT_SIZE {
+            {
+                let _guard = InterruptGuard::new();
+                frame_pool().oversize += 1;
+            }
+            return Some(FrameBuffer {
+                storage: Storage::Heap(vec![0; len]),
+                len: len,
+                checksums_verified: false,
+            });
+        }
+
+        let slot = {
+            let _guard = InterruptGuard::new();
+            frame_pool().lease()
+        };
+        slot.map(|slot| FrameBuffer {
+            storage: Storage::Slot(slot),
+            len: len,
+            checksums_verified: false,
+        })
+    }
+
+    /// Lease a buffer holding a copy of `bytes`
+    pub fn from_slice(bytes: &[u8]) -> Option<FrameBuffer> {
+        FrameBuffer::lease(bytes.len()).map(|mut buffer| {
+            buffer.get_mut().unwrap().copy_from_slice(bytes);
+            buffer
+        })
+    }
+
+    /// Lease a buffer and copy `len` bytes from `ptr` into it, for drivers reading a DMA buffer
+    pub unsafe fn from_raw_parts(ptr: *const u8, len: usize) -> Option<FrameBuffer> {
+        FrameBuffer::from_slice(slice::from_raw_parts(ptr, len))
+    }
+
+    /// The contents, if this is the only handle to them
+    pub fn get_mut(&mut self) -> Option<&mut [u8]> {
+        let len = self.len;
+        match self.storage {
+            Storage::Slot(slot) => {
+                let _guard = InterruptGuard::new();
+                let pool = frame_pool();
+                if pool.refs[slot] == 1 {
+                    Some(unsafe { slice::from_raw_parts_mut(pool.slot(slot), len) })
+                } else {
+                    None
+                }
+            },
+            Storage::Heap(ref mut bytes) => Some(&mut bytes[..len]),
+        }
+    }
+
+    /// The contents to change, copied to a slot of their own first if other handles share them
+    ///
+    /// None if there was no slot for the copy.
+    pub fn make_mut(&mut self) -> Option<&mut [u8]> {
+        if self.get_mut().is_none() {
+            match FrameBuffer::from_slice(&self[..]) {
+                Some(copy) => *self = copy,
+                None => return None,
+            }
+        }
+        self.get_mut()
+    }
+
+    /// Mark the checksums of a received frame as verified, for drivers whose device reported them good
+    pub fn set_checksums_verified(&mut self) {
+        self.checksums_verified = true;
+    }
+
+    /// Check if the device verified the checksums of this frame
+    pub fn checksums_verified(&self) -> bool {
+        self.checksums_verified
+    }
+
+    /// Shorten the frame, the slot keeps its size
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.len {
+            self.len = len;
+        }
+    }
+}
+
+impl Deref for FrameBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.storage {
+            Storage::Slot(slot) => unsafe { slice::from_raw_parts(frame_pool().slot(slot), self.len) },
+            Storage::Heap(ref bytes) => &bytes[..self.len],
+        }
+    }
+}
+
+impl Clone for FrameBuffer {
+    fn clone(&self) -> FrameBuffer {
+        let storage = match self.storage {
+            Storage::Slot(slot) => {
+                let _guard = InterruptGuard::new();
+                frame_pool().refs[slot] += 1;
+                Storage::Slot(slot)
+            },
+            Storage::Heap(ref bytes) => Storage::Heap(bytes.clone()),
+        };
+
+        FrameBuffer {
+            storage: storage,
+            len: self.len,
+            checksums_verified: self.checksums_verified,
+        }
+    }
+}
+
+impl Drop for FrameBuffer {
+    fn drop(&mut self) {
+        if let Storage::Slot(slot) = self.storage {
+            let _guard = InterruptGuard::new();
+            frame_pool().release(slot);
+        }
+    }
+}
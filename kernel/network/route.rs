@@ -0,0 +1,237 @@
+use collections::string::String;
+use collections::vec::Vec;
+
+use common::to_num::ToNum;
+
+use network::common::*;
+
+use system::error::{Error, Result, EINVAL, ENETUNREACH, ENODEV, ENOENT};
+
+/// The interface routes go out of, as network: is the only one the protocol schemes use
+pub const ROUTE_INTERFACE: &'static str = "network";
+/// The loopback interface, packets routed to it are looped back by the network scheme and never sent
+pub const LOOPBACK_INTERFACE: &'static str = "loopback";
+
+/// A route, packets to addresses in `prefix` under `netmask` go to `gateway`, or straight to the address
+/// without one
+#[derive(Copy, Clone)]
+pub struct Route {
+    pub prefix: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Option<Ipv4Addr>,
+    /// `ROUTE_INTERFACE` or `LOOPBACK_INTERFACE`
+    pub interface: &'static str,
+    /// Added through netcfg:routes, the others follow the address set by DHCP or netcfg
+    pub manual: bool,
+}
+
+impl Route {
+    /// The bits of the netmask, a longer prefix is a better match
+    pub fn prefix_len(&self) -> u32 {
+        self.netmask.bytes.iter().fold(0, |len, byte| len + byte.count_ones())
+    }
+
+    pub fn matches(&self, addr: Ipv4Addr) -> bool {
+        (0..4).all(|i| addr.bytes[i] & self.netmask.bytes[i] == self.prefix.bytes[i] & self.netmask.bytes[i])
+    }
+
+    fn same(&self, prefix: Ipv4Addr, netmask: Ipv4Addr) -> bool {
+        self.netmask.equals(netmask) && self.matches(prefix)
+    }
+
+    pub fn to_string(&self) -> String {
+        let mut string = format!("{}/{}", self.prefix.to_string(), self.prefix_len());
+        if let Some(gateway) = self.gateway {
+            string.push_str(&format!(" via {}", gateway.to_string()));
+        }
+        string.push_str(&format!(" dev {} {}", self.interface, if self.manual { "static" } else { "config" }));
+        string
+    }
+}
+
+/// The routes added through netcfg:routes
+static mut ROUTES: Option<Vec<Route>> = None;
+
+/// The netmask of a prefix `len` bits long
+pub fn netmask(len: u32) -> Ipv4Addr {
+    let bits = if len == 0 { 0 } else { !0u32 << (32 - len) };
+    Ipv4Addr { bytes: [(bits >> 24) as u8, (bits >> 16) as u8, (bits >> 8) as u8, bits as u8] }
+}
+
+/// The loopback network, the subnet of the address, and the router as the default route unless it is unset
+fn configured() -> Vec<Route> {
+    let (addr, subnet, router) = unsafe { (IP_ADDR, IP_SUBNET, IP_ROUTER_ADDR) };
+
+    let mut prefix = addr;
+    for i in 0..4 {
+        prefix.bytes[i] &= subnet.bytes[i];
+    }
+
+    let mut routes = vec![Route {
+        prefix: Ipv4Addr { bytes: [127, 0, 0, 0] },
+        netmask: netmask(8),
+        gateway: None,
+        interface: LOOPBACK_INTERFACE,
+        manual: false,
+    }, Route {
+        prefix: prefix,
+        netmask: subnet,
+        gateway: None,
+        interface: ROUTE_INTERFACE,
+        manual: false,
+    }];
+    if ! router.equals(NULL_IP_ADDR) {
+        routes.push(Route {
+            prefix: NULL_IP_ADDR,
+            netmask: NULL_IP_ADDR,
+            gateway: Some(router),
+            interface: ROUTE_INTERFACE,
+            manual: false,
+        });
+    }
+    routes
+}
+
+/// Every route, the manual ones first
+pub fn routes() -> Vec<Route> {
+    let mut routes = match unsafe { ROUTES.as_ref() } {
+        Some(manual) => manual.clone(),
+        None => Vec::new(),
+    };
+    routes.extend(configured());
+    routes
+}
+
+fn manual() -> &'static mut Vec<Route> {
+    unsafe {
+        if ROUTES.is_none() {
+            ROUTES = Some(Vec::new());
+        }
+        ROUTES.as_mut().unwrap()
+    }
+}
+
+/// Add a manual route, replacing one to the same prefix
+pub fn add(route: Route) {
+    let manual = manual();
+    manual.retain(|other| ! other.same(route.prefix, route.netmask));
+    manual.push(route);
+}
+
+/// Remove the manual route to a prefix, the configured ones change with the address
+pub fn remove(prefix: Ipv4Addr, netmask: Ipv4Addr) -> Result<()> {
+    let manual = manual();
+    let len = manual.len();
+    manual.retain(|route| ! route.same(prefix, netmask));
+    if manual.len() < len {
+        Ok(())
+    } else {
+        Err(Error::new(ENOENT))
+    }
+}
+
+/// The route with the longest prefix matching `addr`, the first of those that are as long
+pub fn lookup(routes: &[Route], addr: Ipv4Addr) -> Option<Route> {
+    let mut best: Option<Route> = None;
+    for route in routes.iter() {
+        if route.matches(addr) && best.map_or(true, |best| route.prefix_len() > best.prefix_len()) {
+            best = Some(*route);
+        }
+    }
+    best
+}
+
+/// Check if packets to `addr` are looped back, to our own address or through a route to the loopback interface
+pub fn is_looped(routes: &[Route], addr: Ipv4Addr) -> bool {
+    addr.equals(unsafe { IP_ADDR }) || lookup(routes, addr).map_or(false, |route| route.interface == LOOPBACK_INTERFACE)
+}
+
+/// Where packets to `addr` are sent, the address itself or the gateway of its route
+///
+/// A gateway has to be on a route without one, as packets to it are sent straight out of the interface.
+pub fn next_hop(routes: &[Route], addr: Ipv4Addr) -> Result<Ipv4Addr> {
+    match lookup(routes, addr) {
+        Some(Route { gateway: None, .. }) => Ok(addr),
+        Some(Route { gateway: Some(gateway), .. }) => match lookup(routes, gateway) {
+            Some(Route { gateway: None, .. }) => Ok(gateway),
+            _ => Err(Error::new(ENETUNREACH)),
+        },
+        None => Err(Error::new(ENETUNREACH)),
+    }
+}
+
+/// Parse `PREFIX/LEN`, or `default` for every address
+fn parse_prefix(string: &str) -> Result<(Ipv4Addr, Ipv4Addr)> {
+    if string == "default" {
+        return Ok((NULL_IP_ADDR, NULL_IP_ADDR));
+    }
+
+    let mut parts = string.splitn(2, '/');
+    let prefix = Ipv4Addr::from_str(parts.next().unwrap_or(""));
+    let len = match parts.next() {
+        Some(len) if ! len.is_empty() && len.chars().all(|c| c.is_digit(10)) => len.to_num() as u32,
+        None => 32,
+        _ => return Err(Error::new(EINVAL)),
+    };
+    if len > 32 {
+        return Err(Error::new(EINVAL));
+    }
+
+    let netmask = netmask(len);
+    let mut masked = prefix;
+    for i in 0..4 {
+        masked.bytes[i] &= netmask.bytes[i];
+    }
+    Ok((masked, netmask))
+}
+
+/// Run a line written to netcfg:routes, `add PREFIX/LEN [via GATEWAY] [dev network|loopback]` or `del PREFIX/LEN`
+pub fn command(line: &str) -> Result<()> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.get(0).map(|word| *word) {
+        Some("add") => {
+            let (prefix, netmask) = try!(parse_prefix(words.get(1).map_or("", |word| *word)));
+            let mut gateway = None;
+            let mut interface = ROUTE_INTERFACE;
+            let mut i = 2;
+            while i < words.len() {
+                match (words[i], words.get(i + 1)) {
+                    ("via", Some(addr)) => gateway = Some(Ipv4Addr::from_str(addr)),
+                    ("dev", Some(name)) => interface = if *name == ROUTE_INTERFACE {
+                        ROUTE_INTERFACE
+                    } else if *name == LOOPBACK_INTERFACE {
+                        LOOPBACK_INTERFACE
+                    } else {
+                        return Err(Error::new(ENODEV));
+                    },
+                    _ => return Err(Error::new(EINVAL)),
+                }
+                i += 2;
+            }
+
+            add(Route {
+                prefix: prefix,
+                netmask: netmask,
+                gateway: gateway,
+                interface: interface,
+                manual: true,
+            });
+            Ok(())
+        },
+        Some("del") if words.len() == 2 => {
+            let (prefix, netmask) = try!(parse_prefix(words[1]));
+            remove(prefix, netmask)
+        },
+        _ => Err(Error::new(EINVAL)),
+    }
+}
+
+/// The routes as lines, the manual ones first
+pub fn routes_string() -> String {
+    let mut string = String::new();
+    for route in routes().iter() {
+        string.push_str(&route.to_string());
+        string.push('\n');
+    }
+    string
+}
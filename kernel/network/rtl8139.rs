@@ -2,29 +2,49 @@ use alloc::boxed::Box;
 
 use arch::memory;
 
-use collections::slice;
+use collections::string::{String, ToString};
 use collections::vec::Vec;
 use collections::vec_deque::VecDeque;
 
-use core::cell::UnsafeCell;
-use core::ptr;
+use core::{cmp, ptr};
 
 use drivers::pci::config::PciConfig;
 use drivers::io::{Io, Pio};
 
-use network::common::*;
-use network::scheme::*;
+use common::event::LinkEvent;
 
-use fs::{KScheme, Resource};
+use network::{link_changed, NetworkDevice, NetworkStats, ETHERNET_MTU};
+use network::common::*;
+use network::pool::FrameBuffer;
 
 use system::error::Result;
 
 bitflags! {
     flags TsrFlags: u32 {
+        const TSR_CRS = 1 << 31,
+        const TSR_TABT = 1 << 30,
+        const TSR_OWC = 1 << 29,
+        const TSR_CDH = 1 << 28,
+        const TSR_TOK = 1 << 15,
+        const TSR_TUN = 1 << 14,
         const TSR_OWN = 1 << 13
     }
 }
 
+/// Enable writes to the configuration registers
+const CR9346_EEM_CONFIG: u8 = 0b11 << 6;
+/// Power management, PME# may be asserted
+const CONFIG1_PMEN: u8 = 1;
+/// Wake on a magic packet
+const CONFIG3_MAGIC: u8 = 1 << 5;
+
+/// Frames shorter than this are padded with zeroes
+const MIN_FRAME: usize = 60;
+/// The largest frame the transmit descriptors accept
+const MAX_FRAME: usize = 1792;
+/// Frames waiting for a free transmit descriptor, further frames are dropped
+const TX_QUEUE_MAX: usize = 64;
+
 bitflags! {
     flags CrFlags: u8 {
         const CR_RST = 1 << 4,
@@ -49,9 +69,31 @@ bitflags! {
     }
 }
 
+bitflags! {
+    flags RsrFlags: u16 {
+        const RSR_LONG = 1 << 3,
+        const RSR_CRC = 1 << 2,
+        const RSR_ROK = 1 << 0
+    }
+}
+
+bitflags! {
+    flags BmcrFlags: u16 {
+        const BMCR_SPEED = 1 << 13,
+        const BMCR_DUPLEX = 1 << 8
+    }
+}
+
+bitflags! {
+    flags BmsrFlags: u16 {
+        const BMSR_LINK = 1 << 2
+    }
+}
+
 bitflags! {
     flags TcrFlags: u32 {
-        const TCR_IFG = 0b11 << 24
+        const TCR_IFG = 0b11 << 24,
+        const TCR_LBK = 0b11 << 17
     }
 }
 
@@ -61,7 +103,8 @@ bitflags! {
         const RCR_AR = 1 << 4,
         const RCR_AB = 1 << 3,
         const RCR_AM = 1 << 2,
-        const RCR_APM = 1 << 1
+        const RCR_APM = 1 << 1,
+        const RCR_AAP = 1 << 0
     }
 }
 
@@ -70,10 +113,14 @@ struct Txd {
     pub address_port: Pio<u32>,
     pub status_port: Pio<u32>,
     pub buffer: usize,
+    pub busy: bool,
 }
 
 pub struct Rtl8139Port {
     pub idr: [Pio<u8>; 6],
+    pub idr_low: Pio<u32>,
+    pub idr_high: Pio<u32>,
+    pub cr9346: Pio<u8>,
     pub rbstart: Pio<u32>,
     pub cr: Pio<u8>,
     pub capr: Pio<u16>,
@@ -82,7 +129,12 @@ pub struct Rtl8139Port {
     pub isr: Pio<u16>,
     pub tcr: Pio<u32>,
     pub rcr: Pio<u32>,
+    pub mar: [Pio<u32>; 2],
+    pub mpc: Pio<u32>,
     pub config1: Pio<u8>,
+    pub config3: Pio<u8>,
+    pub bmcr: Pio<u16>,
+    pub bmsr: Pio<u16>,
 }
 
 impl Rtl8139Port {
@@ -94,6 +146,9 @@ impl Rtl8139Port {
                   Pio::<u8>::new(base + 0x03),
                   Pio::<u8>::new(base + 0x04),
                   Pio::<u8>::new(base + 0x05)],
+            idr_low: Pio::<u32>::new(base + 0x00),
+            idr_high: Pio::<u32>::new(base + 0x04),
+            cr9346: Pio::<u8>::new(base + 0x50),
             rbstart: Pio::<u32>::new(base + 0x30),
             cr: Pio::<u8>::new(base + 0x37),
             capr: Pio::<u16>::new(base + 0x38),
@@ -102,7 +157,13 @@ impl Rtl8139Port {
             isr: Pio::<u16>::new(base + 0x3E),
             tcr: Pio::<u32>::new(base + 0x40),
             rcr: Pio::<u32>::new(base + 0x44),
+            mar: [Pio::<u32>::new(base + 0x08),
+                  Pio::<u32>::new(base + 0x0C)],
+            mpc: Pio::<u32>::new(base + 0x4C),
             config1: Pio::<u8>::new(base + 0x52),
+            config3: Pio::<u8>::new(base + 0x59),
+            bmcr: Pio::<u16>::new(base + 0x62),
+            bmsr: Pio::<u16>::new(base + 0x64),
         };
     }
 }
@@ -112,29 +173,36 @@ pub struct Rtl8139 {
     base: usize,
     memory_mapped: bool,
     irq: u8,
-    resources: UnsafeCell<Vec<*mut NetworkResource>>,
-    inbound: VecDeque<Vec<u8>>,
-    outbound: VecDeque<Vec<u8>>,
+    mac: MacAddr,
+    inbound: VecDeque<FrameBuffer>,
+    outbound: VecDeque<FrameBuffer>,
     txds: Vec<Txd>,
     txd_i: usize,
+    /// The multicast hash, kept so it can be restored after promiscuous mode
+    multicast_filter: [u32; 2],
+    promiscuous: bool,
+    stats: NetworkStats,
     port: Rtl8139Port,
 }
 
 impl Rtl8139 {
     pub fn new(mut pci: PciConfig) -> Box<Self> {
         let base = unsafe { pci.read(0x10) as usize };
-        let irq = unsafe { pci.read(0x3C) as u8 & 0xF };
+        let irq = unsafe { pci.irq() };
 
         let mut module = box Rtl8139 {
             pci: pci,
             base: base & 0xFFFFFFF0,
             memory_mapped: base & 1 == 0,
             irq: irq,
-            resources: UnsafeCell::new(Vec::new()),
+            mac: MacAddr { bytes: [0; 6] },
             inbound: VecDeque::new(),
             outbound: VecDeque::new(),
             txds: Vec::new(),
             txd_i: 0,
+            multicast_filter: [0; 2],
+            promiscuous: false,
+            stats: NetworkStats::default(),
             port: Rtl8139Port::new((base & 0xFFFFFFF0) as u16),
         };
 
@@ -161,7 +229,7 @@ impl Rtl8139 {
         self.port.cr.write(CR_RST.bits);
         while self.port.cr.read() & CR_RST.bits != 0 {}
 
-        MAC_ADDR = MacAddr {
+        let mut mac = MacAddr {
             bytes: [self.port.idr[0].read(),
                     self.port.idr[1].read(),
                     self.port.idr[2].read(),
@@ -169,6 +237,12 @@ impl Rtl8139 {
                     self.port.idr[4].read(),
                     self.port.idr[5].read()],
         };
+        if ! mac.valid() {
+            mac = MacAddr::random();
+            syslog_warning!("   - Invalid MAC, using {}", mac.to_string());
+        }
+        self.set_mac(mac);
+        MAC_ADDR = self.mac;
         syslog_info!("   - MAC: {}", &MAC_ADDR.to_string());
 
         let receive_buffer = memory::alloc(10240);
@@ -178,16 +252,24 @@ impl Rtl8139 {
             self.txds.push(Txd {
                 address_port: Pio::<u32>::new(base + 0x20 + (i as u16) * 4),
                 status_port: Pio::<u32>::new(base + 0x10 + (i as u16) * 4),
-                buffer: memory::alloc(4096),
+                buffer: memory::alloc(MAX_FRAME),
+                busy: false,
             });
         }
 
-        self.port.imr.write((ISR_TOK | ISR_ROK).bits);
+        self.port.imr.write((ISR_PUN_LINKCHG | ISR_TOK | ISR_ROK).bits);
         self.port.cr.write((CR_RE | CR_TE).bits);
+        // Multicast frames are only accepted once groups are added to the hash
+        self.set_multicast(&[]);
         self.port.rcr.write((RCR_WRAP | RCR_AR | RCR_AB | RCR_AM | RCR_APM).bits);
         self.port.tcr.writef(TCR_IFG.bits, true);
+
+        let link = self.link();
+        syslog_info!("   - Link: {}", if link.up { "up" } else { "down" });
+        LINK_UP = link.up;
     }
 
+
     unsafe fn receive_inbound(&mut self) {
         let receive_buffer = self.port.rbstart.read() as usize;
         let mut capr = (self.port.capr.read() + 16) as usize;
@@ -199,8 +281,21 @@ impl Rtl8139 {
             let frame_len = ptr::read((receive_buffer + capr + 2) as *const u16) as usize;
 
             //debugln!("RTL8139: CAPR {} CBR {} STATUS {:X} LEN {}", capr, cbr, frame_status, frame_len);
-            if frame_len >= 4 {
-                self.inbound.push_back(Vec::from(slice::from_raw_parts(frame_addr as *const u8, frame_len - 4)));
+            if frame_status & RSR_ROK.bits as usize != RSR_ROK.bits as usize {
+                self.stats.rx_dropped += 1;
+                if frame_status & RSR_LONG.bits as usize == RSR_LONG.bits as usize {
+                    self.stats.rx_oversize += 1;
+                } else if frame_status & RSR_CRC.bits as usize == RSR_CRC.bits as usize {
+                    self.stats.rx_crc_errors += 1;
+                }
+            } else if frame_len >= 4 {
+                match FrameBuffer::from_raw_parts(frame_addr as *const u8, frame_len - 4) {
+                    Some(frame) => self.inbound.push_back(frame),
+                    None => {
+                        self.stats.rx_dropped += 1;
+                        self.stats.rx_no_buffer += 1;
+                    },
+                }
             } else {
                 panic!("RTL8139: Empty Packet: CAPR {} CBR {} STATUS {:X} LEN {}", capr, cbr, frame_status, frame_len);
             }
@@ -213,103 +308,223 @@ impl Rtl8139 {
 
             self.port.capr.write((capr as u16) - 16);
         }
+
+        // The missed packet counter is 24 bits, and cleared by any write
+        let missed = (self.port.mpc.read() & 0xFFFFFF) as u64;
+        if missed > 0 {
+            self.port.mpc.write(0);
+            self.stats.rx_overruns += missed;
+            self.stats.rx_dropped += missed;
+        }
+    }
+
+    /// Free transmit descriptors the card has finished with
+    unsafe fn reclaim_txds(&mut self) {
+        for txd in self.txds.iter_mut() {
+            if txd.busy {
+                let status = txd.status_port.read();
+                if status & (TSR_TOK | TSR_TABT).bits != 0 {
+                    if status & TSR_TABT.bits == TSR_TABT.bits {
+                        debugln!("RTL8139: Transmit aborted: {:X}", status);
+                    }
+                    txd.busy = false;
+                }
+            }
+        }
     }
 
     unsafe fn send_outbound(&mut self) {
+        self.reclaim_txds();
+
         while let Some(bytes) = self.outbound.pop_front() {
-            if let Some(ref mut txd) = self.txds.get_mut(self.txd_i) {
-                if bytes.len() < 4096 {
-                    while !txd.status_port.readf(TSR_OWN.bits) {}
+            if bytes.len() > MAX_FRAME {
+                debugln!("RTL8139: Frame too long for transmit: {}", bytes.len());
+                self.stats.tx_dropped += 1;
+                self.stats.tx_oversize += 1;
+                continue;
+            }
 
-                    ::memcpy(txd.buffer as *mut u8, bytes.as_ptr(), bytes.len());
+            let txd = &mut self.txds[self.txd_i];
+            if txd.busy {
+                // All four descriptors are in use, wait for a TOK interrupt
+                self.outbound.push_front(bytes);
+                break;
+            }
 
-                    txd.address_port.write(txd.buffer as u32);
-                    txd.status_port.write(bytes.len() as u32 & 0xFFF);
+            let len = cmp::max(bytes.len(), MIN_FRAME);
+            ::memset(txd.buffer as *mut u8, 0, len);
+            ::memcpy(txd.buffer as *mut u8, bytes.as_ptr(), bytes.len());
 
-                    self.txd_i = (self.txd_i + 1) % 4;
-                } else {
-                    debugln!("RTL8139: Frame too long for transmit: {}", bytes.len());
-                }
-            } else {
-                debugln!("RTL8139: TXD Overflow!");
-                self.txd_i = 0;
-            }
+            txd.busy = true;
+            txd.address_port.write(txd.buffer as u32);
+            // Clearing OWN starts the transmit
+            txd.status_port.write(len as u32 & 0x1FFF);
+
+            self.txd_i = (self.txd_i + 1) % 4;
         }
-    }
-}
 
-impl KScheme for Rtl8139 {
-    fn scheme(&self) -> &str {
-        "network"
+        while self.outbound.len() > TX_QUEUE_MAX {
+            self.outbound.pop_front();
+            self.stats.tx_dropped += 1;
+        }
     }
+}
 
-    fn open(&mut self, _: &str, _: usize) -> Result<Box<Resource>> {
-        Ok(NetworkResource::new(self))
+impl NetworkDevice for Rtl8139 {
+    fn name(&self) -> &str {
+        "RTL8139"
     }
 
-    fn on_irq(&mut self, irq: u8) {
+    fn on_irq(&mut self, irq: u8) -> bool {
         if irq == self.irq {
             let isr = self.port.isr.read();
             self.port.isr.write(isr);
 
-            // dh(isr as usize);
-            // dl();
+            if isr & ISR_PUN_LINKCHG.bits == ISR_PUN_LINKCHG.bits {
+                let link = self.link();
+                link_changed(link);
+            }
 
-            self.sync();
+            if isr & ISR_TOK.bits == ISR_TOK.bits {
+                unsafe { self.send_outbound(); }
+            }
+
+            isr != 0
+        } else {
+            false
         }
     }
-}
 
-impl NetworkScheme for Rtl8139 {
-    fn add(&mut self, resource: *mut NetworkResource) {
-        unsafe { &mut *self.resources.get() }.push(resource);
+    fn mac(&self) -> MacAddr {
+        self.mac
     }
 
-    fn remove(&mut self, resource: *mut NetworkResource) {
-        let mut resources = unsafe { &mut *self.resources.get() };
+    fn set_mac(&mut self, mac: MacAddr) {
+        self.mac = mac;
 
-        let mut i = 0;
-        while i < resources.len() {
-            let mut remove = false;
+        // IDR only accepts 32-bit writes, and only while configuration writes are enabled
+        self.port.cr9346.write(CR9346_EEM_CONFIG);
+        self.port.idr_low.write((mac.bytes[0] as u32) | (mac.bytes[1] as u32) << 8 |
+                                (mac.bytes[2] as u32) << 16 | (mac.bytes[3] as u32) << 24);
+        self.port.idr_high.write((mac.bytes[4] as u32) | (mac.bytes[5] as u32) << 8);
+        self.port.cr9346.write(0);
+    }
 
-            match resources.get(i) {
-                Some(ptr) => if *ptr == resource {
-                    remove = true;
-                } else {
-                    i += 1;
-                },
-                None => break,
-            }
+    /// Accept all physical addresses and open the multicast hash, the saved hash is written back when disabled
+    fn set_promiscuous(&mut self, promiscuous: bool) {
+        self.promiscuous = promiscuous;
+        self.port.rcr.writef(RCR_AAP.bits, promiscuous);
+        let filter = if promiscuous { [0xFFFFFFFF; 2] } else { self.multicast_filter };
+        self.port.mar[0].write(filter[0]);
+        self.port.mar[1].write(filter[1]);
+    }
 
-            if remove {
-                resources.remove(i);
-            }
-        }
+    fn offload(&self) -> u32 {
+        0
     }
 
-    fn sync(&mut self) {
-        {
-            let resources = unsafe { &mut *self.resources.get() };
+    fn max_mtu(&self) -> usize {
+        ETHERNET_MTU
+    }
 
-            for resource in resources.iter() {
-                while let Some(bytes) = unsafe { &mut *(**resource).outbound.get() }.pop_front() {
-                    self.outbound.push_back(bytes);
-                }
-            }
+    /// The buffers always hold a full ethernet frame
+    fn set_mtu(&mut self, _mtu: usize) {}
+
+    fn set_multicast(&mut self, addrs: &[MacAddr]) {
+        let mut filter = [0; 2];
+        for addr in addrs.iter() {
+            let bit = (ether_crc(addr) >> 26) as usize;
+            filter[bit >> 5] |= 1 << (bit & 31);
         }
+        self.multicast_filter = filter;
 
+        if ! self.promiscuous {
+            self.port.mar[0].write(filter[0]);
+            self.port.mar[1].write(filter[1]);
+        }
+    }
+
+    fn wake_on_lan(&self) -> bool {
+        let mut pci = self.pci;
+        unsafe { pci.pme_supported() }
+    }
+
+    fn set_wake_on_lan(&mut self, enabled: bool) {
+        self.port.cr9346.write(CR9346_EEM_CONFIG);
+        self.port.config1.writef(CONFIG1_PMEN, enabled);
+        self.port.config3.writef(CONFIG3_MAGIC, enabled);
+        self.port.cr9346.write(0);
+        unsafe { self.pci.set_pme(enabled) };
+    }
+
+    /// Go to D3hot, the receiver is left on if it has to see a magic packet
+    fn shutdown(&mut self) {
+        self.port.imr.write(0);
+        let magic = self.port.config3.readf(CONFIG3_MAGIC);
+        self.port.cr.write(if magic { CR_RE.bits } else { 0 });
+        unsafe { self.pci.set_power_state(3) };
+    }
+
+    fn send(&mut self, frame: FrameBuffer) -> Result<usize> {
+        let len = frame.len();
+        self.outbound.push_back(frame);
         unsafe { self.send_outbound(); }
+        Ok(len)
+    }
+
+    fn receive(&mut self) -> Option<FrameBuffer> {
+        if self.inbound.is_empty() {
+            unsafe { self.receive_inbound(); }
+        }
+        self.inbound.pop_front()
+    }
 
-        unsafe { self.receive_inbound(); }
+    /// Read the link state from the basic mode registers
+    fn link(&mut self) -> LinkEvent {
+        let bmcr = self.port.bmcr.read();
+        LinkEvent {
+            up: self.port.bmsr.readf(BMSR_LINK.bits),
+            speed: if bmcr & BMCR_SPEED.bits == BMCR_SPEED.bits { 100 } else { 10 },
+            full_duplex: bmcr & BMCR_DUPLEX.bits == BMCR_DUPLEX.bits,
+        }
+    }
 
-        {
-            let resources = unsafe { &mut *self.resources.get() };
+    fn stats(&mut self) -> &mut NetworkStats {
+        &mut self.stats
+    }
 
-            while let Some(bytes) = self.inbound.pop_front() {
-                for resource in resources.iter() {
-                    unsafe { (**resource).inbound.send(bytes.clone(), "Rtl8139::sync") };
-                }
-            }
+    /// The address, the receiver and transmitter and the interrupt mask
+    fn check_registers(&mut self) -> Option<String> {
+        let cr = self.port.cr.read();
+        if cr == 0xFF {
+            return Some("the device does not respond".to_string());
         }
+        if cr & (CR_RE | CR_TE).bits != (CR_RE | CR_TE).bits {
+            return Some(format!("receiver or transmitter off, CR {:X}", cr));
+        }
+
+        let mac = MacAddr {
+            bytes: [self.port.idr[0].read(),
+                    self.port.idr[1].read(),
+                    self.port.idr[2].read(),
+                    self.port.idr[3].read(),
+                    self.port.idr[4].read(),
+                    self.port.idr[5].read()],
+        };
+        if ! mac.equals(self.mac) {
+            return Some(format!("IDR has {}, not {}", mac.to_string(), self.mac.to_string()));
+        }
+
+        let imr = self.port.imr.read();
+        if imr != (ISR_PUN_LINKCHG | ISR_TOK | ISR_ROK).bits {
+            return Some(format!("IMR is {:X}", imr));
+        }
+
+        None
+    }
+
+    fn set_loopback(&mut self, enabled: bool) -> bool {
+        self.port.tcr.writef(TCR_LBK.bits, enabled);
+        true
     }
 }
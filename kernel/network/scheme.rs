@@ -1,35 +1,557 @@
 use alloc::boxed::Box;
 
+use arch::timekeeping;
+
+use collections::string::{String, ToString};
 use collections::vec::Vec;
 use collections::vec_deque::VecDeque;
 
 use core::cell::UnsafeCell;
 use core::ops::DerefMut;
+use core::str;
+
+use common::event::{IO_READ, IO_WRITE};
+use common::random;
+use common::time::{self, Duration};
+use common::to_num::ToNum;
+
+use fs::{Check, KScheme, Resource, VecResource};
 
-use fs::Resource;
+use network::{link_status, NetworkDevice, ETHERNET_MTU, MIN_MTU, OFFLOAD_RX_CHECKSUM, OFFLOAD_TX_CHECKSUM};
+use network::ipv4::{finish_checksums, verify_checksums};
+use network::packet::{ethernet_frame, EthernetFrame, ETHERNET_HEADER_LEN, ETHERTYPE_IPV4};
+use network::capture::capture;
+use network::common::{MacAddr, MAC_ADDR};
+use network::pool::FrameBuffer;
 
-use system::error::Result;
+use system::error::{Error, Result, EAGAIN, EINVAL, EMSGSIZE, ENOBUFS, ENOENT, EOPNOTSUPP};
+use system::syscall::{MODE_FILE, O_NONBLOCK};
 
 use sync::WaitQueue;
 
-pub trait NetworkScheme {
-    fn add(&mut self, resource: *mut NetworkResource);
-    fn remove(&mut self, resource: *mut NetworkResource);
-    fn sync(&mut self);
+/// Smallest frame accepted for transmit, an ethernet header
+const MIN_FRAME: usize = ETHERNET_HEADER_LEN;
+
+/// The local experimental ethertype, which the loopback check sends its frame with
+const LOOPBACK_ETHERTYPE: u16 = 0x88B5;
+/// What the payload of the loopback frame starts with, before a random token
+const LOOPBACK_MAGIC: &'static [u8] = b"Redox loopback check";
+/// Milliseconds the loopback frame is waited for
+const LOOPBACK_TIMEOUT: i32 = 100;
+
+/// Every network scheme created, for listings across interfaces
+pub static mut NETWORK_INTERFACES: Option<Vec<*mut NetworkScheme>> = None;
+
+/// The interface providing network:, the first one created
+pub fn network_provider() -> Option<&'static mut NetworkScheme> {
+    unsafe { NETWORK_INTERFACES.as_ref().and_then(|interfaces| interfaces.first()).map(|&nic| &mut *nic) }
+}
+
+/// The network scheme, shared by all NIC drivers
+pub struct NetworkScheme {
+    pub device: Box<NetworkDevice>,
+    resources: Vec<*mut NetworkResource>,
+    /// Every frame is accepted, the multicast list is kept for when it is disabled
+    promiscuous: bool,
+    /// The group addresses programmed into the receive filter
+    multicast: Vec<MacAddr>,
+    /// The largest payload sent, frames are at most an ethernet header longer
+    mtu: usize,
+    /// The device is armed to wake the system on a magic packet
+    wake_on_lan: bool,
+    /// The token of the loopback frame while it is waited for, and whether it came back
+    loopback_token: Option<u32>,
+    loopback_received: bool,
+}
+
+impl NetworkScheme {
+    pub fn new(device: Box<NetworkDevice>) -> Box<Self> {
+        let mut ret = box NetworkScheme {
+            device: device,
+            resources: Vec::new(),
+            promiscuous: false,
+            multicast: Vec::new(),
+            mtu: ETHERNET_MTU,
+            wake_on_lan: false,
+            loopback_token: None,
+            loopback_received: false,
+        };
+
+        // Schemes are never dropped, so the pointer stays valid
+        unsafe {
+            if NETWORK_INTERFACES.is_none() {
+                NETWORK_INTERFACES = Some(Vec::new());
+            }
+            NETWORK_INTERFACES.as_mut().unwrap().push(ret.deref_mut());
+        }
+
+        ret
+    }
+
+    pub fn add(&mut self, resource: *mut NetworkResource) {
+        self.resources.push(resource);
+    }
+
+    pub fn remove(&mut self, resource: *mut NetworkResource) {
+        self.resources.retain(|&ptr| ptr != resource);
+    }
+
+    /// The largest payload sent
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// The largest frame accepted for transmit
+    pub fn max_frame(&self) -> usize {
+        MIN_FRAME + self.mtu
+    }
+
+    /// Change the MTU, for the interface providing network: also the one the protocol schemes use
+    fn set_mtu(&mut self, mtu: usize) -> Result<()> {
+        if mtu < MIN_MTU || mtu > self.device.max_mtu() {
+            return Err(Error::new(EINVAL));
+        }
+
+        self.device.set_mtu(mtu);
+        self.mtu = mtu;
+
+        Ok(())
+    }
+
+    /// Send what is queued and prepare the device for power off
+    pub fn shutdown(&mut self) {
+        self.sync();
+        self.device.shutdown();
+    }
+
+    /// Send a frame from a resource, frames addressed to ourselves are looped back
+    fn transmit(&mut self, mut frame: FrameBuffer) {
+        let max_frame = self.max_frame();
+        if frame.len() < MIN_FRAME || frame.len() > max_frame {
+            debugln!("{}: Invalid frame size for transmit: {}", self.device.name(), frame.len());
+            let stats = self.device.stats();
+            stats.tx_dropped += 1;
+            if frame.len() > max_frame {
+                stats.tx_oversize += 1;
+            }
+            return;
+        }
+
+        capture(self, true, &frame);
+
+        let mac = self.device.mac();
+        let (looped, pending) = match EthernetFrame::new(&frame) {
+            Some(view) => (view.dst().equals(mac), view.ipv4().map_or(false, |packet| packet.checksums_pending())),
+            None => (false, false),
+        };
+
+        // Looped back frames never reach the NIC, and a packet built for another interface may be sent by one
+        // without the offload, so their checksums are done here
+        if pending && (looped || self.device.offload() & OFFLOAD_TX_CHECKSUM != OFFLOAD_TX_CHECKSUM) {
+            match frame.make_mut() {
+                Some(bytes) => finish_checksums(&mut bytes[ETHERNET_HEADER_LEN..]),
+                None => {
+                    self.device.stats().tx_dropped += 1;
+                    return;
+                }
+            }
+        }
+
+        if looped {
+            self.deliver(frame);
+            return;
+        }
+
+        match self.device.send(frame) {
+            Ok(len) => {
+                let stats = self.device.stats();
+                stats.tx_frames += 1;
+                stats.tx_bytes += len as u64;
+            },
+            Err(_) => self.device.stats().tx_dropped += 1,
+        }
+    }
+
+    /// Check if `frame` is the loopback frame with `token`
+    fn is_loopback_frame(frame: &[u8], token: u32) -> bool {
+        match EthernetFrame::new(frame) {
+            Some(view) if view.ethertype() == LOOPBACK_ETHERTYPE => {
+                let payload = view.payload();
+                let len = LOOPBACK_MAGIC.len();
+                payload.len() >= len + 4 && &payload[..len] == LOOPBACK_MAGIC
+                    && payload[len..len + 4] == [token as u8, (token >> 8) as u8, (token >> 16) as u8, (token >> 24) as u8]
+            },
+            _ => false,
+        }
+    }
+
+    /// Send a frame to our own address with the device turning it around, returning `None` if it cannot
+    ///
+    /// For the moment the check runs, frames resources send are turned around too, and so lost.
+    fn loopback_check(&mut self) -> Option<Check> {
+        // What is queued goes out before the device stops sending to the wire
+        self.sync();
+        if ! self.device.set_loopback(true) {
+            return None;
+        }
+
+        let token = random::rand() as u32;
+        let mut payload = LOOPBACK_MAGIC.to_vec();
+        payload.extend_from_slice(&[token as u8, (token >> 8) as u8, (token >> 16) as u8, (token >> 24) as u8]);
+        // Short frames are padded by the device, so the check covers a full minimum frame
+        payload.resize(46, 0);
+        let mac = self.device.mac();
+        self.loopback_token = Some(token);
+        self.loopback_received = false;
+
+        let check = match FrameBuffer::from_slice(&ethernet_frame(mac, mac, LOOPBACK_ETHERTYPE, &payload)) {
+            Some(frame) => match self.device.send(frame) {
+                Ok(_) => {
+                    let end = Duration::monotonic() + Duration::new(0, LOOPBACK_TIMEOUT * time::NANOS_PER_MILLI);
+                    while ! self.loopback_received && Duration::monotonic() < end {
+                        timekeeping::sleep(1, "NetworkScheme::loopback_check");
+                        while let Some(frame) = self.device.receive() {
+                            self.deliver(frame);
+                        }
+                    }
+
+                    if self.loopback_received {
+                        Check::new("loopback", true, "frame sent and received again".to_string())
+                    } else {
+                        Check::new("loopback", false, format!("frame not received again within {} ms", LOOPBACK_TIMEOUT))
+                    }
+                },
+                Err(err) => Check::new("loopback", false, format!("send failed: {}", err)),
+            },
+            None => Check::new("loopback", false, "no free frame buffer".to_string()),
+        };
+
+        self.loopback_token = None;
+        self.device.set_loopback(false);
+
+        Some(check)
+    }
+
+    /// Give a received frame to every resource, they share its buffer
+    fn deliver(&mut self, frame: FrameBuffer) {
+        // The loopback frame may be taken by an interrupt while it is waited for
+        if let Some(token) = self.loopback_token {
+            if NetworkScheme::is_loopback_frame(&frame, token) {
+                self.loopback_received = true;
+                return;
+            }
+        }
+
+        // The protocol schemes skip checksums on an interface with the receive offload, so what the device did
+        // not verify, looped back frames included, is checked here
+        if self.device.offload() & OFFLOAD_RX_CHECKSUM == OFFLOAD_RX_CHECKSUM && ! frame.checksums_verified() {
+            let valid = EthernetFrame::new(&frame).map_or(true, |view| view.ethertype() != ETHERTYPE_IPV4 || verify_checksums(view.payload()));
+            if ! valid {
+                let stats = self.device.stats();
+                stats.rx_dropped += 1;
+                stats.rx_checksum_errors += 1;
+                return;
+            }
+        }
+
+        {
+            let stats = self.device.stats();
+            stats.rx_frames += 1;
+            stats.rx_bytes += frame.len() as u64;
+        }
+
+        capture(self, false, &frame);
+
+        for resource in self.resources.iter() {
+            unsafe { (**resource).inbound.send(frame.clone(), "NetworkScheme::deliver") };
+        }
+    }
+
+    pub fn sync(&mut self) {
+        let mut outbound = Vec::new();
+        for resource in self.resources.iter() {
+            while let Some(frame) = unsafe { &mut *(**resource).outbound.get() }.pop_front() {
+                outbound.push(frame);
+            }
+        }
+
+        for frame in outbound {
+            self.transmit(frame);
+        }
+
+        while let Some(frame) = self.device.receive() {
+            self.deliver(frame);
+        }
+    }
+}
+
+impl KScheme for NetworkScheme {
+    fn scheme(&self) -> &str {
+        "network"
+    }
+
+    /// Send what is queued, then stop DMA, leaving the receiver on if wake on LAN is armed
+    fn power_off(&mut self) {
+        self.shutdown();
+    }
+
+    /// Check the registers and the link, and loop a frame through the device
+    fn diagnose(&mut self) -> Vec<Check> {
+        let mut checks = Vec::new();
+
+        checks.push(match self.device.check_registers() {
+            None => Check::new("registers", true, "as the driver programmed them".to_string()),
+            Some(problem) => Check::new("registers", false, problem),
+        });
+
+        let link = self.device.link();
+        checks.push(Check::new("link", link.up, if link.up {
+            format!("up, {} Mbit/s {} duplex", link.speed, if link.full_duplex { "full" } else { "half" })
+        } else {
+            "down".to_string()
+        }));
+
+        if let Some(check) = self.loopback_check() {
+            checks.push(check);
+        }
+
+        for check in checks.iter_mut() {
+            check.detail = format!("{}: {}", self.device.name(), check.detail);
+        }
+
+        checks
+    }
+
+    fn open(&mut self, url: &str, flags: usize) -> Result<Box<Resource>> {
+        match url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/') {
+            "status" => {
+                let wake_on_lan = if ! self.device.wake_on_lan() {
+                    "unsupported"
+                } else if self.wake_on_lan {
+                    "enabled"
+                } else {
+                    "disabled"
+                };
+                let status = link_status(self.device.link()) + &format!("wol: {}\n", wake_on_lan);
+                Ok(box VecResource::new("network:/status".to_string(), status.into_bytes(), MODE_FILE))
+            },
+            path => match NetworkFile::from_name(path) {
+                // Only devices with an EEPROM dump have the file
+                Some(NetworkFile::Eeprom) if self.device.eeprom().is_none() => Err(Error::new(ENOENT)),
+                Some(file) => Ok(box NetworkFileResource {
+                    nic: self,
+                    file: file,
+                    seek: 0,
+                }),
+                None => Ok(NetworkResource::new(self, flags & O_NONBLOCK == O_NONBLOCK)),
+            },
+        }
+    }
+
+    fn on_irq(&mut self, irq: u8) -> bool {
+        let claimed = self.device.on_irq(irq);
+        self.sync();
+        claimed
+    }
+}
+
+/// A NIC control file
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NetworkFile {
+    /// The hardware address, writing an address in the `MacAddr::from_str` format changes it
+    Mac,
+    /// The counters, any write resets them
+    Stats,
+    /// 1 if every frame is accepted, writing 1 or 0 changes it
+    Promisc,
+    /// The accepted group addresses, one per line, writing a list replaces it
+    Multicast,
+    /// The largest payload, writing a size up to what the driver supports changes it
+    Mtu,
+    /// 1 if a magic packet wakes the system after power off, writing 1 or 0 changes it
+    Wol,
+    /// A dump of the EEPROM, writing force brings up a device whose EEPROM failed validation
+    Eeprom,
+}
+
+impl NetworkFile {
+    /// The control file at `name` under network:
+    pub fn from_name(name: &str) -> Option<NetworkFile> {
+        match name {
+            "mac" => Some(NetworkFile::Mac),
+            "stats" => Some(NetworkFile::Stats),
+            "promisc" => Some(NetworkFile::Promisc),
+            "multicast" => Some(NetworkFile::Multicast),
+            "mtu" => Some(NetworkFile::Mtu),
+            "wol" => Some(NetworkFile::Wol),
+            "eeprom" => Some(NetworkFile::Eeprom),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            NetworkFile::Mac => "mac",
+            NetworkFile::Stats => "stats",
+            NetworkFile::Promisc => "promisc",
+            NetworkFile::Multicast => "multicast",
+            NetworkFile::Mtu => "mtu",
+            NetworkFile::Wol => "wol",
+            NetworkFile::Eeprom => "eeprom",
+        }
+    }
+}
+
+/// A resource for one of the control files of a NIC
+pub struct NetworkFileResource {
+    pub nic: *mut NetworkScheme,
+    pub file: NetworkFile,
+    pub seek: usize,
+}
+
+impl NetworkFileResource {
+    fn contents(&self) -> String {
+        let nic = unsafe { &mut *self.nic };
+        match self.file {
+            NetworkFile::Mac => nic.device.mac().to_string(),
+            NetworkFile::Stats => nic.device.stats().to_string(),
+            NetworkFile::Promisc => if nic.promiscuous { "1\n" } else { "0\n" }.to_string(),
+            NetworkFile::Multicast => {
+                let mut string = String::new();
+                for addr in nic.multicast.iter() {
+                    string.push_str(&addr.to_string());
+                    string.push('\n');
+                }
+                string
+            },
+            NetworkFile::Mtu => format!("{}\n", nic.mtu),
+            NetworkFile::Wol => if nic.wake_on_lan { "1\n" } else { "0\n" }.to_string(),
+            NetworkFile::Eeprom => nic.device.eeprom().unwrap_or(String::new()),
+        }
+    }
+}
+
+impl Resource for NetworkFileResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box NetworkFileResource {
+            nic: self.nic,
+            file: self.file,
+            seek: self.seek,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path_string = format!("network:/{}", self.file.name());
+        let path = path_string.as_bytes();
+
+        let mut i = 0;
+        while i < buf.len() && i < path.len() {
+            buf[i] = path[i];
+            i += 1;
+        }
+
+        Ok(i)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let string = self.contents();
+        let data = string.as_bytes();
+
+        let mut i = 0;
+        while i < buf.len() && self.seek < data.len() {
+            buf[i] = data[self.seek];
+            i += 1;
+            self.seek += 1;
+        }
+
+        Ok(i)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let nic = unsafe { &mut *self.nic };
+        let string = unsafe { str::from_utf8_unchecked(buf) }.trim();
+
+        match self.file {
+            NetworkFile::Mac => {
+                let mac = MacAddr::from_str(string);
+                if ! mac.valid() {
+                    return Err(Error::new(EINVAL));
+                }
+
+                nic.device.set_mac(mac);
+                // Frames are built with the global address, keep it in sync with the hardware
+                unsafe { MAC_ADDR = mac; }
+            },
+            NetworkFile::Stats => nic.device.stats().reset(),
+            NetworkFile::Promisc => {
+                let promiscuous = match string {
+                    "1" => true,
+                    "0" => false,
+                    _ => return Err(Error::new(EINVAL)),
+                };
+
+                if promiscuous != nic.promiscuous {
+                    nic.device.set_promiscuous(promiscuous);
+                    nic.promiscuous = promiscuous;
+                }
+            },
+            NetworkFile::Multicast => {
+                let mut addrs = Vec::new();
+                for part in string.split_whitespace() {
+                    let addr = MacAddr::from_str(part);
+                    if ! addr.is_multicast() {
+                        return Err(Error::new(EINVAL));
+                    }
+                    if ! addrs.iter().any(|a: &MacAddr| a.equals(addr)) {
+                        addrs.push(addr);
+                    }
+                }
+
+                nic.device.set_multicast(&addrs);
+                nic.multicast = addrs;
+            },
+            NetworkFile::Mtu => try!(nic.set_mtu(string.to_num())),
+            NetworkFile::Wol => {
+                let wake_on_lan = match string {
+                    "1" => true,
+                    "0" => false,
+                    _ => return Err(Error::new(EINVAL)),
+                };
+
+                if ! nic.device.wake_on_lan() {
+                    return Err(Error::new(EOPNOTSUPP));
+                }
+
+                if wake_on_lan != nic.wake_on_lan {
+                    nic.device.set_wake_on_lan(wake_on_lan);
+                    nic.wake_on_lan = wake_on_lan;
+                }
+            },
+            NetworkFile::Eeprom => if string != "force" || ! nic.device.force_eeprom() {
+                return Err(Error::new(EINVAL));
+            },
+        }
+        self.seek = 0;
+
+        Ok(buf.len())
+    }
 }
 
 pub struct NetworkResource {
     pub nic: *mut NetworkScheme,
     pub ptr: *mut NetworkResource,
-    pub inbound: WaitQueue<Vec<u8>>,
-    pub outbound: UnsafeCell<VecDeque<Vec<u8>>>,
+    pub inbound: WaitQueue<FrameBuffer>,
+    pub outbound: UnsafeCell<VecDeque<FrameBuffer>>,
+    /// Reads fail with EAGAIN instead of waiting for a frame
+    pub nonblock: bool,
 }
 
 impl NetworkResource {
-    pub fn new(nic: *mut NetworkScheme) -> Box<Self> {
+    pub fn new(nic: *mut NetworkScheme, nonblock: bool) -> Box<Self> {
         let mut ret = box NetworkResource {
             nic: nic,
             ptr: 0 as *mut NetworkResource,
+            nonblock: nonblock,
             inbound: WaitQueue::new(),
             outbound: UnsafeCell::new(VecDeque::new()),
         };
@@ -51,6 +573,7 @@ impl Resource for NetworkResource {
             ptr: 0 as *mut NetworkResource,
             inbound: self.inbound.clone(),
             outbound: UnsafeCell::new(unsafe { & *self.outbound.get() }.clone()),
+            nonblock: self.nonblock,
         };
 
         unsafe {
@@ -77,7 +600,14 @@ impl Resource for NetworkResource {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         let bytes = unsafe {
             (*self.nic).sync();
-            (*self.ptr).inbound.receive("NetworkResource::read")
+            if self.nonblock {
+                match (*self.ptr).inbound.inner().pop_front() {
+                    Some(bytes) => bytes,
+                    None => return Err(Error::new(EAGAIN)),
+                }
+            } else {
+                (*self.ptr).inbound.receive("NetworkResource::read")
+            }
         };
 
         let mut i = 0;
@@ -90,8 +620,13 @@ impl Resource for NetworkResource {
     }
 
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.len() > unsafe { (*self.nic).max_frame() } {
+            return Err(Error::new(EMSGSIZE));
+        }
+
+        let frame = try!(FrameBuffer::from_slice(buf).ok_or(Error::new(ENOBUFS)));
         unsafe {
-            (&mut *(*self.ptr).outbound.get()).push_back(Vec::from(buf));
+            (&mut *(*self.ptr).outbound.get()).push_back(frame);
 
             (*self.nic).sync();
         }
@@ -105,6 +640,13 @@ impl Resource for NetworkResource {
         }
         Ok(())
     }
+
+    fn ready(&mut self) -> i64 {
+        unsafe {
+            (*self.nic).sync();
+            if (*self.ptr).inbound.inner().is_empty() { IO_WRITE } else { IO_READ | IO_WRITE }
+        }
+    }
 }
 
 impl Drop for NetworkResource {
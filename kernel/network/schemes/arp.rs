@@ -1,16 +1,59 @@
-use common::slice::GetSlice;
+use alloc::boxed::Box;
 
+use common::time::{self, Duration};
+
+use collections::string::{String, ToString};
 use collections::vec::Vec;
 
 use core::{mem, slice};
 
 use arch::context::context_switch;
+use arch::timekeeping;
 
+use network::PROTOCOL_STATS;
 use network::common::*;
+use network::packet::{ArpPacket, ARP_PACKET_LEN, ETHERTYPE_IPV4};
+
+use fs::{KScheme, Resource, VecResource};
 
-use fs::KScheme;
 
-use system::syscall::O_RDWR;
+use system::error::{Error, Result, EHOSTUNREACH};
+use system::syscall::{MODE_FILE, O_RDWR};
+
+/// Number of cached addresses
+pub const ARP_CACHE_SIZE: usize = 32;
+/// Seconds before a cache entry has to be resolved again
+pub const ARP_TIMEOUT: i64 = 300;
+/// Requests sent before resolution fails
+pub const ARP_RETRIES: usize = 3;
+/// Milliseconds to wait for a reply to each request
+pub const ARP_REPLY_TIMEOUT: i32 = 1000;
+/// Packets held by an IP resource while its next hop is resolved
+pub const ARP_PENDING_MAX: usize = 16;
+/// Seconds a held packet may wait, it is dropped when a resolution fails after that
+pub const ARP_PENDING_TIMEOUT: i64 = 3;
+
+/// A ARP cache entry (MAC + IP)
+#[derive(Copy, Clone)]
+pub struct ArpEntry {
+    pub ip: Ipv4Addr,
+    pub mac: MacAddr,
+    /// When the entry was learned
+    pub time: Duration,
+    pub valid: bool,
+}
+
+const ARP_ENTRY_EMPTY: ArpEntry = ArpEntry {
+    ip: Ipv4Addr { bytes: [0; 4] },
+    mac: MacAddr { bytes: [0; 6] },
+    time: Duration { secs: 0, nanos: 0 },
+    valid: false,
+};
+
+/// The ARP cache, filled by the reply loop
+pub static mut ARP_CACHE: [ArpEntry; ARP_CACHE_SIZE] = [ARP_ENTRY_EMPTY; ARP_CACHE_SIZE];
+/// Set when a gratuitous ARP should be sent, such as after the link comes up
+pub static mut ARP_ANNOUNCE: bool = true;
 
 #[derive(Copy, Clone)]
 #[repr(packed)]
@@ -33,15 +76,20 @@ pub struct Arp {
 
 impl FromBytes for Arp {
     fn from_bytes(bytes: &[u8]) -> Option<Self> {
-        if bytes.len() >= mem::size_of::<ArpHeader>() {
-            unsafe {
-                return Some(Arp {
-                    header: *(bytes.as_ptr() as *const ArpHeader),
-                    data: bytes.get_slice(mem::size_of::<ArpHeader>() ..).to_vec(),
-                });
-            }
-        }
-        None
+        ArpPacket::new(bytes).map(|packet| Arp {
+            header: ArpHeader {
+                htype: n16::new(1),
+                ptype: n16::new(ETHERTYPE_IPV4),
+                hlen: 6,
+                plen: 4,
+                oper: n16::new(packet.oper()),
+                src_mac: packet.src_mac(),
+                src_ip: packet.src_ip(),
+                dst_mac: packet.dst_mac(),
+                dst_ip: packet.dst_ip(),
+            },
+            data: bytes[ARP_PACKET_LEN..].to_vec(),
+        })
     }
 }
 
@@ -57,21 +105,140 @@ impl ToBytes for Arp {
     }
 }
 
+impl Arp {
+    /// Create a request (or a gratuitous announcement, if `ip` is our own address)
+    pub fn request(ip: Ipv4Addr) -> Arp {
+        Arp {
+            header: ArpHeader {
+                htype: n16::new(1),
+                ptype: n16::new(0x800),
+                hlen: 6,
+                plen: 4,
+                oper: n16::new(1),
+                src_mac: unsafe { MAC_ADDR },
+                src_ip: unsafe { IP_ADDR },
+                dst_mac: BROADCAST_MAC_ADDR,
+                dst_ip: ip,
+            },
+            data: Vec::new(),
+        }
+    }
+}
+
+/// Look up a cached address, expired entries are ignored
+pub fn arp_lookup(ip: Ipv4Addr) -> Option<MacAddr> {
+    let now = Duration::monotonic();
+    for entry in unsafe { ARP_CACHE.iter() } {
+        if entry.valid && entry.ip.equals(ip) && (now - entry.time).secs < ARP_TIMEOUT {
+            return Some(entry.mac);
+        }
+    }
+    None
+}
+
+/// Add or refresh a cache entry, an address has at most one, replacing the oldest entry if the cache is full
+pub fn arp_insert(ip: Ipv4Addr, mac: MacAddr) {
+    let cache = unsafe { &mut ARP_CACHE };
+
+    let index = match cache.iter().position(|entry| entry.valid && entry.ip.equals(ip)) {
+        Some(index) => index,
+        None => match cache.iter().position(|entry| ! entry.valid) {
+            Some(index) => index,
+            None => (0..cache.len()).fold(0, |oldest, i| if cache[i].time < cache[oldest].time { i } else { oldest }),
+        },
+    };
+
+    cache[index] = ArpEntry {
+        ip: ip,
+        mac: mac,
+        time: Duration::monotonic(),
+        valid: true,
+    };
+}
+
+/// Forget all cached addresses, used when the link goes down
+pub fn arp_flush() {
+    for entry in unsafe { ARP_CACHE.iter_mut() } {
+        entry.valid = false;
+    }
+}
+
+/// Send a gratuitous ARP if one is pending
+pub fn arp_announce() {
+    if unsafe { ARP_ANNOUNCE && LINK_UP } {
+        if let Ok(mut link) = ::env().open(&format!("ethernet:{}/806", BROADCAST_MAC_ADDR.to_string()), O_RDWR) {
+            if link.write(&Arp::request(unsafe { IP_ADDR }).to_bytes()).is_ok() {
+                unsafe { ARP_ANNOUNCE = false; }
+            }
+        }
+    }
+}
+
+/// Resolve an address, sending requests until the reply loop learns it or the retries run out
+pub fn arp_resolve(ip: Ipv4Addr) -> Result<MacAddr> {
+    if let Some(mac) = arp_lookup(ip) {
+        return Ok(mac);
+    }
+
+    arp_announce();
+
+    let mut link = try!(::env().open(&format!("ethernet:{}/806", BROADCAST_MAC_ADDR.to_string()), O_RDWR));
+    for _ in 0..ARP_RETRIES {
+        try!(link.write(&Arp::request(ip).to_bytes()));
+        unsafe { PROTOCOL_STATS.arp_requests_sent += 1; }
+
+        let end = Duration::monotonic() + Duration::new(0, ARP_REPLY_TIMEOUT * time::NANOS_PER_MILLI);
+        while Duration::monotonic() < end {
+            if let Some(mac) = arp_lookup(ip) {
+                return Ok(mac);
+            }
+
+            timekeeping::sleep(10, "ARP reply");
+        }
+    }
+
+    debugln!("ARP: No reply from {}", ip.to_string());
+    Err(Error::new(EHOSTUNREACH))
+}
+
+/// The ARP scheme, reading it dumps the cache
 pub struct ArpScheme;
 
 impl KScheme for ArpScheme {
     fn scheme(&self) -> &str {
         "arp"
     }
+
+    fn open(&mut self, _: &str, _: usize) -> Result<Box<Resource>> {
+        let now = Duration::monotonic();
+
+        let mut string = String::new();
+        for entry in unsafe { ARP_CACHE.iter() } {
+            if entry.valid {
+                let age = (now - entry.time).secs;
+                string.push_str(&format!("{} {} {}{}\n", entry.ip.to_string(), entry.mac.to_string(), age,
+                                         if age >= ARP_TIMEOUT { " expired" } else { "" }));
+            }
+        }
+
+        Ok(box VecResource::new("arp:".to_string(), string.into_bytes(), MODE_FILE))
+    }
 }
 
 impl ArpScheme {
     pub fn reply_loop() {
         while let Ok(mut link) = ::env().open("ethernet:/806", O_RDWR) {
             loop {
+                arp_announce();
+
                 let mut bytes = [0; 65536];
                 if let Ok(count) = link.read(&mut bytes) {
                     if let Some(packet) = Arp::from_bytes(&bytes[..count]) {
+                        // Learn the sender of any request or reply
+                        if ! packet.header.src_ip.equals(Ipv4Addr { bytes: [0; 4] }) {
+                            arp_insert(packet.header.src_ip, packet.header.src_mac);
+                        }
+
                         if packet.header.oper.get() == 1 && packet.header.dst_ip.equals(unsafe { IP_ADDR }) {
                             let mut response = Arp {
                                 header: packet.header,
@@ -83,7 +250,9 @@ impl ArpScheme {
                             response.header.src_mac = unsafe { MAC_ADDR };
                             response.header.src_ip =unsafe { IP_ADDR };
 
-                            let _ = link.write(&response.to_bytes());
+                            if link.write(&response.to_bytes()).is_ok() {
+                                unsafe { PROTOCOL_STATS.arp_requests_answered += 1; }
+                            }
                         }
                     }
                 } else {
@@ -1,9 +1,107 @@
 use alloc::boxed::Box;
+use core::{cmp, str};
 use fs::{KScheme, Resource, SliceResource, SliceMutResource};
-use network::common::{DNS_ADDR, IP_ADDR, IP_ROUTER_ADDR, IP_SUBNET, MAC_ADDR};
-use system::error::{Error, ENOENT, Result};
+use network::common::{DNS_ADDR, IP_ADDR, IP_ROUTER_ADDR, IP_SUBNET, IPV6_ADDR, MAC_ADDR};
+use network::route;
+use network::schemes::mdns;
+use system::error::{Error, EINVAL, ENOENT, Result};
 use system::syscall::{MODE_DIR, MODE_FILE};
 
+/// The routing table, read as one route a line and changed by writing `add` and `del` lines
+pub struct RoutesResource {
+    seek: usize,
+}
+
+impl Resource for RoutesResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box RoutesResource {
+            seek: self.seek,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = b"netcfg:routes";
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let string = route::routes_string();
+        let data = string.as_bytes();
+
+        let mut i = 0;
+        while i < buf.len() && self.seek < data.len() {
+            buf[i] = data[self.seek];
+            i += 1;
+            self.seek += 1;
+        }
+
+        Ok(i)
+    }
+
+    /// Run each line, stopping at the first that fails
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let string = try!(str::from_utf8(buf).or(Err(Error::new(EINVAL))));
+        for line in string.lines() {
+            if ! line.trim().is_empty() {
+                try!(route::command(line));
+            }
+        }
+        self.seek = 0;
+
+        Ok(buf.len())
+    }
+}
+
+/// The host name answered for over mDNS, writing one claims it in place of the old
+pub struct HostnameResource {
+    seek: usize,
+}
+
+impl Resource for HostnameResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box HostnameResource {
+            seek: self.seek,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = b"netcfg:hostname";
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let string = mdns::hostname() + "\n";
+        let data = string.as_bytes();
+
+        let mut i = 0;
+        while i < buf.len() && self.seek < data.len() {
+            buf[i] = data[self.seek];
+            i += 1;
+            self.seek += 1;
+        }
+
+        Ok(i)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let string = try!(str::from_utf8(buf).or(Err(Error::new(EINVAL))));
+        try!(mdns::set_hostname(string));
+        self.seek = 0;
+
+        Ok(buf.len())
+    }
+}
+
 /// Network configuration scheme
 pub struct NetConfigScheme;
 
@@ -15,11 +113,20 @@ impl KScheme for NetConfigScheme {
     fn open(&mut self, url: &str, _: usize) -> Result<Box<Resource>> {
         match url.splitn(2, ":").nth(1).unwrap_or("") {
             "dns" => Ok(Box::new(SliceMutResource::new("netcfg:dns", unsafe { &mut DNS_ADDR.bytes }, MODE_FILE))),
+            "hostname" => Ok(box HostnameResource {
+                seek: 0,
+            }),
             "ip" => Ok(Box::new(SliceMutResource::new("netcfg:ip", unsafe { &mut IP_ADDR.bytes }, MODE_FILE))),
             "ip_router" => Ok(Box::new(SliceMutResource::new("netcfg:ip_router", unsafe { &mut IP_ROUTER_ADDR.bytes }, MODE_FILE))),
             "ip_subnet" => Ok(Box::new(SliceMutResource::new("netcfg:ip_subnet", unsafe { &mut IP_SUBNET.bytes }, MODE_FILE))),
-            "mac" => Ok(Box::new(SliceMutResource::new("netcfg:mac", unsafe { &mut MAC_ADDR.bytes }, MODE_FILE))),
-            "" => Ok(Box::new(SliceResource::new("netcfg:", b"dns\nip\nmac", MODE_DIR))),
+            // The link-local address follows the MAC address, all zeroes until duplicate address detection passes
+            "ip6" => Ok(Box::new(SliceResource::new("netcfg:ip6", unsafe { &IPV6_ADDR.bytes }, MODE_FILE))),
+            // Changing the address must go through network:/mac so the NIC filter matches
+            "mac" => Ok(Box::new(SliceResource::new("netcfg:mac", unsafe { &MAC_ADDR.bytes }, MODE_FILE))),
+            "routes" => Ok(box RoutesResource {
+                seek: 0,
+            }),
+            "" => Ok(Box::new(SliceResource::new("netcfg:", b"dns\nhostname\nip\nip6\nmac\nroutes", MODE_DIR))),
             _ => Err(Error::new(ENOENT))
         }
     }
@@ -0,0 +1,472 @@
+use alloc::boxed::Box;
+
+use arch::timekeeping;
+
+use collections::string::{String, ToString};
+use collections::vec::Vec;
+
+use common::random::rand;
+use common::time::Duration;
+
+use core::cmp;
+
+use fs::{KScheme, Resource, VecResource};
+
+use network::{offload, OFFLOAD_RX_CHECKSUM};
+use network::common::*;
+use network::packet::{Ipv4Builder, Ipv4Packet, UdpBuilder, UdpDatagram, IP_PROTO_UDP};
+
+use system::error::{Result, EAGAIN};
+use system::syscall::{MODE_FILE, O_NONBLOCK, O_RDWR};
+
+use super::arp::ARP_ANNOUNCE;
+
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+const DHCP_NAK: u8 = 6;
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS: u8 = 6;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_PARAMETERS: u8 = 55;
+const OPT_RENEWAL_TIME: u8 = 58;
+const OPT_REBINDING_TIME: u8 = 59;
+const OPT_END: u8 = 255;
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+
+/// Offset of the options, after the fixed fields and the magic cookie
+const DHCP_OPTIONS: usize = 240;
+const DHCP_MAGIC: [u8; 4] = [99, 130, 83, 99];
+
+/// Seconds to wait for each reply
+const DHCP_TIMEOUT: i64 = 4;
+/// Seconds between attempts when no server answers, doubled up to the maximum
+const DHCP_BACKOFF_MIN: i64 = 4;
+const DHCP_BACKOFF_MAX: i64 = 64;
+/// Shortest wait between renewal attempts, in seconds
+const DHCP_RETRY_MIN: i64 = 60;
+
+/// A DHCP message
+pub struct Dhcp {
+    pub op: u8,
+    pub xid: u32,
+    pub flags: u16,
+    pub ciaddr: Ipv4Addr,
+    pub yiaddr: Ipv4Addr,
+    pub siaddr: Ipv4Addr,
+    pub chaddr: MacAddr,
+    pub options: Vec<(u8, Vec<u8>)>,
+}
+
+impl Dhcp {
+    /// A client request of type `kind`
+    fn request(kind: u8, xid: u32, ciaddr: Ipv4Addr) -> Dhcp {
+        Dhcp {
+            op: 1,
+            xid: xid,
+            // Ask for broadcast replies, we cannot receive unicast before having an address
+            flags: if ciaddr.equals(NULL_IP_ADDR) { 0x8000 } else { 0 },
+            ciaddr: ciaddr,
+            yiaddr: NULL_IP_ADDR,
+            siaddr: NULL_IP_ADDR,
+            chaddr: unsafe { MAC_ADDR },
+            options: vec![(OPT_MESSAGE_TYPE, vec![kind]),
+                          (OPT_PARAMETERS, vec![OPT_SUBNET, OPT_ROUTER, OPT_DNS, OPT_LEASE_TIME,
+                                                OPT_RENEWAL_TIME, OPT_REBINDING_TIME])],
+        }
+    }
+
+    fn option(&self, code: u8) -> Option<&[u8]> {
+        self.options.iter().find(|option| option.0 == code).map(|option| &option.1[..])
+    }
+
+    fn option_addr(&self, code: u8) -> Option<Ipv4Addr> {
+        match self.option(code) {
+            Some(data) if data.len() >= 4 => Some(Ipv4Addr { bytes: [data[0], data[1], data[2], data[3]] }),
+            _ => None,
+        }
+    }
+
+    fn option_u32(&self, code: u8) -> Option<u32> {
+        self.option_addr(code).map(|addr| n32 { bytes: addr.bytes }.get())
+    }
+
+    fn message_type(&self) -> u8 {
+        match self.option(OPT_MESSAGE_TYPE) {
+            Some(data) if ! data.is_empty() => data[0],
+            _ => 0,
+        }
+    }
+}
+
+impl FromBytes for Dhcp {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < DHCP_OPTIONS || &bytes[236..240] != &DHCP_MAGIC[..] {
+            return None;
+        }
+
+        let addr = |i: usize| Ipv4Addr { bytes: [bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]] };
+
+        let mut options = Vec::new();
+        let mut i = DHCP_OPTIONS;
+        while i < bytes.len() {
+            let code = bytes[i];
+            i += 1;
+            match code {
+                OPT_PAD => continue,
+                OPT_END => break,
+                _ => {
+                    if i >= bytes.len() {
+                        return None;
+                    }
+                    let len = bytes[i] as usize;
+                    i += 1;
+                    if i + len > bytes.len() {
+                        return None;
+                    }
+                    options.push((code, bytes[i .. i + len].to_vec()));
+                    i += len;
+                }
+            }
+        }
+
+        Some(Dhcp {
+            op: bytes[0],
+            xid: n32 { bytes: [bytes[4], bytes[5], bytes[6], bytes[7]] }.get(),
+            flags: n16 { bytes: [bytes[10], bytes[11]] }.get(),
+            ciaddr: addr(12),
+            yiaddr: addr(16),
+            siaddr: addr(20),
+            chaddr: MacAddr { bytes: [bytes[28], bytes[29], bytes[30], bytes[31], bytes[32], bytes[33]] },
+            options: options,
+        })
+    }
+}
+
+impl ToBytes for Dhcp {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut ret = vec![0; DHCP_OPTIONS];
+
+        ret[0] = self.op;
+        ret[1] = 1; // Ethernet
+        ret[2] = 6;
+        ret[4..8].copy_from_slice(&n32::new(self.xid).bytes);
+        ret[10..12].copy_from_slice(&n16::new(self.flags).bytes);
+        ret[12..16].copy_from_slice(&self.ciaddr.bytes);
+        ret[16..20].copy_from_slice(&self.yiaddr.bytes);
+        ret[20..24].copy_from_slice(&self.siaddr.bytes);
+        ret[28..34].copy_from_slice(&self.chaddr.bytes);
+        ret[236..240].copy_from_slice(&DHCP_MAGIC);
+
+        for option in self.options.iter() {
+            ret.push(option.0);
+            ret.push(option.1.len() as u8);
+            ret.extend_from_slice(&option.1);
+        }
+        ret.push(OPT_END);
+
+        ret
+    }
+}
+
+/// An address lease
+#[derive(Clone)]
+pub struct DhcpLease {
+    pub addr: Ipv4Addr,
+    pub subnet: Ipv4Addr,
+    pub router: Ipv4Addr,
+    /// DNS servers in order of preference
+    pub dns: Vec<Ipv4Addr>,
+    pub server: Ipv4Addr,
+    /// When the lease was granted
+    pub start: Duration,
+    /// Lease, renewal (T1) and rebinding (T2) times in seconds
+    pub lease_time: i64,
+    pub renewal_time: i64,
+    pub rebinding_time: i64,
+}
+
+impl DhcpLease {
+    fn from_ack(ack: &Dhcp, start: Duration) -> DhcpLease {
+        let lease_time = ack.option_u32(OPT_LEASE_TIME).unwrap_or(86400) as i64;
+
+        let mut dns = Vec::new();
+        if let Some(data) = ack.option(OPT_DNS) {
+            for addr in data.chunks(4) {
+                if addr.len() == 4 {
+                    dns.push(Ipv4Addr { bytes: [addr[0], addr[1], addr[2], addr[3]] });
+                }
+            }
+        }
+
+        DhcpLease {
+            addr: ack.yiaddr,
+            subnet: ack.option_addr(OPT_SUBNET).unwrap_or(Ipv4Addr { bytes: [255, 255, 255, 0] }),
+            router: ack.option_addr(OPT_ROUTER).unwrap_or(ack.siaddr),
+            dns: dns,
+            server: ack.option_addr(OPT_SERVER_ID).unwrap_or(ack.siaddr),
+            start: start,
+            lease_time: lease_time,
+            renewal_time: ack.option_u32(OPT_RENEWAL_TIME).map_or(lease_time / 2, |time| time as i64),
+            rebinding_time: ack.option_u32(OPT_REBINDING_TIME).map_or(lease_time * 7 / 8, |time| time as i64),
+        }
+    }
+
+    /// Seconds since the lease was granted
+    fn elapsed(&self) -> i64 {
+        (Duration::monotonic() - self.start).secs
+    }
+
+    /// Use the lease for the interface
+    fn apply(&self) {
+        unsafe {
+            IP_ADDR = self.addr;
+            IP_SUBNET = self.subnet;
+            IP_ROUTER_ADDR = self.router;
+            if let Some(dns) = self.dns.first() {
+                DNS_ADDR = *dns;
+            }
+            ARP_ANNOUNCE = true;
+        }
+
+        syslog_info!("DHCP: {} router {} dns {} for {} seconds",
+                     self.addr.to_string(), self.router.to_string(), unsafe { DNS_ADDR.to_string() }, self.lease_time);
+    }
+
+    fn to_string(&self) -> String {
+        let mut dns = String::new();
+        for addr in self.dns.iter() {
+            if ! dns.is_empty() {
+                dns.push(' ');
+            }
+            dns.push_str(&addr.to_string());
+        }
+        format!("ip: {}\nsubnet: {}\nrouter: {}\ndns: {}\nserver: {}\nlease: {}\nrenew: {}\nrebind: {}\nremaining: {}\n",
+                self.addr.to_string(),
+                self.subnet.to_string(),
+                self.router.to_string(),
+                dns,
+                self.server.to_string(),
+                self.lease_time,
+                self.renewal_time,
+                self.rebinding_time,
+                cmp::max(self.lease_time - self.elapsed(), 0))
+    }
+}
+
+/// The current lease, if any
+pub static mut DHCP_LEASE: Option<DhcpLease> = None;
+
+/// A broadcast of `message` from `src`, as the IPv4 packet to send on the ethernet link
+fn broadcast_packet(src: Ipv4Addr, message: &Dhcp) -> Vec<u8> {
+    let datagram = UdpBuilder {
+        src: src,
+        dst: BROADCAST_IP_ADDR,
+        src_port: DHCP_CLIENT_PORT,
+        dst_port: DHCP_SERVER_PORT,
+        offload: false,
+    }.build(&message.to_bytes());
+
+    Ipv4Builder {
+        src: src,
+        dst: BROADCAST_IP_ADDR,
+        proto: IP_PROTO_UDP,
+        id: rand() as u16,
+        ttl: 64,
+        offload: false,
+    }.build(&datagram)
+}
+
+/// The message in an IPv4 packet from the ethernet link, if it is a datagram from a server to the client port
+fn broadcast_reply(bytes: &[u8]) -> Option<Dhcp> {
+    let packet = match Ipv4Packet::new(bytes) {
+        Some(packet) if packet.proto() == IP_PROTO_UDP => packet,
+        _ => return None,
+    };
+    match UdpDatagram::new(packet.payload()) {
+        Some(datagram) if datagram.src_port() == DHCP_SERVER_PORT && datagram.dst_port() == DHCP_CLIENT_PORT &&
+                          (offload(OFFLOAD_RX_CHECKSUM) || datagram.checksum_valid(&packet.src(), &packet.dst())) => {
+            Dhcp::from_bytes(datagram.payload())
+        },
+        _ => None,
+    }
+}
+
+/// Send a message to `peer` and wait for a reply with the same transaction id and one of `kinds`, from the
+/// server identified as `server` if given
+///
+/// A broadcast is sent from `src` on the ethernet link, not through udp:, so the address in use is kept until a
+/// lease is bound. Any reply is read, and it is matched on the transaction id and our hardware address rather
+/// than on where it came from.
+fn exchange(peer: Ipv4Addr, src: Ipv4Addr, message: &Dhcp, kinds: &[u8], server: Option<Ipv4Addr>) -> Option<Dhcp> {
+    let broadcast = peer.equals(BROADCAST_IP_ADDR);
+    let path = if broadcast {
+        format!("ethernet:{}/800", BROADCAST_MAC_ADDR.to_string())
+    } else {
+        format!("udp:{}:{}/{}", peer.to_string(), DHCP_SERVER_PORT, DHCP_CLIENT_PORT)
+    };
+    let mut link = match ::env().open(&path, O_RDWR | O_NONBLOCK) {
+        Ok(link) => link,
+        Err(err) => {
+            debugln!("DHCP: Failed to open {}: {}", path, err);
+            return None;
+        }
+    };
+
+    let bytes = if broadcast { broadcast_packet(src, message) } else { message.to_bytes() };
+    if let Err(err) = link.write(&bytes) {
+        debugln!("DHCP: Failed to send: {}", err);
+        return None;
+    }
+
+    let end = Duration::monotonic() + Duration::new(DHCP_TIMEOUT, 0);
+    while Duration::monotonic() < end {
+        let mut bytes = [0; 65536];
+        match link.read(&mut bytes) {
+            Ok(count) => {
+                let reply = if broadcast { broadcast_reply(&bytes[..count]) } else { Dhcp::from_bytes(&bytes[..count]) };
+                if let Some(reply) = reply {
+                    if reply.op == 2 && reply.xid == message.xid && reply.chaddr.equals(message.chaddr) &&
+                       kinds.contains(&reply.message_type()) &&
+                       server.map_or(true, |server| reply.option_addr(OPT_SERVER_ID).map_or(false, |id| id.equals(server))) {
+                        return Some(reply);
+                    }
+                }
+            },
+            Err(ref err) if err.errno == EAGAIN => {
+                timekeeping::sleep(10, "DHCP exchange");
+            },
+            Err(_) => return None,
+        }
+    }
+
+    None
+}
+
+/// Get a new lease with DISCOVER, OFFER, REQUEST and ACK
+fn discover() -> Option<DhcpLease> {
+    let xid = rand() as u32;
+
+    let offer = match exchange(BROADCAST_IP_ADDR, NULL_IP_ADDR, &Dhcp::request(DHCP_DISCOVER, xid, NULL_IP_ADDR), &[DHCP_OFFER], None) {
+        Some(offer) => offer,
+        None => return None,
+    };
+
+    let mut request = Dhcp::request(DHCP_REQUEST, xid, NULL_IP_ADDR);
+    request.options.push((OPT_REQUESTED_IP, offer.yiaddr.bytes.to_vec()));
+    let server = offer.option_addr(OPT_SERVER_ID);
+    if let Some(server) = server {
+        request.options.push((OPT_SERVER_ID, server.bytes.to_vec()));
+    }
+
+    // Other servers see the request too, only the one whose offer was taken answers it
+    let start = Duration::monotonic();
+    match exchange(BROADCAST_IP_ADDR, NULL_IP_ADDR, &request, &[DHCP_ACK, DHCP_NAK], server) {
+        Some(ref ack) if ack.message_type() == DHCP_ACK => Some(DhcpLease::from_ack(ack, start)),
+        Some(_) => {
+            debugln!("DHCP: Request for {} refused", offer.yiaddr.to_string());
+            None
+        },
+        None => None,
+    }
+}
+
+/// Extend a lease, from the server that granted it or, when rebinding, any server
+fn extend(lease: &DhcpLease, rebinding: bool) -> Option<Option<DhcpLease>> {
+    let (peer, server) = if rebinding { (BROADCAST_IP_ADDR, None) } else { (lease.server, Some(lease.server)) };
+    let start = Duration::monotonic();
+    match exchange(peer, lease.addr, &Dhcp::request(DHCP_REQUEST, rand() as u32, lease.addr), &[DHCP_ACK, DHCP_NAK], server) {
+        Some(ref ack) if ack.message_type() == DHCP_ACK => Some(Some(DhcpLease::from_ack(ack, start))),
+        Some(_) => Some(None),
+        None => None,
+    }
+}
+
+/// Keep a lease until it is refused or expires
+fn maintain(mut lease: DhcpLease) {
+    loop {
+        let elapsed = lease.elapsed();
+        if ! unsafe { LINK_UP } || elapsed >= lease.lease_time {
+            syslog_warning!("DHCP: Lease for {} lost", lease.addr.to_string());
+            return;
+        }
+
+        if elapsed < lease.renewal_time {
+            // Wake up regularly to notice the link going down
+            timekeeping::sleep(cmp::min(lease.renewal_time - elapsed, DHCP_RETRY_MIN) as u64 * 1000, "DHCP renewal");
+            continue;
+        }
+
+        let rebinding = elapsed >= lease.rebinding_time;
+        match extend(&lease, rebinding) {
+            Some(Some(new_lease)) => {
+                lease = new_lease;
+                lease.apply();
+                unsafe { DHCP_LEASE = Some(lease.clone()); }
+            },
+            Some(None) => {
+                syslog_warning!("DHCP: Lease for {} refused", lease.addr.to_string());
+                return;
+            },
+            None => {
+                // Retry after half the time left until the next deadline
+                let deadline = if rebinding { lease.lease_time } else { lease.rebinding_time };
+                let retry = cmp::min(cmp::max((deadline - elapsed) / 2, DHCP_RETRY_MIN), cmp::max(deadline - elapsed, 1));
+                timekeeping::sleep(retry as u64 * 1000, "DHCP retry");
+            }
+        }
+    }
+}
+
+/// The DHCP scheme, reading it returns the current lease
+pub struct DhcpScheme;
+
+impl KScheme for DhcpScheme {
+    fn scheme(&self) -> &str {
+        "dhcp"
+    }
+
+    fn open(&mut self, _: &str, _: usize) -> Result<Box<Resource>> {
+        let string = match unsafe { &DHCP_LEASE } {
+            &Some(ref lease) => lease.to_string(),
+            &None => "none\n".to_string(),
+        };
+
+        Ok(box VecResource::new("dhcp:".to_string(), string.into_bytes(), MODE_FILE))
+    }
+}
+
+impl DhcpScheme {
+    pub fn client_loop() {
+        let mut backoff = DHCP_BACKOFF_MIN;
+        loop {
+            while ! unsafe { LINK_UP } {
+                timekeeping::sleep(1000, "DHCP link");
+            }
+
+            // Requests are sent from 0.0.0.0, the address in use is kept until a lease replaces it
+            match discover() {
+                Some(lease) => {
+                    backoff = DHCP_BACKOFF_MIN;
+                    lease.apply();
+                    unsafe { DHCP_LEASE = Some(lease.clone()); }
+                    maintain(lease);
+                    unsafe { DHCP_LEASE = None; }
+                },
+                None => {
+                    timekeeping::sleep(backoff as u64 * 1000, "DHCP backoff");
+                    backoff = cmp::min(backoff * 2, DHCP_BACKOFF_MAX);
+                }
+            }
+        }
+    }
+}
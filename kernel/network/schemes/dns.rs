@@ -0,0 +1,369 @@
+use alloc::boxed::Box;
+
+use arch::timekeeping;
+
+use collections::string::{String, ToString};
+use collections::vec::Vec;
+
+use common::random::rand;
+use common::time::{self, Duration};
+
+use fs::{KScheme, Resource, VecResource};
+
+use network::common::*;
+
+
+use system::error::{Error, Result, EAGAIN, EHOSTUNREACH, ENOENT, ETIMEDOUT};
+use system::syscall::{MODE_FILE, O_NONBLOCK, O_RDWR};
+
+use super::dhcp::DHCP_LEASE;
+
+/// Return None from the function if the option is None
+macro_rules! try_opt {
+    ($option:expr) => (match $option {
+        Some(value) => value,
+        None => return None,
+    })
+}
+
+pub const DNS_TYPE_A: u16 = 1;
+pub const DNS_TYPE_CNAME: u16 = 5;
+pub const DNS_CLASS_IN: u16 = 1;
+
+/// Milliseconds to wait for a reply
+const DNS_TIMEOUT: i64 = 2000;
+/// Queries sent to each server
+const DNS_RETRIES: usize = 2;
+/// Names kept in the cache
+const DNS_CACHE_SIZE: usize = 64;
+/// Longest CNAME chain followed
+const DNS_CHAIN_MAX: usize = 8;
+
+/// A resource record from an answer
+struct DnsRecord {
+    name: String,
+    kind: u16,
+    ttl: u32,
+    data: Vec<u8>,
+    /// The data decoded as a name, for CNAME records
+    target: Option<String>,
+}
+
+/// Names are compared without case
+pub fn lowercase(b: u8) -> u8 {
+    if b >= b'A' && b <= b'Z' {
+        b + (b'a' - b'A')
+    } else {
+        b
+    }
+}
+
+pub fn get_u16(bytes: &[u8], i: usize) -> Option<u16> {
+    if i + 2 <= bytes.len() {
+        Some(n16 { bytes: [bytes[i], bytes[i + 1]] }.get())
+    } else {
+        None
+    }
+}
+
+pub fn get_u32(bytes: &[u8], i: usize) -> Option<u32> {
+    if i + 4 <= bytes.len() {
+        Some(n32 { bytes: [bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]] }.get())
+    } else {
+        None
+    }
+}
+
+/// Read a possibly compressed name at `i`, returning it and the offset after it
+pub fn read_name(bytes: &[u8], mut i: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    let mut jumps = 0;
+
+    loop {
+        let len = *try_opt!(bytes.get(i)) as usize;
+        if len == 0 {
+            i += 1;
+            break;
+        } else if len & 0xC0 == 0xC0 {
+            // Pointers may only go back, and are limited to prevent loops
+            let pointer = (try_opt!(get_u16(bytes, i)) & 0x3FFF) as usize;
+            if pointer >= i || jumps >= 16 {
+                return None;
+            }
+            if end.is_none() {
+                end = Some(i + 2);
+            }
+            i = pointer;
+            jumps += 1;
+        } else if len & 0xC0 == 0 {
+            if i + 1 + len > bytes.len() || name.len() + len > 255 {
+                return None;
+            }
+            if ! name.is_empty() {
+                name.push('.');
+            }
+            for &b in bytes[i + 1 .. i + 1 + len].iter() {
+                name.push(lowercase(b) as char);
+            }
+            i += 1 + len;
+        } else {
+            return None;
+        }
+    }
+
+    Some((name, end.unwrap_or(i)))
+}
+
+/// Build an A query for `name`
+pub fn query(id: u16, name: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&n16::new(id).bytes);
+    bytes.extend_from_slice(&n16::new(0x0100).bytes); // Recursion desired
+    bytes.extend_from_slice(&n16::new(1).bytes);
+    bytes.extend_from_slice(&[0; 6]);
+
+    for label in name.trim_right_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return None;
+        }
+        bytes.push(label.len() as u8);
+        bytes.extend_from_slice(label.as_bytes());
+    }
+    bytes.push(0);
+
+    bytes.extend_from_slice(&n16::new(DNS_TYPE_A).bytes);
+    bytes.extend_from_slice(&n16::new(DNS_CLASS_IN).bytes);
+
+    Some(bytes)
+}
+
+/// Parse the answers of a response to query `id`, None if it is malformed, truncated or an error
+fn parse(bytes: &[u8], id: u16) -> Option<Vec<DnsRecord>> {
+    if try_opt!(get_u16(bytes, 0)) != id {
+        return None;
+    }
+
+    let flags = try_opt!(get_u16(bytes, 2));
+    // Must be a response and not truncated
+    if flags & 0x8000 == 0 || flags & 0x0200 != 0 {
+        return None;
+    }
+    match flags & 0xF {
+        0 => (),
+        // The name does not exist
+        3 => return Some(Vec::new()),
+        _ => return None,
+    }
+
+    let questions = try_opt!(get_u16(bytes, 4));
+    let answers = try_opt!(get_u16(bytes, 6));
+
+    let mut i = 12;
+    for _ in 0..questions {
+        let (_, next) = try_opt!(read_name(bytes, i));
+        i = next + 4;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        let (name, next) = try_opt!(read_name(bytes, i));
+        let kind = try_opt!(get_u16(bytes, next));
+        let ttl = try_opt!(get_u32(bytes, next + 4));
+        let len = try_opt!(get_u16(bytes, next + 8)) as usize;
+        let start = next + 10;
+        if start + len > bytes.len() {
+            return None;
+        }
+
+        let target = if kind == DNS_TYPE_CNAME {
+            Some(try_opt!(read_name(bytes, start)).0)
+        } else {
+            None
+        };
+
+        records.push(DnsRecord {
+            name: name,
+            kind: kind,
+            ttl: ttl,
+            data: bytes[start .. start + len].to_vec(),
+            target: target,
+        });
+        i = start + len;
+    }
+
+    Some(records)
+}
+
+/// Follow the CNAME chain from `name` and collect its addresses, with the smallest TTL seen
+fn addresses(records: &[DnsRecord], name: &str) -> (Vec<Ipv4Addr>, u32) {
+    let mut name = name.to_string();
+    let mut ttl = u32::max_value();
+
+    for _ in 0..DNS_CHAIN_MAX {
+        match records.iter().find(|record| record.kind == DNS_TYPE_CNAME && record.name == name) {
+            Some(record) => {
+                ttl = ::core::cmp::min(ttl, record.ttl);
+                name = record.target.clone().unwrap_or(String::new());
+            },
+            None => break,
+        }
+    }
+
+    let mut addrs = Vec::new();
+    for record in records.iter() {
+        if record.kind == DNS_TYPE_A && record.name == name && record.data.len() == 4 {
+            ttl = ::core::cmp::min(ttl, record.ttl);
+            addrs.push(Ipv4Addr { bytes: [record.data[0], record.data[1], record.data[2], record.data[3]] });
+        }
+    }
+
+    (addrs, ttl)
+}
+
+/// The addresses a response to query `id` gives for `name`, with the smallest TTL seen
+///
+/// None if the response is malformed, truncated or an error. A name that does not exist gives no addresses.
+pub fn answer(bytes: &[u8], id: u16, name: &str) -> Option<(Vec<Ipv4Addr>, u32)> {
+    parse(bytes, id).map(|records| addresses(&records, name))
+}
+
+/// A cached lookup
+struct DnsEntry {
+    name: String,
+    addrs: Vec<Ipv4Addr>,
+    expires: Duration,
+}
+
+static mut DNS_CACHE: Option<Vec<DnsEntry>> = None;
+
+fn cache_lookup(name: &str) -> Option<Vec<Ipv4Addr>> {
+    let now = Duration::monotonic();
+    if let Some(ref cache) = unsafe { DNS_CACHE.as_ref() } {
+        for entry in cache.iter() {
+            if entry.name == name && now < entry.expires {
+                return Some(entry.addrs.clone());
+            }
+        }
+    }
+    None
+}
+
+fn cache_insert(name: &str, addrs: &[Ipv4Addr], ttl: u32) {
+    let cache = unsafe {
+        if DNS_CACHE.is_none() {
+            DNS_CACHE = Some(Vec::new());
+        }
+        DNS_CACHE.as_mut().unwrap()
+    };
+
+    let now = Duration::monotonic();
+    cache.retain(|entry| entry.name != name && now < entry.expires);
+    if cache.len() >= DNS_CACHE_SIZE {
+        cache.remove(0);
+    }
+
+    cache.push(DnsEntry {
+        name: name.to_string(),
+        addrs: addrs.to_vec(),
+        expires: now + Duration::new(ttl as i64, 0),
+    });
+}
+
+/// Send a query to one server and wait for its answer
+fn ask(server: Ipv4Addr, name: &str) -> Result<(Vec<Ipv4Addr>, u32)> {
+    let id = rand() as u16;
+    let packet = try!(query(id, name).ok_or(Error::new(ENOENT)));
+
+    let mut udp = try!(::env().open(&format!("udp:{}:53/0", server.to_string()), O_RDWR | O_NONBLOCK));
+    try!(udp.write(&packet));
+
+    let end = Duration::monotonic() + Duration::new(DNS_TIMEOUT / 1000, (DNS_TIMEOUT % 1000) as i32 * time::NANOS_PER_MILLI);
+    while Duration::monotonic() < end {
+        let mut bytes = [0; 65536];
+        match udp.read(&mut bytes) {
+            // Responses that do not parse are dropped, a valid one may still follow
+            Ok(count) => if let Some((addrs, ttl)) = answer(&bytes[..count], id, name) {
+                if addrs.is_empty() {
+                    return Err(Error::new(ENOENT));
+                }
+                return Ok((addrs, ttl));
+            },
+            Err(ref err) if err.errno == EAGAIN => {
+                timekeeping::sleep(10, "DNS query");
+            },
+            Err(err) => return Err(err),
+        }
+    }
+
+    Err(Error::new(ETIMEDOUT))
+}
+
+/// Check if a host is a dotted IPv4 address rather than a name
+pub fn is_addr(host: &str) -> bool {
+    host.split('.').count() == 4 && host.split('.').all(|part| ! part.is_empty() && part.len() <= 3 && part.bytes().all(|b| b >= b'0' && b <= b'9'))
+}
+
+/// Resolve a host name to its addresses, dotted addresses are returned as they are
+pub fn dns_resolve(host: &str) -> Result<Vec<Ipv4Addr>> {
+    if is_addr(host) {
+        return Ok(vec![Ipv4Addr::from_str(host)]);
+    }
+
+    let name: String = host.trim_right_matches('.').bytes().map(|b| lowercase(b) as char).collect();
+    if let Some(addrs) = cache_lookup(&name) {
+        return Ok(addrs);
+    }
+
+    // The configured server first, then any other server from the DHCP lease
+    let mut servers = vec![unsafe { DNS_ADDR }];
+    if let Some(ref lease) = unsafe { DHCP_LEASE.as_ref() } {
+        for server in lease.dns.iter() {
+            if ! servers.iter().any(|s| s.equals(*server)) {
+                servers.push(*server);
+            }
+        }
+    }
+
+    let mut last_err = Error::new(EHOSTUNREACH);
+    for server in servers.iter() {
+        for _ in 0..DNS_RETRIES {
+            match ask(*server, &name) {
+                Ok((addrs, ttl)) => {
+                    cache_insert(&name, &addrs, ttl);
+                    return Ok(addrs);
+                },
+                // The name does not exist, asking again will not help
+                Err(ref err) if err.errno == ENOENT => return Err(Error::new(ENOENT)),
+                Err(err) => last_err = err,
+            }
+        }
+    }
+
+    debugln!("DNS: Failed to resolve {}: {}", name, last_err);
+    Err(last_err)
+}
+
+/// The DNS scheme, dns:/hostname returns the addresses of the host
+pub struct DnsScheme;
+
+impl KScheme for DnsScheme {
+    fn scheme(&self) -> &str {
+        "dns"
+    }
+
+    fn open(&mut self, url: &str, _: usize) -> Result<Box<Resource>> {
+        let host = url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/');
+        if host.is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+
+        let mut string = String::new();
+        for addr in try!(dns_resolve(host)).iter() {
+            string.push_str(&addr.to_string());
+            string.push('\n');
+        }
+
+        Ok(box VecResource::new(format!("dns:/{}", host), string.into_bytes(), MODE_FILE))
+    }
+}
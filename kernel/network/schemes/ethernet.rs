@@ -1,13 +1,15 @@
 use alloc::boxed::Box;
 
+use collections::string::String;
 use collections::vec::Vec;
 
-use core::{cmp, mem};
+use core::{cmp, mem, str};
 
+use common::event::{IO_READ, IO_WRITE};
 use common::to_num::ToNum;
 
 use network::common::*;
-use network::ethernet::*;
+use network::packet::{ethernet_frame, EthernetFrame};
 
 use fs::{KScheme, Resource};
 
@@ -66,33 +68,23 @@ impl Resource for EthernetResource {
             let mut bytes = [0; 65536];
             let count = try!(self.network.read(&mut bytes));
 
-            if let Some(frame) = EthernetII::from_bytes(&bytes[..count]) {
-                if frame.header.ethertype.get() == self.ethertype /* && (unsafe { frame.header.dst.equals(MAC_ADDR) }
-                    || frame.header.dst.equals(BROADCAST_MAC_ADDR)) && (frame.header.src.equals(self.peer_addr)
+            if let Some(frame) = EthernetFrame::new(&bytes[..count]) {
+                if frame.ethertype() == self.ethertype /* && (unsafe { frame.dst().equals(MAC_ADDR) }
+                    || frame.dst().equals(BROADCAST_MAC_ADDR)) && (frame.src().equals(self.peer_addr)
                     || self.peer_addr.equals(BROADCAST_MAC_ADDR))*/
                 {
-                    for (b, d) in buf.iter_mut().zip(frame.data.iter()) {
+                    for (b, d) in buf.iter_mut().zip(frame.payload().iter()) {
                         *b = *d;
                     }
 
-                    return Ok(cmp::min(buf.len(), frame.data.len()));
+                    return Ok(cmp::min(buf.len(), frame.payload().len()));
                 }
             }
         }
     }
 
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        let data = Vec::from(buf);
-
-        match self.network.write(&EthernetII {
-                                      header: EthernetIIHeader {
-                                          src: unsafe { MAC_ADDR },
-                                          dst: self.peer_addr,
-                                          ethertype: n16::new(self.ethertype),
-                                      },
-                                      data: data,
-                                  }
-                                  .to_bytes()) {
+        match self.network.write(&ethernet_frame(self.peer_addr, unsafe { MAC_ADDR }, self.ethertype, buf)) {
             Ok(_) => Ok(buf.len()),
             Err(err) => Err(err),
         }
@@ -101,6 +93,46 @@ impl Resource for EthernetResource {
     fn sync(&mut self) -> Result<()> {
         self.network.sync()
     }
+
+    /// Frames of other types are dropped, one of this type is kept for the next read
+    fn ready(&mut self) -> i64 {
+        while self.data.is_empty() && self.network.ready() & IO_READ == IO_READ {
+            let mut bytes = [0; 65536];
+            match self.network.read(&mut bytes) {
+                Ok(count) => if let Some(frame) = EthernetFrame::new(&bytes[..count]) {
+                    if frame.ethertype() == self.ethertype {
+                        self.data = Vec::from(frame.payload());
+                    }
+                },
+                // The read fails straight away too
+                Err(_) => return IO_READ | IO_WRITE,
+            }
+        }
+
+        if self.data.is_empty() { IO_WRITE } else { IO_READ | IO_WRITE }
+    }
+}
+
+/// Add group addresses to the receive filter of network:, keeping the groups already in it
+pub fn join_multicast(groups: &[MacAddr]) -> Result<()> {
+    let mut file = try!(::env().open("network:/multicast", O_RDWR));
+
+    let mut bytes = [0; 4096];
+    let count = try!(file.read(&mut bytes));
+
+    let mut addrs: Vec<MacAddr> = unsafe { str::from_utf8_unchecked(&bytes[..count]) }.split_whitespace().map(MacAddr::from_str).collect();
+    for group in groups.iter() {
+        if ! addrs.iter().any(|addr| addr.equals(*group)) {
+            addrs.push(*group);
+        }
+    }
+
+    let mut string = String::new();
+    for addr in addrs.iter() {
+        string.push_str(&addr.to_string());
+        string.push('\n');
+    }
+    file.write(string.as_bytes()).and(Ok(()))
 }
 
 pub struct EthernetScheme;
@@ -110,11 +142,11 @@ impl KScheme for EthernetScheme {
         "ethernet"
     }
 
-    fn open(&mut self, url: &str, _: usize) -> Result<Box<Resource>> {
+    fn open(&mut self, url: &str, flags: usize) -> Result<Box<Resource>> {
         let parts: Vec<&str> = url.splitn(2, ":").nth(1).unwrap_or("").split("/").collect();
         if let Some(host_string) = parts.get(0) {
             if let Some(ethertype_string) = parts.get(1) {
-                if let Ok(mut network) = ::env().open("network:", O_RDWR) {
+                if let Ok(mut network) = ::env().open("network:", flags) {
                     let ethertype = ethertype_string.to_num_radix(16) as u16;
 
                     if !host_string.is_empty() {
@@ -129,14 +161,16 @@ impl KScheme for EthernetScheme {
                             let mut bytes = [0; 65536];
                             match network.read(&mut bytes) {
                                 Ok(count) => {
-                                    if let Some(frame) = EthernetII::from_bytes(&bytes[..count]) {
-                                        if frame.header.ethertype.get() == ethertype &&
-                                           (unsafe { frame.header.dst.equals(MAC_ADDR) } ||
-                                            frame.header.dst.equals(BROADCAST_MAC_ADDR)) {
+                                    if let Some(frame) = EthernetFrame::new(&bytes[..count]) {
+                                        // Group addresses only arrive if a protocol joined them, such as IPv6 neighbor discovery
+                                        if frame.ethertype() == ethertype &&
+                                           (unsafe { frame.dst().equals(MAC_ADDR) } ||
+                                            frame.dst().equals(BROADCAST_MAC_ADDR) ||
+                                            frame.dst().is_multicast()) {
                                             return Ok(box EthernetResource {
                                                 network: network,
-                                                data: frame.data,
-                                                peer_addr: frame.header.src,
+                                                data: frame.payload().to_vec(),
+                                                peer_addr: frame.src(),
                                                 ethertype: ethertype,
                                             });
                                         }
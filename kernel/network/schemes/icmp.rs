@@ -1,16 +1,34 @@
+use alloc::boxed::Box;
+
+use common::random::rand;
 use common::slice::GetSlice;
+use common::time::{self, Duration};
 
 use collections::vec::Vec;
 
-use core::{mem, slice};
+use core::{cmp, mem, slice};
 
 use arch::context::context_switch;
+use arch::timekeeping;
 
+use network::PROTOCOL_STATS;
 use network::common::*;
+use network::ipv4::Ipv4;
+
+use fs::{KScheme, Resource};
+
 
-use fs::KScheme;
+use system::error::{Error, Result, EAGAIN, ENOENT};
+use system::syscall::{O_NONBLOCK, O_RDWR};
 
-use system::syscall::O_RDWR;
+use super::dns::dns_resolve;
+
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMP_DEST_UNREACHABLE: u8 = 3;
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+
+/// Milliseconds to wait for an echo reply
+const PING_TIMEOUT: i64 = 1000;
 
 #[derive(Copy, Clone)]
 #[repr(packed)]
@@ -52,40 +70,166 @@ impl ToBytes for Icmp {
     }
 }
 
+impl Icmp {
+    fn sum(&self) -> usize {
+        unsafe {
+            let header_ptr: *const IcmpHeader = &self.header;
+            Checksum::sum(header_ptr as usize, mem::size_of::<IcmpHeader>()) +
+            Checksum::sum(self.data.as_ptr() as usize, self.data.len())
+        }
+    }
+
+    pub fn calculate(&mut self) {
+        self.header.checksum.data = 0;
+        self.header.checksum.data = Checksum::compile(self.sum());
+    }
+
+    pub fn valid(&self) -> bool {
+        Checksum::compile(self.sum()) == 0
+    }
+
+    /// An echo request or reply with an identifier and sequence number
+    pub fn echo(kind: u8, id: u16, sequence: u16, data: Vec<u8>) -> Icmp {
+        let mut icmp = Icmp {
+            header: IcmpHeader {
+                _type: kind,
+                code: 0,
+                checksum: Checksum { data: 0 },
+                data: [(id >> 8) as u8, id as u8, (sequence >> 8) as u8, sequence as u8],
+            },
+            data: data,
+        };
+        icmp.calculate();
+        icmp
+    }
+}
+
+/// A ping resource, each read sends an echo request and reports the reply or a timeout
+pub struct PingResource {
+    ip: Box<Resource>,
+    peer_addr: Ipv4Addr,
+    id: u16,
+    sequence: u16,
+}
+
+impl PingResource {
+    /// Send the next request and wait for its reply, returning the round trip time in microseconds
+    fn ping(&mut self) -> Result<Option<i64>> {
+        self.sequence = self.sequence.wrapping_add(1);
+
+        let start = Duration::monotonic();
+        let mut data = Vec::new();
+        for i in 0..56 {
+            data.push(i as u8);
+        }
+        try!(self.ip.write(&Icmp::echo(ICMP_ECHO_REQUEST, self.id, self.sequence, data).to_bytes()));
+
+        let end = start + Duration::new(PING_TIMEOUT / 1000, (PING_TIMEOUT % 1000) as i32 * time::NANOS_PER_MILLI);
+        while Duration::monotonic() < end {
+            let mut bytes = [0; 65536];
+            match self.ip.read(&mut bytes) {
+                Ok(count) => if let Some(message) = Icmp::from_bytes(&bytes[..count]) {
+                    let id = (message.header.data[0] as u16) << 8 | message.header.data[1] as u16;
+                    let sequence = (message.header.data[2] as u16) << 8 | message.header.data[3] as u16;
+                    if message.header._type == ICMP_ECHO_REPLY && message.valid() &&
+                       id == self.id && sequence == self.sequence {
+                        let rtt = Duration::monotonic() - start;
+                        return Ok(Some(rtt.secs * 1000000 + (rtt.nanos / time::NANOS_PER_MICRO) as i64));
+                    }
+                },
+                Err(ref err) if err.errno == EAGAIN => {
+                    timekeeping::sleep(1, "ICMP echo");
+                },
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl Resource for PingResource {
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path_string = format!("ping:/{}", self.peer_addr.to_string());
+        let path = path_string.as_bytes();
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let line = match try!(self.ping()) {
+            Some(rtt) => format!("{}: seq={} time={}.{:03} ms\n", self.peer_addr.to_string(), self.sequence, rtt / 1000, rtt % 1000),
+            None => format!("{}: seq={} timeout\n", self.peer_addr.to_string(), self.sequence),
+        };
+
+        for (b, l) in buf.iter_mut().zip(line.as_bytes().iter()) {
+            *b = *l;
+        }
+
+        Ok(cmp::min(buf.len(), line.len()))
+    }
+}
+
+/// The ICMP scheme, ping:/host opens a ping resource
 pub struct IcmpScheme;
 
 impl KScheme for IcmpScheme {
     fn scheme(&self) -> &str {
-        "icmp"
+        "ping"
+    }
+
+    fn open(&mut self, url: &str, _: usize) -> Result<Box<Resource>> {
+        let host = url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/');
+        if host.is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+
+        let peer_addr = match try!(dns_resolve(host)).first() {
+            Some(addr) => *addr,
+            None => return Err(Error::new(ENOENT)),
+        };
+
+        Ok(box PingResource {
+            ip: try!(::env().open(&format!("ip:{}/1", peer_addr.to_string()), O_RDWR | O_NONBLOCK)),
+            peer_addr: peer_addr,
+            id: (rand() % 65536) as u16,
+            sequence: 0,
+        })
     }
 }
 
 impl IcmpScheme {
+    /// Answer echo requests from any host
     pub fn reply_loop() {
-        while let Ok(mut ip) = ::env().open("ip:/1", O_RDWR) {
+        // Frames are not filtered by sender once the link is open, so every IPv4 packet is seen here
+        while let Ok(mut link) = ::env().open("ethernet:/800", O_RDWR) {
             loop {
                 let mut bytes = [0; 65536];
-                if let Ok(count) = ip.read(&mut bytes) {
-                    if let Some(message) = Icmp::from_bytes(&bytes[..count]) {
-                        if message.header._type == 0x08 {
-                            let mut response = Icmp {
-                                header: message.header,
-                                data: message.data,
-                            };
-
-                            response.header._type = 0x00;
-
-                            unsafe {
-                                response.header.checksum.data = 0;
-
-                                let header_ptr: *const IcmpHeader = &response.header;
-                                response.header.checksum.data = Checksum::compile(
-                                    Checksum::sum(header_ptr as usize, mem::size_of::<IcmpHeader>()) +
-                                    Checksum::sum(response.data.as_ptr() as usize, response.data.len())
-                                );
-                            }
+                if let Ok(count) = link.read(&mut bytes) {
+                    if let Some(packet) = Ipv4::from_bytes(&bytes[..count]) {
+                        if packet.header.proto != 1 || ! is_local(packet.header.dst) {
+                            continue;
+                        }
 
-                            let _ = ip.write(&response.to_bytes());
+                        if let Some(message) = Icmp::from_bytes(&packet.data) {
+                            if message.header._type == ICMP_ECHO_REQUEST && message.valid() {
+                                let mut response = Icmp {
+                                    header: message.header,
+                                    data: message.data,
+                                };
+                                response.header._type = ICMP_ECHO_REPLY;
+                                response.calculate();
+
+                                if let Ok(mut ip) = ::env().open(&format!("ip:{}/1", packet.header.src.to_string()), O_RDWR) {
+                                    if ip.write(&response.to_bytes()).is_ok() {
+                                        unsafe { PROTOCOL_STATS.icmp_echo_answered += 1; }
+                                    }
+                                }
+                            }
                         }
                     }
                 } else {
@@ -1,42 +1,140 @@
 use alloc::boxed::Box;
 
 use collections::vec::Vec;
+use collections::vec_deque::VecDeque;
 
 use core::{cmp, mem};
 
+use network::{mtu, offload, OFFLOAD_TX_CHECKSUM};
 use network::common::*;
 use network::ipv4::*;
+use network::packet::Ipv4Builder;
+use network::route;
 
+use common::event::{IO_READ, IO_WRITE};
 use common::random;
+use common::time::Duration;
 use common::to_num::ToNum;
 
-use super::arp::{Arp, ArpHeader};
+use super::arp::{arp_resolve, ARP_PENDING_MAX, ARP_PENDING_TIMEOUT};
+use super::icmp::{Icmp, ICMP_DEST_UNREACHABLE};
 use fs::{KScheme, Resource};
 
-use system::error::{Error, Result, ENOENT};
-use system::syscall::O_RDWR;
+use system::error::{Error, Result, ECONNREFUSED, EHOSTUNREACH, EMSGSIZE, ENETUNREACH, ENOENT};
 
 /// A IP (internet protocole) resource
 pub struct IpResource {
-    link: Box<Resource>,
+    /// The ethernet link, opened once the next hop has been resolved
+    link: Option<Box<Resource>>,
     data: Vec<u8>,
     peer_addr: Ipv4Addr,
+    /// The next hop, either the peer or the gateway of its route
+    route_addr: Ipv4Addr,
+    /// Packets written before the next hop could be resolved, with when they were written
+    pending: VecDeque<(Duration, Vec<u8>)>,
+    /// The start of the last transport header sent (the ports for UDP and TCP), used to match ICMP errors
+    transport: [u8; 4],
+    /// Open flags, passed on to the link
+    flags: usize,
     proto: u8,
     id: u16,
+    /// An ICMP error found while checking readiness, returned by the next read
+    error: Option<isize>,
+}
+
+impl IpResource {
+    /// Check if a packet is an ICMP destination unreachable message for a packet sent by this resource
+    fn unreachable(packet: &Ipv4, proto: u8, peer_addr: Ipv4Addr, transport: [u8; 4]) -> Option<Error> {
+        if packet.header.proto != 1 {
+            return None;
+        }
+
+        if let Some(message) = Icmp::from_bytes(&packet.data) {
+            // The message quotes the original IP header and the start of its payload
+            let quoted = &message.data;
+            if message.header._type == ICMP_DEST_UNREACHABLE && quoted.len() >= 20 {
+                let header_len = ((quoted[0] & 0xF) << 2) as usize;
+                if quoted.len() >= header_len + 4 && quoted[9] == proto &&
+                   &quoted[16..20] == &peer_addr.bytes[..] &&
+                   &quoted[header_len..header_len + 4] == &transport[..] {
+                    return Some(Error::new(match message.header.code {
+                        0 | 6 | 9 | 11 => ENETUNREACH,
+                        2 | 3 => ECONNREFUSED,
+                        4 => EMSGSIZE,
+                        _ => EHOSTUNREACH,
+                    }));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// What a packet from the link is to this resource, `None` if it is for someone else, else its payload or
+    /// the error of an ICMP message about a packet sent
+    fn accept(packet: Ipv4, proto: u8, peer_addr: Ipv4Addr, transport: [u8; 4]) -> Option<Result<Vec<u8>>> {
+        if packet.header.dst.equals(local_addr(peer_addr)) {
+            if let Some(err) = IpResource::unreachable(&packet, proto, peer_addr, transport) {
+                return Some(Err(err));
+            }
+        }
+
+        if packet.header.proto == proto &&
+           (packet.header.dst.equals(local_addr(peer_addr)) || packet.header.dst.equals(BROADCAST_IP_ADDR)) &&
+           (packet.header.src.equals(peer_addr) || peer_addr.equals(BROADCAST_IP_ADDR)) {
+            Some(Ok(packet.data))
+        } else {
+            None
+        }
+    }
+
+    /// Resolve the next hop and open the link to it, sending any pending packets
+    ///
+    /// When resolution fails, with EHOSTUNREACH if there was no answer, the packets that waited long enough are dropped.
+    fn link(&mut self) -> Result<&mut Box<Resource>> {
+        if self.link.is_none() {
+            let route_mac = match arp_resolve(self.route_addr) {
+                Ok(route_mac) => route_mac,
+                Err(err) => {
+                    let now = Duration::monotonic();
+                    self.pending.retain(|&(time, _)| (now - time).secs < ARP_PENDING_TIMEOUT);
+                    return Err(err);
+                }
+            };
+            self.link = Some(try!(::env().open(&format!("ethernet:{}/800", &route_mac.to_string()), self.flags)));
+        }
+
+        let link = self.link.as_mut().unwrap();
+        while let Some((time, packet)) = self.pending.pop_front() {
+            if let Err(err) = link.write(&packet) {
+                self.pending.push_front((time, packet));
+                return Err(err);
+            }
+        }
+
+        Ok(link)
+    }
 }
 
 impl Resource for IpResource {
     fn dup(&self) -> Result<Box<Resource>> {
-        match self.link.dup() {
-            Ok(link) => Ok(box IpResource {
-                link: link,
-                data: self.data.clone(),
-                peer_addr: self.peer_addr,
-                proto: self.proto,
-                id: self.id,
-            }),
-            Err(err) => Err(err),
-        }
+        let link = match self.link {
+            Some(ref link) => Some(try!(link.dup())),
+            None => None,
+        };
+
+        Ok(box IpResource {
+            link: link,
+            data: self.data.clone(),
+            peer_addr: self.peer_addr,
+            route_addr: self.route_addr,
+            pending: self.pending.clone(),
+            transport: self.transport,
+            flags: self.flags,
+            proto: self.proto,
+            id: self.id,
+            error: self.error,
+        })
     }
 
     fn path(&self, buf: &mut [u8]) -> Result<usize> {
@@ -51,6 +149,10 @@ impl Resource for IpResource {
     }
 
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if let Some(errno) = self.error.take() {
+            return Err(Error::new(errno));
+        }
+
         if !self.data.is_empty() {
             let mut data: Vec<u8> = Vec::new();
             mem::swap(&mut self.data, &mut data);
@@ -62,80 +164,98 @@ impl Resource for IpResource {
             return Ok(cmp::min(buf.len(), data.len()));
         }
 
+        let proto = self.proto;
+        let peer_addr = self.peer_addr;
+        let transport = self.transport;
+        let link = try!(self.link());
         loop {
             let mut bytes = [0; 65536];
-            let count = try!(self.link.read(&mut bytes));
+            let count = try!(link.read(&mut bytes));
 
             if let Some(packet) = Ipv4::from_bytes(&bytes[..count]) {
-                if packet.header.proto == self.proto &&
-                   (packet.header.dst.equals(unsafe { IP_ADDR }) || packet.header.dst.equals(BROADCAST_IP_ADDR)) &&
-                   (packet.header.src.equals(self.peer_addr) || self.peer_addr.equals(BROADCAST_IP_ADDR)) {
-                    for (b, d) in buf.iter_mut().zip(packet.data.iter()) {
-                        *b = *d;
-                    }
+                match IpResource::accept(packet, proto, peer_addr, transport) {
+                    Some(Ok(data)) => {
+                        for (b, d) in buf.iter_mut().zip(data.iter()) {
+                            *b = *d;
+                        }
 
-                    return Ok(cmp::min(buf.len(), packet.data.len()));
+                        return Ok(cmp::min(buf.len(), data.len()));
+                    },
+                    Some(Err(err)) => return Err(err),
+                    None => (),
                 }
             }
         }
     }
 
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        let ip_data = Vec::from(buf);
+        // Packets are not fragmented, so they must fit the link
+        if mem::size_of::<Ipv4Header>() + buf.len() > mtu() {
+            return Err(Error::new(EMSGSIZE));
+        }
 
-        self.id += 1;
-        let mut ip = Ipv4 {
-            header: Ipv4Header {
-                ver_hlen: 0x40 | (mem::size_of::<Ipv4Header>() / 4 & 0xF) as u8, // No Options
-                services: 0,
-                len: n16::new((mem::size_of::<Ipv4Header>() + ip_data.len()) as u16), // No Options
-                id: n16::new(self.id),
-                flags_fragment: n16::new(0),
-                ttl: 128,
-                proto: self.proto,
-                checksum: Checksum { data: 0 },
-                src: unsafe { IP_ADDR },
-                dst: self.peer_addr,
-            },
-            options: Vec::new(),
-            data: ip_data,
-        };
+        for (t, b) in self.transport.iter_mut().zip(buf.iter()) {
+            *t = *b;
+        }
 
-        unsafe {
-            let header_ptr: *const Ipv4Header = &ip.header;
-            ip.header.checksum.data =
-                Checksum::compile(Checksum::sum(header_ptr as usize, mem::size_of::<Ipv4Header>()) +
-                                  Checksum::sum(ip.options.as_ptr() as usize, ip.options.len()));
+        self.id += 1;
+        // A zero header checksum leaves both checksums for the NIC, see `checksums_pending`
+        let packet = Ipv4Builder {
+            src: local_addr(self.peer_addr),
+            dst: self.peer_addr,
+            proto: self.proto,
+            id: self.id,
+            ttl: 128,
+            offload: offload(OFFLOAD_TX_CHECKSUM),
+        }.build(buf);
+
+        // Hold the packet until the next hop is known, dropping the oldest if the queue is full
+        if self.pending.len() >= ARP_PENDING_MAX {
+            self.pending.pop_front();
         }
+        self.pending.push_back((Duration::monotonic(), packet));
 
-        match self.link.write(&ip.to_bytes()) {
+        match self.link() {
             Ok(_) => Ok(buf.len()),
             Err(err) => Err(err),
         }
     }
 
     fn sync(&mut self) -> Result<()> {
-        self.link.sync()
+        try!(self.link()).sync()
     }
-}
 
-/// A ARP entry (MAC + IP)
-pub struct ArpEntry {
-    ip: Ipv4Addr,
-    mac: MacAddr,
+    /// Packets for others are dropped, one for this resource is kept for the next read
+    fn ready(&mut self) -> i64 {
+        if let Some(ref mut link) = self.link {
+            while self.data.is_empty() && self.error.is_none() && link.ready() & IO_READ == IO_READ {
+                let mut bytes = [0; 65536];
+                match link.read(&mut bytes) {
+                    Ok(count) => if let Some(packet) = Ipv4::from_bytes(&bytes[..count]) {
+                        match IpResource::accept(packet, self.proto, self.peer_addr, self.transport) {
+                            Some(Ok(data)) => self.data = data,
+                            Some(Err(err)) => self.error = Some(err.errno),
+                            None => (),
+                        }
+                    },
+                    Err(_) => return IO_READ | IO_WRITE,
+                }
+            }
+        }
+
+        if self.data.is_empty() && self.error.is_none() { IO_WRITE } else { IO_READ | IO_WRITE }
+    }
 }
 
 /// A IP scheme
-pub struct IpScheme {
-    pub arp: Vec<ArpEntry>,
-}
+pub struct IpScheme;
 
 impl KScheme for IpScheme {
     fn scheme(&self) -> &str {
         "ip"
     }
 
-    fn open(&mut self, url: &str, _: usize) -> Result<Box<Resource>> {
+    fn open(&mut self, url: &str, flags: usize) -> Result<Box<Resource>> {
         let parts: Vec<&str> = url.splitn(2, ":").nth(1).unwrap_or("").split('/').collect();
         if let Some(host_string) = parts.get(0) {
             if let Some(proto_string) = parts.get(1) {
@@ -143,98 +263,70 @@ impl KScheme for IpScheme {
 
                 if ! host_string.is_empty() {
                     let peer_addr = Ipv4Addr::from_str(host_string);
-                    let mut route_mac = BROADCAST_MAC_ADDR;
-
-                    if ! peer_addr.equals(BROADCAST_IP_ADDR) {
-                        let mut needs_routing = false;
-
-                        for octet in 0..4 {
-                            let me = unsafe { IP_ADDR.bytes[octet] };
-                            let mask = unsafe { IP_SUBNET.bytes[octet] };
-                            let them = peer_addr.bytes[octet];
-                            if me & mask != them & mask {
-                                needs_routing = true;
-                                break;
-                            }
-                        }
-
-                        let route_addr = if needs_routing {
-                            unsafe { IP_ROUTER_ADDR }
-                        } else {
-                            peer_addr
-                        };
 
-                        for entry in self.arp.iter() {
-                            if entry.ip.equals(route_addr) {
-                                route_mac = entry.mac;
-                                break;
-                            }
-                        }
-
-                        if route_mac.equals(BROADCAST_MAC_ADDR) {
-                            if let Ok(mut link) = ::env().open(&format!("ethernet:{}/806", &route_mac.to_string()), O_RDWR) {
-                                let arp = Arp {
-                                    header: ArpHeader {
-                                        htype: n16::new(1),
-                                        ptype: n16::new(0x800),
-                                        hlen: 6,
-                                        plen: 4,
-                                        oper: n16::new(1),
-                                        src_mac: unsafe { MAC_ADDR },
-                                        src_ip: unsafe { IP_ADDR },
-                                        dst_mac: route_mac,
-                                        dst_ip: route_addr,
-                                    },
-                                    data: Vec::new(),
-                                };
-
-                                match link.write(&arp.to_bytes()) {
-                                    Ok(_) => loop {
-                                        let mut bytes = [0; 65536];
-                                        match link.read(&mut bytes) {
-                                            Ok(count) => if let Some(packet) = Arp::from_bytes(&bytes[..count]) {
-                                                if packet.header.oper.get() == 2 &&
-                                                   packet.header.src_ip.equals(route_addr) {
-                                                    route_mac = packet.header.src_mac;
-                                                    self.arp.push(ArpEntry {
-                                                        ip: route_addr,
-                                                        mac: route_mac,
-                                                    });
-                                                    break;
-                                                }
-                                            },
-                                            Err(_) => (),
-                                        }
-                                    },
-                                    Err(err) => debugln!("IP: ARP Write Failed: {}", err),
-                                }
-                            }
+                    // Broadcasts and multicasts need no resolution, and packets routed to the loopback interface are looped back by the network scheme
+                    let routes = route::routes();
+                    let direct_mac = if peer_addr.equals(BROADCAST_IP_ADDR) {
+                        Some(BROADCAST_MAC_ADDR)
+                    } else if route::is_looped(&routes, peer_addr) {
+                        Some(unsafe { MAC_ADDR })
+                    } else if peer_addr.is_multicast() {
+                        Some(peer_addr.multicast_mac())
+                    } else {
+                        None
+                    };
+
+                    if let Some(route_mac) = direct_mac {
+                        if let Ok(link) = ::env().open(&format!("ethernet:{}/800", &route_mac.to_string()), flags) {
+                            return Ok(box IpResource {
+                                link: Some(link),
+                                data: Vec::new(),
+                                peer_addr: peer_addr,
+                                route_addr: peer_addr,
+                                pending: VecDeque::new(),
+                                transport: [0; 4],
+                                flags: flags,
+                                proto: proto,
+                                id: (random::rand() % 65536) as u16,
+                                error: None,
+                            });
                         }
-                    }
+                    } else {
+                        let route_addr = try!(route::next_hop(&routes, peer_addr));
 
-                    if let Ok(link) = ::env().open(&format!("ethernet:{}/800", &route_mac.to_string()), O_RDWR) {
+                        // The next hop is resolved on first use, so opening never blocks on ARP
                         return Ok(box IpResource {
-                            link: link,
+                            link: None,
                             data: Vec::new(),
                             peer_addr: peer_addr,
+                            route_addr: route_addr,
+                            pending: VecDeque::new(),
+                            transport: [0; 4],
+                            flags: flags,
                             proto: proto,
                             id: (random::rand() % 65536) as u16,
+                            error: None,
                         });
                     }
                 } else {
-                    while let Ok(mut link) = ::env().open("ethernet:/800", O_RDWR) {
+                    while let Ok(mut link) = ::env().open("ethernet:/800", flags) {
                         let mut bytes = [0; 65536];
                         match link.read(&mut bytes) {
                             Ok(count) => {
                                 if let Some(packet) = Ipv4::from_bytes(&bytes[..count]) {
                                     if packet.header.proto == proto &&
-                                       (packet.header.dst.equals(unsafe { IP_ADDR }) || packet.header.dst.equals(BROADCAST_IP_ADDR)) {
+                                       (is_local(packet.header.dst) || packet.header.dst.equals(BROADCAST_IP_ADDR)) {
                                         return Ok(box IpResource {
-                                            link: link,
+                                            link: Some(link),
                                             data: packet.data,
                                             peer_addr: packet.header.src,
+                                            route_addr: packet.header.src,
+                                            pending: VecDeque::new(),
+                                            transport: [0; 4],
+                                            flags: flags,
                                             proto: proto,
                                             id: (random::rand() % 65536) as u16,
+                                            error: None,
                                         });
                                     }
                                 }
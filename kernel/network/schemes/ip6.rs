@@ -0,0 +1,245 @@
+use alloc::boxed::Box;
+
+use collections::vec::Vec;
+use collections::vec_deque::VecDeque;
+
+use core::{cmp, mem};
+
+use network::mtu;
+use network::common::*;
+use network::ipv6::hop_limit;
+use network::packet::{Ipv6Builder, Ipv6Packet, IPV6_HEADER_LEN};
+
+use common::event::{IO_READ, IO_WRITE};
+use common::time::Duration;
+use common::to_num::ToNum;
+
+use super::ndp::{ndp_resolve, NDP_PENDING_MAX, NDP_PENDING_TIMEOUT};
+use fs::{KScheme, Resource};
+
+use system::error::{Error, Result, EADDRNOTAVAIL, EMSGSIZE, ENOENT};
+
+/// An IPv6 resource, for one next header to or from one peer on the link
+pub struct Ip6Resource {
+    /// The ethernet link, opened once the peer has been resolved
+    link: Option<Box<Resource>>,
+    data: Vec<u8>,
+    peer_addr: Ipv6Addr,
+    /// Packets written before the peer could be resolved, with when they were written
+    pending: VecDeque<(Duration, Vec<u8>)>,
+    /// Open flags, passed on to the link
+    flags: usize,
+    next_header: u8,
+}
+
+impl Ip6Resource {
+    /// Resolve the peer and open the link to it, sending any pending packets
+    ///
+    /// There is no routing, every destination is expected to be on the link. When resolution fails, with
+    /// EHOSTUNREACH if there was no answer, the packets that waited long enough are dropped.
+    fn link(&mut self) -> Result<&mut Box<Resource>> {
+        if self.link.is_none() {
+            // Packets to ourselves are looped back by the network scheme
+            let peer_mac = if self.peer_addr.is_loopback() || self.peer_addr.equals(unsafe { IPV6_ADDR }) {
+                unsafe { MAC_ADDR }
+            } else {
+                match ndp_resolve(self.peer_addr) {
+                    Ok(peer_mac) => peer_mac,
+                    Err(err) => {
+                        let now = Duration::monotonic();
+                        self.pending.retain(|&(time, _)| (now - time).secs < NDP_PENDING_TIMEOUT);
+                        return Err(err);
+                    }
+                }
+            };
+            self.link = Some(try!(::env().open(&format!("ethernet:{}/86DD", &peer_mac.to_string()), self.flags)));
+        }
+
+        let link = self.link.as_mut().unwrap();
+        while let Some((time, packet)) = self.pending.pop_front() {
+            if let Err(err) = link.write(&packet) {
+                self.pending.push_front((time, packet));
+                return Err(err);
+            }
+        }
+
+        Ok(link)
+    }
+}
+
+impl Resource for Ip6Resource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        let link = match self.link {
+            Some(ref link) => Some(try!(link.dup())),
+            None => None,
+        };
+
+        Ok(box Ip6Resource {
+            link: link,
+            data: self.data.clone(),
+            peer_addr: self.peer_addr,
+            pending: self.pending.clone(),
+            flags: self.flags,
+            next_header: self.next_header,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path_string = format!("ip6:[{}]/{:X}", self.peer_addr.to_string(), self.next_header);
+        let path = path_string.as_bytes();
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if !self.data.is_empty() {
+            let mut data: Vec<u8> = Vec::new();
+            mem::swap(&mut self.data, &mut data);
+
+            for (b, d) in buf.iter_mut().zip(data.iter()) {
+                *b = *d;
+            }
+
+            return Ok(cmp::min(buf.len(), data.len()));
+        }
+
+        let next_header = self.next_header;
+        let peer_addr = self.peer_addr;
+        let link = try!(self.link());
+        loop {
+            let mut bytes = [0; 65536];
+            let count = try!(link.read(&mut bytes));
+
+            if let Some(packet) = Ipv6Packet::new(&bytes[..count]) {
+                if packet.next_header() == next_header && is_local6(packet.dst()) &&
+                   (packet.src().equals(peer_addr) || peer_addr.is_multicast()) {
+                    for (b, d) in buf.iter_mut().zip(packet.payload().iter()) {
+                        *b = *d;
+                    }
+
+                    return Ok(cmp::min(buf.len(), packet.payload().len()));
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        // Packets are not fragmented, so they must fit the link
+        if IPV6_HEADER_LEN + buf.len() > mtu() {
+            return Err(Error::new(EMSGSIZE));
+        }
+
+        // Nothing is sent until duplicate address detection has given us an address
+        let src = local_addr6(self.peer_addr);
+        if src.is_unspecified() {
+            return Err(Error::new(EADDRNOTAVAIL));
+        }
+
+        let packet = Ipv6Builder {
+            src: src,
+            dst: self.peer_addr,
+            next_header: self.next_header,
+            hop_limit: hop_limit(self.peer_addr),
+        }.build(buf);
+
+        // Hold the packet until the peer is known, dropping the oldest if the queue is full
+        if self.pending.len() >= NDP_PENDING_MAX {
+            self.pending.pop_front();
+        }
+        self.pending.push_back((Duration::monotonic(), packet));
+
+        match self.link() {
+            Ok(_) => Ok(buf.len()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        try!(self.link()).sync()
+    }
+
+    /// Packets from other peers or with other next headers are dropped, one for this resource is kept for the next read
+    fn ready(&mut self) -> i64 {
+        if let Some(ref mut link) = self.link {
+            while self.data.is_empty() && link.ready() & IO_READ == IO_READ {
+                let mut bytes = [0; 65536];
+                match link.read(&mut bytes) {
+                    Ok(count) => if let Some(packet) = Ipv6Packet::new(&bytes[..count]) {
+                        if packet.next_header() == self.next_header && is_local6(packet.dst()) &&
+                           (packet.src().equals(self.peer_addr) || self.peer_addr.is_multicast()) {
+                            self.data = Vec::from(packet.payload());
+                        }
+                    },
+                    Err(_) => return IO_READ | IO_WRITE,
+                }
+            }
+        }
+
+        if self.data.is_empty() { IO_WRITE } else { IO_READ | IO_WRITE }
+    }
+}
+
+/// The IPv6 scheme, ip6:[address]/next_header opens a resource to a peer, ip6:/next_header waits for one
+pub struct Ip6Scheme;
+
+impl KScheme for Ip6Scheme {
+    fn scheme(&self) -> &str {
+        "ip6"
+    }
+
+    fn open(&mut self, url: &str, flags: usize) -> Result<Box<Resource>> {
+        let reference = url.splitn(2, ":").nth(1).unwrap_or("");
+        let (host_string, next_header_string) = match reference.rfind('/') {
+            Some(i) => (&reference[..i], &reference[i + 1..]),
+            None => {
+                debug!("IPv6: No next header provided\n");
+                return Err(Error::new(ENOENT));
+            },
+        };
+        let next_header = next_header_string.to_num_radix(16) as u8;
+
+        if ! host_string.is_empty() {
+            let peer_addr = match Ipv6Addr::from_str(host_string.trim_matches(|c| c == '[' || c == ']')) {
+                Some(addr) => addr,
+                None => return Err(Error::new(ENOENT)),
+            };
+
+            // The peer is resolved on first use, so opening never blocks on neighbor discovery
+            return Ok(box Ip6Resource {
+                link: None,
+                data: Vec::new(),
+                peer_addr: peer_addr,
+                pending: VecDeque::new(),
+                flags: flags,
+                next_header: next_header,
+            });
+        }
+
+        while let Ok(mut link) = ::env().open("ethernet:/86DD", flags) {
+            let mut bytes = [0; 65536];
+            match link.read(&mut bytes) {
+                Ok(count) => {
+                    if let Some(packet) = Ipv6Packet::new(&bytes[..count]) {
+                        if packet.next_header() == next_header && is_local6(packet.dst()) && ! packet.src().is_unspecified() {
+                            return Ok(box Ip6Resource {
+                                link: Some(link),
+                                data: packet.payload().to_vec(),
+                                peer_addr: packet.src(),
+                                pending: VecDeque::new(),
+                                flags: flags,
+                                next_header: next_header,
+                            });
+                        }
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+
+        Err(Error::new(ENOENT))
+    }
+}
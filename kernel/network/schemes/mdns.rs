@@ -0,0 +1,507 @@
+use alloc::boxed::Box;
+
+use arch::timekeeping;
+
+use collections::string::{String, ToString};
+use collections::vec::Vec;
+
+use common::random::rand;
+use common::time::{self, Duration};
+
+use fs::{KScheme, Resource, VecResource};
+
+use network::common::*;
+use network::packet::{Ipv4Builder, Ipv4Packet, UdpBuilder, UdpDatagram, IP_PROTO_UDP};
+
+
+use system::error::{Error, Result, EAGAIN, EINVAL};
+use system::syscall::{MODE_FILE, O_NONBLOCK, O_RDWR};
+
+use super::dns::{get_u16, get_u32, lowercase, read_name, DNS_CLASS_IN, DNS_TYPE_A};
+use super::ethernet::join_multicast;
+
+/// The group and port of every mDNS message
+pub const MDNS_ADDR: Ipv4Addr = Ipv4Addr { bytes: [224, 0, 0, 251] };
+pub const MDNS_PORT: u16 = 5353;
+/// The name used until one is written to netcfg:hostname
+pub const MDNS_DEFAULT_HOSTNAME: &'static str = "redox";
+/// Seconds our address record is cached, RFC 6762 asks for 120 for host records
+const MDNS_TTL: u32 = 120;
+/// The most a legacy resolver, one querying from another port than 5353, may cache it
+const MDNS_LEGACY_TTL: u32 = 10;
+/// Multicast messages are sent with a hop limit of 255, so they never leave the link
+const MDNS_HOP_LIMIT: u8 = 255;
+
+/// Probes sent for a name before it is ours, and the milliseconds between them
+const MDNS_PROBES: usize = 3;
+const MDNS_PROBE_INTERVAL: i32 = 250;
+/// Announcements sent once a name is ours, a second apart
+const MDNS_ANNOUNCEMENTS: usize = 2;
+const MDNS_ANNOUNCE_INTERVAL: i32 = 1000;
+/// Names tried in a row before waiting, as RFC 6762 asks after fifteen conflicts
+const MDNS_CONFLICTS_MAX: usize = 15;
+
+const DNS_TYPE_ANY: u16 = 255;
+/// The top bit of the class, asking for a unicast answer in a question and flushing caches in a record
+const MDNS_CLASS_FLAG: u16 = 0x8000;
+
+const DNS_FLAG_RESPONSE: u16 = 0x8000;
+const DNS_FLAG_AUTHORITATIVE: u16 = 0x0400;
+
+/// The host name written to netcfg:hostname, None until one is
+pub static mut MDNS_HOSTNAME: Option<String> = None;
+/// The name we answer for and the address given for it, None until it has been probed
+pub static mut MDNS_CLAIMED: Option<(String, Ipv4Addr)> = None;
+
+/// The host name to claim, without `.local`
+pub fn hostname() -> String {
+    match unsafe { MDNS_HOSTNAME.as_ref() } {
+        Some(hostname) => hostname.clone(),
+        None => MDNS_DEFAULT_HOSTNAME.to_string(),
+    }
+}
+
+/// Set the host name, a single label of letters, digits and hyphens, which is claimed again
+pub fn set_hostname(name: &str) -> Result<()> {
+    let name = name.trim().trim_right_matches(".local");
+    if name.is_empty() || name.len() > 63 || name.starts_with('-') ||
+       ! name.bytes().all(|b| (b as char).is_digit(36) || b == b'-') {
+        return Err(Error::new(EINVAL));
+    }
+
+    unsafe { MDNS_HOSTNAME = Some(name.bytes().map(|b| lowercase(b) as char).collect()); }
+    Ok(())
+}
+
+/// A question or a resource record, questions have no TTL or data
+struct MdnsRecord {
+    name: String,
+    kind: u16,
+    class: u16,
+    ttl: u32,
+    data: Vec<u8>,
+}
+
+/// The parts of a message the responder looks at, the additional records are skipped
+struct MdnsMessage {
+    id: u16,
+    flags: u16,
+    questions: Vec<MdnsRecord>,
+    answers: Vec<MdnsRecord>,
+    authorities: Vec<MdnsRecord>,
+}
+
+impl MdnsMessage {
+    fn parse(bytes: &[u8]) -> Option<MdnsMessage> {
+        let counts = [try_opt!(get_u16(bytes, 4)), try_opt!(get_u16(bytes, 6)), try_opt!(get_u16(bytes, 8))];
+
+        let mut i = 12;
+        let mut sections = Vec::new();
+        for (section, &count) in counts.iter().enumerate() {
+            let mut records = Vec::new();
+            for _ in 0..count {
+                let (name, next) = try_opt!(read_name(bytes, i));
+                let kind = try_opt!(get_u16(bytes, next));
+                let class = try_opt!(get_u16(bytes, next + 2));
+                if section == 0 {
+                    records.push(MdnsRecord {
+                        name: name,
+                        kind: kind,
+                        class: class,
+                        ttl: 0,
+                        data: Vec::new(),
+                    });
+                    i = next + 4;
+                } else {
+                    let ttl = try_opt!(get_u32(bytes, next + 4));
+                    let len = try_opt!(get_u16(bytes, next + 8)) as usize;
+                    let start = next + 10;
+                    if start + len > bytes.len() {
+                        return None;
+                    }
+                    records.push(MdnsRecord {
+                        name: name,
+                        kind: kind,
+                        class: class,
+                        ttl: ttl,
+                        data: bytes[start .. start + len].to_vec(),
+                    });
+                    i = start + len;
+                }
+            }
+            sections.push(records);
+        }
+
+        let authorities = try_opt!(sections.pop());
+        let answers = try_opt!(sections.pop());
+        let questions = try_opt!(sections.pop());
+        Some(MdnsMessage {
+            id: try_opt!(get_u16(bytes, 0)),
+            flags: try_opt!(get_u16(bytes, 2)),
+            questions: questions,
+            answers: answers,
+            authorities: authorities,
+        })
+    }
+}
+
+/// Builds a message, with each name after the first pointing back at the labels already written
+struct MdnsWriter {
+    bytes: Vec<u8>,
+    /// The names written, as each suffix of labels and where it starts
+    names: Vec<(String, usize)>,
+}
+
+impl MdnsWriter {
+    /// A message with `questions`, `answers` and `authorities` to be written in that order
+    fn new(id: u16, flags: u16, questions: u16, answers: u16, authorities: u16) -> MdnsWriter {
+        let mut bytes = Vec::new();
+        for &field in [id, flags, questions, answers, authorities, 0].iter() {
+            bytes.extend_from_slice(&n16::new(field).bytes);
+        }
+        MdnsWriter {
+            bytes: bytes,
+            names: Vec::new(),
+        }
+    }
+
+    fn name(&mut self, name: &str) {
+        let mut rest = name.trim_right_matches('.');
+        while ! rest.is_empty() {
+            if let Some(&(_, offset)) = self.names.iter().find(|&&(ref suffix, _)| suffix == rest) {
+                self.bytes.extend_from_slice(&n16::new(0xC000 | offset as u16).bytes);
+                return;
+            }
+            // Pointers have fourteen bits
+            if self.bytes.len() < 0x4000 {
+                self.names.push((rest.to_string(), self.bytes.len()));
+            }
+
+            let (label, next) = match rest.find('.') {
+                Some(dot) => (&rest[..dot], &rest[dot + 1..]),
+                None => (rest, ""),
+            };
+            self.bytes.push(label.len() as u8);
+            self.bytes.extend_from_slice(label.as_bytes());
+            rest = next;
+        }
+        self.bytes.push(0);
+    }
+
+    fn question(&mut self, name: &str, kind: u16, class: u16) {
+        self.name(name);
+        self.bytes.extend_from_slice(&n16::new(kind).bytes);
+        self.bytes.extend_from_slice(&n16::new(class).bytes);
+    }
+
+    /// An A record for `addr`
+    fn address(&mut self, name: &str, class: u16, ttl: u32, addr: Ipv4Addr) {
+        self.name(name);
+        self.bytes.extend_from_slice(&n16::new(DNS_TYPE_A).bytes);
+        self.bytes.extend_from_slice(&n16::new(class).bytes);
+        self.bytes.extend_from_slice(&n32::new(ttl).bytes);
+        self.bytes.extend_from_slice(&n16::new(4).bytes);
+        self.bytes.extend_from_slice(&addr.bytes);
+    }
+}
+
+/// Check if an A record is ours, the name compared without case
+fn is_our_record(record: &MdnsRecord, name: &str) -> bool {
+    record.name == name && record.class & ! MDNS_CLASS_FLAG == DNS_CLASS_IN
+}
+
+/// Claims the host name on the link and answers queries for it
+struct Responder {
+    /// The multicast group, writes go to its ethernet address and reads see every IPv4 frame
+    link: Box<Resource>,
+    /// The host name and address claimed, and the `-N` suffix that made the name unique
+    hostname: String,
+    addr: Ipv4Addr,
+    suffix: usize,
+    /// Set once the name has been probed and announced
+    announced: bool,
+}
+
+impl Responder {
+    fn new() -> Result<Responder> {
+        try!(join_multicast(&[MDNS_ADDR.multicast_mac()]));
+        let link = try!(::env().open(&format!("ethernet:{}/800", MDNS_ADDR.multicast_mac().to_string()), O_RDWR | O_NONBLOCK));
+        Ok(Responder {
+            link: link,
+            hostname: hostname(),
+            addr: NULL_IP_ADDR,
+            suffix: 1,
+            announced: false,
+        })
+    }
+
+    /// The name claimed, `hostname.local` or `hostname-N.local` after a conflict
+    fn name(&self) -> String {
+        if self.suffix > 1 {
+            format!("{}-{}.local", self.hostname, self.suffix)
+        } else {
+            format!("{}.local", self.hostname)
+        }
+    }
+
+    /// Send to the group, with the hop limit that keeps the message on the link
+    fn multicast(&mut self, message: &[u8]) -> Result<()> {
+        let datagram = UdpBuilder {
+            src: self.addr,
+            dst: MDNS_ADDR,
+            src_port: MDNS_PORT,
+            dst_port: MDNS_PORT,
+            offload: false,
+        }.build(message);
+        let packet = Ipv4Builder {
+            src: self.addr,
+            dst: MDNS_ADDR,
+            proto: IP_PROTO_UDP,
+            id: rand() as u16,
+            ttl: MDNS_HOP_LIMIT,
+            offload: false,
+        }.build(&datagram);
+        self.link.write(&packet).and(Ok(()))
+    }
+
+    /// Send an answer straight to the port that asked
+    fn unicast(&self, peer: Ipv4Addr, port: u16, message: &[u8]) -> Result<()> {
+        let mut udp = try!(::env().open(&format!("udp:{}:{}/{}", peer.to_string(), port, MDNS_PORT), O_RDWR));
+        udp.write(message).and(Ok(()))
+    }
+
+    /// Our address record in a response, TTL zero says goodbye
+    fn response(&self, ttl: u32) -> Vec<u8> {
+        let mut writer = MdnsWriter::new(0, DNS_FLAG_RESPONSE | DNS_FLAG_AUTHORITATIVE, 0, 1, 0);
+        writer.address(&self.name(), DNS_CLASS_IN | MDNS_CLASS_FLAG, ttl, self.addr);
+        writer.bytes
+    }
+
+    /// Ask who has the name, with the record we want to give in the authority section for tie-breaking
+    fn probe(&self) -> Vec<u8> {
+        let name = self.name();
+        let mut writer = MdnsWriter::new(0, 0, 1, 0, 1);
+        writer.question(&name, DNS_TYPE_ANY, DNS_CLASS_IN | MDNS_CLASS_FLAG);
+        writer.address(&name, DNS_CLASS_IN, MDNS_TTL, self.addr);
+        writer.bytes
+    }
+
+    /// Tell caches to forget the name, before it or the address changes
+    fn goodbye(&mut self) {
+        if self.announced {
+            let message = self.response(0);
+            let _ = self.multicast(&message);
+            self.announced = false;
+            unsafe { MDNS_CLAIMED = None; }
+        }
+    }
+
+    /// Give up a name another host answered for, it is claimed again with the next suffix
+    fn conflict(&mut self) {
+        syslog_warning!("mDNS: {} is also used by another host", self.name());
+        self.announced = false;
+        self.suffix += 1;
+        unsafe { MDNS_CLAIMED = None; }
+    }
+
+    /// Probe for the name until one is free, then announce it
+    fn claim(&mut self, hostname: String, addr: Ipv4Addr) {
+        if hostname != self.hostname {
+            self.suffix = 1;
+        }
+        self.hostname = hostname;
+        self.addr = addr;
+
+        let mut conflicts = 0;
+        loop {
+            // A random delay, so hosts starting together do not probe in step
+            timekeeping::sleep((rand() % MDNS_PROBE_INTERVAL as usize) as u64, "mDNS probe delay");
+
+            let mut taken = false;
+            for _ in 0..MDNS_PROBES {
+                let probe = self.probe();
+                let _ = self.multicast(&probe);
+                if self.listen(MDNS_PROBE_INTERVAL, true) {
+                    taken = true;
+                    break;
+                }
+            }
+            if ! taken {
+                break;
+            }
+
+            syslog_warning!("mDNS: {} is in use", self.name());
+            self.suffix += 1;
+            conflicts += 1;
+            if conflicts >= MDNS_CONFLICTS_MAX {
+                timekeeping::sleep(5000, "mDNS conflict");
+                conflicts = 0;
+            }
+        }
+
+        self.announced = true;
+        unsafe { MDNS_CLAIMED = Some((self.name(), self.addr)); }
+        syslog_info!("mDNS: {} for {}", self.name(), self.addr.to_string());
+
+        for i in 0..MDNS_ANNOUNCEMENTS {
+            let message = self.response(MDNS_TTL);
+            let _ = self.multicast(&message);
+            if i + 1 < MDNS_ANNOUNCEMENTS {
+                self.listen(MDNS_ANNOUNCE_INTERVAL, false);
+            }
+        }
+    }
+
+    /// Handle messages for `millis`, returning whether one showed the name is taken
+    fn listen(&mut self, millis: i32, probing: bool) -> bool {
+        let end = Duration::monotonic() + Duration::new((millis / 1000) as i64, (millis % 1000) * time::NANOS_PER_MILLI);
+        while Duration::monotonic() < end {
+            let mut bytes = [0; 65536];
+            match self.link.read(&mut bytes) {
+                Ok(count) => if self.handle(&bytes[..count], probing) {
+                    return true;
+                },
+                Err(ref err) if err.errno == EAGAIN => {
+                    timekeeping::sleep(10, "mDNS receive");
+                },
+                Err(_) => return false,
+            }
+        }
+        false
+    }
+
+    /// Answer a query for the name, returning whether the message showed another host has or wants it
+    fn handle(&mut self, bytes: &[u8], probing: bool) -> bool {
+        let packet = match Ipv4Packet::new(bytes) {
+            Some(packet) => packet,
+            None => return false,
+        };
+        let (src, dst) = (packet.src(), packet.dst());
+        if packet.proto() != IP_PROTO_UDP || packet.is_fragment() || src.equals(self.addr) ||
+           ! (dst.equals(MDNS_ADDR) || dst.equals(self.addr)) {
+            return false;
+        }
+
+        let datagram = match UdpDatagram::new(packet.payload()) {
+            Some(datagram) => datagram,
+            None => return false,
+        };
+        if datagram.dst_port() != MDNS_PORT || ! datagram.checksum_valid(&src, &dst) {
+            return false;
+        }
+
+        let message = match MdnsMessage::parse(datagram.payload()) {
+            Some(message) => message,
+            None => return false,
+        };
+
+        let name = self.name();
+        if message.flags & DNS_FLAG_RESPONSE == DNS_FLAG_RESPONSE {
+            // Any record for the name that is not our address means another host has it
+            return message.answers.iter().any(|record| {
+                is_our_record(record, &name) && (record.kind != DNS_TYPE_A || record.data != &self.addr.bytes[..])
+            });
+        }
+
+        if probing {
+            // Another host probing at the same time, the greater address wins
+            return message.authorities.iter().any(|record| {
+                is_our_record(record, &name) && record.kind == DNS_TYPE_A && record.data.len() == 4 &&
+                record.data > self.addr.bytes.to_vec()
+            });
+        }
+
+        if ! self.announced {
+            return false;
+        }
+
+        let question = message.questions.iter().find(|question| {
+            is_our_record(question, &name) && (question.kind == DNS_TYPE_A || question.kind == DNS_TYPE_ANY)
+        });
+        if let Some(question) = question {
+            // The asker already has our address for at least half its lifetime
+            let known = message.answers.iter().any(|record| {
+                is_our_record(record, &name) && record.kind == DNS_TYPE_A && record.data == &self.addr.bytes[..] &&
+                record.ttl >= MDNS_TTL / 2
+            });
+
+            let port = datagram.src_port();
+            let result = if port != MDNS_PORT {
+                // A legacy resolver gets its question back, as a plain DNS server would answer it
+                let mut writer = MdnsWriter::new(message.id, DNS_FLAG_RESPONSE | DNS_FLAG_AUTHORITATIVE, 1, 1, 0);
+                writer.question(&name, question.kind, DNS_CLASS_IN);
+                writer.address(&name, DNS_CLASS_IN, MDNS_LEGACY_TTL, self.addr);
+                self.unicast(src, port, &writer.bytes)
+            } else if known {
+                Ok(())
+            } else if question.class & MDNS_CLASS_FLAG == MDNS_CLASS_FLAG {
+                self.unicast(src, port, &self.response(MDNS_TTL))
+            } else {
+                let message = self.response(MDNS_TTL);
+                self.multicast(&message)
+            };
+            if let Err(err) = result {
+                debugln!("mDNS: Failed to answer {}: {}", src.to_string(), err);
+            }
+        }
+
+        false
+    }
+}
+
+/// The mDNS scheme, reading it returns the name claimed and its address
+pub struct MdnsScheme;
+
+impl KScheme for MdnsScheme {
+    fn scheme(&self) -> &str {
+        "mdns"
+    }
+
+    fn open(&mut self, _: &str, _: usize) -> Result<Box<Resource>> {
+        let string = match unsafe { MDNS_CLAIMED.as_ref() } {
+            Some(&(ref name, addr)) => format!("{} {}\n", name, addr.to_string()),
+            None => "none\n".to_string(),
+        };
+
+        Ok(box VecResource::new("mdns:".to_string(), string.into_bytes(), MODE_FILE))
+    }
+}
+
+impl MdnsScheme {
+    /// Claim the host name whenever there is an address, and answer for it until the address or name changes
+    pub fn reply_loop() {
+        loop {
+            while ! unsafe { LINK_UP } {
+                timekeeping::sleep(100, "mDNS poll");
+            }
+
+            let mut responder = match Responder::new() {
+                Ok(responder) => responder,
+                Err(err) => {
+                    debugln!("mDNS: Failed to join the group: {}", err);
+                    timekeeping::sleep(1000, "mDNS link");
+                    continue;
+                }
+            };
+
+            while unsafe { LINK_UP } {
+                let (hostname, addr) = (hostname(), unsafe { IP_ADDR });
+                if responder.announced && (hostname != responder.hostname || ! addr.equals(responder.addr)) {
+                    responder.goodbye();
+                }
+                // Nothing is claimed while DHCP has no address
+                if ! responder.announced && ! addr.equals(NULL_IP_ADDR) {
+                    responder.claim(hostname, addr);
+                }
+
+                if responder.listen(100, false) {
+                    responder.conflict();
+                }
+            }
+
+            // The link went down, so the group has to be joined again and goodbyes cannot be sent
+            responder.announced = false;
+            unsafe { MDNS_CLAIMED = None; }
+        }
+    }
+}
@@ -1,15 +1,28 @@
 pub use self::arp::ArpScheme;
 pub use self::config::NetConfigScheme;
+pub use self::dhcp::DhcpScheme;
+pub use self::dns::DnsScheme;
 pub use self::ethernet::EthernetScheme;
 pub use self::icmp::IcmpScheme;
 pub use self::ip::IpScheme;
+pub use self::ip6::Ip6Scheme;
+pub use self::mdns::MdnsScheme;
+pub use self::ndp::NdpScheme;
+pub use self::netstat::NetstatScheme;
 pub use self::tcp::TcpScheme;
 pub use self::udp::UdpScheme;
 
 pub mod arp;
 pub mod config;
+pub mod dhcp;
+#[macro_use]
+pub mod dns;
 pub mod ethernet;
 pub mod icmp;
 pub mod ip;
+pub mod ip6;
+pub mod mdns;
+pub mod ndp;
+pub mod netstat;
 pub mod tcp;
 pub mod udp;
@@ -0,0 +1,425 @@
+use alloc::boxed::Box;
+
+use common::random::rand;
+use common::time::{self, Duration};
+
+use collections::string::{String, ToString};
+use collections::vec::Vec;
+
+use arch::context::context_switch;
+use arch::timekeeping;
+
+use network::PROTOCOL_STATS;
+use network::common::*;
+use network::ipv6::pseudo_header_sum;
+use network::packet::{self, put_checksum, Ipv6Builder, Ipv6Packet, IP_PROTO_ICMPV6};
+
+use fs::{KScheme, Resource, VecResource};
+
+use super::ethernet::join_multicast;
+
+
+use system::error::{Error, Result, EADDRNOTAVAIL, EHOSTUNREACH};
+use system::syscall::{MODE_FILE, O_NONBLOCK, O_RDWR};
+
+/// Number of cached neighbors
+pub const NDP_CACHE_SIZE: usize = 32;
+/// Seconds before a cache entry has to be resolved again
+pub const NDP_TIMEOUT: i64 = 300;
+/// Solicitations sent before resolution fails
+pub const NDP_RETRIES: usize = 3;
+/// Milliseconds to wait for an advertisement after each solicitation, RetransTimer in RFC 4861
+pub const NDP_REPLY_TIMEOUT: i32 = 1000;
+/// Packets held by an IPv6 resource while its next hop is resolved
+pub const NDP_PENDING_MAX: usize = 16;
+/// Seconds a held packet may wait, it is dropped when a resolution fails after that
+pub const NDP_PENDING_TIMEOUT: i64 = 3;
+/// The hop limit of every neighbor discovery message, one with any other came through a router and is ignored
+pub const NDP_HOP_LIMIT: u8 = 255;
+
+const ICMPV6_NEIGHBOR_SOLICITATION: u8 = 135;
+const ICMPV6_NEIGHBOR_ADVERTISEMENT: u8 = 136;
+
+const NDP_OPTION_SOURCE_LINK_ADDR: u8 = 1;
+const NDP_OPTION_TARGET_LINK_ADDR: u8 = 2;
+
+/// Advertisement flags, in the first byte after the checksum
+const NA_SOLICITED: u8 = 0x40;
+const NA_OVERRIDE: u8 = 0x20;
+
+/// The type, code, checksum, flags and target of a solicitation or advertisement
+const NDP_MESSAGE_LEN: usize = 24;
+
+/// A neighbor cache entry (MAC + IPv6)
+#[derive(Copy, Clone)]
+pub struct NdpEntry {
+    pub ip: Ipv6Addr,
+    pub mac: MacAddr,
+    /// When the entry was learned
+    pub time: Duration,
+    pub valid: bool,
+}
+
+const NDP_ENTRY_EMPTY: NdpEntry = NdpEntry {
+    ip: Ipv6Addr { bytes: [0; 16] },
+    mac: MacAddr { bytes: [0; 6] },
+    time: Duration { secs: 0, nanos: 0 },
+    valid: false,
+};
+
+/// The neighbor cache, filled by the reply loop
+pub static mut NDP_CACHE: [NdpEntry; NDP_CACHE_SIZE] = [NDP_ENTRY_EMPTY; NDP_CACHE_SIZE];
+/// Set when the link-local address has to be checked again, such as after the link comes up
+pub static mut NDP_CONFIGURE: bool = true;
+/// The address duplicate address detection found in use, which is then not assigned
+pub static mut NDP_DUPLICATE: Option<Ipv6Addr> = None;
+
+/// A neighbor solicitation or advertisement
+pub struct NdpMessage {
+    pub kind: u8,
+    pub flags: u8,
+    pub target: Ipv6Addr,
+    /// The source link-layer address of a solicitation, or the target link-layer address of an advertisement
+    pub link_addr: Option<MacAddr>,
+}
+
+impl NdpMessage {
+    /// Parse a message, checking everything RFC 4861 requires of a solicitation or advertisement
+    pub fn from_packet(packet: &Ipv6Packet) -> Option<NdpMessage> {
+        let bytes = packet.payload();
+        if packet.next_header() != IP_PROTO_ICMPV6 || packet.hop_limit() != NDP_HOP_LIMIT ||
+           bytes.len() < NDP_MESSAGE_LEN || bytes[1] != 0 ||
+           (bytes[0] != ICMPV6_NEIGHBOR_SOLICITATION && bytes[0] != ICMPV6_NEIGHBOR_ADVERTISEMENT) ||
+           Checksum::compile(packet.pseudo_header_sum() + packet::sum(bytes)) != 0 {
+            return None;
+        }
+
+        let mut target = Ipv6Addr { bytes: [0; 16] };
+        target.bytes.copy_from_slice(&bytes[8..24]);
+        if target.is_multicast() {
+            return None;
+        }
+
+        let wanted = if bytes[0] == ICMPV6_NEIGHBOR_SOLICITATION {
+            NDP_OPTION_SOURCE_LINK_ADDR
+        } else {
+            NDP_OPTION_TARGET_LINK_ADDR
+        };
+
+        // Options are a type and a length in units of eight bytes, a zero length is invalid
+        let mut link_addr = None;
+        let mut i = NDP_MESSAGE_LEN;
+        while i + 2 <= bytes.len() {
+            let len = bytes[i + 1] as usize * 8;
+            if len == 0 || i + len > bytes.len() {
+                return None;
+            }
+            if bytes[i] == wanted && len >= 8 {
+                let mut mac = MacAddr { bytes: [0; 6] };
+                mac.bytes.copy_from_slice(&bytes[i + 2..i + 8]);
+                link_addr = Some(mac);
+            }
+            i += len;
+        }
+
+        Some(NdpMessage {
+            kind: bytes[0],
+            flags: bytes[4],
+            target: target,
+            link_addr: link_addr,
+        })
+    }
+
+    /// Build the IPv6 packet carrying this message
+    pub fn to_packet(&self, src: Ipv6Addr, dst: Ipv6Addr) -> Vec<u8> {
+        let mut bytes = vec![self.kind, 0, 0, 0, self.flags, 0, 0, 0];
+        bytes.extend_from_slice(&self.target.bytes);
+        if let Some(mac) = self.link_addr {
+            let option = if self.kind == ICMPV6_NEIGHBOR_SOLICITATION {
+                NDP_OPTION_SOURCE_LINK_ADDR
+            } else {
+                NDP_OPTION_TARGET_LINK_ADDR
+            };
+            bytes.push(option);
+            bytes.push(1);
+            bytes.extend_from_slice(&mac.bytes);
+        }
+
+        let pseudo = pseudo_header_sum(&src, &dst, IP_PROTO_ICMPV6, bytes.len());
+        let checksum = Checksum::compile(pseudo + packet::sum(&bytes));
+        put_checksum(&mut bytes, 2, checksum);
+
+        Ipv6Builder {
+            src: src,
+            dst: dst,
+            next_header: IP_PROTO_ICMPV6,
+            hop_limit: NDP_HOP_LIMIT,
+        }.build(&bytes)
+    }
+
+    /// A solicitation for `target`, from the unspecified address during duplicate address detection
+    pub fn solicitation(target: Ipv6Addr, detecting: bool) -> NdpMessage {
+        NdpMessage {
+            kind: ICMPV6_NEIGHBOR_SOLICITATION,
+            flags: 0,
+            target: target,
+            // A solicitation from the unspecified address must not have one
+            link_addr: if detecting { None } else { Some(unsafe { MAC_ADDR }) },
+        }
+    }
+
+    /// An advertisement of our address, solicited if it answers a solicitation from an address
+    pub fn advertisement(solicited: bool) -> NdpMessage {
+        NdpMessage {
+            kind: ICMPV6_NEIGHBOR_ADVERTISEMENT,
+            flags: NA_OVERRIDE | if solicited { NA_SOLICITED } else { 0 },
+            target: unsafe { IPV6_ADDR },
+            link_addr: Some(unsafe { MAC_ADDR }),
+        }
+    }
+}
+
+/// Send a message to a neighbor, or to the ethernet group address of a multicast destination
+fn ndp_send(message: &NdpMessage, src: Ipv6Addr, dst: Ipv6Addr, dst_mac: MacAddr) -> Result<()> {
+    let mut link = try!(::env().open(&format!("ethernet:{}/86DD", dst_mac.to_string()), O_RDWR));
+    link.write(&message.to_packet(src, dst)).and(Ok(()))
+}
+
+/// Look up a cached neighbor, expired entries are ignored
+pub fn ndp_lookup(ip: Ipv6Addr) -> Option<MacAddr> {
+    let now = Duration::monotonic();
+    for entry in unsafe { NDP_CACHE.iter() } {
+        if entry.valid && entry.ip.equals(ip) && (now - entry.time).secs < NDP_TIMEOUT {
+            return Some(entry.mac);
+        }
+    }
+    None
+}
+
+/// Add or refresh a cache entry, an address has at most one, replacing the oldest entry if the cache is full
+pub fn ndp_insert(ip: Ipv6Addr, mac: MacAddr) {
+    let cache = unsafe { &mut NDP_CACHE };
+
+    let index = match cache.iter().position(|entry| entry.valid && entry.ip.equals(ip)) {
+        Some(index) => index,
+        None => match cache.iter().position(|entry| ! entry.valid) {
+            Some(index) => index,
+            None => (0..cache.len()).fold(0, |oldest, i| if cache[i].time < cache[oldest].time { i } else { oldest }),
+        },
+    };
+
+    cache[index] = NdpEntry {
+        ip: ip,
+        mac: mac,
+        time: Duration::monotonic(),
+        valid: true,
+    };
+}
+
+/// Forget all cached neighbors, used when the link goes down
+pub fn ndp_flush() {
+    for entry in unsafe { NDP_CACHE.iter_mut() } {
+        entry.valid = false;
+    }
+}
+
+/// Check if a message shows another node has or wants `tentative`
+fn is_duplicate(message: &NdpMessage, src: Ipv6Addr, tentative: Ipv6Addr) -> bool {
+    message.target.equals(tentative) &&
+    (message.kind == ICMPV6_NEIGHBOR_ADVERTISEMENT ||
+     (message.kind == ICMPV6_NEIGHBOR_SOLICITATION && src.is_unspecified()))
+}
+
+/// Generate the link-local address from the MAC address and assign it once duplicate address detection passes
+///
+/// One solicitation is sent for the tentative address, after a random delay of up to a second
+/// as RFC 4862 asks. Any answer, or another node detecting the same address, leaves it unassigned.
+pub fn ndp_configure() -> Result<()> {
+    unsafe {
+        NDP_CONFIGURE = false;
+        IPV6_ADDR = NULL_IPV6_ADDR;
+    }
+
+    let tentative = Ipv6Addr::link_local(unsafe { MAC_ADDR });
+    let solicited = tentative.solicited_node();
+    try!(join_multicast(&[ALL_NODES_IPV6_ADDR.multicast_mac(), solicited.multicast_mac()]));
+
+    let mut link = try!(::env().open(&format!("ethernet:{}/86DD", solicited.multicast_mac().to_string()), O_RDWR | O_NONBLOCK));
+
+    timekeeping::sleep((rand() % 1000) as u64, "NDP delay");
+    try!(link.write(&NdpMessage::solicitation(tentative, true).to_packet(NULL_IPV6_ADDR, solicited)));
+    unsafe { PROTOCOL_STATS.ndp_solicitations_sent += 1; }
+
+    let end = Duration::monotonic() + Duration::new(0, NDP_REPLY_TIMEOUT * time::NANOS_PER_MILLI);
+    while Duration::monotonic() < end {
+        let mut bytes = [0; 65536];
+        match link.read(&mut bytes) {
+            Ok(count) => if let Some(packet) = Ipv6Packet::new(&bytes[..count]) {
+                if let Some(message) = NdpMessage::from_packet(&packet) {
+                    if is_duplicate(&message, packet.src(), tentative) {
+                        syslog_warning!("IPv6: Duplicate address {}, not assigned", tentative.to_string());
+                        unsafe { NDP_DUPLICATE = Some(tentative); }
+                        return Ok(());
+                    }
+                }
+            },
+            Err(_) => timekeeping::sleep(10, "NDP reply"),
+        }
+    }
+
+    unsafe {
+        IPV6_ADDR = tentative;
+        NDP_DUPLICATE = None;
+    }
+    syslog_info!("IPv6: Link-local address {}", tentative.to_string());
+
+    // Let neighbors with a stale entry for us know
+    let _ = ndp_send(&NdpMessage::advertisement(false), tentative, ALL_NODES_IPV6_ADDR, ALL_NODES_IPV6_ADDR.multicast_mac());
+
+    Ok(())
+}
+
+/// Check the link-local address again if it is pending, as the reply loop only does between messages
+fn ndp_announce() {
+    if unsafe { NDP_CONFIGURE && LINK_UP } {
+        if let Err(err) = ndp_configure() {
+            debugln!("IPv6: Failed to configure the link-local address: {}", err);
+        }
+    }
+}
+
+/// Resolve a neighbor, sending solicitations until the reply loop learns it or the retries run out
+pub fn ndp_resolve(ip: Ipv6Addr) -> Result<MacAddr> {
+    if ip.is_multicast() {
+        return Ok(ip.multicast_mac());
+    }
+
+    if let Some(mac) = ndp_lookup(ip) {
+        return Ok(mac);
+    }
+
+    ndp_announce();
+
+    let src = unsafe { IPV6_ADDR };
+    if src.is_unspecified() {
+        return Err(Error::new(EADDRNOTAVAIL));
+    }
+
+    let solicited = ip.solicited_node();
+    let mut link = try!(::env().open(&format!("ethernet:{}/86DD", solicited.multicast_mac().to_string()), O_RDWR));
+    for _ in 0..NDP_RETRIES {
+        try!(link.write(&NdpMessage::solicitation(ip, false).to_packet(src, solicited)));
+        unsafe { PROTOCOL_STATS.ndp_solicitations_sent += 1; }
+
+        let end = Duration::monotonic() + Duration::new(0, NDP_REPLY_TIMEOUT * time::NANOS_PER_MILLI);
+        while Duration::monotonic() < end {
+            if let Some(mac) = ndp_lookup(ip) {
+                return Ok(mac);
+            }
+
+            timekeeping::sleep(10, "NDP reply");
+        }
+    }
+
+    debugln!("NDP: No advertisement from {}", ip.to_string());
+    Err(Error::new(EHOSTUNREACH))
+}
+
+/// The NDP scheme, reading it dumps the neighbor cache
+pub struct NdpScheme;
+
+impl KScheme for NdpScheme {
+    fn scheme(&self) -> &str {
+        "ndp"
+    }
+
+    fn open(&mut self, _: &str, _: usize) -> Result<Box<Resource>> {
+        let now = Duration::monotonic();
+
+        let mut string = String::new();
+        for entry in unsafe { NDP_CACHE.iter() } {
+            if entry.valid {
+                let age = (now - entry.time).secs;
+                string.push_str(&format!("{} {} {}{}\n", entry.ip.to_string(), entry.mac.to_string(), age,
+                                         if age >= NDP_TIMEOUT { " expired" } else { "" }));
+            }
+        }
+
+        Ok(box VecResource::new("ndp:".to_string(), string.into_bytes(), MODE_FILE))
+    }
+}
+
+impl NdpScheme {
+    /// Configure the link-local address, then answer solicitations for it and learn neighbors from advertisements
+    pub fn reply_loop() {
+        while ! unsafe { LINK_UP } {
+            timekeeping::sleep(100, "NDP poll");
+        }
+        ndp_announce();
+
+        while let Ok(mut link) = ::env().open("ethernet:/86DD", O_RDWR) {
+            loop {
+                // The address follows the MAC address, which may have been changed
+                if ! unsafe { IPV6_ADDR.is_unspecified() || IPV6_ADDR.equals(Ipv6Addr::link_local(MAC_ADDR)) } {
+                    unsafe { NDP_CONFIGURE = true; }
+                }
+                ndp_announce();
+
+                let mut bytes = [0; 65536];
+                if let Ok(count) = link.read(&mut bytes) {
+                    if let Some(packet) = Ipv6Packet::new(&bytes[..count]) {
+                        if let Some(message) = NdpMessage::from_packet(&packet) {
+                            NdpScheme::handle(&packet, &message);
+                        }
+                    }
+                } else {
+                    break;
+                }
+            }
+            unsafe { context_switch() };
+        }
+        debug!("NDP: Failed to open ethernet:\n");
+    }
+
+    fn handle(packet: &Ipv6Packet, message: &NdpMessage) {
+        let ours = unsafe { IPV6_ADDR };
+        let src = packet.src();
+
+        if message.kind == ICMPV6_NEIGHBOR_SOLICITATION {
+            // A solicitation from an address tells us its link address
+            if ! src.is_unspecified() {
+                if let Some(mac) = message.link_addr {
+                    ndp_insert(src, mac);
+                }
+            }
+
+            if ours.is_unspecified() || ! message.target.equals(ours) {
+                return;
+            }
+
+            // Another node detecting our address is told it is taken, at the all-nodes group
+            let result = if src.is_unspecified() {
+                ndp_send(&NdpMessage::advertisement(false), ours, ALL_NODES_IPV6_ADDR, ALL_NODES_IPV6_ADDR.multicast_mac())
+            } else {
+                match message.link_addr.or_else(|| ndp_lookup(src)) {
+                    Some(mac) => ndp_send(&NdpMessage::advertisement(true), ours, src, mac),
+                    None => return,
+                }
+            };
+
+            if result.is_ok() {
+                unsafe { PROTOCOL_STATS.ndp_solicitations_answered += 1; }
+            }
+        } else {
+            if message.target.equals(ours) {
+                // Our own advertisements are not looped back, so another node claims the address
+                syslog_warning!("IPv6: Address {} advertised by another node", ours.to_string());
+                return;
+            }
+
+            if let Some(mac) = message.link_addr {
+                ndp_insert(message.target, mac);
+            }
+        }
+    }
+}
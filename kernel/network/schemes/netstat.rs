@@ -0,0 +1,108 @@
+use alloc::boxed::Box;
+
+use collections::string::String;
+
+use core::cmp;
+
+use fs::{KScheme, Resource};
+
+use network::PROTOCOL_STATS;
+use network::capture::capture_status;
+use network::pool::frame_pool_status;
+use network::scheme::NETWORK_INTERFACES;
+
+use system::error::{Error, Result, ENOENT};
+
+/// Format the counters of every interface, followed by the protocol counters and the frame pool
+fn netstat() -> String {
+    let mut string = String::new();
+
+    if let Some(ref interfaces) = unsafe { NETWORK_INTERFACES.as_ref() } {
+        for (i, &interface) in interfaces.iter().enumerate() {
+            let device = unsafe { &mut (*interface).device };
+            string.push_str(&format!("interface {}: {}\n", i, device.name()));
+            for line in device.stats().to_string().lines() {
+                string.push_str(&format!("    {}\n", line));
+            }
+        }
+    }
+
+    string.push_str("protocols:\n");
+    for line in unsafe { PROTOCOL_STATS }.to_string().lines() {
+        string.push_str(&format!("    {}\n", line));
+    }
+
+    string.push_str(&frame_pool_status());
+    string.push_str(&capture_status());
+
+    string
+}
+
+/// The combined counters, any write resets all of them
+pub struct NetstatResource {
+    seek: usize,
+}
+
+impl Resource for NetstatResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box NetstatResource {
+            seek: self.seek,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = b"netstat:";
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let string = netstat();
+        let data = string.as_bytes();
+
+        let mut i = 0;
+        while i < buf.len() && self.seek < data.len() {
+            buf[i] = data[self.seek];
+            i += 1;
+            self.seek += 1;
+        }
+
+        Ok(i)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        unsafe {
+            if let Some(ref interfaces) = NETWORK_INTERFACES.as_ref() {
+                for &interface in interfaces.iter() {
+                    (*interface).device.stats().reset();
+                }
+            }
+            PROTOCOL_STATS.reset();
+        }
+        self.seek = 0;
+
+        Ok(buf.len())
+    }
+}
+
+/// The netstat scheme, a listing of the counters of all interfaces and protocols
+pub struct NetstatScheme;
+
+impl KScheme for NetstatScheme {
+    fn scheme(&self) -> &str {
+        "netstat"
+    }
+
+    fn open(&mut self, url: &str, _: usize) -> Result<Box<Resource>> {
+        match url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/') {
+            "" => Ok(box NetstatResource {
+                seek: 0,
+            }),
+            _ => Err(Error::new(ENOENT))
+        }
+    }
+}
@@ -1,19 +1,55 @@
 use alloc::arc::Arc;
 use alloc::boxed::Box;
 
+use arch::context::Context;
+use arch::timekeeping;
+
 use collections::Vec;
+use collections::vec_deque::VecDeque;
 
+use common::event::{IO_READ, IO_WRITE};
 use common::random::rand;
+use common::time::{self, Duration};
 
 use core::{cmp, mem, slice, str};
 use core::cell::UnsafeCell;
 
 use fs::{KScheme, Resource};
 
-use network::common::{n16, n32, Checksum, Ipv4Addr, IP_ADDR, FromBytes, ToBytes};
+use network::{mtu, offload, OFFLOAD_RX_CHECKSUM, OFFLOAD_TX_CHECKSUM, PROTOCOL_STATS};
+use network::packet::{TcpBuilder, TcpSegment};
+use network::common::{n16, n32, Checksum, Ipv4Addr, local_addr, FromBytes, ToBytes};
+
+use super::dns::dns_resolve;
+
+
+use system::error::{Error, Result, EAGAIN, ECONNREFUSED, ECONNRESET, ENOENT, EPIPE, ETIMEDOUT};
+use system::syscall::{O_NONBLOCK, O_RDWR};
 
-use system::error::{Error, Result, ENOENT, EPIPE};
-use system::syscall::O_RDWR;
+/// Largest payload sent in one segment, what is left of the MTU after the IPv4 and TCP headers
+pub fn tcp_mss() -> usize {
+    mtu() - 20 - mem::size_of::<TcpHeader>()
+}
+/// The receive window advertised, a fixed send window is used as well
+pub const TCP_WINDOW: usize = 65535;
+/// Initial retransmission timeout in milliseconds
+const TCP_RTO_INITIAL: i64 = 1000;
+/// Smallest retransmission timeout in milliseconds
+const TCP_RTO_MIN: i64 = 200;
+/// Largest retransmission timeout in milliseconds
+const TCP_RTO_MAX: i64 = 60000;
+/// Retransmissions of a segment before the connection is dropped
+const TCP_RETRIES: usize = 8;
+/// Milliseconds a closing connection waits for the peer to finish
+const TCP_CLOSE_TIMEOUT: i64 = 10000;
+/// Milliseconds to sleep when no segment is ready
+const TCP_POLL: i32 = 10;
+/// Segments held while waiting for a gap to be filled
+const TCP_REORDER_MAX: usize = 64;
+/// Connections accepted by listeners and not opened yet, past this the oldest is reset
+const TCP_BACKLOG: usize = 16;
+/// Milliseconds an accepted connection waits to be opened before it is reset
+const TCP_ACCEPT_TIMEOUT: i64 = 30000;
 
 #[derive(Copy, Clone)]
 #[repr(packed)]
@@ -35,12 +71,10 @@ pub struct Tcp {
 }
 
 impl Tcp {
-    fn checksum(&mut self, src_addr: &Ipv4Addr, dst_addr: &Ipv4Addr) {
-        self.header.checksum.data = 0;
-
+    fn sum(&self, src_addr: &Ipv4Addr, dst_addr: &Ipv4Addr) -> usize {
         let proto = n16::new(0x06);
         let segment_len = n16::new((mem::size_of::<TcpHeader>() + self.options.len() + self.data.len()) as u16);
-        self.header.checksum.data = Checksum::compile(unsafe {
+        unsafe {
             Checksum::sum(src_addr.bytes.as_ptr() as usize, src_addr.bytes.len()) +
             Checksum::sum(dst_addr.bytes.as_ptr() as usize, dst_addr.bytes.len()) +
             Checksum::sum((&proto as *const n16) as usize, mem::size_of::<n16>()) +
@@ -48,7 +82,12 @@ impl Tcp {
             Checksum::sum((&self.header as *const TcpHeader) as usize, mem::size_of::<TcpHeader>()) +
             Checksum::sum(self.options.as_ptr() as usize, self.options.len()) +
             Checksum::sum(self.data.as_ptr() as usize, self.data.len())
-        });
+        }
+    }
+
+    /// Verify the checksum of a received segment, the NIC may have done so already
+    fn valid(&self, src_addr: &Ipv4Addr, dst_addr: &Ipv4Addr) -> bool {
+        offload(OFFLOAD_RX_CHECKSUM) || Checksum::compile(self.sum(src_addr, dst_addr)) == 0
     }
 }
 
@@ -60,19 +99,11 @@ pub const TCP_ACK: u16 = 1 << 4;
 
 impl FromBytes for Tcp {
     fn from_bytes(bytes: &[u8]) -> Option<Self> {
-        if bytes.len() >= mem::size_of::<TcpHeader>() {
-            unsafe {
-                let header = *(bytes.as_ptr() as *const TcpHeader);
-                let header_len = ((header.flags.get() & 0xF000) >> 10) as usize;
-
-                return Some(Tcp {
-                    header: header,
-                    options: bytes[mem::size_of::<TcpHeader>()..header_len].to_vec(),
-                    data: bytes[header_len..bytes.len()].to_vec(),
-                });
-            }
-        }
-        None
+        TcpSegment::new(bytes).map(|segment| Tcp {
+            header: unsafe { *(bytes.as_ptr() as *const TcpHeader) },
+            options: segment.options().to_vec(),
+            data: segment.payload().to_vec(),
+        })
     }
 }
 
@@ -89,17 +120,113 @@ impl ToBytes for Tcp {
     }
 }
 
+/// Compare sequence numbers, which wrap around
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+fn millis(duration: Duration) -> i64 {
+    duration.secs * 1000 + (duration.nanos / time::NANOS_PER_MILLI) as i64
+}
+
+/// TCP connection states
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TcpState {
+    Closed,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+/// A sent segment waiting to be acknowledged
+struct TcpSent {
+    sequence: u32,
+    flags: u16,
+    data: Vec<u8>,
+    /// When the segment was last sent
+    sent: Duration,
+    retries: usize,
+}
+
+impl TcpSent {
+    /// The sequence space used, SYN and FIN count as one each
+    fn len(&self) -> u32 {
+        let mut len = self.data.len() as u32;
+        if self.flags & TCP_SYN == TCP_SYN {
+            len += 1;
+        }
+        if self.flags & TCP_FIN == TCP_FIN {
+            len += 1;
+        }
+        len
+    }
+}
+
 pub struct TcpStream {
     ip: Box<Resource>,
     peer_addr: Ipv4Addr,
     peer_port: u16,
     host_port: u16,
-    sequence: u32,
-    acknowledge: u32,
-    finished: bool
+    state: TcpState,
+    /// Oldest unacknowledged sequence number
+    snd_una: u32,
+    /// Next sequence number to send
+    snd_nxt: u32,
+    /// Window advertised by the peer
+    snd_wnd: u32,
+    /// Next sequence number expected from the peer
+    rcv_nxt: u32,
+    /// Segments sent but not yet acknowledged, oldest first
+    unacked: VecDeque<TcpSent>,
+    /// Received data that has not been read
+    inbound: VecDeque<u8>,
+    /// Segments received ahead of `rcv_nxt`
+    reorder: Vec<(u32, u16, Vec<u8>)>,
+    /// Smoothed round trip time and its variation in milliseconds, zero until measured
+    srtt: i64,
+    rttvar: i64,
+    /// Retransmission timeout in milliseconds
+    rto: i64,
+    /// The peer has closed its side
+    fin_received: bool,
+    /// Set when the connection was reset or timed out
+    error: Option<isize>,
 }
 
 impl TcpStream {
+    pub fn new(ip: Box<Resource>, peer_addr: Ipv4Addr, peer_port: u16, host_port: u16) -> TcpStream {
+        let iss = rand() as u32;
+        TcpStream {
+            ip: ip,
+            peer_addr: peer_addr,
+            peer_port: peer_port,
+            host_port: host_port,
+            state: TcpState::Closed,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_wnd: TCP_WINDOW as u32,
+            rcv_nxt: 0,
+            unacked: VecDeque::new(),
+            inbound: VecDeque::new(),
+            reorder: Vec::new(),
+            srtt: 0,
+            rttvar: 0,
+            rto: TCP_RTO_INITIAL,
+            fin_received: false,
+            error: None,
+        }
+    }
+
     fn path(&self, buf: &mut [u8]) -> Result<usize> {
         let path_string = format!("tcp:{}:{}/{}", self.peer_addr.to_string(), self.peer_port, self.host_port);
         let path = path_string.as_bytes();
@@ -111,268 +238,452 @@ impl TcpStream {
         Ok(cmp::min(buf.len(), path.len()))
     }
 
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        if self.finished {
-            return Ok(0);
-        }
+    /// Free space in the receive buffer
+    fn window(&self) -> usize {
+        TCP_WINDOW - cmp::min(self.inbound.len(), TCP_WINDOW)
+    }
 
-        loop {
-            let mut bytes = [0; 65536];
-            let count = try!(self.ip.read(&mut bytes));
+    /// Data sent and not yet acknowledged
+    fn flight(&self) -> usize {
+        self.snd_nxt.wrapping_sub(self.snd_una) as usize
+    }
 
-            if let Some(segment) = Tcp::from_bytes(&bytes[..count]) {
-                if segment.header.dst.get() == self.host_port && segment.header.src.get() == self.peer_port {
-                    //syslog_info!("TCP: {}=={} {:X}: {}", segment.header.sequence.get(), self.acknowledge, segment.header.flags.get(), segment.data.len());
+    /// Send a segment without queueing it for retransmission
+    fn send_segment(&mut self, sequence: u32, flags: u16, data: &[u8]) -> Result<()> {
+        let segment = TcpBuilder {
+            src: local_addr(self.peer_addr),
+            dst: self.peer_addr,
+            src_port: self.host_port,
+            dst_port: self.peer_port,
+            sequence: sequence,
+            ack_num: if flags & TCP_ACK == TCP_ACK { self.rcv_nxt } else { 0 },
+            flags: flags,
+            window_size: self.window() as u16,
+            offload: offload(OFFLOAD_TX_CHECKSUM),
+        }.build(&[], data);
+
+        unsafe { PROTOCOL_STATS.tcp_segments_sent += 1; }
+        self.ip.write(&segment).and(Ok(()))
+    }
 
-                    if self.acknowledge == segment.header.sequence.get() {
-                        if segment.header.flags.get() & TCP_FIN == TCP_FIN {
-                            self.finished = true;
-                        }
+    fn send_ack(&mut self) {
+        let sequence = self.snd_nxt;
+        let _ = self.send_segment(sequence, TCP_ACK, &[]);
+    }
 
-                        if segment.header.flags.get() & (TCP_SYN | TCP_ACK) == TCP_ACK {
-                            let flags = if self.finished {
-                                TCP_ACK | TCP_FIN
-                            } else {
-                                TCP_ACK
-                            };
-
-                            // Send ACK
-                            self.acknowledge += segment.data.len() as u32;
-                            let mut tcp = Tcp {
-                                header: TcpHeader {
-                                    src: n16::new(self.host_port),
-                                    dst: n16::new(self.peer_port),
-                                    sequence: n32::new(self.sequence),
-                                    ack_num: n32::new(self.acknowledge),
-                                    flags: n16::new(((mem::size_of::<TcpHeader>() << 10) & 0xF000) as u16 | flags),
-                                    window_size: n16::new(65535),
-                                    checksum: Checksum {
-                                        data: 0
-                                    },
-                                    urgent_pointer: n16::new(0)
-                                },
-                                options: Vec::new(),
-                                data: Vec::new()
-                            };
-
-                            tcp.checksum(& unsafe { IP_ADDR }, &self.peer_addr);
-
-                            let _ = self.ip.write(&tcp.to_bytes());
-
-                            //syslog_info!("ACK: {} {} {:X}", tcp.header.sequence.get(), tcp.header.ack_num.get(), tcp.header.flags.get());
-
-                            // TODO: Support broken packets (one packet in two buffers)
-                            let mut i = 0;
-                            while i < buf.len() && i < segment.data.len() {
-                                buf[i] = segment.data[i];
-                                i += 1;
-                            }
-                            return Ok(i);
-                        }
-                    }
-                } else {
-                    syslog_info!("TCP: MISMATCH: {}=={}", segment.header.sequence.get(), self.acknowledge);
+    /// Send a segment that uses sequence space and keep it until it is acknowledged
+    fn send_queued(&mut self, flags: u16, data: Vec<u8>) -> Result<()> {
+        let sequence = self.snd_nxt;
+        try!(self.send_segment(sequence, flags, &data));
+
+        let segment = TcpSent {
+            sequence: sequence,
+            flags: flags,
+            data: data,
+            sent: Duration::monotonic(),
+            retries: 0,
+        };
+        self.snd_nxt = self.snd_nxt.wrapping_add(segment.len());
+        self.unacked.push_back(segment);
+
+        Ok(())
+    }
+
+    /// Drop the connection, reporting `errno` to later calls
+    fn reset(&mut self, errno: isize) {
+        self.state = TcpState::Closed;
+        self.error = Some(errno);
+        self.unacked.clear();
+    }
+
+    /// Update the retransmission timeout from a round trip time measurement
+    fn sample(&mut self, rtt: i64) {
+        if self.srtt == 0 {
+            self.srtt = cmp::max(rtt, 1);
+            self.rttvar = rtt / 2;
+        } else {
+            self.rttvar = (3 * self.rttvar + (self.srtt - rtt).abs()) / 4;
+            self.srtt = (7 * self.srtt + rtt) / 8;
+        }
+        self.rto = cmp::min(cmp::max(self.srtt + 4 * self.rttvar, TCP_RTO_MIN), TCP_RTO_MAX);
+    }
+
+    /// Remove acknowledged segments from the retransmission queue
+    fn acknowledge(&mut self, ack_num: u32) {
+        self.snd_una = ack_num;
+
+        let now = Duration::monotonic();
+        loop {
+            let acked = match self.unacked.front() {
+                Some(segment) => seq_le(segment.sequence.wrapping_add(segment.len()), ack_num),
+                None => false,
+            };
+            if ! acked {
+                break;
+            }
+
+            if let Some(segment) = self.unacked.pop_front() {
+                // Only segments sent once give a meaningful round trip time
+                if segment.retries == 0 {
+                    self.sample(millis(now - segment.sent));
                 }
             }
         }
     }
 
-    fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        let tcp_data = Vec::from(buf);
-
-        let mut tcp = Tcp {
-            header: TcpHeader {
-                src: n16::new(self.host_port),
-                dst: n16::new(self.peer_port),
-                sequence: n32::new(self.sequence),
-                ack_num: n32::new(self.acknowledge),
-                flags: n16::new((((mem::size_of::<TcpHeader>()) << 10) & 0xF000) as u16 | TCP_PSH |
-                                TCP_ACK),
-                window_size: n16::new(65535),
-                checksum: Checksum { data: 0 },
-                urgent_pointer: n16::new(0),
+    /// Resend the oldest segment if its timeout has passed
+    fn retransmit(&mut self) {
+        let now = Duration::monotonic();
+        let rto = self.rto;
+
+        let (sequence, flags, data) = match self.unacked.front_mut() {
+            Some(segment) => {
+                if millis(now - segment.sent) < rto {
+                    return;
+                }
+                segment.retries += 1;
+                segment.sent = now;
+                (segment.sequence, segment.flags, segment.data.clone())
             },
-            options: Vec::new(),
-            data: tcp_data,
+            None => return,
         };
 
-        tcp.checksum(& unsafe { IP_ADDR }, &self.peer_addr);
-
-        match self.ip.write(&tcp.to_bytes()) {
-            Ok(size) => {
-                loop {
-                    // Wait for ACK
-                    let mut bytes = [0; 65536];
-                    match self.ip.read(&mut bytes) {
-                        Ok(count) => {
-                            if let Some(segment) = Tcp::from_bytes(&bytes[..count]) {
-                                if segment.header.dst.get() == self.host_port &&
-                                   segment.header.src.get() == self.peer_port {
-                                    return if (segment.header.flags.get() & (TCP_SYN | TCP_ACK)) == TCP_ACK {
-                                        self.sequence = segment.header.ack_num.get();
-                                        self.acknowledge = segment.header.sequence.get();
-                                        Ok(size)
-                                    } else {
-                                        Err(Error::new(EPIPE))
-                                    };
-                                }
-                            }
+        if self.unacked.front().map_or(0, |segment| segment.retries) > TCP_RETRIES {
+            debugln!("TCP: {}:{} timed out", self.peer_addr.to_string(), self.peer_port);
+            self.reset(ETIMEDOUT);
+            return;
+        }
+
+        self.rto = cmp::min(self.rto * 2, TCP_RTO_MAX);
+        unsafe { PROTOCOL_STATS.tcp_retransmits += 1; }
+        let _ = self.send_segment(sequence, flags, &data);
+    }
+
+    /// Add a segment at or before `rcv_nxt` to the received data
+    fn take(&mut self, sequence: u32, flags: u16, data: &[u8]) {
+        let offset = self.rcv_nxt.wrapping_sub(sequence) as usize;
+        if offset < data.len() {
+            let count = cmp::min(data.len() - offset, self.window());
+            for b in data[offset .. offset + count].iter() {
+                self.inbound.push_back(*b);
+            }
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(count as u32);
+
+            // The rest, and any FIN, will be retransmitted once the window opens
+            if offset + count < data.len() {
+                return;
+            }
+        }
+
+        if flags & TCP_FIN == TCP_FIN && ! self.fin_received &&
+           sequence.wrapping_add(data.len() as u32) == self.rcv_nxt {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.fin_received = true;
+            self.state = match self.state {
+                TcpState::SynReceived | TcpState::Established => TcpState::CloseWait,
+                TcpState::FinWait1 => TcpState::Closing,
+                TcpState::FinWait2 => TcpState::TimeWait,
+                state => state,
+            };
+        }
+    }
+
+    /// Accept data in order, holding segments that arrive early
+    fn reassemble(&mut self, sequence: u32, flags: u16, data: Vec<u8>) {
+        if seq_lt(self.rcv_nxt, sequence) {
+            if seq_lt(sequence, self.rcv_nxt.wrapping_add(TCP_WINDOW as u32)) &&
+               self.reorder.len() < TCP_REORDER_MAX &&
+               ! self.reorder.iter().any(|held| held.0 == sequence) {
+                self.reorder.push((sequence, flags, data));
+            }
+            return;
+        }
+
+        self.take(sequence, flags, &data);
+
+        loop {
+            let rcv_nxt = self.rcv_nxt;
+            let i = match self.reorder.iter().position(|held| seq_le(held.0, rcv_nxt)) {
+                Some(i) => i,
+                None => break,
+            };
+
+            let (sequence, flags, data) = self.reorder.remove(i);
+            self.take(sequence, flags, &data);
+        }
+    }
+
+    /// Handle a segment received for this connection
+    fn receive(&mut self, segment: Tcp) {
+        let flags = segment.header.flags.get();
+        let sequence = segment.header.sequence.get();
+        let ack_num = segment.header.ack_num.get();
+
+        if flags & TCP_RST == TCP_RST {
+            match self.state {
+                TcpState::SynSent => if flags & TCP_ACK == TCP_ACK && ack_num == self.snd_nxt {
+                    self.reset(ECONNREFUSED);
+                },
+                TcpState::Closed => (),
+                _ => if seq_le(self.rcv_nxt, sequence) &&
+                        seq_lt(sequence, self.rcv_nxt.wrapping_add(TCP_WINDOW as u32)) {
+                    self.reset(ECONNRESET);
+                },
+            }
+            return;
+        }
+
+        match self.state {
+            TcpState::Closed => return,
+            TcpState::SynSent => {
+                if flags & TCP_SYN == TCP_SYN {
+                    self.rcv_nxt = sequence.wrapping_add(1);
+                    self.snd_wnd = segment.header.window_size.get() as u32;
+
+                    if flags & TCP_ACK == TCP_ACK {
+                        if ack_num == self.snd_nxt {
+                            self.acknowledge(ack_num);
+                            self.state = TcpState::Established;
+                            self.send_ack();
                         }
-                        Err(err) => return Err(err),
+                    } else {
+                        // Simultaneous open, our SYN is resent with an ACK of theirs
+                        self.state = TcpState::SynReceived;
+                        if let Some(syn) = self.unacked.front_mut() {
+                            syn.flags |= TCP_ACK;
+                        }
+                        let iss = self.snd_una;
+                        let _ = self.send_segment(iss, TCP_SYN | TCP_ACK, &[]);
                     }
                 }
+                return;
+            },
+            _ => (),
+        }
+
+        if flags & TCP_SYN == TCP_SYN && ! (self.state == TcpState::SynReceived && flags & TCP_ACK == TCP_ACK) {
+            // A repeated SYN means our answer was lost, the retransmission timer resends a SYN-ACK
+            if self.state != TcpState::SynReceived {
+                self.send_ack();
+            }
+            return;
+        }
+
+        if flags & TCP_ACK != TCP_ACK {
+            return;
+        }
+
+        let acceptable = seq_lt(self.snd_una, ack_num) && seq_le(ack_num, self.snd_nxt);
+        if self.state == TcpState::SynReceived {
+            if ! acceptable {
+                return;
+            }
+            self.state = TcpState::Established;
+        }
+
+        if acceptable {
+            self.acknowledge(ack_num);
+        }
+        self.snd_wnd = segment.header.window_size.get() as u32;
+
+        // Our FIN is the last sequence number, so everything being acknowledged includes it
+        if self.unacked.is_empty() {
+            self.state = match self.state {
+                TcpState::FinWait1 => TcpState::FinWait2,
+                TcpState::Closing => TcpState::TimeWait,
+                TcpState::LastAck => TcpState::Closed,
+                state => state,
+            };
+        }
+
+        if ! segment.data.is_empty() || flags & TCP_FIN == TCP_FIN {
+            let data_sequence = if flags & TCP_SYN == TCP_SYN {
+                sequence.wrapping_add(1)
+            } else {
+                sequence
+            };
+
+            match self.state {
+                TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2 => {
+                    self.reassemble(data_sequence, flags, segment.data);
+                },
+                _ => (),
             }
-            Err(err) => Err(err),
+
+            // Acknowledge data, including duplicates, so the peer stops retransmitting
+            self.send_ack();
         }
     }
 
-    fn sync(&mut self) -> Result<()> {
-        self.ip.sync()
-    }
-
-    /// Etablish client
-    pub fn client_establish(&mut self) -> bool {
-        // Send SYN
-        let mut tcp = Tcp {
-            header: TcpHeader {
-                src: n16::new(self.host_port),
-                dst: n16::new(self.peer_port),
-                sequence: n32::new(self.sequence),
-                ack_num: n32::new(self.acknowledge),
-                flags: n16::new(((mem::size_of::<TcpHeader>() << 10) & 0xF000) as u16 | TCP_SYN),
-                window_size: n16::new(65535),
-                checksum: Checksum { data: 0 },
-                urgent_pointer: n16::new(0),
-            },
-            options: Vec::new(),
-            data: Vec::new(),
-        };
+    /// Handle all received segments and retransmit, returns true if a segment was received
+    fn poll(&mut self) -> Result<bool> {
+        let mut received = false;
 
-        tcp.checksum(& unsafe { IP_ADDR }, &self.peer_addr);
-
-        match self.ip.write(&tcp.to_bytes()) {
-            Ok(_) => {
-                loop {
-                    // Wait for SYN-ACK
-                    let mut bytes = [0; 65536];
-                    match self.ip.read(&mut bytes) {
-                        Ok(count) => {
-                            if let Some(segment) = Tcp::from_bytes(&bytes[..count]) {
-                                if segment.header.dst.get() == self.host_port &&
-                                   segment.header.src.get() == self.peer_port {
-                                    return if segment.header.flags.get() & (TCP_SYN | TCP_ACK) == TCP_SYN | TCP_ACK {
-                                        self.sequence = segment.header.ack_num.get();
-                                        self.acknowledge = segment.header.sequence.get();
-
-                                        self.acknowledge += 1;
-                                        tcp = Tcp {
-                                            header: TcpHeader {
-                                                src: n16::new(self.host_port),
-                                                dst: n16::new(self.peer_port),
-                                                sequence: n32::new(self.sequence),
-                                                ack_num: n32::new(self.acknowledge),
-                                                flags: n16::new(((mem::size_of::<TcpHeader>() << 10) & 0xF000) as u16 | TCP_ACK),
-                                                window_size: n16::new(65535),
-                                                checksum: Checksum {
-                                                    data: 0
-                                                },
-                                                urgent_pointer: n16::new(0)
-                                            },
-                                            options: Vec::new(),
-                                            data: Vec::new()
-                                        };
-
-                                        tcp.checksum(& unsafe { IP_ADDR }, &self.peer_addr);
-
-                                        let _ = self.ip.write(&tcp.to_bytes());
-
-                                        true
-                                    } else {
-                                        false
-                                    };
-                                }
-                            }
+        let mut bytes = [0; 65536];
+        loop {
+            match self.ip.read(&mut bytes) {
+                Ok(count) => if let Some(segment) = Tcp::from_bytes(&bytes[..count]) {
+                    if segment.header.dst.get() == self.host_port &&
+                       segment.header.src.get() == self.peer_port {
+                        if segment.valid(&self.peer_addr, &local_addr(self.peer_addr)) {
+                            self.receive(segment);
+                            received = true;
+                        } else {
+                            unsafe { PROTOCOL_STATS.tcp_checksum_errors += 1; }
                         }
-                        Err(_) => return false,
                     }
+                },
+                Err(ref err) if err.errno == EAGAIN => break,
+                Err(err) => {
+                    self.reset(err.errno);
+                    break;
                 }
             }
-            Err(_) => false,
-        }
-    }
-
-    /// Try to establish a server connection
-    pub fn server_establish(&mut self, _: Tcp) -> bool {
-        // Send SYN-ACK
-        self.acknowledge += 1;
-        let mut tcp = Tcp {
-            header: TcpHeader {
-                src: n16::new(self.host_port),
-                dst: n16::new(self.peer_port),
-                sequence: n32::new(self.sequence),
-                ack_num: n32::new(self.acknowledge),
-                flags: n16::new(((mem::size_of::<TcpHeader>() << 10) & 0xF000) as u16 | TCP_SYN |
-                                TCP_ACK),
-                window_size: n16::new(65535),
-                checksum: Checksum { data: 0 },
-                urgent_pointer: n16::new(0),
-            },
-            options: Vec::new(),
-            data: Vec::new(),
-        };
+        }
 
-        tcp.checksum(& unsafe { IP_ADDR }, &self.peer_addr);
-
-        match self.ip.write(&tcp.to_bytes()) {
-            Ok(_) => {
-                loop {
-                    // Wait for ACK
-                    let mut bytes = [0; 65536];
-                    match self.ip.read(&mut bytes) {
-                        Ok(count ) => {
-                            if let Some(segment) = Tcp::from_bytes(&bytes[..count]) {
-                                if segment.header.dst.get() == self.host_port &&
-                                   segment.header.src.get() == self.peer_port {
-                                    return if segment.header.flags.get() & (TCP_SYN | TCP_ACK) == TCP_ACK {
-                                        self.sequence = segment.header.ack_num.get();
-                                        self.acknowledge = segment.header.sequence.get();
-                                        true
-                                    } else {
-                                        false
-                                    };
-                                }
-                            }
-                        }
-                        Err(_) => return false,
-                    }
+        if self.state != TcpState::Closed {
+            self.retransmit();
+        }
+
+        match self.error {
+            Some(errno) => Err(Error::new(errno)),
+            None => Ok(received),
+        }
+    }
+
+    /// Poll until `done` is true, the connection fails, or `timeout` milliseconds pass
+    fn wait<F: Fn(&TcpStream) -> bool>(&mut self, timeout: Option<i64>, done: F) -> Result<()> {
+        let start = Duration::monotonic();
+        loop {
+            let received = try!(self.poll());
+            if done(self) {
+                return Ok(());
+            }
+
+            if let Some(timeout) = timeout {
+                if millis(Duration::monotonic() - start) >= timeout {
+                    return Err(Error::new(ETIMEDOUT));
                 }
             }
-            Err(_) => false,
+
+            if ! received {
+                timekeeping::sleep(TCP_POLL as u64, "TCP poll");
+            }
         }
     }
-}
 
-impl Drop for TcpStream {
-    fn drop(&mut self) {
-        // Send FIN-ACK
-        let mut tcp = Tcp {
-            header: TcpHeader {
-                src: n16::new(self.host_port),
-                dst: n16::new(self.peer_port),
-                sequence: n32::new(self.sequence),
-                ack_num: n32::new(self.acknowledge),
-                flags: n16::new((((mem::size_of::<TcpHeader>()) << 10) & 0xF000) as u16 | TCP_FIN | TCP_ACK),
-                window_size: n16::new(65535),
-                checksum: Checksum { data: 0 },
-                urgent_pointer: n16::new(0),
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        try!(self.wait(None, |stream| ! stream.inbound.is_empty() || stream.fin_received || stream.state == TcpState::Closed));
+
+        let was_closed = self.window() < tcp_mss();
+
+        let mut i = 0;
+        while i < buf.len() {
+            match self.inbound.pop_front() {
+                Some(b) => buf[i] = b,
+                None => break,
+            }
+            i += 1;
+        }
+
+        // Tell the peer it can send again
+        if was_closed && self.window() >= tcp_mss() && self.state != TcpState::Closed {
+            self.send_ack();
+        }
+
+        Ok(i)
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        for chunk in buf.chunks(tcp_mss()) {
+            // With nothing in flight a segment is sent even into a closed window, probing for it to open
+            try!(self.wait(None, |stream| {
+                (stream.state != TcpState::Established && stream.state != TcpState::CloseWait) ||
+                stream.flight() == 0 ||
+                stream.flight() + chunk.len() <= cmp::min(stream.snd_wnd as usize, TCP_WINDOW)
+            }));
+
+            if self.state != TcpState::Established && self.state != TcpState::CloseWait {
+                return Err(Error::new(EPIPE));
+            }
+
+            try!(self.send_queued(TCP_ACK | TCP_PSH, Vec::from(chunk)));
+        }
+
+        Ok(buf.len())
+    }
+
+    /// Handle what was received, then whether a read and a full segment written would wait
+    pub fn ready(&mut self) -> i64 {
+        if self.poll().is_err() {
+            return IO_READ | IO_WRITE;
+        }
+
+        let mut flags = 0;
+        if ! self.inbound.is_empty() || self.fin_received || self.state == TcpState::Closed {
+            flags |= IO_READ;
+        }
+        if (self.state != TcpState::Established && self.state != TcpState::CloseWait) ||
+           self.flight() == 0 ||
+           self.flight() + tcp_mss() <= cmp::min(self.snd_wnd as usize, TCP_WINDOW) {
+            flags |= IO_WRITE;
+        }
+        flags
+    }
+
+    /// Wait for all sent data to be acknowledged
+    pub fn sync(&mut self) -> Result<()> {
+        self.wait(None, |stream| stream.unacked.is_empty())
+    }
+
+    /// Active open
+    pub fn connect(&mut self) -> Result<()> {
+        self.state = TcpState::SynSent;
+        try!(self.send_queued(TCP_SYN, Vec::new()));
+        self.wait(None, |stream| stream.state != TcpState::SynSent && stream.state != TcpState::SynReceived)
+    }
+
+    /// Passive open, answering a received SYN
+    pub fn accept(&mut self, syn: &Tcp) -> Result<()> {
+        self.rcv_nxt = syn.header.sequence.get().wrapping_add(1);
+        self.snd_wnd = syn.header.window_size.get() as u32;
+        self.state = TcpState::SynReceived;
+        try!(self.send_queued(TCP_SYN | TCP_ACK, Vec::new()));
+        self.wait(None, |stream| stream.state != TcpState::SynReceived)
+    }
+
+    pub fn state(&self) -> TcpState {
+        self.state
+    }
+
+    /// Send our FIN and wait a while for the peer to finish closing
+    pub fn close(&mut self) {
+        let next = match self.state {
+            TcpState::Established => TcpState::FinWait1,
+            TcpState::CloseWait => TcpState::LastAck,
+            TcpState::SynSent | TcpState::SynReceived => {
+                let sequence = self.snd_nxt;
+                let _ = self.send_segment(sequence, TCP_RST, &[]);
+                self.state = TcpState::Closed;
+                return;
             },
-            options: Vec::new(),
-            data: Vec::new(),
+            _ => return,
         };
 
-        tcp.checksum(& unsafe { IP_ADDR }, &self.peer_addr);
+        if self.send_queued(TCP_FIN | TCP_ACK, Vec::new()).is_ok() {
+            self.state = next;
+            // TIME-WAIT is not kept around, the connection is gone once the resource is dropped
+            let _ = self.wait(Some(TCP_CLOSE_TIMEOUT), |stream| stream.state == TcpState::TimeWait || stream.state == TcpState::Closed);
+        }
+    }
 
-        let _ = self.ip.write(&tcp.to_bytes());
+    /// Drop the connection at once, telling the peer with a reset
+    pub fn abort(&mut self) {
+        if self.state != TcpState::Closed {
+            let sequence = self.snd_nxt;
+            let _ = self.send_segment(sequence, TCP_RST | TCP_ACK, &[]);
+            self.reset(ECONNRESET);
+        }
     }
 }
 
@@ -403,10 +714,117 @@ impl Resource for TcpResource {
     fn sync(&mut self) -> Result<()> {
         unsafe { (*self.stream.get()).sync() }
     }
+
+    fn ready(&mut self) -> i64 {
+        unsafe { (*self.stream.get()).ready() }
+    }
+}
+
+impl Drop for TcpResource {
+    /// The last handle closes the connection, waiting for the peer in its own context rather than the caller's
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.stream) == 1 {
+            let stream = self.stream.clone();
+            Context::spawn("ktcp_close".into(), box move || {
+                unsafe { (*stream.get()).close() };
+            });
+        }
+    }
+}
+
+/// A TCP listener, each read accepts a connection and returns the path to open it with
+pub struct TcpListener {
+    scheme: *mut TcpScheme,
+    host_port: u16,
+}
+
+impl Resource for TcpListener {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box TcpListener {
+            scheme: self.scheme,
+            host_port: self.host_port,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path_string = format!("tcp:/:{}", self.host_port);
+        let path = path_string.as_bytes();
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut stream = try!(TcpScheme::accept(self.host_port));
+        let count = match stream.path(buf) {
+            Ok(count) => count,
+            Err(err) => {
+                stream.abort();
+                return Err(err);
+            }
+        };
+
+        let scheme = unsafe { &mut *self.scheme };
+        scheme.accepted.push((Duration::monotonic(), stream));
+        scheme.expire();
+        Ok(count)
+    }
 }
 
 /// A TCP scheme
-pub struct TcpScheme;
+pub struct TcpScheme {
+    /// Connections accepted by listeners, waiting to be opened by path, with when they were accepted
+    pub accepted: Vec<(Duration, TcpStream)>,
+}
+
+impl TcpScheme {
+    /// Reset the accepted connections not opened in time, and the oldest past the backlog
+    fn expire(&mut self) {
+        let now = Duration::monotonic();
+        let mut i = 0;
+        while i < self.accepted.len() {
+            if self.accepted.len() > TCP_BACKLOG || millis(now - self.accepted[i].0) >= TCP_ACCEPT_TIMEOUT {
+                self.accepted.remove(i).1.abort();
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Wait for a SYN to `host_port` and establish the connection
+    fn accept(host_port: u16) -> Result<TcpStream> {
+        while let Ok(mut ip) = ::env().open("ip:/6", O_RDWR) {
+            let mut bytes = [0; 65536];
+            match ip.read(&mut bytes) {
+                Ok(count) => {
+                    if let Some(segment) = Tcp::from_bytes(&bytes[..count]) {
+                        if segment.header.dst.get() == host_port && segment.header.flags.get() & (TCP_SYN | TCP_ACK | TCP_RST) == TCP_SYN {
+                            let mut path = [0; 256];
+                            if let Ok(path_count) = ip.path(&mut path) {
+                                let ip_reference = unsafe { str::from_utf8_unchecked(&path[.. path_count]) }.split(':').nth(1).unwrap_or("");
+                                let ip_remote = ip_reference.split('/').next().unwrap_or("");
+                                let peer_addr = Ipv4Addr::from_str(ip_remote.split(':').next().unwrap_or(""));
+
+                                // The listening link is not polled, so the connection gets its own
+                                let link = try!(::env().open(&format!("ip:{}/6", peer_addr.to_string()), O_RDWR | O_NONBLOCK));
+                                let mut stream = TcpStream::new(link, peer_addr, segment.header.src.get(), host_port);
+                                if stream.accept(&segment).is_ok() {
+                                    return Ok(stream);
+                                }
+                            }
+                        }
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Err(Error::new(ENOENT))
+    }
+}
 
 impl KScheme for TcpScheme {
     fn scheme(&self) -> &str {
@@ -415,74 +833,63 @@ impl KScheme for TcpScheme {
 
     fn open(&mut self, url: &str, _: usize) -> Result<Box<Resource>> {
         let mut parts = url.splitn(2, ":").nth(1).unwrap_or("").split('/');
-        let remote = parts.next().unwrap_or("");
-        let path = parts.next().unwrap_or("");
+        let mut remote = parts.next().unwrap_or("");
+        let mut path = parts.next().unwrap_or("");
+
+        // Both tcp:host:port and tcp:/host:port connect
+        if remote.is_empty() && path.contains(':') {
+            remote = path;
+            path = "";
+        }
 
         let mut remote_parts = remote.split(':');
         let host = remote_parts.next().unwrap_or("");
         let port = remote_parts.next().unwrap_or("");
 
         if ! host.is_empty() && ! port.is_empty() {
-            let peer_addr = Ipv4Addr::from_str(host);
+            let peer_addr = match try!(dns_resolve(host)).first() {
+                Some(addr) => *addr,
+                None => return Err(Error::new(ENOENT)),
+            };
             let peer_port = port.parse::<u16>().unwrap_or(0);
-            let host_port = (rand() % 32768 + 32768) as u16;
-
-            match ::env().open(&format!("ip:{}/6", peer_addr.to_string()), O_RDWR) {
-                Ok(ip) => {
-                    let mut stream = TcpStream {
-                        ip: ip,
-                        peer_addr: peer_addr,
-                        peer_port: peer_port,
-                        host_port: host_port,
-                        sequence: rand() as u32,
-                        acknowledge: 0,
-                        finished: false
-                    };
-
-                    if stream.client_establish() {
-                        return Ok(box TcpResource {
-                            stream: Arc::new(UnsafeCell::new(stream))
-                        });
-                    }
-                }
-                Err(err) => return Err(err),
+            let host_port = match path.parse::<u16>() {
+                Ok(host_port) if host_port > 0 => host_port,
+                _ => (rand() % 32768 + 32768) as u16,
+            };
+
+            // A connection accepted by a listener
+            self.expire();
+            let accepted = self.accepted.iter().map(|accepted| &accepted.1).position(|stream| {
+                stream.peer_addr.equals(peer_addr) && stream.peer_port == peer_port && stream.host_port == host_port
+            });
+            if let Some(i) = accepted {
+                return Ok(box TcpResource {
+                    stream: Arc::new(UnsafeCell::new(self.accepted.remove(i).1))
+                });
+            }
+
+            let ip = try!(::env().open(&format!("ip:{}/6", peer_addr.to_string()), O_RDWR | O_NONBLOCK));
+            let mut stream = TcpStream::new(ip, peer_addr, peer_port, host_port);
+            try!(stream.connect());
+            return Ok(box TcpResource {
+                stream: Arc::new(UnsafeCell::new(stream))
+            });
+        } else if host.is_empty() && ! port.is_empty() {
+            let host_port = port.parse::<u16>().unwrap_or(0);
+            if host_port > 0 {
+                return Ok(box TcpListener {
+                    scheme: self,
+                    host_port: host_port,
+                });
             }
         } else if ! path.is_empty() {
+            // tcp:/port accepts a single connection
             let host_port = path.parse::<u16>().unwrap_or(0);
-
-            while let Ok(mut ip) = ::env().open("ip:/6", O_RDWR) {
-                let mut bytes = [0; 65536];
-                match ip.read(&mut bytes) {
-                    Ok(count) => {
-                        if let Some(segment) = Tcp::from_bytes(&bytes[..count]) {
-                            if segment.header.dst.get() == host_port && segment.header.flags.get() & (TCP_SYN | TCP_ACK) == TCP_SYN {
-                                let mut path = [0; 256];
-                                if let Ok(path_count) = ip.path(&mut path) {
-                                    let ip_reference = unsafe { str::from_utf8_unchecked(&path[.. path_count]) }.split(':').nth(1).unwrap_or("");
-                                    let ip_remote = ip_reference.split('/').next().unwrap_or("");
-                                    let peer_addr = ip_remote.split(':').next().unwrap_or("");
-
-                                    let mut stream = TcpStream {
-                                        ip: ip,
-                                        peer_addr: Ipv4Addr::from_str(peer_addr),
-                                        peer_port: segment.header.src.get(),
-                                        host_port: host_port,
-                                        sequence: rand() as u32,
-                                        acknowledge: segment.header.sequence.get(),
-                                        finished: false
-                                    };
-
-                                    if stream.server_establish(segment) {
-                                        return Ok(box TcpResource {
-                                            stream: Arc::new(UnsafeCell::new(stream))
-                                        });
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    Err(err) => return Err(err),
-                }
+            if host_port > 0 {
+                let stream = try!(TcpScheme::accept(host_port));
+                return Ok(box TcpResource {
+                    stream: Arc::new(UnsafeCell::new(stream))
+                });
             }
         }
 
@@ -2,17 +2,22 @@ use alloc::boxed::Box;
 
 use collections::Vec;
 
+use common::event::{IO_READ, IO_WRITE};
 use common::random::rand;
 
 use core::{cmp, mem, ptr, slice, str};
 
 use fs::{KScheme, Resource};
 
-use network::common::{n16, Checksum, Ipv4Addr, IP_ADDR, FromBytes, ToBytes};
+use network::{offload, OFFLOAD_RX_CHECKSUM, OFFLOAD_TX_CHECKSUM, PROTOCOL_STATS};
+use network::packet::{Udp6Builder, UdpBuilder, UdpDatagram};
+use network::common::{n16, Checksum, IpAddr, Ipv4Addr, Ipv6Addr, BROADCAST_IP_ADDR, local_addr, local_addr6, FromBytes, ToBytes};
 
-use system::error::{Error, Result, ENOENT};
+use system::error::{Error, Result, EADDRINUSE, ENOENT};
 use system::syscall::O_RDWR;
 
+use super::dns::dns_resolve;
+
 #[derive(Copy, Clone)]
 #[repr(packed)]
 pub struct UdpHeader {
@@ -29,16 +34,10 @@ pub struct Udp {
 
 impl FromBytes for Udp {
     fn from_bytes(bytes: &[u8]) -> Option<Self> {
-        if bytes.len() >= mem::size_of::<UdpHeader>() {
-            unsafe {
-                Option::Some(Udp {
-                    header: ptr::read(bytes.as_ptr() as *const UdpHeader),
-                    data: bytes[mem::size_of::<UdpHeader>()..bytes.len()].to_vec(),
-                })
-            }
-        } else {
-            Option::None
-        }
+        UdpDatagram::new(bytes).map(|datagram| Udp {
+            header: unsafe { ptr::read(bytes.as_ptr() as *const UdpHeader) },
+            data: datagram.payload().to_vec(),
+        })
     }
 }
 
@@ -54,11 +53,51 @@ impl ToBytes for Udp {
     }
 }
 
+impl Udp {
+    /// Sum the IPv4 pseudo header, the UDP header and the data
+    fn sum(&self, src: Ipv4Addr, dst: Ipv4Addr) -> usize {
+        unsafe {
+            let proto = n16::new(0x11);
+            let datagram_len = n16::new((mem::size_of::<UdpHeader>() + self.data.len()) as u16);
+            Checksum::sum((&src as *const Ipv4Addr) as usize, mem::size_of::<Ipv4Addr>()) +
+            Checksum::sum((&dst as *const Ipv4Addr) as usize, mem::size_of::<Ipv4Addr>()) +
+            Checksum::sum((&proto as *const n16) as usize, mem::size_of::<n16>()) +
+            Checksum::sum((&datagram_len as *const n16) as usize, mem::size_of::<n16>()) +
+            Checksum::sum((&self.header as *const UdpHeader) as usize, mem::size_of::<UdpHeader>()) +
+            Checksum::sum(self.data.as_ptr() as usize, self.data.len())
+        }
+    }
+
+    /// Verify the checksum of a datagram sent to us or to the broadcast address, zero means the sender did not calculate one
+    ///
+    /// A broadcast `src` accepts any datagram, the real sender is not known to the resource
+    pub fn verify(&self, src: Ipv4Addr) -> bool {
+        offload(OFFLOAD_RX_CHECKSUM) || self.header.checksum.data == 0 || src.equals(BROADCAST_IP_ADDR) ||
+        Checksum::compile(self.sum(src, local_addr(src))) == 0 ||
+        Checksum::compile(self.sum(src, BROADCAST_IP_ADDR)) == 0
+    }
+
+    /// Verify the checksum of a datagram sent to us over IPv6, where a checksum is required
+    pub fn verify_ipv6(&self, src: Ipv6Addr) -> bool {
+        let bytes = self.to_bytes();
+        UdpDatagram::new(&bytes).map_or(false, |datagram| datagram.checksum_valid_ipv6(&src, &local_addr6(src)))
+    }
+
+    /// Verify the checksum against the pseudo header of either IP version
+    pub fn verify_from(&self, src: IpAddr) -> bool {
+        match src {
+            IpAddr::V4(addr) => self.verify(addr),
+            IpAddr::V6(addr) => self.verify_ipv6(addr),
+        }
+    }
+}
+
 /// UDP resource
 pub struct UdpResource {
+    scheme: *mut UdpScheme,
     ip: Box<Resource>,
     data: Vec<u8>,
-    peer_addr: Ipv4Addr,
+    peer_addr: IpAddr,
     peer_port: u16,
     host_port: u16,
 }
@@ -67,7 +106,10 @@ impl Resource for UdpResource {
     fn dup(&self) -> Result<Box<Resource>> {
         match self.ip.dup() {
             Ok(ip) => {
+                unsafe { (*self.scheme).ports.push(self.host_port) };
+
                 Ok(Box::new(UdpResource {
+                    scheme: self.scheme,
                     ip: ip,
                     data: self.data.clone(),
                     peer_addr: self.peer_addr,
@@ -112,6 +154,12 @@ impl Resource for UdpResource {
             if let Some(datagram) = Udp::from_bytes(&bytes[..count]) {
                 if datagram.header.dst.get() == self.host_port &&
                    datagram.header.src.get() == self.peer_port {
+                    if ! datagram.verify_from(self.peer_addr) {
+                        unsafe { PROTOCOL_STATS.udp_checksum_errors += 1; }
+                        continue;
+                    }
+                    unsafe { PROTOCOL_STATS.udp_delivered += 1; }
+
                     // TODO: Allow splitting
                     let mut i = 0;
                     while i < buf.len() && i < datagram.data.len() {
@@ -126,58 +174,111 @@ impl Resource for UdpResource {
     }
 
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        let mut udp = Udp {
-            header: UdpHeader {
-                src: n16::new(self.host_port),
-                dst: n16::new(self.peer_port),
-                len: n16::new((mem::size_of::<UdpHeader>() + buf.len()) as u16),
-                checksum: Checksum { data: 0 },
-            },
-            data: Vec::from(buf),
+        let datagram = match self.peer_addr {
+            IpAddr::V4(peer_addr) => UdpBuilder {
+                src: local_addr(peer_addr),
+                dst: peer_addr,
+                src_port: self.host_port,
+                dst_port: self.peer_port,
+                offload: offload(OFFLOAD_TX_CHECKSUM),
+            }.build(buf),
+            IpAddr::V6(peer_addr) => Udp6Builder {
+                src: local_addr6(peer_addr),
+                dst: peer_addr,
+                src_port: self.host_port,
+                dst_port: self.peer_port,
+            }.build(buf),
         };
 
-        unsafe {
-            let proto = n16::new(0x11);
-            let datagram_len = n16::new((mem::size_of::<UdpHeader>() + udp.data.len()) as u16);
-            udp.header.checksum.data =
-                Checksum::compile(Checksum::sum((&IP_ADDR as *const Ipv4Addr) as usize,
-                                                mem::size_of::<Ipv4Addr>()) +
-                                  Checksum::sum((&self.peer_addr as *const Ipv4Addr) as usize,
-                                                mem::size_of::<Ipv4Addr>()) +
-                                  Checksum::sum((&proto as *const n16) as usize,
-                                                mem::size_of::<n16>()) +
-                                  Checksum::sum((&datagram_len as *const n16) as usize,
-                                                mem::size_of::<n16>()) +
-                                  Checksum::sum((&udp.header as *const UdpHeader) as usize,
-                                                mem::size_of::<UdpHeader>()) +
-                                  Checksum::sum(udp.data.as_ptr() as usize, udp.data.len()));
-        }
-
-        self.ip.write(&udp.to_bytes()).and(Ok(buf.len()))
+        self.ip.write(&datagram).and(Ok(buf.len()))
     }
 
     fn sync(&mut self) -> Result<()> {
         self.ip.sync()
     }
+
+    /// Datagrams for other ports are dropped, one for this resource is kept for the next read
+    fn ready(&mut self) -> i64 {
+        while self.data.is_empty() && self.ip.ready() & IO_READ == IO_READ {
+            let mut bytes = [0; 65536];
+            match self.ip.read(&mut bytes) {
+                Ok(count) => if let Some(datagram) = Udp::from_bytes(&bytes[..count]) {
+                    if datagram.header.dst.get() == self.host_port &&
+                       datagram.header.src.get() == self.peer_port {
+                        if datagram.verify_from(self.peer_addr) {
+                            unsafe { PROTOCOL_STATS.udp_delivered += 1; }
+                            self.data = datagram.data;
+                        } else {
+                            unsafe { PROTOCOL_STATS.udp_checksum_errors += 1; }
+                        }
+                    }
+                },
+                Err(_) => return IO_READ | IO_WRITE,
+            }
+        }
+
+        if self.data.is_empty() { IO_WRITE } else { IO_READ | IO_WRITE }
+    }
+}
+
+impl Drop for UdpResource {
+    fn drop(&mut self) {
+        unsafe { (*self.scheme).release(self.host_port) };
+    }
 }
 
 /// UDP UdpScheme
-pub struct UdpScheme;
+pub struct UdpScheme {
+    /// Local ports of open resources, a port appears once for each resource using it
+    pub ports: Vec<u16>,
+}
+
+impl UdpScheme {
+    /// Claim a local port, picking a free ephemeral port if `port` is zero
+    fn bind(&mut self, port: u16) -> Result<u16> {
+        if port == 0 {
+            let start = rand() % 32768;
+            for i in 0..32768 {
+                let port = ((start + i) % 32768 + 32768) as u16;
+                if ! self.ports.contains(&port) {
+                    self.ports.push(port);
+                    return Ok(port);
+                }
+            }
+        } else if ! self.ports.contains(&port) {
+            self.ports.push(port);
+            return Ok(port);
+        }
+
+        Err(Error::new(EADDRINUSE))
+    }
+
+    /// Release a local port claimed by `bind`
+    fn release(&mut self, port: u16) {
+        if let Some(i) = self.ports.iter().position(|&p| p == port) {
+            self.ports.remove(i);
+        }
+    }
+}
 
 impl KScheme for UdpScheme {
     fn scheme(&self) -> &str {
         "udp"
     }
 
-    fn open(&mut self, url: &str, _: usize) -> Result<Box<Resource>> {
+    fn open(&mut self, url: &str, flags: usize) -> Result<Box<Resource>> {
         let mut parts = url.splitn(2, ":").nth(1).unwrap_or("").split('/');
         let remote = parts.next().unwrap_or("");
         let path = parts.next().unwrap_or("");
 
+        let scheme = self as *mut UdpScheme;
+
         // Check host and port vs path
         if remote.is_empty() {
             let host_port = path.parse::<u16>().unwrap_or(0);
             if host_port > 0 {
+                try!(self.bind(host_port));
+
                 while let Ok(mut ip) = ::env().open("ip:/11", O_RDWR) {
                     let mut bytes = [0; 65536];
                     if let Ok(count) = ip.read(&mut bytes) {
@@ -186,36 +287,69 @@ impl KScheme for UdpScheme {
                                 let mut path = [0; 256];
                                 if let Ok(path_count) = ip.path(&mut path) {
                                     let ip_reference = unsafe { str::from_utf8_unchecked(&path[.. path_count]) }.split(':').nth(1).unwrap_or("");
-                                    let peer_addr = ip_reference.split('/').next().unwrap_or("").split(':').next().unwrap_or("");
-
-                                    return Ok(Box::new(UdpResource {
-                                        ip: ip,
-                                        data: datagram.data,
-                                        peer_addr: Ipv4Addr::from_str(peer_addr),
-                                        peer_port: datagram.header.src.get(),
-                                        host_port: host_port,
-                                    }));
+                                    let peer_addr = Ipv4Addr::from_str(ip_reference.split('/').next().unwrap_or("").split(':').next().unwrap_or(""));
+
+                                    if datagram.verify(peer_addr) {
+                                        unsafe { PROTOCOL_STATS.udp_delivered += 1; }
+                                        return Ok(Box::new(UdpResource {
+                                            scheme: scheme,
+                                            ip: ip,
+                                            data: datagram.data,
+                                            peer_addr: IpAddr::V4(peer_addr),
+                                            peer_port: datagram.header.src.get(),
+                                            host_port: host_port,
+                                        }));
+                                    } else {
+                                        unsafe { PROTOCOL_STATS.udp_checksum_errors += 1; }
+                                    }
                                 }
                             }
                         }
                     }
                 }
+
+                self.release(host_port);
             }
         } else {
-            let mut remote_parts = remote.split(':');
-            let peer_addr = remote_parts.next().unwrap_or("");
-            let peer_port = remote_parts.next().unwrap_or("").parse::<u16>().unwrap_or(0);
+            // An IPv6 literal is bracketed, as its colons would be taken for the port
+            let (host, port) = if remote.starts_with('[') {
+                match remote.find(']') {
+                    Some(end) => (&remote[1..end], remote[end + 1..].trim_left_matches(':')),
+                    None => return Err(Error::new(ENOENT)),
+                }
+            } else {
+                let mut remote_parts = remote.split(':');
+                (remote_parts.next().unwrap_or(""), remote_parts.next().unwrap_or(""))
+            };
+            let peer_port = port.parse::<u16>().unwrap_or(0);
             if peer_port > 0 {
-                let host_port = path.parse::<u16>().unwrap_or((rand() % 32768 + 32768) as u16);
-                if let Ok(ip) = ::env().open(&format!("ip:{}/11", peer_addr), O_RDWR) {
+                let peer_addr = if remote.starts_with('[') {
+                    match Ipv6Addr::from_str(host) {
+                        Some(addr) => IpAddr::V6(addr),
+                        None => return Err(Error::new(ENOENT)),
+                    }
+                } else {
+                    match try!(dns_resolve(host)).first() {
+                        Some(addr) => IpAddr::V4(*addr),
+                        None => return Err(Error::new(ENOENT)),
+                    }
+                };
+                let host_port = try!(self.bind(path.parse::<u16>().unwrap_or(0)));
+                let ip_path = match peer_addr {
+                    IpAddr::V4(addr) => format!("ip:{}/11", addr.to_string()),
+                    IpAddr::V6(addr) => format!("ip6:[{}]/11", addr.to_string()),
+                };
+                if let Ok(ip) = ::env().open(&ip_path, flags) {
                     return Ok(Box::new(UdpResource {
+                        scheme: scheme,
                         ip: ip,
                         data: Vec::new(),
-                        peer_addr: Ipv4Addr::from_str(peer_addr),
+                        peer_addr: peer_addr,
                         peer_port: peer_port as u16,
                         host_port: host_port,
                     }));
                 }
+                self.release(host_port);
             }
         }
 
@@ -1,5 +1,7 @@
 use core::fmt;
 
+use audio::pcspkr;
+
 use syscall;
 
 #[lang="panic_fmt"]
@@ -17,6 +19,8 @@ pub extern "C" fn panic_fmt(args: fmt::Arguments, file: &'static str, line: u32)
 
     debugln!("  KP {}: {}: {}", file, line, args);
 
+    pcspkr::beep_blocking(880, 250);
+
     loop {
         unsafe { asm!("cli ; hlt" : : : : "intel", "volatile"); }
     }
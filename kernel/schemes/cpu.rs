@@ -0,0 +1,149 @@
+use alloc::boxed::Box;
+
+use arch::cpuid::cpuid;
+
+use collections::string::{String, ToString};
+use collections::vec::Vec;
+
+use core::cmp;
+
+use fs::{KScheme, Resource, VecResource};
+
+use system::error::{Error, Result, ENOENT};
+use system::syscall::MODE_FILE;
+
+/// Feature flags by the cpuid register and bit that has them
+const FEATURES_1_EDX: [(u32, &'static str); 24] = [
+    (0, "fpu"), (1, "vme"), (2, "de"), (3, "pse"), (4, "tsc"), (5, "msr"), (6, "pae"), (7, "mce"),
+    (8, "cx8"), (9, "apic"), (11, "sep"), (12, "mtrr"), (13, "pge"), (14, "mca"), (15, "cmov"), (16, "pat"),
+    (17, "pse36"), (19, "clflush"), (23, "mmx"), (24, "fxsr"), (25, "sse"), (26, "sse2"), (27, "ss"), (28, "htt"),
+];
+
+const FEATURES_1_ECX: [(u32, &'static str); 20] = [
+    (0, "sse3"), (1, "pclmulqdq"), (3, "monitor"), (5, "vmx"), (9, "ssse3"), (12, "fma"), (13, "cx16"),
+    (19, "sse4_1"), (20, "sse4_2"), (21, "x2apic"), (22, "movbe"), (23, "popcnt"), (24, "tsc_deadline"),
+    (25, "aes"), (26, "xsave"), (27, "osxsave"), (28, "avx"), (29, "f16c"), (30, "rdrand"), (31, "hypervisor"),
+];
+
+const FEATURES_7_EBX: [(u32, &'static str); 11] = [
+    (0, "fsgsbase"), (3, "bmi1"), (4, "hle"), (5, "avx2"), (7, "smep"), (8, "bmi2"), (11, "rtm"),
+    (18, "rdseed"), (19, "adx"), (20, "smap"), (29, "sha"),
+];
+
+const FEATURES_EXT_EDX: [(u32, &'static str); 5] = [
+    (11, "syscall"), (20, "nx"), (26, "pdpe1gb"), (27, "rdtscp"), (29, "lm"),
+];
+
+const FEATURES_EXT_ECX: [(u32, &'static str); 4] = [
+    (0, "lahf_lm"), (2, "svm"), (5, "abm"), (6, "sse4a"),
+];
+
+fn flags(features: &mut Vec<&'static str>, register: u32, table: &[(u32, &'static str)]) {
+    for &(bit, name) in table.iter() {
+        if register & 1 << bit == 1 << bit {
+            features.push(name);
+        }
+    }
+}
+
+/// The registers as the bytes of a string, which the vendor and brand are given as
+fn register_string(string: &mut String, registers: &[u32]) {
+    for register in registers.iter() {
+        for i in 0..4 {
+            let c = (register >> (i * 8)) as u8;
+            if c != 0 {
+                string.push(c as char);
+            }
+        }
+    }
+}
+
+/// What cpuid says about this processor, one `key: value` a line
+fn info() -> String {
+    let (max, vendor_b, vendor_c, vendor_d) = cpuid(0, 0);
+    let mut vendor = String::new();
+    register_string(&mut vendor, &[vendor_b, vendor_d, vendor_c]);
+
+    let (ext_max, _, _, _) = cpuid(0x80000000, 0);
+
+    let mut brand = String::new();
+    if ext_max >= 0x80000004 {
+        for leaf in 0x80000002..0x80000005 {
+            let (a, b, c, d) = cpuid(leaf, 0);
+            register_string(&mut brand, &[a, b, c, d]);
+        }
+    }
+
+    let (signature, misc, ecx, edx) = if max >= 1 { cpuid(1, 0) } else { (0, 0, 0, 0) };
+    let mut family = (signature >> 8) & 0xF;
+    let mut model = (signature >> 4) & 0xF;
+    if family == 0xF {
+        family += (signature >> 20) & 0xFF;
+    }
+    if family == 0x6 || family >= 0xF {
+        model += (signature >> 16 & 0xF) << 4;
+    }
+    let stepping = signature & 0xF;
+
+    let mut features = Vec::new();
+    flags(&mut features, edx, &FEATURES_1_EDX);
+    flags(&mut features, ecx, &FEATURES_1_ECX);
+    if max >= 7 {
+        let (_, ebx, _, _) = cpuid(7, 0);
+        flags(&mut features, ebx, &FEATURES_7_EBX);
+    }
+    if ext_max >= 0x80000001 {
+        let (_, _, ecx, edx) = cpuid(0x80000001, 0);
+        flags(&mut features, edx, &FEATURES_EXT_EDX);
+        flags(&mut features, ecx, &FEATURES_EXT_ECX);
+    }
+
+    // Leaf 1 gives the logical processors a package has room for, leaf 4 or the extended leaf the cores
+    let logical = if edx & 1 << 28 == 1 << 28 { cmp::max(1, (misc >> 16) & 0xFF) } else { 1 };
+    let cores = if vendor == "GenuineIntel" && max >= 4 {
+        (cpuid(4, 0).0 >> 26) + 1
+    } else if vendor == "AuthenticAMD" && ext_max >= 0x80000008 {
+        (cpuid(0x80000008, 0).2 & 0xFF) + 1
+    } else {
+        1
+    };
+
+    let mut string = format!("vendor: {}\nbrand: {}\nfamily: {}\nmodel: {}\nstepping: {}\nlogical: {}\ncores: {}\nonline: 1\nfeatures:",
+                             vendor, brand.trim(), family, model, stepping, logical, cores);
+    for feature in features.iter() {
+        string.push(' ');
+        string.push_str(feature);
+    }
+    string.push('\n');
+    string
+}
+
+/// The processor scheme
+///
+/// `cpu:` has the vendor, brand, family, model, stepping, processor counts and feature flags. The
+/// kernel only runs on the processor it booted on, so `online` is always 1.
+pub struct CpuScheme {
+    info: String,
+}
+
+impl CpuScheme {
+    pub fn new() -> Box<CpuScheme> {
+        box CpuScheme {
+            info: info(),
+        }
+    }
+}
+
+impl KScheme for CpuScheme {
+    fn scheme(&self) -> &str {
+        "cpu"
+    }
+
+    fn open(&mut self, url: &str, _flags: usize) -> Result<Box<Resource>> {
+        if ! url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/').is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+
+        Ok(box VecResource::new("cpu:".to_string(), self.info.clone().into_bytes(), MODE_FILE))
+    }
+}
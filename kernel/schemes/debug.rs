@@ -2,17 +2,25 @@ use alloc::boxed::Box;
 
 use collections::borrow::ToOwned;
 use collections::string::String;
+use collections::vec::Vec;
+
+use common::event::{IO_READ, IO_WRITE};
+use common::to_num::ToNum;
 
 use core::cmp;
 
+use env::console::CONSOLES;
+
 use fs::{KScheme, Resource};
 
-use system::error::Result;
+use system::error::{Error, Result, ENOENT};
 
-/// A debug resource
+/// A debug resource, attached to one of the virtual consoles
 pub struct DebugResource {
     pub path: String,
     pub command: String,
+    /// The terminal of the console, from 0
+    pub terminal: usize,
 }
 
 impl Resource for DebugResource {
@@ -20,6 +28,7 @@ impl Resource for DebugResource {
         Ok(box DebugResource {
             path: self.path.clone(),
             command: self.command.clone(),
+            terminal: self.terminal,
         })
     }
 
@@ -35,7 +44,7 @@ impl Resource for DebugResource {
 
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         if self.command.is_empty() {
-            self.command = unsafe { &mut *::env().console.get() }.commands.receive("DebugResource::read");
+            self.command = unsafe { &mut *::env().console.get() }.terminals[self.terminal].commands.receive("DebugResource::read");
         }
 
         let mut i = 0;
@@ -48,18 +57,26 @@ impl Resource for DebugResource {
     }
 
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        unsafe { &mut *::env().console.get() }.write(buf);
+        unsafe { &mut *::env().console.get() }.write_to(self.terminal, buf);
         Ok(buf.len())
     }
 
     fn sync(&mut self) -> Result<()> {
         let console = unsafe { &mut *::env().console.get() };
-        if let Some(ref mut inner) = console.inner {
+        if let Some(ref mut inner) = console.terminals[self.terminal].inner {
             inner.redraw = true;
         }
-        console.write(&[]);
+        console.write_to(self.terminal, &[]);
         Ok(())
     }
+
+    fn ready(&mut self) -> i64 {
+        if self.command.is_empty() && unsafe { (*::env().console.get()).terminals[self.terminal].commands.inner() }.is_empty() {
+            IO_WRITE
+        } else {
+            IO_READ | IO_WRITE
+        }
+    }
 }
 
 pub struct DebugScheme;
@@ -75,18 +92,41 @@ impl KScheme for DebugScheme {
         "debug"
     }
 
-    fn open(&mut self, _: &str, _: usize) -> Result<Box<Resource>> {
+    /// `debug:` is the first console, and `debug:N` console N, the one shown with Ctrl+Alt+FN
+    ///
+    /// The path of a console after the first ends with its number, after the size.
+    fn open(&mut self, url: &str, _: usize) -> Result<Box<Resource>> {
+        let parts: Vec<&str> = url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/').split('/').collect();
+        let number = match parts.len() {
+            1 => parts[0],
+            3 => parts[2],
+            _ => "",
+        };
+        let terminal = if number.is_empty() {
+            0
+        } else if number.chars().all(|c| c.is_digit(10)) && number.to_num() >= 1 && number.to_num() <= CONSOLES {
+            number.to_num() - 1
+        } else {
+            return Err(Error::new(ENOENT));
+        };
+
         let console = unsafe { & *::env().console.get() };
-        if let Some(ref display) = console.display {
-            Ok(box DebugResource {
-                path: format!("debug:{}/{}", display.width/8, display.height/16),
-                command: String::new()
-            })
+        let mut path = if let Some(ref display) = console.display {
+            format!("debug:{}/{}", display.width/8, display.height/16)
         } else {
-            Ok(box DebugResource {
-                path: "debug:".to_owned(),
-                command: String::new()
-            })
+            "debug:".to_owned()
+        };
+        if terminal > 0 {
+            if ! path.ends_with(':') {
+                path.push('/');
+            }
+            path.push_str(&format!("{}", terminal + 1));
         }
+
+        Ok(box DebugResource {
+            path: path,
+            command: String::new(),
+            terminal: terminal,
+        })
     }
 }
@@ -0,0 +1,62 @@
+use alloc::boxed::Box;
+
+use collections::string::String;
+
+use fs::{KScheme, Resource, VecResource};
+
+use system::error::{Error, Result, ENOENT, EPERM};
+use system::syscall::MODE_FILE;
+
+/// The checks of every scheme named `name`, or of every scheme if it is empty, `None` if there is no such scheme
+fn report(name: &str) -> Option<String> {
+    let mut string = String::new();
+    let mut found = false;
+    let (mut passed, mut failed) = (0, 0);
+
+    for entry in ::env().schemes.snapshot().iter() {
+        if entry.name.is_empty() || (! name.is_empty() && entry.name != name) {
+            continue;
+        }
+        found = true;
+
+        for check in entry.scheme().diagnose().iter() {
+            if check.passed {
+                passed += 1;
+            } else {
+                failed += 1;
+            }
+            string.push_str(&format!("{:<10}{}", entry.name, check.to_string()));
+        }
+    }
+
+    if found {
+        string.push_str(&format!("{} passed, {} failed\n", passed, failed));
+        Some(string)
+    } else {
+        None
+    }
+}
+
+/// The diagnostics scheme, opening `diagnostics:` runs the self checks of every driver, and
+/// `diagnostics:/NAME` those of the schemes named NAME
+///
+/// The checks use the devices, so only a process with I/O privileges may run them.
+pub struct DiagnosticsScheme;
+
+impl KScheme for DiagnosticsScheme {
+    fn scheme(&self) -> &str {
+        "diagnostics"
+    }
+
+    fn open(&mut self, url: &str, _flags: usize) -> Result<Box<Resource>> {
+        if try!(unsafe { & *::env().contexts.get() }.current()).iopl != 3 {
+            return Err(Error::new(EPERM));
+        }
+
+        let name = url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/');
+        match report(name) {
+            Some(report) => Ok(box VecResource::new(format!("diagnostics:/{}", name), report.into_bytes(), MODE_FILE)),
+            None => Err(Error::new(ENOENT)),
+        }
+    }
+}
@@ -3,15 +3,19 @@ use alloc::boxed::Box;
 
 use collections::borrow::ToOwned;
 use collections::String;
+use collections::vec::Vec;
 
 use core::cell::UnsafeCell;
 use core::cmp;
 use disk::Disk;
-use fs::{KScheme, Resource, ResourceSeek, VecResource};
+use disk::gpt::{self, Partition};
+use fs::{Check, KScheme, Resource, ResourceSeek, ShutdownStage, VecResource};
+
+use schemes::initfs::boot_option;
 
 use syscall::{MODE_DIR, MODE_FILE, Stat};
 
-use system::error::{Error, Result, ENOENT};
+use system::error::{Error, Result, ENOENT, ESPIPE};
 
 /// A disk resource
 pub struct DiskResource {
@@ -38,14 +42,28 @@ impl Resource for DiskResource {
         Ok(cmp::min(buf.len(), path.len()))
     }
 
+    /// Read through the cache of the disk, a read it cannot answer fills it
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        let count = try!(unsafe { &mut *self.disk.get() }.read(self.seek/512, buf));
+        let disk = unsafe { &mut *self.disk.get() };
+        let block = self.seek/512;
+        let count = if disk.cache().get(block, buf) {
+            buf.len()
+        } else {
+            let count = try!(disk.read(block, buf));
+            disk.cache().insert(block, &buf[.. count]);
+            count
+        };
         self.seek += count as u64;
         Ok(count)
     }
 
+    /// Write through to the disk, keeping what was written in its cache
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        let count = try!(unsafe { &mut *self.disk.get() }.write(self.seek/512, buf));
+        let disk = unsafe { &mut *self.disk.get() };
+        let block = self.seek/512;
+        disk.cache().invalidate(block, buf.len());
+        let count = try!(disk.write(block, buf));
+        disk.cache().insert(block, &buf[.. count]);
         self.seek += count as u64;
         Ok(count)
     }
@@ -67,7 +85,7 @@ impl Resource for DiskResource {
     }
 
     fn sync(&mut self) -> Result<()> {
-        Ok(())
+        unsafe { &mut *self.disk.get() }.flush()
     }
 }
 
@@ -77,41 +95,282 @@ impl Drop for DiskResource {
     }
 }
 
+/// A disk statistics resource, writing to it resets the counters
+pub struct DiskStatsResource {
+    pub path: String,
+    pub disk: Arc<UnsafeCell<Box<Disk>>>,
+    pub seek: usize,
+}
+
+impl Resource for DiskStatsResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box DiskStatsResource {
+            path: self.path.clone(),
+            disk: self.disk.clone(),
+            seek: self.seek,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = self.path.as_bytes();
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let disk = unsafe { &mut *self.disk.get() };
+        let string = disk.stats().to_string() + &disk.cache().to_string();
+        let data = string.as_bytes();
+
+        let mut i = 0;
+        while i < buf.len() && self.seek < data.len() {
+            buf[i] = data[self.seek];
+            i += 1;
+            self.seek += 1;
+        }
+
+        Ok(i)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let disk = unsafe { &mut *self.disk.get() };
+        disk.stats().reset();
+        disk.cache().reset();
+        self.seek = 0;
+        Ok(buf.len())
+    }
+
+    fn seek(&mut self, pos: ResourceSeek) -> Result<usize> {
+        match pos {
+            ResourceSeek::Start(offset) => self.seek = offset,
+            ResourceSeek::Current(offset) => self.seek = cmp::max(0, self.seek as isize + offset) as usize,
+            ResourceSeek::End(_) => return Err(Error::new(ESPIPE)),
+        }
+        Ok(self.seek)
+    }
+
+    fn stat(&self, stat: &mut Stat) -> Result<()> {
+        stat.st_mode = MODE_FILE;
+        Ok(())
+    }
+}
+
+/// The disk holding the root filesystem, as a path `find` takes
+///
+/// ROOT_DISK in the boot env file selects it, then the ROOT_DISK the kernel was built with, then disk 0.
+fn root_disk() -> String {
+    boot_option("ROOT_DISK").unwrap_or(option_env!("ROOT_DISK").unwrap_or("0").to_owned())
+}
+
+/// Find the first disk with a partition that `matches`, returning its index
+fn find_partition<F: Fn(&Partition) -> bool>(matches: F) -> Option<usize> {
+    for (number, disk) in unsafe { & *::env().disks.get() }.iter().enumerate() {
+        if let Some(ref disk) = *disk {
+            if let Ok(partitions) = gpt::partitions(&mut **unsafe { &mut *disk.get() }) {
+                if partitions.iter().any(|partition| matches(partition)) {
+                    return Some(number);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// The serial of a disk, if no other disk shares it
+fn unique_serial(number: usize) -> Option<String> {
+    let disks = unsafe { & *::env().disks.get() };
+    if let Some(&Some(ref disk)) = disks.get(number) {
+        let serial = unsafe { & *disk.get() }.serial();
+        if ! serial.is_empty() {
+            for (i, other) in disks.iter().enumerate() {
+                if i != number && other.as_ref().map_or(false, |other| unsafe { & *other.get() }.serial() == serial) {
+                    return None;
+                }
+            }
+            return Some(serial);
+        }
+    }
+    None
+}
+
+/// Find a disk by index, serial, partition GUID or label, or alias, returning its index and the rest of the path
+fn find(path: &str) -> Option<(usize, &str)> {
+    let count = unsafe { & *::env().disks.get() }.len();
+
+    let mut parts = path.splitn(2, '/');
+    match parts.next().unwrap_or("") {
+        "root" => find(&root_disk()).map(|(number, _)| (number, parts.next().unwrap_or(""))),
+        "serial" => {
+            let mut serial_parts = parts.next().unwrap_or("").splitn(2, '/');
+            let name = serial_parts.next().unwrap_or("");
+            for number in 0..count {
+                if unique_serial(number).map_or(false, |serial| serial == name) {
+                    return Some((number, serial_parts.next().unwrap_or("")));
+                }
+            }
+            None
+        },
+        "guid" => {
+            let mut guid_parts = parts.next().unwrap_or("").splitn(2, '/');
+            let guid = guid_parts.next().unwrap_or("");
+            find_partition(|partition| partition.has_guid(guid)).map(|number| (number, guid_parts.next().unwrap_or("")))
+        },
+        "label" => {
+            let mut label_parts = parts.next().unwrap_or("").splitn(2, '/');
+            let label = label_parts.next().unwrap_or("");
+            find_partition(|partition| partition.label == label).map(|number| (number, label_parts.next().unwrap_or("")))
+        },
+        name => match name.parse::<usize>() {
+            Ok(number) if number < count => Some((number, parts.next().unwrap_or(""))),
+            _ => None
+        }
+    }
+}
+
 /// A disk scheme
-pub struct DiskScheme;
+pub struct DiskScheme {
+    /// The first sector of each disk as the last check read it
+    first_sectors: Vec<Option<Vec<u8>>>,
+}
+
+impl DiskScheme {
+    pub fn new() -> Box<Self> {
+        box DiskScheme {
+            first_sectors: Vec::new(),
+        }
+    }
+}
 
 impl KScheme for DiskScheme {
     fn scheme(&self) -> &str {
         "disk"
     }
 
-    fn on_irq(&mut self, irq: u8) {
-        for disk in unsafe { &mut *::env().disks.get() }.iter_mut() {
-            unsafe { &mut *disk.get() }.on_irq(irq);
+    fn shutdown_stage(&self) -> ShutdownStage {
+        ShutdownStage::Storage
+    }
+
+    /// Write the cache of every disk to its medium
+    fn power_off(&mut self) {
+        for disk in unsafe { &mut *::env().disks.get() }.iter_mut().filter_map(|disk| disk.as_mut()) {
+            let disk = unsafe { &mut *disk.get() };
+            if let Err(err) = disk.flush() {
+                syslog_warning!("{}: Flush failed: {}", disk.name(), err);
+            }
         }
     }
 
+    /// Read the first sector of every disk and compare it with what the last check read
+    ///
+    /// The first check only keeps the copy. A program writing the sector in between also counts as a mismatch.
+    fn diagnose(&mut self) -> Vec<Check> {
+        let mut checks = Vec::new();
+
+        for (i, disk) in unsafe { & *::env().disks.get() }.iter().enumerate() {
+            let disk = match *disk {
+                Some(ref disk) => unsafe { &mut *disk.get() },
+                None => continue,
+            };
+            let name = format!("disk {}", i);
+            if self.first_sectors.len() <= i {
+                self.first_sectors.resize(i + 1, None);
+            }
+
+            let mut sector = vec![0; 512];
+            match disk.read(0, &mut sector) {
+                Ok(count) if count == sector.len() => {
+                    let check = match self.first_sectors[i] {
+                        Some(ref copy) if *copy == sector => Check::new(&name, true, format!("{}: sector 0 matches the last check", disk.name())),
+                        Some(_) => Check::new(&name, false, format!("{}: sector 0 differs from the last check", disk.name())),
+                        None => Check::new(&name, true, format!("{}: sector 0 read, kept for the next check", disk.name())),
+                    };
+                    checks.push(check);
+                    self.first_sectors[i] = Some(sector);
+                },
+                Ok(count) => checks.push(Check::new(&name, false, format!("{}: read {} of {} bytes of sector 0", disk.name(), count, sector.len()))),
+                Err(err) => checks.push(Check::new(&name, false, format!("{}: reading sector 0 failed: {}", disk.name(), err))),
+            }
+        }
+
+        checks
+    }
+
+    fn on_irq(&mut self, irq: u8) -> bool {
+        let mut claimed = false;
+        for disk in unsafe { &mut *::env().disks.get() }.iter_mut().filter_map(|disk| disk.as_mut()) {
+            if unsafe { &mut *disk.get() }.on_irq(irq) {
+                claimed = true;
+            }
+        }
+        claimed
+    }
+
     fn open(&mut self, url: &str, _flags: usize) -> Result<Box<Resource>> {
         let path = url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/');
 
         if path.is_empty() {
             let mut list = String::new();
-            for i in 0..unsafe { & *::env().disks.get() }.len() {
-                if ! list.is_empty() {
-                    list.push('\n');
+            for (i, disk) in unsafe { & *::env().disks.get() }.iter().enumerate() {
+                if disk.is_some() {
+                    list.push_str(&format!("{}\n", i));
                 }
-                list.push_str(&format!("{}", i));
             }
+            list.push_str("root\nserial\nguid\nlabel");
 
             return Ok(box VecResource::new("disk:/".to_owned(), list.into_bytes(), MODE_DIR));
-        } else {
-            if let Ok(number) = path.parse::<usize>() {
-                if let Some(disk) = unsafe { & *::env().disks.get() }.get(number) {
-                    return Ok(box DiskResource {
+        } else if path == "serial" {
+            let mut list = String::new();
+            for i in 0..unsafe { & *::env().disks.get() }.len() {
+                if let Some(serial) = unique_serial(i) {
+                    if ! list.is_empty() {
+                        list.push('\n');
+                    }
+                    list.push_str(&serial);
+                }
+            }
+
+            return Ok(box VecResource::new("disk:/serial".to_owned(), list.into_bytes(), MODE_DIR));
+        } else if path == "guid" || path == "label" {
+            let mut list = String::new();
+            for disk in unsafe { & *::env().disks.get() }.iter().filter_map(|disk| disk.as_ref()) {
+                for partition in gpt::partitions(&mut **unsafe { &mut *disk.get() }).unwrap_or(Vec::new()) {
+                    if ! list.is_empty() {
+                        list.push('\n');
+                    }
+                    if path == "guid" {
+                        list.push_str(&gpt::format_guid(&partition.guid));
+                    } else {
+                        list.push_str(&partition.label);
+                    }
+                }
+            }
+
+            return Ok(box VecResource::new(format!("disk:/{}", path), list.into_bytes(), MODE_DIR));
+        } else if let Some((number, rest)) = find(path) {
+            if let Some(&Some(ref disk)) = unsafe { & *::env().disks.get() }.get(number) {
+                match rest.trim_matches('/') {
+                    "" => return Ok(box DiskResource {
                         path: format!("disk:/{}", number),
                         disk: disk.clone(),
                         seek: 0
-                    });
+                    }),
+                    "stats" => return Ok(box DiskStatsResource {
+                        path: format!("disk:/{}/stats", number),
+                        disk: disk.clone(),
+                        seek: 0
+                    }),
+                    "bad" => {
+                        let mut list = String::new();
+                        for block in unsafe { &mut *disk.get() }.stats().bad_sectors.iter() {
+                            list.push_str(&format!("{}\n", block));
+                        }
+
+                        return Ok(box VecResource::new(format!("disk:/{}/bad", number), list.into_bytes(), MODE_FILE));
+                    },
+                    _ => ()
                 }
             }
         }
@@ -1,16 +1,22 @@
 use alloc::boxed::Box;
 
-use collections::String;
+use collections::{String, Vec};
 
-use common::event::Event;
+use common::event::{RedrawEvent, ResizeEvent, EVENT_FRAME_LEN, IO_READ, IO_WRITE};
+use common::to_num::ToNum;
 
-use core::{cmp, ptr};
-use core::mem::size_of;
+use core::{cmp, slice, str};
 
-use fs::{KScheme, Resource, ResourceSeek};
+use drivers::cursor::cursor_move;
 
-use system::error::{Error, Result, EACCES, EBADF, ENOENT, EINVAL};
-use system::graphics::fast_copy;
+use env::events::{EVENT_QUEUE_RESERVE, EVENT_QUEUE_SIZE};
+use env::focus;
+use env::log::InterruptGuard;
+
+use fs::{Check, KScheme, Resource, ResourceSeek, VecResource};
+
+use system::error::{Error, Result, EACCES, EBADF, ENOENT, EINVAL, EPERM};
+use system::syscall::MODE_FILE;
 
 /// A display resource
 pub struct DisplayResource {
@@ -18,13 +24,20 @@ pub struct DisplayResource {
     path: String,
     /// Seek
     seek: usize,
+    /// Opened as `display:manager`, the display goes back to the console once every such file is closed
+    manager: bool,
 }
 
 impl Resource for DisplayResource {
     fn dup(&self) -> Result<Box<Resource>> {
+        if self.manager {
+            let _guard = InterruptGuard::new();
+            unsafe { &mut *::env().console.get() }.managers += 1;
+        }
         Ok(Box::new(DisplayResource {
             path: self.path.clone(),
-            seek: self.seek
+            seek: self.seek,
+            manager: self.manager,
         }))
     }
 
@@ -39,16 +52,20 @@ impl Resource for DisplayResource {
         Ok(cmp::min(buf.len(), path.len()))
     }
 
+    /// Read as many events as fit, each with the header of `Event::to_bytes`
+    ///
+    /// This waits for one event, then takes the others queued without waiting. An event that does not
+    /// fit whole is left for the next read.
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        if buf.len() >= size_of::<Event>() {
+        if buf.len() >= EVENT_FRAME_LEN {
             let event = ::env().events.receive("DisplayResource::read");
-            unsafe { ptr::write(buf.as_mut_ptr().offset(0isize) as *mut Event, event) };
-            let mut i = size_of::<Event>();
+            buf[..EVENT_FRAME_LEN].copy_from_slice(&event.to_bytes());
+            let mut i = EVENT_FRAME_LEN;
 
-            while i + size_of::<Event>() <= buf.len() {
-                if let Some(event) = unsafe { ::env().events.inner() }.pop_front() {
-                    unsafe { ptr::write(buf.as_mut_ptr().offset(i as isize) as *mut Event, event) };
-                    i += size_of::<Event>();
+            while i + EVENT_FRAME_LEN <= buf.len() {
+                if let Some(event) = ::env().events.try_receive() {
+                    buf[i..i + EVENT_FRAME_LEN].copy_from_slice(&event.to_bytes());
+                    i += EVENT_FRAME_LEN;
                 } else {
                     break;
                 }
@@ -61,14 +78,18 @@ impl Resource for DisplayResource {
     }
 
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        let console = unsafe { & *::env().console.get() };
-        if let Some(ref display) = console.display {
+        // The cursor is moved from interrupts, and must not be drawn in the middle of this
+        let _guard = InterruptGuard::new();
+        let console = unsafe { &mut *::env().console.get() };
+        // The console is shown, through Alt+Tab, so what the display manager draws waits for the redraw
+        if console.draw {
+            return Ok(buf.len() / 4);
+        }
+        if let Some(ref mut display) = console.display {
             let size = cmp::max(0, cmp::min(display.size as isize - self.seek as isize, (buf.len()/4) as isize)) as usize;
 
             if size > 0 {
-                unsafe {
-                    fast_copy(display.onscreen.offset(self.seek as isize), buf.as_ptr() as *const u32, size);
-                }
+                display.write(self.seek, unsafe { slice::from_raw_parts(buf.as_ptr() as *const u32, size) });
             }
 
             Ok(size)
@@ -95,8 +116,126 @@ impl Resource for DisplayResource {
     fn sync(&mut self) -> Result<()> {
         Ok(())
     }
+
+    fn ready(&mut self) -> i64 {
+        if ::env().events.is_empty() { IO_WRITE } else { IO_READ | IO_WRITE }
+    }
+}
+
+impl Drop for DisplayResource {
+    fn drop(&mut self) {
+        if self.manager {
+            let _guard = InterruptGuard::new();
+            let console = unsafe { &mut *::env().console.get() };
+            console.managers -= 1;
+            if console.managers == 0 {
+                focus::manager_closed();
+            }
+        }
+    }
+}
+
+/// Switch the display to `width` by `height`, telling the display manager to lay out and draw again
+fn set_mode(width: usize, height: usize) -> Result<()> {
+    let _guard = InterruptGuard::new();
+    let console = unsafe { &mut *::env().console.get() };
+    if console.display.is_none() {
+        return Err(Error::new(EBADF));
+    }
+    if ! console.set_mode(width, height) {
+        return Err(Error::new(EINVAL));
+    }
+
+    // Back on the screen, and drawn again when it next moves
+    cursor_move(0, 0);
+    ::env().events.send(ResizeEvent {
+        width: width,
+        height: height,
+    }.to_event(), "display::set_mode");
+    ::env().events.send(RedrawEvent.to_event(), "display::set_mode");
+
+    syslog_info!("Display: switched to {}x{}", width, height);
+    Ok(())
+}
+
+/// `display:mode` is the mode the display is in, as `WIDTHxHEIGHT`, and switches it when written one
+///
+/// Only a process with I/O privileges may switch it, as the display manager does.
+pub struct DisplayModeResource {
+    data: Vec<u8>,
+    seek: usize,
+}
+
+impl Resource for DisplayModeResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box DisplayModeResource {
+            data: self.data.clone(),
+            seek: self.seek,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = b"display:mode";
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut i = 0;
+        while i < buf.len() && self.seek < self.data.len() {
+            buf[i] = self.data[self.seek];
+            i += 1;
+            self.seek += 1;
+        }
+        Ok(i)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let iopl = try!(unsafe { & *::env().contexts.get() }.current()).iopl;
+        if iopl != 3 {
+            return Err(Error::new(EPERM));
+        }
+
+        let mode: Vec<&str> = str::from_utf8(buf).unwrap_or("").trim().split('x').collect();
+        if mode.len() != 2 || ! mode.iter().all(|part| ! part.is_empty() && part.chars().all(|c| c.is_digit(10))) {
+            return Err(Error::new(EINVAL));
+        }
+        try!(set_mode(mode[0].to_num(), mode[1].to_num()));
+
+        self.data = try!(mode_string()).into_bytes();
+        self.seek = 0;
+        Ok(buf.len())
+    }
+
+    fn seek(&mut self, pos: ResourceSeek) -> Result<usize> {
+        self.seek = match pos {
+            ResourceSeek::Start(offset) => cmp::min(self.data.len(), offset),
+            ResourceSeek::Current(offset) => cmp::max(0, cmp::min(self.data.len() as isize, self.seek as isize + offset)) as usize,
+            ResourceSeek::End(offset) => cmp::max(0, cmp::min(self.data.len() as isize, self.data.len() as isize + offset)) as usize,
+        };
+        Ok(self.seek)
+    }
 }
 
+fn mode_string() -> Result<String> {
+    let console = unsafe { & *::env().console.get() };
+    match console.display {
+        Some(ref display) => Ok(format!("{}x{}\n", display.width, display.height)),
+        None => Err(Error::new(ENOENT)),
+    }
+}
+
+/// The display scheme
+///
+/// - `display:manager` is the display for the display manager, which takes it from the console
+/// - `display:mode` is the mode, which can be switched by writing another
+/// - `display:modes` lists the modes it can be switched to, one `WIDTHxHEIGHT` a line
+///
+/// Anything else opens the display to write pixels to, named by its size at the time.
 pub struct DisplayScheme;
 
 impl KScheme for DisplayScheme {
@@ -104,16 +243,46 @@ impl KScheme for DisplayScheme {
         "display"
     }
 
+    /// Report what the event queue dropped to stay within its size, which fails if a key or button was lost
+    fn diagnose(&mut self) -> Vec<Check> {
+        let stats = ::env().events.stats();
+        let mut checks = Vec::new();
+        checks.push(Check::new("events", stats.lost == 0,
+                               format!("{} queued of {} and {} reserved, peak {}, {} coalesced, dropped {} moves, {} scrolls, {} audio, lost {} keys, buttons and device events",
+                                       ::env().events.len(), EVENT_QUEUE_SIZE, EVENT_QUEUE_RESERVE, stats.peak, stats.coalesced,
+                                       stats.dropped_moves, stats.dropped_scrolls, stats.dropped_audio, stats.lost)));
+        checks
+    }
+
     fn open(&mut self, url: &str, _: usize) -> Result<Box<Resource>> {
+        match url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/') {
+            "mode" => return Ok(box DisplayModeResource {
+                data: try!(mode_string()).into_bytes(),
+                seek: 0,
+            }),
+            "modes" => {
+                let console = unsafe { & *::env().console.get() };
+                let display = try!(console.display.as_ref().ok_or(Error::new(ENOENT)));
+                let mut list = String::new();
+                for &(width, height) in display.modes().iter() {
+                    list.push_str(&format!("{}x{}\n", width, height));
+                }
+                return Ok(box VecResource::new("display:modes".into(), list.into_bytes(), MODE_FILE));
+            },
+            _ => (),
+        }
+
         if url.splitn(2, ":").nth(1).unwrap_or("") == "manager" {
+            let _guard = InterruptGuard::new();
             let console = unsafe { &mut *::env().console.get() };
-            if console.draw {
-                console.draw = false;
-
+            if console.managers == 0 {
                 if let Some(ref display) = console.display {
+                    console.managers = 1;
+                    console.draw = false;
                     Ok(box DisplayResource {
                         path: format!("display:{}/{}", display.width, display.height),
                         seek: 0,
+                        manager: true,
                     })
                 } else {
                     Err(Error::new(ENOENT))
@@ -127,6 +296,7 @@ impl KScheme for DisplayScheme {
                 Ok(box DisplayResource {
                     path: format!("display:{}/{}", display.width, display.height),
                     seek: 0,
+                    manager: false,
                 })
             } else {
                 Err(Error::new(ENOENT))
@@ -0,0 +1,59 @@
+use alloc::boxed::Box;
+
+use collections::string::{String, ToString};
+
+use drivers::pci::PciOutcome;
+use drivers::pci::config::PciBarKind;
+
+use fs::{KScheme, Resource, VecResource};
+
+use system::error::{Error, Result, ENOENT};
+use system::syscall::MODE_FILE;
+
+/// Every PCI function found at boot, with its BARs and the driver that took it, or why none did
+fn report() -> String {
+    let mut string = format!("{:<10}{:<11}{:<11}{:<12}{}\n", "PCI", "CLASS", "ID", "DRIVER", "STATUS");
+
+    for function in unsafe { & *::env().pci.get() }.iter() {
+        let (driver, status) = match function.outcome {
+            PciOutcome::Bound(driver) => (driver, "bound".to_string()),
+            PciOutcome::Failed(driver, error) => (driver, format!("failed: {}", error)),
+            PciOutcome::Unmatched => ("-", "no driver".to_string()),
+        };
+
+        string.push_str(&format!("{:<10}{:<11}{:<11}{:<12}{}\n",
+                                 format!("{:02X}:{:02X}.{}", function.bus, function.slot, function.func),
+                                 format!("{:02X}.{:02X}.{:02X}", function.class_id, function.subclass_id, function.interface_id),
+                                 format!("{:04X}:{:04X}", function.vendor_code, function.device_code),
+                                 driver, status));
+
+        for (i, bar) in function.bars.iter().enumerate() {
+            let kind = match bar.kind {
+                PciBarKind::None => continue,
+                PciBarKind::Port => "port",
+                PciBarKind::Memory => "memory",
+                PciBarKind::Memory64 => "memory64",
+            };
+            string.push_str(&format!("{:<10}BAR{} {:<9}{:X} size {:X}\n", "", i, kind, bar.base, bar.size));
+        }
+    }
+
+    string
+}
+
+/// The drivers scheme, telling what PCI enumeration probed, bound and skipped
+pub struct DriversScheme;
+
+impl KScheme for DriversScheme {
+    fn scheme(&self) -> &str {
+        "drivers"
+    }
+
+    fn open(&mut self, url: &str, _flags: usize) -> Result<Box<Resource>> {
+        if ! url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/').is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+
+        Ok(box VecResource::new("drivers:".to_string(), report().into_bytes(), MODE_FILE))
+    }
+}
@@ -0,0 +1,238 @@
+use alloc::boxed::Box;
+
+use arch::timekeeping;
+
+use collections::vec::Vec;
+
+use common::event::{IoEvent, EVENT_FRAME_LEN, IO_CLOSED, IO_READ, IO_WRITE};
+use common::to_num::ToNum;
+
+use core::{cmp, str};
+
+use fs::{KScheme, Resource};
+
+use system::error::{Error, Result, EAGAIN, EBADF, EEXIST, EINVAL, ENOENT};
+use system::syscall::O_NONBLOCK;
+
+/// Milliseconds between checks of the registered files while a read waits
+const EVENT_POLL: u64 = 10;
+
+/// A file an event queue watches
+struct Registration {
+    id: i64,
+    fd: usize,
+    /// The resource the file was when registered, so a file closed and its number reused is noticed
+    resource: usize,
+    /// What to watch for, `IO_READ` and `IO_WRITE`
+    flags: i64,
+    /// What was reported ready, each flag is reported again only after it was lost
+    reported: i64,
+}
+
+fn address(resource: &Box<Resource>) -> usize {
+    &**resource as *const Resource as *const u8 as usize
+}
+
+/// An event queue, which registers the files of the process that opened it
+///
+/// Writing `add ID FD FLAGS`, with FLAGS `r`, `w` or `rw`, watches file FD, and `remove ID` stops. Reading
+/// gives an `IoEvent` for each registration that became ready since it was last reported, waiting for one
+/// unless the queue was opened with `O_NONBLOCK`, and `IO_CLOSED` once for a file that was closed.
+/// Readiness that repeats before it is read is reported once.
+pub struct EventResource {
+    registrations: Vec<Registration>,
+    /// Events found and not read yet, at most one for each registration
+    pending: Vec<IoEvent>,
+    nonblock: bool,
+}
+
+impl EventResource {
+    /// Check every registered file, adding what became ready to the pending events
+    fn poll(&mut self) {
+        let contexts = unsafe { &mut *::env().contexts.get() };
+        let current = match contexts.current_mut() {
+            Ok(current) => current,
+            Err(_) => return,
+        };
+
+        let mut i = 0;
+        while i < self.registrations.len() {
+            let (id, flags) = {
+                let registration = &mut self.registrations[i];
+                let flags = match current.get_file_mut(registration.fd) {
+                    Ok(resource) => if address(resource) == registration.resource {
+                        let ready = resource.ready();
+                        if ready & IO_CLOSED == IO_CLOSED {
+                            // The scheme of the file is gone, so it is as good as closed
+                            IO_CLOSED
+                        } else {
+                            let ready = ready & registration.flags;
+                            let flags = ready & ! registration.reported;
+                            registration.reported = ready;
+                            flags
+                        }
+                    } else {
+                        IO_CLOSED
+                    },
+                    Err(_) => IO_CLOSED,
+                };
+                (registration.id, flags)
+            };
+
+            if flags != 0 {
+                match self.pending.iter().position(|event| event.id == id) {
+                    Some(j) => self.pending[j].flags |= flags,
+                    None => self.pending.push(IoEvent {
+                        id: id,
+                        flags: flags,
+                    }),
+                }
+            }
+
+            if flags == IO_CLOSED {
+                self.registrations.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    fn add(&mut self, id: i64, fd: usize, flags: i64) -> Result<()> {
+        if self.registrations.iter().any(|registration| registration.id == id) {
+            return Err(Error::new(EEXIST));
+        }
+
+        let contexts = unsafe { &mut *::env().contexts.get() };
+        let resource = try!(try!(contexts.current_mut()).get_file_mut(fd));
+        // A queue watching itself would check itself while checking
+        if address(resource) == self as *const EventResource as usize {
+            return Err(Error::new(EINVAL));
+        }
+
+        self.registrations.push(Registration {
+            id: id,
+            fd: fd,
+            resource: address(resource),
+            flags: flags,
+            reported: 0,
+        });
+        Ok(())
+    }
+
+    fn remove(&mut self, id: i64) -> Result<()> {
+        match self.registrations.iter().position(|registration| registration.id == id) {
+            Some(i) => {
+                self.registrations.remove(i);
+                self.pending.retain(|event| event.id != id);
+                Ok(())
+            },
+            None => Err(Error::new(ENOENT)),
+        }
+    }
+}
+
+impl Resource for EventResource {
+    /// A duplicate starts with no registrations, the files belong to whoever has it
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box EventResource {
+            registrations: Vec::new(),
+            pending: Vec::new(),
+            nonblock: self.nonblock,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = b"event:";
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    /// Read as many events as fit, returns 0 if nothing is registered
+    ///
+    /// This waits for one event, unless the queue was opened non-blocking, then takes the others pending
+    /// without waiting. An event that does not fit whole is left for the next read.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.len() < EVENT_FRAME_LEN {
+            return Err(Error::new(EINVAL));
+        }
+
+        loop {
+            self.poll();
+            if ! self.pending.is_empty() {
+                break;
+            } else if self.registrations.is_empty() {
+                return Ok(0);
+            } else if self.nonblock {
+                return Err(Error::new(EAGAIN));
+            }
+            timekeeping::sleep(EVENT_POLL, "EventResource::read");
+        }
+
+        let mut i = 0;
+        while i + EVENT_FRAME_LEN <= buf.len() && ! self.pending.is_empty() {
+            let event = self.pending.remove(0).to_event();
+            buf[i..i + EVENT_FRAME_LEN].copy_from_slice(&event.to_bytes());
+            i += EVENT_FRAME_LEN;
+        }
+        Ok(i)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        for line in str::from_utf8(buf).unwrap_or("").lines() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let number = |word: &str| if ! word.is_empty() && word.chars().all(|c| c.is_digit(10)) {
+                Ok(word.to_num())
+            } else {
+                Err(Error::new(EINVAL))
+            };
+
+            match words.get(0) {
+                Some(&"add") if words.len() == 4 => {
+                    let flags = match words[3] {
+                        "r" => IO_READ,
+                        "w" => IO_WRITE,
+                        "rw" => IO_READ | IO_WRITE,
+                        _ => return Err(Error::new(EINVAL)),
+                    };
+                    let fd = try!(number(words[2]).map_err(|_| Error::new(EBADF)));
+                    try!(self.add(try!(number(words[1])) as i64, fd, flags));
+                },
+                Some(&"remove") if words.len() == 2 => try!(self.remove(try!(number(words[1])) as i64)),
+                None => (),
+                _ => return Err(Error::new(EINVAL)),
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    /// Queues are not checked through one another, so one is never readable to another
+    fn ready(&mut self) -> i64 {
+        IO_WRITE
+    }
+}
+
+/// The event queue scheme, a select for the files of a process built on events
+pub struct EventScheme;
+
+impl KScheme for EventScheme {
+    fn scheme(&self) -> &str {
+        "event"
+    }
+
+    fn open(&mut self, url: &str, flags: usize) -> Result<Box<Resource>> {
+        if ! url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/').is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+
+        Ok(box EventResource {
+            registrations: Vec::new(),
+            pending: Vec::new(),
+            nonblock: flags & O_NONBLOCK == O_NONBLOCK,
+        })
+    }
+}
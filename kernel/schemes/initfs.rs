@@ -1,6 +1,7 @@
 use alloc::boxed::Box;
 
 use collections::{BTreeMap, String};
+use collections::borrow::ToOwned;
 
 use core::cmp::{min, max};
 
@@ -88,6 +89,32 @@ impl Resource for InitFsResource {
     }
 }
 
+/// The settings read at boot, a `NAME=value` line for each, with `#` starting a comment
+pub const BOOT_ENV: &'static str = "etc/kernel.env";
+
+/// Find the value of `name` in an env file
+pub fn env_value(env: &[u8], name: &str) -> Option<String> {
+    for line in String::from_utf8_lossy(env).lines() {
+        let line = line.trim();
+        if line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, '=');
+        if parts.next().map(|key| key.trim()) == Some(name) {
+            return parts.next().map(|value| value.trim().to_owned());
+        }
+    }
+    None
+}
+
+/// The value of the boot setting `name`, from the env file in this filesystem
+///
+/// The file is read each time a setting is looked up, not when the kernel is compiled.
+pub fn boot_option(name: &str) -> Option<String> {
+    gen::gen().get(BOOT_ENV).and_then(|env| env_value(env, name))
+}
+
 /// A memory scheme
 pub struct InitFsScheme {
     pub files: BTreeMap<&'static str, &'static [u8]>
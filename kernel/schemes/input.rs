@@ -0,0 +1,289 @@
+use alloc::boxed::Box;
+
+use arch::context::Context;
+use arch::timekeeping;
+
+use audio::scheme::media_key;
+
+use collections::Vec;
+
+use common::event::{Decoded, Event, EventDecoder, EventOption, KeyEvent, MouseEvent, K_CAPS, K_LEFT_SHIFT, K_RIGHT_SHIFT};
+
+use core::{cmp, str};
+
+use drivers::cursor::cursor_set;
+use drivers::kb_layouts::layouts;
+
+use env::focus;
+use env::log::InterruptGuard;
+
+use fs::{KScheme, Resource};
+
+use schemes::keyboard::KeyRepeat;
+
+use system::error::{Error, Result, EACCES, EINVAL, ENOENT, EPERM};
+
+/// Set through `input:enable`, nothing can be injected until it is
+static mut INPUT_ENABLED: bool = false;
+/// Set while the context repeating held keys runs
+static mut INPUT_REPEATING: bool = false;
+
+/// The keys held by what was injected, with the modifiers and locks the characters of keys depend on
+///
+/// A hardware keyboard keeps its own, so a shift injected does not shift what is typed on it or the
+/// other way around.
+struct Injector {
+    keys: Vec<u8>,
+    caps_lock: bool,
+    /// A key injected as pressed repeats as one held on a keyboard
+    repeat: KeyRepeat,
+}
+
+static mut INJECTOR: Option<Injector> = None;
+
+fn injector() -> &'static mut Injector {
+    unsafe {
+        if INJECTOR.is_none() {
+            INJECTOR = Some(Injector {
+                keys: Vec::new(),
+                caps_lock: false,
+                repeat: KeyRepeat::new(),
+            });
+        }
+        INJECTOR.as_mut().unwrap()
+    }
+}
+
+/// Pass a key on as the keyboard drivers do, media keys go to the mixer
+fn send_key(key_event: KeyEvent) {
+    if ! media_key(&key_event) {
+        focus::send(key_event.to_event().synthetic());
+    }
+}
+
+impl Injector {
+    /// Track the key, give it the character of the layout if it has none, and send it
+    fn key(&mut self, mut key_event: KeyEvent) {
+        let held = self.keys.iter().position(|&scancode| scancode == key_event.scancode);
+        if key_event.pressed {
+            if held.is_none() {
+                if key_event.scancode == K_CAPS {
+                    self.caps_lock = ! self.caps_lock;
+                }
+                self.keys.push(key_event.scancode);
+            }
+        } else if let Some(i) = held {
+            self.keys.remove(i);
+        }
+
+        if key_event.character.is_none() {
+            let shift = self.caps_lock != (self.keys.contains(&K_LEFT_SHIFT) || self.keys.contains(&K_RIGHT_SHIFT));
+            key_event.character = layouts::char_for_scancode(key_event.scancode, shift, false, &focus::layout());
+        }
+
+        // A press of a key already held is sent all the same, as the program injecting it means it
+        self.repeat.key(&key_event);
+
+        send_key(key_event);
+    }
+
+    /// Release every key held, so none is left down after the injecting program is gone
+    fn release(&mut self) {
+        while let Some(&scancode) = self.keys.last() {
+            self.key(KeyEvent {
+                character: None,
+                scancode: scancode,
+                pressed: false,
+            });
+        }
+        self.repeat.clear();
+    }
+
+    /// Press the held key again, once it is held long enough
+    fn repeat(&mut self) {
+        if let Some(key_event) = self.repeat.due() {
+            send_key(key_event);
+        }
+    }
+}
+
+/// Inject a key, mouse or scroll event, where the drivers send theirs
+fn inject(event: Event) -> Result<()> {
+    let _guard = InterruptGuard::new();
+    match event.to_option() {
+        EventOption::Key(key_event) => injector().key(key_event),
+        EventOption::Mouse(mouse_event) => {
+            // The drivers move the cursor on from here
+            let (x, y) = cursor_set(mouse_event.x, mouse_event.y);
+            focus::send(MouseEvent {
+                x: x,
+                y: y,
+                .. mouse_event
+            }.to_event().synthetic());
+        },
+        EventOption::Scroll(_) => focus::send(event.synthetic()),
+        _ => return Err(Error::new(EINVAL)),
+    }
+    Ok(())
+}
+
+/// Repeat the held keys until injection is disabled, when they are released
+fn repeater() {
+    Context::spawn("kinput".into(), box move || {
+        loop {
+            {
+                let _guard = InterruptGuard::new();
+                if ! unsafe { INPUT_ENABLED } {
+                    injector().release();
+                    unsafe { INPUT_REPEATING = false };
+                    break;
+                }
+                injector().repeat();
+            }
+
+            timekeeping::sleep(10, "Input repeat sleep");
+        }
+    });
+}
+
+fn set_enabled(enabled: bool) {
+    let _guard = InterruptGuard::new();
+    unsafe {
+        if enabled && ! INPUT_ENABLED {
+            syslog_info!("Input injection enabled");
+        } else if ! enabled && INPUT_ENABLED {
+            syslog_info!("Input injection disabled");
+        }
+        INPUT_ENABLED = enabled;
+        if enabled && ! INPUT_REPEATING {
+            INPUT_REPEATING = true;
+            repeater();
+        }
+    }
+}
+
+/// `input:` takes key, mouse and scroll events and sends them on as if a driver had, marked synthetic
+///
+/// The events are framed or raw as those read from `display:`, each write holds whole events. The keys
+/// held are released when the file is closed.
+pub struct InputResource {
+    decoder: EventDecoder,
+}
+
+impl Resource for InputResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box InputResource {
+            decoder: EventDecoder::new(),
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = b"input:";
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        // Checked on every write, so disabling takes effect for the files already open
+        if ! unsafe { INPUT_ENABLED } {
+            return Err(Error::new(EACCES));
+        }
+
+        let mut i = 0;
+        while i < buf.len() {
+            match self.decoder.decode(&buf[i..]) {
+                (Decoded::Event(event), len) => {
+                    try!(inject(event));
+                    i += len;
+                },
+                (Decoded::Skipped, len) => i += len,
+                (Decoded::Incomplete, _) | (Decoded::Corrupt, _) => return Err(Error::new(EINVAL)),
+            }
+        }
+        Ok(i)
+    }
+}
+
+impl Drop for InputResource {
+    fn drop(&mut self) {
+        let _guard = InterruptGuard::new();
+        injector().release();
+    }
+}
+
+/// `input:enable` switches injection, `on` or `off`, which only a process with I/O privileges may do
+pub struct InputEnableResource {
+    seek: usize,
+}
+
+impl Resource for InputEnableResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box InputEnableResource {
+            seek: self.seek,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = b"input:enable";
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let bytes: &[u8] = if unsafe { INPUT_ENABLED } { b"on\n" } else { b"off\n" };
+        let mut i = 0;
+        while i < buf.len() && self.seek < bytes.len() {
+            buf[i] = bytes[self.seek];
+            i += 1;
+            self.seek += 1;
+        }
+        Ok(i)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let iopl = try!(unsafe { & *::env().contexts.get() }.current()).iopl;
+        if iopl != 3 {
+            return Err(Error::new(EPERM));
+        }
+
+        match str::from_utf8(buf).unwrap_or("").trim() {
+            "on" => set_enabled(true),
+            "off" => set_enabled(false),
+            _ => return Err(Error::new(EINVAL)),
+        }
+        Ok(buf.len())
+    }
+}
+
+/// The input scheme, for on screen keyboards and scripts to type and point as a device would
+pub struct InputScheme;
+
+impl KScheme for InputScheme {
+    fn scheme(&self) -> &str {
+        "input"
+    }
+
+    fn open(&mut self, url: &str, _flags: usize) -> Result<Box<Resource>> {
+        match url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/') {
+            "" => if unsafe { INPUT_ENABLED } {
+                Ok(box InputResource {
+                    decoder: EventDecoder::new(),
+                })
+            } else {
+                Err(Error::new(EACCES))
+            },
+            "enable" => Ok(box InputEnableResource {
+                seek: 0,
+            }),
+            _ => Err(Error::new(ENOENT)),
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use alloc::boxed::Box;
+
+use collections::string::{String, ToString};
+use collections::vec::Vec;
+
+use core::{cmp, str};
+
+use env::irq::{lines, mode, route};
+
+use fs::{KScheme, Resource, ResourceSeek};
+
+use system::error::{Error, Result, EINVAL, ENOENT};
+
+/// The counts of every line, and the schemes that claimed interrupts on it
+///
+/// Schemes are given by name, or by their id in `sys:/scheme` if they have none. The timer on line 0
+/// is handled by the kernel itself. With the IOAPIC the GSI of each line is given with its trigger and
+/// polarity, and the lines from 16 are listed.
+fn report() -> String {
+    let stats = unsafe { & *::env().irqs.get() };
+    let schemes = ::env().schemes.snapshot();
+    let mut string = format!("since {}.{:>03}\n", stats.since.secs, stats.since.nanos/1000000);
+    string.push_str(&format!("mode: {:?}\n", mode()));
+    string.push_str(&format!("{:<6}{:<16}{:<10}{:<11}{:<20}{}\n", "IRQ", "COUNT", "SPURIOUS", "UNCLAIMED", "ROUTE",
+                             "HANDLERS"));
+
+    for irq in 0..lines() {
+        let line = &stats.lines[irq];
+
+        let mut handlers = String::new();
+        if irq == 0 {
+            handlers.push_str(&format!(" timer:{}", line.count));
+        }
+        for entry in schemes.iter() {
+            let count = entry.irqs()[irq];
+            if count > 0 {
+                if entry.name.is_empty() {
+                    handlers.push_str(&format!(" #{}:{}", entry.id, count));
+                } else {
+                    handlers.push_str(&format!(" {}:{}", entry.name, count));
+                }
+            }
+        }
+
+        let routed = match route(irq) {
+            Some(route) => format!("{}/{:?}/{:?}{}", route.gsi, route.trigger, route.polarity,
+                                   if route.masked { "/masked" } else { "" }),
+            None => "-".to_string(),
+        };
+
+        string.push_str(&format!("{:<6}{:<16}{:<10}{:<11}{:<20}{}\n", irq, line.count, line.spurious, line.unclaimed,
+                                 routed, handlers.trim_left()));
+    }
+
+    string
+}
+
+/// The interrupt counts, read when opened, writing `reset` zeroes them and reads them again
+pub struct IrqResource {
+    data: Vec<u8>,
+    seek: usize,
+}
+
+impl Resource for IrqResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box IrqResource {
+            data: self.data.clone(),
+            seek: self.seek,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = b"irq:";
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut i = 0;
+        while i < buf.len() && self.seek < self.data.len() {
+            buf[i] = self.data[self.seek];
+            i += 1;
+            self.seek += 1;
+        }
+        Ok(i)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match str::from_utf8(buf).map(|string| string.trim()) {
+            Ok("reset") => {
+                unsafe { &mut *::env().irqs.get() }.reset();
+                self.data = report().into_bytes();
+                self.seek = 0;
+                Ok(buf.len())
+            },
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+
+    fn seek(&mut self, pos: ResourceSeek) -> Result<usize> {
+        self.seek = match pos {
+            ResourceSeek::Start(offset) => cmp::min(self.data.len(), offset),
+            ResourceSeek::Current(offset) => cmp::max(0, cmp::min(self.data.len() as isize, self.seek as isize + offset)) as usize,
+            ResourceSeek::End(offset) => cmp::max(0, cmp::min(self.data.len() as isize, self.data.len() as isize + offset)) as usize,
+        };
+        Ok(self.seek)
+    }
+}
+
+/// Interrupt statistics of each IRQ line, like `/proc/interrupts`
+pub struct IrqScheme;
+
+impl KScheme for IrqScheme {
+    fn scheme(&self) -> &str {
+        "irq"
+    }
+
+    fn open(&mut self, url: &str, _flags: usize) -> Result<Box<Resource>> {
+        if ! url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/').is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+
+        Ok(box IrqResource {
+            data: report().into_bytes(),
+            seek: 0,
+        })
+    }
+}
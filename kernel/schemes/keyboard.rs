@@ -0,0 +1,318 @@
+use alloc::boxed::Box;
+
+use collections::string::String;
+use collections::vec::Vec;
+
+use common::event::{Decoded, EventDecoder, EventOption, KeyEvent, K_ALT, K_CAPS, K_CTRL, K_LEFT_SHIFT, K_RIGHT_SHIFT};
+use common::time::{self, Duration};
+
+use core::{cmp, str};
+
+use drivers::kb_layouts::layouts::{Layout, LAYOUTS};
+use drivers::ps2;
+
+use env::focus;
+use env::log::InterruptGuard;
+
+use fs::{KScheme, Resource};
+
+use system::error::{Error, Result, EINVAL, ENOENT, EPERM};
+
+/// The lock LEDs, in the order of the PS/2 set LEDs command
+pub const LED_SCROLL: u8 = 1;
+pub const LED_NUM: u8 = 2;
+pub const LED_CAPS: u8 = 4;
+
+static LED_NAMES: [(u8, &'static str); 3] = [(LED_CAPS, "caps"), (LED_NUM, "num"), (LED_SCROLL, "scroll")];
+
+/// Milliseconds a key is held before it repeats, and between repeats, the defaults a PS/2 keyboard is set to
+const REPEAT_DELAY: i32 = 500;
+const REPEAT_INTERVAL: i32 = 92;
+/// The keys that do not repeat
+const NO_REPEAT: [u8; 5] = [K_LEFT_SHIFT, K_RIGHT_SHIFT, K_CTRL, K_ALT, K_CAPS];
+
+/// The key repeat of a keyboard, done in software so every kind of keyboard repeats the same way
+///
+/// The driver passes each key event through `key`, and sends what `due` gives while a key is held. As with
+/// a PS/2 keyboard, the last key pressed repeats until it is released or another key is pressed.
+pub struct KeyRepeat {
+    held: Vec<u8>,
+    /// The key repeating and when it next repeats
+    repeat: Option<(KeyEvent, Duration)>,
+}
+
+impl KeyRepeat {
+    pub fn new() -> KeyRepeat {
+        KeyRepeat {
+            held: Vec::new(),
+            repeat: None,
+        }
+    }
+
+    /// Track `key_event`, returning false for a press of a key already held, which the keyboard repeated itself
+    pub fn key(&mut self, key_event: &KeyEvent) -> bool {
+        let held = self.held.iter().position(|&scancode| scancode == key_event.scancode);
+        if key_event.pressed {
+            if held.is_some() {
+                return false;
+            }
+            self.held.push(key_event.scancode);
+            self.repeat = if NO_REPEAT.contains(&key_event.scancode) {
+                None
+            } else {
+                Some((*key_event, Duration::monotonic() + Duration::new(0, REPEAT_DELAY * time::NANOS_PER_MILLI)))
+            };
+        } else {
+            if let Some(i) = held {
+                self.held.remove(i);
+            }
+            if self.repeat.map_or(false, |(repeat, _)| repeat.scancode == key_event.scancode) {
+                self.repeat = None;
+            }
+        }
+        true
+    }
+
+    /// The press of the repeating key, once it is due
+    pub fn due(&mut self) -> Option<KeyEvent> {
+        if let Some((key_event, at)) = self.repeat {
+            let now = Duration::monotonic();
+            if now >= at {
+                self.repeat = Some((key_event, now + Duration::new(0, REPEAT_INTERVAL * time::NANOS_PER_MILLI)));
+                return Some(key_event);
+            }
+        }
+        None
+    }
+
+    /// Forget the keys held, ending the repeat
+    pub fn clear(&mut self) {
+        self.held.clear();
+        self.repeat = None;
+    }
+}
+
+/// The LEDs set through `keyboard:leds`, `None` while they follow the lock keys
+static mut OVERRIDE: Option<u8> = None;
+/// The file that set the override
+static mut OWNER: usize = 0;
+
+/// The LEDs a keyboard should light with the locks in `locks` on
+pub fn leds(locks: u8) -> u8 {
+    unsafe { OVERRIDE }.unwrap_or(locks)
+}
+
+fn set_override(owner: usize, leds: Option<u8>) {
+    unsafe {
+        OWNER = owner;
+        OVERRIDE = leds;
+    }
+    // The USB keyboards see it when they next poll
+    ps2::update_leds();
+}
+
+/// `keyboard:leds` sets the LEDs of every keyboard
+///
+/// Writing names from `caps`, `num` and `scroll` lights those and no others, until `auto` is written or
+/// the file that set them is closed, when they follow the lock keys again. Reading gives what is set, or
+/// `auto`.
+pub struct KeyboardResource {
+    seek: usize,
+}
+
+impl KeyboardResource {
+    fn id(&self) -> usize {
+        self as *const KeyboardResource as usize
+    }
+}
+
+impl Resource for KeyboardResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box KeyboardResource {
+            seek: self.seek,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = b"keyboard:leds";
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut string = String::new();
+        match unsafe { OVERRIDE } {
+            Some(leds) => for &(led, name) in LED_NAMES.iter().filter(|&&(led, _)| leds & led == led) {
+                if ! string.is_empty() {
+                    string.push(' ');
+                }
+                string.push_str(name);
+            },
+            None => string.push_str("auto"),
+        }
+        string.push('\n');
+
+        let bytes = string.as_bytes();
+        let mut i = 0;
+        while i < buf.len() && self.seek < bytes.len() {
+            buf[i] = bytes[self.seek];
+            i += 1;
+            self.seek += 1;
+        }
+        Ok(i)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let string = str::from_utf8(buf).unwrap_or("").trim();
+        if string == "auto" {
+            set_override(0, None);
+        } else {
+            let mut leds = 0;
+            for word in string.split_whitespace() {
+                match LED_NAMES.iter().find(|&&(_, name)| name == word) {
+                    Some(&(led, _)) => leds |= led,
+                    None => return Err(Error::new(EINVAL)),
+                }
+            }
+            set_override(self.id(), Some(leds));
+        }
+        Ok(buf.len())
+    }
+}
+
+impl Drop for KeyboardResource {
+    fn drop(&mut self) {
+        if unsafe { OWNER } == self.id() {
+            set_override(0, None);
+        }
+    }
+}
+
+/// `keyboard:layout` is the default layout and that of the focus, as `default: NAME` and `focused: NAME`
+///
+/// Writing a name sets the default, which only a process with I/O privileges may do.
+pub struct LayoutResource {
+    seek: usize,
+}
+
+impl Resource for LayoutResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box LayoutResource {
+            seek: self.seek,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = b"keyboard:layout";
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let default = {
+            let _guard = InterruptGuard::new();
+            unsafe { & *::env().focus.get() }.default_layout()
+        };
+        let string = format!("default: {}\nfocused: {}\n", default.name(), focus::layout().name());
+
+        let bytes = string.as_bytes();
+        let mut i = 0;
+        while i < buf.len() && self.seek < bytes.len() {
+            buf[i] = bytes[self.seek];
+            i += 1;
+            self.seek += 1;
+        }
+        Ok(i)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let iopl = try!(unsafe { & *::env().contexts.get() }.current()).iopl;
+        if iopl != 3 {
+            return Err(Error::new(EPERM));
+        }
+
+        let layout = try!(Layout::from_name(str::from_utf8(buf).unwrap_or("").trim()).ok_or(Error::new(EINVAL)));
+        {
+            let _guard = InterruptGuard::new();
+            unsafe { &mut *::env().focus.get() }.set_default_layout(layout);
+        }
+        syslog_info!("Keyboard: default layout {}", layout.name());
+        Ok(buf.len())
+    }
+}
+
+/// `keyboard:control` takes `LayoutRequest` events, framed or raw, for the layout of the focus
+pub struct ControlResource {
+    decoder: EventDecoder,
+}
+
+impl Resource for ControlResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box ControlResource {
+            decoder: EventDecoder::new(),
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = b"keyboard:control";
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut i = 0;
+        while i < buf.len() {
+            match self.decoder.decode(&buf[i..]) {
+                (Decoded::Event(event), len) => {
+                    match event.to_option() {
+                        EventOption::Layout(request) => match request.layout {
+                            Some(layout) => focus::set_layout(Some(*try!(LAYOUTS.get(layout).ok_or(Error::new(EINVAL))))),
+                            None => focus::set_layout(None),
+                        },
+                        _ => return Err(Error::new(EINVAL)),
+                    }
+                    i += len;
+                },
+                (Decoded::Skipped, len) => i += len,
+                (Decoded::Incomplete, _) | (Decoded::Corrupt, _) => return Err(Error::new(EINVAL)),
+            }
+        }
+        Ok(i)
+    }
+}
+
+/// The keyboard scheme
+pub struct KeyboardScheme;
+
+impl KScheme for KeyboardScheme {
+    fn scheme(&self) -> &str {
+        "keyboard"
+    }
+
+    fn open(&mut self, url: &str, _flags: usize) -> Result<Box<Resource>> {
+        match url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/') {
+            "leds" => Ok(box KeyboardResource {
+                seek: 0,
+            }),
+            "layout" => Ok(box LayoutResource {
+                seek: 0,
+            }),
+            "control" => Ok(box ControlResource {
+                decoder: EventDecoder::new(),
+            }),
+            _ => Err(Error::new(ENOENT)),
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use alloc::boxed::Box;
+
+use core::cmp;
+
+use env::log::InterruptGuard;
+
+use fs::{KScheme, Resource};
+
+use system::error::{Error, Result, ENOENT};
+
+/// A reader of the kernel log
+///
+/// Reading `log:` gives what was logged and then ends, reading `log:/follow` waits for more once it has
+/// read everything, like `dmesg -w`. Lines dropped from the ring before they were read are skipped.
+pub struct LogResource {
+    /// The position of the next byte to read, counted from the first byte ever logged
+    pos: usize,
+    follow: bool,
+}
+
+impl Resource for LogResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box LogResource {
+            pos: self.pos,
+            follow: self.follow,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path: &[u8] = if self.follow { b"log:/follow" } else { b"log:" };
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let log = unsafe { &mut *::env().log.get() };
+        loop {
+            {
+                // Nothing can be logged between finding the end and waiting, so no line is missed
+                let _guard = InterruptGuard::new();
+                let (count, pos) = log.read_from(self.pos, buf);
+                self.pos = pos;
+                if count > 0 || ! self.follow || buf.is_empty() {
+                    return Ok(count);
+                }
+                log.condition.wait("LogResource::read");
+            }
+        }
+    }
+}
+
+/// The kernel log scheme
+pub struct LogScheme;
+
+impl KScheme for LogScheme {
+    fn scheme(&self) -> &str {
+        "log"
+    }
+
+    fn open(&mut self, url: &str, _flags: usize) -> Result<Box<Resource>> {
+        let follow = match url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/') {
+            "" => false,
+            "follow" => true,
+            _ => return Err(Error::new(ENOENT)),
+        };
+
+        Ok(box LogResource {
+            pos: 0,
+            follow: follow,
+        })
+    }
+}
@@ -0,0 +1,55 @@
+use alloc::boxed::Box;
+
+use arch::memory::{self, CLUSTER_SIZE};
+
+use collections::string::ToString;
+
+use core::cmp;
+
+use env::log::InterruptGuard;
+
+use fs::{KScheme, Resource, VecResource};
+
+use system::error::{Error, Result, ENOENT};
+use system::syscall::MODE_FILE;
+
+/// The memory scheme
+///
+/// `memory:` has the physical memory in KB, then the counters of the cluster allocator that `brk`,
+/// contexts and drivers allocate from, and of the kernel heap on top of it, one `key: value` a line.
+/// The counters are kept as memory is allocated, so this is cheap to read as often as wanted.
+pub struct MemoryScheme;
+
+impl KScheme for MemoryScheme {
+    fn scheme(&self) -> &str {
+        "memory"
+    }
+
+    fn open(&mut self, url: &str, _flags: usize) -> Result<Box<Resource>> {
+        if ! url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/').is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+
+        let stats = {
+            let _guard = InterruptGuard::new();
+            memory::alloc_stats()
+        };
+        let free = stats.total - cmp::min(stats.total, stats.used);
+        let string = format!("total: {} KB\nused: {} KB\nfree: {} KB\npeak: {} KB\ncluster: {} B\n\
+                              allocations: {}\nfrees: {}\nfailures: {}\n\
+                              heap: {} KB\nheap_allocations: {}\nheap_frees: {}\n",
+                             stats.total * CLUSTER_SIZE / 1024,
+                             stats.used * CLUSTER_SIZE / 1024,
+                             free * CLUSTER_SIZE / 1024,
+                             stats.peak * CLUSTER_SIZE / 1024,
+                             CLUSTER_SIZE,
+                             stats.allocations,
+                             stats.frees,
+                             stats.failures,
+                             stats.heap_bytes / 1024,
+                             stats.heap_allocations,
+                             stats.heap_frees);
+
+        Ok(box VecResource::new("memory:".to_string(), string.into_bytes(), MODE_FILE))
+    }
+}
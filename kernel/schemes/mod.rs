@@ -1,16 +1,38 @@
+/// Processor information
+pub mod cpu;
 /// Debug scheme
 pub mod debug;
+/// Driver self checks
+pub mod diagnostics;
 /// Disk scheme
 pub mod disk;
 /// Display Scheme
 pub mod display;
+/// PCI driver summary
+pub mod drivers;
 /// Environment variables scheme
 pub mod env;
+/// Event queues for files
+pub mod event;
 /// Init Filesystem
 pub mod initfs;
+/// Input injection
+pub mod input;
+/// Interrupt statistics
+pub mod irq;
+/// Keyboard LEDs
+pub mod keyboard;
+/// Kernel log
+pub mod log;
+/// Memory information
+pub mod memory;
 /// Pipes
 pub mod pipe;
 /// Psuedoterminals
 pub mod pty;
+/// Random numbers
+pub mod random;
 /// Sys scheme
 pub mod sys;
+/// Wall clock
+pub mod time;
@@ -1,6 +1,8 @@
 use alloc::arc::{Arc, Weak};
 use alloc::boxed::Box;
 
+use common::event::IO_READ;
+
 use core::cmp;
 
 use fs::Resource;
@@ -62,6 +64,14 @@ impl Resource for PipeRead {
             Ok(i)
         }
     }
+
+    fn ready(&mut self) -> i64 {
+        if ! unsafe { self.vec.inner() }.is_empty() || Arc::weak_count(&self.vec) == 0 {
+            IO_READ
+        } else {
+            0
+        }
+    }
 }
 
 /// Read side of a pipe
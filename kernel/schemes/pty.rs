@@ -3,6 +3,8 @@ use alloc::boxed::Box;
 
 use collections::{Vec, VecDeque};
 
+use common::event::{IO_READ, IO_WRITE};
+
 use core::cmp;
 
 use fs::{KScheme, Resource};
@@ -127,6 +129,10 @@ impl Resource for PtyMaster {
 
         Ok(buf.len())
     }
+
+    fn ready(&mut self) -> i64 {
+        if unsafe { self.inner.output.inner() }.is_empty() { IO_WRITE } else { IO_READ | IO_WRITE }
+    }
 }
 
 /// Psuedoterminal slave
@@ -210,4 +216,11 @@ impl Resource for PtySlave {
         }
         Ok(())
     }
+
+    fn ready(&mut self) -> i64 {
+        match self.inner.upgrade() {
+            Some(ref inner) if unsafe { inner.input.inner() }.is_empty() => IO_WRITE,
+            _ => IO_READ | IO_WRITE,
+        }
+    }
 }
@@ -0,0 +1,77 @@
+use alloc::boxed::Box;
+
+use collections::vec::Vec;
+
+use common::random;
+
+use core::cmp;
+
+use fs::{KScheme, Resource};
+
+use system::error::{Error, Result, ENOENT};
+
+/// Random bytes, from `random:`, or the entropy estimate, from `random:/entropy`
+pub struct RandomResource {
+    /// The estimate as it was when opened, `None` for random bytes
+    status: Option<Vec<u8>>,
+    seek: usize,
+}
+
+impl Resource for RandomResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box RandomResource {
+            status: self.status.clone(),
+            seek: self.seek,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path: &[u8] = if self.status.is_some() { b"random:/entropy" } else { b"random:" };
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self.status {
+            Some(ref status) => {
+                let mut i = 0;
+                while i < buf.len() && self.seek < status.len() {
+                    buf[i] = status[self.seek];
+                    i += 1;
+                    self.seek += 1;
+                }
+                Ok(i)
+            },
+            None => {
+                random::fill(buf);
+                Ok(buf.len())
+            }
+        }
+    }
+}
+
+/// The random number scheme, which never blocks
+pub struct RandomScheme;
+
+impl KScheme for RandomScheme {
+    fn scheme(&self) -> &str {
+        "random"
+    }
+
+    fn open(&mut self, url: &str, _flags: usize) -> Result<Box<Resource>> {
+        let status = match url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/') {
+            "" => None,
+            "entropy" => Some(random::status().into_bytes()),
+            _ => return Err(Error::new(ENOENT)),
+        };
+
+        Ok(box RandomResource {
+            status: status,
+            seek: 0,
+        })
+    }
+}
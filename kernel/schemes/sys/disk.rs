@@ -8,9 +8,13 @@ use system::error::Result;
 use system::syscall::MODE_FILE;
 
 pub fn resource() -> Result<Box<Resource>> {
-    let mut string = format!("{:<6}{:<10}{}\n", "PATH", "SIZE", "NAME");
+    let mut string = format!("{:<6}{:<10}{:<6}{:<24}{}\n", "PATH", "SIZE", "BAD", "SERIAL", "NAME");
 
     for (i, disk) in unsafe { &mut *::env().disks.get() }.iter().enumerate() {
+        let disk = match *disk {
+            Some(ref disk) => disk,
+            None => continue,
+        };
         let size = unsafe { & *disk.get() }.size();
         let size_string = if size >= 1024 * 1024 * 1024 {
             format!("{} GB", size / 1024 / 1024 / 1024)
@@ -21,7 +25,8 @@ pub fn resource() -> Result<Box<Resource>> {
         } else {
             format!("{} B", size)
         };
-        string.push_str(&format!("{:<6}{:<10}{}\n", i, size_string, unsafe { & *disk.get() }.name()));
+        let bad = unsafe { &mut *disk.get() }.stats().bad_sectors.len();
+        string.push_str(&format!("{:<6}{:<10}{:<6}{:<24}{}\n", i, size_string, bad, unsafe { & *disk.get() }.serial(), unsafe { & *disk.get() }.name()));
     }
 
     Ok(box VecResource::new("sys:/disk".to_string(), string.into_bytes(), MODE_FILE))
@@ -28,8 +28,8 @@ impl Resource for SyslogResource {
     /// - `DEBUG`
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         let log = unsafe { & *::env().log.get() };
-        let count = log.read_at(self.pos, buf);
-        self.pos += count;
+        let (count, pos) = log.read_from(self.pos, buf);
+        self.pos = pos;
         Ok(count)
     }
 }
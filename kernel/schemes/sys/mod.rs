@@ -12,7 +12,8 @@ mod disk;
 mod interrupt;
 mod log;
 mod memory;
-mod test;
+mod scheme;
+pub mod test;
 
 /// System information scheme
 pub struct SysScheme {
@@ -28,7 +29,9 @@ impl SysScheme {
         files.insert("interrupt", box move || interrupt::resource());
         files.insert("log", box move || log::resource());
         files.insert("memory", box move || memory::resource());
+        files.insert("scheme", box move || scheme::resource());
         files.insert("test", box move || test::resource());
+        files.insert("test/rtc", box move || test::rtc_resource());
 
         Box::new(SysScheme {
             files: files
@@ -0,0 +1,30 @@
+use alloc::boxed::Box;
+
+use collections::string::ToString;
+
+use fs::{Resource, VecResource};
+
+use system::error::Result;
+use system::syscall::MODE_FILE;
+
+pub fn resource() -> Result<Box<Resource>> {
+    let mut string = format!("{:<6}{:<16}{:<16}{}\n", "ID", "NAME", "SCHEME", "RESOURCES");
+
+    {
+        let schemes = &::env().schemes;
+        let mut unnamed = 0;
+        for entry in schemes.snapshot().iter() {
+            if entry.name.is_empty() {
+                unnamed += 1;
+            } else {
+                string.push_str(&format!("{:<6}{:<16}{:<16}{}\n", entry.id, entry.name, entry.scheme().scheme(), entry.resources()));
+            }
+        }
+        for entry in schemes.removed().iter() {
+            string.push_str(&format!("{:<6}{:<16}{:<16}{} removed\n", entry.id, entry.name, entry.scheme().scheme(), entry.resources()));
+        }
+        string.push_str(&format!("{} interrupt handlers without a name\n", unnamed));
+    }
+
+    Ok(box VecResource::new("sys:/scheme".to_string(), string.into_bytes(), MODE_FILE))
+}
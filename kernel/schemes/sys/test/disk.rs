@@ -0,0 +1,128 @@
+pub fn test() -> bool {
+    use collections::vec::Vec;
+
+    use disk::{chunks, Chunk, Limits, PartError};
+
+    use system::error::{Error, EIO};
+
+    fn layout(chunks: &[Chunk]) -> Vec<(u64, usize, Vec<(usize, usize)>)> {
+        chunks.iter().map(|chunk| (chunk.block, chunk.sectors, chunk.regions.clone())).collect()
+    }
+
+    let limits = Limits {
+        max_sectors: 8,
+        max_regions: 2,
+        max_region_len: 2048,
+    };
+
+    // A region longer than an entry takes several, and a command ends when its sectors or entries run out
+    let split = chunks(100, &[(0x10000, 5120), (0x40000, 512), (0x50000, 1024)], limits).unwrap();
+    test!(layout(&split) == vec![
+        (100, 8, vec![(0x10000, 2048), (0x10800, 2048)]),
+        (108, 3, vec![(0x11000, 1024), (0x40000, 512)]),
+        (111, 2, vec![(0x50000, 1024)]),
+    ]);
+
+    // A region is cut where the sectors of a command run out
+    let limits = Limits {
+        max_sectors: 3,
+        max_regions: 16,
+        max_region_len: 65536,
+    };
+    let split = chunks(0, &[(0x20000, 2048)], limits).unwrap();
+    test!(layout(&split) == vec![(0, 3, vec![(0x20000, 1536)]), (3, 1, vec![(0x20600, 512)])]);
+
+    // A part failing is reported at the byte it starts at
+    let part = PartError::new(&split, 1, Error::new(EIO));
+    test!(part.part == 1 && part.parts == 2 && part.offset == 1536);
+
+    // Sectors are not split between regions
+    test!(chunks(0, &[(0x20000, 700)], limits).is_err());
+    test!(chunks(0, &[], limits).unwrap().is_empty());
+
+    succ!();
+}
+
+/// Partitions are read from a GUID partition table, and found by their GUID in either case
+pub fn partitions_test() -> bool {
+    use collections::vec::Vec;
+
+    use disk::gpt::{entries_at, format_guid, parse_entries, parse_guid};
+
+    const GUID: [u8; 16] = [0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47, 0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d, 0xe4];
+
+    test!(parse_guid("0fc63daf-8483-4772-8e79-3d69d8477de4") == Some(GUID));
+    test!(parse_guid("0FC63DAF-8483-4772-8E79-3D69D8477DE4") == Some(GUID));
+    test!(format_guid(&GUID) == "0fc63daf-8483-4772-8e79-3d69d8477de4");
+    test!(parse_guid("0fc63daf-8483-4772-8e79").is_none() && parse_guid("0fc63daf-8483-4772-8e79-3d69d8477dez").is_none());
+
+    // The header gives the entries at block 2, four of 128 bytes
+    let mut header = vec![0; 512];
+    for (b, h) in b"EFI PART".iter().zip(header.iter_mut()) {
+        *h = *b;
+    }
+    header[72] = 2;
+    header[80] = 4;
+    header[84] = 128;
+    test!(entries_at(&header).ok() == Some((2, 4, 128)));
+    header[84] = 100;
+    test!(entries_at(&header).is_err());
+    test!(entries_at(&[0; 512]).is_err());
+
+    // The second entry has no type, so no partition is there
+    let mut entries = vec![0; 4 * 128];
+    for (i, name) in [Some("redox"), None, Some("swap"), None].iter().enumerate() {
+        if let Some(name) = *name {
+            let entry = &mut entries[i * 128 .. (i + 1) * 128];
+            entry[0] = 1;
+            for (b, g) in entry[16 .. 32].iter_mut().zip(GUID.iter()) {
+                *b = *g + i as u8;
+            }
+            for (j, c) in name.bytes().enumerate() {
+                entry[56 + j * 2] = c;
+            }
+        }
+    }
+    let partitions = parse_entries(&entries, 128);
+    test!(partitions.iter().map(|partition| &partition.label[..]).collect::<Vec<&str>>() == vec!["redox", "swap"]);
+    test!(partitions[0].has_guid("0FC63DAF-8483-4772-8e79-3d69d8477de4") && ! partitions[1].has_guid("0fc63daf-8483-4772-8e79-3d69d8477de4"));
+
+    // A table cut short leaves out the entry it cuts
+    test!(parse_entries(&entries[.. 3 * 128 - 1], 128).len() == 1);
+
+    succ!();
+}
+
+/// Reads of cached sectors are counted as hits, and the oldest sectors make room for new ones
+pub fn cache_test() -> bool {
+    use collections::vec::Vec;
+
+    use disk::cache::BlockCache;
+
+    let mut cache = BlockCache::new(4);
+    let mut buf = vec![0; 1024];
+
+    test!(! cache.get(10, &mut buf) && cache.misses == 1);
+
+    let data: Vec<u8> = (0..1536).map(|i| (i / 512) as u8 + 1).collect();
+    cache.insert(10, &data);
+    test!(cache.get(11, &mut buf) && buf[.. 512].iter().all(|&b| b == 2) && buf[512 ..].iter().all(|&b| b == 3));
+    test!(cache.hits == 1);
+
+    // A request is only a hit when every sector of it is cached
+    test!(! cache.get(12, &mut buf) && cache.misses == 2);
+
+    // Sector 10 is the oldest, so two more push it out
+    cache.insert(13, &data[.. 1024]);
+    test!(! cache.get(10, &mut buf[.. 512]) && cache.get(14, &mut buf[.. 512]));
+
+    // A write drops what it overwrites until it is done
+    cache.invalidate(13, 600);
+    test!(! cache.get(13, &mut buf[.. 512]) && ! cache.get(14, &mut buf[.. 512]) && cache.get(12, &mut buf[.. 512]));
+
+    test!(cache.to_string() == "cache_hits: 3\ncache_misses: 5\ncache_sectors: 2\n");
+    cache.reset();
+    test!(cache.hits == 0 && cache.misses == 0 && cache.get(11, &mut buf[.. 512]));
+
+    succ!();
+}
@@ -0,0 +1,151 @@
+use collections::vec::Vec;
+
+use network::schemes::dns::{answer, query, DNS_CLASS_IN, DNS_TYPE_A, DNS_TYPE_CNAME};
+
+use system::dns::{parse_addr, parse_addrs};
+
+const ID: u16 = 0x1234;
+
+fn push_u16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.push((value >> 8) as u8);
+    bytes.push(value as u8);
+}
+
+fn push_name(bytes: &mut Vec<u8>, name: &str) {
+    for label in name.split('.') {
+        bytes.push(label.len() as u8);
+        bytes.extend_from_slice(label.as_bytes());
+    }
+    bytes.push(0);
+}
+
+/// The header and question of a response, the question name is at offset 12
+fn response(answers: u16) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    push_u16(&mut bytes, ID);
+    push_u16(&mut bytes, 0x8180);
+    push_u16(&mut bytes, 1);
+    push_u16(&mut bytes, answers);
+    bytes.extend_from_slice(&[0; 4]);
+    push_name(&mut bytes, "Example.com");
+    push_u16(&mut bytes, DNS_TYPE_A);
+    push_u16(&mut bytes, DNS_CLASS_IN);
+    bytes
+}
+
+/// The record fields after its name
+fn push_record(bytes: &mut Vec<u8>, kind: u16, ttl: u32, data: &[u8]) {
+    push_u16(bytes, kind);
+    push_u16(bytes, DNS_CLASS_IN);
+    push_u16(bytes, (ttl >> 16) as u16);
+    push_u16(bytes, ttl as u16);
+    push_u16(bytes, data.len() as u16);
+    bytes.extend_from_slice(data);
+}
+
+/// example.com is a CNAME for www.example.net, which has two addresses
+fn valid() -> Vec<u8> {
+    let mut bytes = response(3);
+
+    push_u16(&mut bytes, 0xC00C);
+    let mut target = Vec::new();
+    push_name(&mut target, "www.example.net");
+    let target_at = bytes.len() as u16 + 10;
+    push_record(&mut bytes, DNS_TYPE_CNAME, 300, &target);
+
+    for &(ttl, last) in [(60, 34), (120, 35)].iter() {
+        push_u16(&mut bytes, 0xC000 | target_at);
+        push_record(&mut bytes, DNS_TYPE_A, ttl, &[93, 184, 216, last]);
+    }
+    bytes
+}
+
+/// Responses are read with the CNAME chain followed, and malformed or truncated ones are rejected
+pub fn test() -> bool {
+    let addrs = |bytes: &[u8]| answer(bytes, ID, "example.com").map(|(addrs, ttl)| {
+        (addrs.iter().map(|addr| addr.bytes).collect::<Vec<[u8; 4]>>(), ttl)
+    });
+
+    // A query is the question of a response without the answers
+    test!(query(ID, "Example.com").map_or(false, |query| query[4 ..] == response(0)[4 ..]));
+    test!(query(ID, "example..com").is_none());
+
+    let bytes = valid();
+    test!(addrs(&bytes) == Some((vec![[93, 184, 216, 34], [93, 184, 216, 35]], 60)));
+
+    // Another id, a query, a truncated response and a server failure
+    test!(answer(&bytes, ID + 1, "example.com").is_none());
+    for &(offset, flags) in [(2, 0x01), (2, 0x83), (3, 0x82)].iter() {
+        let mut bad = bytes.clone();
+        bad[offset] = flags;
+        test!(addrs(&bad).is_none());
+    }
+
+    // A name that does not exist has no addresses
+    let mut missing = response(0);
+    missing[3] = 0x83;
+    test!(addrs(&missing) == Some((Vec::new(), u32::max_value())));
+
+    // Cut anywhere, the counts promise more than is there
+    for len in 0..bytes.len() {
+        test!(addrs(&bytes[.. len]).is_none());
+    }
+
+    // Data running past the end
+    let mut bad = bytes.clone();
+    let len_at = bad.len() - 6;
+    bad[len_at] = 0x10;
+    test!(addrs(&bad).is_none());
+
+    // A pointer to itself, a pointer forward and a label with the reserved bits set
+    for &(first, second) in [(0xC0, 29), (0xC0, 0xFF), (0x80, 0)].iter() {
+        let mut bad = response(1);
+        bad.push(first);
+        bad.push(second);
+        push_record(&mut bad, DNS_TYPE_A, 60, &[10, 0, 0, 1]);
+        test!(addrs(&bad).is_none());
+    }
+
+    // A name longer than 255 bytes
+    let mut bad = response(1);
+    for _ in 0..5 {
+        bad.push(63);
+        bad.extend_from_slice(&[b'a'; 63]);
+    }
+    bad.push(0);
+    push_record(&mut bad, DNS_TYPE_A, 60, &[10, 0, 0, 1]);
+    test!(addrs(&bad).is_none());
+
+    // A CNAME loop ends without addresses, and an A record of the wrong size is left out
+    let mut looped = response(3);
+    push_u16(&mut looped, 0xC00C);
+    let target_at = looped.len() as u16 + 10;
+    let mut target = Vec::new();
+    push_name(&mut target, "loop.example.com");
+    push_record(&mut looped, DNS_TYPE_CNAME, 60, &target);
+    push_u16(&mut looped, 0xC000 | target_at);
+    push_record(&mut looped, DNS_TYPE_CNAME, 60, &[0xC0, 12]);
+    push_u16(&mut looped, 0xC00C);
+    push_record(&mut looped, DNS_TYPE_A, 60, &[10, 0, 0]);
+    test!(addrs(&looped).map_or(false, |(addrs, _)| addrs.is_empty()));
+
+    succ!();
+}
+
+/// The helper for programs reads the addresses dns: gives, and rejects anything else
+pub fn helper_test() -> bool {
+    test!(parse_addr("10.0.2.3") == Some([10, 0, 2, 3]));
+    test!(parse_addr("10.0.2").is_none() && parse_addr("10.0.2.3.4").is_none() && parse_addr("10.0.2.256").is_none());
+    test!(parse_addr("10..2.3").is_none() && parse_addr("example.com").is_none());
+
+    let mut addrs = [[0; 4]; 2];
+    test!(parse_addrs(b"93.184.216.34\n93.184.216.35\n10.0.0.1\n", &mut addrs).ok() == Some(2));
+    test!(addrs == [[93, 184, 216, 34], [93, 184, 216, 35]]);
+    test!(parse_addrs(b"", &mut addrs).ok() == Some(0));
+
+    // A line cut short or garbage fails the whole list
+    test!(parse_addrs(b"93.184.216.34\n93.184.2", &mut addrs).is_err());
+    test!(parse_addrs(b"\xFF\xFE", &mut addrs).is_err());
+
+    succ!();
+}
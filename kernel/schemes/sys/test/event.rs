@@ -0,0 +1,221 @@
+pub fn test() -> bool {
+    use collections::vec::Vec;
+    use common::event::*;
+
+    let mouse = MouseEvent {
+        x: -5,
+        y: 700,
+        left_button: true,
+        middle_button: false,
+        right_button: true,
+        device: 2,
+    };
+    match mouse.to_event().to_option() {
+        EventOption::Mouse(event) => {
+            test!(event.x == mouse.x && event.y == mouse.y);
+            test!(event.left_button && ! event.middle_button && event.right_button);
+            test!(event.device == mouse.device);
+        },
+        _ => fail!(),
+    }
+
+    // Every character, the ones beside the surrogates and past the basic plane included
+    for &character in ['é', '\u{1}', '\u{D7FF}', '\u{E000}', '\u{FFFF}', '\u{10000}', '\u{1F600}', '\u{10FFFF}'].iter() {
+        let key = KeyEvent {
+            character: Some(character),
+            scancode: K_E,
+            pressed: true,
+        };
+        match key.to_event().to_option() {
+            EventOption::Key(event) => test!(event.character == key.character && event.scancode == key.scancode && event.pressed),
+            _ => fail!(),
+        }
+    }
+    for value in (1..0x110000).filter(|value| value % 0x3FF == 0) {
+        if let Some(character) = ::core::char::from_u32(value) {
+            match (KeyEvent { character: Some(character), scancode: 0, pressed: false }).to_event().to_option() {
+                EventOption::Key(event) => test!(event.character == Some(character)),
+                _ => fail!(),
+            }
+        }
+    }
+
+    let shift = KeyEvent {
+        character: None,
+        scancode: K_LEFT_SHIFT,
+        pressed: false,
+    };
+    match shift.to_event().to_option() {
+        EventOption::Key(event) => test!(event.character.is_none() && event.scancode == K_LEFT_SHIFT && ! event.pressed),
+        _ => fail!(),
+    }
+
+    // A value that is no character is unknown, rather than a key without one
+    for &value in [0xD800, 0xDFFF, 0x110000, -1, 0x1_0000_0041].iter() {
+        match (Event { code: EVENT_KEY, a: value, b: 0, c: 1 }).to_option() {
+            EventOption::Unknown(event) => test!(event.a == value),
+            _ => fail!(),
+        }
+    }
+
+    let scroll = ScrollEvent {
+        x: 0,
+        y: -3,
+        device: 1,
+    };
+    match scroll.to_event().to_option() {
+        EventOption::Scroll(event) => test!(event.x == scroll.x && event.y == scroll.y && event.device == scroll.device),
+        _ => fail!(),
+    }
+
+    // An injected event reads as the same event, a release stays one
+    test!(! shift.to_event().is_synthetic() && shift.to_event().synthetic().is_synthetic());
+    match shift.to_event().synthetic().to_option() {
+        EventOption::Key(event) => test!(event.scancode == K_LEFT_SHIFT && ! event.pressed),
+        _ => fail!(),
+    }
+    match mouse.to_event().synthetic().to_option() {
+        EventOption::Mouse(event) => test!(event.right_button && ! event.middle_button && event.device == mouse.device),
+        _ => fail!(),
+    }
+    test!(scroll.to_event().synthetic().is_synthetic() && ! (Event { code: EVENT_QUIT, a: 0, b: 0, c: EVENT_SYNTHETIC }).is_synthetic());
+
+    let link = LinkEvent {
+        up: true,
+        speed: 1000,
+        full_duplex: false,
+    };
+    match link.to_event().to_option() {
+        EventOption::Link(event) => test!(event.up && event.speed == 1000 && ! event.full_duplex),
+        _ => fail!(),
+    }
+
+    let audio = AudioEvent {
+        kind: AUDIO_JACK,
+        device: 1,
+        value: 0,
+    };
+    match audio.to_event().to_option() {
+        EventOption::Audio(event) => test!(event.kind == AUDIO_JACK && event.device == 1 && event.value == 0),
+        _ => fail!(),
+    }
+
+    let power = PowerEvent {
+        kind: POWER_REBOOT,
+        grace: 3000,
+    };
+    match power.to_event().to_option() {
+        EventOption::Power(event) => test!(event.kind == POWER_REBOOT && event.grace == 3000),
+        _ => fail!(),
+    }
+
+    let io = IoEvent {
+        id: 7,
+        flags: IO_READ | IO_WRITE,
+    };
+    match io.to_event().to_option() {
+        EventOption::Io(event) => test!(event.id == 7 && event.flags == IO_READ | IO_WRITE),
+        _ => fail!(),
+    }
+
+    match (RaiseRequest { raise: false }).to_event().to_option() {
+        EventOption::Raise(event) => test!(! event.raise),
+        _ => fail!(),
+    }
+
+    // Partly covered, fully covered, and visible again
+    for &(obscured, fully) in [(true, false), (true, true), (false, false)].iter() {
+        match (ObscuredEvent { obscured: obscured, fully: fully }).to_event().to_option() {
+            EventOption::Obscured(event) => test!(event.obscured == obscured && event.fully == fully),
+            _ => fail!(),
+        }
+    }
+
+    match (ResizeEvent { width: 1280, height: 800 }).to_event().to_option() {
+        EventOption::Resize(event) => test!(event.width == 1280 && event.height == 800),
+        _ => fail!(),
+    }
+    match RedrawEvent.to_event().to_option() {
+        EventOption::Redraw(_) => (),
+        _ => fail!(),
+    }
+    for &layout in [None, Some(0), Some(2)].iter() {
+        match (LayoutRequest { layout: layout }).to_event().to_option() {
+            EventOption::Layout(request) => test!(request.layout == layout),
+            _ => fail!(),
+        }
+    }
+    match (FocusEvent { focused: true }).to_event().to_option() {
+        EventOption::Focus(event) => test!(event.focused),
+        _ => fail!(),
+    }
+
+    // Events pass through pipes and schemes as bytes
+    let mut event = Event::new();
+    event.copy_from_slice(&link.to_event());
+    test!(event.code == EVENT_LINK);
+
+    // Framed events
+    let bytes = link.to_event().to_bytes();
+    test!(bytes[0] == EVENT_MAGIC && bytes[1] == EVENT_VERSION);
+    test!(Event::from_bytes(&bytes).map_or(false, |event| event.code == EVENT_LINK && event.b == 1000));
+    test!(Event::from_bytes(&bytes[..EVENT_FRAME_LEN - 1]).is_none());
+
+    // A stream with an event of a newer version between two of ours, which is skipped over
+    let mut stream = [0; EVENT_FRAME_LEN * 2 + EVENT_HEADER_LEN + 40];
+    stream[..EVENT_FRAME_LEN].copy_from_slice(&scroll.to_event().to_bytes());
+    stream[EVENT_FRAME_LEN..EVENT_FRAME_LEN + 4].copy_from_slice(&[EVENT_MAGIC, EVENT_VERSION + 1, 40, 0]);
+    stream[EVENT_FRAME_LEN + EVENT_HEADER_LEN + 40..].copy_from_slice(&io.to_event().to_bytes());
+    let mut decoder = EventDecoder::new();
+    let mut codes = Vec::new();
+    let mut i = 0;
+    loop {
+        match decoder.decode(&stream[i..]) {
+            (Decoded::Event(event), len) => {
+                codes.push(event.code);
+                i += len;
+            },
+            (Decoded::Skipped, len) => i += len,
+            (Decoded::Incomplete, _) => break,
+            (Decoded::Corrupt, _) => fail!(),
+        }
+    }
+    test!(i == stream.len() && codes == vec![EVENT_SCROLL, EVENT_IO]);
+    test!(decoder.format() == Some(EventFormat::Framed));
+
+    // Raw events are sniffed from the first one
+    let mut raw = [0; 64];
+    raw[..32].copy_from_slice(&audio.to_event());
+    raw[32..].copy_from_slice(&power.to_event());
+    let mut decoder = EventDecoder::new();
+    match decoder.decode(&raw) {
+        (Decoded::Event(event), 32) => test!(event.code == EVENT_AUDIO),
+        _ => fail!(),
+    }
+    test!(decoder.format() == Some(EventFormat::Raw));
+    match decoder.decode(&raw[32..]) {
+        (Decoded::Event(event), 32) => test!(event.code == EVENT_POWER && event.b == 3000),
+        _ => fail!(),
+    }
+
+    // Once framed, a stream out of step is corrupt, as is a header of this version with another length
+    let mut decoder = EventDecoder::new();
+    test!(match decoder.decode(&bytes) { (Decoded::Event(_), EVENT_FRAME_LEN) => true, _ => false });
+    test!(match decoder.decode(&raw) { (Decoded::Corrupt, 0) => true, _ => false });
+    test!(match EventDecoder::new().decode(&[EVENT_MAGIC, EVENT_VERSION, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0]) {
+        (Decoded::Corrupt, 0) => true,
+        _ => false,
+    });
+    test!(match EventDecoder::new().decode(&[0, 0, 0]) { (Decoded::Incomplete, 0) => true, _ => false });
+
+    match Event::new().to_option() {
+        EventOption::None => (),
+        _ => fail!(),
+    }
+    match (Event { code: 1000, a: 0, b: 0, c: 0 }).to_option() {
+        EventOption::Unknown(_) => (),
+        _ => fail!(),
+    }
+
+    succ!();
+}
@@ -0,0 +1,61 @@
+use common::event::*;
+
+use env::events::{EventQueue, EVENT_QUEUE_RESERVE, EVENT_QUEUE_SIZE};
+
+fn mouse(x: i32, left_button: bool) -> Event {
+    MouseEvent {
+        x: x,
+        y: 0,
+        left_button: left_button,
+        middle_button: false,
+        right_button: false,
+        device: 0,
+    }.to_event()
+}
+
+fn key(pressed: bool) -> Event {
+    KeyEvent {
+        character: Some('a'),
+        scancode: K_A,
+        pressed: pressed,
+    }.to_event()
+}
+
+pub fn test() -> bool {
+    // Moves past the size are merged into the last one, which ends up where the mouse last was
+    let queue = EventQueue::new();
+    for x in 0..EVENT_QUEUE_SIZE as i32 + 10 {
+        queue.send(mouse(x, false), "event_queue::test");
+    }
+    test!(queue.len() == EVENT_QUEUE_SIZE);
+    test!(queue.stats().coalesced == 10);
+    for _ in 0..EVENT_QUEUE_SIZE - 1 {
+        queue.try_receive();
+    }
+    match queue.try_receive().map(|event| event.to_option()) {
+        Some(EventOption::Mouse(event)) => test!(event.x == EVENT_QUEUE_SIZE as i32 + 9),
+        _ => fail!(),
+    }
+
+    // Keys and clicks push out the moves, then use the reserve, and the moves after them are dropped
+    let queue = EventQueue::new();
+    for x in 0..EVENT_QUEUE_SIZE as i32 {
+        queue.send(mouse(x, false), "event_queue::test");
+    }
+    queue.send(mouse(0, true), "event_queue::test");
+    for i in 0..EVENT_QUEUE_SIZE + EVENT_QUEUE_RESERVE {
+        queue.send(key(i % 2 == 0), "event_queue::test");
+    }
+    queue.send(mouse(1, true), "event_queue::test");
+    let stats = queue.stats();
+    test!(queue.len() == EVENT_QUEUE_SIZE + EVENT_QUEUE_RESERVE);
+    test!(stats.dropped_moves == EVENT_QUEUE_SIZE as u64 + 1);
+    test!(stats.lost == 1);
+    test!(stats.peak == EVENT_QUEUE_SIZE + EVENT_QUEUE_RESERVE);
+    match queue.try_receive().map(|event| event.to_option()) {
+        Some(EventOption::Mouse(event)) => test!(event.left_button),
+        _ => fail!(),
+    }
+
+    succ!();
+}
@@ -0,0 +1,153 @@
+use collections::vec::Vec;
+
+use common::event::*;
+
+use drivers::kb_layouts::layouts::Layout;
+
+use env::focus::{Focus, Router, Switch, Switcher};
+
+fn key(scancode: u8, pressed: bool) -> Event {
+    KeyEvent {
+        character: None,
+        scancode: scancode,
+        pressed: pressed,
+    }.to_event()
+}
+
+fn key_event(scancode: u8, pressed: bool) -> KeyEvent {
+    KeyEvent {
+        character: None,
+        scancode: scancode,
+        pressed: pressed,
+    }
+}
+
+fn typed(scancode: u8, character: char, pressed: bool) -> Event {
+    KeyEvent {
+        character: Some(character),
+        scancode: scancode,
+        pressed: pressed,
+    }.to_event()
+}
+
+/// The characters of the presses delivered, with where they went
+fn characters(deliveries: Vec<(Focus, Event)>) -> Vec<(Focus, Option<char>)> {
+    deliveries.iter().filter_map(|&(focus, event)| match event.to_option() {
+        EventOption::Key(key) if key.pressed => Some((focus, key.character)),
+        _ => None,
+    }).collect()
+}
+
+fn mouse(x: i32, y: i32, left_button: bool) -> Event {
+    MouseEvent {
+        x: x,
+        y: y,
+        left_button: left_button,
+        middle_button: false,
+        right_button: false,
+        device: 0,
+    }.to_event()
+}
+
+/// The deliveries as the focus, the code and the values of each event
+fn summary(deliveries: Vec<(Focus, Event)>) -> Vec<(Focus, i64, i64, i64, i64)> {
+    deliveries.iter().map(|&(focus, event)| (focus, event.code, event.a, event.b, event.c)).collect()
+}
+
+fn expect(deliveries: Vec<(Focus, Event)>, events: &[(Focus, Event)]) -> bool {
+    summary(deliveries) == summary(events.to_vec())
+}
+
+pub fn test() -> bool {
+    let (first, second) = (Focus::Terminal(0), Focus::Display);
+
+    // Alt+Tab with shift held: the first focus has everything released, the second has the modifiers
+    // pressed, and the releases of what went down in the first are not seen twice
+    let mut router = Router::new(first);
+    test!(expect(router.route(first, key(K_ALT, true)), &[(first, key(K_ALT, true))]));
+    test!(expect(router.route(first, key(K_LEFT_SHIFT, true)), &[(first, key(K_LEFT_SHIFT, true))]));
+    test!(expect(router.route(first, key(K_TAB, true)), &[(first, key(K_TAB, true))]));
+    test!(expect(router.route(second, key(K_TAB, false)), &[
+        (first, key(K_ALT, false)),
+        (first, key(K_LEFT_SHIFT, false)),
+        (first, key(K_TAB, false)),
+        (second, key(K_ALT, true)),
+        (second, key(K_LEFT_SHIFT, true)),
+    ]));
+    test!(expect(router.route(second, key(K_ALT, false)), &[(second, key(K_ALT, false))]));
+    test!(expect(router.route(second, key(K_LEFT_SHIFT, false)), &[(second, key(K_LEFT_SHIFT, false))]));
+    // A key repeating while held goes on, and nothing is left held to be released
+    test!(expect(router.route(second, key(K_A, true)), &[(second, key(K_A, true))]));
+    test!(expect(router.route(second, key(K_A, true)), &[(second, key(K_A, true))]));
+    test!(expect(router.route(second, key(K_A, false)), &[(second, key(K_A, false))]));
+    test!(expect(router.route(first, key(K_B, true)), &[(first, key(K_B, true))]));
+
+    // A drag that loses the focus is let go where it started, where the pointer last was
+    let mut router = Router::new(second);
+    test!(expect(router.route(second, mouse(10, 20, true)), &[(second, mouse(10, 20, true))]));
+    test!(expect(router.route(second, mouse(30, 40, true)), &[(second, mouse(30, 40, true))]));
+    test!(expect(router.route(first, key(K_ESC, true)), &[
+        (second, mouse(30, 40, false)),
+        (first, key(K_ESC, true)),
+    ]));
+    test!(expect(router.route(first, mouse(50, 60, false)), &[(first, mouse(50, 60, false))]));
+    // The buttons were let go, so losing the focus again releases only the key
+    test!(expect(router.route(second, mouse(50, 60, false)), &[(first, key(K_ESC, false)), (second, mouse(50, 60, false))]));
+
+    // Alt+Tab goes through the targets in the order they were used, Shift goes back, and Alt commits
+    let (terminal, display) = (Focus::Terminal(0), Focus::Display);
+    let mut switcher = Switcher::new(vec![terminal, Focus::Terminal(1), Focus::Terminal(2)]);
+    switcher.focused(display);
+    test!(switcher.targets() == &[display, terminal, Focus::Terminal(1), Focus::Terminal(2)]);
+    test!(switcher.key(&key_event(K_TAB, true)) == Switch::Pass);
+    test!(switcher.key(&key_event(K_ALT, true)) == Switch::Pass);
+    test!(switcher.key(&key_event(K_TAB, true)) == Switch::Candidate(1));
+    test!(switcher.key(&key_event(K_TAB, false)) == Switch::Taken);
+    test!(switcher.key(&key_event(K_TAB, true)) == Switch::Candidate(2));
+    test!(switcher.key(&key_event(K_LEFT_SHIFT, true)) == Switch::Pass);
+    test!(switcher.key(&key_event(K_TAB, true)) == Switch::Candidate(1));
+    test!(switcher.key(&key_event(K_LEFT_SHIFT, false)) == Switch::Pass);
+    test!(switcher.key(&key_event(K_ALT, false)) == Switch::Commit(terminal));
+    switcher.focused(terminal);
+    test!(switcher.targets() == &[terminal, display, Focus::Terminal(1), Focus::Terminal(2)]);
+
+    // A target that goes while it is the candidate gives way to the next, and Escape closes the switcher
+    test!(switcher.key(&key_event(K_ALT, true)) == Switch::Pass);
+    test!(switcher.key(&key_event(K_TAB, true)) == Switch::Candidate(1));
+    switcher.remove(display);
+    test!(switcher.candidate() == Some(1));
+    test!(switcher.key(&key_event(K_ALT, false)) == Switch::Commit(Focus::Terminal(1)));
+    test!(switcher.key(&key_event(K_ALT, true)) == Switch::Pass);
+    test!(switcher.key(&key_event(K_TAB, true)) == Switch::Candidate(1));
+    test!(switcher.key(&key_event(K_ESC, true)) == Switch::Cancel);
+    test!(switcher.key(&key_event(K_ALT, false)) == Switch::Pass);
+    test!(switcher.candidate().is_none());
+
+    // A dead key of the layout of the focus waits for the next letter, in that focus only
+    let mut router = Router::new(first);
+    router.set_layout(first, Some(Layout::French));
+    test!(router.layout(first) == Layout::French && router.layout(second) == Layout::English);
+    test!(characters(router.route(first, typed(0x1A, '^', true))) == vec![(first, None)]);
+    router.route(first, typed(0x1A, '^', false));
+    test!(characters(router.route(first, typed(0x12, 'e', true))) == vec![(first, Some('ê'))]);
+    router.route(first, typed(0x12, 'e', false));
+    // One it does not combine with follows the dead key typed on its own
+    router.route(first, typed(0x1A, '^', true));
+    router.route(first, typed(0x1A, '^', false));
+    test!(characters(router.route(first, typed(0x2D, 'x', true))) == vec![(first, Some('^')), (first, Some('x'))]);
+    router.route(first, typed(0x2D, 'x', false));
+    // Switching away keeps it for coming back, the other focus types plainly with its own layout
+    router.route(first, typed(0x1A, '^', true));
+    router.route(first, typed(0x1A, '^', false));
+    test!(characters(router.route(second, typed(0x1A, '^', true))) == vec![(second, Some('^'))]);
+    router.route(second, typed(0x1A, '^', false));
+    test!(characters(router.route(first, typed(0x10, 'a', true))) == vec![(first, Some('â'))]);
+    router.route(first, typed(0x10, 'a', false));
+    // Back to the default forgets the dead key
+    router.route(first, typed(0x1A, '^', true));
+    router.route(first, typed(0x1A, '^', false));
+    router.set_layout(first, None);
+    test!(characters(router.route(first, typed(0x10, 'a', true))) == vec![(first, Some('a'))]);
+
+    succ!();
+}
@@ -0,0 +1,70 @@
+pub fn test() -> bool {
+    use collections::vec::Vec;
+
+    use fs::{KScheme, ResourceSeek};
+    use schemes::initfs::InitFsScheme;
+    use system::error::ENOENT;
+    use system::syscall::{MODE_DIR, MODE_FILE, Stat};
+
+    let mut scheme = InitFsScheme::new();
+    let data = match scheme.files.get("bin/init") {
+        Some(&data) => data,
+        None => fail!(),
+    };
+
+    // The root lists the directory the file is in
+    let mut root = match scheme.open("initfs:/", 0) {
+        Ok(root) => root,
+        Err(_) => fail!(),
+    };
+    let mut list = [0; 4096];
+    let count = match root.read(&mut list) {
+        Ok(count) => count,
+        Err(_) => fail!(),
+    };
+    test!(list[..count].split(|&b| b == b'\n').any(|item| item == b"bin"));
+    let mut stat = Stat::default();
+    test!(root.stat(&mut stat).is_ok() && stat.st_mode == MODE_DIR);
+
+    let mut file = match scheme.open("initfs:/bin/init", 0) {
+        Ok(file) => file,
+        Err(_) => fail!(),
+    };
+    let mut path = [0; 64];
+    test!(file.path(&mut path).map_or(false, |count| &path[..count] == b"initfs:/bin/init"));
+    test!(file.stat(&mut stat).is_ok() && stat.st_mode == MODE_FILE && stat.st_size as usize == data.len());
+
+    // Read in pieces that do not divide the file
+    let mut contents = Vec::new();
+    let mut buf = [0; 1000];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(count) => contents.extend_from_slice(&buf[..count]),
+            Err(_) => fail!(),
+        }
+    }
+    test!(&contents[..] == data);
+
+    test!(file.seek(ResourceSeek::Start(1)).ok() == Some(1));
+    let mut byte = [0; 1];
+    test!(file.read(&mut byte).ok() == Some(1) && byte[0] == data[1]);
+    test!(file.seek(ResourceSeek::End(0)).ok() == Some(data.len()));
+    test!(file.read(&mut byte).ok() == Some(0));
+
+    test!(scheme.open("initfs:/bin/missing", 0).err().map_or(false, |err| err.errno == ENOENT));
+
+    succ!();
+}
+
+/// Boot settings are read from `NAME=value` lines, with comments left out
+pub fn env_test() -> bool {
+    use schemes::initfs::env_value;
+
+    let env = b"# ROOT_DISK=1\nROOT_DISK = label/redox \nIRQ_MODE=pic\n";
+    test!(env_value(env, "ROOT_DISK").map_or(false, |value| value == "label/redox"));
+    test!(env_value(env, "IRQ_MODE").map_or(false, |value| value == "pic"));
+    test!(env_value(env, "ROOT").is_none() && env_value(b"", "ROOT_DISK").is_none());
+
+    succ!();
+}
@@ -0,0 +1,43 @@
+use arch::timekeeping;
+
+use common::event::*;
+
+use schemes::keyboard::KeyRepeat;
+
+fn key_event(scancode: u8, pressed: bool) -> KeyEvent {
+    KeyEvent {
+        character: None,
+        scancode: scancode,
+        pressed: pressed,
+    }
+}
+
+/// The last key pressed repeats once held long enough, until it is released or another key is pressed
+pub fn repeat_test() -> bool {
+    let mut repeat = KeyRepeat::new();
+
+    // A press of a key already held is the keyboard repeating it
+    test!(repeat.key(&key_event(K_A, true)));
+    test!(! repeat.key(&key_event(K_A, true)));
+    test!(repeat.due().is_none());
+
+    timekeeping::sleep(600, "Key repeat test");
+    test!(repeat.due().map_or(false, |key_event| key_event.scancode == K_A && key_event.pressed));
+    test!(repeat.due().is_none());
+
+    // A modifier does not repeat, and stops the key before it
+    test!(repeat.key(&key_event(K_LEFT_SHIFT, true)));
+    timekeeping::sleep(600, "Key repeat test");
+    test!(repeat.due().is_none());
+
+    // A release ends the repeat of its key
+    test!(repeat.key(&key_event(K_B, true)));
+    test!(repeat.key(&key_event(K_B, false)));
+    timekeeping::sleep(600, "Key repeat test");
+    test!(repeat.due().is_none());
+
+    repeat.clear();
+    test!(repeat.key(&key_event(K_A, true)) && repeat.key(&key_event(K_LEFT_SHIFT, true)));
+
+    succ!();
+}
@@ -1,6 +1,9 @@
 use alloc::boxed::Box;
 
 use collections::string::{String, ToString};
+use collections::vec::Vec;
+
+use drivers::debugcon;
 
 use fs::{Resource, VecResource};
 
@@ -31,52 +34,61 @@ macro_rules! fail {
 }
 
 // Add your test here!
+pub mod disk;
+pub mod dns;
+pub mod event;
+pub mod event_queue;
+pub mod focus;
 pub mod get_slice;
+pub mod initfs;
+pub mod keyboard;
 pub mod meta;
+pub mod packet;
+pub mod registry;
+pub mod route;
+pub mod tcp;
+pub mod timekeeping;
+pub mod usb;
 
-pub fn resource() -> Result<Box<Resource>> {
-    let mut string = String::new();
+/// The outcome of a test, with what it tests
+pub struct TestResult {
+    pub name: &'static str,
+    pub description: String,
+    pub passed: bool,
+}
+
+/// Run every test
+pub fn run() -> Vec<TestResult> {
+    let mut results = Vec::new();
 
     macro_rules! reg_test {
         (! $test:path) => (
-            if !$test() {
-                string.push_str("\x1B[32mSUCCESS: ");
-            } else {
-                string.push_str("\x1B[31mFAILURE: ");
-            }
-            string.push_str(stringify!($test));
-            string.push_str("\x1B[0m\n");
+            results.push(TestResult {
+                name: stringify!($test),
+                description: String::new(),
+                passed: ! $test(),
+            });
         );
         (! $test:path, $($arg:tt)*) => (
-            if !$test() {
-                string.push_str("\x1B[32mSUCCESS: ");
-            } else {
-                string.push_str("\x1B[31mFAILURE: ");
-            }
-            string.push_str(stringify!($test));
-            string.push_str(": ");
-            string.push_str(&format!($($arg)*));
-            string.push_str("\x1B[0m\n");
+            results.push(TestResult {
+                name: stringify!($test),
+                description: format!($($arg)*),
+                passed: ! $test(),
+            });
         );
         ($test:path) => (
-            if $test() {
-                string.push_str("\x1B[32mSUCCESS: ");
-            } else {
-                string.push_str("\x1B[31mFAILURE: ");
-            }
-            string.push_str(stringify!($test));
-            string.push_str("\x1B[0m\n");
+            results.push(TestResult {
+                name: stringify!($test),
+                description: String::new(),
+                passed: $test(),
+            });
         );
         ($test:path, $($arg:tt)*) => (
-            if $test() {
-                string.push_str("\x1B[32mSUCCESS: ");
-            } else {
-                string.push_str("\x1B[31mFAILURE: ");
-            }
-            string.push_str(stringify!($test));
-            string.push_str(": ");
-            string.push_str(&format!($($arg)*));
-            string.push_str("\x1B[0m\n");
+            results.push(TestResult {
+                name: stringify!($test),
+                description: format!($($arg)*),
+                passed: $test(),
+            });
         );
     }
 
@@ -84,6 +96,87 @@ pub fn resource() -> Result<Box<Resource>> {
     reg_test!(meta::meta_test_woah, "Testing the testing (wut)");
     reg_test!(!meta::meta_test_woah_fail, "Testing the fail testing (wut)");
     reg_test!(get_slice::test, "GetSlice");
+    reg_test!(event::test, "Event round trips");
+    reg_test!(disk::test, "Disk request splitting");
+    reg_test!(disk::partitions_test, "GUID partition tables");
+    reg_test!(disk::cache_test, "Disk block cache");
+    reg_test!(dns::test, "Malformed and truncated DNS responses");
+    reg_test!(dns::helper_test, "DNS lookup helper");
+    reg_test!(event_queue::test, "Event queue bounds and overflow");
+    reg_test!(focus::test, "Keys and buttons released where they were pressed");
+    reg_test!(packet::test, "Packet building and parsing");
+    reg_test!(packet::captured_test, "Parsing of captured packets");
+    reg_test!(route::test, "Longest prefix routing");
+    reg_test!(tcp::test, "TCP reordering, loss and close");
+    reg_test!(registry::test, "Scheme registration, lookup, numbered names and readiness");
+    reg_test!(initfs::test, "InitFs files");
+    reg_test!(initfs::env_test, "Boot settings");
+    reg_test!(usb::test, "USB descriptors and strings through a mock controller");
+    reg_test!(usb::control_test, "USB control transfer stalls, babble, timeouts and short packets");
+
+    results
+}
+
+/// Run the tests too slow to run with every read of sys:test
+///
+/// Measuring the clock against the RTC takes a minute and the key repeat test waits out its delays for
+/// seconds, so they are only run from sys:test/rtc.
+pub fn run_rtc() -> Vec<TestResult> {
+    vec![TestResult {
+        name: "timekeeping::test",
+        description: "Monotonic clock against the RTC over a minute".to_string(),
+        passed: timekeeping::test(),
+    }, TestResult {
+        name: "keyboard::repeat_test",
+        description: "Key repeat".to_string(),
+        passed: keyboard::repeat_test(),
+    }]
+}
+
+/// Run the tests at boot, for a kernel built with KERNEL_TEST set, and end the emulator with the result
+///
+/// The exit code is 0 if every test passed and 1 otherwise. Without the exit device, booting goes on.
+pub fn boot() {
+    let results = run();
+    let failed = results.iter().filter(|result| ! result.passed).count();
+    for result in results.iter() {
+        if result.passed {
+            syslog_info!("Test passed: {}", result.name);
+        } else {
+            syslog_error!("Test failed: {}", result.name);
+        }
+    }
+    syslog_info!("Tests: {} passed, {} failed", results.len() - failed, failed);
+
+    debugcon::exit(if failed == 0 { 0 } else { 1 });
+    syslog_warning!("Tests: no exit device, booting on");
+}
+
+/// The results as lines of text, colored by outcome
+fn report(path: &str, results: Vec<TestResult>) -> Result<Box<Resource>> {
+    let mut string = String::new();
+
+    for result in results.iter() {
+        if result.passed {
+            string.push_str("\x1B[32mSUCCESS: ");
+        } else {
+            string.push_str("\x1B[31mFAILURE: ");
+        }
+        string.push_str(result.name);
+        if ! result.description.is_empty() {
+            string.push_str(": ");
+            string.push_str(&result.description);
+        }
+        string.push_str("\x1B[0m\n");
+    }
+
+    Ok(box VecResource::new(path.to_string(), string.into_bytes(), MODE_FILE))
+}
+
+pub fn resource() -> Result<Box<Resource>> {
+    report("sys:test", run())
+}
 
-    Ok(box VecResource::new("sys:test".to_string(), string.into_bytes(), MODE_FILE))
+pub fn rtc_resource() -> Result<Box<Resource>> {
+    report("sys:test/rtc", run_rtc())
 }
@@ -0,0 +1,236 @@
+/// Frames captured on a link between two Linux hosts, 10.0.2.15 (fd01::15) at 52:54:00:12:34:56 and 10.0.2.2
+/// (fd01::2) at 52:55:0a:00:02:02, with checksum offload off so the checksums are those the sender computed
+const ARP_REQUEST: [u8; 42] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0x08, 0x06, 0x00, 0x01,
+    0x08, 0x00, 0x06, 0x04, 0x00, 0x01, 0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0x0A, 0x00, 0x02, 0x0F,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0A, 0x00, 0x02, 0x02,
+];
+const ARP_REPLY: [u8; 42] = [
+    0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0x52, 0x55, 0x0A, 0x00, 0x02, 0x02, 0x08, 0x06, 0x00, 0x01,
+    0x08, 0x00, 0x06, 0x04, 0x00, 0x02, 0x52, 0x55, 0x0A, 0x00, 0x02, 0x02, 0x0A, 0x00, 0x02, 0x02,
+    0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0x0A, 0x00, 0x02, 0x0F,
+];
+const UDP_DNS_QUERY: [u8; 71] = [
+    0x52, 0x55, 0x0A, 0x00, 0x02, 0x02, 0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0x08, 0x00, 0x45, 0x00,
+    0x00, 0x39, 0x0A, 0xA6, 0x40, 0x00, 0x40, 0x11, 0x17, 0xFE, 0x0A, 0x00, 0x02, 0x0F, 0x0A, 0x00,
+    0x02, 0x02, 0xC0, 0x00, 0x00, 0x35, 0x00, 0x25, 0x3D, 0xC5, 0x1A, 0x2B, 0x01, 0x00, 0x00, 0x01,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x65, 0x78, 0x61, 0x6D, 0x70, 0x6C, 0x65, 0x03, 0x63,
+    0x6F, 0x6D, 0x00, 0x00, 0x01, 0x00, 0x01,
+];
+const TCP_SYN_ACK: [u8; 74] = [
+    0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0x52, 0x55, 0x0A, 0x00, 0x02, 0x02, 0x08, 0x00, 0x45, 0x00,
+    0x00, 0x3C, 0x00, 0x00, 0x40, 0x00, 0x40, 0x06, 0x22, 0xAC, 0x0A, 0x00, 0x02, 0x02, 0x0A, 0x00,
+    0x02, 0x0F, 0x00, 0x50, 0xD8, 0x66, 0xC7, 0xA2, 0xFA, 0xB4, 0xCE, 0x4D, 0xBF, 0xF8, 0xA0, 0x12,
+    0xFE, 0x88, 0xB9, 0x6F, 0x00, 0x00, 0x02, 0x04, 0x05, 0xB4, 0x04, 0x02, 0x08, 0x0A, 0xE2, 0x73,
+    0xCF, 0x61, 0xB0, 0x41, 0xEC, 0x77, 0x01, 0x03, 0x03, 0x0A,
+];
+const TCP_DATA: [u8; 85] = [
+    0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0x52, 0x55, 0x0A, 0x00, 0x02, 0x02, 0x08, 0x00, 0x45, 0x00,
+    0x00, 0x47, 0x96, 0x7E, 0x40, 0x00, 0x40, 0x06, 0x8C, 0x22, 0x0A, 0x00, 0x02, 0x02, 0x0A, 0x00,
+    0x02, 0x0F, 0x00, 0x50, 0xD8, 0x66, 0xC7, 0xA2, 0xFA, 0xB5, 0xCE, 0x4D, 0xC0, 0x0A, 0x80, 0x18,
+    0x00, 0x40, 0x1C, 0x89, 0x00, 0x00, 0x01, 0x01, 0x08, 0x0A, 0xE2, 0x73, 0xCF, 0x61, 0xB0, 0x41,
+    0xEC, 0x77, 0x48, 0x54, 0x54, 0x50, 0x2F, 0x31, 0x2E, 0x30, 0x20, 0x32, 0x30, 0x30, 0x20, 0x4F,
+    0x4B, 0x0D, 0x0A, 0x0D, 0x0A,
+];
+const NDP_ADVERTISEMENT: [u8; 86] = [
+    0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0x52, 0x55, 0x0A, 0x00, 0x02, 0x02, 0x86, 0xDD, 0x60, 0x00,
+    0x00, 0x00, 0x00, 0x20, 0x3A, 0xFF, 0xFD, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0xFD, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x15, 0x88, 0x00, 0xC0, 0x2D, 0x60, 0x00, 0x00, 0x00, 0xFD, 0x01,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x02, 0x01,
+    0x52, 0x55, 0x0A, 0x00, 0x02, 0x02,
+];
+const UDP6_ECHO: [u8; 67] = [
+    0x52, 0x55, 0x0A, 0x00, 0x02, 0x02, 0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0x86, 0xDD, 0x60, 0x07,
+    0x84, 0x1B, 0x00, 0x0D, 0x11, 0x40, 0xFD, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x15, 0xFD, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0xC0, 0x01, 0x00, 0x07, 0x00, 0x0D, 0x01, 0xDF, 0x68, 0x65,
+    0x6C, 0x6C, 0x6F,
+];
+
+pub fn test() -> bool {
+    use network::common::{Ipv4Addr, MacAddr};
+    use network::packet::*;
+
+    let src = Ipv4Addr { bytes: [10, 0, 2, 15] };
+    let dst = Ipv4Addr { bytes: [10, 0, 2, 2] };
+    let src_mac = MacAddr { bytes: [0x52, 0x54, 0x00, 0x12, 0x34, 0x56] };
+    let dst_mac = MacAddr { bytes: [0xFF; 6] };
+
+    let udp = UdpBuilder {
+        src: src,
+        dst: dst,
+        src_port: 68,
+        dst_port: 67,
+        offload: false,
+    }.build(b"payload");
+    let ip = Ipv4Builder {
+        src: src,
+        dst: dst,
+        proto: IP_PROTO_UDP,
+        id: 7,
+        ttl: 64,
+        offload: false,
+    }.build(&udp);
+    let bytes = ethernet_frame(dst_mac, src_mac, ETHERTYPE_IPV4, &ip);
+
+    let frame = match EthernetFrame::new(&bytes) {
+        Some(frame) => frame,
+        None => fail!(),
+    };
+    test!(frame.dst().equals(dst_mac) && frame.src().equals(src_mac));
+    test!(frame.ethertype() == ETHERTYPE_IPV4);
+    test!(frame.ipv6().is_none());
+
+    let packet = match frame.ipv4() {
+        Some(packet) => packet,
+        None => fail!(),
+    };
+    test!(packet.header_valid() && ! packet.is_fragment());
+    test!(packet.src().equals(src) && packet.dst().equals(dst));
+    test!(packet.proto() == IP_PROTO_UDP && packet.id() == 7 && packet.ttl() == 64);
+    test!(packet.total_len() == ip.len());
+
+    let datagram = match UdpDatagram::new(packet.payload()) {
+        Some(datagram) => datagram,
+        None => fail!(),
+    };
+    test!(datagram.src_port() == 68 && datagram.dst_port() == 67);
+    test!(datagram.payload() == b"payload");
+    test!(datagram.checksum_valid(&src, &dst));
+
+    // A flipped bit breaks both checksums
+    let mut corrupt = ip.clone();
+    corrupt[IPV4_HEADER_LEN + UDP_HEADER_LEN] ^= 1;
+    test!(! UdpDatagram::new(&corrupt[IPV4_HEADER_LEN..]).map_or(false, |datagram| datagram.checksum_valid(&src, &dst)));
+    corrupt[8] ^= 1;
+    test!(! Ipv4Packet::new(&corrupt).map_or(false, |packet| packet.header_valid()));
+
+    // Truncated input is refused, not read past
+    test!(EthernetFrame::new(&bytes[..ETHERNET_HEADER_LEN - 1]).is_none());
+    test!(Ipv4Packet::new(&ip[..IPV4_HEADER_LEN - 1]).is_none());
+    test!(Ipv4Packet::new(&ip[..ip.len() - 1]).is_none());
+    test!(UdpDatagram::new(&udp[..UDP_HEADER_LEN - 1]).is_none());
+
+    succ!();
+}
+
+/// What other stacks send is parsed as they meant it
+pub fn captured_test() -> bool {
+    use network::common::{Ipv4Addr, Ipv6Addr, MacAddr};
+    use network::packet::*;
+    use network::schemes::ndp::NdpMessage;
+    use network::schemes::tcp::{TCP_ACK, TCP_PSH, TCP_SYN};
+
+    let host_mac = MacAddr { bytes: [0x52, 0x54, 0x00, 0x12, 0x34, 0x56] };
+    let peer_mac = MacAddr { bytes: [0x52, 0x55, 0x0A, 0x00, 0x02, 0x02] };
+    let host = Ipv4Addr { bytes: [10, 0, 2, 15] };
+    let peer = Ipv4Addr { bytes: [10, 0, 2, 2] };
+    let host6 = match Ipv6Addr::from_str("fd01::15") {
+        Some(host6) => host6,
+        None => fail!(),
+    };
+    let peer6 = match Ipv6Addr::from_str("fd01::2") {
+        Some(peer6) => peer6,
+        None => fail!(),
+    };
+
+    // ARP, a request broadcast for the peer and its answer
+    let frame = match EthernetFrame::new(&ARP_REQUEST) {
+        Some(frame) => frame,
+        None => fail!(),
+    };
+    test!(frame.dst().equals(MacAddr { bytes: [0xFF; 6] }) && frame.src().equals(host_mac) && frame.ethertype() == ETHERTYPE_ARP);
+    let arp = match ArpPacket::new(frame.payload()) {
+        Some(arp) => arp,
+        None => fail!(),
+    };
+    test!(arp.oper() == 1 && arp.src_mac().equals(host_mac) && arp.src_ip().equals(host) && arp.dst_ip().equals(peer));
+
+    let arp = match EthernetFrame::new(&ARP_REPLY).and_then(|frame| ArpPacket::new(frame.payload())) {
+        Some(arp) => arp,
+        None => fail!(),
+    };
+    test!(arp.oper() == 2 && arp.src_mac().equals(peer_mac) && arp.src_ip().equals(peer));
+    test!(arp.dst_mac().equals(host_mac) && arp.dst_ip().equals(host));
+
+    // UDP over IPv4, a DNS query
+    let packet = match EthernetFrame::new(&UDP_DNS_QUERY).and_then(|frame| frame.ipv4()) {
+        Some(packet) => packet,
+        None => fail!(),
+    };
+    test!(packet.header_valid() && ! packet.is_fragment() && packet.proto() == IP_PROTO_UDP);
+    test!(packet.src().equals(host) && packet.dst().equals(peer) && packet.ttl() == 64 && packet.id() == 0x0AA6);
+    let datagram = match UdpDatagram::new(packet.payload()) {
+        Some(datagram) => datagram,
+        None => fail!(),
+    };
+    test!(datagram.src_port() == 49152 && datagram.dst_port() == 53 && datagram.checksum_valid(&host, &peer));
+    test!(datagram.payload().len() == 29 && &datagram.payload()[.. 2] == &[0x1A, 0x2B]);
+
+    // TCP, the SYN-ACK with its options, then data
+    let packet = match EthernetFrame::new(&TCP_SYN_ACK).and_then(|frame| frame.ipv4()) {
+        Some(packet) => packet,
+        None => fail!(),
+    };
+    test!(packet.header_valid() && packet.proto() == IP_PROTO_TCP && packet.src().equals(peer));
+    let segment = match TcpSegment::new(packet.payload()) {
+        Some(segment) => segment,
+        None => fail!(),
+    };
+    test!(segment.src_port() == 80 && segment.dst_port() == 55398 && segment.flags() == TCP_SYN | TCP_ACK);
+    test!(segment.sequence() == 0xC7A2FAB4 && segment.ack_num() == 0xCE4DBFF8 && segment.window_size() == 65160);
+    test!(segment.header_len() == 40 && segment.options().len() == 20 && &segment.options()[.. 4] == &[2, 4, 0x05, 0xB4]);
+    test!(segment.payload().is_empty() && segment.checksum_valid(&peer, &host));
+
+    let packet = match EthernetFrame::new(&TCP_DATA).and_then(|frame| frame.ipv4()) {
+        Some(packet) => packet,
+        None => fail!(),
+    };
+    let segment = match TcpSegment::new(packet.payload()) {
+        Some(segment) => segment,
+        None => fail!(),
+    };
+    test!(segment.flags() == TCP_PSH | TCP_ACK && segment.sequence() == 0xC7A2FAB5);
+    test!(segment.payload() == b"HTTP/1.0 200 OK\r\n\r\n" && segment.checksum_valid(&peer, &host));
+
+    // IPv6, a neighbor advertisement answering for the peer, then UDP
+    let frame = match EthernetFrame::new(&NDP_ADVERTISEMENT) {
+        Some(frame) => frame,
+        None => fail!(),
+    };
+    test!(frame.ethertype() == ETHERTYPE_IPV6 && frame.ipv4().is_none());
+    let packet = match frame.ipv6() {
+        Some(packet) => packet,
+        None => fail!(),
+    };
+    test!(packet.next_header() == IP_PROTO_ICMPV6 && packet.hop_limit() == 255);
+    test!(packet.src().equals(peer6) && packet.dst().equals(host6));
+    let message = match NdpMessage::from_packet(&packet) {
+        Some(message) => message,
+        None => fail!(),
+    };
+    test!(message.kind == 136 && message.flags == 0x60 && message.target.equals(peer6));
+    test!(message.link_addr.map_or(false, |link_addr| link_addr.equals(peer_mac)));
+
+    let packet = match EthernetFrame::new(&UDP6_ECHO).and_then(|frame| frame.ipv6()) {
+        Some(packet) => packet,
+        None => fail!(),
+    };
+    test!(packet.next_header() == IP_PROTO_UDP && packet.flow_label() == 0x7841B && packet.hop_limit() == 64);
+    let datagram = match UdpDatagram::new(packet.payload()) {
+        Some(datagram) => datagram,
+        None => fail!(),
+    };
+    test!(datagram.src_port() == 49153 && datagram.dst_port() == 7 && datagram.payload() == b"hello");
+    test!(datagram.checksum_valid_ipv6(&host6, &peer6));
+
+    // A bit flipped on the way is caught
+    let mut corrupt = TCP_DATA;
+    corrupt[corrupt.len() - 1] ^= 1;
+    test!(! EthernetFrame::new(&corrupt).and_then(|frame| frame.ipv4())
+                                        .and_then(|packet| TcpSegment::new(packet.payload()))
+                                        .map_or(false, |segment| segment.checksum_valid(&peer, &host)));
+
+    succ!();
+}
@@ -0,0 +1,93 @@
+use alloc::boxed::Box;
+
+use collections::string::ToString;
+
+use common::event::IO_CLOSED;
+
+use fs::{KScheme, Resource, VecResource};
+
+use system::error::{Result, ENODEV};
+use system::syscall::MODE_FILE;
+
+/// The name the test schemes are registered under, which no driver uses
+const NAME: &'static str = "registrytest";
+
+/// A resource that never has anything to read or room to write
+struct IdleResource;
+
+impl Resource for IdleResource {
+    fn ready(&mut self) -> i64 {
+        0
+    }
+}
+
+struct TestScheme;
+
+impl KScheme for TestScheme {
+    fn scheme(&self) -> &str {
+        NAME
+    }
+
+    fn open(&mut self, url: &str, _: usize) -> Result<Box<Resource>> {
+        if url.ends_with("/idle") {
+            Ok(box IdleResource)
+        } else {
+            Ok(box VecResource::new(url.to_string(), b"test".to_vec(), MODE_FILE))
+        }
+    }
+}
+
+/// Schemes are found by name, a taken name is numbered, and resources fail and read as closed once their
+/// scheme is gone
+pub fn test() -> bool {
+    let schemes = &::env().schemes;
+    let second = format!("{}2", NAME);
+    test!(! schemes.contains(NAME) && ! schemes.contains(&second));
+
+    schemes.register(box TestScheme);
+    schemes.register(box TestScheme);
+    let (first, numbered) = match (schemes.lookup(NAME), schemes.lookup(&second)) {
+        (Some(first), Some(numbered)) => (first, numbered),
+        _ => fail!(),
+    };
+    test!(first.id < numbered.id);
+    test!(schemes.names().iter().filter(|name| name.starts_with(NAME)).count() == 2);
+    test!(schemes.lookup("").is_none() && schemes.lookup("registrytes").is_none());
+
+    // Opened through the registry, the resource holds the scheme until it is closed
+    let mut resource = match numbered.open(&format!("{}:/file", second), 0) {
+        Ok(resource) => resource,
+        Err(_) => fail!(),
+    };
+    test!(numbered.resources() == 1);
+    let mut buf = [0; 4];
+    test!(resource.read(&mut buf).ok() == Some(4) && &buf == b"test");
+
+    test!(schemes.unregister(&second));
+    test!(! schemes.unregister(&second) && schemes.lookup(&second).is_none());
+    test!(resource.read(&mut buf).err().map_or(false, |err| err.errno == ENODEV));
+    test!(schemes.removed().iter().any(|entry| entry.id == numbered.id));
+    drop(resource);
+    test!(numbered.resources() == 0);
+
+    // Readiness is the scheme's own until it is unregistered, then the resource reads as closed
+    let mut idle = match first.open(&format!("{}:/idle", NAME), 0) {
+        Ok(idle) => idle,
+        Err(_) => fail!(),
+    };
+    test!(idle.ready() == 0);
+
+    // The name is free again once its scheme is gone, and is not reused while taken
+    test!(schemes.unregister(NAME) && ! schemes.contains(NAME));
+    test!(idle.ready() == IO_CLOSED);
+    drop(idle);
+    schemes.register(box TestScheme);
+    let again = match schemes.lookup(NAME) {
+        Some(again) => again,
+        None => fail!(),
+    };
+    test!(again.id > numbered.id && ! schemes.contains(&second));
+    test!(schemes.unregister(NAME));
+
+    succ!();
+}
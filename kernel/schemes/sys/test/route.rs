@@ -0,0 +1,60 @@
+pub fn test() -> bool {
+    use network::common::Ipv4Addr;
+    use network::route::*;
+
+    fn addr(a: u8, b: u8, c: u8, d: u8) -> Ipv4Addr {
+        Ipv4Addr { bytes: [a, b, c, d] }
+    }
+
+    fn route(prefix: Ipv4Addr, len: u32, gateway: Option<Ipv4Addr>) -> Route {
+        Route {
+            prefix: prefix,
+            netmask: netmask(len),
+            gateway: gateway,
+            interface: ROUTE_INTERFACE,
+            manual: true,
+        }
+    }
+
+    fn hop(routes: &[Route], to: Ipv4Addr) -> Option<Ipv4Addr> {
+        next_hop(routes, to).ok()
+    }
+
+    let router = addr(10, 85, 85, 1);
+    let vpn = addr(10, 85, 85, 200);
+    let routes = [
+        route(addr(0, 0, 0, 0), 0, Some(router)),
+        route(addr(10, 85, 85, 0), 24, None),
+        route(addr(172, 16, 0, 0), 12, Some(vpn)),
+        route(addr(172, 16, 5, 0), 24, None),
+        // A gateway that is not on the link
+        route(addr(192, 168, 0, 0), 16, Some(addr(172, 16, 1, 1))),
+    ];
+
+    test!(netmask(0).equals(addr(0, 0, 0, 0)));
+    test!(netmask(12).equals(addr(255, 240, 0, 0)));
+    test!(netmask(32).equals(addr(255, 255, 255, 255)));
+
+    // The longest prefix wins, whatever the order
+    test!(hop(&routes, addr(10, 85, 85, 7)).map_or(false, |hop| hop.equals(addr(10, 85, 85, 7))));
+    test!(hop(&routes, addr(172, 20, 1, 1)).map_or(false, |hop| hop.equals(vpn)));
+    test!(hop(&routes, addr(172, 16, 5, 9)).map_or(false, |hop| hop.equals(addr(172, 16, 5, 9))));
+    test!(hop(&routes, addr(8, 8, 8, 8)).map_or(false, |hop| hop.equals(router)));
+    test!(hop(&routes, addr(192, 168, 1, 1)).is_none());
+    // Without a default route what is off the link is unreachable
+    test!(hop(&routes[1..], addr(8, 8, 8, 8)).is_none());
+
+    // The loopback network is routed to the loopback interface, and a manual route there loops back too
+    let configured = ::network::route::routes();
+    test!(is_looped(&configured, addr(127, 0, 0, 1)) && is_looped(&configured, addr(127, 45, 0, 9)));
+    test!(lookup(&configured, addr(127, 0, 0, 1)).map_or(false, |route| route.to_string() == "127.0.0.0/8 dev loopback config"));
+    test!(! is_looped(&configured, addr(8, 8, 8, 8)));
+    let mut local = routes.to_vec();
+    local.push(Route {
+        interface: LOOPBACK_INTERFACE,
+        .. route(addr(10, 99, 0, 0), 16, None)
+    });
+    test!(is_looped(&local, addr(10, 99, 3, 4)) && hop(&local, addr(10, 99, 3, 4)).map_or(false, |hop| hop.equals(addr(10, 99, 3, 4))));
+
+    succ!();
+}
@@ -0,0 +1,142 @@
+use alloc::arc::Arc;
+
+use arch::timekeeping;
+
+use collections::vec::Vec;
+use collections::vec_deque::VecDeque;
+
+use common::event::{IO_READ, IO_WRITE};
+
+use core::cell::UnsafeCell;
+
+use fs::Resource;
+
+use network::common::{FromBytes, Ipv4Addr};
+use network::packet::{TcpBuilder, TcpSegment};
+use network::schemes::tcp::{Tcp, TcpState, TcpStream, TCP_ACK, TCP_FIN, TCP_PSH, TCP_SYN};
+
+use system::error::{Error, Result, EAGAIN};
+
+const PEER_PORT: u16 = 80;
+const HOST_PORT: u16 = 40000;
+const PEER_ISS: u32 = 0xFFFF_FFF0;
+
+fn peer() -> Ipv4Addr {
+    Ipv4Addr::from_str("127.0.0.1")
+}
+
+/// A segment from the peer
+fn segment(sequence: u32, ack_num: u32, flags: u16, data: &[u8]) -> Vec<u8> {
+    TcpBuilder {
+        src: peer(),
+        dst: peer(),
+        src_port: PEER_PORT,
+        dst_port: HOST_PORT,
+        sequence: sequence,
+        ack_num: ack_num,
+        flags: flags,
+        window_size: 65535,
+        offload: false,
+    }.build(&[], data)
+}
+
+/// What the peer sends and what it was sent
+struct Wire {
+    inbound: VecDeque<Vec<u8>>,
+    outbound: Vec<Vec<u8>>,
+}
+
+/// The link of the connection under test, the peer acknowledges a SYN or a FIN written to it at once
+struct WireResource {
+    wire: Arc<UnsafeCell<Wire>>,
+}
+
+impl Resource for WireResource {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match unsafe { &mut *self.wire.get() }.inbound.pop_front() {
+            Some(segment) => {
+                for (b, s) in buf.iter_mut().zip(segment.iter()) {
+                    *b = *s;
+                }
+                Ok(segment.len())
+            },
+            None => Err(Error::new(EAGAIN)),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let wire = unsafe { &mut *self.wire.get() };
+        if let Some(sent) = TcpSegment::new(buf) {
+            if sent.flags() & (TCP_SYN | TCP_FIN) != 0 {
+                wire.inbound.push_back(segment(sent.ack_num(), sent.sequence().wrapping_add(1), TCP_ACK, &[]));
+            }
+        }
+        wire.outbound.push(buf.to_vec());
+        Ok(buf.len())
+    }
+}
+
+/// Received segments are put in order, and lost ones are sent again
+pub fn test() -> bool {
+    let wire = Arc::new(UnsafeCell::new(Wire {
+        inbound: VecDeque::new(),
+        outbound: Vec::new(),
+    }));
+    let sent = |i: usize| unsafe { &*wire.get() }.outbound.get(i).map(|bytes| bytes.clone()).unwrap_or(Vec::new());
+    let sent_count = || unsafe { &*wire.get() }.outbound.len();
+    let last_ack = || TcpSegment::new(&sent(sent_count() - 1)).map(|segment| segment.ack_num());
+    let receive = |bytes: Vec<u8>| unsafe { &mut *wire.get() }.inbound.push_back(bytes);
+
+    let mut stream = TcpStream::new(box WireResource { wire: wire.clone() }, peer(), PEER_PORT, HOST_PORT);
+    let syn = match Tcp::from_bytes(&segment(PEER_ISS, 0, TCP_SYN, &[])) {
+        Some(syn) => syn,
+        None => fail!(),
+    };
+    test!(stream.accept(&syn).is_ok() && stream.state() == TcpState::Established);
+    let iss = match TcpSegment::new(&sent(0)) {
+        Some(syn_ack) => syn_ack.sequence(),
+        None => fail!(),
+    };
+
+    // The sequence numbers of the peer wrap around while it sends
+    let start = PEER_ISS.wrapping_add(1);
+
+    // A segment ahead of a gap is held, and the gap acknowledged again
+    receive(segment(start.wrapping_add(6), iss.wrapping_add(1), TCP_ACK | TCP_PSH, b"world "));
+    test!(stream.ready() & IO_READ == 0 && last_ack() == Some(start));
+
+    // Filling the gap gives both in order
+    receive(segment(start, iss.wrapping_add(1), TCP_ACK | TCP_PSH, b"hello "));
+    test!(stream.ready() & IO_READ == IO_READ && last_ack() == Some(start.wrapping_add(12)));
+    let mut buf = [0; 64];
+    test!(stream.read(&mut buf).ok() == Some(12) && &buf[.. 12] == b"hello world ");
+
+    // A duplicate is acknowledged without being read again
+    receive(segment(start, iss.wrapping_add(1), TCP_ACK | TCP_PSH, b"hello "));
+    test!(stream.ready() & IO_READ == 0 && last_ack() == Some(start.wrapping_add(12)));
+
+    // A segment the peer never acknowledges is sent again once the retransmission timeout passes
+    let count = sent_count();
+    test!(stream.write(b"ping").ok() == Some(4) && sent_count() == count + 1);
+    for _ in 0..30 {
+        if sent_count() > count + 1 {
+            break;
+        }
+        timekeeping::sleep(100, "TCP test");
+        stream.ready();
+    }
+    test!(sent_count() > count + 1);
+    test!(sent(count) == sent(count + 1));
+    test!(TcpSegment::new(&sent(count + 1)).map_or(false, |resent| resent.sequence() == iss.wrapping_add(1) && resent.payload() == b"ping"));
+
+    receive(segment(start.wrapping_add(12), iss.wrapping_add(5), TCP_ACK, &[]));
+    test!(stream.sync().is_ok() && stream.ready() & IO_WRITE == IO_WRITE);
+
+    // The peer closing its side ends reading, and our FIN finishes the close
+    receive(segment(start.wrapping_add(12), iss.wrapping_add(5), TCP_ACK | TCP_FIN, &[]));
+    test!(stream.read(&mut buf).ok() == Some(0) && stream.state() == TcpState::CloseWait);
+    stream.close();
+    test!(stream.state() == TcpState::Closed);
+
+    succ!();
+}
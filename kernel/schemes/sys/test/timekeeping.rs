@@ -0,0 +1,50 @@
+use arch::timekeeping;
+
+use common::time;
+
+use drivers::rtc::Rtc;
+
+/// Seconds of the RTC the monotonic clock is measured over
+const SECONDS: i64 = 60;
+
+/// Wait for the RTC to tick on from `secs`, returning the second it ticked to and the monotonic clock then
+fn next_second(secs: i64) -> Option<(i64, u64)> {
+    for _ in 0..3000 {
+        let now = Rtc::new().time().secs;
+        if now != secs {
+            return Some((now, timekeeping::monotonic_ns()));
+        }
+        timekeeping::sleep(1, "timekeeping test");
+    }
+    None
+}
+
+/// The monotonic clock keeps to within 2% of the RTC over a minute, whatever its source
+///
+/// The edges of the RTC seconds are found to within a sleep, a few milliseconds.
+pub fn test() -> bool {
+    let (start_secs, start) = match next_second(Rtc::new().time().secs) {
+        Some(edge) => edge,
+        None => fail!(),
+    };
+
+    // Sleep until just before the minute is up, then find the edge it ends on
+    timekeeping::sleep((SECONDS - 1) as u64 * 1000, "timekeeping test");
+    let (mut end_secs, mut end) = (Rtc::new().time().secs, 0);
+    while end == 0 || end_secs < start_secs + SECONDS {
+        match next_second(end_secs) {
+            Some(edge) => {
+                end_secs = edge.0;
+                end = edge.1;
+            },
+            None => fail!(),
+        }
+    }
+
+    let expected = (end_secs - start_secs) as u64 * time::NANOS_PER_SEC as u64;
+    let measured = end - start;
+    let error = if measured > expected { measured - expected } else { expected - measured };
+    test!(error * 50 <= expected);
+
+    succ!();
+}
@@ -0,0 +1,116 @@
+use collections::vec::Vec;
+
+use core::mem;
+
+use system::error::{Result, EINVAL, EIO, EPIPE, ETIMEDOUT};
+
+use usb::{Packet, Pipe, Setup, UsbHc};
+use usb::desc::{find_endpoint, find_interface, DeviceDescriptor, DESC_CFG, DESC_DEV};
+use usb::mock::{MockHc, MockStage};
+
+/// A flash drive: its device descriptor, and a configuration with one bulk-only interface
+const DEVICE: [u8; 18] = [18, 1, 0x00, 0x02, 0, 0, 0, 64, 0x81, 0x07, 0x51, 0x55, 0x00, 0x01, 1, 2, 3, 1];
+const CONFIGURATION: [u8; 32] = [9, 2, 32, 0, 1, 1, 0, 0x80, 50,
+                                 9, 4, 0, 0, 2, 8, 6, 80, 0,
+                                 7, 5, 0x81, 2, 0x00, 0x02, 0,
+                                 7, 5, 0x02, 2, 0x00, 0x02, 0];
+
+/// Descriptors and strings are read with control transfers through the `UsbHc` trait, and parsed
+pub fn test() -> bool {
+    let mut hci = MockHc::new();
+
+    // The device descriptor goes through a setup, an IN data stage and an OUT status stage
+    hci.reply(&DEVICE);
+    let mut desc_dev = DeviceDescriptor::default();
+    let count = hci.descriptor(0, DESC_DEV, 0, &mut desc_dev as *mut DeviceDescriptor as usize, mem::size_of::<DeviceDescriptor>());
+    test!(count.ok() == Some(18));
+    test!(desc_dev.vendor == 0x0781 && desc_dev.product == 0x5551 && desc_dev.max_packet_size == 64);
+    test!(hci.transfers.len() == 1);
+    test!(hci.transfers[0].address == 0 && hci.transfers[0].endpoint == 0 && hci.transfers[0].pipe == Pipe::Control);
+    test!(hci.transfers[0].stages == vec![MockStage::Setup(0x06), MockStage::In(18), MockStage::Out(0)]);
+
+    // A string is read in the first language the device lists
+    hci.reply(&[4, 3, 0x09, 0x04]);
+    hci.reply(&[10, 3, b'D', 0, b'i', 0, b's', 0, b'k', 0]);
+    test!(hci.string(1, desc_dev.product_string).map_or(false, |string| string == "Disk"));
+    test!(hci.string(1, 0).is_none() && hci.transfers.len() == 3);
+
+    // Transfers to a device that does not answer time out, and the string is left out
+    test!(hci.string(1, 3).is_none());
+
+    // The configuration is found by its class, and its endpoints by their type and direction
+    hci.reply(&CONFIGURATION);
+    let mut configuration = vec![0; 255];
+    let count = match hci.descriptor(1, DESC_CFG, 0, configuration.as_mut_ptr() as usize, configuration.len()) {
+        Ok(count) => count,
+        Err(_) => fail!(),
+    };
+    configuration.truncate(count);
+    test!(configuration == CONFIGURATION.iter().cloned().collect::<Vec<u8>>());
+    let desc_int = match find_interface(&configuration, 8, Some(6), Some(80)) {
+        Some(desc_int) => desc_int,
+        None => fail!(),
+    };
+    test!(find_endpoint(&configuration, &desc_int, Pipe::Bulk, true).map_or(false, |desc_end| desc_end.address == 0x81));
+    test!(find_endpoint(&configuration, &desc_int, Pipe::Bulk, false).map_or(false, |desc_end| desc_end.address == 0x02));
+    test!(find_endpoint(&configuration, &desc_int, Pipe::Interrupt, true).is_none());
+
+    succ!();
+}
+
+/// Control transfers are sent again once after a stall and a few times after a transaction error like
+/// babble, a timeout is not retried, and a short data stage ends the transfer early
+pub fn control_test() -> bool {
+    let errno = |result: Result<usize>| result.err().map(|err| err.errno);
+    let setup = Setup::get_descriptor(DESC_DEV, 0, 0, 18);
+    let mut data = [0; 18];
+
+    // A stall refuses the request, the next setup packet clears it
+    let mut hci = MockHc::new();
+    hci.fail(EPIPE);
+    hci.reply(&DEVICE);
+    test!(hci.control_in(1, &setup, &mut data).ok() == Some(18) && data == DEVICE);
+    test!(hci.transfers.len() == 2);
+
+    let mut hci = MockHc::new();
+    hci.fail(EPIPE);
+    hci.fail(EPIPE);
+    hci.reply(&DEVICE);
+    test!(errno(hci.control_in(1, &setup, &mut data)) == Some(EPIPE) && hci.transfers.len() == 2);
+
+    // Babble is a transaction error, sent again up to three times
+    let mut hci = MockHc::new();
+    for _ in 0..3 {
+        hci.fail(EIO);
+    }
+    hci.reply(&DEVICE);
+    test!(hci.control_in(1, &setup, &mut data).ok() == Some(18) && hci.transfers.len() == 4);
+
+    let mut hci = MockHc::new();
+    for _ in 0..4 {
+        hci.fail(EIO);
+    }
+    hci.reply(&DEVICE);
+    test!(errno(hci.control_in(1, &setup, &mut data)) == Some(EIO) && hci.transfers.len() == 4);
+
+    // A device that does not answer is not asked again
+    let mut hci = MockHc::new();
+    hci.fail(ETIMEDOUT);
+    hci.reply(&DEVICE);
+    test!(errno(hci.control_in(1, &setup, &mut data)) == Some(ETIMEDOUT) && hci.transfers.len() == 1);
+
+    // A short packet ends the data stage, the status stage follows as usual
+    let mut hci = MockHc::new();
+    hci.reply(&DEVICE[.. 8]);
+    test!(hci.control_in(1, &setup, &mut data).ok() == Some(8));
+    test!(hci.transfers[0].stages == vec![MockStage::Setup(0x06), MockStage::In(18), MockStage::Out(0)]);
+
+    // Without a data stage the status stage is IN, and the data stage has to go the way the request does
+    let mut hci = MockHc::new();
+    hci.reply(&[]);
+    test!(hci.control_out(0, &Setup::set_address(1), &[]).ok() == Some(0));
+    test!(hci.transfers[0].stages == vec![MockStage::Setup(0x05), MockStage::In(0)]);
+    test!(errno(hci.control_transfer(1, &setup, Packet::Out(&DEVICE))) == Some(EINVAL) && hci.transfers.len() == 1);
+
+    succ!();
+}
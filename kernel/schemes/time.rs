@@ -0,0 +1,146 @@
+use alloc::boxed::Box;
+
+use arch::timekeeping;
+
+use collections::string::String;
+use collections::vec::Vec;
+
+use common::time::{DateTime, Duration};
+use common::to_num::ToNum;
+
+use core::{cmp, str};
+
+use drivers::rtc::Rtc;
+
+use fs::{KScheme, Resource, ResourceSeek, VecResource};
+
+use system::error::{Error, Result, EINVAL, EIO, ENOENT, EPERM};
+use system::syscall::MODE_FILE;
+
+/// The time given as seconds since the Unix epoch, or as a date like `2016-07-14 12:30:00`
+fn parse(string: &str) -> Option<DateTime> {
+    if ! string.is_empty() && string.chars().all(|c| c.is_digit(10)) {
+        return Some(DateTime::from_unix(string.to_num() as i64));
+    }
+
+    let fields: Vec<&str> = string.split(|c| c == '-' || c == ' ' || c == ':' || c == 'T').collect();
+    if fields.len() != 6 || ! fields.iter().all(|field| ! field.is_empty() && field.chars().all(|c| c.is_digit(10))) {
+        return None;
+    }
+    let date = DateTime {
+        year: fields[0].to_num() as i64,
+        month: fields[1].to_num() as u8,
+        day: fields[2].to_num() as u8,
+        hour: fields[3].to_num() as u8,
+        minute: fields[4].to_num() as u8,
+        second: fields[5].to_num() as u8,
+    };
+    if date.valid() {
+        Some(date)
+    } else {
+        None
+    }
+}
+
+fn contents(rtc: bool) -> String {
+    let time = if rtc { Rtc::new().time() } else { Duration::realtime() };
+    format!("{}.{:>09}\n{}\n", time.secs, time.nanos, DateTime::from_unix(time.secs))
+}
+
+/// The wall clock, as seconds since the Unix epoch and as a date, or the RTC it was read from at boot
+pub struct TimeResource {
+    rtc: bool,
+    data: Vec<u8>,
+    seek: usize,
+}
+
+impl Resource for TimeResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box TimeResource {
+            rtc: self.rtc,
+            data: self.data.clone(),
+            seek: self.seek,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path: &[u8] = if self.rtc { b"time:/rtc" } else { b"time:" };
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut i = 0;
+        while i < buf.len() && self.seek < self.data.len() {
+            buf[i] = self.data[self.seek];
+            i += 1;
+            self.seek += 1;
+        }
+        Ok(i)
+    }
+
+    /// Set the wall clock and the RTC
+    ///
+    /// Only a process with I/O privileges may, since it could change the RTC through its ports anyway.
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let iopl = try!(unsafe { & *::env().contexts.get() }.current()).iopl;
+        if iopl != 3 {
+            return Err(Error::new(EPERM));
+        }
+
+        let date = try!(parse(str::from_utf8(buf).unwrap_or("").trim()).ok_or(Error::new(EINVAL)));
+        if ! Rtc::new().set_date(&date) {
+            return Err(Error::new(EIO));
+        }
+        unsafe { *::env().clock_realtime.get() = Duration::new(date.to_unix(), 0) };
+        syslog_info!("Clock set to {}", date);
+
+        self.data = contents(self.rtc).into_bytes();
+        self.seek = 0;
+
+        Ok(buf.len())
+    }
+
+    fn seek(&mut self, pos: ResourceSeek) -> Result<usize> {
+        self.seek = match pos {
+            ResourceSeek::Start(offset) => cmp::min(self.data.len(), offset),
+            ResourceSeek::Current(offset) => cmp::max(0, cmp::min(self.data.len() as isize, self.seek as isize + offset)) as usize,
+            ResourceSeek::End(offset) => cmp::max(0, cmp::min(self.data.len() as isize, self.data.len() as isize + offset)) as usize,
+        };
+        Ok(self.seek)
+    }
+}
+
+/// The time scheme
+///
+/// - `time:` is the wall clock, kept by the timer after it is read from the RTC at boot
+/// - `time:/rtc` is the RTC, read again
+/// - `time:/clock` is the source of the monotonic clock, its rate and its reading
+///
+/// Writing seconds since the Unix epoch or a date in UTC to `time:` or `time:/rtc` sets both.
+pub struct TimeScheme;
+
+impl KScheme for TimeScheme {
+    fn scheme(&self) -> &str {
+        "time"
+    }
+
+    fn open(&mut self, url: &str, _flags: usize) -> Result<Box<Resource>> {
+        let rtc = match url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/') {
+            "" => false,
+            "rtc" => true,
+            "clock" => return Ok(box VecResource::new("time:/clock".into(), timekeeping::clock_status().into_bytes(), MODE_FILE)),
+            _ => return Err(Error::new(ENOENT)),
+        };
+
+        Ok(box TimeResource {
+            rtc: rtc,
+            data: contents(rtc).into_bytes(),
+            seek: 0,
+        })
+    }
+}
@@ -124,16 +124,17 @@ pub fn execute_thread(context_ptr: *mut Context, entry: usize, mut args: Vec<Str
     }
 }
 
-/// Execute an executable
-pub fn execute(mut args: Vec<String>) -> Result<usize> {
+/// Read all of the file at the canonical `path` into the kernel
+///
+/// The current context must be a process, since a scheme in userspace reads into its memory.
+pub fn read_file(path: &str) -> Result<Vec<u8>> {
     let contexts = unsafe { &mut *::env().contexts.get() };
     let current = try!(contexts.current_mut());
 
     let mut vec: Vec<u8> = Vec::new();
 
-    let path = current.canonicalize(args.get(0).map_or("", |p| &p));
     {
-        let mut resource = try!(::env().open(&path, O_RDONLY));
+        let mut resource = try!(::env().open(path, O_RDONLY));
 
         // Hack to allow file scheme to find memory in context's memory space
         unsafe {
@@ -179,6 +180,17 @@ pub fn execute(mut args: Vec<String>) -> Result<usize> {
         }
     }
 
+    Ok(vec)
+}
+
+/// Execute an executable
+pub fn execute(mut args: Vec<String>) -> Result<usize> {
+    let contexts = unsafe { &mut *::env().contexts.get() };
+    let current = try!(contexts.current_mut());
+
+    let path = current.canonicalize(args.get(0).map_or("", |p| &p));
+    let vec = try!(read_file(&path));
+
     if vec.starts_with(b"#!") {
         if let Some(mut arg) = args.get_mut(0) {
             *arg = path.to_string();
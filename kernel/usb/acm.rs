@@ -0,0 +1,431 @@
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+
+use arch::context::{context_switch, Context};
+use arch::timekeeping;
+
+use collections::string::{String, ToString};
+use collections::vec::Vec;
+use collections::vec_deque::VecDeque;
+
+use common::to_num::ToNum;
+
+use core::cell::UnsafeCell;
+use core::{cmp, mem, slice, str};
+
+use fs::{KScheme, Resource, VecResource};
+
+use system::error::{Error, Result, EINVAL, ENODEV, ENOENT};
+use system::syscall::MODE_FILE;
+
+use super::{Packet, Pipe, Setup, UsbHc};
+use super::desc::{find_endpoint, find_interface};
+use super::device::{usb_attached, usb_claim};
+
+pub const CDC_CLASS: u8 = 2;
+pub const CDC_SUBCLASS_ACM: u8 = 2;
+pub const CDC_DATA_CLASS: u8 = 10;
+
+const NOTIFICATION_SERIAL_STATE: u8 = 0x20;
+
+const LINE_DTR: u16 = 1 << 0;
+const LINE_RTS: u16 = 1 << 1;
+
+const STATE_DCD: u16 = 1 << 0;
+const STATE_DSR: u16 = 1 << 1;
+const STATE_RING: u16 = 1 << 3;
+
+/// The most bytes kept for reading, what the device sends while it is full is dropped like a UART overrun
+const INPUT_MAX: usize = 16384;
+
+/// The baud rate and character format, as SET_LINE_CODING takes it
+#[repr(packed)]
+#[derive(Copy, Clone)]
+struct LineCoding {
+    rate: u32,
+    /// 0 for 1 stop bit, 1 for 1.5 and 2 for 2
+    stop_bits: u8,
+    /// None, odd, even, mark or space
+    parity: u8,
+    data_bits: u8,
+}
+
+impl LineCoding {
+    /// The format in the usual short form, like 8N1
+    fn format(&self) -> String {
+        let parity = ['N', 'O', 'E', 'M', 'S'].get(self.parity as usize).map_or('?', |&parity| parity);
+        let stop_bits = match self.stop_bits {
+            0 => "1",
+            1 => "1.5",
+            _ => "2",
+        };
+        format!("{}{}{}", self.data_bits, parity, stop_bits)
+    }
+
+    /// Change the format to one given like 8N1, returning whether it was valid
+    fn set_format(&mut self, format: &str) -> bool {
+        let mut chars = format.chars();
+        let data_bits = match chars.next() {
+            Some(c @ '5' ... '8') => c as u8 - b'0',
+            _ => return false,
+        };
+        let parity = match chars.next() {
+            Some('N') => 0,
+            Some('O') => 1,
+            Some('E') => 2,
+            Some('M') => 3,
+            Some('S') => 4,
+            _ => return false,
+        };
+        let stop_bits = match chars.as_str() {
+            "1" => 0,
+            "1.5" => 1,
+            "2" => 2,
+            _ => return false,
+        };
+
+        self.data_bits = data_bits;
+        self.parity = parity;
+        self.stop_bits = stop_bits;
+        true
+    }
+}
+
+/// A CDC ACM device, with the bytes it has sent that were not read yet
+pub struct UsbSerial {
+    hci: *mut UsbHc,
+    address: u8,
+    /// The communication interface, which takes the class requests
+    interface: u8,
+    bulk_in: u8,
+    bulk_out: u8,
+    line_coding: LineCoding,
+    /// The state bits of the last SERIAL_STATE notification
+    serial_state: u16,
+    input: VecDeque<u8>,
+    /// The bytes dropped because nobody read the input
+    overruns: usize,
+    removed: bool,
+}
+
+impl UsbSerial {
+    unsafe fn set_line_coding(&mut self, line_coding: LineCoding) -> Result<()> {
+        try!((*self.hci).control_out(self.address, &Setup::set_line_coding(self.interface),
+                                     slice::from_raw_parts(&line_coding as *const LineCoding as *const u8,
+                                                           mem::size_of::<LineCoding>())));
+        self.line_coding = line_coding;
+        Ok(())
+    }
+
+    fn status(&self) -> String {
+        let rate = self.line_coding.rate;
+        let state = self.serial_state;
+        format!("baud: {}\nformat: {}\ndcd: {}\ndsr: {}\nring: {}\noverruns: {}\n",
+                rate,
+                self.line_coding.format(),
+                state & STATE_DCD == STATE_DCD,
+                state & STATE_DSR == STATE_DSR,
+                state & STATE_RING == STATE_RING,
+                self.overruns)
+    }
+}
+
+/// The CDC ACM devices, by the number they have in the serial-usb scheme, a removed one leaves None
+static mut USB_SERIALS: Option<Vec<Option<Arc<UnsafeCell<UsbSerial>>>>> = None;
+
+fn usb_serials() -> &'static mut Vec<Option<Arc<UnsafeCell<UsbSerial>>>> {
+    unsafe {
+        if USB_SERIALS.is_none() {
+            USB_SERIALS = Some(Vec::new());
+        }
+        USB_SERIALS.as_mut().unwrap()
+    }
+}
+
+/// Start the serial driver if the device at `address` of `hci` has a CDC ACM interface with a data interface
+pub unsafe fn serial(hci: *mut UsbHc, address: u8) {
+    let device = match usb_claim("serial", |device| {
+        device.on(hci) && device.address == address
+            && find_interface(&device.configuration, CDC_CLASS, Some(CDC_SUBCLASS_ACM), None).is_some()
+            && find_interface(&device.configuration, CDC_DATA_CLASS, None, None).is_some()
+    }) {
+        Some(device) => device,
+        None => return,
+    };
+    let generation = device.generation;
+
+    let desc_comm = find_interface(&device.configuration, CDC_CLASS, Some(CDC_SUBCLASS_ACM), None).unwrap();
+    let desc_data = find_interface(&device.configuration, CDC_DATA_CLASS, None, None).unwrap();
+    let (bulk_in, bulk_out) = match (find_endpoint(&device.configuration, &desc_data, Pipe::Bulk, true),
+                                     find_endpoint(&device.configuration, &desc_data, Pipe::Bulk, false)) {
+        (Some(bulk_in), Some(bulk_out)) => (bulk_in.number(), bulk_out.number()),
+        _ => {
+            syslog_warning!("USB serial: No bulk endpoints");
+            device.driver = None;
+            return;
+        }
+    };
+
+    // The notifications are optional, without them the line state is never known
+    let notifications = match find_endpoint(&device.configuration, &desc_comm, Pipe::Interrupt, true) {
+        Some(desc_end) => match (*hci).interrupt_open(address, &desc_end) {
+            Ok(pipe) => Some(pipe),
+            Err(err) => {
+                syslog_warning!("USB serial: Failed to open notification pipe: {}", err);
+                None
+            }
+        },
+        None => None,
+    };
+
+    let arc = Arc::new(UnsafeCell::new(UsbSerial {
+        hci: hci,
+        address: address,
+        interface: desc_comm.number,
+        bulk_in: bulk_in,
+        bulk_out: bulk_out,
+        line_coding: LineCoding {
+            rate: 115200,
+            stop_bits: 0,
+            parity: 0,
+            data_bits: 8,
+        },
+        serial_state: 0,
+        input: VecDeque::new(),
+        overruns: 0,
+        removed: false,
+    }));
+
+    {
+        let serial = &mut *arc.get();
+        let line_coding = serial.line_coding;
+        if let Err(err) = serial.set_line_coding(line_coding) {
+            syslog_warning!("USB serial: Failed to set line coding: {}", err);
+        }
+        if let Err(err) = (*hci).control_out(address, &Setup::set_control_line_state(serial.interface, LINE_DTR | LINE_RTS), &[]) {
+            syslog_warning!("USB serial: Failed to set control lines: {}", err);
+        }
+    }
+
+    usb_serials().push(Some(arc.clone()));
+    syslog_info!("USB serial: attached as serial-usb:/{}", usb_serials().len() - 1);
+
+    Context::spawn("kusb_serial".into(),
+                   box move || {
+        let serial = &mut *arc.get();
+
+        while usb_attached(hci, address, generation) {
+            // A bulk endpoint with nothing to send NAKs like an interrupt endpoint, polling it with the short
+            // interrupt timeout keeps the controller free for other devices
+            let mut data = [0; 512];
+            let count = (*hci).msg(address, serial.bulk_in, Pipe::Interrupt, &[Packet::In(&mut data)]).unwrap_or(0);
+            let room = cmp::min(count, INPUT_MAX - serial.input.len());
+            serial.input.extend(data[.. room].iter().cloned());
+            if room < count {
+                if serial.overruns == 0 {
+                    syslog_warning!("USB serial: Input full, dropping data");
+                }
+                serial.overruns += count - room;
+            }
+
+            if let Some(ref pipe) = notifications {
+                let mut notification = [0; 16];
+                if let Some(len) = (*hci).interrupt_report(pipe, &mut notification) {
+                    if len >= 10 && notification[1] == NOTIFICATION_SERIAL_STATE {
+                        serial.serial_state = notification[8] as u16 | (notification[9] as u16) << 8;
+                    }
+                }
+            }
+
+            if count == 0 {
+                timekeeping::sleep(10, "USB serial sleep");
+            }
+        }
+
+        if let Some(ref pipe) = notifications {
+            (*hci).interrupt_close(pipe);
+        }
+
+        // Reads waiting for data end, writes fail with ENODEV
+        serial.removed = true;
+
+        let serials = usb_serials();
+        if let Some(index) = serials.iter().position(|other| other.as_ref().map_or(false, |other| other.get() == arc.get())) {
+            // The slot is kept, so the devices after it keep their numbers
+            serials[index] = None;
+            syslog_info!("USB serial: detached serial-usb:/{}", index);
+        }
+    });
+}
+
+/// The data of a CDC ACM device
+pub struct UsbSerialResource {
+    serial: Arc<UnsafeCell<UsbSerial>>,
+    path: String,
+}
+
+impl Resource for UsbSerialResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box UsbSerialResource {
+            serial: self.serial.clone(),
+            path: self.path.clone(),
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = self.path.as_bytes();
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    /// Wait for the device to send something, then read what it has sent, or nothing once it is removed
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let serial = unsafe { &mut *self.serial.get() };
+        while serial.input.is_empty() {
+            if serial.removed {
+                return Ok(0);
+            }
+            unsafe { context_switch() };
+        }
+
+        let mut i = 0;
+        while i < buf.len() {
+            match serial.input.pop_front() {
+                Some(b) => {
+                    buf[i] = b;
+                    i += 1;
+                },
+                None => break,
+            }
+        }
+
+        Ok(i)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let serial = unsafe { &mut *self.serial.get() };
+        if serial.removed {
+            return Err(Error::new(ENODEV));
+        }
+
+        unsafe { (*serial.hci).bulk_out(serial.address, serial.bulk_out, buf) }
+    }
+}
+
+/// The line settings and state of a CDC ACM device
+///
+/// Writing a baud rate, a format like 8N1, or both separated by a space changes them.
+pub struct UsbSerialCtlResource {
+    serial: Arc<UnsafeCell<UsbSerial>>,
+    path: String,
+    seek: usize,
+}
+
+impl Resource for UsbSerialCtlResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box UsbSerialCtlResource {
+            serial: self.serial.clone(),
+            path: self.path.clone(),
+            seek: self.seek,
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = self.path.as_bytes();
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let status = unsafe { & *self.serial.get() }.status();
+        let data = status.as_bytes();
+
+        let mut i = 0;
+        while i < buf.len() && self.seek < data.len() {
+            buf[i] = data[self.seek];
+            i += 1;
+            self.seek += 1;
+        }
+
+        Ok(i)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let serial = unsafe { &mut *self.serial.get() };
+        if serial.removed {
+            return Err(Error::new(ENODEV));
+        }
+
+        let mut line_coding = serial.line_coding;
+        for setting in str::from_utf8(buf).unwrap_or("").split_whitespace() {
+            if setting.chars().all(|c| c.is_digit(10)) {
+                line_coding.rate = setting.to_num() as u32;
+            } else if ! line_coding.set_format(setting) {
+                return Err(Error::new(EINVAL));
+            }
+        }
+        if line_coding.rate == 0 {
+            return Err(Error::new(EINVAL));
+        }
+
+        try!(unsafe { serial.set_line_coding(line_coding) });
+        self.seek = 0;
+
+        Ok(buf.len())
+    }
+}
+
+/// The serial-usb scheme, `serial-usb:/N` is the data of device N and `serial-usb:/N/ctl` its line settings
+pub struct UsbSerialScheme;
+
+impl KScheme for UsbSerialScheme {
+    fn scheme(&self) -> &str {
+        "serial-usb"
+    }
+
+    fn open(&mut self, url: &str, _: usize) -> Result<Box<Resource>> {
+        let reference = url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/');
+
+        if reference.is_empty() {
+            let mut list = String::new();
+            for i in (0..usb_serials().len()).filter(|&i| usb_serials()[i].is_some()) {
+                if ! list.is_empty() {
+                    list.push('\n');
+                }
+                list.push_str(&i.to_string());
+            }
+            return Ok(box VecResource::new("serial-usb:/".to_string(), list.into_bytes(), MODE_FILE));
+        }
+
+        let mut parts = reference.splitn(2, '/');
+        let number = parts.next().unwrap_or("");
+        if ! number.chars().all(|c| c.is_digit(10)) {
+            return Err(Error::new(ENOENT));
+        }
+        let serial = match usb_serials().get(number.to_num()) {
+            Some(&Some(ref serial)) => serial.clone(),
+            None => return Err(Error::new(ENOENT)),
+        };
+
+        match parts.next() {
+            None => Ok(box UsbSerialResource {
+                serial: serial,
+                path: format!("serial-usb:/{}", reference),
+            }),
+            Some("ctl") => Ok(box UsbSerialCtlResource {
+                serial: serial,
+                path: format!("serial-usb:/{}", reference),
+                seek: 0,
+            }),
+            Some(_) => Err(Error::new(ENOENT)),
+        }
+    }
+}
@@ -0,0 +1,354 @@
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+
+use arch::context::{context_switch, Context};
+use arch::timekeeping;
+
+use collections::string::{String, ToString};
+use collections::vec::Vec;
+use collections::vec_deque::VecDeque;
+
+use common::to_num::ToNum;
+
+use core::cell::UnsafeCell;
+use core::cmp;
+
+use fs::{KScheme, Resource, VecResource};
+
+use system::error::{Error, Result, ENODEV, ENOENT};
+use system::syscall::MODE_FILE;
+
+use super::{Pipe, Setup, UsbHc};
+use super::desc::{descriptors, interface_descriptors, Descriptor, EndpointDescriptor, InterfaceDescriptor};
+use super::device::{usb_attached, usb_claim};
+use super::hci::{IsochBuffer, IsochStatus};
+
+pub const AUDIO_CLASS: u8 = 1;
+pub const AUDIO_SUBCLASS_STREAMING: u8 = 2;
+
+const CS_INTERFACE: u8 = 0x24;
+const AS_GENERAL: u8 = 1;
+const FORMAT_TYPE: u8 = 2;
+const FORMAT_TYPE_I: u8 = 1;
+const FORMAT_PCM: u16 = 1;
+
+/// The rate a device is set to if it has it, the one most sound is made at
+const PREFERRED_RATE: u32 = 48000;
+/// Intervals in each buffer queued on the stream
+const PACKETS: usize = 8;
+/// Buffers kept on the stream, so it goes on while the next one is filled
+const BUFFERS: usize = 4;
+/// Bytes written and not yet queued, a write waits while there are more
+const PCM_MAX: usize = 65536;
+
+/// An alternate setting of an audio streaming interface that plays 16 bit PCM
+struct Playback {
+    desc_int: InterfaceDescriptor,
+    desc_end: EndpointDescriptor,
+    channels: u8,
+    rate: u32,
+    /// Whether the device offers more than one rate, so the one chosen has to be set
+    set_rate: bool,
+}
+
+/// The rate of a Type I format descriptor to play at, with whether there is a choice
+fn rate(format: &[u8]) -> Option<(u32, bool)> {
+    let rate_at = |i: usize| if format.len() >= 11 + i * 3 {
+        let bytes = &format[8 + i * 3 ..];
+        Some(bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16)
+    } else {
+        None
+    };
+    match format[7] {
+        // A continuous range, from the lower to the upper rate
+        0 => match (rate_at(0), rate_at(1)) {
+            (Some(lower), Some(upper)) if lower <= PREFERRED_RATE && PREFERRED_RATE <= upper => Some((PREFERRED_RATE, true)),
+            (Some(lower), Some(_)) => Some((lower, true)),
+            _ => None,
+        },
+        count => {
+            let rates: Vec<u32> = (0..count as usize).filter_map(|i| rate_at(i)).collect();
+            let choice = rates.iter().find(|&&rate| rate == PREFERRED_RATE).or(rates.first()).map(|&rate| rate);
+            choice.map(|rate| (rate, rates.len() > 1))
+        }
+    }
+}
+
+/// The first setting of a streaming interface in `configuration` with an isochronous OUT endpoint and 16 bit PCM
+fn playback(configuration: &[u8]) -> Option<Playback> {
+    for descriptor in descriptors(configuration) {
+        let desc_int = match descriptor {
+            Descriptor::Interface(desc_int) if desc_int.class == AUDIO_CLASS
+                                               && desc_int.sub_class == AUDIO_SUBCLASS_STREAMING
+                                               && desc_int.alternate != 0 => desc_int,
+            _ => continue,
+        };
+
+        let mut pcm = false;
+        let mut format = None;
+        let mut endpoint = None;
+        for descriptor in interface_descriptors(configuration, &desc_int) {
+            match descriptor {
+                Descriptor::Other(CS_INTERFACE, data) if data.len() >= 7 && data[2] == AS_GENERAL => {
+                    pcm = (data[5] as u16 | (data[6] as u16) << 8) == FORMAT_PCM;
+                },
+                // Type I, with 2 byte subframes holding 16 bit samples
+                Descriptor::Other(CS_INTERFACE, data) if data.len() >= 8 && data[2] == FORMAT_TYPE
+                                                         && data[3] == FORMAT_TYPE_I
+                                                         && data[5] == 2 && data[6] == 16 => {
+                    format = Some(data);
+                },
+                Descriptor::Endpoint(desc_end) if desc_end.pipe() == Pipe::Isochronous && ! desc_end.is_in() => {
+                    endpoint = Some(desc_end);
+                },
+                _ => (),
+            }
+        }
+
+        if let (true, Some(format), Some(desc_end)) = (pcm, format, endpoint) {
+            if format[4] == 0 || format[4] > 2 {
+                continue;
+            }
+            if let Some((rate, set_rate)) = rate(format) {
+                return Some(Playback {
+                    desc_int: desc_int,
+                    desc_end: desc_end,
+                    channels: format[4],
+                    rate: rate,
+                    set_rate: set_rate,
+                });
+            }
+        }
+    }
+
+    None
+}
+
+/// A USB Audio Class 1 device playing what is written to it
+pub struct UsbAudio {
+    rate: u32,
+    channels: u8,
+    /// The PCM written and not yet queued
+    pcm: VecDeque<u8>,
+    /// Packets whose interval went by before they were sent, or failed
+    missed: usize,
+    removed: bool,
+}
+
+/// The audio devices, by the number they have in the audio-usb scheme, a removed one leaves None
+static mut USB_AUDIOS: Option<Vec<Option<Arc<UnsafeCell<UsbAudio>>>>> = None;
+
+fn usb_audios() -> &'static mut Vec<Option<Arc<UnsafeCell<UsbAudio>>>> {
+    unsafe {
+        if USB_AUDIOS.is_none() {
+            USB_AUDIOS = Some(Vec::new());
+        }
+        USB_AUDIOS.as_mut().unwrap()
+    }
+}
+
+/// Start the audio driver if the device at `address` of `hci` can play 16 bit PCM
+///
+/// The streaming interface is switched to the setting that plays it, at 48 kHz if the device has it.
+pub unsafe fn audio(hci: *mut UsbHc, address: u8) {
+    let device = match usb_claim("audio", |device| {
+        device.on(hci) && device.address == address && playback(&device.configuration).is_some()
+    }) {
+        Some(device) => device,
+        None => return,
+    };
+    let generation = device.generation;
+
+    let playback = playback(&device.configuration).unwrap();
+    let frame_bytes = playback.channels as usize * 2;
+
+    let stream = match (*hci).isoch_open(address, &playback.desc_end) {
+        Ok(stream) => stream,
+        Err(err) => {
+            syslog_warning!("USB audio: Failed to open stream: {}", err);
+            device.driver = None;
+            return;
+        }
+    };
+
+    // A packet carries the frames of one interval, with one more now and then when the rate does not divide evenly
+    let most = ((playback.rate as usize * stream.interval as usize + 7999) / 8000) * frame_bytes;
+    if most > playback.desc_end.max_packet_size() as usize {
+        syslog_warning!("USB audio: {} Hz does not fit in {} byte packets", playback.rate, playback.desc_end.max_packet_size());
+        (*hci).isoch_close(&stream);
+        device.driver = None;
+        return;
+    }
+
+    if let Err(err) = (*hci).set_interface(address, &device.configuration, &playback.desc_int) {
+        syslog_warning!("USB audio: Failed to select setting {}: {}", playback.desc_int.alternate, err);
+        (*hci).isoch_close(&stream);
+        device.driver = None;
+        return;
+    }
+    if playback.set_rate {
+        let rate = playback.rate;
+        let data = [rate as u8, (rate >> 8) as u8, (rate >> 16) as u8];
+        if let Err(err) = (*hci).control_out(address, &Setup::set_sampling_frequency(playback.desc_end.address), &data) {
+            syslog_warning!("USB audio: Failed to set the rate: {}", err);
+        }
+    }
+
+    let arc = Arc::new(UnsafeCell::new(UsbAudio {
+        rate: playback.rate,
+        channels: playback.channels,
+        pcm: VecDeque::new(),
+        missed: 0,
+        removed: false,
+    }));
+
+    usb_audios().push(Some(arc.clone()));
+    syslog_info!("USB audio: {} Hz, {} channels, attached as audio-usb:/{}",
+                 playback.rate, playback.channels, usb_audios().len() - 1);
+
+    Context::spawn("kusb_audio".into(),
+                   box move || {
+        let audio = &mut *arc.get();
+        let per_packet = audio.rate as usize * stream.interval as usize;
+
+        let mut queued = 0;
+        // The sample frames over a whole number in the intervals queued so far, in 1/8000ths
+        let mut remainder = 0;
+        while usb_attached(hci, address, generation) {
+            while let Some(buffer) = (*hci).isoch_reap(&stream) {
+                queued -= 1;
+                audio.missed += buffer.packets.iter().filter(|packet| match packet.status {
+                    IsochStatus::Missed | IsochStatus::Failed => true,
+                    _ => false,
+                }).count();
+            }
+
+            // With nothing written the stream runs dry, and starts again with the next write
+            while queued < BUFFERS && ! audio.pcm.is_empty() {
+                let mut data = Vec::new();
+                let mut lengths = Vec::new();
+                for _ in 0..PACKETS {
+                    let frames = (remainder + per_packet) / 8000;
+                    remainder = (remainder + per_packet) % 8000;
+
+                    // What was written last is made up to whole packets with silence
+                    for _ in 0..frames * frame_bytes {
+                        data.push(audio.pcm.pop_front().unwrap_or(0));
+                    }
+                    lengths.push((frames * frame_bytes) as u16);
+                }
+
+                match (*hci).isoch_queue(&stream, IsochBuffer::new(data, &lengths)) {
+                    Ok(()) => queued += 1,
+                    Err(err) => {
+                        syslog_debug!("USB audio: Failed to queue: {}", err);
+                        break;
+                    }
+                }
+            }
+
+            timekeeping::sleep(2, "USB audio sleep");
+        }
+
+        (*hci).isoch_close(&stream);
+
+        // Writes waiting for room end with ENODEV
+        audio.removed = true;
+
+        let audios = usb_audios();
+        if let Some(index) = audios.iter().position(|other| other.as_ref().map_or(false, |other| other.get() == arc.get())) {
+            // The slot is kept, so the devices after it keep their numbers
+            audios[index] = None;
+            syslog_info!("USB audio: detached audio-usb:/{}", index);
+        }
+    });
+}
+
+/// The playback of an audio device, which takes interleaved 16 bit little endian samples at its rate
+pub struct UsbAudioResource {
+    audio: Arc<UnsafeCell<UsbAudio>>,
+    path: String,
+}
+
+impl Resource for UsbAudioResource {
+    fn dup(&self) -> Result<Box<Resource>> {
+        Ok(box UsbAudioResource {
+            audio: self.audio.clone(),
+            path: self.path.clone(),
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Result<usize> {
+        let path = self.path.as_bytes();
+
+        for (b, p) in buf.iter_mut().zip(path.iter()) {
+            *b = *p;
+        }
+
+        Ok(cmp::min(buf.len(), path.len()))
+    }
+
+    /// Wait for room, then queue `buf` to be played after what was written before it
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let audio = unsafe { &mut *self.audio.get() };
+        while audio.pcm.len() + buf.len() > cmp::max(PCM_MAX, buf.len()) {
+            if audio.removed {
+                return Err(Error::new(ENODEV));
+            }
+            unsafe { context_switch() };
+        }
+        if audio.removed {
+            return Err(Error::new(ENODEV));
+        }
+
+        audio.pcm.extend(buf.iter().cloned());
+        Ok(buf.len())
+    }
+}
+
+/// The audio-usb scheme, `audio-usb:/N` plays on device N and `audio-usb:/N/format` reads what it takes
+pub struct UsbAudioScheme;
+
+impl KScheme for UsbAudioScheme {
+    fn scheme(&self) -> &str {
+        "audio-usb"
+    }
+
+    fn open(&mut self, url: &str, _: usize) -> Result<Box<Resource>> {
+        let reference = url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/');
+
+        if reference.is_empty() {
+            let mut list = String::new();
+            for i in (0..usb_audios().len()).filter(|&i| usb_audios()[i].is_some()) {
+                if ! list.is_empty() {
+                    list.push('\n');
+                }
+                list.push_str(&i.to_string());
+            }
+            return Ok(box VecResource::new("audio-usb:/".to_string(), list.into_bytes(), MODE_FILE));
+        }
+
+        let mut parts = reference.splitn(2, '/');
+        let number = parts.next().unwrap_or("");
+        if ! number.chars().all(|c| c.is_digit(10)) {
+            return Err(Error::new(ENOENT));
+        }
+        let audio = match usb_audios().get(number.to_num()) {
+            Some(&Some(ref audio)) => audio.clone(),
+            None => return Err(Error::new(ENOENT)),
+        };
+
+        match parts.next() {
+            None => Ok(box UsbAudioResource {
+                audio: audio,
+                path: format!("audio-usb:/{}", reference),
+            }),
+            Some("format") => {
+                let audio = unsafe { & *audio.get() };
+                let format = format!("rate: {}\nchannels: {}\nbits: 16\nmissed: {}\n", audio.rate, audio.channels, audio.missed);
+                Ok(box VecResource::new(format!("audio-usb:/{}", reference), format.into_bytes(), MODE_FILE))
+            },
+            Some(_) => Err(Error::new(ENOENT)),
+        }
+    }
+}
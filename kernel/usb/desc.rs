@@ -1,4 +1,9 @@
-use core::{cmp, str};
+use collections::string::String;
+use collections::vec::Vec;
+
+use core::{cmp, mem, ptr};
+
+use super::{Pipe, Speed};
 
 pub const DESC_DEV: u8 = 1;
 #[repr(packed)]
@@ -35,20 +40,6 @@ pub struct ConfigDescriptor {
 }
 
 pub const DESC_STR: u8 = 3;
-#[repr(packed)]
-#[derive(Copy, Clone, Debug, Default)]
-pub struct StringDescriptor {
-    pub length: u8,
-    pub descriptor_type: u8,
-    //TODO: Support longer strings
-    pub data: [u8; 32],
-}
-
-impl StringDescriptor {
-    pub fn str<'a>(&'a self) -> &'a str {
-        unsafe { str::from_utf8_unchecked(&self.data[.. cmp::min(self.length as usize, self.data.len())]) }
-    }
-}
 
 pub const DESC_INT: u8 = 4;
 #[repr(packed)]
@@ -77,6 +68,44 @@ pub struct EndpointDescriptor {
     pub interval: u8,
 }
 
+impl EndpointDescriptor {
+    pub fn number(&self) -> u8 {
+        self.address & 0xF
+    }
+
+    pub fn is_in(&self) -> bool {
+        self.address & 0x80 == 0x80
+    }
+
+    pub fn pipe(&self) -> Pipe {
+        match self.attributes & 0b11 {
+            0 => Pipe::Control,
+            1 => Pipe::Isochronous,
+            2 => Pipe::Bulk,
+            _ => Pipe::Interrupt,
+        }
+    }
+
+    /// The packet size, without the extra transactions per microframe of high bandwidth endpoints
+    pub fn max_packet_size(&self) -> u16 {
+        self.max_packet_size & 0x7FF
+    }
+
+    /// How often a periodic endpoint on a device of `speed` is run, in 125 us microframes rounded down to a power of two
+    ///
+    /// Full and low speed devices give the interval of interrupt endpoints in milliseconds, others are an exponent.
+    pub fn period(&self, speed: Speed) -> u32 {
+        match speed {
+            Speed::High | Speed::Super => 1 << (cmp::min(cmp::max(1, self.interval), 16) - 1),
+            Speed::Full if self.pipe() == Pipe::Isochronous => 8 << (cmp::min(cmp::max(1, self.interval), 16) - 1),
+            _ => {
+                let microframes = cmp::max(1, self.interval as u32) * 8;
+                1 << (31 - microframes.leading_zeros())
+            }
+        }
+    }
+}
+
 pub const DESC_HID: u8 = 0x21;
 #[repr(packed)]
 #[derive(Copy, Clone, Debug, Default)]
@@ -89,3 +118,135 @@ pub struct HIDDescriptor {
     pub sub_descriptor_type: u8,
     pub sub_descriptor_length: u16,
 }
+
+/// A descriptor in a configuration
+#[derive(Debug)]
+pub enum Descriptor<'a> {
+    Config(ConfigDescriptor),
+    Interface(InterfaceDescriptor),
+    Endpoint(EndpointDescriptor),
+    Hid(HIDDescriptor),
+    /// A class specific, unknown or truncated descriptor, with its type and bytes
+    Other(u8, &'a [u8]),
+}
+
+/// Copy a `T` from the start of `data`, if it is long enough
+fn read<T: Copy>(data: &[u8]) -> Option<T> {
+    if data.len() >= mem::size_of::<T>() {
+        // Descriptors are packed, so any address is aligned
+        Some(unsafe { ptr::read(data.as_ptr() as *const T) })
+    } else {
+        None
+    }
+}
+
+/// The descriptors in a configuration buffer, which stop at the first one that does not fit
+pub struct Descriptors<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Descriptors<'a> {
+    type Item = Descriptor<'a>;
+
+    fn next(&mut self) -> Option<Descriptor<'a>> {
+        if self.data.len() < 2 {
+            return None;
+        }
+
+        let length = self.data[0] as usize;
+        if length < 2 || length > self.data.len() {
+            self.data = &[];
+            return None;
+        }
+
+        let (data, rest) = self.data.split_at(length);
+        self.data = rest;
+
+        let descriptor = match data[1] {
+            DESC_CFG => read(data).map(Descriptor::Config),
+            DESC_INT => read(data).map(Descriptor::Interface),
+            DESC_END => read(data).map(Descriptor::Endpoint),
+            DESC_HID => read(data).map(Descriptor::Hid),
+            _ => None,
+        };
+        Some(descriptor.unwrap_or(Descriptor::Other(data[1], data)))
+    }
+}
+
+pub fn descriptors<'a>(data: &'a [u8]) -> Descriptors<'a> {
+    Descriptors {
+        data: data,
+    }
+}
+
+/// The first interface in `configuration` of `class`, and of `sub_class` and `protocol` if they are given
+pub fn find_interface(configuration: &[u8], class: u8, sub_class: Option<u8>, protocol: Option<u8>) -> Option<InterfaceDescriptor> {
+    for descriptor in descriptors(configuration) {
+        if let Descriptor::Interface(desc_int) = descriptor {
+            if desc_int.class == class
+                && sub_class.map_or(true, |sub_class| desc_int.sub_class == sub_class)
+                && protocol.map_or(true, |protocol| desc_int.protocol == protocol) {
+                return Some(desc_int);
+            }
+        }
+    }
+
+    None
+}
+
+/// The descriptors following `desc_int` in `configuration`, up to the next interface
+pub fn interface_descriptors<'a>(configuration: &'a [u8], desc_int: &InterfaceDescriptor) -> Vec<Descriptor<'a>> {
+    let mut found = false;
+    let mut interface = Vec::new();
+    for descriptor in descriptors(configuration) {
+        if let Descriptor::Interface(other) = descriptor {
+            if found {
+                break;
+            }
+            found = other.number == desc_int.number && other.alternate == desc_int.alternate;
+        } else if found {
+            interface.push(descriptor);
+        }
+    }
+    interface
+}
+
+/// The first endpoint of `desc_int` in `configuration` with the transfer type `pipe`, going in or out
+pub fn find_endpoint(configuration: &[u8], desc_int: &InterfaceDescriptor, pipe: Pipe, direction_in: bool) -> Option<EndpointDescriptor> {
+    for descriptor in interface_descriptors(configuration, desc_int) {
+        if let Descriptor::Endpoint(desc_end) = descriptor {
+            if desc_end.pipe() == pipe && desc_end.is_in() == direction_in {
+                return Some(desc_end);
+            }
+        }
+    }
+
+    None
+}
+
+/// The UTF-16LE code units of a string descriptor
+fn string_units(data: &[u8]) -> Option<Vec<u16>> {
+    if data.len() < 2 || data[1] != DESC_STR {
+        return None;
+    }
+
+    let length = cmp::min(data[0] as usize, data.len());
+    if length < 2 {
+        return None;
+    }
+
+    Some(data[2 .. length].chunks(2)
+                          .filter(|unit| unit.len() == 2)
+                          .map(|unit| unit[0] as u16 | (unit[1] as u16) << 8)
+                          .collect())
+}
+
+/// The text of a string descriptor
+pub fn string(data: &[u8]) -> Option<String> {
+    string_units(data).map(|units| String::from_utf16_lossy(&units))
+}
+
+/// The language ids listed by string descriptor 0
+pub fn languages(data: &[u8]) -> Vec<u16> {
+    string_units(data).unwrap_or(Vec::new())
+}
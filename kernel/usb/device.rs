@@ -0,0 +1,181 @@
+use arch::context::context_switch;
+
+use collections::string::String;
+use collections::vec::Vec;
+
+use env::log::InterruptGuard;
+
+use super::{UsbHc, Speed};
+use super::desc::DeviceDescriptor;
+
+/// A device enumerated by a controller, waiting to be claimed by a class driver
+pub struct UsbDevice {
+    /// The controller the device is attached to
+    pub hci: *mut UsbHc,
+    /// The address of the hub the device is attached to, 0 for the root hub
+    pub hub: u8,
+    /// The port of the hub, counting from 1
+    pub port: u8,
+    pub address: u8,
+    /// Counts the devices attached to every controller, one given an address another had has a newer one
+    pub generation: u64,
+    pub speed: Speed,
+    pub descriptor: DeviceDescriptor,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    /// The active configuration descriptor, followed by its interface, endpoint and class descriptors
+    pub configuration: Vec<u8>,
+    /// The class driver using the device, if any
+    pub driver: Option<&'static str>,
+}
+
+impl UsbDevice {
+    /// Whether the device is attached to `hci`
+    pub fn on(&self, hci: *mut UsbHc) -> bool {
+        self.hci as *mut u8 == hci as *mut u8
+    }
+}
+
+/// The devices of every controller
+pub static mut USB_DEVICES: Option<Vec<UsbDevice>> = None;
+
+pub fn usb_devices() -> &'static mut Vec<UsbDevice> {
+    unsafe {
+        if USB_DEVICES.is_none() {
+            USB_DEVICES = Some(Vec::new());
+        }
+        USB_DEVICES.as_mut().unwrap()
+    }
+}
+
+/// The generation of the device attached last
+static mut USB_GENERATION: u64 = 0;
+
+/// The generation of a device being attached, newer than that of every device before it
+pub fn usb_generation() -> u64 {
+    let _guard = InterruptGuard::new();
+    unsafe {
+        USB_GENERATION += 1;
+        USB_GENERATION
+    }
+}
+
+/// The controllers, in the order their first device was attached
+static mut USB_BUSES: Option<Vec<*mut u8>> = None;
+
+/// The bus number of `hci`, given to it when it is first seen
+pub fn usb_bus(hci: *mut UsbHc) -> usize {
+    unsafe {
+        if USB_BUSES.is_none() {
+            USB_BUSES = Some(Vec::new());
+        }
+        let buses = USB_BUSES.as_mut().unwrap();
+        match buses.iter().position(|&bus| bus == hci as *mut u8) {
+            Some(bus) => bus,
+            None => {
+                buses.push(hci as *mut u8);
+                buses.len() - 1
+            }
+        }
+    }
+}
+
+/// The lowest address not used by a device on `hci`
+pub fn usb_free_address(hci: *mut UsbHc) -> Option<u8> {
+    (1..128).find(|&address| ! usb_devices().iter().any(|device| device.on(hci) && device.address == address))
+}
+
+/// Claim the first unclaimed device accepted by `accept` for `driver`
+pub fn usb_claim<F: Fn(&UsbDevice) -> bool>(driver: &'static str, accept: F) -> Option<&'static mut UsbDevice> {
+    for device in usb_devices().iter_mut() {
+        if device.driver.is_none() && accept(device) {
+            device.driver = Some(driver);
+            return Some(device);
+        }
+    }
+
+    None
+}
+
+/// Forget the device on `port` of `hub` of `hci`, and every device behind it if it is a hub
+///
+/// Returns the addresses that were removed, those behind it first.
+pub fn usb_remove(hci: *mut UsbHc, hub: u8, port: u8) -> Vec<u8> {
+    let mut removed = Vec::new();
+
+    if let Some(i) = usb_devices().iter().position(|device| device.on(hci) && device.hub == hub && device.port == port) {
+        let address = usb_devices().remove(i).address;
+
+        let ports: Vec<u8> = usb_devices().iter()
+                                          .filter(|device| device.on(hci) && device.hub == address)
+                                          .map(|device| device.port)
+                                          .collect();
+        for port in ports {
+            removed.extend(usb_remove(hci, address, port));
+        }
+
+        removed.push(address);
+    }
+
+    removed
+}
+
+/// Whether the device of `generation` at `address` of `hci` is still attached
+///
+/// A device attached later at the same address has a newer generation, so the driver of the one removed
+/// stops even if it did not look while the address was free.
+pub fn usb_attached(hci: *mut UsbHc, address: u8, generation: u64) -> bool {
+    usb_devices().iter().any(|device| device.on(hci) && device.address == address && device.generation == generation)
+}
+
+/// The addresses whose device was removed, transfers to them are cut off
+static mut USB_CANCELLED: Option<Vec<(*mut u8, u8)>> = None;
+
+/// Fail the transfers in progress to `address` of `hci`, and those after them
+pub fn usb_cancel(hci: *mut UsbHc, address: u8) {
+    unsafe {
+        if USB_CANCELLED.is_none() {
+            USB_CANCELLED = Some(Vec::new());
+        }
+        USB_CANCELLED.as_mut().unwrap().push((hci as *mut u8, address));
+    }
+}
+
+/// Whether the device at `address` of `hci` was removed, and the controller should stop waiting for it
+pub fn usb_cancelled(hci: *mut UsbHc, address: u8) -> bool {
+    unsafe {
+        USB_CANCELLED.as_ref().map_or(false, |cancelled| cancelled.contains(&(hci as *mut u8, address)))
+    }
+}
+
+/// Let transfers to `address` of `hci` run again, when it is given to a new device
+///
+/// The drivers of the device that had it last stop at their next `usb_attached`, which compares
+/// generations, before they can start a transfer to the new one.
+pub fn usb_reuse(hci: *mut UsbHc, address: u8) {
+    unsafe {
+        if let Some(ref mut cancelled) = USB_CANCELLED {
+            cancelled.retain(|&entry| entry != (hci as *mut u8, address));
+        }
+    }
+}
+
+/// Set while a device answers at address 0, which only one device may do at a time
+static mut USB_ENUMERATING: bool = false;
+
+/// Wait for any device being enumerated to be given its address, before resetting a port
+pub fn usb_enumerate_begin() {
+    unsafe {
+        while USB_ENUMERATING {
+            context_switch();
+        }
+        USB_ENUMERATING = true;
+    }
+}
+
+pub fn usb_enumerate_end() {
+    unsafe {
+        USB_ENUMERATING = false;
+    }
+}
@@ -1,64 +1,318 @@
 use alloc::boxed::Box;
 
+use arch::context::{context_switch, Context};
+use arch::dma::{self, Dma};
+
+use common::time::{self, Duration};
+
 use collections::vec::Vec;
 
-use core::intrinsics::volatile_load;
-use core::mem::size_of;
-//use core::slice;
+use core::{cmp, mem};
 
 use drivers::io::{Io, Mmio};
 use drivers::pci::config::PciConfig;
 
-use arch::context::context_switch;
-
 use fs::KScheme;
 
-use super::{Hci, Packet, Pipe, Setup};
+use system::error::{Error, Result, EAGAIN, EINVAL, EIO, ENODEV, ENOSPC, EPIPE, ETIMEDOUT};
+
+use super::{delay, hold, physical, wait_for, Held, UsbHc, Packet, Pipe, Setup, Speed};
+use super::hci::{InterruptPipe, IsochBuffer, IsochStatus, IsochStream};
+use super::desc::EndpointDescriptor;
+use super::device::{usb_cancel, usb_cancelled, usb_enumerate_begin, usb_enumerate_end, usb_reuse};
+use super::pool::Descriptors;
+use super::watchdog::{Watchdog, WATCHDOG_TIMEOUTS};
+
+/// Milliseconds a control or bulk transfer may take before it is abandoned
+const TRANSFER_TIMEOUT: i32 = 1000;
+/// Milliseconds an interrupt transfer is polled, a device with nothing to report does not answer
+const INTERRUPT_TIMEOUT: i32 = 20;
+/// Bytes in one qTD, a multiple of every packet size that spans at most five pages
+const QTD_MAX: usize = 16384;
+
+const CMD_RS: u32 = 1;
+const CMD_HCRESET: u32 = 1 << 1;
+const CMD_PSE: u32 = 1 << 4;
+const CMD_ASE: u32 = 1 << 5;
+const CMD_IAAD: u32 = 1 << 6;
+const CMD_ITC_8: u32 = 8 << 16;
+
+const STS_IAA: u32 = 1 << 5;
+const STS_HCHALTED: u32 = 1 << 12;
+const STS_ASS: u32 = 1 << 15;
+
+const PORT_CCS: u32 = 1;
+const PORT_CSC: u32 = 1 << 1;
+const PORT_PE: u32 = 1 << 2;
+const PORT_PEC: u32 = 1 << 3;
+const PORT_OCC: u32 = 1 << 5;
+const PORT_PR: u32 = 1 << 8;
+const PORT_LS: u32 = 0b11 << 10;
+const PORT_LS_K: u32 = 0b01 << 10;
+const PORT_PP: u32 = 1 << 12;
+const PORT_PO: u32 = 1 << 13;
+/// The bits cleared by writing them
+const PORT_CHANGE: u32 = PORT_CSC | PORT_PEC | PORT_OCC;
+
+const LEGACY_SUPPORT: u32 = 1;
+const LEGACY_BIOS_OWNED: u32 = 1 << 16;
+const LEGACY_OS_OWNED: u32 = 1 << 24;
+/// The SMI enables of USBLEGCTLSTS, the status bits above them are cleared by writing them
+const LEGACY_SMI_ENABLES: u32 = 0b111111 | 0b111 << 13;
+
+const LINK_TERMINATE: u32 = 1;
+const LINK_ITD: u32 = 0 << 1;
+const LINK_QH: u32 = 1 << 1;
+const LINK_SITD: u32 = 2 << 1;
+
+const QH_EPS_FULL: u32 = 0 << 12;
+const QH_EPS_LOW: u32 = 1 << 12;
+const QH_EPS_HIGH: u32 = 2 << 12;
+const QH_DTC: u32 = 1 << 14;
+const QH_HEAD: u32 = 1 << 15;
+/// A control endpoint of a full or low speed device
+const QH_CONTROL: u32 = 1 << 27;
+const QH_NAK_RELOAD: u32 = 4 << 28;
+const QH_MULT_1: u32 = 1 << 30;
+
+const TOKEN_ACTIVE: u32 = 1 << 7;
+const TOKEN_HALTED: u32 = 1 << 6;
+const TOKEN_BUFFER_ERROR: u32 = 1 << 5;
+const TOKEN_BABBLE: u32 = 1 << 4;
+const TOKEN_XACT_ERROR: u32 = 1 << 3;
+const TOKEN_PID_OUT: u32 = 0 << 8;
+const TOKEN_PID_IN: u32 = 1 << 8;
+const TOKEN_PID_SETUP: u32 = 2 << 8;
+const TOKEN_ERROR_COUNT: u32 = 3 << 10;
+const TOKEN_TOGGLE: u32 = 1 << 31;
+
+const ITD_ACTIVE: u32 = 1 << 31;
+const ITD_BUFFER_ERROR: u32 = 1 << 30;
+const ITD_BABBLE: u32 = 1 << 29;
+const ITD_XACT_ERROR: u32 = 1 << 28;
+const ITD_DIR_IN: u32 = 1 << 11;
+
+const SITD_DIR_IN: u32 = 1 << 31;
+const SITD_ACTIVE: u32 = 1 << 7;
+const SITD_ERR: u32 = 1 << 6;
+const SITD_BUFFER_ERROR: u32 = 1 << 5;
+const SITD_BABBLE: u32 = 1 << 4;
+const SITD_XACT_ERROR: u32 = 1 << 3;
+const SITD_MISSED: u32 = 1 << 2;
+const SITD_TP_ALL: u32 = 0 << 3;
+const SITD_TP_BEGIN: u32 = 1 << 3;
+/// An IN split is started in microframe 0, and completed by the translator in any of 2 to 7
+const SITD_IN_SMASK: u32 = 0x01;
+const SITD_IN_CMASK: u32 = 0xFC;
+/// Bytes of a full speed transaction the translator takes in one start split
+const SPLIT_MAX: usize = 188;
+
+/// Bytes the periodic schedule may move in a microframe, 80% of what fits in one
+const MICROFRAME_BUDGET: u32 = 6000;
+/// Frames ahead of the controller that buffers may be queued in, half the frame list
+const ISOCH_AHEAD: usize = 512;
 
 #[repr(packed)]
-#[derive(Copy, Clone, Debug, Default)]
 struct Qtd {
-    next: u32,
-    next_alt: u32,
-    token: u32,
-    buffers: [u32; 5],
+    next: Mmio<u32>,
+    next_alt: Mmio<u32>,
+    token: Mmio<u32>,
+    buffers: [Mmio<u32>; 5],
+    /// The high halves of the buffers, for controllers with 64 bit addressing
+    buffers_hi: [Mmio<u32>; 5],
+    _padding: [u32; 3],
+}
+
+impl Qtd {
+    fn clear(&mut self) {
+        self.next.write(LINK_TERMINATE);
+        self.next_alt.write(LINK_TERMINATE);
+        self.token.write(0);
+        for i in 0..5 {
+            self.buffers[i].write(0);
+            self.buffers_hi[i].write(0);
+        }
+    }
 }
 
 #[repr(packed)]
 struct QueueHead {
-    next: u32,
-    characteristics: u32,
-    capabilities: u32,
-    qtd_ptr: u32,
-    qtd: Qtd,
+    next: Mmio<u32>,
+    characteristics: Mmio<u32>,
+    capabilities: Mmio<u32>,
+    qtd_ptr: Mmio<u32>,
+    overlay: Qtd,
+}
+
+/// The packets of an isochronous endpoint in one frame, up to one in each microframe
+#[repr(packed)]
+struct Itd {
+    next: Mmio<u32>,
+    transactions: [Mmio<u32>; 8],
+    buffers: [Mmio<u32>; 7],
+    buffers_hi: [Mmio<u32>; 7],
+    _padding: [u32; 9],
+}
+
+/// The packet of a full speed isochronous endpoint in one frame, moved by split transactions
+#[repr(packed)]
+struct Sitd {
+    next: Mmio<u32>,
+    characteristics: Mmio<u32>,
+    schedule: Mmio<u32>,
+    state: Mmio<u32>,
+    buffers: [Mmio<u32>; 2],
+    back: Mmio<u32>,
+    buffers_hi: [Mmio<u32>; 2],
+    _padding: [u32; 7],
+}
+
+#[repr(packed)]
+pub struct EhciOpRegs {
+    pub usb_cmd: Mmio<u32>,
+    pub usb_sts: Mmio<u32>,
+    pub usb_intr: Mmio<u32>,
+    pub frame_index: Mmio<u32>,
+    pub ctrl_ds_segment: Mmio<u32>,
+    pub periodic_list_base: Mmio<u32>,
+    pub async_list_addr: Mmio<u32>,
+    _reserved: [Mmio<u32>; 9],
+    pub config_flag: Mmio<u32>,
+    pub port_sc: [Mmio<u32>; 15],
+}
+
+/// The state of an address, for building its queue heads
+#[derive(Copy, Clone)]
+struct EhciDevice {
+    speed: Speed,
+    /// The address and port of the high speed hub whose transaction translator reaches a full or low speed device
+    tt: (u8, u8),
+    max_packet_sizes: [u16; 16],
+    /// The next data toggle of each endpoint, OUT in the low 16 bits and IN in the high 16
+    toggles: u32,
+}
+
+/// Interrupt queue heads are polled every 2^n frames, for n up to this
+const PERIODS: usize = 8;
+
+/// An interrupt endpoint polled by the controller, with one descriptor that is run again once its report is read
+struct EhciPipe {
+    id: usize,
+    address: u8,
+    endpoint: u8,
+    /// The skeleton queue head the pipe is linked after
+    period: usize,
+    /// The microframes it is polled in, with the bandwidth kept for them
+    smask: u32,
+    queue_head: Dma<QueueHead>,
+    qtd: Dma<Qtd>,
+    buffer: Dma<u8>,
+}
+
+/// A buffer queued on a stream, with the copy the controller moves
+struct EhciIsoch {
+    buffer: IsochBuffer,
+    data: Dma<u8>,
+    /// The frame of each iTD, and the transaction and packet of each packet in it
+    itds: Vec<(usize, Dma<Itd>, Vec<(usize, usize)>)>,
+    /// The frame of each siTD, and its packet
+    sitds: Vec<(usize, Dma<Sitd>, usize)>,
+}
+
+impl EhciIsoch {
+    /// The frames it has descriptors in
+    fn frames(&self) -> Vec<usize> {
+        self.itds.iter().map(|&(frame, _, _)| frame).chain(self.sitds.iter().map(|&(frame, _, _)| frame)).collect()
+    }
+}
+
+/// An isochronous endpoint, with an iTD in each frame it has packets in, or a siTD for a full speed device
+struct EhciStream {
+    id: usize,
+    address: u8,
+    endpoint: u8,
+    direction_in: bool,
+    /// Reached through split transactions to the translator of its hub, one packet a frame
+    split: bool,
+    max_packet_size: u16,
+    /// Microframes from one packet to the next
+    interval: usize,
+    /// The microframes with packets, in the frames that have them
+    smask: u32,
+    /// The frame the next buffer starts in, if the stream has not run dry
+    next_frame: Option<usize>,
+    queued: Vec<EhciIsoch>,
 }
 
 pub struct Ehci {
     pub pci: PciConfig,
     pub base: usize,
     pub irq: u8,
+    ports: usize,
+    /// The periodic frame list, each frame starts at a skeleton queue head
+    frame_list: Dma<Mmio<u32>>,
+    /// Queue heads that never run, the one for every 2^n frames links to the one for every 2^(n-1)
+    skeletons: Dma<QueueHead>,
+    pipes: Vec<EhciPipe>,
+    streams: Vec<EhciStream>,
+    next_pipe: usize,
+    /// The bytes kept in each microframe of every frame, by interrupt pipes and streams
+    bandwidth: [u32; 8],
+    /// The head of the asynchronous schedule, which never runs, transfers are linked in after it
+    async_head: Dma<QueueHead>,
+    /// Set while a transfer is in the asynchronous schedule, or a poll or change of the pipes and streams is done
+    busy: bool,
+    devices: [EhciDevice; 128],
+    watchdog: Watchdog,
 }
 
 impl KScheme for Ehci {
-    #[allow(non_snake_case)]
-    fn on_irq(&mut self, irq: u8) {
+    fn on_irq(&mut self, irq: u8) -> bool {
         if irq == self.irq {
-            unsafe {
-                let cap_length = &mut *(self.base as *mut Mmio<u8>);
-                let op_base = self.base + cap_length.read() as usize;
-                let usb_sts = &mut *((op_base + 4) as *mut Mmio<u32>);
-                usb_sts.writef(0b111111, true);
-            }
+            let sts = self.op().usb_sts.read() & 0b111111;
+            self.op().usb_sts.write(sts);
+            sts != 0
+        } else {
+            false
+        }
+    }
+
+    /// Halt the controller, so it stops running the schedules
+    fn power_off(&mut self) {
+        let op = self.op();
+        op.usb_intr.write(0);
+        op.usb_cmd.writef(CMD_RS, false);
+        if ! wait_for(20, || op.usb_sts.readf(STS_HCHALTED)) {
+            syslog_warning!("EHCI: Halt timed out");
         }
     }
 }
 
 impl Ehci {
     pub unsafe fn new(mut pci: PciConfig) -> Box<Self> {
+        pci.flag(4, 4, true); // Bus mastering
+
+        let base = pci.read(0x10) as usize & 0xFFFFFFF0;
         let mut module = box Ehci {
             pci: pci,
-            base: pci.read(0x10) as usize & 0xFFFFFFF0,
-            irq: pci.read(0x3C) as u8 & 0xF,
+            base: base,
+            irq: pci.irq(),
+            ports: ((*((base + 4) as *const Mmio<u32>)).read() & 0xF) as usize,
+            frame_list: dma::alloc_contiguous(1024 * mem::size_of::<Mmio<u32>>(), 4096).unwrap(),
+            skeletons: dma::alloc_contiguous(PERIODS * mem::size_of::<QueueHead>(), 32).unwrap(),
+            pipes: Vec::new(),
+            streams: Vec::new(),
+            next_pipe: 1,
+            bandwidth: [0; 8],
+            async_head: dma::alloc_contiguous(mem::size_of::<QueueHead>(), 32).unwrap(),
+            busy: false,
+            devices: [EhciDevice {
+                speed: Speed::High,
+                tt: (0, 0),
+                max_packet_sizes: [64; 16],
+                toggles: 0,
+            }; 128],
+            watchdog: Watchdog::new("EHCI", base),
         };
 
         module.init();
@@ -66,196 +320,948 @@ impl Ehci {
         module
     }
 
-    #[allow(non_snake_case)]
     pub unsafe fn init(&mut self) {
-        syslog_info!(" + EHCI on: {:X}, IRQ {:X}", self.base, self.irq);
+        syslog_info!(" + EHCI on: {:X}, IRQ {:X}, Ports {}", self.base, self.irq, self.ports);
+
+        self.watchdog.register();
 
-        /*
-        self.pci.flag(4, 4, true); // Bus master
+        // The resets and port delays need to sleep, so they are done once the scheduler runs
+        let this = self as *mut Ehci;
+        Context::spawn("kehci".into(), box move || {
+            if (*this).reset() {
+                loop {
+                    (*this).check();
+                    (*this).port_changes();
+                    delay(250);
+                }
+            }
+        });
+    }
 
-        let cap_length = &mut *(self.base as *mut Mmio<u8>);
-        let hcs_params = &mut *((self.base + 4) as *mut Mmio<u32>);
-        let hcc_params = &mut *((self.base + 8) as *mut Mmio<u32>);
+    fn op(&self) -> &'static mut EhciOpRegs {
+        unsafe {
+            let cap_length = (*(self.base as *const Mmio<u8>)).read();
+            &mut *((self.base + cap_length as usize) as *mut EhciOpRegs)
+        }
+    }
 
-        let ports = (hcs_params.read() & 0b1111) as usize;
-        debug!(" PORTS ");
-        debug::dd(ports);
+    /// Take the controller from the firmware, if the firmware has it
+    ///
+    /// The legacy support capability is in PCI configuration space, at the offset given by HCCPARAMS.
+    unsafe fn handoff(&mut self) {
+        let mut pci = self.pci;
+        let mut offset = ((*((self.base + 8) as *const Mmio<u32>)).read() >> 8) as u8;
+        while offset >= 0x40 {
+            let value = pci.read(offset);
+            if value & 0xFF == LEGACY_SUPPORT {
+                if value & LEGACY_BIOS_OWNED == LEGACY_BIOS_OWNED {
+                    syslog_info!("EHCI: Taking the controller from the firmware");
+                    pci.write(offset, value | LEGACY_OS_OWNED);
+                    if ! wait_for(1000, || pci.read(offset) & LEGACY_BIOS_OWNED != LEGACY_BIOS_OWNED) {
+                        // Take it anyway, and keep the firmware from being called into through SMIs
+                        syslog_warning!("EHCI: Firmware did not release the controller, forcing the handoff");
+                        let value = pci.read(offset);
+                        pci.write(offset, (value & ! LEGACY_BIOS_OWNED) | LEGACY_OS_OWNED);
+                        let ctl_sts = pci.read(offset + 4);
+                        pci.write(offset + 4, ctl_sts & ! LEGACY_SMI_ENABLES);
+                    }
+                } else {
+                    pci.write(offset, value | LEGACY_OS_OWNED);
+                }
+                return;
+            }
 
-        let eecp = (hcc_params.read() >> 8) as u8;
-        debug!(" EECP ");
-        debug::dh(eecp as usize);
+            offset = (value >> 8) as u8;
+        }
+    }
 
-        debug::dl();
+    /// Halt and reset the controller, then start it with empty schedules and take the ports from the companions
+    unsafe fn reset(&mut self) -> bool {
+        self.handoff();
 
-        if eecp > 0 {
-            if self.pci.read(eecp) & (1 << 24 | 1 << 16) == 1 << 16 {
-                debug!("Taking Ownership");
-                debug!(" ");
-                debug::dh(self.pci.read(eecp) as usize);
+        let op = self.op();
+
+        if ! op.usb_sts.readf(STS_HCHALTED) {
+            op.usb_cmd.writef(CMD_RS, false);
+            if ! wait_for(20, || op.usb_sts.readf(STS_HCHALTED)) {
+                syslog_warning!("EHCI: Halt timed out");
+                return false;
+            }
+        }
+
+        op.usb_cmd.writef(CMD_HCRESET, true);
+        if ! wait_for(250, || ! op.usb_cmd.readf(CMD_HCRESET)) {
+            syslog_warning!("EHCI: Reset timed out");
+            return false;
+        }
 
-                self.pci.flag(eecp, 1 << 24, true);
+        for period in 0..PERIODS {
+            let skeleton = &mut self.skeletons[period];
+            skeleton.characteristics.write(QH_EPS_HIGH);
+            // A queue head in the periodic schedule needs a microframe, this one is halted so it does nothing
+            skeleton.capabilities.write(QH_MULT_1 | 1);
+            skeleton.qtd_ptr.write(0);
+            skeleton.overlay.clear();
+            skeleton.overlay.token.write(TOKEN_HALTED);
+        }
+        for period in 0..PERIODS {
+            self.link(period);
+        }
+        for i in 0..self.frame_list.len() {
+            let skeleton = self.skeleton(i);
+            self.frame_list[i].write(skeleton);
+        }
 
-                debug!(" ");
-                debug::dh(self.pci.read(eecp) as usize);
-                debug::dl();
+        let head = self.async_head.physical() as u32;
+        self.async_head[0].next.write(head | LINK_QH);
+        self.async_head[0].characteristics.write(QH_HEAD | QH_EPS_HIGH);
+        self.async_head[0].capabilities.write(QH_MULT_1);
+        self.async_head[0].qtd_ptr.write(0);
+        self.async_head[0].overlay.clear();
+        self.async_head[0].overlay.token.write(TOKEN_HALTED);
 
-                debug!("Waiting");
-                debug!(" ");
-                debug::dh(self.pci.read(eecp) as usize);
+        // Completions are polled
+        op.usb_intr.write(0);
+        op.ctrl_ds_segment.write(0);
+        op.periodic_list_base.write(self.frame_list.physical() as u32);
+        op.async_list_addr.write(head);
+        op.usb_cmd.write(CMD_ITC_8 | CMD_PSE | CMD_ASE | CMD_RS);
+        if ! wait_for(20, || ! op.usb_sts.readf(STS_HCHALTED)) {
+            syslog_warning!("EHCI: Start timed out");
+            return false;
+        }
 
-                while self.pci.read(eecp) & (1 << 24 | 1 << 16) != 1 << 24 {}
+        // Route every port to this controller, ports with full and low speed devices are handed back
+        op.config_flag.write(1);
+        delay(5);
 
-                debug!(" ");
-                debug::dh(self.pci.read(eecp) as usize);
-                debug::dl();
+        // Ports without power switching are always powered
+        for i in 0..self.ports {
+            if ! op.port_sc[i].readf(PORT_PP) {
+                op.port_sc[i].write(PORT_PP);
             }
         }
+        delay(20);
 
-        let op_base = self.base + cap_length.read() as usize;
+        true
+    }
+
+    /// Reset the controller if it has halted, its frame index has stopped, or it no longer advances the
+    /// asynchronous schedule
+    unsafe fn check(&mut self) {
+        let op = self.op();
+        let sts = op.usb_sts.read();
+        let reason = if sts & STS_HCHALTED == STS_HCHALTED {
+            format!("Halted, status {:X}", sts)
+        } else if self.watchdog.frame_stuck(op.frame_index.read()) {
+            format!("Frame index stopped at {}", op.frame_index.read())
+        } else if self.watchdog.timeouts >= WATCHDOG_TIMEOUTS {
+            format!("{} async advances timed out", self.watchdog.timeouts)
+        } else {
+            return;
+        };
 
-        let usb_cmd = &mut *(op_base as *mut Mmio<u32>);
-        let usb_sts = &mut *((op_base + 4) as *mut Mmio<u32>);
-        let usb_intr = &mut *((op_base + 8) as *mut Mmio<u32>);
-        let config_flag = &mut *((op_base + 0x40) as *mut Mmio<u32>);
-        let port_scs = &mut slice::from_raw_parts_mut((op_base + 0x44) as *mut Mmio<u32>, ports);
+        if self.watchdog.allow(reason) {
+            self.restart();
+        }
+    }
 
-        // let FRINDEX = (opbase + 0xC) as *mut Mmio<u32>;
-        // let CTRLDSSEGMENT = (opbase + 0x10) as *mut Mmio<u32>;
-        // let PERIODICLISTBASE = (opbase + 0x14) as *mut Mmio<u32>;
-        // let ASYNCLISTADDR = (opbase + 0x18) as *mut Mmio<u32>;
+    /// Reset the controller and enumerate the devices on it again
+    ///
+    /// Every device is disconnected first, which fails the transfers waiting on it.
+    unsafe fn restart(&mut self) {
+        self.watchdog.resetting = true;
 
-        //Halt
-        if usb_sts.read() & 1 << 12 == 0 {
-            usb_cmd.writef(0xF, false);
-            while ! usb_sts.readf(1 << 12) {}
+        let hci = self as *mut Ehci as *mut UsbHc;
+        usb_cancel(hci, 0);
+        for i in 0..self.ports {
+            self.port_disconnected(i as u8 + 1);
         }
 
-        //Reset
-        usb_cmd.writef(1 << 1, true);
-        while usb_cmd.readf(1 << 1) {}
+        let started = {
+            let _held = hold(&mut self.busy);
+            self.pipes.clear();
+            self.streams.clear();
+            self.bandwidth = [0; 8];
+            let started = self.reset();
+            usb_reuse(hci, 0);
+            self.watchdog.resetting = false;
+            started
+        };
 
-        //Enable
-        usb_intr.write(0b111111);
-        usb_cmd.writef(1, true);
-        config_flag.write(1);
-        while usb_sts.readf(1 << 12) {}
+        if ! started {
+            self.watchdog.failed += 1;
+            return;
+        }
 
-        for i in 0..port_scs.len() {
-            let port_sc = &mut port_scs[i];
-            debugln!("Port {}: {:X}", i, port_sc.read());
+        for i in 0..self.ports {
+            if self.op().port_sc[i].readf(PORT_CCS) {
+                self.port_change(i);
+            }
+        }
+    }
 
-            if port_sc.readf(1) {
-                debugln!("Device Found");
+    /// Handle connects and disconnects on the root ports
+    unsafe fn port_changes(&mut self) {
+        for i in 0..self.ports {
+            if self.op().port_sc[i].readf(PORT_CSC) {
+                self.port_change(i);
+            }
+        }
+    }
 
-                if port_sc.readf(1 << 1) {
-                    debugln!("Connection Change");
+    /// Forget the device that was on port `i`, and enumerate the one on it now
+    unsafe fn port_change(&mut self, i: usize) {
+        let port_sc = &mut self.op().port_sc[i];
+        let status = port_sc.read();
+        port_sc.write((status & ! PORT_CHANGE) | PORT_CSC);
 
-                    port_sc.writef(1 << 1, true);
+        self.port_disconnected(i as u8 + 1);
+        if status & PORT_CCS == PORT_CCS {
+            // Let the connection settle before the reset
+            delay(100);
+            usb_enumerate_begin();
+            if self.reset_port(i) {
+                syslog_info!("EHCI: High speed device on port {}", i + 1);
+                if let Err(err) = self.port_connected(i as u8 + 1, Speed::High) {
+                    syslog_warning!("EHCI: Failed to enumerate port {}: {}", i + 1, err);
                 }
+            }
+            usb_enumerate_end();
+        } else {
+            syslog_info!("EHCI: Device removed from port {}", i + 1);
+        }
+    }
 
-                if ! port_sc.readf(1 << 2) {
-                    debugln!("Reset");
+    /// Reset port `i`, handing it to a companion controller unless its device is high speed
+    unsafe fn reset_port(&mut self, i: usize) -> bool {
+        let port_sc = &mut self.op().port_sc[i];
 
-                    while ! port_sc.readf(1 << 8) {
-                        port_sc.writef(1 << 8, true);
-                    }
+        // A low speed device is recognised from the line state, before the reset
+        if port_sc.read() & PORT_LS == PORT_LS_K {
+            syslog_info!("EHCI: Low speed device on port {}, handed to companion", i + 1);
+            port_sc.write((port_sc.read() & ! PORT_CHANGE) | PORT_PO);
+            return false;
+        }
 
-                    let mut spin = 1000000000;
-                    while spin > 0 {
-                        spin -= 1;
-                    }
+        // The enable bit is cleared as the reset starts, and the reset is held for 50 ms
+        port_sc.write((port_sc.read() & ! (PORT_CHANGE | PORT_PE)) | PORT_PR);
+        delay(50);
+        port_sc.write(port_sc.read() & ! (PORT_CHANGE | PORT_PR));
+        if ! wait_for(10, || ! port_sc.readf(PORT_PR)) {
+            syslog_warning!("EHCI: Reset of port {} timed out", i + 1);
+            return false;
+        }
 
-                    while port_sc.readf(1 << 8) {
-                        port_sc.writef(1 << 8, false);
+        // Only a high speed device leaves the reset with the port enabled
+        if port_sc.readf(PORT_PE) {
+            // The device is given 10 ms to recover from the reset
+            delay(10);
+            true
+        } else {
+            syslog_info!("EHCI: Full speed device on port {}, handed to companion", i + 1);
+            port_sc.write((port_sc.read() & ! PORT_CHANGE) | PORT_PO);
+            false
+        }
+    }
+
+    /// The link to the skeleton queue head frame `i` starts its interrupt queue heads at
+    ///
+    /// Frame i runs the interrupt queue heads of every period that divides it.
+    fn skeleton(&self, i: usize) -> u32 {
+        let period = cmp::min(PERIODS - 1, (i as u32 | 1 << 31).trailing_zeros() as usize);
+        self.skeletons.physical_of(period) as u32 | LINK_QH
+    }
+
+    /// Point frame `i` at the iTDs and siTDs the streams have in it, which come before its interrupt queue heads
+    fn link_frame(&mut self, i: usize) {
+        let mut next = self.skeleton(i);
+        for stream in self.streams.iter_mut().rev() {
+            for isoch in stream.queued.iter_mut().rev() {
+                for &mut (frame, ref mut itd, _) in isoch.itds.iter_mut().rev() {
+                    if frame == i {
+                        itd[0].next.write(next);
+                        next = itd.physical() as u32 | LINK_ITD;
+                    }
+                }
+                for &mut (frame, ref mut sitd, _) in isoch.sitds.iter_mut().rev() {
+                    if frame == i {
+                        sitd[0].next.write(next);
+                        next = sitd.physical() as u32 | LINK_SITD;
                     }
                 }
+            }
+        }
+        self.frame_list[i].write(next);
+    }
+
+    /// The frame the controller is in
+    fn frame(&self) -> usize {
+        (self.op().frame_index.read() as usize >> 3) % self.frame_list.len()
+    }
+
+    /// Add `bytes` to the bandwidth of the microframes in `smask`, or take them away
+    fn reserve(&mut self, smask: u32, bytes: u32, add: bool) {
+        for microframe in 0..8 {
+            if smask & 1 << microframe != 0 {
+                if add {
+                    self.bandwidth[microframe] += bytes;
+                } else {
+                    self.bandwidth[microframe] -= cmp::min(self.bandwidth[microframe], bytes);
+                }
+            }
+        }
+    }
+
+    /// Chain the skeleton queue head of `period` through its pipes to the next shorter period
+    ///
+    /// The links are written from the end, so the controller never follows one to a queue head that is not ready.
+    fn link(&mut self, period: usize) {
+        let mut next = if period == 0 {
+            LINK_TERMINATE
+        } else {
+            self.skeletons.physical_of(period - 1) as u32 | LINK_QH
+        };
+        for pipe in self.pipes.iter_mut().rev().filter(|pipe| pipe.period == period) {
+            pipe.queue_head[0].next.write(next);
+            next = pipe.queue_head.physical() as u32 | LINK_QH;
+        }
+        self.skeletons[period].next.write(next);
+    }
+
+    /// Start the descriptor of pipe `i` again, for its next report
+    fn arm(&mut self, i: usize) {
+        let (address, endpoint) = (self.pipes[i].address, self.pipes[i].endpoint);
+        let toggle = self.devices[address as usize & 0x7F].toggles & 1 << (endpoint + 16) != 0;
+
+        let pipe = &mut self.pipes[i];
+        let len = pipe.buffer.len() as u32;
+        pipe.qtd[0].clear();
+        pipe.qtd[0].buffers[0].write(pipe.buffer.physical() as u32);
+        pipe.qtd[0].token.write((if toggle { TOKEN_TOGGLE } else { 0 }) | len << 16 | TOKEN_ERROR_COUNT | TOKEN_PID_IN | TOKEN_ACTIVE);
+
+        // The overlay is left inactive or halted by the last report, so the controller fetches the descriptor again
+        pipe.queue_head[0].overlay.clear();
+        pipe.queue_head[0].overlay.next.write(pipe.qtd.physical() as u32);
+    }
+
+    /// Take the controller for a transfer or a change of its schedule, which fails while the watchdog resets it
+    ///
+    /// The reset drops the pipes and streams, so whatever would be queued is gone with them.
+    fn take(&mut self) -> Result<Held> {
+        let held = hold(&mut self.busy);
+        if self.watchdog.resetting {
+            Err(Error::new(ENODEV))
+        } else {
+            Ok(held)
+        }
+    }
+
+    /// Take pipe `i` out of the schedule
+    fn unlink(&mut self, i: usize) {
+        let pipe = self.pipes.remove(i);
+        self.link(pipe.period);
+        self.reserve(pipe.smask, pipe.buffer.len() as u32, false);
+        // The controller may be in the queue head until the end of the frame
+        delay(2);
+    }
+
+    /// Take stream `i` out of the schedule, with the buffers still queued on it
+    fn unlink_stream(&mut self, i: usize) {
+        let stream = self.streams.remove(i);
+        for isoch in stream.queued.iter() {
+            for frame in isoch.frames() {
+                self.link_frame(frame);
+            }
+        }
+        if ! stream.split {
+            self.reserve(stream.smask, stream.max_packet_size as u32, false);
+        }
+        // The controller may be in an iTD until the end of the frame
+        delay(2);
+    }
+
+    /// Fill in the status of the packets of `isoch` the controller is done with, or has gone past
+    fn isoch_status(isoch: &mut EhciIsoch, frame: usize, frames: usize) {
+        for &(itd_frame, ref itd, ref packets) in isoch.itds.iter() {
+            // A frame that is not ahead of the controller, or the one it is in, has gone by
+            let behind = (frame + frames - itd_frame) % frames;
+            let passed = behind >= 2 && behind < frames - ISOCH_AHEAD;
+            for &(transaction, k) in packets.iter() {
+                if isoch.buffer.packets[k].status != IsochStatus::Pending {
+                    continue;
+                }
 
-                debugln!("Port Enabled {:X}", port_sc.read());
+                let status = itd[0].transactions[transaction].read();
+                isoch.buffer.packets[k].status = if status & ITD_ACTIVE == ITD_ACTIVE {
+                    if passed { IsochStatus::Missed } else { IsochStatus::Pending }
+                } else if status & (ITD_BUFFER_ERROR | ITD_BABBLE | ITD_XACT_ERROR) != 0 {
+                    IsochStatus::Failed
+                } else {
+                    // The length of an IN packet is what the device sent
+                    IsochStatus::Done(((status >> 16) & 0xFFF) as u16)
+                };
+            }
+        }
 
-                self.device(i as u8 + 1);
+        for &(sitd_frame, ref sitd, k) in isoch.sitds.iter() {
+            let behind = (frame + frames - sitd_frame) % frames;
+            let passed = behind >= 2 && behind < frames - ISOCH_AHEAD;
+            if isoch.buffer.packets[k].status != IsochStatus::Pending {
+                continue;
             }
+
+            let state = sitd[0].state.read();
+            isoch.buffer.packets[k].status = if state & SITD_ACTIVE == SITD_ACTIVE {
+                if passed { IsochStatus::Missed } else { IsochStatus::Pending }
+            } else if state & SITD_MISSED == SITD_MISSED {
+                IsochStatus::Missed
+            } else if state & (SITD_ERR | SITD_BUFFER_ERROR | SITD_BABBLE | SITD_XACT_ERROR) != 0 {
+                IsochStatus::Failed
+            } else {
+                // The bytes left of an IN packet are what the device did not send
+                let length = isoch.buffer.packets[k].length;
+                IsochStatus::Done(length - cmp::min(length, ((state >> 16) & 0x3FF) as u16))
+            };
         }
-        */
+    }
+
+    /// Run one stage of a transfer through a queue head in the asynchronous schedule
+    unsafe fn transfer(&mut self, address: u8, endpoint: u8, pid: u32, toggle: bool,
+                       ptr: usize, len: usize, timeout: i32) -> Result<usize> {
+        let device = self.devices[address as usize & 0x7F];
+        let max_packet_size = device.max_packet_sizes[endpoint as usize & 0xF] as u32;
+
+        // The last descriptor never runs, a short packet goes to it to stop the queue
+        let count = cmp::max(1, (len + QTD_MAX - 1) / QTD_MAX);
+        let mut qtds = try!(Descriptors::<Qtd>::new(count + 1, 32));
+        for i in 0..count + 1 {
+            qtds[i].clear();
+        }
+        let stop = physical(qtds.address() + count * mem::size_of::<Qtd>());
+
+        for i in 0..count {
+            let offset = i * QTD_MAX;
+            let size = cmp::min(QTD_MAX, len - cmp::min(len, offset));
+
+            qtds[i].next.write(physical(qtds.address() + (i + 1) * mem::size_of::<Qtd>()));
+            qtds[i].next_alt.write(stop);
+            qtds[i].token.write((if toggle { TOKEN_TOGGLE } else { 0 }) | (size as u32) << 16 |
+                                TOKEN_ERROR_COUNT | pid | TOKEN_ACTIVE);
+
+            let buffer = physical(ptr + offset);
+            for page in 0..5 {
+                qtds[i].buffers[page].write(if page == 0 {
+                    buffer
+                } else {
+                    (buffer & 0xFFFFF000) + page as u32 * 4096
+                });
+            }
+        }
+
+        let mut queue_head = try!(Descriptors::<QueueHead>::new(1, 32));
+        queue_head[0].next.write(self.async_head.physical() as u32 | LINK_QH);
+        // Full and low speed devices are reached through split transactions to the translator in their hub
+        let (eps, control) = match device.speed {
+            Speed::Low => (QH_EPS_LOW, if endpoint == 0 { QH_CONTROL } else { 0 }),
+            Speed::Full => (QH_EPS_FULL, if endpoint == 0 { QH_CONTROL } else { 0 }),
+            _ => (QH_EPS_HIGH, 0),
+        };
+        queue_head[0].characteristics.write(QH_NAK_RELOAD | control | max_packet_size << 16 | QH_DTC | eps |
+                                            (endpoint as u32 & 0xF) << 8 | address as u32 & 0x7F);
+        queue_head[0].capabilities.write(QH_MULT_1 | (device.tt.1 as u32 & 0x7F) << 23 | (device.tt.0 as u32 & 0x7F) << 16);
+        queue_head[0].qtd_ptr.write(0);
+        queue_head[0].overlay.clear();
+        queue_head[0].overlay.next.write(physical(qtds.address()));
+
+        self.async_head[0].next.write(physical(queue_head.address()) | LINK_QH);
+
+        let end = Duration::monotonic() + Duration::new(0, timeout * time::NANOS_PER_MILLI);
+        let mut result = Ok(0);
+        for i in 0..count {
+            while qtds[i].token.readf(TOKEN_ACTIVE) {
+                if usb_cancelled(self as *mut Ehci as *mut UsbHc, address) {
+                    result = Err(Error::new(ENODEV));
+                    break;
+                }
+                if Duration::monotonic() > end {
+                    result = Err(Error::new(ETIMEDOUT));
+                    break;
+                }
+                context_switch();
+            }
+            if result.is_err() {
+                break;
+            }
+
+            let token = qtds[i].token.read();
+            if token & (TOKEN_BUFFER_ERROR | TOKEN_BABBLE | TOKEN_XACT_ERROR) != 0 {
+                result = Err(Error::new(EIO));
+                break;
+            } else if token & TOKEN_HALTED == TOKEN_HALTED {
+                result = Err(Error::new(EPIPE));
+                break;
+            }
+
+            let size = cmp::min(QTD_MAX, len - cmp::min(len, i * QTD_MAX));
+            let remaining = ((token >> 16) & 0x7FFF) as usize;
+            if let Ok(ref mut total) = result {
+                *total += size - cmp::min(size, remaining);
+            }
+            if remaining > 0 {
+                break;
+            }
+        }
+
+        // The controller may still hold the queue head until it acknowledges the unlink
+        self.async_head[0].next.write(self.async_head.physical() as u32 | LINK_QH);
+        let op = self.op();
+        if op.usb_sts.readf(STS_ASS) {
+            op.usb_cmd.writef(CMD_IAAD, true);
+            let advanced = wait_for(100, || op.usb_sts.readf(STS_IAA));
+            if ! advanced {
+                syslog_warning!("EHCI: Async advance timed out");
+            }
+            self.watchdog.completed(! advanced);
+            op.usb_sts.write(STS_IAA);
+        }
+
+        result
     }
 }
 
-impl Hci for Ehci {
-    fn msg(&mut self, address: u8, endpoint: u8, _pipe: Pipe, msgs: &[Packet]) -> usize {
-        let mut tds = Vec::new();
-        for msg in msgs.iter().rev() {
-            let link_ptr = match tds.last() {
-                Some(td) => (td as *const Qtd) as u32,
-                None => 1
+impl UsbHc for Ehci {
+    fn msg(&mut self, address: u8, endpoint: u8, pipe: Pipe, msgs: &[Packet]) -> Result<usize> {
+        let _held = try!(self.take());
+
+        let timeout = match pipe {
+            Pipe::Interrupt => INTERRUPT_TIMEOUT,
+            _ => TRANSFER_TIMEOUT,
+        };
+        let max_packet_size = cmp::max(1, self.devices[address as usize & 0x7F].max_packet_sizes[endpoint as usize & 0xF] as usize);
+
+        let mut control_toggle = true;
+        let mut result = Ok(0);
+        for msg in msgs.iter() {
+            let (pid, ptr, len, toggle_bit) = match *msg {
+                Packet::Setup(setup) => (TOKEN_PID_SETUP, (setup as *const Setup) as usize, mem::size_of::<Setup>(), endpoint as u32),
+                Packet::In(ref data) => (TOKEN_PID_IN, data.as_ptr() as usize, data.len(), endpoint as u32 + 16),
+                Packet::Out(ref data) => (TOKEN_PID_OUT, data.as_ptr() as usize, data.len(), endpoint as u32),
             };
 
-            match *msg {
-                Packet::Setup(setup) => tds.push(Qtd {
-                    next: link_ptr,
-                    next_alt: 1,
-                    token: (size_of::<Setup>() as u32) << 16 | 0b10 << 8 | 1 << 7,
-                    buffers: [(setup as *const Setup) as u32, 0, 0, 0, 0]
-                }),
-                Packet::In(ref data) => tds.push(Qtd {
-                    next: link_ptr,
-                    next_alt: 1,
-                    token: ((data.len() as u32) & 0x7FFF) << 16 | 0b01 << 8 | 1 << 7,
-                    buffers: [if data.is_empty() {
-                        0
-                    } else {
-                        data.as_ptr() as u32
-                    }, 0, 0, 0, 0]
-                }),
-                Packet::Out(ref data) => tds.push(Qtd {
-                    next: link_ptr,
-                    next_alt: 1,
-                    token: ((data.len() as u32) & 0x7FFF) << 16 | 0b00 << 8 | 1 << 7,
-                    buffers: [if data.is_empty() {
-                        0
-                    } else {
-                        data.as_ptr() as u32
-                    }, 0, 0, 0, 0]
-                })
+            // Control transfers set the toggle of each stage, the status stage is the empty packet
+            let toggle = match (pipe, msg) {
+                (Pipe::Control, &Packet::Setup(_)) => false,
+                (Pipe::Control, _) if len == 0 => true,
+                (Pipe::Control, _) => control_toggle,
+                _ => self.devices[address as usize & 0x7F].toggles & 1 << toggle_bit == 1 << toggle_bit,
+            };
+
+            match unsafe { self.transfer(address, endpoint, pid, toggle, ptr, len, timeout) } {
+                Ok(count) => {
+                    // The toggle moves once for each packet that was sent
+                    let sent = cmp::max(1, (count + max_packet_size - 1) / max_packet_size);
+                    if sent % 2 == 1 {
+                        match (pipe, msg) {
+                            (Pipe::Control, &Packet::Setup(_)) => (),
+                            (Pipe::Control, _) => control_toggle = ! control_toggle,
+                            _ => self.devices[address as usize & 0x7F].toggles ^= 1 << toggle_bit,
+                        }
+                    }
+
+                    if let Packet::Setup(_) = *msg {} else {
+                        if let Ok(ref mut total) = result {
+                            *total += count;
+                        }
+                    }
+                },
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
             }
         }
 
-        let mut count = 0;
+        result
+    }
+
+    fn attach(&mut self, hub: u8, port: u8, speed: Speed) -> Result<()> {
+        let parent = self.devices[hub as usize & 0x7F];
+        self.devices[0].tt = match speed {
+            Speed::High => (0, 0),
+            _ if parent.speed == Speed::High => (hub, port),
+            _ => parent.tt,
+        };
+        Ok(())
+    }
+
+    fn set_device(&mut self, address: u8, speed: Speed, max_packet_size: u16) {
+        // The device being enumerated answers at address 0, so its translator was recorded there
+        let tt = self.devices[0].tt;
+        self.devices[address as usize & 0x7F] = EhciDevice {
+            speed: speed,
+            tt: tt,
+            max_packet_sizes: [max_packet_size; 16],
+            toggles: 0,
+        };
+    }
+
+    fn reset_toggle(&mut self, address: u8, endpoint_address: u8) {
+        let toggle_bit = (endpoint_address & 0xF) + if endpoint_address & 0x80 == 0x80 { 16 } else { 0 };
+        self.devices[address as usize & 0x7F].toggles &= ! (1 << toggle_bit);
+    }
+
+    fn set_endpoint(&mut self, address: u8, desc_end: &EndpointDescriptor) -> Result<()> {
+        self.devices[address as usize & 0x7F].max_packet_sizes[desc_end.number() as usize] = desc_end.max_packet_size();
+        Ok(())
+    }
+
+    fn detach(&mut self, address: u8) {
+        let _held = hold(&mut self.busy);
+        while let Some(i) = self.pipes.iter().position(|pipe| pipe.address == address) {
+            self.unlink(i);
+        }
+        while let Some(i) = self.streams.iter().position(|stream| stream.address == address) {
+            self.unlink_stream(i);
+        }
+    }
+
+    fn interrupt_open(&mut self, address: u8, desc_end: &EndpointDescriptor) -> Result<InterruptPipe> {
+        let _held = try!(self.take());
+        let device = self.devices[address as usize & 0x7F];
+        let endpoint = desc_end.number();
+        let max_packet_size = desc_end.max_packet_size() as u32;
+        let microframes = desc_end.period(device.speed);
 
-        if ! tds.is_empty() {
-            unsafe {
-                let cap_length = &mut *(self.base as *mut Mmio<u8>);
+        // A full or low speed transaction is started in microframe 0 and completed by the translator in 2 to 4
+        let (eps, smask, cmask) = match device.speed {
+            Speed::Low => (QH_EPS_LOW, 0x01, 0x1C),
+            Speed::Full => (QH_EPS_FULL, 0x01, 0x1C),
+            _ => (QH_EPS_HIGH, match microframes {
+                1 => 0xFF,
+                2 => 0x55,
+                4 => 0x11,
+                _ => 0x01,
+            }, 0),
+        };
+        let period = cmp::min(PERIODS - 1, cmp::max(1, microframes / 8).trailing_zeros() as usize);
 
-                let op_base = self.base + cap_length.read() as usize;
+        let mut queue_head = try!(dma::alloc_contiguous::<QueueHead>(mem::size_of::<QueueHead>(), 32));
+        queue_head[0].characteristics.write(max_packet_size << 16 | QH_DTC | eps | (endpoint as u32 & 0xF) << 8 | address as u32 & 0x7F);
+        queue_head[0].capabilities.write(QH_MULT_1 | (device.tt.1 as u32 & 0x7F) << 23 | (device.tt.0 as u32 & 0x7F) << 16 |
+                                         cmask << 8 | smask);
+        queue_head[0].qtd_ptr.write(0);
 
-                let usb_cmd = &mut *(op_base as *mut Mmio<u32>);
-                let async_list = &mut *((op_base + 0x18) as *mut Mmio<u32>);
+        let id = self.next_pipe;
+        self.next_pipe += 1;
+        self.pipes.push(EhciPipe {
+            id: id,
+            address: address,
+            endpoint: endpoint,
+            period: period,
+            smask: if device.speed == Speed::High { smask } else { 0 },
+            queue_head: queue_head,
+            qtd: try!(dma::alloc_contiguous(mem::size_of::<Qtd>(), 32)),
+            buffer: try!(dma::alloc_contiguous(cmp::max(1, max_packet_size as usize), 4096)),
+        });
 
-                let queuehead = box QueueHead {
-                    next: 1,
-                    characteristics: 64 << 16 | 1 << 15 | 1 << 14 | 0b10 << 12 | (endpoint as u32) << 8 | address as u32,
-                    capabilities: 0b01 << 30,
-                    qtd_ptr: (tds.last().unwrap() as *const Qtd) as u32,
-                    qtd: *tds.last().unwrap()
-                };
+        // Split transactions are counted by the translator of their hub, not here
+        if device.speed == Speed::High {
+            self.reserve(smask, max_packet_size, true);
+        }
+
+        let i = self.pipes.len() - 1;
+        self.arm(i);
+        self.link(period);
 
-                //TODO: Calculate actual bytes
-                for td in tds.iter().rev() {
-                    count += (td.token as usize >> 16) & 0x7FFF;
+        Ok(InterruptPipe {
+            id: id,
+            address: address,
+            endpoint: endpoint,
+        })
+    }
+
+    fn interrupt_report(&mut self, pipe: &InterruptPipe, data: &mut [u8]) -> Option<usize> {
+        let _held = match self.take() {
+            Ok(held) => held,
+            Err(_) => return None,
+        };
+        let i = match self.pipes.iter().position(|other| other.id == pipe.id) {
+            Some(i) => i,
+            None => return None,
+        };
+
+        let token = self.pipes[i].qtd[0].token.read();
+        if token & TOKEN_ACTIVE == TOKEN_ACTIVE {
+            return None;
+        }
+
+        let result = if token & (TOKEN_HALTED | TOKEN_BUFFER_ERROR | TOKEN_BABBLE | TOKEN_XACT_ERROR) != 0 {
+            syslog_debug!("EHCI: Interrupt transfer to {} failed: {:X}", pipe.address, token);
+            None
+        } else {
+            let len = self.pipes[i].buffer.len();
+            let count = cmp::min(len - cmp::min(len, ((token >> 16) & 0x7FFF) as usize), data.len());
+            for j in 0..count {
+                data[j] = self.pipes[i].buffer[j];
+            }
+            // A report is one packet, so the toggle moves once
+            self.devices[pipe.address as usize & 0x7F].toggles ^= 1 << (pipe.endpoint + 16);
+            Some(count)
+        };
+
+        self.arm(i);
+
+        result
+    }
+
+    fn interrupt_close(&mut self, pipe: &InterruptPipe) {
+        let _held = hold(&mut self.busy);
+        if let Some(i) = self.pipes.iter().position(|other| other.id == pipe.id) {
+            self.unlink(i);
+        }
+    }
+
+    fn isoch_open(&mut self, address: u8, desc_end: &EndpointDescriptor) -> Result<IsochStream> {
+        let _held = try!(self.take());
+        let device = self.devices[address as usize & 0x7F];
+        match device.speed {
+            // Low speed devices have no isochronous endpoints
+            Speed::Low => return Err(Error::new(EINVAL)),
+            Speed::Full => {
+                // Split transactions are counted by the translator of their hub, like those of interrupt pipes
+                let id = self.next_pipe;
+                self.next_pipe += 1;
+                let interval = desc_end.period(device.speed) as usize;
+                self.streams.push(EhciStream {
+                    id: id,
+                    address: address,
+                    endpoint: desc_end.number(),
+                    direction_in: desc_end.is_in(),
+                    split: true,
+                    max_packet_size: cmp::min(1023, desc_end.max_packet_size()),
+                    interval: interval,
+                    smask: SITD_IN_SMASK,
+                    next_frame: None,
+                    queued: Vec::new(),
+                });
+
+                return Ok(IsochStream {
+                    id: id,
+                    address: address,
+                    endpoint: desc_end.number(),
+                    interval: interval as u32,
+                });
+            },
+            _ => (),
+        }
+
+        let max_packet_size = cmp::min(1024, desc_end.max_packet_size());
+        let interval = desc_end.period(device.speed) as usize;
+
+        // Every microframe `interval` apart from the first, within one frame, a stream slower than that has one
+        let step = cmp::min(interval, 8);
+        let mut best = None;
+        for first in 0..step {
+            let smask = (first..8).filter(|&microframe| (microframe - first) % step == 0)
+                                  .fold(0, |smask, microframe| smask | 1 << microframe);
+            let load = (0..8).filter(|&microframe| smask & 1 << microframe != 0)
+                             .map(|microframe| self.bandwidth[microframe])
+                             .max()
+                             .unwrap_or(0);
+            if best.map_or(true, |(_, best_load)| load < best_load) {
+                best = Some((smask, load));
+            }
+        }
+        let smask = match best {
+            Some((smask, load)) if load + max_packet_size as u32 <= MICROFRAME_BUDGET => smask,
+            _ => return Err(Error::new(ENOSPC)),
+        };
+        self.reserve(smask, max_packet_size as u32, true);
+
+        let id = self.next_pipe;
+        self.next_pipe += 1;
+        self.streams.push(EhciStream {
+            id: id,
+            address: address,
+            endpoint: desc_end.number(),
+            direction_in: desc_end.is_in(),
+            split: false,
+            max_packet_size: max_packet_size,
+            interval: interval,
+            smask: smask,
+            next_frame: None,
+            queued: Vec::new(),
+        });
+
+        Ok(IsochStream {
+            id: id,
+            address: address,
+            endpoint: desc_end.number(),
+            interval: interval as u32,
+        })
+    }
+
+    fn isoch_queue(&mut self, stream: &IsochStream, buffer: IsochBuffer) -> Result<()> {
+        let _held = try!(self.take());
+        let i = match self.streams.iter().position(|other| other.id == stream.id) {
+            Some(i) => i,
+            None => return Err(Error::new(ENODEV)),
+        };
+        if ! buffer.fits(self.streams[i].max_packet_size) {
+            return Err(Error::new(EINVAL));
+        }
+
+        let frames = self.frame_list.len();
+        let now = self.frame();
+        let (interval, smask) = (self.streams[i].interval, self.streams[i].smask);
+        let frame_step = cmp::max(1, interval / 8);
+        let per_frame = if self.streams[i].split { 1 } else { smask.count_ones() as usize };
+        let span = (buffer.packets.len() + per_frame - 1) / per_frame * frame_step;
+
+        // A stream that fell behind starts again past the frame the controller may already have fetched
+        let start = match self.streams[i].next_frame {
+            Some(frame) if (frame + frames - now) % frames >= 2 && (frame + frames - now) % frames < ISOCH_AHEAD => frame,
+            _ => (now + 2) % frames,
+        };
+        if (start + frames - now) % frames + span > ISOCH_AHEAD {
+            return Err(Error::new(EAGAIN));
+        }
+
+        let mut data = try!(dma::alloc_contiguous::<u8>(cmp::max(1, buffer.data.len()), 4096));
+        for j in 0..buffer.data.len() {
+            data[j] = buffer.data[j];
+        }
+
+        let (address, endpoint) = (self.streams[i].address, self.streams[i].endpoint);
+        let direction = if self.streams[i].direction_in { ITD_DIR_IN } else { 0 };
+        let max_packet_size = self.streams[i].max_packet_size as u32;
+
+        let mut itds = Vec::new();
+        let mut sitds = Vec::new();
+        let mut frame = start;
+        let mut k = 0;
+        let mut offset = 0;
+        while self.streams[i].split && k < buffer.packets.len() {
+            let (hub, port) = self.devices[address as usize & 0x7F].tt;
+            let length = buffer.packets[k].length as usize;
+            let ptr = (data.physical() + offset) as u32;
+
+            let mut sitd = try!(dma::alloc_contiguous::<Sitd>(mem::size_of::<Sitd>(), 32));
+            sitd[0].characteristics.write((if self.streams[i].direction_in { SITD_DIR_IN } else { 0 }) |
+                                          (port as u32 & 0x7F) << 24 | (hub as u32 & 0x7F) << 16 |
+                                          (endpoint as u32 & 0xF) << 8 | address as u32 & 0x7F);
+            // An OUT packet is given to the translator in pieces, one start split in each microframe from 0
+            let (schedule, position) = if self.streams[i].direction_in {
+                (SITD_IN_CMASK << 8 | SITD_IN_SMASK, 0)
+            } else {
+                let starts = cmp::max(1, (length + SPLIT_MAX - 1) / SPLIT_MAX) as u32;
+                ((1 << starts) - 1, (if starts == 1 { SITD_TP_ALL } else { SITD_TP_BEGIN }) | starts)
+            };
+            sitd[0].schedule.write(schedule);
+            sitd[0].state.write((length as u32) << 16 | SITD_ACTIVE);
+            // A packet of up to 1023 bytes crosses at most into the next page
+            sitd[0].buffers[0].write(ptr);
+            sitd[0].buffers[1].write(((ptr >> 12) + 1) << 12 | position);
+            sitd[0].buffers_hi[0].write(0);
+            sitd[0].buffers_hi[1].write(0);
+            sitd[0].back.write(LINK_TERMINATE);
+
+            sitds.push((frame, sitd, k));
+            offset += length;
+            k += 1;
+            frame = (frame + frame_step) % frames;
+        }
+        while k < buffer.packets.len() {
+            let mut itd = try!(dma::alloc_contiguous::<Itd>(mem::size_of::<Itd>(), 32));
+            // The pages are counted from the one the first packet of the frame starts in
+            let first_page = (data.physical() + offset) as u32 >> 12;
+            for page in 0..7 {
+                itd[0].buffers[page].write((first_page + page as u32) << 12);
+                itd[0].buffers_hi[page].write(0);
+            }
+            let value = itd[0].buffers[0].read();
+            itd[0].buffers[0].write(value | (endpoint as u32 & 0xF) << 8 | address as u32 & 0x7F);
+            let value = itd[0].buffers[1].read();
+            itd[0].buffers[1].write(value | direction | max_packet_size);
+            let value = itd[0].buffers[2].read();
+            itd[0].buffers[2].write(value | 1);
+
+            let mut packets = Vec::new();
+            for transaction in 0..8 {
+                itd[0].transactions[transaction].write(0);
+                if smask & 1 << transaction == 0 || k >= buffer.packets.len() {
+                    continue;
                 }
 
-                async_list.write((&*queuehead as *const QueueHead) as u32 | 2);
-                usb_cmd.writef(1 << 5 | 1, true);
+                let ptr = (data.physical() + offset) as u32;
+                let length = buffer.packets[k].length as u32;
+                itd[0].transactions[transaction].write(ITD_ACTIVE | length << 16 | ((ptr >> 12) - first_page) << 12 | ptr & 0xFFF);
+                packets.push((transaction, k));
 
-                for td in tds.iter().rev() {
-                    while volatile_load(td as *const Qtd).token & 1 << 7 == 1 << 7 {
-                        context_switch();
-                    }
+                offset += length as usize;
+                k += 1;
+            }
+
+            itds.push((frame, itd, packets));
+            frame = (frame + frame_step) % frames;
+        }
+
+        self.streams[i].next_frame = Some(frame);
+        self.streams[i].queued.push(EhciIsoch {
+            buffer: buffer,
+            data: data,
+            itds: itds,
+            sitds: sitds,
+        });
+
+        let used = self.streams[i].queued.last().unwrap().frames();
+        for frame in used {
+            self.link_frame(frame);
+        }
+
+        Ok(())
+    }
+
+    fn isoch_reap(&mut self, stream: &IsochStream) -> Option<IsochBuffer> {
+        let _held = match self.take() {
+            Ok(held) => held,
+            Err(_) => return None,
+        };
+        let i = match self.streams.iter().position(|other| other.id == stream.id) {
+            Some(i) => i,
+            None => return None,
+        };
+
+        let (frame, frames) = (self.frame(), self.frame_list.len());
+        match self.streams[i].queued.first_mut() {
+            Some(isoch) => {
+                Ehci::isoch_status(isoch, frame, frames);
+                if ! isoch.buffer.done() {
+                    return None;
                 }
+            },
+            None => return None,
+        }
 
-                usb_cmd.writef(1 << 5 | 1, false);
-                async_list.write(0);
+        // Its frames have gone by, so the controller is not in its iTDs as they are unlinked
+        let mut isoch = self.streams[i].queued.remove(0);
+        for frame in isoch.frames() {
+            self.link_frame(frame);
+        }
+
+        if self.streams[i].direction_in {
+            for j in 0..isoch.buffer.data.len() {
+                isoch.buffer.data[j] = isoch.data[j];
             }
         }
+        Some(isoch.buffer)
+    }
 
-        count
+    fn isoch_close(&mut self, stream: &IsochStream) {
+        let _held = hold(&mut self.busy);
+        if let Some(i) = self.streams.iter().position(|other| other.id == stream.id) {
+            self.unlink_stream(i);
+        }
     }
 }
@@ -1,197 +1,459 @@
-use arch::context::{context_switch, Context};
-use arch::memory;
+use collections::string::String;
+use collections::vec::Vec;
 
-use common::event::MouseEvent;
-use common::time::{self, Duration};
+use common::event::{HotplugEvent, HOTPLUG_USB};
 
-use core::{cmp, mem, ptr, slice};
+use core::{cmp, mem, slice};
 
-use graphics::display::VBEMODEINFO;
+use system::error::{Error, Result, EINVAL, EIO, ENOSPC, ENOSYS, EPIPE};
 
-use super::{Packet, Pipe, Setup};
-use super::desc::*;
+use super::{delay, Packet, Pipe, Setup, Speed, UsbDevice};
+use super::acm::serial;
+use super::audio::audio;
+use super::desc::{self, descriptors, interface_descriptors, Descriptor, DeviceDescriptor, ConfigDescriptor, EndpointDescriptor,
+                  InterfaceDescriptor, DESC_CFG, DESC_DEV, DESC_STR};
+use super::device::{usb_bus, usb_cancel, usb_claim, usb_devices, usb_free_address, usb_generation, usb_remove, usb_reuse};
+use super::hid::{keyboard, mouse, tablet};
+use super::hub::hub;
+use super::msd::mass_storage;
 
-pub trait Hci {
-    fn msg(&mut self, address: u8, endpoint: u8, pipe: Pipe, msgs: &[Packet]) -> usize;
+/// How often a control transfer that failed with a transaction error is sent again
+const CONTROL_RETRIES: usize = 3;
+
+/// An interrupt IN endpoint in the periodic schedule of a controller
+pub struct InterruptPipe {
+    /// Which pipe of the controller this is
+    pub id: usize,
+    pub address: u8,
+    pub endpoint: u8,
+}
+
+/// How a packet of an isochronous stream went
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum IsochStatus {
+    /// Not run yet
+    Pending,
+    /// Run, moving this many bytes
+    Done(u16),
+    /// Its interval went by before the controller reached it, the stream goes on with the next packet
+    Missed,
+    /// A transaction error, which is not retried
+    Failed,
+}
+
+/// A packet of an isochronous buffer, with the bytes it is to move
+#[derive(Copy, Clone, Debug)]
+pub struct IsochPacket {
+    pub length: u16,
+    pub status: IsochStatus,
+}
+
+/// A buffer of an isochronous stream, sent or filled one packet every interval
+pub struct IsochBuffer {
+    /// The packets, one after the other
+    pub data: Vec<u8>,
+    pub packets: Vec<IsochPacket>,
+}
+
+impl IsochBuffer {
+    /// A buffer with `data` cut into packets of `lengths`
+    pub fn new(data: Vec<u8>, lengths: &[u16]) -> IsochBuffer {
+        IsochBuffer {
+            data: data,
+            packets: lengths.iter().map(|&length| IsochPacket {
+                length: length,
+                status: IsochStatus::Pending,
+            }).collect(),
+        }
+    }
+
+    /// Whether the lengths of the packets fit in the data, and in `max_packet_size`
+    pub fn fits(&self, max_packet_size: u16) -> bool {
+        self.packets.iter().all(|packet| packet.length <= max_packet_size)
+            && self.packets.iter().fold(0, |total, packet| total + packet.length as usize) <= self.data.len()
+    }
+
+    /// Whether every packet was run, or missed
+    pub fn done(&self) -> bool {
+        self.packets.iter().all(|packet| packet.status != IsochStatus::Pending)
+    }
+}
+
+/// An isochronous endpoint in the periodic schedule of a controller, with bandwidth kept for it
+pub struct IsochStream {
+    /// Which stream of the controller this is
+    pub id: usize,
+    pub address: u8,
+    pub endpoint: u8,
+    /// The 125 us microframes from one packet to the next
+    pub interval: u32,
+}
+
+/// A host controller, which class drivers use without knowing which kind it is
+///
+/// Controllers implement `msg` and the bookkeeping hooks, enumeration and the typed transfers are built on them.
+pub trait UsbHc {
+    /// Run the stages `msgs` of one transfer on `endpoint` of `address`, returning the data bytes moved
+    fn msg(&mut self, address: u8, endpoint: u8, pipe: Pipe, msgs: &[Packet]) -> Result<usize>;
+
+    /// Record the speed and default pipe packet size of `address`, which transfers to it need
+    fn set_device(&mut self, _address: u8, _speed: Speed, _max_packet_size: u16) {}
+
+    /// Prepare the endpoint described by `desc_end` on `address`, before its configuration is set
+    fn set_endpoint(&mut self, _address: u8, _desc_end: &EndpointDescriptor) -> Result<()> {
+        Ok(())
+    }
+
+    /// Start the endpoint with `endpoint_address` of `address` again at DATA0, after it was halted
+    fn reset_toggle(&mut self, _address: u8, _endpoint_address: u8) {}
+
+    /// Move the device answering at address 0 to `address`
+    ///
+    /// Controllers that assign addresses themselves do it here instead of sending the request.
+    fn address_device(&mut self, address: u8) -> Result<()> {
+        try!(self.control_out(0, &Setup::set_address(address), &[]));
+        // The device has 2 ms to move to its new address
+        delay(2);
+        Ok(())
+    }
+
+    /// A control transfer on the default pipe, with the data stage `data` in the direction `setup` gives
+    ///
+    /// The data stage moves at most the length in `setup`, and may end early with a short packet, which is
+    /// followed by the status stage as usual. A stall of the default pipe refuses the request and is cleared by
+    /// the next setup packet, so the request is sent once more before the stall is returned. Other errors are
+    /// retried a few times, a timeout or a removed device is not.
+    fn control_transfer(&mut self, address: u8, setup: &Setup, mut data: Packet) -> Result<usize> {
+        let len = setup.len as usize;
+        match (&data, setup.request_type & 0x80 == 0x80) {
+            (&Packet::In(_), true) | (&Packet::Out(_), false) => (),
+            _ => return Err(Error::new(EINVAL)),
+        }
+
+        let mut stalled = false;
+        let mut retries = 0;
+        loop {
+            let result = match data {
+                Packet::In(ref mut data) => {
+                    let len = cmp::min(len, data.len());
+                    if len == 0 {
+                        self.msg(address, 0, Pipe::Control, &[Packet::Setup(setup), Packet::In(&mut [])])
+                    } else {
+                        self.msg(address, 0, Pipe::Control, &[Packet::Setup(setup), Packet::In(&mut data[.. len]), Packet::Out(&[])])
+                    }
+                },
+                Packet::Out(data) => {
+                    let len = cmp::min(len, data.len());
+                    if len == 0 {
+                        self.msg(address, 0, Pipe::Control, &[Packet::Setup(setup), Packet::In(&mut [])])
+                    } else {
+                        self.msg(address, 0, Pipe::Control, &[Packet::Setup(setup), Packet::Out(&data[.. len]), Packet::In(&mut [])])
+                    }
+                },
+                Packet::Setup(_) => return Err(Error::new(EINVAL)),
+            };
+
+            match result {
+                Ok(count) => return Ok(count),
+                Err(err) => if err.errno == EPIPE && ! stalled {
+                    stalled = true;
+                } else if err.errno == EIO && retries < CONTROL_RETRIES {
+                    retries += 1;
+                    delay(1);
+                } else {
+                    return Err(err);
+                },
+            }
+        }
+    }
+
+    /// A control transfer on the default pipe, reading into `data`
+    fn control_in(&mut self, address: u8, setup: &Setup, data: &mut [u8]) -> Result<usize> {
+        self.control_transfer(address, setup, Packet::In(data))
+    }
+
+    /// A control transfer on the default pipe, writing `data`
+    fn control_out(&mut self, address: u8, setup: &Setup, data: &[u8]) -> Result<usize> {
+        self.control_transfer(address, setup, Packet::Out(data))
+    }
+
+    /// Clear a stalled endpoint, on the device and in the controller
+    fn clear_halt(&mut self, address: u8, endpoint_address: u8) -> Result<()> {
+        try!(self.control_out(address, &Setup::clear_halt(endpoint_address), &[]));
+        self.reset_toggle(address, endpoint_address);
+        Ok(())
+    }
+
+    fn bulk_in(&mut self, address: u8, endpoint: u8, data: &mut [u8]) -> Result<usize> {
+        self.msg(address, endpoint, Pipe::Bulk, &[Packet::In(data)])
+    }
+
+    fn bulk_out(&mut self, address: u8, endpoint: u8, data: &[u8]) -> Result<usize> {
+        self.msg(address, endpoint, Pipe::Bulk, &[Packet::Out(data)])
+    }
+
+    /// Poll an interrupt endpoint, which times out if the device has nothing to report
+    fn interrupt_in(&mut self, address: u8, endpoint: u8, data: &mut [u8]) -> Result<usize> {
+        self.msg(address, endpoint, Pipe::Interrupt, &[Packet::In(data)])
+    }
 
     fn descriptor(&mut self,
                          address: u8,
                          descriptor_type: u8,
                          descriptor_index: u8,
                          descriptor_ptr: usize,
-                         descriptor_len: usize) {
-        self.msg(address, 0, Pipe::Control, &[
-            Packet::Setup(&Setup::get_descriptor(descriptor_type, descriptor_index, 0, descriptor_len as u16)),
-            Packet::In(&mut unsafe { slice::from_raw_parts_mut(descriptor_ptr as *mut u8, descriptor_len as usize) }),
-            Packet::Out(&[])
-        ]);
-    }
-
-    unsafe fn device(&mut self, address: u8) where Self: Sized + 'static {
-        self.msg(0, 0, Pipe::Control, &[
-            Packet::Setup(&Setup::set_address(address)),
-            Packet::In(&mut [])
-        ]);
-
-        let mut desc_dev = box DeviceDescriptor::default();
-        self.descriptor(address,
-                        DESC_DEV,
-                        0,
-                        (&mut *desc_dev as *mut DeviceDescriptor) as usize,
-                        mem::size_of_val(&*desc_dev));
-        syslog_debug!("{:#?}", *desc_dev);
-
-        if desc_dev.manufacturer_string > 0 {
-            let mut desc_str = box StringDescriptor::default();
-            self.descriptor(address,
-                            DESC_STR,
-                            desc_dev.manufacturer_string,
-                            (&mut *desc_str as *mut StringDescriptor) as usize,
-                            mem::size_of_val(&*desc_str));
-            syslog_info!("Manufacturer: {}", desc_str.str());
+                         descriptor_len: usize) -> Result<usize> {
+        self.control_in(address,
+                        &Setup::get_descriptor(descriptor_type, descriptor_index, 0, descriptor_len as u16),
+                        unsafe { slice::from_raw_parts_mut(descriptor_ptr as *mut u8, descriptor_len as usize) })
+    }
+
+    /// The language ids the strings of `address` are available in
+    fn languages(&mut self, address: u8) -> Vec<u16> {
+        let mut data = [0; 255];
+        match self.control_in(address, &Setup::get_descriptor(DESC_STR, 0, 0, data.len() as u16), &mut data) {
+            Ok(count) => desc::languages(&data[.. count]),
+            Err(_) => Vec::new(),
         }
+    }
 
-        if desc_dev.product_string > 0 {
-            let mut desc_str = box StringDescriptor::default();
-            self.descriptor(address,
-                            DESC_STR,
-                            desc_dev.product_string,
-                            (&mut *desc_str as *mut StringDescriptor) as usize,
-                            mem::size_of_val(&*desc_str));
-            syslog_info!("Product: {}", desc_str.str());
+    /// String `index` of `address`, in the first language it lists
+    fn string(&mut self, address: u8, index: u8) -> Option<String> {
+        if index == 0 {
+            return None;
         }
 
-        if desc_dev.serial_string > 0 {
-            let mut desc_str = box StringDescriptor::default();
-            self.descriptor(address,
-                            DESC_STR,
-                            desc_dev.serial_string,
-                            (&mut *desc_str as *mut StringDescriptor) as usize,
-                            mem::size_of_val(&*desc_str));
-            syslog_info!("Serial: {}", desc_str.str());
+        // US English, for devices that do not list their languages
+        let language = self.languages(address).get(0).map_or(0x0409, |&language| language);
+
+        let mut data = [0; 255];
+        match self.control_in(address, &Setup::get_descriptor(DESC_STR, index, language, data.len() as u16), &mut data) {
+            Ok(count) => desc::string(&data[.. count]),
+            Err(_) => None,
         }
+    }
 
-        for configuration in 0..(*desc_dev).configurations {
-            let desc_cfg_len = 1023;
-            let desc_cfg_buf = memory::alloc(desc_cfg_len) as *mut u8;
-            for i in 0..desc_cfg_len as isize {
-                ptr::write(desc_cfg_buf.offset(i), 0);
-            }
-            self.descriptor(address,
-                            DESC_CFG,
-                            configuration,
-                            desc_cfg_buf as usize,
-                            desc_cfg_len);
-
-            let desc_cfg = ptr::read(desc_cfg_buf as *const ConfigDescriptor);
-            syslog_debug!("{:#?}", desc_cfg);
-
-            if desc_cfg.string > 0 {
-                let mut desc_str = box StringDescriptor::default();
-                self.descriptor(address,
-                                DESC_STR,
-                                desc_cfg.string,
-                                (&mut *desc_str as *mut StringDescriptor) as usize,
-                                mem::size_of_val(&*desc_str));
-                syslog_info!("Configuration: {}", desc_str.str());
-            }
+    /// Prepare for the device that was just reset on `port` of `hub`, 0 for the root hub, before it is enumerated
+    fn attach(&mut self, _hub: u8, _port: u8, _speed: Speed) -> Result<()> {
+        Ok(())
+    }
 
-            let mut hid = false;
-
-            let mut i = desc_cfg.length as isize;
-            while i < desc_cfg.total_length as isize {
-                let length = ptr::read(desc_cfg_buf.offset(i));
-                let descriptor_type = ptr::read(desc_cfg_buf.offset(i + 1));
-                match descriptor_type {
-                    DESC_INT => {
-                        let desc_int = ptr::read(desc_cfg_buf.offset(i) as *const InterfaceDescriptor);
-                        syslog_debug!("{:#?}", desc_int);
-
-                        if desc_int.string > 0 {
-                            let mut desc_str = box StringDescriptor::default();
-                            self.descriptor(address,
-                                            DESC_STR,
-                                            desc_int.string,
-                                            (&mut *desc_str as *mut StringDescriptor) as usize,
-                                            mem::size_of_val(&*desc_str));
-                            syslog_info!("Interface: {}", desc_str.str());
-                        }
-                    }
-                    DESC_END => {
-                        let desc_end = ptr::read(desc_cfg_buf.offset(i) as *const EndpointDescriptor);
-                        syslog_debug!("{:#?}", desc_end);
-
-                        let endpoint = desc_end.address & 0xF;
-                        let in_len = desc_end.max_packet_size as usize;
-
-                        if hid {
-                            let this = self as *mut Hci;
-                            Context::spawn("kuhci_hid".into(),
-                                           box move || {
-                                if let Some(mode_info) = VBEMODEINFO {
-                                    syslog_info!("Starting HID driver");
-
-                                    let in_ptr = memory::alloc_aligned(in_len, 4096) as *mut u8;
-
-                                    loop {
-                                        for i in 0..in_len as isize {
-                                            ptr::write(in_ptr.offset(i), 0);
-                                        }
-
-                                        if (*this).msg(address, endpoint, Pipe::Isochronous, &[
-                                            Packet::In(&mut slice::from_raw_parts_mut(in_ptr, in_len))
-                                        ]) > 0 {
-                                            let buttons = ptr::read(in_ptr.offset(0) as *const u8) as usize;
-                                            let x = ptr::read(in_ptr.offset(1) as *const u16) as usize;
-                                            let y = ptr::read(in_ptr.offset(3) as *const u16) as usize;
-
-                                            let mouse_x = (x * mode_info.xresolution as usize) / 32768;
-                                            let mouse_y = (y * mode_info.yresolution as usize) / 32768;
-
-                                            let mouse_event = MouseEvent {
-                                                x: cmp::max(0, cmp::min(mode_info.xresolution as i32 - 1, mouse_x as i32)),
-                                                y: cmp::max(0, cmp::min(mode_info.yresolution as i32 - 1, mouse_y as i32)),
-                                                left_button: buttons & 1 == 1,
-                                                middle_button: buttons & 4 == 4,
-                                                right_button: buttons & 2 == 2,
-                                            };
-
-                                            if (& *::env().console.get()).draw {
-                                                //ignore mouse event
-                                            } else {
-                                                ::env().events.send(mouse_event.to_event(), "HCI events send");
-                                            }
-                                        }
-
-                                        {
-                                            let contexts = &mut *::env().contexts.get();
-                                            if let Ok(mut current) = contexts.current_mut() {
-                                                current.wake = Some(Duration::monotonic() + Duration::new(0, 10 * time::NANOS_PER_MILLI));
-                                                current.block("HCI sleep");
-                                            }
-                                        }
-
-                                        context_switch();
-                                    }
-
-                                    //memory::unalloc(in_ptr as usize);
-                                }
-                            });
-                        }
-                    }
-                    DESC_HID => {
-                        let desc_hid = &*(desc_cfg_buf.offset(i) as *const HIDDescriptor);
-                        syslog_debug!("{:#?}", desc_hid);
-                        hid = true;
-                    }
-                    _ => {
-                        syslog_debug!("Unknown Descriptor Length {} Type {:X}", length as usize, descriptor_type);
-                    }
-                }
-                i += length as isize;
+    /// Release what is kept for `address`, after its device is gone or failed to enumerate
+    fn detach(&mut self, _address: u8) {}
+
+    /// Record that `address` is a hub with `ports` downstream ports
+    fn set_hub(&mut self, _address: u8, _ports: u8, _think_time: u8) -> Result<()> {
+        Ok(())
+    }
+
+    /// Have the controller poll the interrupt IN endpoint `desc_end` of `address` at the period it asks for
+    ///
+    /// Controllers without a periodic schedule poll it in `interrupt_report` instead.
+    fn interrupt_open(&mut self, address: u8, desc_end: &EndpointDescriptor) -> Result<InterruptPipe> {
+        Ok(InterruptPipe {
+            id: 0,
+            address: address,
+            endpoint: desc_end.number(),
+        })
+    }
+
+    /// Copy the report the controller has received on `pipe` into `data`, if there is one, and poll for the next
+    fn interrupt_report(&mut self, pipe: &InterruptPipe, data: &mut [u8]) -> Option<usize> {
+        self.interrupt_in(pipe.address, pipe.endpoint, data).ok()
+    }
+
+    /// Stop polling `pipe`, the pipes of a device are also closed when it is detached
+    fn interrupt_close(&mut self, _pipe: &InterruptPipe) {}
+
+    /// Keep bandwidth for the isochronous endpoint `desc_end` of `address`, for buffers queued with `isoch_queue`
+    ///
+    /// Fails with ENOSPC if the periodic schedule has no room for it, and ENOSYS on controllers without
+    /// isochronous transfers.
+    fn isoch_open(&mut self, _address: u8, _desc_end: &EndpointDescriptor) -> Result<IsochStream> {
+        Err(Error::new(ENOSYS))
+    }
+
+    /// Queue `buffer` on `stream`, its first packet in the interval after the last packet queued before it
+    ///
+    /// A stream that ran dry starts again a few frames ahead, so a late buffer loses the intervals that went by
+    /// and the stream goes on. Fails with EAGAIN while too much is queued already.
+    fn isoch_queue(&mut self, _stream: &IsochStream, _buffer: IsochBuffer) -> Result<()> {
+        Err(Error::new(ENOSYS))
+    }
+
+    /// Take the oldest buffer of `stream` once all its packets are done, with the status of each
+    fn isoch_reap(&mut self, _stream: &IsochStream) -> Option<IsochBuffer> {
+        None
+    }
+
+    /// Stop `stream` and give back its bandwidth, dropping the buffers queued on it
+    fn isoch_close(&mut self, _stream: &IsochStream) {}
+
+    /// Switch `desc_int` of `address` to its alternate setting, preparing the endpoints it has in `configuration`
+    fn set_interface(&mut self, address: u8, configuration: &[u8], desc_int: &InterfaceDescriptor) -> Result<()> {
+        for descriptor in interface_descriptors(configuration, desc_int) {
+            if let Descriptor::Endpoint(desc_end) = descriptor {
+                try!(self.set_endpoint(address, &desc_end));
             }
+        }
+
+        try!(self.control_out(address, &Setup::set_interface(desc_int.number, desc_int.alternate), &[]));
+        Ok(())
+    }
+
+    /// Enumerate the device on root `port`, called by the controller once the port is reset and the device answers at address 0
+    unsafe fn port_connected(&mut self, port: u8, speed: Speed) -> Result<u8> where Self: Sized + 'static {
+        usb_connect(self as *mut UsbHc, 0, port, speed)
+    }
+
+    /// Forget the device on root `port`, called by the controller when the port is disconnected
+    fn port_disconnected(&mut self, port: u8) where Self: Sized + 'static {
+        usb_disconnect(self as *mut UsbHc, 0, port);
+    }
+}
 
-            memory::unalloc(desc_cfg_buf as usize);
+/// Enumerate the device on `port` of `hub` of `hci`, which was just reset and answers at address 0
+///
+/// The device is given an address and its first configuration, then recorded for the class drivers.
+pub unsafe fn usb_connect(hci: *mut UsbHc, hub: u8, port: u8, speed: Speed) -> Result<u8> {
+    // Number the controller now, so buses keep the order they were found in
+    usb_bus(hci);
+
+    try!((*hci).attach(hub, port, speed));
+
+    let mut assigned = 0;
+    let result = enumerate(hci, hub, port, speed, &mut assigned);
+    match result {
+        Ok(address) => ::env().events.send(HotplugEvent {
+            kind: HOTPLUG_USB,
+            index: address as i64,
+            added: true,
+        }.to_event(), "usb_connect"),
+        Err(_) => (*hci).detach(assigned),
+    }
+
+    result
+}
+
+/// Forget the device on `port` of `hub` of `hci`, and those behind it
+///
+/// Their drivers stop once they see the device is gone, their transfers in progress fail with ENODEV.
+pub fn usb_disconnect(hci: *mut UsbHc, hub: u8, port: u8) {
+    for address in usb_remove(hci, hub, port) {
+        usb_cancel(hci, address);
+        unsafe { (*hci).detach(address) };
+        ::env().events.send(HotplugEvent {
+            kind: HOTPLUG_USB,
+            index: address as i64,
+            added: false,
+        }.to_event(), "usb_disconnect");
+    }
+}
+
+/// Address and configure the device, setting `assigned` to its address once it has one
+unsafe fn enumerate(hci: *mut UsbHc, hub: u8, port: u8, speed: Speed, assigned: &mut u8) -> Result<u8> {
+    // Every device takes 8 byte packets on the default pipe, the first 8 bytes of its descriptor give its real size
+    (*hci).set_device(0, speed, 8);
+
+    let mut desc_dev = box DeviceDescriptor::default();
+    try!((*hci).descriptor(0,
+                           DESC_DEV,
+                           0,
+                           (&mut *desc_dev as *mut DeviceDescriptor) as usize,
+                           8));
+    // A super speed device gives the size as a power of two
+    let max_packet_size = if speed == Speed::Super {
+        1 << cmp::min(desc_dev.max_packet_size, 9)
+    } else {
+        cmp::max(8, desc_dev.max_packet_size as u16)
+    };
+
+    let address = match usb_free_address(hci) {
+        Some(address) => address,
+        None => return Err(Error::new(ENOSPC)),
+    };
+
+    usb_reuse(hci, address);
+    try!((*hci).address_device(address));
+    *assigned = address;
+    (*hci).set_device(address, speed, max_packet_size);
+
+    try!((*hci).descriptor(address,
+                           DESC_DEV,
+                           0,
+                           (&mut *desc_dev as *mut DeviceDescriptor) as usize,
+                           mem::size_of_val(&*desc_dev)));
+    syslog_debug!("{:#?}", *desc_dev);
+
+    let manufacturer = (*hci).string(address, desc_dev.manufacturer_string);
+    if let Some(ref manufacturer) = manufacturer {
+        syslog_info!("Manufacturer: {}", manufacturer);
+    }
+    let product = (*hci).string(address, desc_dev.product_string);
+    if let Some(ref product) = product {
+        syslog_info!("Product: {}", product);
+    }
+    let serial = (*hci).string(address, desc_dev.serial_string);
+    if let Some(ref serial) = serial {
+        syslog_info!("Serial: {}", serial);
+    }
+
+    // The header gives the length of the whole configuration, with its interfaces and endpoints
+    let mut desc_cfg = box ConfigDescriptor::default();
+    try!((*hci).descriptor(address,
+                           DESC_CFG,
+                           0,
+                           (&mut *desc_cfg as *mut ConfigDescriptor) as usize,
+                           mem::size_of_val(&*desc_cfg)));
+
+    let mut configuration = vec![0; cmp::max(desc_cfg.total_length as usize, mem::size_of::<ConfigDescriptor>())];
+    let count = try!((*hci).descriptor(address,
+                                       DESC_CFG,
+                                       0,
+                                       configuration.as_mut_ptr() as usize,
+                                       configuration.len()));
+    configuration.truncate(count);
+
+    // Only the first setting of each interface is in use, the endpoints of the others are prepared once chosen
+    let mut alternate = 0;
+    for descriptor in descriptors(&configuration) {
+        match descriptor {
+            Descriptor::Interface(desc_int) => alternate = desc_int.alternate,
+            Descriptor::Endpoint(desc_end) => if alternate == 0 {
+                try!((*hci).set_endpoint(address, &desc_end));
+            },
+            _ => (),
         }
     }
+
+    try!((*hci).control_out(address, &Setup::set_configuration(desc_cfg.number), &[]));
+
+    if let Some(name) = (*hci).string(address, desc_cfg.string) {
+        syslog_info!("Configuration: {}", name);
+    }
+
+    usb_devices().push(UsbDevice {
+        hci: hci,
+        hub: hub,
+        port: port,
+        address: address,
+        generation: usb_generation(),
+        speed: speed,
+        descriptor: *desc_dev,
+        manufacturer: manufacturer,
+        product: product,
+        serial: serial,
+        configuration: configuration,
+        driver: None,
+    });
+
+    keyboard(hci, address);
+    mouse(hci, address);
+    mass_storage(hci, address);
+    serial(hci, address);
+    audio(hci, address);
+    hub(hci, address);
+    tablet(hci, address);
+
+    Ok(address)
 }
@@ -0,0 +1,457 @@
+use arch::context::Context;
+use arch::memory;
+use arch::timekeeping;
+
+use audio::scheme::media_key;
+
+use collections::vec::Vec;
+
+use common::event::*;
+
+use core::{cmp, ptr, slice};
+
+use drivers::cursor::cursor_move;
+use drivers::kb_layouts::layouts;
+
+use env::focus;
+
+use graphics::display::VBEMODEINFO;
+
+use schemes::keyboard::{self, KeyRepeat, LED_CAPS, LED_NUM, LED_SCROLL};
+
+use super::{Pipe, Setup, UsbHc};
+use super::desc::{descriptors, find_endpoint, find_interface, Descriptor};
+use super::device::{usb_attached, usb_claim};
+
+pub const HID_CLASS: u8 = 3;
+pub const HID_SUBCLASS_BOOT: u8 = 1;
+pub const HID_PROTOCOL_KEYBOARD: u8 = 1;
+pub const HID_PROTOCOL_MOUSE: u8 = 2;
+
+/// The last id given to a pointing device, 0 is the PS/2 mouse
+static mut POINTERS: u8 = 0;
+
+/// An id for a new pointing device, for its events to be told apart from others
+pub fn pointer_id() -> u8 {
+    unsafe {
+        POINTERS = POINTERS.wrapping_add(1);
+        POINTERS
+    }
+}
+
+/// The usage reported in every key slot when too many keys are held to tell which
+const USAGE_ROLLOVER: u8 = 0x01;
+
+/// The scancodes of the keyboard usages, 0 for keys without one
+static USAGE_SCANCODES: [u8; 0x82] = [
+    0, 0, 0, 0, K_A, K_B, K_C, K_D,
+    K_E, K_F, K_G, K_H, K_I, K_J, K_K, K_L,
+    K_M, K_N, K_O, K_P, K_Q, K_R, K_S, K_T,
+    K_U, K_V, K_W, K_X, K_Y, K_Z, K_1, K_2,
+    K_3, K_4, K_5, K_6, K_7, K_8, K_9, K_0,
+    K_ENTER, K_ESC, K_BKSP, K_TAB, K_SPACE, K_MINUS, K_EQUALS, K_BRACE_OPEN,
+    K_BRACE_CLOSE, K_BACKSLASH, K_BACKSLASH, K_SEMICOLON, K_QUOTE, K_TICK, K_COMMA, K_PERIOD,
+    K_SLASH, K_CAPS, K_F1, K_F2, K_F3, K_F4, K_F5, K_F6,
+    K_F7, K_F8, K_F9, K_F10, K_F11, K_F12, 0, 0x46,
+    0, 0x52, K_HOME, K_PGUP, K_DEL, K_END, K_PGDN, K_RIGHT,
+    K_LEFT, K_DOWN, K_UP, 0x45, K_SLASH, 0x37, 0x4A, 0x4E,
+    // The keypad gives the scancodes of its navigation keys, as it does on PS/2
+    K_ENTER, K_END, K_DOWN, K_PGDN, K_LEFT, 0x4C, K_RIGHT, K_HOME,
+    K_UP, K_PGUP, 0x52, K_DEL, 0x56, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, K_MUTE,
+    K_VOLUP, K_VOLDOWN,
+];
+
+/// The scancodes of the bits of the modifier byte: left control, shift, alt and GUI, then the right ones
+static MODIFIER_SCANCODES: [u8; 8] = [K_CTRL, K_LEFT_SHIFT, K_ALT, 0x5B, K_CTRL, K_RIGHT_SHIFT, K_ALT, 0x5C];
+
+/// The scancodes of num lock and scroll lock
+const K_NUM_LOCK: u8 = 0x45;
+const K_SCROLL_LOCK: u8 = 0x46;
+
+const MOD_SHIFT: u8 = 1 << 1 | 1 << 5;
+const MOD_ALTGR: u8 = 1 << 6;
+
+/// The state of a boot protocol keyboard, from which its reports are turned into key events
+struct Keyboard {
+    modifiers: u8,
+    keys: [u8; 6],
+    caps_lock: bool,
+    num_lock: bool,
+    scroll_lock: bool,
+}
+
+impl Keyboard {
+    fn event(&self, scancode: u8, pressed: bool) -> KeyEvent {
+        let shift = self.caps_lock != (self.modifiers & MOD_SHIFT != 0);
+        let altgr = self.modifiers & MOD_ALTGR != 0;
+
+        KeyEvent {
+            character: layouts::char_for_scancode(scancode, shift, altgr, &focus::layout()),
+            scancode: scancode,
+            pressed: pressed,
+        }
+    }
+
+    fn scancode(usage: u8) -> u8 {
+        USAGE_SCANCODES.get(usage as usize).map_or(0, |&scancode| scancode)
+    }
+
+    /// The presses and releases between the last report and `report`
+    fn report(&mut self, report: &[u8; 8]) -> Vec<KeyEvent> {
+        let mut events = Vec::new();
+
+        // The keys cannot be told apart, so keep the old state until some are released
+        if report[2..].iter().any(|&usage| usage == USAGE_ROLLOVER) {
+            return events;
+        }
+
+        for bit in 0..8 {
+            let mask = 1u8 << bit;
+            if (report[0] ^ self.modifiers) & mask == mask {
+                self.modifiers ^= mask;
+                events.push(self.event(MODIFIER_SCANCODES[bit], self.modifiers & mask == mask));
+            }
+        }
+
+        let keys = &report[2..];
+
+        for &usage in self.keys.iter() {
+            let scancode = Keyboard::scancode(usage);
+            if scancode != 0 && ! keys.contains(&usage) {
+                events.push(self.event(scancode, false));
+            }
+        }
+
+        for &usage in keys.iter() {
+            let scancode = Keyboard::scancode(usage);
+            if scancode != 0 && ! self.keys.contains(&usage) {
+                match scancode {
+                    K_CAPS => self.caps_lock = ! self.caps_lock,
+                    K_NUM_LOCK => self.num_lock = ! self.num_lock,
+                    K_SCROLL_LOCK => self.scroll_lock = ! self.scroll_lock,
+                    _ => (),
+                }
+                events.push(self.event(scancode, true));
+            }
+        }
+
+        for i in 0..self.keys.len() {
+            self.keys[i] = keys[i];
+        }
+
+        events
+    }
+
+    /// The LEDs to light, in the bits of the boot protocol output report: num lock, caps lock, scroll lock
+    fn leds(&self) -> u8 {
+        let mut locks = 0;
+        if self.caps_lock {
+            locks |= LED_CAPS;
+        }
+        if self.num_lock {
+            locks |= LED_NUM;
+        }
+        if self.scroll_lock {
+            locks |= LED_SCROLL;
+        }
+
+        let leds = keyboard::leds(locks);
+        let mut report = 0;
+        if leds & LED_NUM == LED_NUM {
+            report |= 1;
+        }
+        if leds & LED_CAPS == LED_CAPS {
+            report |= 2;
+        }
+        if leds & LED_SCROLL == LED_SCROLL {
+            report |= 4;
+        }
+        report
+    }
+}
+
+/// Pass a key event to the focus, media keys go to the mixer instead
+fn send(key_event: KeyEvent) {
+    if ! media_key(&key_event) {
+        focus::send(key_event.to_event());
+    }
+}
+
+/// Start the keyboard driver if the device at `address` of `hci` has a boot keyboard interface
+pub unsafe fn keyboard(hci: *mut UsbHc, address: u8) {
+    let device = match usb_claim("keyboard", |device| {
+        device.on(hci) && device.address == address
+            && find_interface(&device.configuration, HID_CLASS, Some(HID_SUBCLASS_BOOT), Some(HID_PROTOCOL_KEYBOARD)).is_some()
+    }) {
+        Some(device) => device,
+        None => return,
+    };
+    let generation = device.generation;
+
+    let desc_int = find_interface(&device.configuration, HID_CLASS, Some(HID_SUBCLASS_BOOT), Some(HID_PROTOCOL_KEYBOARD)).unwrap();
+    let desc_end = match find_endpoint(&device.configuration, &desc_int, Pipe::Interrupt, true) {
+        Some(desc_end) => desc_end,
+        None => {
+            syslog_warning!("USB keyboard: No interrupt endpoint");
+            device.driver = None;
+            return;
+        }
+    };
+
+    if let Err(err) = (*hci).control_out(address, &Setup::set_protocol(desc_int.number, 0), &[]) {
+        syslog_warning!("USB keyboard: Failed to set boot protocol: {}", err);
+        device.driver = None;
+        return;
+    }
+    // Only report changes, repeating keys is done here. Some keyboards do not support this.
+    let _ = (*hci).control_out(address, &Setup::set_idle(desc_int.number, 0), &[]);
+
+    let pipe = match (*hci).interrupt_open(address, &desc_end) {
+        Ok(pipe) => pipe,
+        Err(err) => {
+            syslog_warning!("USB keyboard: Failed to open interrupt pipe: {}", err);
+            device.driver = None;
+            return;
+        }
+    };
+
+    let interface = desc_int.number;
+    Context::spawn("kusb_keyboard".into(),
+                   box move || {
+        syslog_info!("Starting USB keyboard driver");
+
+        let mut keyboard = Keyboard {
+            modifiers: 0,
+            keys: [0; 6],
+            caps_lock: false,
+            num_lock: false,
+            scroll_lock: false,
+        };
+        // The keyboard only reports changes, so its keys repeat here
+        let mut repeat = KeyRepeat::new();
+        let mut leds = 0;
+
+        while usb_attached(hci, address, generation) {
+            let mut report = [0; 8];
+            if let Some(count) = (*hci).interrupt_report(&pipe, &mut report) {
+                if count > 0 {
+                    for key_event in keyboard.report(&report) {
+                        repeat.key(&key_event);
+                        send(key_event);
+                    }
+                }
+            }
+
+            if let Some(key_event) = repeat.due() {
+                send(key_event);
+            }
+
+            // Some keyboards have no LEDs and fail this, they are not asked again until the LEDs change
+            if keyboard.leds() != leds {
+                leds = keyboard.leds();
+                let _ = (*hci).control_out(address, &Setup::set_report(interface, 1), &[leds]);
+            }
+
+            timekeeping::sleep(10, "USB keyboard sleep");
+        }
+
+        (*hci).interrupt_close(&pipe);
+
+        // An empty report releases the keys and modifiers still held, which also ends the repeat
+        for key_event in keyboard.report(&[0; 8]) {
+            repeat.key(&key_event);
+            send(key_event);
+        }
+
+        syslog_info!("USB keyboard driver stopped");
+    });
+}
+
+/// Start the mouse driver if the device at `address` of `hci` has a boot mouse interface
+pub unsafe fn mouse(hci: *mut UsbHc, address: u8) {
+    let device = match usb_claim("mouse", |device| {
+        device.on(hci) && device.address == address
+            && find_interface(&device.configuration, HID_CLASS, Some(HID_SUBCLASS_BOOT), Some(HID_PROTOCOL_MOUSE)).is_some()
+    }) {
+        Some(device) => device,
+        None => return,
+    };
+    let generation = device.generation;
+
+    let desc_int = find_interface(&device.configuration, HID_CLASS, Some(HID_SUBCLASS_BOOT), Some(HID_PROTOCOL_MOUSE)).unwrap();
+    let desc_end = match find_endpoint(&device.configuration, &desc_int, Pipe::Interrupt, true) {
+        Some(desc_end) => desc_end,
+        None => {
+            syslog_warning!("USB mouse: No interrupt endpoint");
+            device.driver = None;
+            return;
+        }
+    };
+
+    if let Err(err) = (*hci).control_out(address, &Setup::set_protocol(desc_int.number, 0), &[]) {
+        syslog_warning!("USB mouse: Failed to set boot protocol: {}", err);
+        device.driver = None;
+        return;
+    }
+    let _ = (*hci).control_out(address, &Setup::set_idle(desc_int.number, 0), &[]);
+
+    let pipe = match (*hci).interrupt_open(address, &desc_end) {
+        Ok(pipe) => pipe,
+        Err(err) => {
+            syslog_warning!("USB mouse: Failed to open interrupt pipe: {}", err);
+            device.driver = None;
+            return;
+        }
+    };
+    let id = pointer_id();
+
+    Context::spawn("kusb_mouse".into(),
+                   box move || {
+        syslog_info!("Starting USB mouse driver {}", id);
+
+        let mut buttons = 0;
+
+        while usb_attached(hci, address, generation) {
+            let mut report = [0; 4];
+            if let Some(count) = (*hci).interrupt_report(&pipe, &mut report) {
+                if count >= 3 {
+                    buttons = report[0];
+                    let (x, y) = cursor_move(report[1] as i8 as i32, report[2] as i8 as i32);
+
+                    focus::send(MouseEvent {
+                        x: x,
+                        y: y,
+                        left_button: buttons & 1 == 1,
+                        middle_button: buttons & 4 == 4,
+                        right_button: buttons & 2 == 2,
+                        device: id,
+                    }.to_event());
+
+                    if count >= 4 && report[3] != 0 {
+                        focus::send(ScrollEvent {
+                            x: 0,
+                            y: report[3] as i8 as i32,
+                            device: id,
+                        }.to_event());
+                    }
+                }
+            }
+
+            timekeeping::sleep(10, "USB mouse sleep");
+        }
+
+        (*hci).interrupt_close(&pipe);
+
+        // A drag cannot end with the mouse gone, so let go of the buttons
+        if buttons != 0 {
+            let (x, y) = cursor_move(0, 0);
+            focus::send(MouseEvent {
+                x: x,
+                y: y,
+                left_button: false,
+                middle_button: false,
+                right_button: false,
+                device: id,
+            }.to_event());
+        }
+
+        syslog_info!("USB mouse driver {} stopped", id);
+    });
+}
+
+/// Start the tablet driver if the device at `address` has a HID interface
+pub unsafe fn tablet(hci: *mut UsbHc, address: u8) {
+    let device = match usb_claim("tablet", |device| device.on(hci) && device.address == address) {
+        Some(device) => device,
+        None => return,
+    };
+    let generation = device.generation;
+
+    let mut hid = false;
+
+    for descriptor in descriptors(&device.configuration) {
+        match descriptor {
+            Descriptor::Interface(desc_int) => {
+                syslog_debug!("{:#?}", desc_int);
+
+                if let Some(name) = (*hci).string(address, desc_int.string) {
+                    syslog_info!("Interface: {}", name);
+                }
+            }
+            Descriptor::Endpoint(desc_end) => {
+                syslog_debug!("{:#?}", desc_end);
+
+                let in_len = desc_end.max_packet_size() as usize;
+
+                if hid && desc_end.pipe() == Pipe::Interrupt && desc_end.is_in() {
+                    let pipe = match (*hci).interrupt_open(address, &desc_end) {
+                        Ok(pipe) => pipe,
+                        Err(err) => {
+                            syslog_warning!("HID: Failed to open interrupt pipe: {}", err);
+                            continue;
+                        }
+                    };
+                    let id = pointer_id();
+                    Context::spawn("kuhci_hid".into(),
+                                   box move || {
+                        if let Some(mode_info) = VBEMODEINFO {
+                            syslog_info!("Starting HID driver");
+
+                            let in_ptr = memory::alloc_aligned(in_len, 4096) as *mut u8;
+
+                            while usb_attached(hci, address, generation) {
+                                for i in 0..in_len as isize {
+                                    ptr::write(in_ptr.offset(i), 0);
+                                }
+
+                                if let Some(count) = (*hci).interrupt_report(&pipe, slice::from_raw_parts_mut(in_ptr, in_len)) {
+                                    if count > 0 {
+                                        let buttons = ptr::read(in_ptr.offset(0) as *const u8) as usize;
+                                        let x = ptr::read(in_ptr.offset(1) as *const u16) as usize;
+                                        let y = ptr::read(in_ptr.offset(3) as *const u16) as usize;
+
+                                        // The mode may have been switched since the driver started
+                                        let mode_info = VBEMODEINFO.unwrap_or(mode_info);
+                                        let mouse_x = (x * mode_info.xresolution as usize) / 32768;
+                                        let mouse_y = (y * mode_info.yresolution as usize) / 32768;
+
+                                        let mouse_event = MouseEvent {
+                                            x: cmp::max(0, cmp::min(mode_info.xresolution as i32 - 1, mouse_x as i32)),
+                                            y: cmp::max(0, cmp::min(mode_info.yresolution as i32 - 1, mouse_y as i32)),
+                                            left_button: buttons & 1 == 1,
+                                            middle_button: buttons & 4 == 4,
+                                            right_button: buttons & 2 == 2,
+                                            device: id,
+                                        };
+
+                                        focus::send(mouse_event.to_event());
+                                    }
+                                }
+
+                                timekeeping::sleep(10, "HCI sleep");
+                            }
+
+                            (*hci).interrupt_close(&pipe);
+                            memory::unalloc(in_ptr as usize);
+                        }
+                    });
+                }
+            }
+            Descriptor::Hid(desc_hid) => {
+                syslog_debug!("{:#?}", desc_hid);
+                hid = true;
+            }
+            Descriptor::Config(_) => (),
+            Descriptor::Other(descriptor_type, data) => {
+                syslog_debug!("Unknown Descriptor Length {} Type {:X}", data.len(), descriptor_type);
+            }
+        }
+    }
+
+    if ! hid {
+        device.driver = None;
+    }
+}
@@ -0,0 +1,264 @@
+use arch::context::Context;
+use arch::timekeeping;
+
+use core::cmp;
+
+use system::error::{Error, Result, EIO, ETIMEDOUT};
+
+use super::{delay, Pipe, Setup, Speed, UsbHc};
+use super::desc::{find_endpoint, find_interface};
+use super::device::{usb_attached, usb_claim, usb_devices, usb_enumerate_begin, usb_enumerate_end};
+use super::hci::{usb_connect, usb_disconnect};
+
+pub const HUB_CLASS: u8 = 9;
+
+const DESC_HUB: u8 = 0x29;
+const DESC_SS_HUB: u8 = 0x2A;
+
+const PORT_RESET: u16 = 4;
+const PORT_POWER: u16 = 8;
+
+const STS_CONNECTION: u16 = 1 << 0;
+const STS_ENABLE: u16 = 1 << 1;
+const STS_LOW_SPEED: u16 = 1 << 9;
+const STS_HIGH_SPEED: u16 = 1 << 10;
+
+const CHG_CONNECTION: u16 = 1 << 0;
+const CHG_RESET: u16 = 1 << 4;
+
+/// The feature that clears each port change bit, the last three are only on super speed hubs
+static CHANGE_FEATURES: [(u16, u16); 8] = [
+    (1 << 0, 16), // C_PORT_CONNECTION
+    (1 << 1, 17), // C_PORT_ENABLE
+    (1 << 2, 18), // C_PORT_SUSPEND
+    (1 << 3, 19), // C_PORT_OVER_CURRENT
+    (1 << 4, 20), // C_PORT_RESET
+    (1 << 5, 29), // C_BH_PORT_RESET
+    (1 << 6, 25), // C_PORT_LINK_STATE
+    (1 << 7, 26), // C_PORT_CONFIG_ERROR
+];
+
+/// A hub, enumerating the devices on its downstream ports
+struct Hub {
+    hci: *mut UsbHc,
+    address: u8,
+    ports: u8,
+    super_speed: bool,
+}
+
+impl Hub {
+    /// The status and change bits of `port`
+    unsafe fn port_status(&self, port: u8) -> Result<(u16, u16)> {
+        let mut status = [0; 4];
+        let count = try!((*self.hci).control_in(self.address, &Setup::get_port_status(port), &mut status));
+        if count < status.len() {
+            return Err(Error::new(EIO));
+        }
+
+        Ok((status[0] as u16 | (status[1] as u16) << 8, status[2] as u16 | (status[3] as u16) << 8))
+    }
+
+    /// Acknowledge the changes of `port` in `change`
+    unsafe fn clear_changes(&self, port: u8, change: u16) {
+        for &(bit, feature) in CHANGE_FEATURES.iter() {
+            if change & bit == bit {
+                let _ = (*self.hci).control_out(self.address, &Setup::clear_port_feature(port, feature), &[]);
+            }
+        }
+    }
+
+    /// Reset `port`, which enables it, returning the speed of its device
+    unsafe fn reset_port(&self, port: u8) -> Result<Speed> {
+        try!((*self.hci).control_out(self.address, &Setup::set_port_feature(port, PORT_RESET), &[]));
+
+        // The hub ends the reset after 10 to 20 ms
+        let mut status = 0;
+        let mut change = 0;
+        for _ in 0..50 {
+            delay(10);
+            let (new_status, new_change) = try!(self.port_status(port));
+            status = new_status;
+            change = new_change;
+            if change & CHG_RESET == CHG_RESET {
+                break;
+            }
+        }
+        self.clear_changes(port, change & CHG_RESET);
+
+        if change & CHG_RESET != CHG_RESET {
+            return Err(Error::new(ETIMEDOUT));
+        }
+        if status & STS_ENABLE != STS_ENABLE {
+            return Err(Error::new(EIO));
+        }
+
+        // The device has 10 ms to recover from the reset
+        delay(10);
+
+        Ok(if self.super_speed {
+            Speed::Super
+        } else if status & STS_LOW_SPEED == STS_LOW_SPEED {
+            Speed::Low
+        } else if status & STS_HIGH_SPEED == STS_HIGH_SPEED {
+            Speed::High
+        } else {
+            Speed::Full
+        })
+    }
+
+    /// Handle a connect or disconnect on `port`
+    unsafe fn port_change(&self, port: u8) {
+        let (status, change) = match self.port_status(port) {
+            Ok(status) => status,
+            Err(err) => {
+                syslog_warning!("USB hub {}: Failed to read port {}: {}", self.address, port, err);
+                return;
+            }
+        };
+        self.clear_changes(port, change);
+        if change & CHG_CONNECTION != CHG_CONNECTION {
+            return;
+        }
+
+        usb_disconnect(self.hci, self.address, port);
+        if status & STS_CONNECTION == STS_CONNECTION {
+            // Let the connection settle before the reset
+            delay(100);
+
+            usb_enumerate_begin();
+            match self.reset_port(port) {
+                Ok(speed) => {
+                    syslog_info!("USB hub {}: {:?} speed device on port {}", self.address, speed, port);
+                    if let Err(err) = usb_connect(self.hci, self.address, port, speed) {
+                        syslog_warning!("USB hub {}: Failed to enumerate port {}: {}", self.address, port, err);
+                    }
+                },
+                Err(err) => syslog_warning!("USB hub {}: Port {} could not be enabled: {}", self.address, port, err),
+            }
+            usb_enumerate_end();
+        } else {
+            syslog_info!("USB hub {}: Device removed from port {}", self.address, port);
+        }
+    }
+}
+
+/// The number of hubs above the one attached to `hub`
+fn depth(hci: *mut UsbHc, mut hub: u8) -> u16 {
+    let mut depth = 0;
+    while hub != 0 {
+        depth += 1;
+        hub = usb_devices().iter()
+                           .find(|device| device.on(hci) && device.address == hub)
+                           .map_or(0, |device| device.hub);
+    }
+    depth
+}
+
+/// Start the hub driver if the device at `address` of `hci` is a hub
+///
+/// Devices on its ports are enumerated like those on root ports, and removed with it.
+pub unsafe fn hub(hci: *mut UsbHc, address: u8) {
+    let device = match usb_claim("hub", |device| {
+        device.on(hci) && device.address == address && find_interface(&device.configuration, HUB_CLASS, None, None).is_some()
+    }) {
+        Some(device) => device,
+        None => return,
+    };
+    let generation = device.generation;
+
+    let desc_int = find_interface(&device.configuration, HUB_CLASS, None, None).unwrap();
+    let desc_end = match find_endpoint(&device.configuration, &desc_int, Pipe::Interrupt, true) {
+        Some(desc_end) => desc_end,
+        None => {
+            syslog_warning!("USB hub {}: No status change endpoint", address);
+            device.driver = None;
+            return;
+        }
+    };
+
+    let super_speed = device.speed == Speed::Super;
+    if super_speed {
+        let depth = depth(hci, device.hub);
+        if let Err(err) = (*hci).control_out(address, &Setup::set_hub_depth(depth), &[]) {
+            syslog_warning!("USB hub {}: Failed to set depth: {}", address, err);
+        }
+    }
+
+    let mut desc_hub = [0; 16];
+    let descriptor_type = if super_speed { DESC_SS_HUB } else { DESC_HUB };
+    match (*hci).control_in(address, &Setup::get_hub_descriptor(descriptor_type, desc_hub.len() as u16), &mut desc_hub) {
+        Ok(count) if count >= 7 => (),
+        Ok(_) => {
+            syslog_warning!("USB hub {}: Short hub descriptor", address);
+            device.driver = None;
+            return;
+        },
+        Err(err) => {
+            syslog_warning!("USB hub {}: Failed to read hub descriptor: {}", address, err);
+            device.driver = None;
+            return;
+        }
+    }
+
+    let ports = desc_hub[2];
+    let characteristics = desc_hub[3] as u16 | (desc_hub[4] as u16) << 8;
+    let power_good = desc_hub[5] as i32 * 2;
+    // Only a high speed hub has a transaction translator, with a think time in units of 8 bit times
+    let think_time = if device.speed == Speed::High { (characteristics >> 5) as u8 & 0b11 } else { 0 };
+
+    if let Err(err) = (*hci).set_hub(address, ports, think_time) {
+        syslog_warning!("USB hub {}: Failed to set up hub: {}", address, err);
+        device.driver = None;
+        return;
+    }
+
+    syslog_info!("USB hub {}: {} ports", address, ports);
+
+    for port in 1..ports as u16 + 1 {
+        let _ = (*hci).control_out(address, &Setup::set_port_feature(port as u8, PORT_POWER), &[]);
+    }
+    delay(power_good);
+
+    let pipe = match (*hci).interrupt_open(address, &desc_end) {
+        Ok(pipe) => pipe,
+        Err(err) => {
+            syslog_warning!("USB hub {}: Failed to open status change pipe: {}", address, err);
+            device.driver = None;
+            return;
+        }
+    };
+    let bitmap_len = cmp::min((ports as usize + 8) / 8, cmp::max(1, desc_end.max_packet_size() as usize));
+
+    Context::spawn("kusb_hub".into(),
+                   box move || {
+        let hub = Hub {
+            hci: hci,
+            address: address,
+            ports: ports,
+            super_speed: super_speed,
+        };
+
+        // Devices that were attached when the ports were powered show as connection changes
+        for port in 1..hub.ports as u16 + 1 {
+            hub.port_change(port as u8);
+        }
+
+        while usb_attached(hci, address, generation) {
+            // Bit 0 is the hub, the bits after it its ports
+            let mut bitmap = [0; 32];
+            if let Some(count) = (*hci).interrupt_report(&pipe, &mut bitmap[.. cmp::min(bitmap_len, 32)]) {
+                for port in 1..hub.ports as usize + 1 {
+                    if port / 8 < count && bitmap[port / 8] & 1 << (port % 8) != 0 {
+                        hub.port_change(port as u8);
+                    }
+                }
+            }
+
+            timekeeping::sleep(50, "USB hub sleep");
+        }
+
+        (*hci).interrupt_close(&pipe);
+
+        syslog_info!("USB hub {}: Removed", address);
+    });
+}
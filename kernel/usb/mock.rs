@@ -0,0 +1,93 @@
+use collections::vec::Vec;
+use collections::vec_deque::VecDeque;
+
+use system::error::{Error, Result, ETIMEDOUT};
+
+use super::{Packet, Pipe, UsbHc};
+
+/// A stage of a transfer given to the mock, with the request of a setup stage and the length of a data stage
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MockStage {
+    Setup(u8),
+    In(usize),
+    Out(usize),
+}
+
+/// A transfer given to the mock
+pub struct MockTransfer {
+    pub address: u8,
+    pub endpoint: u8,
+    pub pipe: Pipe,
+    pub stages: Vec<MockStage>,
+}
+
+/// A host controller without hardware, which answers transfers from a script
+///
+/// Each transfer takes the next reply: the bytes the device sends in its IN data stage, or the error the
+/// controller would report. Once the replies run out, transfers time out as with a device that does not
+/// answer. Everything built on `UsbHc` can be tested with it, from the control transfers up.
+pub struct MockHc {
+    pub replies: VecDeque<Result<Vec<u8>>>,
+    /// The transfers given so far, in order
+    pub transfers: Vec<MockTransfer>,
+}
+
+impl MockHc {
+    pub fn new() -> MockHc {
+        MockHc {
+            replies: VecDeque::new(),
+            transfers: Vec::new(),
+        }
+    }
+
+    /// Answer the next transfer with `data`
+    pub fn reply(&mut self, data: &[u8]) {
+        self.replies.push_back(Ok(data.to_vec()));
+    }
+
+    /// Fail the next transfer with `errno`
+    pub fn fail(&mut self, errno: isize) {
+        self.replies.push_back(Err(Error::new(errno)));
+    }
+}
+
+impl UsbHc for MockHc {
+    /// Moves what fits of the reply into the first IN data stage that has room, OUT data stages take all of theirs
+    fn msg(&mut self, address: u8, endpoint: u8, pipe: Pipe, msgs: &[Packet]) -> Result<usize> {
+        self.transfers.push(MockTransfer {
+            address: address,
+            endpoint: endpoint,
+            pipe: pipe,
+            stages: msgs.iter().map(|msg| match *msg {
+                Packet::Setup(setup) => MockStage::Setup(setup.request),
+                Packet::In(ref data) => MockStage::In(data.len()),
+                Packet::Out(ref data) => MockStage::Out(data.len()),
+            }).collect(),
+        });
+
+        let reply = match self.replies.pop_front() {
+            Some(reply) => try!(reply),
+            None => return Err(Error::new(ETIMEDOUT)),
+        };
+
+        let mut count = 0;
+        let mut replied = false;
+        for msg in msgs.iter() {
+            match *msg {
+                // The stages are shared like those given to a real controller, which writes them by DMA
+                Packet::In(ref data) if ! data.is_empty() && ! replied => {
+                    let ptr = data.as_ptr() as *mut u8;
+                    for (i, &byte) in reply.iter().take(data.len()).enumerate() {
+                        unsafe { *ptr.offset(i as isize) = byte };
+                        count += 1;
+                    }
+                    replied = true;
+                },
+                Packet::Out(data) => count += data.len(),
+                _ => (),
+            }
+        }
+
+        Ok(count)
+    }
+}
@@ -1,12 +1,31 @@
-pub use self::hci::Hci;
+use arch::context::context_switch;
+use arch::memory::LOGICAL_OFFSET;
+use arch::timekeeping;
+
+use common::time::{self, Duration};
+
+use env::log::InterruptGuard;
+
+pub use self::device::UsbDevice;
+pub use self::hci::UsbHc;
 pub use self::setup::Setup;
 
+pub mod acm;
+pub mod audio;
 pub mod desc;
+pub mod device;
 pub mod ehci;
 pub mod hci;
+pub mod hid;
+pub mod hub;
+pub mod mock;
+pub mod msd;
 pub mod ohci;
+pub mod pool;
+pub mod scheme;
 pub mod setup;
 pub mod uhci;
+pub mod watchdog;
 pub mod xhci;
 
 #[derive(Debug)]
@@ -16,10 +35,79 @@ pub enum Packet<'a> {
     Out(&'a [u8]),
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Pipe {
     Control,
     Interrupt,
     Isochronous,
     Bulk
 }
+
+/// The signalling speed of a device, detected on the port it is attached to
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Speed {
+    Low,
+    Full,
+    High,
+    Super,
+}
+
+/// A controller taken by `hold`, given back when dropped
+pub struct Held {
+    busy: *mut bool,
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        unsafe { *self.busy = false };
+    }
+}
+
+/// Take a controller by its `busy` flag, once the transfer or poll that has it is done
+///
+/// Transfers, pipe and stream polls and the watchdog reset all change the schedules, rings and pipes of
+/// the controller, so each holds it until it is done with them. The flag is in the boxed controller,
+/// which outlives whatever holds it.
+pub fn hold(busy: &mut bool) -> Held {
+    loop {
+        {
+            let _guard = InterruptGuard::new();
+            if ! *busy {
+                *busy = true;
+                return Held {
+                    busy: busy,
+                };
+            }
+        }
+        unsafe { context_switch() };
+    }
+}
+
+/// Wait `ms` milliseconds, for the delays the specification puts between port operations
+pub fn delay(ms: i32) {
+    timekeeping::sleep(ms as u64, "usb::delay");
+}
+
+/// The physical address of `ptr`, which may be logical, for a controller to transfer to or from
+pub fn physical(ptr: usize) -> u32 {
+    if ptr >= LOGICAL_OFFSET {
+        (ptr - LOGICAL_OFFSET) as u32
+    } else {
+        ptr as u32
+    }
+}
+
+/// Check `done` every millisecond for up to `ms` milliseconds, returning whether it became true
+///
+/// Every wait on a controller has a limit, so a stuck controller cannot hang the kernel. The limit is
+/// kept by the monotonic clock, as each delay may sleep longer than asked.
+pub fn wait_for<F: FnMut() -> bool>(ms: i32, mut done: F) -> bool {
+    let end = Duration::monotonic() + Duration::new((ms / 1000) as i64, ms % 1000 * time::NANOS_PER_MILLI);
+    while Duration::monotonic() < end {
+        if done() {
+            return true;
+        }
+        delay(1);
+    }
+    done()
+}
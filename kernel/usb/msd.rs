@@ -0,0 +1,475 @@
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+
+use arch::context::{context_switch, Context};
+use arch::timekeeping;
+
+use collections::string::{String, ToString};
+use collections::vec::Vec;
+
+use common::event::{HotplugEvent, HOTPLUG_DISK};
+use common::time::Duration;
+
+use core::cell::UnsafeCell;
+use core::{cmp, mem, slice, str};
+
+use disk::{media_error, Disk, DiskStats, DISK_RETRIES};
+use disk::cache::{BlockCache, CACHE_SECTORS};
+
+use system::error::{Error, Result, EINVAL, EIO, ENODATA, ENODEV, ENOMEDIUM, EPIPE};
+
+use super::{Packet, Pipe, Setup, UsbHc};
+use super::desc::{find_endpoint, find_interface};
+use super::device::{usb_attached, usb_claim};
+
+pub const MSD_CLASS: u8 = 8;
+pub const MSD_SUBCLASS_SCSI: u8 = 6;
+pub const MSD_PROTOCOL_BOT: u8 = 0x50;
+
+const CBW_SIGNATURE: u32 = 0x43425355;
+const CSW_SIGNATURE: u32 = 0x53425355;
+
+const CSW_PASSED: u8 = 0;
+const CSW_FAILED: u8 = 1;
+
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_REQUEST_SENSE: u8 = 0x03;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_READ_CAPACITY: u8 = 0x25;
+const SCSI_READ: u8 = 0x28;
+const SCSI_WRITE: u8 = 0x2A;
+const SCSI_SYNCHRONIZE_CACHE: u8 = 0x35;
+
+const SENSE_NOT_READY: u8 = 2;
+const SENSE_MEDIUM_ERROR: u8 = 3;
+
+/// The peripheral device type of a disk in the INQUIRY data
+const TYPE_DIRECT_ACCESS: u8 = 0;
+
+/// The most bytes one command moves, which keeps each transfer well inside its timeout
+const MAX_TRANSFER: usize = 32768;
+
+/// Command block wrapper, sent before every command
+#[repr(packed)]
+#[derive(Copy, Clone, Debug)]
+struct Cbw {
+    signature: u32,
+    tag: u32,
+    data_len: u32,
+    flags: u8,
+    lun: u8,
+    cb_len: u8,
+    cb: [u8; 16],
+}
+
+/// Command status wrapper, received after every command
+#[repr(packed)]
+#[derive(Copy, Clone, Debug, Default)]
+struct Csw {
+    signature: u32,
+    tag: u32,
+    residue: u32,
+    status: u8,
+}
+
+/// The bulk-only transport of a device, shared by the disks of its logical units
+struct Bot {
+    hci: *mut UsbHc,
+    address: u8,
+    generation: u64,
+    interface: u8,
+    bulk_in: u8,
+    bulk_out: u8,
+    tag: u32,
+    busy: bool,
+    removed: bool,
+}
+
+impl Bot {
+    /// Run the SCSI command `cb` on `lun`, moving `data` in or out
+    unsafe fn command(&mut self, lun: u8, cb: &[u8], data: Packet) -> Result<usize> {
+        // The stages of the commands of different logical units must not interleave
+        while self.busy {
+            context_switch();
+        }
+
+        // Disk requests fail at once when the device is pulled, before its driver has noticed
+        if self.removed || ! usb_attached(self.hci, self.address, self.generation) {
+            return Err(Error::new(ENODEV));
+        }
+        self.busy = true;
+
+        let mut result = self.bulk_only(lun, cb, data);
+        if let Ok((_, CSW_FAILED)) = result {
+            result = Err(self.sense(lun));
+        }
+
+        self.busy = false;
+
+        result.map(|(count, _)| count)
+    }
+
+    /// The CBW, data and CSW stages of one command, with the recovery the specification gives for each failure
+    unsafe fn bulk_only(&mut self, lun: u8, cb: &[u8], data: Packet) -> Result<(usize, u8)> {
+        let hci = &mut *self.hci;
+
+        let (data_len, flags, endpoint_address) = match data {
+            Packet::In(ref data) => (data.len(), 0x80, self.bulk_in),
+            Packet::Out(ref data) => (data.len(), 0, self.bulk_out),
+            Packet::Setup(_) => return Err(Error::new(EINVAL)),
+        };
+
+        self.tag = self.tag.wrapping_add(1);
+        let mut cbw = Cbw {
+            signature: CBW_SIGNATURE,
+            tag: self.tag,
+            data_len: data_len as u32,
+            flags: flags,
+            lun: lun,
+            cb_len: cmp::min(cb.len(), 16) as u8,
+            cb: [0; 16],
+        };
+        for (i, &byte) in cb.iter().take(16).enumerate() {
+            cbw.cb[i] = byte;
+        }
+
+        if let Err(err) = hci.bulk_out(self.address, self.bulk_out & 0xF,
+                                       slice::from_raw_parts(&cbw as *const Cbw as *const u8, mem::size_of::<Cbw>())) {
+            self.reset_recovery();
+            return Err(err);
+        }
+
+        let result = match data {
+            _ if data_len == 0 => Ok(0),
+            Packet::In(data) => hci.bulk_in(self.address, self.bulk_in & 0xF, data),
+            Packet::Out(data) => hci.bulk_out(self.address, self.bulk_out & 0xF, data),
+            Packet::Setup(_) => Ok(0),
+        };
+        let count = match result {
+            Ok(count) => count,
+            // A stalled data stage is still followed by the status
+            Err(ref err) if err.errno == EPIPE => {
+                let _ = hci.clear_halt(self.address, endpoint_address);
+                0
+            },
+            Err(err) => {
+                self.reset_recovery();
+                return Err(err);
+            }
+        };
+
+        let mut csw = Csw::default();
+        let mut result = self.csw(&mut csw);
+        if let Err(ref err) = result {
+            if err.errno == EPIPE {
+                let _ = hci.clear_halt(self.address, self.bulk_in);
+            }
+        }
+        if result.is_err() {
+            result = self.csw(&mut csw);
+        }
+
+        match result {
+            Ok(count) if count == mem::size_of::<Csw>() && csw.signature == CSW_SIGNATURE && csw.tag == self.tag => (),
+            Ok(_) => {
+                self.reset_recovery();
+                return Err(Error::new(EIO));
+            },
+            Err(err) => {
+                self.reset_recovery();
+                return Err(err);
+            },
+        }
+
+        match csw.status {
+            CSW_PASSED | CSW_FAILED => Ok((count, csw.status)),
+            _ => {
+                // A phase error, the device and host disagree on the command
+                self.reset_recovery();
+                Err(Error::new(EIO))
+            }
+        }
+    }
+
+    unsafe fn csw(&mut self, csw: &mut Csw) -> Result<usize> {
+        (*self.hci).bulk_in(self.address, self.bulk_in & 0xF,
+                            slice::from_raw_parts_mut(csw as *mut Csw as *mut u8, mem::size_of::<Csw>()))
+    }
+
+    /// The error a failed command on `lun` gives, from the sense data
+    unsafe fn sense(&mut self, lun: u8) -> Error {
+        let mut sense = [0; 18];
+        match self.bulk_only(lun, &[SCSI_REQUEST_SENSE, 0, 0, 0, sense.len() as u8, 0], Packet::In(&mut sense)) {
+            Ok((_, CSW_PASSED)) => match sense[2] & 0xF {
+                SENSE_NOT_READY => Error::new(ENOMEDIUM),
+                SENSE_MEDIUM_ERROR => Error::new(ENODATA),
+                key => {
+                    syslog_debug!("USB disk: LUN {} sense key {:X} ASC {:X} ASCQ {:X}", lun, key, sense[12], sense[13]);
+                    Error::new(EIO)
+                }
+            },
+            _ => Error::new(EIO),
+        }
+    }
+
+    /// Bring the device back to accepting commands after the transport broke down
+    unsafe fn reset_recovery(&mut self) {
+        let hci = &mut *self.hci;
+        let _ = hci.control_out(self.address, &Setup::bulk_only_reset(self.interface), &[]);
+        let _ = hci.clear_halt(self.address, self.bulk_in);
+        let _ = hci.clear_halt(self.address, self.bulk_out);
+    }
+}
+
+/// A logical unit of a USB mass storage device
+pub struct UsbDisk {
+    bot: Arc<UnsafeCell<Bot>>,
+    lun: u8,
+    name: String,
+    serial: String,
+    block_size: u64,
+    size: u64,
+    stats: DiskStats,
+    cache: BlockCache,
+}
+
+impl UsbDisk {
+    /// Identify `lun`, returning `None` while it has no medium
+    unsafe fn probe(bot: Arc<UnsafeCell<Bot>>, lun: u8, serial: &str) -> Result<Option<UsbDisk>> {
+        let mut inquiry = [0; 36];
+        try!((*bot.get()).command(lun, &[SCSI_INQUIRY, 0, 0, 0, inquiry.len() as u8, 0], Packet::In(&mut inquiry)));
+        if inquiry[0] & 0x1F != TYPE_DIRECT_ACCESS {
+            syslog_info!("USB disk: LUN {} has device type {:X}", lun, inquiry[0] & 0x1F);
+            return Err(Error::new(ENODEV));
+        }
+
+        // Any error may just be the unit attention after the medium changed, so ask again next time
+        if (*bot.get()).command(lun, &[SCSI_TEST_UNIT_READY, 0, 0, 0, 0, 0], Packet::Out(&[])).is_err() {
+            return Ok(None);
+        }
+
+        let mut capacity = [0; 8];
+        try!((*bot.get()).command(lun, &[SCSI_READ_CAPACITY, 0, 0, 0, 0, 0, 0, 0, 0, 0], Packet::In(&mut capacity)));
+        let last = (capacity[0] as u64) << 24 | (capacity[1] as u64) << 16 | (capacity[2] as u64) << 8 | capacity[3] as u64;
+        let block_size = (capacity[4] as u64) << 24 | (capacity[5] as u64) << 16 | (capacity[6] as u64) << 8 | capacity[7] as u64;
+        if block_size < 512 || block_size % 512 != 0 {
+            syslog_warning!("USB disk: LUN {} has {} byte blocks", lun, block_size);
+            return Err(Error::new(EINVAL));
+        }
+
+        let vendor = str::from_utf8(&inquiry[8..16]).unwrap_or("").trim();
+        let product = str::from_utf8(&inquiry[16..32]).unwrap_or("").trim();
+
+        Ok(Some(UsbDisk {
+            bot: bot,
+            lun: lun,
+            name: format!("USB {} {} LUN {}", vendor, product, lun),
+            serial: serial.to_string(),
+            block_size: block_size,
+            size: (last + 1) * block_size,
+            stats: DiskStats::default(),
+            cache: BlockCache::new(CACHE_SECTORS),
+        }))
+    }
+
+    /// Move the 512 byte sectors from `block` in or out of `buf`, in commands of at most `MAX_TRANSFER` bytes
+    unsafe fn rw(&mut self, block: u64, buf: usize, len: usize, write: bool) -> Result<usize> {
+        let offset = block * 512;
+        if offset % self.block_size != 0 || len as u64 % self.block_size != 0 {
+            return Err(Error::new(EINVAL));
+        }
+
+        let chunk = MAX_TRANSFER - MAX_TRANSFER % self.block_size as usize;
+        let mut done = 0;
+        while done < len {
+            let size = cmp::min(chunk, len - done);
+            let lba = (offset + done as u64) / self.block_size;
+            let blocks = size as u64 / self.block_size;
+
+            let opcode = if write { SCSI_WRITE } else { SCSI_READ };
+            let cb = [opcode, 0, (lba >> 24) as u8, (lba >> 16) as u8, (lba >> 8) as u8, lba as u8,
+                      0, (blocks >> 8) as u8, blocks as u8, 0];
+            let data = if write {
+                Packet::Out(slice::from_raw_parts((buf + done) as *const u8, size))
+            } else {
+                Packet::In(slice::from_raw_parts_mut((buf + done) as *mut u8, size))
+            };
+
+            let count = try!((*self.bot.get()).command(self.lun, &cb, data));
+            done += count;
+            if count < size {
+                break;
+            }
+        }
+
+        Ok(done)
+    }
+
+    fn request(&mut self, block: u64, buf: usize, len: usize, write: bool) -> Result<usize> {
+        let start = Duration::monotonic();
+
+        let mut result = unsafe { self.rw(block, buf, len, write) };
+        let mut retries = 0;
+        while media_error(&result) && retries < DISK_RETRIES {
+            retries += 1;
+            result = unsafe { self.rw(block, buf, len, write) };
+        }
+
+        self.stats.retries += retries as u64;
+        if media_error(&result) {
+            syslog_error!("{}: media error at {} after {} retries", self.name, block, retries);
+            self.stats.bad_sector(block);
+        }
+        self.stats.record(write, start, &result);
+
+        result
+    }
+}
+
+impl Disk for UsbDisk {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn on_irq(&mut self, _irq: u8) -> bool {
+        false
+    }
+
+    fn serial(&self) -> String {
+        self.serial.clone()
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn stats(&mut self) -> &mut DiskStats {
+        &mut self.stats
+    }
+
+    fn cache(&mut self) -> &mut BlockCache {
+        &mut self.cache
+    }
+
+    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        self.request(block, buffer.as_ptr() as usize, buffer.len() - buffer.len() % 512, false)
+    }
+
+    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        self.request(block, buffer.as_ptr() as usize, buffer.len() - buffer.len() % 512, true)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        // A count of zero synchronizes every block
+        unsafe { (*self.bot.get()).command(self.lun, &[SCSI_SYNCHRONIZE_CACHE, 0, 0, 0, 0, 0, 0, 0, 0, 0], Packet::Out(&[])) }
+            .map(|_| ())
+    }
+}
+
+/// Start the mass storage driver if the device at `address` of `hci` has a bulk-only SCSI interface
+///
+/// Its logical units are added as disks once they have a medium, and removed when the device is.
+pub unsafe fn mass_storage(hci: *mut UsbHc, address: u8) {
+    let device = match usb_claim("mass storage", |device| {
+        device.on(hci) && device.address == address
+            && find_interface(&device.configuration, MSD_CLASS, Some(MSD_SUBCLASS_SCSI), Some(MSD_PROTOCOL_BOT)).is_some()
+    }) {
+        Some(device) => device,
+        None => return,
+    };
+    let generation = device.generation;
+
+    let desc_int = find_interface(&device.configuration, MSD_CLASS, Some(MSD_SUBCLASS_SCSI), Some(MSD_PROTOCOL_BOT)).unwrap();
+    let (bulk_in, bulk_out) = match (find_endpoint(&device.configuration, &desc_int, Pipe::Bulk, true),
+                                     find_endpoint(&device.configuration, &desc_int, Pipe::Bulk, false)) {
+        (Some(bulk_in), Some(bulk_out)) => (bulk_in.address, bulk_out.address),
+        _ => {
+            syslog_warning!("USB disk: Missing bulk endpoints");
+            device.driver = None;
+            return;
+        }
+    };
+
+    // Devices with a single logical unit may stall the request
+    let mut max_lun = [0];
+    let luns = match (*hci).control_in(address, &Setup::get_max_lun(desc_int.number), &mut max_lun) {
+        Ok(1) => cmp::min(max_lun[0], 15) + 1,
+        _ => 1,
+    };
+
+    let serial = (*hci).string(address, device.descriptor.serial_string).unwrap_or(String::new());
+
+    let bot = Arc::new(UnsafeCell::new(Bot {
+        hci: hci,
+        address: address,
+        generation: generation,
+        interface: desc_int.number,
+        bulk_in: bulk_in,
+        bulk_out: bulk_out,
+        tag: 0,
+        busy: false,
+        removed: false,
+    }));
+
+    Context::spawn("kusb_msd".into(),
+                   box move || {
+        syslog_info!("Starting USB mass storage driver with {} LUNs", luns);
+
+        // The disk of each logical unit, once it has one, or whether it is of no use
+        let mut units: Vec<Option<Arc<UnsafeCell<Box<Disk>>>>> = (0..luns).map(|_| None).collect();
+        let mut unusable = vec![false; luns as usize];
+
+        while usb_attached(hci, address, generation) {
+            for lun in 0..luns {
+                if units[lun as usize].is_some() || unusable[lun as usize] {
+                    continue;
+                }
+
+                match UsbDisk::probe(bot.clone(), lun, &serial) {
+                    Ok(Some(disk)) => {
+                        let arc = Arc::new(UnsafeCell::new(box disk as Box<Disk>));
+
+                        let disks = &mut *::env().disks.get();
+                        disks.push(Some(arc.clone()));
+
+                        syslog_info!("USB disk: LUN {} attached as disk:/{}", lun, disks.len() - 1);
+                        ::env().events.send(HotplugEvent {
+                            kind: HOTPLUG_DISK,
+                            index: (disks.len() - 1) as i64,
+                            added: true,
+                        }.to_event(), "usb mass_storage attach");
+
+                        units[lun as usize] = Some(arc);
+                    },
+                    Ok(None) => (),
+                    Err(err) => {
+                        syslog_warning!("USB disk: LUN {} could not be used: {}", lun, err);
+                        unusable[lun as usize] = true;
+                    }
+                }
+            }
+
+            timekeeping::sleep(1000, "USB mass storage sleep");
+        }
+
+        // Requests already issued against the disks fail with ENODEV
+        (*bot.get()).removed = true;
+
+        for unit in units.iter() {
+            if let Some(ref arc) = *unit {
+                let disks = &mut *::env().disks.get();
+                if let Some(index) = disks.iter().position(|disk| disk.as_ref().map_or(false, |disk| disk.get() == arc.get())) {
+                    // The slot is kept, so the disks after it keep their numbers
+                    disks[index] = None;
+
+                    syslog_info!("USB disk: detached disk:/{}", index);
+                    ::env().events.send(HotplugEvent {
+                        kind: HOTPLUG_DISK,
+                        index: index as i64,
+                        added: false,
+                    }.to_event(), "usb mass_storage detach");
+                }
+            }
+        }
+    });
+}
@@ -1,35 +1,48 @@
 use alloc::boxed::Box;
 
+use arch::context::{context_switch, Context};
+use arch::memory::Memory;
+
 use collections::vec::Vec;
 
-use core::intrinsics::volatile_load;
-use core::mem;
+use common::time::{self, Duration};
+
+use core::intrinsics::{volatile_load, volatile_store};
+use core::{cmp, mem};
 
 use drivers::io::{Io, Mmio};
 use drivers::pci::config::PciConfig;
 
-use arch::context::context_switch;
-
 use fs::KScheme;
 
-use super::{Hci, Packet, Pipe, Setup};
+use system::error::{Error, Result, EIO, ENODEV, EPIPE, ETIMEDOUT};
+
+use super::{delay, physical, wait_for, UsbHc, Packet, Pipe, Setup, Speed};
+use super::desc::EndpointDescriptor;
+use super::device::{usb_cancelled, usb_enumerate_begin, usb_enumerate_end};
+use super::pool::Descriptors;
+
+/// Milliseconds a control or bulk transfer may take before it is abandoned
+const TRANSFER_TIMEOUT: i32 = 1000;
+/// Milliseconds an interrupt transfer is polled, a device with nothing to report does not answer
+const INTERRUPT_TIMEOUT: i32 = 20;
+/// Bytes in one TD, which can cross one page boundary
+const TD_MAX: usize = 4096;
 
 #[repr(packed)]
-#[derive(Copy, Clone, Debug, Default)]
 struct Gtd {
-    flags: u32,
-    buffer: u32,
-    next: u32,
-    end: u32,
+    flags: Mmio<u32>,
+    buffer: Mmio<u32>,
+    next: Mmio<u32>,
+    end: Mmio<u32>,
 }
 
 #[repr(packed)]
-#[derive(Copy, Clone, Debug, Default)]
 struct Ed {
-    flags: u32,
-    tail: u32,
-    head: u32,
-    next: u32,
+    flags: Mmio<u32>,
+    tail: Mmio<u32>,
+    head: Mmio<u32>,
+    next: Mmio<u32>,
 }
 
 const CTRL_CBSR: u32 = 0b11;
@@ -38,6 +51,7 @@ const CTRL_IE: u32 = 1 << 3;
 const CTRL_CLE: u32 = 1 << 4;
 const CTRL_BLE: u32 = 1 << 5;
 const CTRL_HCFS: u32 = 0b11 << 6;
+const CTRL_HCFS_OPERATIONAL: u32 = 0b10 << 6;
 const CTRL_IR: u32 = 1 << 8;
 const CTRL_RWC: u32 = 1 << 9;
 const CTRL_RWE: u32 = 1 << 10;
@@ -47,10 +61,20 @@ const CMD_STS_CLF: u32 = 1 << 1;
 const CMD_STS_BLF: u32 = 1 << 2;
 const CMD_STS_OCR: u32 = 1 << 3;
 
+const INT_WDH: u32 = 1 << 1;
+const INT_SF: u32 = 1 << 2;
+const INT_MIE: u32 = 1 << 31;
+
+const RH_DESC_A_PSM: u32 = 1 << 8;
+const RH_DESC_A_NPS: u32 = 1 << 9;
+const RH_STS_LPSC: u32 = 1 << 16;
+
 const PORT_STS_CCS: u32 = 1;
 const PORT_STS_PES: u32 = 1 << 1;
 const PORT_STS_PSS: u32 = 1 << 2;
 const PORT_STS_POCI: u32 = 1 << 3;
+/// Written, starts a reset
+const PORT_STS_PRS: u32 = 1 << 4;
 const PORT_STS_PPS: u32 = 1 << 8;
 const PORT_STS_LSDA: u32 = 1 << 9;
 const PORT_STS_CSC: u32 = 1 << 16;
@@ -59,6 +83,26 @@ const PORT_STS_PSSC: u32 = 1 << 18;
 const PORT_STS_OCIC: u32 = 1 << 19;
 const PORT_STS_PRSC: u32 = 1 << 20;
 
+const ED_LOW_SPEED: u32 = 1 << 13;
+const ED_HALTED: u32 = 1;
+const ED_TOGGLE_CARRY: u32 = 1 << 1;
+
+const TD_ROUNDING: u32 = 1 << 18;
+const TD_DP_SETUP: u32 = 0b00 << 19;
+const TD_DP_OUT: u32 = 0b01 << 19;
+const TD_DP_IN: u32 = 0b10 << 19;
+/// Interrupt once the TD is on the done queue, without delay
+const TD_DI_NOW: u32 = 0 << 21;
+/// The toggle comes from the ED
+const TD_T_CARRY: u32 = 0b00 << 24;
+const TD_T_DATA0: u32 = 0b10 << 24;
+const TD_T_DATA1: u32 = 0b11 << 24;
+const TD_CC_NOT_ACCESSED: u32 = 0b1111 << 28;
+
+const CC_NO_ERROR: u32 = 0;
+const CC_STALL: u32 = 4;
+const CC_DATA_UNDERRUN: u32 = 9;
+
 #[repr(packed)]
 pub struct OhciRegs {
     pub revision: Mmio<u32>,
@@ -94,18 +138,42 @@ pub struct OhciHcca {
     pub reserved: [u8; 116],
 }
 
+/// The state of an address, for building its EDs
+#[derive(Copy, Clone)]
+struct OhciDevice {
+    low_speed: bool,
+    max_packet_sizes: [u16; 16],
+    /// The next data toggle of each endpoint, OUT in the low 16 bits and IN in the high 16
+    toggles: u32,
+}
+
 pub struct Ohci {
     pub regs: &'static mut OhciRegs,
-    pub hcca: Box<OhciHcca>,
+    pub hcca: Memory<OhciHcca>,
     pub irq: u8,
+    /// TDs the controller has put on the done queue, by physical address
+    retired: Vec<u32>,
+    /// Set while a transfer is in the control or bulk list
+    busy: bool,
+    devices: [OhciDevice; 128],
 }
 
 impl KScheme for Ohci {
-    fn on_irq(&mut self, irq: u8) {
-        if irq == self.irq {
-            // d("OHCI IRQ\n");
+    fn on_irq(&mut self, irq: u8) -> bool {
+        if irq == self.irq && self.regs.int_sts.readf(INT_WDH) {
+            self.reap();
+            true
+        } else {
+            false
         }
     }
+
+    /// Put the controller in the reset state, where it processes no lists
+    fn power_off(&mut self) {
+        self.regs.int_dis.write(INT_MIE);
+        let control = self.regs.control.read();
+        self.regs.control.write(control & ! CTRL_HCFS);
+    }
 }
 
 impl Ohci {
@@ -117,14 +185,15 @@ impl Ohci {
 
         let mut module = box Ohci {
             regs: regs,
-            hcca: box OhciHcca {
-                interrupt_table: [0; 32],
-                frame_number: 0,
-                padding: 0,
-                done_head: 0,
-                reserved: [0; 116],
-            },
-            irq: pci.read(0x3C) as u8 & 0xF,
+            hcca: Memory::new_aligned(1, 256).unwrap(),
+            irq: pci.irq(),
+            retired: Vec::new(),
+            busy: false,
+            devices: [OhciDevice {
+                low_speed: false,
+                max_packet_sizes: [8; 16],
+                toggles: 0,
+            }; 128],
         };
 
         module.init();
@@ -135,137 +204,332 @@ impl Ohci {
     pub unsafe fn init(&mut self) {
         syslog_info!(" + OHCI on: {:X}, IRQ: {:X}", (self.regs as *mut OhciRegs) as usize, self.irq);
 
-        /*
-        self.regs.hcca.write((&*self.hcca as *const OhciHcca) as u32);
+        // The resets and port delays need to sleep, so they are done once the scheduler runs
+        let this = self as *mut Ohci;
+        Context::spawn("kohci".into(), box move || {
+            if (*this).reset() {
+                loop {
+                    (*this).port_changes();
+                    delay(250);
+                }
+            }
+        });
+    }
 
-        debugln!("Enable: {:X}", self.regs.control.read());
-        loop {
-            let ctrl = self.regs.control.read();
-            let desired_ctrl = (ctrl & (0xFFFFFFFF - CTRL_HCFS)) | 0b10 << 6;
-            if ctrl != desired_ctrl {
-                self.regs.control.write(desired_ctrl);
+    /// Take the controller from the firmware, reset it and make it operational with empty lists
+    unsafe fn reset(&mut self) -> bool {
+        // Firmware using the controller from SMM gives it up when asked for ownership
+        if self.regs.control.readf(CTRL_IR) {
+            self.regs.cmd_sts.write(CMD_STS_OCR);
+            let released = wait_for(1000, || ! self.regs.control.readf(CTRL_IR));
+            if released {
+                syslog_info!("OHCI: Ownership taken from firmware");
             } else {
-                break;
+                syslog_warning!("OHCI: Firmware did not give up ownership, resetting anyway");
+                self.regs.control.writef(CTRL_IR, false);
             }
         }
 
-        debugln!("CTRL: {:X} CMDSTS: {:X} HCCA: {:X}", self.regs.control.read(), self.regs.cmd_sts.read(), self.regs.hcca.read());
-
-        let ndp = self.regs.rh_desc_a.read() & 0xF;
-        for i in 0..ndp as usize {
-            debugln!("Port {}: {:X}", i, self.regs.port_sts[i].read());
+        // The frame interval is lost in the reset
+        let fm_interval = match self.regs.fm_interval.read() & 0x3FFF {
+            0 => 0x2EDF,
+            fm_interval => fm_interval,
+        };
+        self.regs.cmd_sts.write(CMD_STS_HCR);
+        let reset = wait_for(10, || ! self.regs.cmd_sts.readf(CMD_STS_HCR));
+        if ! reset {
+            syslog_warning!("OHCI: Reset timed out");
+            return false;
+        }
 
-            if self.regs.port_sts[i].readf(PORT_STS_CCS) {
-                debugln!("  Device Found");
+        {
+            let hcca = &mut self.hcca[0];
+            for i in 0..hcca.interrupt_table.len() {
+                hcca.interrupt_table[i] = 0;
+            }
+            hcca.frame_number = 0;
+            hcca.done_head = 0;
+        }
 
-                while ! self.regs.port_sts[i].readf(PORT_STS_PES) {
-                    self.regs.port_sts[i].writef(PORT_STS_PES, true);
+        self.regs.hcca.write(physical(self.hcca.address()));
+        self.regs.control_head.write(0);
+        self.regs.bulk_head.write(0);
+
+        // The largest packet that fits the frame after its overhead, and periodic work in 90% of it
+        let fs_max = ((fm_interval - 210) * 6 / 7) << 16;
+        let toggle = (self.regs.fm_interval.read() & 1 << 31) ^ 1 << 31;
+        self.regs.fm_interval.write(toggle | fs_max | fm_interval);
+        self.regs.periodic_start.write(fm_interval * 9 / 10);
+        self.regs.ls_thresh.write(0x628);
+
+        self.regs.int_sts.write(0xFFFFFFFF);
+        self.regs.int_en.write(INT_WDH | INT_MIE);
+        self.regs.control.write(CTRL_HCFS_OPERATIONAL | CTRL_CBSR);
+
+        // Power the ports, globally or one by one, and wait until the power is good
+        let rh_desc_a = self.regs.rh_desc_a.read();
+        if rh_desc_a & RH_DESC_A_NPS != RH_DESC_A_NPS {
+            self.regs.rh_sts.write(RH_STS_LPSC);
+            if rh_desc_a & RH_DESC_A_PSM == RH_DESC_A_PSM {
+                for i in 0..self.ports() {
+                    self.regs.port_sts[i].write(PORT_STS_PPS);
                 }
-
-                self.device(i as u8 + 1);
             }
         }
-        */
+        delay(cmp::max(20, (rh_desc_a >> 24) as i32 * 2));
+
+        true
     }
-}
 
+    fn ports(&self) -> usize {
+        cmp::min(15, (self.regs.rh_desc_a.read() & 0xFF) as usize)
+    }
 
-impl Hci for Ohci {
-    fn msg(&mut self, address: u8, endpoint: u8, _pipe: Pipe, msgs: &[Packet]) -> usize {
-        let mut tds = Vec::new();
-        for msg in msgs.iter() {
-            match *msg {
-                Packet::Setup(setup) => tds.push(Gtd {
-                    flags: 0b1111 << 28 | 0b00 << 19 | 1 << 18,
-                    buffer: (setup as *const Setup) as u32,
-                    next: 0,
-                    end: (setup as *const Setup) as u32 + mem::size_of::<Setup>() as u32 - 1
-                }),
-                Packet::In(ref data) => tds.push(Gtd {
-                    flags: 0b1111 << 28 | 0b10 << 19 | 1 << 18,
-                    buffer: if data.is_empty() {
-                        0
-                    } else {
-                        data.as_ptr() as u32
-                    },
-                    next: 0,
-                    end: if data.is_empty() {
-                        0
-                    } else {
-                        data.as_ptr() as u32 + data.len() as u32 - 1
-                    }
-                }),
-                Packet::Out(ref data) => tds.push(Gtd {
-                    flags: 0b1111 << 28 | 0b01 << 19 | 1 << 18,
-                    buffer: if data.is_empty() {
-                        0
-                    } else {
-                        data.as_ptr() as u32
-                    },
-                    next: 0,
-                    end: if data.is_empty() {
-                        0
-                    } else {
-                        data.as_ptr() as u32 + data.len() as u32 - 1
+    /// Collect the TDs on the done queue
+    fn reap(&mut self) {
+        let hcca = &mut self.hcca[0] as *mut OhciHcca;
+        let mut td = unsafe { volatile_load(&(*hcca).done_head) } & ! 0xF;
+        unsafe { volatile_store(&mut (*hcca).done_head, 0) };
+        self.regs.int_sts.write(INT_WDH);
+
+        while td != 0 {
+            self.retired.push(td);
+            td = unsafe { (*(td as *const Gtd)).next.read() } & ! 0xF;
+        }
+    }
+
+    /// Handle connects and disconnects on the root ports
+    ///
+    /// Port status bits have a different meaning when written, so only the command or change bit is written.
+    unsafe fn port_changes(&mut self) {
+        for i in 0..self.ports() {
+            let status = self.regs.port_sts[i].read();
+            if status & PORT_STS_CSC != PORT_STS_CSC {
+                continue;
+            }
+            self.regs.port_sts[i].write(PORT_STS_CSC);
+
+            self.port_disconnected(i as u8 + 1);
+            if status & PORT_STS_CCS == PORT_STS_CCS {
+                // Let the connection settle before the reset
+                delay(100);
+                usb_enumerate_begin();
+                if let Some(speed) = self.reset_port(i) {
+                    syslog_info!("OHCI: {:?} speed device on port {}", speed, i + 1);
+                    if let Err(err) = self.port_connected(i as u8 + 1, speed) {
+                        syslog_warning!("OHCI: Failed to enumerate port {}: {}", i + 1, err);
                     }
-                })
+                }
+                usb_enumerate_end();
+            } else {
+                syslog_info!("OHCI: Device removed from port {}", i + 1);
             }
         }
+    }
 
-        let mut count = 0;
+    /// Reset port `i`, which enables it, returning the speed of its device
+    unsafe fn reset_port(&mut self, i: usize) -> Option<Speed> {
+        self.regs.port_sts[i].write(PORT_STS_PRS);
+        let reset = wait_for(50, || self.regs.port_sts[i].readf(PORT_STS_PRSC));
+        if ! reset {
+            syslog_warning!("OHCI: Reset of port {} timed out", i + 1);
+            return None;
+        }
+        self.regs.port_sts[i].write(PORT_STS_PRSC | PORT_STS_PESC);
+        // The device is given 10 ms to recover from the reset
+        delay(10);
 
-        if ! tds.is_empty() {
-            for i in 0..tds.len() - 1 {
-                tds[i].next = (&tds[i + 1] as *const Gtd) as u32;
-            }
+        let status = self.regs.port_sts[i].read();
+        if status & (PORT_STS_CCS | PORT_STS_PES) != PORT_STS_CCS | PORT_STS_PES {
+            return None;
+        }
 
-            let ed = box Ed {
-                flags: 8 << 16 | (endpoint as u32) << 7 | address as u32,
-                tail: (tds.last().unwrap() as *const Gtd) as u32 + mem::size_of::<Gtd>() as u32,
-                head: (tds.first().unwrap() as *const Gtd) as u32,
-                next: 0
-            };
+        Some(if status & PORT_STS_LSDA == PORT_STS_LSDA {
+            Speed::Low
+        } else {
+            Speed::Full
+        })
+    }
 
-            //debugln!("ED: {:X}, FLG: {:X}, TAIL: {:X}, HEAD: {:X}, NEXT: {:X}", (&*ed as *const Ed) as usize, ed.flags, ed.tail, ed.head, ed.next);
+    /// Run one stage of a transfer through an ED on the control or bulk list
+    ///
+    /// Returns the bytes transferred and the toggle the ED was left with.
+    unsafe fn transfer(&mut self, address: u8, endpoint: u8, pipe: Pipe, dp: u32, toggle: u32, carry: bool,
+                       ptr: usize, len: usize, timeout: i32) -> Result<(usize, bool)> {
+        let device = self.devices[address as usize & 0x7F];
+        let max_packet_size = device.max_packet_sizes[endpoint as usize & 0xF] as u32;
+
+        // The ED stops at the last TD, which is never run
+        let count = cmp::max(1, (len + TD_MAX - 1) / TD_MAX);
+        let mut tds = try!(Descriptors::<Gtd>::new(count + 1, 16));
+        for i in 0..count + 1 {
+            let size = cmp::min(TD_MAX, len - cmp::min(len, i * TD_MAX));
+            let buffer = physical(ptr + i * TD_MAX);
+
+            // Without rounding a short packet halts the ED, so the TDs after it are not waited for
+            let rounding = if i + 1 == count { TD_ROUNDING } else { 0 };
+            let toggle = if i == 0 { toggle } else { TD_T_CARRY };
+            tds[i].flags.write(TD_CC_NOT_ACCESSED | toggle | TD_DI_NOW | dp | rounding);
+            tds[i].buffer.write(if size == 0 || i == count { 0 } else { buffer });
+            tds[i].next.write(physical(tds.address() + (i + 1) * mem::size_of::<Gtd>()));
+            tds[i].end.write(if size == 0 || i == count { 0 } else { buffer + size as u32 - 1 });
+        }
+        tds[count].next.write(0);
+
+        let mut ed = try!(Descriptors::<Ed>::new(1, 16));
+        let speed = if device.low_speed { ED_LOW_SPEED } else { 0 };
+        ed[0].flags.write(max_packet_size << 16 | speed | (endpoint as u32 & 0xF) << 7 | address as u32 & 0x7F);
+        ed[0].tail.write(physical(tds.address() + count * mem::size_of::<Gtd>()));
+        ed[0].head.write(physical(tds.address()) | if carry { ED_TOGGLE_CARRY } else { 0 });
+        ed[0].next.write(0);
+
+        let (list_enable, list_filled) = match pipe {
+            Pipe::Control => {
+                self.regs.control_head.write(physical(ed.address()));
+                (CTRL_CLE, CMD_STS_CLF)
+            },
+            _ => {
+                self.regs.bulk_head.write(physical(ed.address()));
+                (CTRL_BLE, CMD_STS_BLF)
+            },
+        };
+        self.regs.control.writef(list_enable, true);
+        self.regs.cmd_sts.write(list_filled);
+
+        let end = Duration::monotonic() + Duration::new(0, timeout * time::NANOS_PER_MILLI);
+        let mut result = Ok(0);
+        let mut i = 0;
+        while i < count {
+            // Completions arrive on the done queue, the condition code shows a TD that was taken off early
+            let td = physical(tds.address() + i * mem::size_of::<Gtd>());
+            self.reap();
+            if let Some(j) = self.retired.iter().position(|&retired| retired == td) {
+                self.retired.remove(j);
+            } else if ed[0].head.read() & ED_HALTED != ED_HALTED {
+                if usb_cancelled(self as *mut Ohci as *mut UsbHc, address) {
+                    result = Err(Error::new(ENODEV));
+                    break;
+                }
+                if Duration::monotonic() > end {
+                    result = Err(Error::new(ETIMEDOUT));
+                    break;
+                }
+                context_switch();
+                continue;
+            }
 
-            while ! self.regs.control.readf(CTRL_CLE) {
-                self.regs.control.writef(CTRL_CLE, true);
+            let flags = tds[i].flags.read();
+            let size = cmp::min(TD_MAX, len - cmp::min(len, i * TD_MAX));
+            let remaining = match tds[i].buffer.read() {
+                0 => 0,
+                buffer => (physical(ptr + i * TD_MAX) + size as u32 - buffer) as usize,
+            };
+            match flags >> 28 {
+                CC_NO_ERROR | CC_DATA_UNDERRUN => if let Ok(ref mut total) = result {
+                    *total += size - cmp::min(size, remaining);
+                },
+                CC_STALL => result = Err(Error::new(EPIPE)),
+                0b1111 | 0b1110 => (),
+                code => {
+                    syslog_debug!("OHCI: Transfer failed: {}", code);
+                    result = Err(Error::new(EIO));
+                },
             }
-            self.regs.control_head.write((&*ed as *const Ed) as u32);
-            while ! self.regs.cmd_sts.readf(CMD_STS_CLF) {
-                self.regs.cmd_sts.writef(CMD_STS_CLF, true);
+
+            // A short packet or an error halts the ED
+            if result.is_err() || flags >> 28 == CC_DATA_UNDERRUN || ed[0].head.read() & ED_HALTED == ED_HALTED {
+                break;
             }
+            i += 1;
+        }
+
+        // The controller may still hold the ED until the next frame starts
+        self.regs.control.writef(list_enable, false);
+        self.regs.int_sts.write(INT_SF);
+        wait_for(2, || self.regs.int_sts.readf(INT_SF));
+        match pipe {
+            Pipe::Control => self.regs.control_head.write(0),
+            _ => self.regs.bulk_head.write(0),
+        }
+        self.retired.clear();
 
-            for td in tds.iter() {
-                //debugln!("  TD: {:X}, FLG: {:X}, BUF: {:X}, NEXT: {:X}, END: {:X}", (td as *const Gtd) as usize, td.flags, td.buffer, td.next, td.end);
+        let carry = ed[0].head.read() & ED_TOGGLE_CARRY == ED_TOGGLE_CARRY;
+        result.map(|count| (count, carry))
+    }
+}
 
-                while unsafe { volatile_load(td as *const Gtd).flags } & 0b1111 << 28 == 0b1111 << 28 {
-                    unsafe { context_switch() };
-                }
+impl UsbHc for Ohci {
+    fn msg(&mut self, address: u8, endpoint: u8, pipe: Pipe, msgs: &[Packet]) -> Result<usize> {
+        while self.busy {
+            unsafe { context_switch() };
+        }
+        self.busy = true;
 
-                let condition = (unsafe { volatile_load(td as *const Gtd).flags } & 0b1111 << 28) >> 28;
-                if condition != 0 {
-                    //debugln!("  /TD: {:X}, FLG: {:X}, BUF: {:X}, NEXT: {:X}, END: {:X}", (td as *const Gtd) as usize, td.flags, td.buffer, td.next, td.end);
-                    debugln!("Condition: {:X}", condition);
+        let timeout = match pipe {
+            Pipe::Interrupt => INTERRUPT_TIMEOUT,
+            _ => TRANSFER_TIMEOUT,
+        };
+
+        let mut result = Ok(0);
+        for msg in msgs.iter() {
+            let (dp, ptr, len, toggle_bit) = match *msg {
+                Packet::Setup(setup) => (TD_DP_SETUP, (setup as *const Setup) as usize, mem::size_of::<Setup>(), endpoint as u32),
+                Packet::In(ref data) => (TD_DP_IN, data.as_ptr() as usize, data.len(), endpoint as u32 + 16),
+                Packet::Out(ref data) => (TD_DP_OUT, data.as_ptr() as usize, data.len(), endpoint as u32),
+            };
+
+            // Control transfers set the toggle of each stage, other pipes carry it in the ED
+            let carry = self.devices[address as usize & 0x7F].toggles & 1 << toggle_bit == 1 << toggle_bit;
+            let toggle = match (pipe, msg) {
+                (Pipe::Control, &Packet::Setup(_)) => TD_T_DATA0,
+                (Pipe::Control, _) => TD_T_DATA1,
+                _ => TD_T_CARRY,
+            };
+
+            match unsafe { self.transfer(address, endpoint, pipe, dp, toggle, carry, ptr, len, timeout) } {
+                Ok((count, carry)) => {
+                    if let Pipe::Control = pipe {} else {
+                        let device = &mut self.devices[address as usize & 0x7F];
+                        if carry {
+                            device.toggles |= 1 << toggle_bit;
+                        } else {
+                            device.toggles &= ! (1 << toggle_bit);
+                        }
+                    }
+
+                    if let Packet::Setup(_) = *msg {} else {
+                        if let Ok(ref mut total) = result {
+                            *total += count;
+                        }
+                    }
+                },
+                Err(err) => {
+                    result = Err(err);
                     break;
-                } else {
-                    count += (td.end - td.buffer) as usize;
                 }
             }
+        }
 
-            /*
-            while self.regs.cmd_sts.readf(CMD_STS_CLF) {
-                self.regs.cmd_sts.writef(CMD_STS_CLF, false);
-            }
-            */
-            while self.regs.control.readf(CTRL_CLE) {
-                self.regs.control.writef(CTRL_CLE, false);
-            }
-            self.regs.control_head.write(0);
+        self.busy = false;
 
-            //debugln!("/ED: {:X}, FLG: {:X}, TAIL: {:X}, HEAD: {:X}, NEXT: {:X}", (&*ed as *const Ed) as usize, ed.flags, ed.tail, ed.head, ed.next);
-        }
+        result
+    }
+
+    fn set_device(&mut self, address: u8, speed: Speed, max_packet_size: u16) {
+        self.devices[address as usize & 0x7F] = OhciDevice {
+            low_speed: speed == Speed::Low,
+            max_packet_sizes: [max_packet_size; 16],
+            toggles: 0,
+        };
+    }
+
+    fn reset_toggle(&mut self, address: u8, endpoint_address: u8) {
+        let toggle_bit = (endpoint_address & 0xF) + if endpoint_address & 0x80 == 0x80 { 16 } else { 0 };
+        self.devices[address as usize & 0x7F].toggles &= ! (1 << toggle_bit);
+    }
 
-        count
+    fn set_endpoint(&mut self, address: u8, desc_end: &EndpointDescriptor) -> Result<()> {
+        self.devices[address as usize & 0x7F].max_packet_sizes[desc_end.number() as usize] = desc_end.max_packet_size();
+        Ok(())
     }
 }
@@ -0,0 +1,177 @@
+use collections::string::String;
+
+use core::ops::{Index, IndexMut};
+use core::mem;
+
+use arch::memory::{self, Memory};
+
+use env::log::InterruptGuard;
+
+use system::error::Result;
+
+/// Slots in the pool
+const DESCRIPTOR_POOL_SLOTS: usize = 64;
+/// Bytes in a slot, enough for the descriptors of a transfer of a few pages
+///
+/// Slots are aligned to their size within a page aligned block, so none crosses a page as the controllers
+/// require of a descriptor.
+pub const DESCRIPTOR_SLOT_SIZE: usize = 512;
+
+/// Fixed size blocks for the descriptors built for each transfer, shared by every controller
+struct DescriptorPool {
+    /// The slots, allocated once
+    memory: usize,
+    /// The free slot numbers, the last is leased first
+    free: [u8; DESCRIPTOR_POOL_SLOTS],
+    free_count: usize,
+    /// Descriptors put in a slot
+    hits: u64,
+    /// Leases refused because every slot was in use, which were allocated instead
+    exhausted: u64,
+    /// Descriptors too large for a slot, which were allocated instead
+    oversize: u64,
+}
+
+static mut DESCRIPTOR_POOL: Option<DescriptorPool> = None;
+
+fn descriptor_pool() -> &'static mut DescriptorPool {
+    unsafe {
+        if DESCRIPTOR_POOL.is_none() {
+            let mut pool = DescriptorPool {
+                memory: memory::alloc_aligned(DESCRIPTOR_POOL_SLOTS * DESCRIPTOR_SLOT_SIZE, 4096),
+                free: [0; DESCRIPTOR_POOL_SLOTS],
+                free_count: DESCRIPTOR_POOL_SLOTS,
+                hits: 0,
+                exhausted: 0,
+                oversize: 0,
+            };
+            for (i, slot) in pool.free.iter_mut().enumerate() {
+                *slot = i as u8;
+            }
+            DESCRIPTOR_POOL = Some(pool);
+        }
+        DESCRIPTOR_POOL.as_mut().unwrap()
+    }
+}
+
+impl DescriptorPool {
+    fn lease(&mut self) -> Option<usize> {
+        if self.free_count == 0 || self.memory == 0 {
+            self.exhausted += 1;
+            return None;
+        }
+
+        self.free_count -= 1;
+        self.hits += 1;
+        Some(self.free[self.free_count] as usize)
+    }
+
+    fn release(&mut self, slot: usize) {
+        self.free[self.free_count] = slot as u8;
+        self.free_count += 1;
+    }
+
+    fn slot(&self, slot: usize) -> usize {
+        self.memory + slot * DESCRIPTOR_SLOT_SIZE
+    }
+}
+
+/// The state of the pool, for usb:/pool
+pub fn descriptor_pool_status() -> String {
+    let _guard = InterruptGuard::new();
+    let pool = descriptor_pool();
+    format!("descriptor pool: slots: {} free: {} hits: {} exhausted: {} oversize: {}\n",
+            DESCRIPTOR_POOL_SLOTS,
+            pool.free_count,
+            pool.hits,
+            pool.exhausted,
+            pool.oversize)
+}
+
+enum Storage<T> {
+    Slot(usize),
+    Heap(Memory<T>),
+}
+
+/// Descriptors for one transfer, in a pool slot when they fit and one is free
+///
+/// Otherwise they are allocated as before, so a busy bus works the same only slower. The slot returns to
+/// the pool on drop.
+pub struct Descriptors<T> {
+    storage: Storage<T>,
+    ptr: *mut T,
+    length: usize,
+}
+
+impl<T> Descriptors<T> {
+    /// Descriptors for `length` T, aligned to `align`
+    pub fn new(length: usize, align: usize) -> Result<Descriptors<T>> {
+        let size = length * mem::size_of::<T>();
+        let slot = {
+            let _guard = InterruptGuard::new();
+            let pool = descriptor_pool();
+            if size > DESCRIPTOR_SLOT_SIZE || align > DESCRIPTOR_SLOT_SIZE {
+                pool.oversize += 1;
+                None
+            } else {
+                pool.lease().map(|slot| (slot, pool.slot(slot)))
+            }
+        };
+
+        match slot {
+            Some((slot, address)) => Ok(Descriptors {
+                storage: Storage::Slot(slot),
+                ptr: address as *mut T,
+                length: length,
+            }),
+            None => {
+                let mut memory = try!(Memory::new_aligned(length, align));
+                let ptr = unsafe { memory.as_mut_ptr() };
+                Ok(Descriptors {
+                    storage: Storage::Heap(memory),
+                    ptr: ptr,
+                    length: length,
+                })
+            },
+        }
+    }
+
+    /// Get the length in T elements
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// Get the address
+    pub fn address(&self) -> usize {
+        self.ptr as usize
+    }
+}
+
+impl<T> Index<usize> for Descriptors<T> {
+    type Output = T;
+
+    fn index(&self, i: usize) -> &T {
+        if i >= self.length {
+            panic!("Descriptors: {} >= {}", i, self.length);
+        }
+        unsafe { &*self.ptr.offset(i as isize) }
+    }
+}
+
+impl<T> IndexMut<usize> for Descriptors<T> {
+    fn index_mut(&mut self, i: usize) -> &mut T {
+        if i >= self.length {
+            panic!("Descriptors: {} >= {}", i, self.length);
+        }
+        unsafe { &mut *self.ptr.offset(i as isize) }
+    }
+}
+
+impl<T> Drop for Descriptors<T> {
+    fn drop(&mut self) {
+        if let Storage::Slot(slot) = self.storage {
+            let _guard = InterruptGuard::new();
+            descriptor_pool().release(slot);
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use alloc::boxed::Box;
+
+use collections::string::String;
+
+use fs::{KScheme, Resource, VecResource};
+
+use system::error::{Error, Result, ENOENT};
+use system::syscall::MODE_FILE;
+
+use super::UsbDevice;
+use super::desc::descriptors;
+use super::device::{usb_bus, usb_devices};
+use super::pool::descriptor_pool_status;
+use super::watchdog::watchdog_status;
+
+/// The name of a device in the listing, its bus and address
+fn name(device: &UsbDevice) -> String {
+    format!("{}-{}", usb_bus(device.hci), device.address)
+}
+
+/// One line for every device
+fn list() -> String {
+    let mut string = format!("{:<8}{:<6}{:<6}{:<11}{:<7}{:<8}{:<24}{}\n",
+                             "DEVICE", "HUB", "PORT", "ID", "CLASS", "SPEED", "DRIVER", "NAME");
+
+    for device in usb_devices().iter() {
+        let desc_dev = &device.descriptor;
+        let speed = format!("{:?}", device.speed);
+        let product = match (device.manufacturer.as_ref(), device.product.as_ref()) {
+            (Some(manufacturer), Some(product)) => format!("{} {}", manufacturer, product),
+            (Some(name), None) | (None, Some(name)) => name.clone(),
+            (None, None) => String::new(),
+        };
+        string.push_str(&format!("{:<8}{:<6}{:<6}{:04x}:{:04x}  {:02x}{:02x}{:02x} {:<8}{:<24}{}\n",
+                                 name(device),
+                                 device.hub,
+                                 device.port,
+                                 desc_dev.vendor,
+                                 desc_dev.product,
+                                 desc_dev.class,
+                                 desc_dev.sub_class,
+                                 desc_dev.protocol,
+                                 speed,
+                                 device.driver.unwrap_or("-"),
+                                 product));
+    }
+
+    string
+}
+
+/// The strings and descriptors of `device`
+fn dump(device: &UsbDevice) -> String {
+    let mut string = format!("device: {}\nhub: {}\nport: {}\nspeed: {:?}\ndriver: {}\n",
+                             name(device), device.hub, device.port, device.speed, device.driver.unwrap_or("-"));
+    for &(label, value) in [("manufacturer", &device.manufacturer),
+                                ("product", &device.product),
+                                ("serial", &device.serial)].iter() {
+        if let Some(ref value) = *value {
+            string.push_str(&format!("{}: {}\n", label, value));
+        }
+    }
+
+    string.push_str(&format!("{:#?}\n", device.descriptor));
+    for descriptor in descriptors(&device.configuration) {
+        string.push_str(&format!("{:#?}\n", descriptor));
+    }
+
+    string
+}
+
+/// The usb scheme, a listing of the enumerated devices, and a dump of each at `usb:/bus-address`
+///
+/// `usb:/pool` counts the transfers whose descriptors came from the pool, and `usb:/watchdog` the resets of
+/// each controller that stopped.
+pub struct UsbScheme;
+
+impl KScheme for UsbScheme {
+    fn scheme(&self) -> &str {
+        "usb"
+    }
+
+    fn open(&mut self, url: &str, _: usize) -> Result<Box<Resource>> {
+        let reference = url.splitn(2, ":").nth(1).unwrap_or("").trim_matches('/');
+
+        // Built on every open, so a device shows as soon as it is enumerated
+        let string = if reference.is_empty() {
+            list()
+        } else if reference == "pool" {
+            descriptor_pool_status()
+        } else if reference == "watchdog" {
+            watchdog_status()
+        } else {
+            match usb_devices().iter().find(|device| name(device) == reference) {
+                Some(device) => dump(device),
+                None => return Err(Error::new(ENOENT)),
+            }
+        };
+
+        Ok(box VecResource::new(format!("usb:/{}", reference), string.into_bytes(), MODE_FILE))
+    }
+}
@@ -88,4 +88,175 @@ impl Setup {
             len: 0,
         }
     }
+
+    /// Choose the alternate setting of `interface`
+    pub fn set_interface(interface: u8, alternate: u8) -> Setup {
+        Setup {
+            request_type: 0b00000001,
+            request: 0x0B,
+            value: alternate as u16,
+            index: interface as u16,
+            len: 0,
+        }
+    }
+
+    /// Choose the boot (0) or report (1) protocol of HID `interface`
+    pub fn set_protocol(interface: u8, protocol: u8) -> Setup {
+        Setup {
+            request_type: 0b00100001,
+            request: 0x0B,
+            value: protocol as u16,
+            index: interface as u16,
+            len: 0,
+        }
+    }
+
+    /// Set how often HID `interface` repeats an unchanged report, in 4 ms units, with 0 reporting only changes
+    pub fn set_idle(interface: u8, duration: u8) -> Setup {
+        Setup {
+            request_type: 0b00100001,
+            request: 0x0A,
+            value: (duration as u16) << 8,
+            index: interface as u16,
+            len: 0,
+        }
+    }
+
+    /// Set the output report of HID `interface` to the `len` bytes of the data stage, as the LEDs of a boot keyboard
+    pub fn set_report(interface: u8, len: u16) -> Setup {
+        Setup {
+            request_type: 0b00100001,
+            request: 0x09,
+            value: 2 << 8,
+            index: interface as u16,
+            len: len,
+        }
+    }
+
+    /// Clear the halt of the endpoint with `endpoint_address`, which resets its data toggle
+    pub fn clear_halt(endpoint_address: u8) -> Setup {
+        Setup {
+            request_type: 0b00000010,
+            request: 0x01,
+            value: 0,
+            index: endpoint_address as u16,
+            len: 0,
+        }
+    }
+
+    /// Reset the bulk-only mass storage `interface`, ready for the next command
+    pub fn bulk_only_reset(interface: u8) -> Setup {
+        Setup {
+            request_type: 0b00100001,
+            request: 0xFF,
+            value: 0,
+            index: interface as u16,
+            len: 0,
+        }
+    }
+
+    /// Read the highest logical unit number of bulk-only mass storage `interface`
+    pub fn get_max_lun(interface: u8) -> Setup {
+        Setup {
+            request_type: 0b10100001,
+            request: 0xFE,
+            value: 0,
+            index: interface as u16,
+            len: 1,
+        }
+    }
+
+    /// Set the baud rate and character format of CDC ACM `interface`, given in the 7 bytes of data
+    pub fn set_line_coding(interface: u8) -> Setup {
+        Setup {
+            request_type: 0b00100001,
+            request: 0x20,
+            value: 0,
+            index: interface as u16,
+            len: 7,
+        }
+    }
+
+    pub fn get_line_coding(interface: u8) -> Setup {
+        Setup {
+            request_type: 0b10100001,
+            request: 0x21,
+            value: 0,
+            index: interface as u16,
+            len: 7,
+        }
+    }
+
+    /// Set the DTR and RTS signals of CDC ACM `interface`, bits 0 and 1 of `state`
+    pub fn set_control_line_state(interface: u8, state: u16) -> Setup {
+        Setup {
+            request_type: 0b00100001,
+            request: 0x22,
+            value: state,
+            index: interface as u16,
+            len: 0,
+        }
+    }
+
+    /// Set the sample rate of the audio endpoint with `endpoint_address`, given in the 3 bytes of data
+    pub fn set_sampling_frequency(endpoint_address: u8) -> Setup {
+        Setup {
+            request_type: 0b00100010,
+            request: 0x01,
+            value: 0x01 << 8,
+            index: endpoint_address as u16,
+            len: 3,
+        }
+    }
+
+    pub fn get_hub_descriptor(descriptor_type: u8, descriptor_len: u16) -> Setup {
+        Setup {
+            request_type: 0b10100000,
+            request: 0x06,
+            value: (descriptor_type as u16) << 8,
+            index: 0,
+            len: descriptor_len,
+        }
+    }
+
+    /// Tell a super speed hub how many hubs are above it, which it needs to route packets
+    pub fn set_hub_depth(depth: u16) -> Setup {
+        Setup {
+            request_type: 0b00100000,
+            request: 0x0C,
+            value: depth,
+            index: 0,
+            len: 0,
+        }
+    }
+
+    pub fn get_port_status(port: u8) -> Setup {
+        Setup {
+            request_type: 0b10100011,
+            request: 0x00,
+            value: 0,
+            index: port as u16,
+            len: 4,
+        }
+    }
+
+    pub fn set_port_feature(port: u8, feature: u16) -> Setup {
+        Setup {
+            request_type: 0b00100011,
+            request: 0x03,
+            value: feature,
+            index: port as u16,
+            len: 0,
+        }
+    }
+
+    pub fn clear_port_feature(port: u8, feature: u16) -> Setup {
+        Setup {
+            request_type: 0b00100011,
+            request: 0x01,
+            value: feature,
+            index: port as u16,
+            len: 0,
+        }
+    }
 }
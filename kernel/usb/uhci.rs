@@ -1,30 +1,97 @@
 use alloc::boxed::Box;
 
-use collections::vec::Vec;
+use arch::context::{context_switch, Context};
+use arch::memory::Memory;
 
-use core::mem;
+use common::time::{self, Duration};
 
-use arch::context::context_switch;
-use arch::memory::Memory;
+use collections::vec::Vec;
+
+use core::{cmp, mem};
 
 use drivers::pci::config::PciConfig;
 use drivers::io::{Io, Mmio, Pio, PhysAddr};
 
 use fs::KScheme;
 
-use super::{Hci, Packet, Pipe, Setup};
+use system::error::{Error, Result, EIO, ENODEV, EPIPE, ETIMEDOUT};
+
+use super::{delay, wait_for, UsbHc, Packet, Pipe, Setup, Speed};
+use super::hci::InterruptPipe;
+use super::desc::EndpointDescriptor;
+use super::device::{usb_cancelled, usb_enumerate_begin, usb_enumerate_end};
+use super::pool::Descriptors;
+
+/// Milliseconds a control or bulk transfer may take before it is abandoned
+const TRANSFER_TIMEOUT: i32 = 1000;
+/// Milliseconds an interrupt transfer is polled, a device with nothing to report does not answer
+const INTERRUPT_TIMEOUT: i32 = 20;
+
+const USBCMD_RS: u16 = 1;
+const USBCMD_HCRESET: u16 = 1 << 1;
+const USBCMD_GRESET: u16 = 1 << 2;
+const USBCMD_CF: u16 = 1 << 6;
+const USBCMD_MAXP: u16 = 1 << 7;
+
+const PORT_CCS: u16 = 1;
+const PORT_CSC: u16 = 1 << 1;
+const PORT_PE: u16 = 1 << 2;
+const PORT_PEC: u16 = 1 << 3;
+const PORT_LSDA: u16 = 1 << 8;
+const PORT_PR: u16 = 1 << 9;
+
+/// The state of an address, for building its transfer descriptors
+#[derive(Copy, Clone)]
+struct UhciDevice {
+    low_speed: bool,
+    max_packet_sizes: [u16; 16],
+    /// The next data toggle of each endpoint, OUT in the low 16 bits and IN in the high 16
+    toggles: u32,
+}
+
+/// Interrupt queue heads are polled every 2^n frames, for n up to this
+const PERIODS: usize = 8;
+
+/// An interrupt endpoint polled by the controller, with one descriptor that is run again once its report is read
+struct UhciPipe {
+    id: usize,
+    address: u8,
+    endpoint: u8,
+    /// The skeleton queue head the pipe is linked after
+    period: usize,
+    queue_head: Memory<Qh>,
+    td: Memory<Td>,
+    buffer: Memory<u8>,
+}
 
 pub struct Uhci {
     pub base: usize,
     pub irq: u8,
     pub frame_list: Memory<PhysAddr<Mmio<u32>>>,
+    /// Every frame ends at this queue head, a transfer is run by hanging its descriptors off it
+    queue_head: Memory<Qh>,
+    /// The queue heads each frame starts at, the one for every 2^n frames links to the one for every 2^(n-1)
+    skeletons: Memory<Qh>,
+    pipes: Vec<UhciPipe>,
+    next_pipe: usize,
+    /// Set while a transfer uses the queue head
+    busy: bool,
+    devices: [UhciDevice; 128],
 }
 
 impl KScheme for Uhci {
-    fn on_irq(&mut self, irq: u8) {
+    fn on_irq(&mut self, irq: u8) -> bool {
         if irq == self.irq {
             // d("UHCI IRQ\n");
         }
+        irq == self.irq
+    }
+
+    /// Stop the schedule, so the controller no longer reads the frame list
+    fn power_off(&mut self) {
+        let base = self.base as u16;
+        Pio::<u16>::new(base + 0x4).write(0);
+        Pio::<u16>::new(base).writef(USBCMD_RS, false);
     }
 }
 
@@ -44,6 +111,7 @@ bitflags! {
 bitflags! {
     flags CtrlStsFlags: u32 {
         const CTRL_SHORT_PACKET_DETECT = 1 << 29,
+        const CTRL_ERROR_COUNT = 3 << 27,
         const CTRL_LOW_SPEED = 1 << 26,
         const CTRL_ISOCHRONOUS = 1 << 25,
         const CTRL_INTERRUPT = 1 << 24,
@@ -77,8 +145,18 @@ impl Uhci {
 
         let mut module = box Uhci {
             base: pci.read(0x20) as usize & 0xFFFFFFF0,
-            irq: pci.read(0x3C) as u8 & 0xF,
+            irq: pci.irq(),
             frame_list: Memory::new_aligned(1024, 4096).unwrap(),
+            queue_head: Memory::new_aligned(1, 16).unwrap(),
+            skeletons: Memory::new_aligned(PERIODS, 16).unwrap(),
+            pipes: Vec::new(),
+            next_pipe: 1,
+            busy: false,
+            devices: [UhciDevice {
+                low_speed: false,
+                max_packet_sizes: [8; 16],
+                toggles: 0,
+            }; 128],
         };
 
         module.init();
@@ -89,134 +167,417 @@ impl Uhci {
     pub unsafe fn init(&mut self) {
         syslog_info!(" + UHCI on: {:X}, IRQ: {:X}", self.base, self.irq);
 
+        // The resets and port delays need to sleep, so they are done once the scheduler runs
+        let this = self as *mut Uhci;
+        Context::spawn("kuhci".into(), box move || {
+            (*this).reset();
+            loop {
+                (*this).ports();
+                delay(250);
+            }
+        });
+    }
+
+    /// Reset the controller and start it on an empty schedule
+    unsafe fn reset(&mut self) {
         let base = self.base as u16;
         let mut usbcmd = Pio::<u16>::new(base);
-        let usbsts = Pio::<u16>::new(base + 0x2);
-        let usbintr = Pio::<u16>::new(base + 0x4);
+        let mut usbsts = Pio::<u16>::new(base + 0x2);
+        let mut usbintr = Pio::<u16>::new(base + 0x4);
         let mut frnum = Pio::<u16>::new(base + 0x6);
         let mut flbaseadd = Pio::<u32>::new(base + 0x8);
-        let mut portscs = [Pio::<u16>::new(base + 0x10), Pio::<u16>::new(base + 0x12)];
 
-        debug!(" CMD {:X}", usbcmd.read());
-        usbcmd.write(1 << 2 | 1 << 1);
-        debug!(" to {:X}", usbcmd.read());
+        // A global reset must be held for 10 ms
+        usbcmd.write(USBCMD_GRESET);
+        delay(10);
         usbcmd.write(0);
-        debug!(" to {:X}", usbcmd.read());
-
-        debug!(" STS {:X}", usbsts.read());
 
-        debug!(" INTR {:X}", usbintr.read());
+        usbcmd.write(USBCMD_HCRESET);
+        if ! wait_for(10, || ! usbcmd.readf(USBCMD_HCRESET)) {
+            syslog_warning!("UHCI: Reset timed out");
+        }
 
-        debug!(" FRNUM {:X}", frnum.read());
-        frnum.write(0);
-        debug!(" to {:X}", frnum.read());
+        // Completions are polled
+        usbintr.write(0);
+        usbsts.write(0x3F);
 
-        debug!(" FLBASEADD {:X}", flbaseadd.read());
+        self.queue_head[0].head_ptr.write(LINK_TERMINATE.bits);
+        self.queue_head[0].element_ptr.write(LINK_TERMINATE.bits);
+        for period in 0..PERIODS {
+            self.skeletons[period].element_ptr.write(LINK_TERMINATE.bits);
+            self.link(period);
+        }
+        // Frame i runs the interrupt queue heads of every period that divides it
         for i in 0..1024 {
-            self.frame_list[i].write(LINK_TERMINATE.bits);
+            let period = cmp::min(PERIODS - 1, (i as u32 | 1 << 31).trailing_zeros() as usize);
+            self.frame_list[i].write((self.skeletons.address() + period * mem::size_of::<Qh>()) as u32 | LINK_QH_SELECT.bits);
         }
-        flbaseadd.write(self.frame_list.address() as u32);
-        debug!(" to {:X}", flbaseadd.read());
 
-        debug!(" CMD {:X}", usbcmd.read());
-        usbcmd.write(1);
-        debug!(" to {:X}", usbcmd.read());
+        frnum.write(0);
+        flbaseadd.write(self.frame_list.address() as u32);
+        usbcmd.write(USBCMD_RS | USBCMD_CF | USBCMD_MAXP);
+    }
 
-        debugln!("");
+    fn port(&self, i: usize) -> Pio<u16> {
+        Pio::<u16>::new(self.base as u16 + 0x10 + i as u16 * 2)
+    }
 
-        for i in 0..portscs.len() {
-            let portsc = &mut portscs[i];
+    /// Handle connects and disconnects on the root ports
+    unsafe fn ports(&mut self) {
+        for i in 0..2 {
+            let mut portsc = self.port(i);
+            let status = portsc.read();
+            if status & PORT_CSC != PORT_CSC {
+                continue;
+            }
 
-            debug!(" PORTSC{} {:X}", i + 1, portsc.read());
+            // The change bits are cleared by writing them, the enable bit has to be written back
+            portsc.write(status & (PORT_CSC | PORT_PEC | PORT_PE));
+
+            self.port_disconnected(i as u8 + 1);
+            if status & PORT_CCS == PORT_CCS {
+                // Let the connection settle before the reset
+                delay(100);
+                usb_enumerate_begin();
+                if let Some(speed) = self.reset_port(i) {
+                    syslog_info!("UHCI: {:?} speed device on port {}", speed, i + 1);
+                    if let Err(err) = self.port_connected(i as u8 + 1, speed) {
+                        syslog_warning!("UHCI: Failed to enumerate port {}: {}", i + 1, err);
+                    }
+                }
+                usb_enumerate_end();
+            } else {
+                syslog_info!("UHCI: Device removed from port {}", i + 1);
+            }
+        }
+    }
 
-            portsc.write(1 << 9);
-            debug!(" to {:X}", portsc.read());
+    /// Reset and enable port `i`, returning the speed of its device
+    unsafe fn reset_port(&mut self, i: usize) -> Option<Speed> {
+        let mut portsc = self.port(i);
+
+        // The root port reset is held for 50 ms
+        portsc.write(PORT_PR);
+        delay(50);
+        portsc.write(0);
+        delay(1);
+
+        // The port is given 100 ms to enable
+        let end = Duration::monotonic() + Duration::new(0, 100 * time::NANOS_PER_MILLI);
+        while Duration::monotonic() < end {
+            let status = portsc.read();
+            if status & PORT_CCS != PORT_CCS {
+                return None;
+            }
 
-            portsc.write(0);
-            debugln!(" to {:X}", portsc.read());
+            if status & (PORT_CSC | PORT_PEC) != 0 {
+                portsc.write(status & (PORT_CSC | PORT_PEC | PORT_PE));
+            } else if status & PORT_PE == PORT_PE {
+                // The device is given 10 ms to recover from the reset
+                delay(10);
+                return Some(if status & PORT_LSDA == PORT_LSDA {
+                    Speed::Low
+                } else {
+                    Speed::Full
+                });
+            } else {
+                portsc.write(PORT_PE);
+            }
 
-            if portsc.read() & 1 == 1 {
-                debug!(" Device Found {:X}", portsc.read());
+            delay(10);
+        }
 
-                portsc.write(4);
-                debugln!(" to {:X}", portsc.read());
+        syslog_warning!("UHCI: Port {} could not be enabled", i + 1);
+        None
+    }
 
-                self.device((i + 1) as u8);
-            }
+    /// Chain the skeleton queue head of `period` through its pipes to the next shorter period
+    ///
+    /// The links are written from the end, so the controller never follows one to a queue head that is not ready.
+    fn link(&mut self, period: usize) {
+        let mut next = if period == 0 {
+            self.queue_head.address() as u32 | LINK_QH_SELECT.bits
+        } else {
+            (self.skeletons.address() + (period - 1) * mem::size_of::<Qh>()) as u32 | LINK_QH_SELECT.bits
+        };
+        for pipe in self.pipes.iter_mut().rev().filter(|pipe| pipe.period == period) {
+            pipe.queue_head[0].head_ptr.write(next);
+            next = pipe.queue_head.address() as u32 | LINK_QH_SELECT.bits;
         }
+        self.skeletons[period].head_ptr.write(next);
     }
-}
 
-impl Hci for Uhci {
-    fn msg(&mut self, address: u8, endpoint: u8, pipe: Pipe, msgs: &[Packet]) -> usize {
-        let mut tds = Vec::new();
-        for msg in msgs.iter().rev() {
-            let mut td = Td {
-                link_ptr: PhysAddr::new(Mmio::new()),
-                ctrl_sts: Mmio::new(),
-                token: Mmio::new(),
-                buffer: PhysAddr::new(Mmio::new()),
-            };
+    /// Start the descriptor of pipe `i` again, for its next report
+    fn arm(&mut self, i: usize) {
+        let (address, endpoint) = (self.pipes[i].address, self.pipes[i].endpoint);
+        let device = self.devices[address as usize & 0x7F];
+        let toggle = device.toggles & 1 << (endpoint + 16) != 0;
 
-            td.link_ptr.write(match tds.last() {
-                Some(last_td) => (last_td as *const Td) as u32 | LINK_DEPTH_SELECT.bits,
-                None => LINK_TERMINATE.bits
-            });
+        let mut ctrl = CTRL_ERROR_COUNT | STS_ACTIVE;
+        if device.low_speed {
+            ctrl.insert(CTRL_LOW_SPEED);
+        }
 
-            td.ctrl_sts.write(match pipe {
-                Pipe::Isochronous => (CTRL_ISOCHRONOUS | STS_ACTIVE).bits,
-                _ => STS_ACTIVE.bits
-            });
+        let pipe = &mut self.pipes[i];
+        let len = pipe.buffer.len() as u32;
+        pipe.td[0].link_ptr.write(LINK_TERMINATE.bits);
+        pipe.td[0].ctrl_sts.write(ctrl.bits);
+        pipe.td[0].token.write((len - 1) << 21 | (toggle as u32) << 19 | (endpoint as u32 & 0xF) << 15 |
+                               (address as u32 & 0x7F) << 8 | 0x69);
+        pipe.td[0].buffer.write(pipe.buffer.address() as u32);
+        pipe.queue_head[0].element_ptr.write(pipe.td.address() as u32);
+    }
 
-            match *msg {
-                Packet::Setup(setup) => {
-                    td.token.write((mem::size_of::<Setup>() as u32 - 1) << 21 | (endpoint as u32) << 15 | (address as u32) << 8 | 0x2D);
-                    td.buffer.write((&*setup as *const Setup) as u32);
-                },
-                Packet::In(ref data) => {
-                    td.token.write(((data.len() as u32 - 1) & 0x7FF) << 21 | (endpoint as u32) << 15 | (address as u32) << 8 | 0x69);
-                    td.buffer.write(data.as_ptr() as u32);
-                },
-                Packet::Out(ref data) => {
-                    td.token.write(((data.len() as u32 - 1) & 0x7FF) << 21 | (endpoint as u32) << 15 | (address as u32) << 8 | 0xE1);
-                    td.buffer.write(data.as_ptr() as u32);
+    /// Take pipe `i` out of the schedule
+    fn unlink(&mut self, i: usize) {
+        let pipe = self.pipes.remove(i);
+        self.link(pipe.period);
+        // The controller may be in the queue head until the end of the frame
+        delay(1);
+    }
+
+    /// Run the descriptors in `tds` for `address` on the queue head, returning the bytes transferred
+    unsafe fn run(&mut self, address: u8, tds: &mut Descriptors<Td>, timeout: i32) -> Result<usize> {
+        self.queue_head[0].element_ptr.write(tds.address() as u32);
+
+        let end = Duration::monotonic() + Duration::new(0, timeout * time::NANOS_PER_MILLI);
+        let mut result = Ok(0);
+        let mut done = 0;
+        for i in 0..tds.len() {
+            while tds[i].ctrl_sts.read() & STS_ACTIVE.bits == STS_ACTIVE.bits {
+                if usb_cancelled(self as *mut Uhci as *mut UsbHc, address) {
+                    result = Err(Error::new(ENODEV));
+                    break;
                 }
+                if Duration::monotonic() > end {
+                    result = Err(Error::new(ETIMEDOUT));
+                    break;
+                }
+                context_switch();
+            }
+            if result.is_err() {
+                break;
             }
 
-            tds.push(td);
+            let ctrl_sts = tds[i].ctrl_sts.read();
+            if ctrl_sts & STS_STALLED.bits == STS_STALLED.bits {
+                result = Err(Error::new(EPIPE));
+                break;
+            } else if ctrl_sts & (STS_BUFFER_ERROR | STS_BABBLE | STS_TIMEOUT | STS_BITSTUFF).bits != 0 {
+                result = Err(Error::new(EIO));
+                break;
+            }
+
+            let actual = ((ctrl_sts + 1) & 0x7FF) as usize;
+            let max = (((tds[i].token.read() >> 21) + 1) & 0x7FF) as usize;
+            if let Ok(ref mut count) = result {
+                *count += actual;
+            }
+            done += 1;
+            // A short packet ends the stage, the descriptors after it are not used
+            if actual < max {
+                break;
+            }
+        }
+
+        self.queue_head[0].element_ptr.write(LINK_TERMINATE.bits);
+        // The controller may still hold a descriptor that was cut off until the end of the frame
+        if done < tds.len() {
+            delay(1);
         }
 
-        let mut count = 0;
+        result
+    }
+}
+
+impl UsbHc for Uhci {
+    fn msg(&mut self, address: u8, endpoint: u8, pipe: Pipe, msgs: &[Packet]) -> Result<usize> {
+        while self.busy {
+            unsafe { context_switch() };
+        }
+        self.busy = true;
+
+        let device = self.devices[address as usize & 0x7F];
+        let max_packet_size = cmp::max(1, device.max_packet_sizes[endpoint as usize & 0xF] as usize);
+        let timeout = match pipe {
+            Pipe::Interrupt => INTERRUPT_TIMEOUT,
+            _ => TRANSFER_TIMEOUT,
+        };
 
-        if ! tds.is_empty() {
-            let mut queue_head = box Qh {
-                 head_ptr: PhysAddr::new(Mmio::new()),
-                 element_ptr: PhysAddr::new(Mmio::new()),
+        let mut ctrl = CTRL_ERROR_COUNT | STS_ACTIVE;
+        if device.low_speed {
+            ctrl.insert(CTRL_LOW_SPEED);
+        }
+        if let Pipe::Isochronous = pipe {
+            ctrl.insert(CTRL_ISOCHRONOUS);
+        }
+
+        let mut control_toggle = true;
+        let mut result = Ok(0);
+        for msg in msgs.iter() {
+            let (pid, ptr, len, toggle_bit) = match *msg {
+                Packet::Setup(setup) => (0x2D, (setup as *const Setup) as usize, mem::size_of::<Setup>(), endpoint as u32),
+                Packet::In(ref data) => (0x69, data.as_ptr() as usize, data.len(), endpoint as u32 + 16),
+                Packet::Out(ref data) => (0xE1, data.as_ptr() as usize, data.len(), endpoint as u32),
             };
 
-            queue_head.head_ptr.write(LINK_TERMINATE.bits);
-            queue_head.element_ptr.write((tds.last().unwrap() as *const Td) as u32);
+            // Control transfers set the toggle of each stage, the status stage is the empty packet
+            let mut toggle = match (pipe, msg) {
+                (Pipe::Control, &Packet::Setup(_)) => false,
+                (Pipe::Control, _) if len == 0 => true,
+                (Pipe::Control, _) => control_toggle,
+                _ => self.devices[address as usize & 0x7F].toggles & 1 << toggle_bit == 1 << toggle_bit,
+            };
 
-            let frame_ptr = if tds.len() == 1 {
-                (&tds[0] as *const Td) as u32
-            } else {
-                (&*queue_head as *const Qh) as u32 | LINK_QH_SELECT.bits
+            let packets = cmp::max(1, (len + max_packet_size - 1) / max_packet_size);
+            let mut tds = match Descriptors::<Td>::new(packets, 16) {
+                Ok(tds) => tds,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
             };
 
-            let frnum = Pio::<u16>::new(self.base as u16 + 6);
-            let frame = (frnum.read() + 1) & 0x3FF;
-            self.frame_list[frame as usize].write(frame_ptr);
+            for i in 0..packets {
+                let offset = i * max_packet_size;
+                let size = cmp::min(max_packet_size, len - cmp::min(len, offset));
+
+                let link = if i + 1 < packets {
+                    (tds.address() + (i + 1) * mem::size_of::<Td>()) as u32 | LINK_DEPTH_SELECT.bits
+                } else {
+                    LINK_TERMINATE.bits
+                };
+                let max_len = if size == 0 {
+                    0x7FF
+                } else {
+                    size as u32 - 1
+                };
+
+                tds[i].link_ptr.write(link);
+                tds[i].ctrl_sts.write((ctrl | CTRL_SHORT_PACKET_DETECT).bits);
+                tds[i].token.write(max_len << 21 | (toggle as u32) << 19 | (endpoint as u32 & 0xF) << 15 |
+                                   (address as u32 & 0x7F) << 8 | pid);
+                tds[i].buffer.write((ptr + offset) as u32);
+
+                toggle = ! toggle;
+            }
 
-            for td in tds.iter().rev() {
-                while td.ctrl_sts.read() & STS_ACTIVE.bits == STS_ACTIVE.bits {
-                    unsafe { context_switch() };
+            match unsafe { self.run(address, &mut tds, timeout) } {
+                Ok(count) => {
+                    // The toggle moves once for each packet that was sent
+                    let sent = cmp::max(1, (count + max_packet_size - 1) / max_packet_size);
+                    if sent % 2 == 1 {
+                        match (pipe, msg) {
+                            (Pipe::Control, &Packet::Setup(_)) => (),
+                            (Pipe::Control, _) => control_toggle = ! control_toggle,
+                            _ => self.devices[address as usize & 0x7F].toggles ^= 1 << toggle_bit,
+                        }
+                    }
+
+                    if let Packet::Setup(_) = *msg {} else {
+                        if let Ok(ref mut total) = result {
+                            *total += count;
+                        }
+                    }
+                },
+                Err(err) => {
+                    result = Err(err);
+                    break;
                 }
-                count += td.ctrl_sts.read() & 0x7FF;
             }
+        }
 
-            self.frame_list[frame as usize].write(LINK_TERMINATE.bits);
+        self.busy = false;
+
+        result
+    }
+
+    fn set_device(&mut self, address: u8, speed: Speed, max_packet_size: u16) {
+        self.devices[address as usize & 0x7F] = UhciDevice {
+            low_speed: speed == Speed::Low,
+            max_packet_sizes: [max_packet_size; 16],
+            toggles: 0,
+        };
+    }
+
+    fn reset_toggle(&mut self, address: u8, endpoint_address: u8) {
+        let toggle_bit = (endpoint_address & 0xF) + if endpoint_address & 0x80 == 0x80 { 16 } else { 0 };
+        self.devices[address as usize & 0x7F].toggles &= ! (1 << toggle_bit);
+    }
+
+    fn set_endpoint(&mut self, address: u8, desc_end: &EndpointDescriptor) -> Result<()> {
+        self.devices[address as usize & 0x7F].max_packet_sizes[desc_end.number() as usize] = desc_end.max_packet_size();
+        Ok(())
+    }
+
+    fn detach(&mut self, address: u8) {
+        while let Some(i) = self.pipes.iter().position(|pipe| pipe.address == address) {
+            self.unlink(i);
+        }
+    }
+
+    fn interrupt_open(&mut self, address: u8, desc_end: &EndpointDescriptor) -> Result<InterruptPipe> {
+        let speed = if self.devices[address as usize & 0x7F].low_speed { Speed::Low } else { Speed::Full };
+        let frames = cmp::max(1, desc_end.period(speed) / 8);
+        let period = cmp::min(PERIODS - 1, frames.trailing_zeros() as usize);
+
+        let mut queue_head = try!(Memory::<Qh>::new_aligned(1, 16));
+        queue_head[0].element_ptr.write(LINK_TERMINATE.bits);
+        let id = self.next_pipe;
+        self.next_pipe += 1;
+        self.pipes.push(UhciPipe {
+            id: id,
+            address: address,
+            endpoint: desc_end.number(),
+            period: period,
+            queue_head: queue_head,
+            td: try!(Memory::new_aligned(1, 16)),
+            buffer: try!(Memory::new(cmp::max(1, desc_end.max_packet_size() as usize))),
+        });
+
+        let i = self.pipes.len() - 1;
+        self.arm(i);
+        self.link(period);
+
+        Ok(InterruptPipe {
+            id: id,
+            address: address,
+            endpoint: desc_end.number(),
+        })
+    }
+
+    fn interrupt_report(&mut self, pipe: &InterruptPipe, data: &mut [u8]) -> Option<usize> {
+        let i = match self.pipes.iter().position(|other| other.id == pipe.id) {
+            Some(i) => i,
+            None => return None,
+        };
+
+        let ctrl_sts = self.pipes[i].td[0].ctrl_sts.read();
+        if ctrl_sts & STS_ACTIVE.bits == STS_ACTIVE.bits {
+            return None;
         }
 
-        count as usize
+        let result = if ctrl_sts & (STS_STALLED | STS_BUFFER_ERROR | STS_BABBLE | STS_TIMEOUT | STS_BITSTUFF).bits != 0 {
+            syslog_debug!("UHCI: Interrupt transfer to {} failed: {:X}", pipe.address, ctrl_sts);
+            None
+        } else {
+            let actual = cmp::min(((ctrl_sts + 1) & 0x7FF) as usize, self.pipes[i].buffer.len());
+            let count = cmp::min(actual, data.len());
+            for j in 0..count {
+                data[j] = self.pipes[i].buffer[j];
+            }
+            self.devices[pipe.address as usize & 0x7F].toggles ^= 1 << (pipe.endpoint + 16);
+            Some(count)
+        };
+
+        self.arm(i);
+
+        result
+    }
+
+    fn interrupt_close(&mut self, pipe: &InterruptPipe) {
+        if let Some(i) = self.pipes.iter().position(|other| other.id == pipe.id) {
+            self.unlink(i);
+        }
     }
 }
@@ -0,0 +1,139 @@
+use collections::string::String;
+use collections::vec::Vec;
+
+use common::time::Duration;
+
+/// Seconds to wait after a reset before the next, doubled for each reset in a row
+const WATCHDOG_BACKOFF: i64 = 10;
+/// Seconds a controller has to go without a reset for the next one not to count as in a row
+const WATCHDOG_HEALTHY: i64 = 300;
+/// Resets in a row before the controller is left alone for `WATCHDOG_HEALTHY` seconds
+const WATCHDOG_MAX_RESETS: u32 = 5;
+/// Checks in a row the frame index may stay the same for
+const WATCHDOG_FRAME_CHECKS: u32 = 2;
+/// Commands in a row that may time out before the controller is taken to have stopped
+pub const WATCHDOG_TIMEOUTS: u32 = 3;
+
+/// Watches a controller for having wedged, and limits how often it is reset
+pub struct Watchdog {
+    pub name: &'static str,
+    pub base: usize,
+    /// Resets done, whether or not they brought the controller back
+    pub resets: u64,
+    /// Resets that did not bring the controller back
+    pub failed: u64,
+    /// Commands in a row that timed out, of those that only the controller finishes
+    pub timeouts: u32,
+    /// Set while the controller is being reset, so nothing is issued to it
+    pub resetting: bool,
+    /// Why the last reset was done
+    reason: Option<String>,
+    in_a_row: u32,
+    last_reset: Option<Duration>,
+    last_frame: u32,
+    /// Checks in a row that saw `last_frame`
+    stuck: u32,
+}
+
+/// The watchdog of every controller, for usb:/watchdog
+static mut USB_WATCHDOGS: Option<Vec<*const Watchdog>> = None;
+
+impl Watchdog {
+    pub fn new(name: &'static str, base: usize) -> Watchdog {
+        Watchdog {
+            name: name,
+            base: base,
+            resets: 0,
+            failed: 0,
+            timeouts: 0,
+            resetting: false,
+            reason: None,
+            in_a_row: 0,
+            last_reset: None,
+            last_frame: 0,
+            stuck: 0,
+        }
+    }
+
+    /// List the watchdog in usb:/watchdog, once it is in the controller where it stays
+    pub fn register(&self) {
+        unsafe {
+            if USB_WATCHDOGS.is_none() {
+                USB_WATCHDOGS = Some(Vec::new());
+            }
+            USB_WATCHDOGS.as_mut().unwrap().push(self as *const Watchdog);
+        }
+    }
+
+    /// Record whether a command timed out
+    pub fn completed(&mut self, timed_out: bool) {
+        if timed_out {
+            self.timeouts += 1;
+        } else {
+            self.timeouts = 0;
+        }
+    }
+
+    /// Whether the frame index of a running controller has stayed at `frame` for too many checks
+    pub fn frame_stuck(&mut self, frame: u32) -> bool {
+        if frame == self.last_frame {
+            self.stuck += 1;
+        } else {
+            self.last_frame = frame;
+            self.stuck = 0;
+        }
+        self.stuck >= WATCHDOG_FRAME_CHECKS
+    }
+
+    /// Whether the controller may be reset now for `reason`, recording the reset if it may
+    ///
+    /// Each reset in a row waits twice as long as the one before. After `WATCHDOG_MAX_RESETS` the
+    /// controller is not reset again until `WATCHDOG_HEALTHY` seconds have passed.
+    pub fn allow(&mut self, reason: String) -> bool {
+        let now = Duration::monotonic();
+        if let Some(last) = self.last_reset {
+            let since = (now - last).secs;
+            if since >= WATCHDOG_HEALTHY {
+                self.in_a_row = 0;
+            } else if self.in_a_row >= WATCHDOG_MAX_RESETS || since < WATCHDOG_BACKOFF << (self.in_a_row - 1) {
+                return false;
+            }
+        }
+
+        syslog_warning!("{} {:X}: {}, resetting the controller", self.name, self.base, reason);
+
+        self.resets += 1;
+        self.in_a_row += 1;
+        if self.in_a_row == WATCHDOG_MAX_RESETS {
+            syslog_error!("{} {:X}: Reset {} times in a row, not resetting again for {} seconds",
+                          self.name, self.base, self.in_a_row, WATCHDOG_HEALTHY);
+        }
+        self.timeouts = 0;
+        self.stuck = 0;
+        self.last_reset = Some(now);
+        self.reason = Some(reason);
+        true
+    }
+
+    pub fn to_string(&self) -> String {
+        format!("{} {:X}: resets: {} failed: {} in_a_row: {}{} last: {}\n",
+                self.name,
+                self.base,
+                self.resets,
+                self.failed,
+                self.in_a_row,
+                if self.in_a_row >= WATCHDOG_MAX_RESETS { " (held off)" } else { "" },
+                self.reason.as_ref().map_or("-", |reason| &reason[..]))
+    }
+}
+
+/// A line for every controller, with the resets its watchdog has done
+pub fn watchdog_status() -> String {
+    let mut string = String::new();
+    if let Some(watchdogs) = unsafe { USB_WATCHDOGS.as_ref() } {
+        for &watchdog in watchdogs.iter() {
+            string.push_str(&unsafe { &*watchdog }.to_string());
+        }
+    }
+    string
+}
@@ -1,250 +1,1282 @@
 use alloc::boxed::Box;
 
-//use arch::memory::*;
+use arch::context::{context_switch, Context};
+use arch::memory::Memory;
 
-use drivers::pci::config::PciConfig;
+use collections::vec::Vec;
+
+use common::time::{self, Duration};
 
-//use core::mem::size_of;
+use core::{cmp, mem, ptr};
+
+use drivers::io::{Io, Mmio};
+use drivers::pci::config::PciConfig;
 
 use fs::KScheme;
 
+use system::error::{Error, Result, EAGAIN, EINVAL, EIO, ENODEV, EPIPE, ETIMEDOUT};
+
+use super::{delay, hold, physical, wait_for, Held, UsbHc, Packet, Pipe, Setup, Speed};
+use super::hci::{InterruptPipe, IsochBuffer, IsochStatus, IsochStream};
+use super::desc::EndpointDescriptor;
+use super::device::{usb_cancel, usb_cancelled, usb_enumerate_begin, usb_enumerate_end, usb_reuse};
+use super::watchdog::{Watchdog, WATCHDOG_TIMEOUTS};
+
+/// Milliseconds a command, control or bulk transfer may take before it is abandoned
+const TRANSFER_TIMEOUT: i32 = 1000;
+/// Milliseconds an interrupt transfer is polled, a device with nothing to report does not answer
+const INTERRUPT_TIMEOUT: i32 = 20;
+/// TRBs in each ring, the last is the link back to the start
+const RING_SIZE: usize = 64;
+/// Bytes in one normal TRB, which must not cross a 64 KB boundary
+const TRB_MAX: usize = 65536;
+
+const CMD_RS: u32 = 1;
+const CMD_HCRST: u32 = 1 << 1;
+
+const STS_HCH: u32 = 1;
+const STS_HSE: u32 = 1 << 2;
+const STS_CNR: u32 = 1 << 11;
+const STS_HCE: u32 = 1 << 12;
+
+const PORT_CCS: u32 = 1;
+const PORT_PED: u32 = 1 << 1;
+const PORT_PR: u32 = 1 << 4;
+const PORT_PP: u32 = 1 << 9;
+const PORT_CSC: u32 = 1 << 17;
+const PORT_PRC: u32 = 1 << 21;
+/// The bits cleared by writing them, with the enable bit which is disabled by writing it
+const PORT_CHANGE: u32 = PORT_PED | 0b1111111 << 17;
+
+const EXT_CAP_LEGACY: u32 = 1;
+const LEGACY_BIOS_OWNED: u32 = 1 << 16;
+const LEGACY_OS_OWNED: u32 = 1 << 24;
+/// The SMI enables of USBLEGCTLSTS, the status bits above them are cleared by writing them
+const LEGACY_SMI_ENABLES: u32 = 1 | 1 << 4 | 0b111 << 13;
+
+const TRB_NORMAL: u32 = 1;
+const TRB_SETUP: u32 = 2;
+const TRB_DATA: u32 = 3;
+const TRB_STATUS: u32 = 4;
+const TRB_LINK: u32 = 6;
+const TRB_ENABLE_SLOT: u32 = 9;
+const TRB_DISABLE_SLOT: u32 = 10;
+const TRB_ADDRESS_DEVICE: u32 = 11;
+const TRB_CONFIGURE_ENDPOINT: u32 = 12;
+const TRB_EVALUATE_CONTEXT: u32 = 13;
+const TRB_RESET_ENDPOINT: u32 = 14;
+const TRB_STOP_ENDPOINT: u32 = 15;
+const TRB_SET_DEQUEUE: u32 = 16;
+const TRB_ISOCH: u32 = 17;
+const TRB_TRANSFER_EVENT: u32 = 32;
+const TRB_COMMAND_COMPLETION: u32 = 33;
+const TRB_PORT_STATUS_CHANGE: u32 = 34;
+
+const TRB_CYCLE: u32 = 1;
+const TRB_TOGGLE_CYCLE: u32 = 1 << 1;
+const TRB_ISP: u32 = 1 << 2;
+const TRB_CHAIN: u32 = 1 << 4;
+const TRB_IOC: u32 = 1 << 5;
+const TRB_IDT: u32 = 1 << 6;
+const TRB_BSR: u32 = 1 << 9;
+const TRB_DIR_IN: u32 = 1 << 16;
+/// Start an isochronous TD in the next interval the endpoint has, instead of a given frame
+const TRB_SIA: u32 = 1 << 31;
+
+const COMPLETION_SUCCESS: u32 = 1;
+const COMPLETION_STALL: u32 = 6;
+const COMPLETION_SHORT_PACKET: u32 = 13;
+const COMPLETION_MISSED_SERVICE: u32 = 23;
+
+const EP_CONTROL: u32 = 4;
+const EP_ISOCH: u32 = 1;
+
 #[repr(packed)]
 struct Ste {
-    pub ptr: u64,
-    pub length: u64,
+    pub ptr: Mmio<u64>,
+    pub length: Mmio<u64>,
 }
 
 #[repr(packed)]
 struct Trb {
-    pub data: u64,
-    pub status: u32,
-    pub control: u32,
+    pub data: Mmio<u64>,
+    pub status: Mmio<u32>,
+    pub control: Mmio<u32>,
 }
 
-impl Trb {
-    pub fn new() -> Self {
-        Trb {
-            data: 0,
-            status: 0,
-            control: 0,
+/// A ring of TRBs for the controller to consume, wrapped by a link TRB that toggles the cycle
+struct Ring {
+    trbs: Memory<Trb>,
+    enqueue: usize,
+    cycle: bool,
+}
+
+impl Ring {
+    fn new() -> Result<Ring> {
+        let mut trbs = try!(Memory::<Trb>::new_aligned(RING_SIZE, 64));
+        for i in 0..RING_SIZE {
+            trbs[i].data.write(0);
+            trbs[i].status.write(0);
+            trbs[i].control.write(0);
         }
+
+        let address = physical(trbs.address()) as u64;
+        trbs[RING_SIZE - 1].data.write(address);
+        trbs[RING_SIZE - 1].control.write(TRB_LINK << 10 | TRB_TOGGLE_CYCLE);
+
+        Ok(Ring {
+            trbs: trbs,
+            enqueue: 0,
+            cycle: true,
+        })
+    }
+
+    /// The dequeue pointer the controller should start from, with the cycle it should expect
+    fn pointer(&self) -> u64 {
+        (physical(self.trbs.address()) as usize + self.enqueue * mem::size_of::<Trb>()) as u64 | self.cycle as u64
     }
 
-    pub fn from_type(trb_type: u32) -> Self {
-        Trb {
-            data: 0,
-            status: 0,
-            control: (trb_type & 0x3F) << 10,
+    /// Queue a TRB, returning its address
+    ///
+    /// The control word, which holds the cycle bit, is written last so the controller never sees half a TRB.
+    fn push(&mut self, data: u64, status: u32, control: u32) -> u64 {
+        let address = self.pointer() & ! 0xF;
+        let cycle = self.cycle as u32;
+
+        let trb = &mut self.trbs[self.enqueue];
+        trb.data.write(data);
+        trb.status.write(status);
+        trb.control.write((control & ! TRB_CYCLE) | cycle);
+
+        self.enqueue += 1;
+        if self.enqueue == RING_SIZE - 1 {
+            let link = &mut self.trbs[RING_SIZE - 1];
+            let control = link.control.read();
+            link.control.write((control & ! TRB_CYCLE) | cycle);
+            self.enqueue = 0;
+            self.cycle = ! self.cycle;
         }
+
+        address
     }
 }
 
+/// The slot of a device, with its contexts and transfer rings
+struct XhciDevice {
+    /// The address the device is known by, 0 while it is enumerated
+    address: u8,
+    /// The root port the device is reached through
+    port: u8,
+    /// The hub ports from the root port to the device, a nibble for each tier
+    route: u32,
+    /// The slot and port of the high speed hub whose transaction translator reaches a full or low speed device
+    tt: u32,
+    slot: u8,
+    speed: Speed,
+    input: Memory<u8>,
+    output: Memory<u8>,
+    /// The transfer ring of each endpoint, by device context index
+    rings: Vec<(u8, Ring)>,
+    /// The highest device context index in use
+    context_entries: u8,
+}
+
+/// An interrupt endpoint with a TRB queued on its ring, which the controller runs at the interval of the endpoint
+struct XhciPipe {
+    id: usize,
+    address: u8,
+    dci: u8,
+    buffer: Memory<u8>,
+    /// The TRB waiting for a report
+    trb: u64,
+    /// The completion code and residue of the TRB, once it is done
+    completion: Option<u32>,
+}
+
+/// An isochronous endpoint, with a TRB on its ring for each packet queued
+struct XhciStream {
+    id: usize,
+    address: u8,
+    dci: u8,
+    max_packet_size: u16,
+    /// The buffers in the order they were queued, each with the copy the controller moves and the TRB of each packet
+    queued: Vec<(IsochBuffer, Memory<u8>, Vec<u64>)>,
+}
+
+/// Events kept for whoever waits for them, when they were taken from the ring while looking for other events,
+/// past which the waits are taken to have lost them
+const STRAY_EVENTS: usize = 16;
+
 pub struct Xhci {
     pub pci: PciConfig,
     pub base: usize,
     pub irq: u8,
+    op_base: usize,
+    db_base: usize,
+    rt_base: usize,
+    ports: usize,
+    /// Bytes in each context, 32 or 64
+    context_size: usize,
+    dcbaa: Memory<Mmio<u64>>,
+    scratchpad_array: Option<Memory<Mmio<u64>>>,
+    scratchpads: Vec<Memory<u8>>,
+    command_ring: Option<Ring>,
+    event_ring: Memory<Trb>,
+    event_dequeue: usize,
+    event_cycle: bool,
+    erst: Memory<Ste>,
+    /// Set while a command, a transfer or a poll of the pipes and streams has the rings
+    busy: bool,
+    devices: Vec<XhciDevice>,
+    pipes: Vec<XhciPipe>,
+    streams: Vec<XhciStream>,
+    next_pipe: usize,
+    stray: Vec<(u64, u32, u32)>,
+    watchdog: Watchdog,
 }
 
 impl KScheme for Xhci {
-    fn on_irq(&mut self, irq: u8) {
+    fn on_irq(&mut self, irq: u8) -> bool {
         if irq == self.irq {
             debug!("XHCI handle\n");
         }
+        irq == self.irq
+    }
+
+    /// Halt the controller, so it stops running the rings
+    fn power_off(&mut self) {
+        let usb_cmd = self.reg(self.op_base);
+        let usb_sts = self.reg(self.op_base + 4);
+        usb_cmd.writef(CMD_RS, false);
+        if ! wait_for(20, || usb_sts.readf(STS_HCH)) {
+            syslog_warning!("XHCI: Halt timed out");
+        }
     }
 }
 
 impl Xhci {
     pub unsafe fn new(mut pci: PciConfig) -> Box<Xhci> {
+        pci.flag(4, 4, true); // Bus mastering
+
+        let bar = pci.bar(0);
+        let base = bar.base;
+        let op_base = base + (*(base as *const Mmio<u8>)).read() as usize;
+        // Only the port registers that fit in the BAR are used
+        let ports = cmp::min(((*((base + 4) as *const Mmio<u32>)).read() >> 24) as usize,
+                             (base + bar.size).saturating_sub(op_base + 0x400) / 0x10);
         let mut module = box Xhci {
             pci: pci,
-            base: pci.read(0x10) as usize & 0xFFFFFFF0,
-            irq: pci.read(0x3C) as u8 & 0xF,
+            base: base,
+            irq: pci.irq(),
+            op_base: op_base,
+            db_base: base + ((*((base + 0x14) as *const Mmio<u32>)).read() & ! 0b11) as usize,
+            rt_base: base + ((*((base + 0x18) as *const Mmio<u32>)).read() & ! 0x1F) as usize,
+            ports: ports,
+            context_size: if (*((base + 0x10) as *const Mmio<u32>)).readf(1 << 2) { 64 } else { 32 },
+            dcbaa: Memory::new_aligned(256, 64).unwrap(),
+            scratchpad_array: None,
+            scratchpads: Vec::new(),
+            command_ring: None,
+            event_ring: Memory::new_aligned(RING_SIZE, 64).unwrap(),
+            event_dequeue: 0,
+            event_cycle: true,
+            erst: Memory::new_aligned(1, 64).unwrap(),
+            busy: false,
+            devices: Vec::new(),
+            pipes: Vec::new(),
+            streams: Vec::new(),
+            next_pipe: 1,
+            stray: Vec::new(),
+            watchdog: Watchdog::new("XHCI", base),
         };
         module.init();
         module
     }
 
     pub unsafe fn init(&mut self) {
-        syslog_info!(" + XHCI on: {:X}, IRQ: {:X}", self.base, self.irq);
+        syslog_info!(" + XHCI on: {:X}, IRQ: {:X}, Ports: {}", self.base, self.irq, self.ports);
+
+        self.watchdog.register();
+
+        // The resets and port delays need to sleep, so they are done once the scheduler runs
+        let this = self as *mut Xhci;
+        Context::spawn("kxhci".into(), box move || {
+            if let Err(err) = (*this).reset() {
+                syslog_warning!("XHCI: Failed to start: {}", err);
+                return;
+            }
+            loop {
+                (*this).check();
+                (*this).port_changes();
+                delay(250);
+            }
+        });
+    }
 
-        /*
-        self.pci.flag(4, 4, true); // Bus mastering
+    fn reg(&self, address: usize) -> &'static mut Mmio<u32> {
+        unsafe { &mut *(address as *mut Mmio<u32>) }
+    }
 
-        let cap_base = self.base;
-        let op_base = cap_base + *(cap_base as *mut u8) as usize;
-        let db_base = cap_base + *((cap_base + 0x14) as *mut u32) as usize;
-        let rt_base = cap_base + *((cap_base + 0x18) as *mut u32) as usize;
+    fn write64(&self, address: usize, value: u64) {
+        self.reg(address).write(value as u32);
+        self.reg(address + 4).write((value >> 32) as u32);
+    }
 
-        d("CAP_BASE: ");
-        dh(cap_base);
+    fn port(&self, i: usize) -> &'static mut Mmio<u32> {
+        self.reg(self.op_base + 0x400 + i * 0x10)
+    }
 
-        d(" OP_BASE: ");
-        dh(op_base);
+    /// Take the controller from the firmware, if the firmware has it
+    unsafe fn handoff(&mut self) {
+        let hcc_params = self.reg(self.base + 0x10).read();
+        let mut offset = (hcc_params >> 16) as usize * 4;
+        while offset != 0 {
+            let cap = self.reg(self.base + offset);
+            let value = cap.read();
+            if value & 0xFF == EXT_CAP_LEGACY {
+                if value & LEGACY_BIOS_OWNED == LEGACY_BIOS_OWNED {
+                    syslog_info!("XHCI: Taking the controller from the firmware");
+                    cap.writef(LEGACY_OS_OWNED, true);
+                    if ! wait_for(1000, || ! cap.readf(LEGACY_BIOS_OWNED)) {
+                        // Take it anyway, and keep the firmware from being called into through SMIs
+                        syslog_warning!("XHCI: Firmware did not release the controller, forcing the handoff");
+                        cap.writef(LEGACY_BIOS_OWNED, false);
+                        let ctl_sts = self.reg(self.base + offset + 4);
+                        let value = ctl_sts.read();
+                        ctl_sts.write(value & ! LEGACY_SMI_ENABLES);
+                    }
+                } else {
+                    cap.writef(LEGACY_OS_OWNED, true);
+                }
+                return;
+            }
 
-        d(" DB_BASE: ");
-        dh(db_base);
+            let next = ((value >> 8) & 0xFF) as usize * 4;
+            offset = if next == 0 { 0 } else { offset + next };
+        }
+    }
 
-        d(" RT_BASE: ");
-        dh(rt_base);
-        dl();
+    /// Reset the controller and start it with a command ring and an event ring
+    unsafe fn reset(&mut self) -> Result<()> {
+        self.handoff();
 
-        //Set FLADJ Frame Length Adjustment (optional?)
-        //Set I/O memory maps (optional?)
+        let usb_cmd = self.reg(self.op_base);
+        let usb_sts = self.reg(self.op_base + 4);
 
-        //Wait until the Controller Not Ready flag in USBSTS is 0
-        let usbsts = (op_base + 0x04) as *mut u32;
-        while *usbsts & (1 << 11) == (1 << 11) {
-            d("Controller Not Ready\n");
+        usb_cmd.writef(CMD_RS, false);
+        if ! wait_for(20, || usb_sts.readf(STS_HCH)) {
+            syslog_warning!("XHCI: Halt timed out");
+            return Err(Error::new(ETIMEDOUT));
         }
-        d("Controller Ready ");
-        dh(*usbsts as usize);
-        dl();
 
-        //Set Run/Stop to 0
-        let usbcmd = op_base as *mut u32;
-        *usbcmd = *usbcmd & 0xFFFFFFFE;
+        usb_cmd.writef(CMD_HCRST, true);
+        if ! wait_for(1000, || ! usb_cmd.readf(CMD_HCRST) && ! usb_sts.readf(STS_CNR)) {
+            syslog_warning!("XHCI: Reset timed out");
+            return Err(Error::new(ETIMEDOUT));
+        }
+
+        // Left from before an earlier reset, the controller has forgotten them
+        self.scratchpad_array = None;
+        self.scratchpads.clear();
+        self.devices.clear();
+        self.pipes.clear();
+        self.streams.clear();
+        self.stray.clear();
+        self.event_dequeue = 0;
+        self.event_cycle = true;
+
+        let max_slots = self.reg(self.base + 4).read() & 0xFF;
+        self.reg(self.op_base + 0x38).write(max_slots);
+
+        for i in 0..self.dcbaa.len() {
+            self.dcbaa[i].write(0);
+        }
 
-        while *usbsts & 1 == 0 {
-            d("Command Not Ready\n");
+        // The controller keeps its own state in scratchpad pages, listed in the first entry
+        let hcs_params2 = self.reg(self.base + 8).read();
+        let scratchpads = ((hcs_params2 >> 21) & 0x1F) << 5 | (hcs_params2 >> 27) & 0x1F;
+        if scratchpads > 0 {
+            let mut array = try!(Memory::<Mmio<u64>>::new_aligned(scratchpads as usize, 64));
+            for i in 0..scratchpads as usize {
+                let mut page = try!(Memory::<u8>::new_aligned(4096, 4096));
+                for j in 0..4096 {
+                    page[j] = 0;
+                }
+                array[i].write(physical(page.address()) as u64);
+                self.scratchpads.push(page);
+            }
+            self.dcbaa[0].write(physical(array.address()) as u64);
+            self.scratchpad_array = Some(array);
         }
+        let dcbaap = physical(self.dcbaa.address()) as u64;
+        self.write64(self.op_base + 0x30, dcbaap);
+
+        let command_ring = try!(Ring::new());
+        self.write64(self.op_base + 0x18, command_ring.pointer());
+        self.command_ring = Some(command_ring);
 
-        d("Command Ready ");
-        dh(*usbcmd as usize);
-        dl();
+        for i in 0..RING_SIZE {
+            self.event_ring[i].data.write(0);
+            self.event_ring[i].status.write(0);
+            self.event_ring[i].control.write(0);
+        }
+        self.erst[0].ptr.write(physical(self.event_ring.address()) as u64);
+        self.erst[0].length.write(RING_SIZE as u64);
+
+        // Interrupter 0 delivers every event, which are polled
+        let interrupter = self.rt_base + 0x20;
+        self.reg(interrupter + 8).write(1);
+        let event_ring = physical(self.event_ring.address()) as u64;
+        self.write64(interrupter + 0x18, event_ring);
+        let erst = physical(self.erst.address()) as u64;
+        self.write64(interrupter + 0x10, erst);
+
+        usb_cmd.writef(CMD_RS, true);
+        if ! wait_for(20, || ! usb_sts.readf(STS_HCH)) {
+            syslog_warning!("XHCI: Start timed out");
+            return Err(Error::new(ETIMEDOUT));
+        }
+
+        // Ports with power switching are powered off after the reset
+        for i in 0..self.ports {
+            let port = self.port(i);
+            if ! port.readf(PORT_PP) {
+                port.write(PORT_PP);
+            }
+        }
+        delay(20);
 
-        //Program the Max Device Slots Enabled in the CONFIG register
-        let hcsparams1 = (cap_base + 0x04) as *const u32;
-        let max_slots = *hcsparams1 & 0xFF;
-        let max_ports = (*hcsparams1 >> 24) & 0xFF;
+        Ok(())
+    }
 
-        d("Max Slots ");
-        dd(max_slots as usize);
-        dl();
+    /// Take the controller for work on its rings, which fails while the watchdog resets it
+    ///
+    /// The reset clears the devices, pipes and streams, so whatever would be queued is gone with them.
+    fn take(&mut self) -> Result<Held> {
+        let held = hold(&mut self.busy);
+        if self.watchdog.resetting {
+            Err(Error::new(ENODEV))
+        } else {
+            Ok(held)
+        }
+    }
 
-        d("Max Ports ");
-        dd(max_ports as usize);
-        dl();
+    /// The next event, if the controller has written one
+    ///
+    /// The completions of pipes and stream packets are kept with them, so waits for other events never take them.
+    fn event(&mut self) -> Option<(u64, u32, u32)> {
+        while let Some((data, status, control)) = self.next_event() {
+            if (control >> 10) & 0x3F == TRB_TRANSFER_EVENT {
+                if let Some(pipe) = self.pipes.iter_mut().find(|pipe| pipe.completion.is_none() && pipe.trb == data) {
+                    pipe.completion = Some(status);
+                    continue;
+                }
+                if self.isoch_event(data, status) {
+                    continue;
+                }
+            }
+            return Some((data, status, control));
+        }
 
-        let config = (op_base + 0x38) as *mut u32;
-        *config = max_slots;
-        d("Slots Enabled ");
-        dd(*config as usize);
-        dl();
+        None
+    }
 
-        //Program the Device Context Base Address Array Pointer with a pointer to the Device Context Base Address Array
-        let device_context_base_address_array = alloc(max_slots as usize * size_of::<u64>()) as *mut u64;
-        for slot in 0..max_slots as isize {
-            *device_context_base_address_array.offset(slot) = alloc(2048) as u64;
+    /// Record the completion of the stream packet whose TRB is at `trb`, returning whether there is one
+    fn isoch_event(&mut self, trb: u64, status: u32) -> bool {
+        for stream in self.streams.iter_mut() {
+            for &mut (ref mut buffer, _, ref trbs) in stream.queued.iter_mut() {
+                if let Some(k) = trbs.iter().position(|&other| other == trb) {
+                    let packet = &mut buffer.packets[k];
+                    let residue = cmp::min(packet.length as u32, status & 0xFFFFFF) as u16;
+                    packet.status = match status >> 24 {
+                        COMPLETION_SUCCESS | COMPLETION_SHORT_PACKET => IsochStatus::Done(packet.length - residue),
+                        COMPLETION_MISSED_SERVICE => IsochStatus::Missed,
+                        _ => IsochStatus::Failed,
+                    };
+                    return true;
+                }
+            }
         }
+        false
+    }
 
-        let dcbaap = (op_base + 0x30) as *mut u64;
-        *dcbaap = device_context_base_address_array as u64;
+    /// Take what the controller has written, the events that are not about pipes or streams are left for their waits
+    ///
+    /// Port changes are read from the port registers, so their events are let go. Every other event is kept,
+    /// however many there are, until the watchdog sees too many and resets the controller.
+    fn collect_events(&mut self) {
+        while let Some(event) = self.event() {
+            if (event.2 >> 10) & 0x3F != TRB_PORT_STATUS_CHANGE {
+                self.stray.push(event);
+            }
+        }
+    }
 
-        d("Set Device Context Base Address Array ");
-        dh(*dcbaap as usize);
-        dl();
+    fn next_event(&mut self) -> Option<(u64, u32, u32)> {
+        let (data, status, control) = {
+            let trb = &self.event_ring[self.event_dequeue];
+            if (trb.control.read() & TRB_CYCLE == TRB_CYCLE) != self.event_cycle {
+                return None;
+            }
+            (trb.data.read(), trb.status.read(), trb.control.read())
+        };
 
-        //Define the Command Ring Dequeue Pointer by programming the Command ring Control register with a pointer to the first Trb
-        let command_ring_length = 256;
-        let mut command_ring_offset = 0;
-        let command_ring = alloc(command_ring_length * size_of::<Trb>()) as *mut Trb;
-        for i in 0..command_ring_length {
-            *command_ring.offset(i as isize) = Trb::new();
-            d("."); //Timing issue?
+        self.event_dequeue += 1;
+        if self.event_dequeue == RING_SIZE {
+            self.event_dequeue = 0;
+            self.event_cycle = ! self.event_cycle;
         }
-        dl();
 
-        let crcr = (op_base + 0x18) as *mut u64;
-        *crcr = command_ring as u64;
-        d("Set Command Ring Dequeue Pointer ");
-        dh(*crcr as usize);
-        dl();
+        // Writing the dequeue pointer, with the busy bit, tells the controller the event was handled
+        let erdp = (physical(self.event_ring.address()) as usize + self.event_dequeue * mem::size_of::<Trb>()) as u64 | 1 << 3;
+        let interrupter = self.rt_base + 0x20;
+        self.write64(interrupter + 0x18, erdp);
 
-        //Define the Event Ring for interrupter 0
-        let event_ring_segments = 1;
-        let event_ring_segment_table = alloc(event_ring_segments * size_of::<Ste>()) as *mut Ste;
-        let mut event_ring_dequeue = 0;
+        Some((data, status, control))
+    }
 
-        for segment in 0..event_ring_segments {
-            let ste = &mut *event_ring_segment_table.offset(segment as isize);
-            ste.length = 256;
-            ste.ptr = alloc(ste.length as usize * size_of::<Trb>()) as u64;
+    /// Wait for an event of `trb_type` about the TRB at `address`, or any event about `slot` if `address` is 0
+    fn wait_event(&mut self, trb_type: u32, address: u64, slot: u8, timeout: i32) -> Result<(u64, u32, u32)> {
+        let matches = |&(data, _, control): &(u64, u32, u32)| {
+            (control >> 10) & 0x3F == trb_type && (data == address || (address == 0 && (control >> 24) as u8 == slot))
+        };
 
-            for i in 0..ste.length as isize {
-                *(ste.ptr as *mut Trb).offset(i) = Trb::new();
-                dd(i as usize);
-                d(" ");
+        let end = Duration::monotonic() + Duration::new(0, timeout * time::NANOS_PER_MILLI);
+        loop {
+            if let Some(j) = self.stray.iter().position(&matches) {
+                return Ok(self.stray.remove(j));
+            }
+            while let Some(event) = self.event() {
+                if matches(&event) {
+                    return Ok(event);
+                } else if (event.2 >> 10) & 0x3F != TRB_PORT_STATUS_CHANGE {
+                    self.stray.push(event);
+                }
             }
-            dl();
 
-            if segment == 0 {
-                event_ring_dequeue = ste.ptr;
+            // The events of a removed device may never come, nor those of a controller being reset
+            if self.watchdog.resetting {
+                return Err(Error::new(ENODEV));
             }
+            let hci = self as *mut Xhci as *mut UsbHc;
+            if address == 0 && self.devices.iter().any(|device| device.slot == slot && usb_cancelled(hci, device.address)) {
+                return Err(Error::new(ENODEV));
+            }
+            if Duration::monotonic() > end {
+                return Err(Error::new(ETIMEDOUT));
+            }
+            unsafe { context_switch() };
         }
+    }
+
+    /// Run a command, returning the slot id of its completion
+    fn command(&mut self, data: u64, control: u32) -> Result<u8> {
+        if self.watchdog.resetting {
+            return Err(Error::new(ENODEV));
+        }
+        let address = match self.command_ring {
+            Some(ref mut ring) => ring.push(data, 0, control),
+            None => return Err(Error::new(ENODEV)),
+        };
+        self.reg(self.db_base).write(0);
+
+        let result = self.wait_event(TRB_COMMAND_COMPLETION, address, 0, TRANSFER_TIMEOUT);
+        self.watchdog.completed(result.as_ref().err().map_or(false, |err| err.errno == ETIMEDOUT));
+        let (_, status, control) = try!(result);
+        if status >> 24 == COMPLETION_SUCCESS {
+            Ok((control >> 24) as u8)
+        } else {
+            syslog_debug!("XHCI: Command {} failed: {}", (control >> 10) & 0x3F, status >> 24);
+            Err(Error::new(EIO))
+        }
+    }
+
+    /// A dword of context `index` in the input or output context at `base`
+    fn context(&self, base: usize, index: usize, dword: usize) -> &'static mut Mmio<u32> {
+        self.reg(base + index * self.context_size + dword * 4)
+    }
 
-        let erstsz = (rt_base + 0x28) as *mut u32;
-        *erstsz = event_ring_segments as u32;
+    /// Reset the controller if it has halted or reported an error, its microframe index has stopped, or its
+    /// commands no longer complete
+    unsafe fn check(&mut self) {
+        let sts = self.reg(self.op_base + 4).read();
+        let reason = if sts & (STS_HCH | STS_HSE | STS_HCE) != 0 {
+            format!("Halted, status {:X}", sts)
+        } else if self.watchdog.frame_stuck(self.reg(self.rt_base).read() & 0x3FFF) {
+            format!("Microframe index stopped at {}", self.reg(self.rt_base).read() & 0x3FFF)
+        } else if self.watchdog.timeouts >= WATCHDOG_TIMEOUTS {
+            format!("{} commands timed out", self.watchdog.timeouts)
+        } else if self.stray.len() > STRAY_EVENTS {
+            format!("{} events were never waited for", self.stray.len())
+        } else {
+            return;
+        };
+
+        if self.watchdog.allow(reason) {
+            self.restart();
+        }
+    }
 
-        let erdp = (rt_base + 0x38) as *mut u64;
-        *erdp = event_ring_dequeue;
+    /// Reset the controller and enumerate the devices on it again
+    ///
+    /// Every device is disconnected first, its transfers fail and its slot is dropped without a command.
+    unsafe fn restart(&mut self) {
+        self.watchdog.resetting = true;
 
-        let erstba = (rt_base + 0x30) as *mut u64;
-        *erstba = event_ring_segment_table as u64;
+        let hci = self as *mut Xhci as *mut UsbHc;
+        usb_cancel(hci, 0);
+        for i in 0..self.ports {
+            self.port_disconnected(i as u8 + 1);
+        }
 
-        d("Set Event Ring Segment Table ");
-        dh(*erstba as usize);
-        d(" ");
-        dd(*erstsz as usize);
-        d(" ");
-        dh(*erdp as usize);
-        dl();
+        let result = {
+            let _held = hold(&mut self.busy);
+            let result = self.reset();
+            usb_reuse(hci, 0);
+            self.watchdog.resetting = false;
+            result
+        };
 
-        //Write the USBCMD to turn on the host controller by setting Run/Stop to 1
-        let usbcmd = op_base as *mut u32;
-        *usbcmd = *usbcmd | 1;
+        if let Err(err) = result {
+            syslog_warning!("XHCI: Failed to restart: {}", err);
+            self.watchdog.failed += 1;
+            return;
+        }
 
-        while *usbsts & 1 != 0 {
-            d("Not Running\n");
+        for i in 0..self.ports {
+            if self.port(i).readf(PORT_CCS) {
+                self.port_change(i);
+            }
         }
+    }
 
-        d("Running ");
-        dh(*usbcmd as usize);
-        dl();
+    /// Handle connects and disconnects on the root ports
+    unsafe fn port_changes(&mut self) {
+        for i in 0..self.ports {
+            if self.port(i).readf(PORT_CSC) {
+                self.port_change(i);
+            }
+        }
+    }
 
-        for i in 0..max_ports as usize {
-            let portsc = (op_base + 0x400 + (0x10 * i)) as *mut u32;
-            d("Port ");
-            dd(i + 1);
-            d(" is ");
-            dh(*portsc as usize);
-            dl();
+    /// Forget the device that was on port `i`, and enumerate the one on it now
+    unsafe fn port_change(&mut self, i: usize) {
+        let port = self.port(i);
+        let status = port.read();
+        port.write((status & ! PORT_CHANGE) | PORT_CSC);
 
-            if *portsc & 1 == 1 {
-                d("Connected\n");
+        self.port_disconnected(i as u8 + 1);
+        if status & PORT_CCS == PORT_CCS {
+            // Let the connection settle before the reset
+            delay(100);
+            usb_enumerate_begin();
+            match self.reset_port(i) {
+                Some(speed) => {
+                    syslog_info!("XHCI: {:?} speed device on port {}", speed, i + 1);
+                    if let Err(err) = self.port_connected(i as u8 + 1, speed) {
+                        syslog_warning!("XHCI: Failed to enumerate port {}: {}", i + 1, err);
+                    }
+                },
+                None => syslog_warning!("XHCI: Port {} could not be enabled", i + 1),
             }
-            if *portsc & 2 == 2 {
-                d("Enabled\n");
+            usb_enumerate_end();
+        } else {
+            syslog_info!("XHCI: Device removed from port {}", i + 1);
+        }
+    }
+
+    /// Reset port `i`, returning the speed of its device
+    ///
+    /// Super speed ports enable themselves once the link is trained, others need a reset.
+    unsafe fn reset_port(&mut self, i: usize) -> Option<Speed> {
+        let port = self.port(i);
+        if ! port.readf(PORT_PED) {
+            port.write((port.read() & ! PORT_CHANGE) | PORT_PR);
+            if ! wait_for(100, || port.readf(PORT_PRC)) {
+                return None;
             }
-            if *portsc & 3 == 3 {
-                d("Enabling slot\n");
+            port.write((port.read() & ! PORT_CHANGE) | PORT_PRC);
+            // The device is given 10 ms to recover from the reset
+            delay(10);
+        }
 
-                *command_ring.offset(command_ring_offset as isize) = Trb::from_type(23);
-                command_ring_offset += 1;
-                if command_ring_offset >= command_ring_length {
-                    command_ring_offset = 0;
+        let status = port.read();
+        if status & (PORT_CCS | PORT_PED) != PORT_CCS | PORT_PED {
+            return None;
+        }
+
+        match (status >> 10) & 0xF {
+            1 => Some(Speed::Full),
+            2 => Some(Speed::Low),
+            3 => Some(Speed::High),
+            _ => Some(Speed::Super),
+        }
+    }
+
+    /// Give the device on `port` a slot with a default pipe, without addressing it yet
+    fn enable_slot(&mut self, port: u8, route: u32, tt: u32, speed: Speed) -> Result<()> {
+        let slot = try!(self.command(0, TRB_ENABLE_SLOT << 10));
+
+        let mut input = try!(Memory::<u8>::new_aligned(33 * self.context_size, 64));
+        let mut output = try!(Memory::<u8>::new_aligned(32 * self.context_size, 64));
+        for i in 0..input.len() {
+            input[i] = 0;
+        }
+        for i in 0..output.len() {
+            output[i] = 0;
+        }
+        self.dcbaa[slot as usize].write(physical(output.address()) as u64);
+        let input_base = input.address();
+
+        let ring = try!(Ring::new());
+        let (speed_id, max_packet_size) = match speed {
+            Speed::Full => (1, 8),
+            Speed::Low => (2, 8),
+            Speed::High => (3, 64),
+            Speed::Super => (4, 512),
+        };
+
+        // Add the slot context and the default pipe
+        self.context(input_base, 0, 1).write(0b11);
+        self.context(input_base, 1, 0).write(1 << 27 | speed_id << 20 | route & 0xFFFFF);
+        self.context(input_base, 1, 1).write((port as u32) << 16);
+        self.context(input_base, 1, 2).write(tt & 0xFFFF);
+        self.context(input_base, 2, 1).write(max_packet_size << 16 | EP_CONTROL << 3 | 3 << 1);
+        let dequeue = ring.pointer();
+        self.context(input_base, 2, 2).write(dequeue as u32);
+        self.context(input_base, 2, 3).write((dequeue >> 32) as u32);
+        self.context(input_base, 2, 4).write(8);
+
+        // The request is blocked, so transfers to address 0 reach the device until it is addressed
+        let address = physical(input_base) as u64;
+        try!(self.command(address, TRB_ADDRESS_DEVICE << 10 | TRB_BSR | (slot as u32) << 24));
+
+        self.devices.push(XhciDevice {
+            address: 0,
+            port: port,
+            route: route,
+            tt: tt,
+            slot: slot,
+            speed: speed,
+            input: input,
+            output: output,
+            rings: vec![(1, ring)],
+            context_entries: 1,
+        });
+
+        Ok(())
+    }
+
+    fn device_index(&self, address: u8) -> Result<usize> {
+        match self.devices.iter().position(|device| device.address == address) {
+            Some(i) => Ok(i),
+            None => Err(Error::new(ENODEV)),
+        }
+    }
+
+    /// Bring a halted or abandoned endpoint back to the start of its next transfer
+    fn recover(&mut self, i: usize, dci: u8, halted: bool) {
+        let slot = self.devices[i].slot as u32;
+        let command = if halted {
+            TRB_RESET_ENDPOINT
+        } else {
+            TRB_STOP_ENDPOINT
+        };
+        let _ = self.command(0, command << 10 | (dci as u32) << 16 | slot << 24);
+
+        let dequeue = match self.devices[i].rings.iter().find(|&&(ring_dci, _)| ring_dci == dci) {
+            Some(&(_, ref ring)) => ring.pointer(),
+            None => return,
+        };
+        let _ = self.command(dequeue, TRB_SET_DEQUEUE << 10 | (dci as u32) << 16 | slot << 24);
+    }
+
+    /// Queue the TRB of pipe `i` for its next report
+    fn arm(&mut self, i: usize) -> Result<()> {
+        let (address, dci) = (self.pipes[i].address, self.pipes[i].dci);
+        let (buffer, len) = (physical(self.pipes[i].buffer.address()) as u64, self.pipes[i].buffer.len() as u32);
+
+        let j = try!(self.device_index(address));
+        let slot = self.devices[j].slot;
+        let trb = match self.devices[j].rings.iter_mut().find(|&&mut (ring_dci, _)| ring_dci == dci) {
+            Some(&mut (_, ref mut ring)) => ring.push(buffer, len, TRB_NORMAL << 10 | TRB_ISP | TRB_IOC),
+            None => return Err(Error::new(ENODEV)),
+        };
+
+        self.pipes[i].trb = trb;
+        self.pipes[i].completion = None;
+        self.reg(self.db_base + slot as usize * 4).write(dci as u32);
+
+        Ok(())
+    }
+
+    /// Queue `trbs` on an endpoint ring and wait for the last one
+    ///
+    /// Returns the bytes transferred by each TRB that reports a length.
+    fn transfer(&mut self, address: u8, dci: u8, trbs: &[(u64, u32, u32)], timeout: i32) -> Result<usize> {
+        let i = try!(self.device_index(address));
+        let slot = self.devices[i].slot;
+
+        let mut addresses = Vec::new();
+        {
+            let ring = match self.devices[i].rings.iter_mut().find(|&&mut (ring_dci, _)| ring_dci == dci) {
+                Some(&mut (_, ref mut ring)) => ring,
+                None => return Err(Error::new(ENODEV)),
+            };
+            for &(data, status, control) in trbs.iter() {
+                addresses.push(ring.push(data, status, control));
+            }
+        }
+        let last = match addresses.last() {
+            Some(&last) => last,
+            None => return Ok(0),
+        };
+
+        self.reg(self.db_base + slot as usize * 4).write(dci as u32);
+
+        let mut count = 0;
+        loop {
+            let (data, status, _) = match self.wait_event(TRB_TRANSFER_EVENT, 0, slot, timeout) {
+                Ok(event) => event,
+                Err(err) => {
+                    // The slot of a removed device is disabled, which stops its rings
+                    if err.errno != ENODEV {
+                        self.recover(i, dci, false);
+                    }
+                    return Err(err);
+                }
+            };
+
+            // Events left from an earlier transfer that was cut short are skipped
+            let j = match addresses.iter().position(|&address| address == data) {
+                Some(j) => j,
+                None => continue,
+            };
+
+            // The event gives the bytes left over of the TRB it is about
+            let length = (trbs[j].1 & 0x1FFFF) as usize;
+            let residue = (status & 0xFFFFFF) as usize;
+            count += length - cmp::min(length, residue);
+
+            match status >> 24 {
+                COMPLETION_SUCCESS if data == last => return Ok(count),
+                COMPLETION_SUCCESS => (),
+                // A short packet ends a normal transfer, the status stage of a control transfer still follows
+                COMPLETION_SHORT_PACKET if data == last || (trbs[j].2 >> 10) & 0x3F == TRB_NORMAL => return Ok(count),
+                COMPLETION_SHORT_PACKET => (),
+                COMPLETION_STALL => {
+                    self.recover(i, dci, true);
+                    return Err(Error::new(EPIPE));
+                },
+                code => {
+                    syslog_debug!("XHCI: Transfer failed: {}", code);
+                    self.recover(i, dci, true);
+                    return Err(Error::new(EIO));
+                }
+            }
+        }
+    }
+}
+
+impl UsbHc for Xhci {
+    fn msg(&mut self, address: u8, endpoint: u8, pipe: Pipe, msgs: &[Packet]) -> Result<usize> {
+        let _held = try!(self.take());
+
+        let timeout = match pipe {
+            Pipe::Interrupt => INTERRUPT_TIMEOUT,
+            _ => TRANSFER_TIMEOUT,
+        };
+
+        let mut trbs = Vec::new();
+        let mut dci = endpoint * 2;
+        match pipe {
+            Pipe::Control => {
+                dci = 1;
+                for (i, msg) in msgs.iter().enumerate() {
+                    match *msg {
+                        Packet::Setup(setup) => {
+                            // The setup packet is held in the TRB, the transfer type comes from the data stage
+                            let transfer_type = match msgs.get(i + 1) {
+                                Some(&Packet::In(ref data)) if ! data.is_empty() => 3,
+                                Some(&Packet::Out(ref data)) if ! data.is_empty() => 2,
+                                _ => 0,
+                            };
+                            let data = unsafe { ptr::read(setup as *const Setup as *const u64) };
+                            trbs.push((data, 8, transfer_type << 16 | TRB_SETUP << 10 | TRB_IDT));
+                        },
+                        Packet::In(ref data) if ! data.is_empty() => {
+                            trbs.push((physical(data.as_ptr() as usize) as u64, data.len() as u32,
+                                       TRB_DIR_IN | TRB_DATA << 10 | TRB_IOC | TRB_ISP));
+                        },
+                        Packet::Out(ref data) if ! data.is_empty() => {
+                            trbs.push((physical(data.as_ptr() as usize) as u64, data.len() as u32,
+                                       TRB_DATA << 10 | TRB_IOC | TRB_ISP));
+                        },
+                        Packet::In(_) => trbs.push((0, 0, TRB_DIR_IN | TRB_STATUS << 10 | TRB_IOC)),
+                        Packet::Out(_) => trbs.push((0, 0, TRB_STATUS << 10 | TRB_IOC)),
+                    }
                 }
+            },
+            _ => for msg in msgs.iter() {
+                let (ptr, len) = match *msg {
+                    Packet::In(ref data) => {
+                        dci = endpoint * 2 + 1;
+                        (data.as_ptr() as usize, data.len())
+                    },
+                    Packet::Out(ref data) => (data.as_ptr() as usize, data.len()),
+                    Packet::Setup(_) => continue,
+                };
 
-                *command_ring.offset(command_ring_offset as isize) = Trb::from_type(9);
-                command_ring_offset += 1;
-                if command_ring_offset >= command_ring_length {
-                   command_ring_offset = 0;
+                // Each TRB stays within a 64 KB boundary, the TRBs of a buffer are chained
+                let mut offset = 0;
+                loop {
+                    let address = physical(ptr + offset) as usize;
+                    let size = cmp::min(len - offset, TRB_MAX - (address & (TRB_MAX - 1)));
+                    offset += size;
+                    let chain = if offset < len { TRB_CHAIN } else { TRB_IOC };
+                    trbs.push((address as u64, size as u32, TRB_NORMAL << 10 | TRB_ISP | chain));
+                    if offset >= len {
+                        break;
+                    }
                 }
+            },
+        }
+
+        self.transfer(address, dci, &trbs, timeout)
+    }
+
+    fn attach(&mut self, hub: u8, port: u8, speed: Speed) -> Result<()> {
+        let _held = try!(self.take());
+        if hub == 0 {
+            return self.enable_slot(port, 0, 0, speed);
+        }
+
+        let (root_port, route, tt) = {
+            let parent = &self.devices[try!(self.device_index(hub))];
+            let depth = (32 - parent.route.leading_zeros() + 3) / 4;
+            let route = parent.route | (cmp::min(port, 15) as u32) << (depth * 4);
+            let tt = match speed {
+                Speed::Low | Speed::Full if parent.speed == Speed::High => parent.slot as u32 | (port as u32) << 8,
+                _ => parent.tt,
+            };
+            (parent.port, route, tt)
+        };
+        self.enable_slot(root_port, route, tt, speed)
+    }
+
+    fn detach(&mut self, address: u8) {
+        // A transfer to the device may still be waiting for its events, it ends once it sees the device is gone
+        let _held = hold(&mut self.busy);
+
+        if let Ok(i) = self.device_index(address) {
+            let device = self.devices.remove(i);
+            let _ = self.command(0, TRB_DISABLE_SLOT << 10 | (device.slot as u32) << 24);
+            self.dcbaa[device.slot as usize].write(0);
+        }
+        self.pipes.retain(|pipe| pipe.address != address);
+        self.streams.retain(|stream| stream.address != address);
+    }
+
+    fn set_hub(&mut self, address: u8, ports: u8, think_time: u8) -> Result<()> {
+        let _held = try!(self.take());
+        let i = try!(self.device_index(address));
+
+        let input = self.devices[i].input.address();
+        self.context(input, 0, 0).write(0);
+        self.context(input, 0, 1).write(1);
+        let value = self.context(input, 1, 0).read();
+        self.context(input, 1, 0).write(value | 1 << 26);
+        let value = self.context(input, 1, 1).read();
+        self.context(input, 1, 1).write((value & 0xFFFFFF) | (ports as u32) << 24);
+        let value = self.context(input, 1, 2).read();
+        self.context(input, 1, 2).write((value & ! (0b11 << 16)) | (think_time as u32 & 0b11) << 16);
+
+        let slot = self.devices[i].slot as u32;
+        let result = self.command(physical(input) as u64, TRB_CONFIGURE_ENDPOINT << 10 | slot << 24);
+        try!(result);
+        Ok(())
+    }
+
+    fn set_device(&mut self, address: u8, _speed: Speed, max_packet_size: u16) {
+        let _held = match self.take() {
+            Ok(held) => held,
+            Err(_) => return,
+        };
+        let i = match self.device_index(address) {
+            Ok(i) => i,
+            Err(_) => return,
+        };
+
+        // Only the packet size of the default pipe changes, once the device has told it
+        let input = self.devices[i].input.address();
+        let current = self.context(input, 2, 1).read() >> 16;
+        if address != 0 && current != max_packet_size as u32 {
+            self.context(input, 0, 0).write(0);
+            self.context(input, 0, 1).write(1 << 1);
+            let ep = self.context(input, 2, 1);
+            let value = ep.read();
+            ep.write((value & 0xFFFF) | (max_packet_size as u32) << 16);
+
+            let slot = self.devices[i].slot as u32;
+            let _ = self.command(physical(input) as u64, TRB_EVALUATE_CONTEXT << 10 | slot << 24);
+        }
+    }
+
+    fn set_endpoint(&mut self, address: u8, desc_end: &EndpointDescriptor) -> Result<()> {
+        let _held = try!(self.take());
+        let i = try!(self.device_index(address));
+
+        let endpoint = desc_end.number();
+        let direction_in = desc_end.is_in();
+        let dci = endpoint * 2 + direction_in as u8;
+        let transfer_type = (desc_end.attributes & 0b11) as u32;
+        let ep_type = transfer_type + if direction_in { 4 } else { 0 };
+        let max_packet_size = desc_end.max_packet_size() as u32;
+        // Isochronous packets are never retried
+        let error_count = if transfer_type == EP_ISOCH { 0 } else { 3 };
+        // An endpoint that is already there, from another alternate setting, is dropped and added again
+        let drop = if self.devices[i].rings.iter().any(|&(ring_dci, _)| ring_dci == dci) { 1 << dci } else { 0 };
+
+        // The interval is the exponent of the period
+        let interval = desc_end.period(self.devices[i].speed).trailing_zeros();
+
+        let ring = try!(Ring::new());
+        let dequeue = ring.pointer();
+        let context_entries = cmp::max(self.devices[i].context_entries, dci);
+
+        let input = self.devices[i].input.address();
+        let slot_context = self.context(input, 1, 0).read();
+        for j in 0..self.context_size / 4 {
+            self.context(input, 1 + dci as usize, j).write(0);
+        }
+        self.context(input, 0, 0).write(drop);
+        self.context(input, 0, 1).write(1 << dci | 1);
+        self.context(input, 1, 0).write((slot_context & 0x7FFFFFF) | (context_entries as u32) << 27);
+        self.context(input, 1 + dci as usize, 0).write(interval << 16);
+        self.context(input, 1 + dci as usize, 1).write(max_packet_size << 16 | ep_type << 3 | error_count << 1);
+        self.context(input, 1 + dci as usize, 2).write(dequeue as u32);
+        self.context(input, 1 + dci as usize, 3).write((dequeue >> 32) as u32);
+        self.context(input, 1 + dci as usize, 4).write(max_packet_size << 16 | max_packet_size);
+
+        let slot = self.devices[i].slot as u32;
+        let result = self.command(physical(input) as u64, TRB_CONFIGURE_ENDPOINT << 10 | slot << 24);
+        try!(result);
+
+        let device = &mut self.devices[i];
+        device.rings.retain(|&(ring_dci, _)| ring_dci != dci);
+        device.rings.push((dci, ring));
+        device.context_entries = context_entries;
+
+        Ok(())
+    }
+
+    fn address_device(&mut self, address: u8) -> Result<()> {
+        let _held = try!(self.take());
+        let i = try!(self.device_index(0));
+
+        // The controller picks the address it sends, the one given is how the device is known here
+        let input = self.devices[i].input.address();
+        self.context(input, 0, 0).write(0);
+        self.context(input, 0, 1).write(0b11);
+        let slot = self.devices[i].slot as u32;
+        let result = self.command(physical(input) as u64, TRB_ADDRESS_DEVICE << 10 | slot << 24);
+        try!(result);
+
+        self.devices[i].address = address;
+        // The device has 2 ms to move to its new address
+        delay(2);
+        Ok(())
+    }
+
+    fn interrupt_open(&mut self, address: u8, desc_end: &EndpointDescriptor) -> Result<InterruptPipe> {
+        let _held = try!(self.take());
+        // The interval was given to the controller with the endpoint context
+        let id = self.next_pipe;
+        self.next_pipe += 1;
+        self.pipes.push(XhciPipe {
+            id: id,
+            address: address,
+            dci: desc_end.number() * 2 + 1,
+            buffer: try!(Memory::new_aligned(cmp::max(1, desc_end.max_packet_size() as usize), 64)),
+            trb: 0,
+            completion: None,
+        });
+
+        let i = self.pipes.len() - 1;
+        if let Err(err) = self.arm(i) {
+            self.pipes.remove(i);
+            return Err(err);
+        }
+
+        Ok(InterruptPipe {
+            id: id,
+            address: address,
+            endpoint: desc_end.number(),
+        })
+    }
+
+    fn interrupt_report(&mut self, pipe: &InterruptPipe, data: &mut [u8]) -> Option<usize> {
+        let _held = match self.take() {
+            Ok(held) => held,
+            Err(_) => return None,
+        };
+        self.collect_events();
+
+        let i = match self.pipes.iter().position(|other| other.id == pipe.id) {
+            Some(i) => i,
+            None => return None,
+        };
+        let status = match self.pipes[i].completion {
+            Some(status) => status,
+            None => return None,
+        };
+
+        let result = match status >> 24 {
+            COMPLETION_SUCCESS | COMPLETION_SHORT_PACKET => {
+                let len = self.pipes[i].buffer.len();
+                let count = cmp::min(len - cmp::min(len, (status & 0xFFFFFF) as usize), data.len());
+                for j in 0..count {
+                    data[j] = self.pipes[i].buffer[j];
+                }
+                Some(count)
+            },
+            code => {
+                syslog_debug!("XHCI: Interrupt transfer to {} failed: {}", pipe.address, code);
+                if let Ok(j) = self.device_index(pipe.address) {
+                    let dci = self.pipes[i].dci;
+                    self.recover(j, dci, true);
+                }
+                None
+            }
+        };
+
+        if let Err(err) = self.arm(i) {
+            syslog_debug!("XHCI: Interrupt transfer to {} could not be queued: {}", pipe.address, err);
+        }
+
+        result
+    }
+
+    fn interrupt_close(&mut self, pipe: &InterruptPipe) {
+        let _held = hold(&mut self.busy);
+        if let Some(i) = self.pipes.iter().position(|other| other.id == pipe.id) {
+            let dci = self.pipes.remove(i).dci;
+            // Stopping the endpoint drops the TRB still queued on it
+            if let Ok(j) = self.device_index(pipe.address) {
+                self.recover(j, dci, false);
+            }
+        }
+    }
+
+    fn isoch_open(&mut self, address: u8, desc_end: &EndpointDescriptor) -> Result<IsochStream> {
+        let _held = try!(self.take());
+        // The controller kept the bandwidth, or refused the endpoint, when it was configured
+        let interval = {
+            let device = &self.devices[try!(self.device_index(address))];
+            desc_end.period(device.speed)
+        };
+
+        let id = self.next_pipe;
+        self.next_pipe += 1;
+        self.streams.push(XhciStream {
+            id: id,
+            address: address,
+            dci: desc_end.number() * 2 + desc_end.is_in() as u8,
+            max_packet_size: desc_end.max_packet_size(),
+            queued: Vec::new(),
+        });
+
+        Ok(IsochStream {
+            id: id,
+            address: address,
+            endpoint: desc_end.number(),
+            interval: interval,
+        })
+    }
+
+    fn isoch_queue(&mut self, stream: &IsochStream, buffer: IsochBuffer) -> Result<()> {
+        let _held = try!(self.take());
+        let i = match self.streams.iter().position(|other| other.id == stream.id) {
+            Some(i) => i,
+            None => return Err(Error::new(ENODEV)),
+        };
+        if ! buffer.fits(self.streams[i].max_packet_size) || buffer.data.len() > TRB_MAX {
+            return Err(Error::new(EINVAL));
+        }
+
+        // The ring is not told where the controller is, so it never holds more than it can before wrapping
+        let queued = self.streams[i].queued.iter().fold(0, |total, &(_, _, ref trbs)| total + trbs.len());
+        if queued + buffer.packets.len() > RING_SIZE - 2 {
+            return Err(Error::new(EAGAIN));
+        }
+
+        // Aligned to its size, the copy never crosses the 64 KB boundary a TRB may not cross
+        let mut data = try!(Memory::<u8>::new_aligned(cmp::max(1, buffer.data.len()),
+                                                      cmp::max(64, buffer.data.len().next_power_of_two())));
+        for j in 0..buffer.data.len() {
+            data[j] = buffer.data[j];
+        }
+
+        let (address, dci) = (self.streams[i].address, self.streams[i].dci);
+        let j = try!(self.device_index(address));
+        let slot = self.devices[j].slot;
+
+        let mut trbs = Vec::new();
+        {
+            let ring = match self.devices[j].rings.iter_mut().find(|&&mut (ring_dci, _)| ring_dci == dci) {
+                Some(&mut (_, ref mut ring)) => ring,
+                None => return Err(Error::new(ENODEV)),
+            };
+
+            // Each packet is a TD of its own, run in the interval after the one before it
+            let mut offset = 0;
+            for packet in buffer.packets.iter() {
+                let ptr = physical(data.address() + offset) as u64;
+                trbs.push(ring.push(ptr, packet.length as u32, TRB_ISOCH << 10 | TRB_SIA | TRB_ISP | TRB_IOC));
+                offset += packet.length as usize;
+            }
+        }
+
+        self.streams[i].queued.push((buffer, data, trbs));
+        // A ring that ran dry starts again here
+        self.reg(self.db_base + slot as usize * 4).write(dci as u32);
+
+        Ok(())
+    }
+
+    fn isoch_reap(&mut self, stream: &IsochStream) -> Option<IsochBuffer> {
+        let _held = match self.take() {
+            Ok(held) => held,
+            Err(_) => return None,
+        };
+        self.collect_events();
+
+        let i = match self.streams.iter().position(|other| other.id == stream.id) {
+            Some(i) => i,
+            None => return None,
+        };
+        if ! self.streams[i].queued.first().map_or(false, |&(ref buffer, _, _)| buffer.done()) {
+            return None;
+        }
+
+        let (mut buffer, data, _) = self.streams[i].queued.remove(0);
+        if self.streams[i].dci % 2 == 1 {
+            for j in 0..buffer.data.len() {
+                buffer.data[j] = data[j];
+            }
+        }
+        Some(buffer)
+    }
 
-                d("Write Doorbell\n");
-                let doorbell = db_base as *mut u32;
-                *doorbell = 0;
+    fn isoch_close(&mut self, stream: &IsochStream) {
+        let _held = hold(&mut self.busy);
+        if let Some(i) = self.streams.iter().position(|other| other.id == stream.id) {
+            let dci = self.streams[i].dci;
+            // Stopping the endpoint drops the packets still on its ring, before their buffers are freed
+            if let Ok(j) = self.device_index(stream.address) {
+                self.recover(j, dci, false);
             }
+            self.streams.remove(i);
         }
-        */
     }
 }